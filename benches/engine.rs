@@ -0,0 +1,66 @@
+//! Micro benchmarks of the hot paths: parsing a record, decoding a binary record, parsing an
+//! amount and handling a transaction end to end
+//!
+//! `cargo bench [filter]`
+use bank::{
+    binary,
+    csv_parser::parse_line,
+    currency::Currency,
+    payment_engine::ClientTable,
+    transaction::{ClientId, Transaction, TxId},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+fn parsing(c: &mut Criterion) {
+    let mut i = 0u64;
+    c.bench_function("parse_line", |b| {
+        b.iter(|| {
+            i += 1;
+            parse_line(Ok(format!("deposit, {}, {}, 12.3456", i % 1000, i))).unwrap()
+        })
+    });
+    let records: Vec<_> = (0..1000)
+        .map(|i| {
+            let line = format!("deposit, {}, {}, 12.3456", i % 1000, i);
+            binary::encode(&parse_line(Ok(line)).unwrap())
+        })
+        .collect();
+    let mut i = 0;
+    c.bench_function("decode_binary", |b| {
+        b.iter(|| {
+            i = (i + 1) % records.len();
+            binary::decode(black_box(&records[i])).unwrap()
+        })
+    });
+    let amounts = ["0.5", "12.3456", "1000000", "-3.25", "99999.9999"];
+    let mut i = 0;
+    c.bench_function("currency_from_str", |b| {
+        b.iter(|| {
+            i = (i + 1) % amounts.len();
+            black_box(amounts[i]).parse::<Currency>().unwrap()
+        })
+    });
+}
+
+fn handling(c: &mut Criterion) {
+    let mut table = ClientTable::new();
+    let mut i = 0u64;
+    c.bench_function("handle_transaction", |b| {
+        b.iter(|| {
+            i += 1;
+            // 997 clients so every client sees both deposits and withdrawals
+            let (client, tx) = ((i % 997) as ClientId, i as TxId);
+            let amount = Currency::new(10_000);
+            let transaction = match i % 4 {
+                3 => Transaction::Withdraw { client, tx, amount },
+                _ => Transaction::Deposit { client, tx, amount },
+            };
+            table.handle_transaction(black_box(transaction))
+        })
+    });
+    eprintln!("  {}", table.stats());
+}
+
+criterion_group!(benches, parsing, handling);
+criterion_main!(benches);
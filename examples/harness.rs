@@ -0,0 +1,189 @@
+//! End to end check of the serve mode against a batch run of the same records
+//!
+//! `cargo run --release --example harness [records] [seed]`
+//!
+//! Generates a feed of deposits, withdrawals and disputes, drives an in-process server with it
+//! from several concurrent connections, then compares the served report with the report of a
+//! parallel batch run over the same feed. Every diverging client is printed and the harness
+//! exits with a failure status if there is any
+use bank::{cancel::CancellationToken, payment_engine::ClientTable, server::Server};
+use std::{
+    collections::BTreeMap,
+    env,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    process, thread,
+};
+
+const CLIENTS: u64 = 500;
+/// Concurrent connections posting transactions, every client is only ever posted by one of them
+const SENDERS: u64 = 4;
+/// Records per request
+const BATCH: usize = 500;
+
+fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let records = args
+        .next()
+        .map_or(Ok(100_000), |n| n.parse())
+        .map_err(invalid_input)?;
+    let seed = args
+        .next()
+        .map_or(Ok(1), |n| n.parse())
+        .map_err(invalid_input)?;
+    let feed = generate(records, seed);
+
+    let server = Server::bind("127.0.0.1:0", ClientTable::new())?;
+    let addr = server.local_addr()?;
+    let token = CancellationToken::new();
+    let running = {
+        let token = token.clone();
+        thread::spawn(move || server.run(&token))
+    };
+    let senders: Vec<_> = (0..SENDERS)
+        .map(|sender| {
+            let lines: Vec<String> = feed
+                .iter()
+                .filter(|(client, _)| client % SENDERS == sender)
+                .map(|(_, line)| line.clone())
+                .collect();
+            thread::spawn(move || -> io::Result<()> {
+                for batch in lines.chunks(BATCH) {
+                    let mut body = batch.join("\n");
+                    body.push('\n');
+                    expect_ok(&request(addr, "POST", "/transactions", &body)?)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for sender in senders {
+        sender.join().expect("sender panicked")?;
+    }
+    let report = request(addr, "GET", "/report", "")?;
+    let served = body(expect_ok(&report)?);
+    expect_ok(&request(addr, "POST", "/admin/stop", "")?)?;
+    running.join().expect("server panicked")?;
+
+    let mut csv = String::new();
+    for (_, line) in &feed {
+        csv.push_str(line);
+        csv.push('\n');
+    }
+    let mut batch = ClientTable::new();
+    batch.process_parallel(csv.as_bytes(), 4)?;
+    let mut expected = Vec::new();
+    batch.write_csv(&mut expected)?;
+    let expected = String::from_utf8(expected).map_err(invalid_input)?;
+
+    let served = rows(served);
+    let expected = rows(&expected);
+    let mut divergences = 0;
+    for (client, row) in &expected {
+        if served.get(client) != Some(row) {
+            divergences += 1;
+            println!(
+                "client {}: served `{}`, batch `{}`",
+                client,
+                served.get(client).copied().unwrap_or("missing"),
+                row
+            );
+        }
+    }
+    for (client, row) in &served {
+        if !expected.contains_key(client) {
+            divergences += 1;
+            println!("client {}: served `{}`, batch missing", client, row);
+        }
+    }
+    println!(
+        "{} records, {} clients, {} divergences",
+        feed.len(),
+        expected.len(),
+        divergences
+    );
+    if divergences > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Feed of `records` csv lines keyed by client, the same seed always yields the same feed
+fn generate(records: usize, seed: u64) -> Vec<(u64, String)> {
+    let mut state = seed.max(1);
+    let mut next = move || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut deposits: Vec<Vec<usize>> = vec![Vec::new(); CLIENTS as usize];
+    let mut feed = Vec::with_capacity(records);
+    for tx in 1..=records {
+        let client = next() % CLIENTS;
+        let own = &mut deposits[client as usize];
+        let amount = format!("{}.{:02}", next() % 1000, next() % 100);
+        let roll = next() % 100;
+        let line = if roll < 70 || own.is_empty() {
+            own.push(tx);
+            format!("deposit, {}, {}, {}", client, tx, amount)
+        } else if roll < 90 {
+            format!("withdrawal, {}, {}, {}", client, tx, amount)
+        } else {
+            let disputed = own[next() as usize % own.len()];
+            let kind = match roll {
+                90..=95 => "dispute",
+                96..=98 => "resolve",
+                _ => "chargeback",
+            };
+            format!("{}, {}, {},", kind, client, disputed)
+        };
+        feed.push((client, line));
+    }
+    feed
+}
+
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nContent-Type: text/csv\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+fn expect_ok(response: &str) -> io::Result<&str> {
+    if response.starts_with("HTTP/1.1 2") {
+        Ok(response)
+    } else {
+        let status = response.lines().next().unwrap_or_default();
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            status.to_string(),
+        ))
+    }
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
+
+/// Report rows keyed by client id, the header is skipped
+fn rows(report: &str) -> BTreeMap<&str, &str> {
+    report
+        .lines()
+        .skip(1)
+        .filter_map(|row| Some((row.split(',').next()?, row)))
+        .collect()
+}
+
+fn invalid_input<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+}
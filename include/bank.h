@@ -0,0 +1,57 @@
+/* C interface of the payment engine, see src/ffi.rs
+ *
+ * Build the shared library with `cargo build --release --features ffi` and link against
+ * target/release/libbank.so. An engine is not thread safe, callers serialize its use */
+
+#ifndef BANK_H
+#define BANK_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The transaction was applied */
+#define BANK_APPLIED 0
+/* The engine rejected the transaction, the accounts are unchanged */
+#define BANK_REJECTED 1
+/* The record couldn't be parsed, or an argument was null */
+#define BANK_INVALID (-1)
+
+typedef struct BankEngine BankEngine;
+
+/* Balances of a client in units of the smallest amount, 10^-decimals */
+typedef struct BankBalance {
+    int64_t available;
+    int64_t held;
+    int64_t total;
+    bool locked;
+} BankBalance;
+
+/* Creates an engine parsing amounts with `decimals` decimals, null if there are too many */
+BankEngine *bank_engine_new(uint32_t decimals);
+
+/* Frees an engine, null is ignored */
+void bank_engine_free(BankEngine *engine);
+
+/* Handles a csv record such as "deposit, 1, 1, 2.5", returns BANK_APPLIED, BANK_REJECTED or
+ * BANK_INVALID */
+int32_t bank_engine_submit(BankEngine *engine, const char *record);
+
+/* Writes the balances of `client` to `out`, returns false if the client hasn't been seen or a
+ * balance doesn't fit in 64 bits */
+bool bank_engine_balance(const BankEngine *engine, uint16_t client, BankBalance *out);
+
+/* Writes the csv report to `buf` as a NUL terminated string and returns its length without the
+ * terminator. Like snprintf a longer report is truncated, retry with the returned length plus
+ * one. `buf` may be null when `len` is 0 */
+size_t bank_engine_report(const BankEngine *engine, char *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
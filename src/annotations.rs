@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+
+use crate::{client_info::TransactionError, rejects::quote};
+
+/// What became of an input record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    Applied,
+    /// Held until an operator approves it, see `ApprovalPolicy`
+    Quarantined,
+    /// Refused because it reuses the id of an earlier transaction
+    Duplicate,
+    /// Refused for the given reason, named as in the rejects file
+    Rejected(String),
+}
+
+impl Disposition {
+    /// Disposition of a record the engine handled, `queued` if it now awaits approval
+    pub fn of(outcome: Result<(), TransactionError>, queued: bool) -> Self {
+        match outcome {
+            Ok(()) if queued => Disposition::Quarantined,
+            Ok(()) => Disposition::Applied,
+            Err(TransactionError::DuplicateTxId) => Disposition::Duplicate,
+            Err(e) => Disposition::Rejected(format!("{:?}", e)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Disposition::Applied => "applied",
+            Disposition::Quarantined => "quarantined",
+            Disposition::Duplicate => "duplicate",
+            Disposition::Rejected(_) => "rejected",
+        }
+    }
+}
+
+/// Csv side output with the disposition of every input record, by the line the record starts on
+/// Unlike the rejects file it also lists the records that went through, so a feed provider can
+/// reconcile the whole feed against it
+pub struct Annotations<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Annotations<W> {
+    /// Writes the header right away, so an empty feed still leaves a valid file
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "line, outcome, reason")?;
+        Ok(Self { out })
+    }
+
+    /// The reason is left empty for records that were not rejected
+    pub fn annotate(&mut self, line: usize, disposition: &Disposition) -> io::Result<()> {
+        match disposition {
+            Disposition::Rejected(reason) => writeln!(
+                self.out,
+                "{}, {}, {}",
+                line,
+                disposition.name(),
+                quote(reason)
+            ),
+            _ => writeln!(self.out, "{}, {},", line, disposition.name()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_parser::split_fields;
+
+    #[test]
+    fn every_disposition_is_annotated() {
+        let mut annotations = Annotations::new(Vec::new()).unwrap();
+        let dispositions = [
+            Disposition::of(Ok(()), false),
+            Disposition::of(Ok(()), true),
+            Disposition::of(Err(TransactionError::DuplicateTxId), false),
+            Disposition::of(Err(TransactionError::Overdraw), false),
+            Disposition::Rejected("parse error".to_string()),
+        ];
+        for (line, disposition) in dispositions.iter().enumerate() {
+            annotations.annotate(line + 2, disposition).unwrap();
+        }
+        let out = String::from_utf8(annotations.into_inner()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "line, outcome, reason");
+        let fields: Vec<_> = lines[1..]
+            .iter()
+            .map(|l| split_fields(l).unwrap().join("|"))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "2|applied|",
+                "3|quarantined|",
+                "4|duplicate|",
+                "5|rejected|Overdraw",
+                "6|rejected|parse error"
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    client_info::TransactionError,
+    currency::{AggregationOverflow, Currency},
+    transaction::{ApproverId, ClientId, Transaction, TxId},
+};
+
+/// Large deposits and withdrawals waiting for enough matching `approve` records
+/// Approvals are expected to be rare, so a plain vector is searched on every lookup
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PendingApprovals {
+    pending: Vec<Pending>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Pending {
+    transaction: Transaction,
+    queued_at: u64,
+    /// Distinct operators who signed off so far
+    approved_by: Vec<ApproverId>,
+}
+
+impl PendingApprovals {
+    pub fn queue(&mut self, transaction: Transaction, now: u64) {
+        self.pending.push(Pending {
+            transaction,
+            queued_at: now,
+            approved_by: Vec::new(),
+        });
+    }
+
+    pub fn contains(&self, tx: TxId) -> bool {
+        self.pending.iter().any(|p| p.transaction.tx() == tx)
+    }
+
+    /// Records the sign-off of `approver` on pending transaction `tx`, once `required` distinct
+    /// approvers signed off the transaction is removed and returned
+    pub fn approve(
+        &mut self,
+        client: ClientId,
+        tx: TxId,
+        approver: ApproverId,
+        required: usize,
+    ) -> Result<Option<Transaction>, TransactionError> {
+        let position = self
+            .pending
+            .iter()
+            .position(|p| p.transaction.client() == client && p.transaction.tx() == tx)
+            .ok_or(TransactionError::InvalidTxId)?;
+        let approved_by = &mut self.pending[position].approved_by;
+        if approved_by.contains(&approver) {
+            return Err(TransactionError::AlreadyApproved);
+        }
+        approved_by.push(approver);
+        if approved_by.len() < required {
+            return Ok(None);
+        }
+        Ok(Some(self.pending.remove(position).transaction))
+    }
+
+    /// Drops everything that has been waiting for more than `timeout` ticks of the engine clock
+    pub fn expire(&mut self, now: u64, timeout: u64) {
+        if !self.pending.is_empty() {
+            self.pending.retain(|p| now - p.queued_at <= timeout);
+        }
+    }
+
+    /// Sum of the pending amounts for a single client, withdrawals count as negative
+    pub fn amount_for(&self, client: ClientId) -> Result<Currency, AggregationOverflow> {
+        let overflow = AggregationOverflow {
+            aggregate: "pending amount",
+        };
+        let amounts = self
+            .pending
+            .iter()
+            .filter_map(|p| match p.transaction {
+                Transaction::Deposit {
+                    client: c, amount, ..
+                } if c == client => Some(Some(amount)),
+                Transaction::Withdraw {
+                    client: c, amount, ..
+                } if c == client => Some(amount.checked_neg()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(overflow)?;
+        Currency::checked_sum(amounts).ok_or(overflow)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.pending.iter().map(|p| &p.transaction)
+    }
+
+    /// Pending transactions together with the clock tick they were queued at
+    pub fn entries(&self) -> impl Iterator<Item = (&Transaction, u64)> {
+        self.pending.iter().map(|p| (&p.transaction, p.queued_at))
+    }
+
+    /// Sign-offs collected so far, as `(client, tx, approver)`
+    pub fn approvals(&self) -> impl Iterator<Item = (ClientId, TxId, ApproverId)> + '_ {
+        self.pending.iter().flat_map(|p| {
+            let (client, tx) = (p.transaction.client(), p.transaction.tx());
+            p.approved_by
+                .iter()
+                .map(move |&approver| (client, tx, approver))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Moves the pending transactions matching `belongs` into a separate set
+    pub fn split_off(&mut self, belongs: impl Fn(&Transaction) -> bool) -> Self {
+        let (split, kept) = self
+            .pending
+            .drain(..)
+            .partition(|p| belongs(&p.transaction));
+        self.pending = kept;
+        Self { pending: split }
+    }
+
+    pub fn merge(&mut self, other: Self) {
+        self.pending.extend(other.pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::deposit;
+
+    #[test]
+    fn approve_matches_client_and_tx() {
+        let mut pending = PendingApprovals::default();
+        pending.queue(deposit(1, 1, 10000), 0);
+        assert_eq!(
+            pending.approve(2, 1, 7, 1),
+            Err(TransactionError::InvalidTxId)
+        );
+        assert_eq!(pending.approve(1, 1, 7, 1), Ok(Some(deposit(1, 1, 10000))));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn release_needs_distinct_approvers() {
+        let mut pending = PendingApprovals::default();
+        pending.queue(deposit(1, 1, 10000), 0);
+        assert_eq!(pending.approve(1, 1, 7, 2), Ok(None));
+        assert_eq!(
+            pending.approve(1, 1, 7, 2),
+            Err(TransactionError::AlreadyApproved)
+        );
+        assert!(pending.contains(1));
+        assert_eq!(pending.approvals().collect::<Vec<_>>(), [(1, 1, 7)]);
+        assert_eq!(pending.approve(1, 1, 8, 2), Ok(Some(deposit(1, 1, 10000))));
+    }
+
+    #[test]
+    fn expire_drops_old_entries() {
+        let mut pending = PendingApprovals::default();
+        pending.queue(deposit(1, 1, 10000), 0);
+        pending.queue(deposit(1, 2, 10000), 5);
+        pending.expire(10, 5);
+        assert!(!pending.contains(1));
+        assert!(pending.contains(2));
+        assert_eq!(pending.amount_for(1), Ok(Currency::new(10000)));
+    }
+}
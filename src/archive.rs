@@ -0,0 +1,216 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    client_info::{ClientTransaction, DisputeState, TransferKind},
+    transaction::{ClientId, Timestamp, TxId},
+    version::{CompatCheck, Stamp},
+};
+
+/// How long transfers stay in the active history, `periods` periods of `period_secs` seconds
+/// each. Transfers older than that are moved out by `ClientTable::archive_expired`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionPeriod {
+    pub periods: u64,
+    pub period_secs: u64,
+}
+
+impl RetentionPeriod {
+    pub const DAY: u64 = 24 * 60 * 60;
+
+    pub fn days(days: u64) -> Self {
+        Self {
+            periods: days,
+            period_secs: Self::DAY,
+        }
+    }
+
+    /// Transfers that took place before the returned time have expired at `now`
+    pub fn cutoff(&self, now: Timestamp) -> Timestamp {
+        now.saturating_sub(self.periods.saturating_mul(self.period_secs))
+    }
+}
+
+/// Append only csv file holding transfers moved out of the active client history
+/// The file starts with a compatibility stamp followed by `client, tx, kind, amount` lines with
+/// the amount signed like in the active history, and the timestamp of the transfer when it has one
+/// Lookups scan the whole file, archived transfers are only expected to be needed for late
+/// disputes and statements so trading speed for memory is fine here
+pub struct Archive {
+    path: PathBuf,
+    check: CompatCheck,
+}
+
+impl Archive {
+    pub fn new(path: impl Into<PathBuf>, check: CompatCheck) -> Self {
+        Self {
+            path: path.into(),
+            check,
+        }
+    }
+
+    pub fn append<'a>(
+        &self,
+        records: impl IntoIterator<Item = (ClientId, &'a ClientTransaction)>,
+    ) -> io::Result<usize> {
+        let mut w = self.writer()?;
+        let mut written = 0;
+        for (client, t) in records {
+            write!(
+                w,
+                "{}, {}, {}, {}",
+                client,
+                t.tx(),
+                t.kind().name(),
+                t.amount()
+            )?;
+            if let Some(at) = t.at() {
+                write!(w, ", {}", at)?;
+            }
+            writeln!(w)?;
+            written += 1;
+        }
+        w.flush()?;
+        Ok(written)
+    }
+
+    /// Records how the disputes of archived transfers ended as `client, tx, state` lines, so a
+    /// recalled transfer can't be disputed again
+    pub fn append_settled(
+        &self,
+        records: impl IntoIterator<Item = (ClientId, TxId, DisputeState)>,
+    ) -> io::Result<usize> {
+        let mut w = self.writer()?;
+        let mut written = 0;
+        for (client, tx, state) in records {
+            writeln!(w, "{}, {}, {}", client, tx, state.name())?;
+            written += 1;
+        }
+        w.flush()?;
+        Ok(written)
+    }
+
+    fn writer(&self) -> io::Result<BufWriter<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let new = file.metadata()?.len() == 0;
+        if !new {
+            Stamp::check(&mut BufReader::new(File::open(&self.path)?), self.check)?;
+        }
+        let mut w = BufWriter::new(file);
+        if new {
+            Stamp::write(&mut w)?;
+        }
+        Ok(w)
+    }
+
+    /// Finds everything archived about transaction `tx` of `client`, returns `None` when the
+    /// archive has nothing about it or does not exist yet
+    pub fn find(&self, client: ClientId, tx: TxId) -> io::Result<Option<ArchivedTx>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+        Stamp::check(&mut reader, self.check)?;
+        let mut found = ArchivedTx::default();
+        for line in reader.lines() {
+            let line = line?;
+            let mut fields = line.split(',').map(|f| f.trim());
+            if fields.next() != Some(&client.to_string()) || fields.next() != Some(&tx.to_string())
+            {
+                continue;
+            }
+            let kind = fields.next().ok_or_else(|| corrupt(&line))?;
+            let amount = match fields.next() {
+                Some(amount) => amount.parse().map_err(|_| corrupt(&line))?,
+                None => {
+                    found.settled =
+                        Some(DisputeState::from_name(kind).ok_or_else(|| corrupt(&line))?);
+                    continue;
+                }
+            };
+            let kind = TransferKind::from_name(kind).ok_or_else(|| corrupt(&line))?;
+            let at = match fields.next() {
+                Some(at) => Some(at.parse().map_err(|_| corrupt(&line))?),
+                None => None,
+            };
+            found
+                .entries
+                .push(ClientTransaction::new(kind, amount, tx).stamped(at));
+        }
+        Ok(Some(found).filter(|found| *found != ArchivedTx::default()))
+    }
+}
+
+/// What an archive holds about a single transaction
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchivedTx {
+    /// The transfer together with the fees and refunds referencing it, in archival order
+    pub entries: Vec<ClientTransaction>,
+    /// How its dispute ended, if it was disputed
+    pub settled: Option<DisputeState>,
+}
+
+fn corrupt(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupt archive record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+
+    #[test]
+    fn append_and_find() {
+        let path = std::env::temp_dir().join(format!("bank_archive_{}.csv", std::process::id()));
+        let archive = Archive::new(&path, CompatCheck::Strict);
+        assert!(archive.find(1, 1).unwrap().is_none());
+        let deposit =
+            ClientTransaction::new(TransferKind::Deposit, Currency::new(15000), 1).stamped(Some(9));
+        let fee = ClientTransaction::new(TransferKind::Fee, Currency::new(-100), 1);
+        let withdrawal = ClientTransaction::new(TransferKind::Withdrawal, Currency::new(-5000), 2);
+        archive
+            .append(vec![(1, &deposit), (2, &withdrawal), (1, &fee)])
+            .unwrap();
+        archive
+            .append_settled(vec![(1, 1, DisputeState::ChargedBack)])
+            .unwrap();
+        let found = archive.find(2, 2).unwrap().unwrap();
+        let charged_back = archive.find(1, 1).unwrap().unwrap();
+        let missing = archive.find(1, 2).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(found.entries[0].kind(), TransferKind::Withdrawal);
+        assert_eq!(found.entries[0].amount(), Currency::new(-5000));
+        assert_eq!(found.entries[0].at(), None);
+        assert_eq!(found.settled, None);
+        assert!(missing.is_none());
+        assert_eq!(charged_back.entries, [deposit, fee]);
+        assert_eq!(charged_back.entries[0].at(), Some(9));
+        assert_eq!(charged_back.settled, Some(DisputeState::ChargedBack));
+    }
+
+    #[test]
+    fn incompatible_archive_is_refused() {
+        let path =
+            std::env::temp_dir().join(format!("bank_archive_old_{}.csv", std::process::id()));
+        std::fs::write(&path, "1, 1, deposit, 1.0000\n").unwrap();
+        let strict = Archive::new(&path, CompatCheck::Strict).find(1, 1);
+        let forced = Archive::new(&path, CompatCheck::Force).find(1, 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(strict.is_err());
+        assert_eq!(
+            forced.unwrap().unwrap().entries[0].amount(),
+            Currency::new(10000)
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, Lines};
+
+use crate::{
+    csv_parser::{parse_line_with, ParseCSVError},
+    currency::CurrencyConfig,
+    payment_engine::{ClientTable, Summary},
+    transaction::Transaction,
+};
+
+impl ClientTable {
+    /// Applies the transactions of `stream` as they arrive and returns how many were applied and
+    /// rejected once it ends. The engine itself never blocks, so this only waits on the stream
+    pub async fn process_async<S: Stream<Item = Transaction> + Unpin>(
+        &mut self,
+        mut stream: S,
+    ) -> Summary {
+        let mut summary = Summary::default();
+        while let Some(tx) = stream.next().await {
+            summary += self.process(Some(tx));
+        }
+        summary
+    }
+}
+
+/// Lines of a tokio reader, such as a `BufReader` over a socket, as a `Stream`
+pub struct AsyncLines<R> {
+    lines: Lines<R>,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncLines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Stream for AsyncLines<R> {
+    type Item = io::Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.lines)
+            .poll_next_line(cx)
+            .map(Result::transpose)
+    }
+}
+
+/// Parses a stream of csv lines, such as the lines read from a socket, into transactions
+/// Like the blocking reader the header has to be dropped by the caller
+pub struct CsvStream<S> {
+    lines: S,
+    currency: CurrencyConfig,
+}
+
+impl<S> CsvStream<S> {
+    pub fn new(lines: S, currency: CurrencyConfig) -> Self {
+        Self { lines, currency }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> CsvStream<AsyncLines<R>> {
+    /// Parses the lines of a tokio reader
+    pub fn from_reader(reader: R, currency: CurrencyConfig) -> Self {
+        Self::new(AsyncLines::new(reader), currency)
+    }
+}
+
+impl<S: Stream<Item = io::Result<String>> + Unpin> Stream for CsvStream<S> {
+    type Item = Result<Transaction, ParseCSVError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let currency = self.currency;
+        Pin::new(&mut self.lines)
+            .poll_next(cx)
+            .map(|line| line.map(|line| parse_line_with(line, currency)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::collections::VecDeque;
+
+    /// Stream yielding its items but only every other poll, to exercise the pending path
+    struct Lagging<T> {
+        items: VecDeque<T>,
+        ready: bool,
+    }
+
+    impl<T: Unpin> Stream for Lagging<T> {
+        type Item = T;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.items.pop_front())
+        }
+    }
+
+    const EXPECTED: &str =
+        "client, available, held, total, locked\n1, 1.5000, 0.0000, 1.5000, false\n";
+
+    #[test]
+    fn processes_async_csv_stream() {
+        let lines = ["deposit, 1, 1, 2.0", "garbage", "withdrawal, 1, 2, 0.5"];
+        let lines = Lagging {
+            items: lines.iter().map(|l| Ok(l.to_string())).collect(),
+            ready: false,
+        };
+        let transactions = CsvStream::new(lines, CurrencyConfig::default())
+            .filter_map(|tx| futures::future::ready(tx.ok()));
+        let mut table = ClientTable::new();
+        let summary = block_on(table.process_async(Box::pin(transactions)));
+        assert_eq!(
+            summary,
+            Summary {
+                applied: 2,
+                rejected: 0
+            }
+        );
+        assert_eq!(table.to_string(), EXPECTED);
+    }
+
+    #[test]
+    fn processes_a_tokio_reader() {
+        let input: &[u8] = b"deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 0.5\n";
+        let transactions = CsvStream::from_reader(input, CurrencyConfig::default())
+            .map(|tx| tx.expect("valid records"));
+        let mut table = ClientTable::new();
+        let summary = block_on(table.process_async(transactions));
+        assert_eq!(summary.applied, 2);
+        assert_eq!(table.to_string(), EXPECTED);
+    }
+}
@@ -0,0 +1,192 @@
+use std::io::{self, Write};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::payment_engine::ClientTable;
+
+/// Header of the attested statement, the balance columns match the regular report
+pub const HEADER: &str = "client, available, held, total, locked, period, state_root, attestation";
+
+impl ClientTable {
+    /// Writes the balance report with every row signed by the deployment key
+    ///
+    /// The state root is the SHA-256 of the plain report rows (`client, available, held, total, locked`
+    /// lines, each terminated by a newline) and ties every row to the complete statement.
+    /// The attestation of a row is the HMAC-SHA256 under `key` of its balance columns, the
+    /// period and the state root, joined by `|`: `client|available|held|total|locked|period|state_root`.
+    /// Digests are written as lowercase hex, a period or client label that can't be written as a
+    /// plain column is refused, see `check_period`
+    pub fn write_attested_csv<W: Write>(
+        &self,
+        mut w: W,
+        key: &[u8],
+        period: &str,
+    ) -> io::Result<()> {
+        check_period(period)?;
+        let currency = self.currency_config();
+        let rows = self
+            .clients()
+            .map(|(client, info)| {
+                let label = self.client_label(client);
+                check_column("client label", &label)?;
+                Ok([
+                    label,
+                    currency.display(info.available_funds()).to_string(),
+                    currency.display(info.held_funds()).to_string(),
+                    currency.display(info.total_funds()?).to_string(),
+                    info.is_locked().to_string(),
+                ])
+            })
+            .collect::<io::Result<Vec<[String; 5]>>>()?;
+        let mut statement = Vec::new();
+        for row in &rows {
+            writeln!(statement, "{}", row.join(", "))?;
+        }
+        let state_root = hex(&sha256(&statement));
+
+        writeln!(w, "{}", HEADER)?;
+        for row in &rows {
+            let message = format!("{}|{}|{}", row.join("|"), period, state_root);
+            let attestation = hex(&hmac_sha256(key, message.as_bytes()));
+            writeln!(
+                w,
+                "{}, {}, {}, {}",
+                row.join(", "),
+                period,
+                state_root,
+                attestation
+            )?;
+        }
+        w.flush()
+    }
+}
+
+/// SHA-256 as specified in FIPS 180-4
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// HMAC-SHA256 as specified in RFC 2104
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Refuses periods that would break the statement columns or the signed message
+pub fn check_period(period: &str) -> io::Result<()> {
+    check_column("period", period)
+}
+
+fn check_column(name: &str, value: &str) -> io::Result<()> {
+    if value.contains([',', '"', '|', '\n', '\r']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the {} {:?} can't contain commas, quotes, pipes or line breaks",
+                name, value
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{currency::Currency, transaction::Transaction};
+    use std::collections::HashMap;
+
+    #[test]
+    fn attested_rows_verify_against_the_key() {
+        let mut table = ClientTable::new();
+        table.process(vec![Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(15000),
+        }]);
+        let mut out = Vec::new();
+        table
+            .write_attested_csv(&mut out, b"secret", "2026-09")
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some(HEADER));
+        let row: Vec<_> = lines.next().unwrap().split(", ").collect();
+        let root = hex(&sha256(b"1, 1.5000, 0.0000, 1.5000, false\n"));
+        assert_eq!(
+            row[..7],
+            ["1", "1.5000", "0.0000", "1.5000", "false", "2026-09", &root]
+        );
+        let message = format!("1|1.5000|0.0000|1.5000|false|2026-09|{}", root);
+        assert_eq!(row[7], hex(&hmac_sha256(b"secret", message.as_bytes())));
+        assert_ne!(row[7], hex(&hmac_sha256(b"other", message.as_bytes())));
+    }
+
+    #[test]
+    fn periods_that_break_the_columns_are_refused() {
+        let table = ClientTable::new();
+        for period in &["2026,09", "2026\"09", "2026|09", "2026\n09"] {
+            let error = table
+                .write_attested_csv(Vec::new(), b"secret", period)
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(table
+            .write_attested_csv(Vec::new(), b"secret", "2026 Q3")
+            .is_ok());
+    }
+
+    #[test]
+    fn labels_that_break_the_columns_are_refused() {
+        let mut table = ClientTable::new();
+        table.process(vec![Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(15000),
+        }]);
+        for label in &["acme, inc", "acme|inc", "acme \"inc\"", "acme\ninc"] {
+            table.set_client_labels(HashMap::from([(1, label.to_string())]));
+            let mut out = Vec::new();
+            let error = table
+                .write_attested_csv(&mut out, b"secret", "2026-09")
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn separators_cant_forge_a_row() {
+        let mut table = ClientTable::new();
+        table.process(vec![Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(15000),
+        }]);
+        let mut out = Vec::new();
+        table
+            .write_attested_csv(&mut out, b"secret", "2026-09")
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let root = out.lines().nth(1).unwrap().split(", ").nth(6).unwrap();
+        // Either would sign a message starting with the balance columns of client 1
+        table.set_client_labels(HashMap::from([(
+            1,
+            "1|1.5000|0.0000|1.5000|false".to_string(),
+        )]));
+        assert!(table
+            .write_attested_csv(Vec::new(), b"secret", "2026-09")
+            .is_err());
+        table.set_client_labels(HashMap::new());
+        let period = format!("2026-09|{}", root);
+        assert!(table
+            .write_attested_csv(Vec::new(), b"secret", &period)
+            .is_err());
+    }
+}
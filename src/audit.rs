@@ -0,0 +1,385 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    attestation::{hex, sha256},
+    client_info::{ClientInfo, TransactionError},
+    csv_parser::{parse_line_with, split_fields},
+    currency::CurrencyConfig,
+    error::EngineError,
+    payment_engine::{ClientTable, Summary},
+    rejects::quote,
+    transaction::Transaction,
+};
+
+/// Header of the audit trail
+pub const HEADER: &str = "time, operator, record, outcome, before, after, chain";
+
+/// Chain value the first record of a trail links to
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Csv audit trail of the transactions entered by hand rather than read from a feed, one record
+/// per transaction with when and by whom it was entered, the record, its outcome and the
+/// balances of the account before and after it
+///
+/// The records are hash chained: the last column is the hex SHA-256 of the chain value of the
+/// previous record, `GENESIS` for the first one, followed by a newline and the other columns of
+/// the record as written. Editing, dropping or reordering records breaks the chain from there on
+pub struct AuditLog<W: Write> {
+    out: W,
+    /// Chain value of the last record written
+    chain: String,
+}
+
+/// Record of the trail as read back by `read_trail`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Line of the trail the record is on
+    pub line: usize,
+    pub operator: String,
+    /// The transaction in the csv input format
+    pub record: String,
+    pub outcome: String,
+    /// Balances of the account after the transaction, as in the report
+    pub after: String,
+}
+
+/// A transaction entered by an operator together with its effect on the account
+pub struct AuditEntry<'a> {
+    /// Seconds since the unix epoch, or the number of records the table handled when it is
+    /// deterministic
+    pub time: u64,
+    pub operator: &'a str,
+    pub transaction: Transaction,
+    /// Precision the amount of the transaction is written with
+    pub currency: CurrencyConfig,
+    pub outcome: Result<(), TransactionError>,
+    pub before: &'a ClientInfo,
+    pub after: &'a ClientInfo,
+}
+
+impl AuditLog<File> {
+    /// Opens the audit trail at `path` for appending, a new trail starts with the header
+    /// The existing records are verified so new ones are never chained onto a broken trail
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let out = OpenOptions::new().append(true).create(true).open(path)?;
+        if out.metadata()?.len() == 0 {
+            return Self::new(out);
+        }
+        let (_, chain) = read_chain(BufReader::new(File::open(path)?))?;
+        Ok(Self { out, chain })
+    }
+}
+
+impl<W: Write> AuditLog<W> {
+    /// Starts a new audit trail with its header
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "{}", HEADER)?;
+        Ok(Self {
+            out,
+            chain: GENESIS.to_string(),
+        })
+    }
+
+    /// Appends `entry` and flushes it, so the trail is complete even if the process dies next
+    pub fn record(&mut self, entry: &AuditEntry<'_>) -> io::Result<()> {
+        let t = &entry.transaction;
+        let mut record = format!("{}, {}, {}, ", t.kind_name(), t.client(), t.tx());
+        if let Some(code) = t.currency_code() {
+            let _ = write!(record, "{}, ", code);
+        }
+        if let Some(amount) = t.record_amount() {
+            let _ = write!(record, "{}", entry.currency.display(amount));
+        }
+        if let Some(approver) = t.approver() {
+            let _ = write!(record, "{}", approver);
+        }
+        if let Some(value_date) = t.value_date() {
+            let _ = write!(record, ", {}", value_date);
+        }
+        if let Some(to) = t.target_currency() {
+            let _ = write!(record, ", {}", to);
+        }
+        let line = format!(
+            "{}, {}, {}, {}, {}, {}",
+            entry.time,
+            quote(entry.operator),
+            quote(&record),
+            quote(&outcome_name(entry.outcome)),
+            quote(&entry.before.to_string()),
+            quote(&entry.after.to_string())
+        );
+        self.chain = link(&self.chain, &line);
+        writeln!(self.out, "{}, {}", line, self.chain)?;
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl ClientTable {
+    /// Applies a single transaction entered by `operator` and records it in the audit trail,
+    /// whether the engine accepts it or not. The outer error is a failure to write the trail,
+    /// which happens after the transaction was handled
+    pub fn apply_audited<W: Write>(
+        &mut self,
+        transaction: Transaction,
+        operator: &str,
+        log: &mut AuditLog<W>,
+    ) -> io::Result<Result<(), TransactionError>> {
+        let before = self.audited_account(&transaction);
+        let outcome = self.handle_transaction(transaction);
+        let after = self.audited_account(&transaction);
+        let time = if self.is_deterministic() {
+            self.clock
+        } else {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        };
+        log.record(&AuditEntry {
+            time,
+            operator,
+            transaction,
+            currency: self.currency_config(),
+            outcome,
+            before: &before,
+            after: &after,
+        })?;
+        Ok(outcome)
+    }
+
+    /// Rebuilds the state from the audit trail at `path` alone, the way back when the snapshot of a
+    /// state maintained with `apply_audited` is missing or corrupt. The trail only holds the
+    /// transactions entered by hand, so the state is complete if it was built with them from an
+    /// empty table
+    ///
+    /// The chain is verified while reading, then every record is replayed on an empty table and
+    /// must reach the outcome and balances it was logged with. The table keeps its configuration
+    /// and is left untouched on error
+    pub fn recover_from_audit(&mut self, path: impl AsRef<Path>) -> Result<Summary, EngineError> {
+        let records = read_trail(BufReader::new(File::open(path)?))?;
+        let mut table = self.empty_like();
+        let mut summary = Summary::default();
+        for entry in records {
+            let transaction = parse_line_with(Ok(entry.record.clone()), table.currency_config())?;
+            let outcome = table.handle_transaction(transaction);
+            match outcome {
+                Ok(()) => summary.applied += 1,
+                Err(_) => summary.rejected += 1,
+            }
+            let after = table.audited_account(&transaction).to_string();
+            if outcome_name(outcome) != entry.outcome || after != entry.after {
+                return Err(invalid(format!(
+                    "Audit record on line {} replays as {}, {} instead",
+                    entry.line,
+                    outcome_name(outcome),
+                    after
+                ))
+                .into());
+            }
+        }
+        self.replace_state(table);
+        Ok(summary)
+    }
+
+    /// Account the transaction applies to, the foreign one for transactions in a foreign currency
+    fn audited_account(&self, transaction: &Transaction) -> ClientInfo {
+        let client = transaction.client();
+        let code = match transaction.amount() {
+            Some(_) => transaction.currency_code(),
+            None => self.tx_codes.get(&transaction.tx()).copied(),
+        };
+        let account = match code {
+            Some(code) if Some(code) != self.base_currency() => self.foreign_account(client, code),
+            _ => self.client(client),
+        };
+        account.cloned().unwrap_or_default()
+    }
+}
+
+/// Reads a trail written by `AuditLog`, verifying its chain
+pub fn read_trail<R: BufRead>(reader: R) -> io::Result<Vec<AuditRecord>> {
+    read_chain(reader).map(|(records, _)| records)
+}
+
+/// Records of the trail and the chain value of the last one
+fn read_chain<R: BufRead>(reader: R) -> io::Result<(Vec<AuditRecord>, String)> {
+    let mut lines = reader.lines();
+    match lines.next().transpose()? {
+        Some(header) if header.trim() == HEADER => {}
+        _ => return Err(invalid("Not an audit trail".to_string())),
+    }
+    let mut records = Vec::new();
+    let mut chain = GENESIS.to_string();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let number = i + 2;
+        let broken = || invalid(format!("Audit chain broken on line {}", number));
+        let (body, link_value) = line.rsplit_once(',').ok_or_else(broken)?;
+        if link(&chain, body) != link_value.trim() {
+            return Err(broken());
+        }
+        chain = link_value.trim().to_string();
+        let fields = split_fields(body).map_err(|_| broken())?;
+        match &fields[..] {
+            [_, operator, record, outcome, _, after] => records.push(AuditRecord {
+                line: number,
+                operator: operator.to_string(),
+                record: record.to_string(),
+                outcome: outcome.to_string(),
+                after: after.to_string(),
+            }),
+            _ => return Err(broken()),
+        }
+    }
+    Ok((records, chain))
+}
+
+/// Chain value of the record `line` following the one with chain value `previous`
+fn link(previous: &str, line: &str) -> String {
+    let mut data = Vec::with_capacity(previous.len() + 1 + line.len());
+    data.extend_from_slice(previous.as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(line.as_bytes());
+    hex(&sha256(&data))
+}
+
+fn outcome_name(outcome: Result<(), TransactionError>) -> String {
+    match outcome {
+        Ok(()) => "applied".to_string(),
+        Err(e) => format!("rejected {:?}", e),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{csv_parser::split_fields, currency::Currency};
+
+    #[test]
+    fn applied_and_rejected_transactions_are_audited() {
+        let mut table = ClientTable::new();
+        let mut log = AuditLog::new(Vec::new()).unwrap();
+        let deposit = Transaction::Deposit {
+            client: 42,
+            tx: 9001,
+            amount: Currency::new(100000),
+        };
+        let withdrawal = Transaction::Withdraw {
+            client: 42,
+            tx: 9002,
+            amount: Currency::new(200000),
+        };
+        assert_eq!(
+            table.apply_audited(deposit, "ops", &mut log).unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            table.apply_audited(withdrawal, "ops", &mut log).unwrap(),
+            Err(TransactionError::Overdraw)
+        );
+        let out = String::from_utf8(log.into_inner()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], HEADER);
+        assert_eq!(
+            split_fields(lines[1]).unwrap()[1..6],
+            [
+                "ops",
+                "deposit, 42, 9001, 10.0000",
+                "applied",
+                "0.0000, 0.0000, 0.0000, false",
+                "10.0000, 0.0000, 10.0000, false"
+            ]
+        );
+        assert_eq!(
+            split_fields(lines[2]).unwrap()[2..4],
+            ["withdrawal, 42, 9002, 20.0000", "rejected Overdraw"]
+        );
+    }
+
+    #[test]
+    fn deterministic_trails_are_reproducible() {
+        let deposit = |tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(10000),
+        };
+        let trail = || {
+            let mut table = ClientTable::new();
+            table.set_deterministic(true);
+            let mut log = AuditLog::new(Vec::new()).unwrap();
+            for tx in 1..=3 {
+                table
+                    .apply_audited(deposit(tx), "ops", &mut log)
+                    .unwrap()
+                    .unwrap();
+            }
+            String::from_utf8(log.into_inner()).unwrap()
+        };
+        let out = trail();
+        assert_eq!(out, trail());
+        let times: Vec<_> = out
+            .lines()
+            .skip(1)
+            .map(|l| split_fields(l).unwrap()[0].to_string())
+            .collect();
+        assert_eq!(times, ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn state_is_rebuilt_from_the_trail() {
+        let path = std::env::temp_dir().join(format!("bank_audit_{}.audit", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut table = ClientTable::new();
+        let transactions = [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(50000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(90000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+        ];
+        // Reopening the trail carries on its chain
+        for t in transactions.iter() {
+            let mut log = AuditLog::open(&path).unwrap();
+            table.apply_audited(*t, "ops", &mut log).unwrap().ok();
+        }
+        let mut rebuilt = ClientTable::new();
+        let summary = rebuilt.recover_from_audit(&path).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                applied: 2,
+                rejected: 1
+            }
+        );
+        assert_eq!(rebuilt.to_string(), table.to_string());
+
+        // A tampered record breaks the chain and leaves the table untouched
+        let trail = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, trail.replacen("5.0000", "9.0000", 1)).unwrap();
+        let mut table = ClientTable::new();
+        let error = table.recover_from_audit(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.to_string(), "I/O error: Audit chain broken on line 2");
+        assert_eq!(table.to_string(), ClientTable::new().to_string());
+    }
+}
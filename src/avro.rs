@@ -0,0 +1,968 @@
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
+};
+
+use serde_json::Value as Json;
+
+use crate::{
+    csv_parser::{self, FIELD_NAMES},
+    currency::CurrencyConfig,
+    transaction::Transaction,
+};
+
+/// First bytes of an Avro object container file
+pub const MAGIC: [u8; 4] = *b"Obj\x01";
+
+/// First byte of a message framed for a schema registry, the id of its schema follows
+pub const REGISTRY_MAGIC: u8 = 0;
+
+/// Length of the marker closing every block of a container file
+const SYNC_LEN: usize = 16;
+
+/// Schemas and values nest at most this deep
+const MAX_DEPTH: u32 = 32;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid avro input: {}", message),
+    )
+}
+
+/// Type of an Avro schema
+#[derive(Clone, Debug, PartialEq)]
+enum Type {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Fixed(usize),
+    Enum(Vec<String>),
+    Array(Box<Type>),
+    Map(Box<Type>),
+    Union(Vec<Type>),
+    Record(Vec<(String, Type)>),
+    /// Bytes or fixed holding a decimal with the given scale
+    Decimal(Box<Type>, u32),
+}
+
+impl Type {
+    /// Named types are looked up in `names` once defined, references to a type being defined are
+    /// not supported
+    fn from_json(
+        json: &Json,
+        names: &mut HashMap<String, Type>,
+        namespace: Option<&str>,
+        depth: u32,
+    ) -> io::Result<Self> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("schema nested too deeply"));
+        }
+        let attributes = match json {
+            Json::String(name) => return Self::named(name, names, namespace),
+            Json::Array(branches) => {
+                return branches
+                    .iter()
+                    .map(|branch| Self::from_json(branch, names, namespace, depth + 1))
+                    .collect::<io::Result<_>>()
+                    .map(Type::Union)
+            }
+            Json::Object(_) => json,
+            _ => return Err(invalid("invalid type in the schema")),
+        };
+        let name = match attributes.get("type") {
+            Some(Json::String(name)) => name.as_str(),
+            Some(nested) => return Self::from_json(nested, names, namespace, depth + 1),
+            None => return Err(invalid("type without a type in the schema")),
+        };
+        let scale = attributes.get("scale").and_then(Json::as_u64).unwrap_or(0);
+        let decimal = attributes.get("logicalType").and_then(Json::as_str) == Some("decimal");
+        let decimal = |t: Type| -> io::Result<Type> {
+            if !decimal {
+                return Ok(t);
+            }
+            let scale = u32::try_from(scale)
+                .ok()
+                .filter(|&s| s <= 38)
+                .ok_or_else(|| invalid("unsupported decimal scale"))?;
+            Ok(Type::Decimal(Box::new(t), scale))
+        };
+        let full_name = || -> io::Result<(String, Option<String>)> {
+            let name = attributes
+                .get("name")
+                .and_then(Json::as_str)
+                .ok_or_else(|| invalid("named type without a name"))?;
+            let namespace = match name.rsplit_once('.') {
+                Some((namespace, _)) => Some(namespace.to_string()),
+                None => attributes
+                    .get("namespace")
+                    .and_then(Json::as_str)
+                    .or(namespace)
+                    .map(str::to_string),
+            };
+            Ok((name.to_string(), namespace))
+        };
+        let t = match name {
+            "record" | "error" => {
+                let (name, namespace) = full_name()?;
+                let fields = attributes
+                    .get("fields")
+                    .and_then(|fields| match fields {
+                        Json::Array(fields) => Some(fields),
+                        _ => None,
+                    })
+                    .ok_or_else(|| invalid("record without fields"))?;
+                let fields = fields
+                    .iter()
+                    .map(|field| {
+                        let name = field
+                            .get("name")
+                            .and_then(Json::as_str)
+                            .ok_or_else(|| invalid("record field without a name"))?;
+                        let t = field
+                            .get("type")
+                            .ok_or_else(|| invalid("record field without a type"))?;
+                        let t = Self::from_json(t, names, namespace.as_deref(), depth + 1)?;
+                        Ok((name.to_string(), t))
+                    })
+                    .collect::<io::Result<_>>()?;
+                Self::define(names, name, namespace, Type::Record(fields))
+            }
+            "enum" => {
+                let (name, namespace) = full_name()?;
+                let symbols = match attributes.get("symbols") {
+                    Some(Json::Array(symbols)) => symbols
+                        .iter()
+                        .map(|s| s.as_str().map(str::to_string))
+                        .collect::<Option<_>>(),
+                    _ => None,
+                };
+                let symbols = symbols.ok_or_else(|| invalid("enum without symbols"))?;
+                Self::define(names, name, namespace, Type::Enum(symbols))
+            }
+            "fixed" => {
+                let (name, namespace) = full_name()?;
+                let size = attributes
+                    .get("size")
+                    .and_then(Json::as_u64)
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or_else(|| invalid("fixed without a size"))?;
+                Self::define(names, name, namespace, decimal(Type::Fixed(size))?)
+            }
+            "array" => {
+                let items = attributes
+                    .get("items")
+                    .ok_or_else(|| invalid("array without items"))?;
+                Type::Array(Box::new(Self::from_json(
+                    items,
+                    names,
+                    namespace,
+                    depth + 1,
+                )?))
+            }
+            "map" => {
+                let values = attributes
+                    .get("values")
+                    .ok_or_else(|| invalid("map without values"))?;
+                Type::Map(Box::new(Self::from_json(
+                    values,
+                    names,
+                    namespace,
+                    depth + 1,
+                )?))
+            }
+            "bytes" => decimal(Type::Bytes)?,
+            name => Self::named(name, names, namespace)?,
+        };
+        Ok(t)
+    }
+
+    /// Registers a named type under its full and short names
+    fn define(
+        names: &mut HashMap<String, Type>,
+        name: String,
+        namespace: Option<String>,
+        t: Type,
+    ) -> Type {
+        let short = name.rsplit('.').next().unwrap_or(&name).to_string();
+        if let Some(namespace) = namespace.filter(|_| !name.contains('.')) {
+            names.insert(format!("{}.{}", namespace, name), t.clone());
+        }
+        names.insert(short, t.clone());
+        names.insert(name, t.clone());
+        t
+    }
+
+    /// Primitive type or reference to a named type
+    fn named(
+        name: &str,
+        names: &HashMap<String, Type>,
+        namespace: Option<&str>,
+    ) -> io::Result<Self> {
+        Ok(match name {
+            "null" => Type::Null,
+            "boolean" => Type::Boolean,
+            "int" => Type::Int,
+            "long" => Type::Long,
+            "float" => Type::Float,
+            "double" => Type::Double,
+            "bytes" => Type::Bytes,
+            "string" => Type::String,
+            name => {
+                let qualified = namespace.map(|namespace| format!("{}.{}", namespace, name));
+                qualified
+                    .and_then(|qualified| names.get(&qualified))
+                    .or_else(|| names.get(name))
+                    .cloned()
+                    .ok_or_else(|| invalid(&format!("unknown type {}", name)))?
+            }
+        })
+    }
+}
+
+/// Schema of transaction records
+///
+/// The root is a record whose fields are looked up by name, the same names as the keys of the
+/// JSON input: `type`, `client` and `tx` are required, `amount`, `currency`, `value_date` and
+/// `to` are optional. Any other field is skipped. Fields may be strings, enums, numbers or
+/// decimals, optionally in a union with null
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    fields: Vec<(String, Type)>,
+    /// Position of each of `FIELD_NAMES` among the fields
+    positions: [Option<usize>; 7],
+}
+
+impl Schema {
+    /// Reads the JSON definition of the schema
+    pub fn parse(json: &str) -> io::Result<Self> {
+        let json: Json = serde_json::from_str(json)
+            .map_err(|e| invalid(&format!("the schema is not JSON: {}", e)))?;
+        let fields = match Type::from_json(&json, &mut HashMap::new(), None, 0)? {
+            Type::Record(fields) => fields,
+            _ => return Err(invalid("the schema is not a record")),
+        };
+        let positions = FIELD_NAMES.map(|name| fields.iter().position(|(n, _)| n == name));
+        for (name, position) in FIELD_NAMES.iter().zip(&positions).take(3) {
+            if position.is_none() {
+                return Err(invalid(&format!("the schema has no {} field", name)));
+            }
+        }
+        Ok(Schema { fields, positions })
+    }
+
+    /// Decodes a record written with this schema
+    fn transaction(
+        &self,
+        data: &mut Decoder<'_>,
+        currency: CurrencyConfig,
+    ) -> io::Result<Result<Transaction, csv_parser::ParseCSVError>> {
+        let mut values = Vec::with_capacity(self.fields.len());
+        for (_, t) in &self.fields {
+            values.push(data.value(t, 0)?);
+        }
+        let mut text: [Option<String>; 7] = Default::default();
+        for (text, position) in text.iter_mut().zip(&self.positions) {
+            if let Some(i) = position {
+                *text = values[*i].text(&self.fields[*i].0)?;
+            }
+        }
+        Ok(csv_parser::parse_named(
+            [0, 1, 2, 3, 4, 5, 6].map(|i| text[i].as_deref()),
+            currency,
+        ))
+    }
+}
+
+/// Value of a record field, only scalars are kept
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Bytes(Vec<u8>),
+    String(String),
+    Decimal(i128, u32),
+    /// Arrays, maps and records
+    Compound,
+}
+
+impl Value {
+    /// The value as the text parsers expect it
+    fn text(&self, field: &str) -> io::Result<Option<String>> {
+        Ok(Some(match self {
+            Value::Null => return Ok(None),
+            Value::Bool(b) => b.to_string(),
+            Value::Long(n) => n.to_string(),
+            Value::Float(x) => x.to_string(),
+            Value::Double(x) => x.to_string(),
+            Value::Bytes(bytes) => String::from_utf8(bytes.clone())
+                .map_err(|_| invalid(&format!("field {} is not utf-8", field)))?,
+            Value::String(s) => s.clone(),
+            Value::Decimal(units, scale) => csv_parser::decimal_text(*units, *scale),
+            Value::Compound => return Err(invalid(&format!("field {} is not a scalar", field))),
+        }))
+    }
+}
+
+/// Reads values of the binary encoding from the front of a buffer
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if n > self.buf.len() {
+            return Err(invalid("unexpected end of a record"));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn long(&mut self) -> io::Result<i64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok((n >> 1) as i64 ^ -((n & 1) as i64));
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    /// Length of bytes, strings and blocks
+    fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.long()?).map_err(|_| invalid("negative length"))
+    }
+
+    fn value(&mut self, t: &Type, depth: u32) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("value nested too deeply"));
+        }
+        Ok(match t {
+            Type::Null => Value::Null,
+            Type::Boolean => Value::Bool(self.take(1)?[0] != 0),
+            Type::Int | Type::Long => Value::Long(self.long()?),
+            Type::Float => Value::Float(f32::from_le_bytes(
+                self.take(4)?.try_into().expect("4 bytes"),
+            )),
+            Type::Double => Value::Double(f64::from_le_bytes(
+                self.take(8)?.try_into().expect("8 bytes"),
+            )),
+            Type::Bytes => {
+                let len = self.len()?;
+                Value::Bytes(self.take(len)?.to_vec())
+            }
+            Type::String => {
+                let len = self.len()?;
+                let s = std::str::from_utf8(self.take(len)?)
+                    .map_err(|_| invalid("string is not utf-8"))?;
+                Value::String(s.to_string())
+            }
+            Type::Fixed(size) => Value::Bytes(self.take(*size)?.to_vec()),
+            Type::Enum(symbols) => {
+                let symbol = usize::try_from(self.long()?)
+                    .ok()
+                    .and_then(|i| symbols.get(i))
+                    .ok_or_else(|| invalid("enum index out of range"))?;
+                Value::String(symbol.clone())
+            }
+            Type::Union(branches) => {
+                let branch = usize::try_from(self.long()?)
+                    .ok()
+                    .and_then(|i| branches.get(i))
+                    .ok_or_else(|| invalid("union branch out of range"))?;
+                self.value(branch, depth + 1)?
+            }
+            Type::Decimal(inner, scale) => {
+                let bytes = match self.value(inner, depth + 1)? {
+                    Value::Bytes(bytes) => bytes,
+                    _ => return Err(invalid("decimal that isn't bytes")),
+                };
+                if bytes.len() > 16 {
+                    return Err(invalid("decimal wider than 128 bits"));
+                }
+                // Big endian two's complement, sign extended from the first byte
+                let fill = if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+                    -1
+                } else {
+                    0
+                };
+                let units = bytes
+                    .iter()
+                    .fold(fill, |n: i128, &b| n << 8 | i128::from(b));
+                Value::Decimal(units, *scale)
+            }
+            Type::Array(items) => {
+                self.blocks(|d| d.value(items, depth + 1).map(drop))?;
+                Value::Compound
+            }
+            Type::Map(values) => {
+                self.blocks(|d| {
+                    d.value(&Type::String, depth + 1)?;
+                    d.value(values, depth + 1).map(drop)
+                })?;
+                Value::Compound
+            }
+            Type::Record(fields) => {
+                for (_, t) in fields {
+                    self.value(t, depth + 1)?;
+                }
+                Value::Compound
+            }
+        })
+    }
+
+    /// Reads the items of an array or map, written in blocks each starting with its count. A
+    /// negative count is followed by the size of the block
+    fn blocks(&mut self, mut item: impl FnMut(&mut Self) -> io::Result<()>) -> io::Result<()> {
+        loop {
+            let count = match self.long()? {
+                0 => return Ok(()),
+                count if count < 0 => {
+                    self.long()?;
+                    count.unsigned_abs()
+                }
+                count => count as u64,
+            };
+            // Keeps items that take no space from looping for ever
+            if count > self.buf.len() as u64 {
+                return Err(invalid("block with more items than bytes"));
+            }
+            for _ in 0..count {
+                item(self)?;
+            }
+        }
+    }
+}
+
+/// Reads a long of the binary encoding from a stream, `None` at its end
+fn read_long<R: Read>(input: &mut R) -> io::Result<Option<i64>> {
+    let mut n: u64 = 0;
+    for (i, shift) in (0..64).step_by(7).enumerate() {
+        let mut b = [0];
+        if input.read(&mut b)? == 0 {
+            return match i {
+                0 => Ok(None),
+                _ => Err(invalid("unexpected end of the file")),
+            };
+        }
+        n |= u64::from(b[0] & 0x7f) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(Some((n >> 1) as i64 ^ -((n & 1) as i64)));
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+/// Reads `len` bytes from a stream, which may announce more than it holds
+fn read_exact<R: Read>(input: &mut R, len: i64) -> io::Result<Vec<u8>> {
+    let len = u64::try_from(len).map_err(|_| invalid("negative length"))?;
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(invalid("unexpected end of the file"));
+    }
+    Ok(bytes)
+}
+
+/// CRC-32 closing every Snappy compressed block
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+/// Compression of the blocks of a container file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Codec {
+    Null,
+    Deflate,
+    /// Snappy followed by the CRC-32 of the uncompressed block
+    Snappy,
+}
+
+/// Transactions of an Avro object container file, whose header embeds the schema of its records
+///
+/// Blocks may be uncompressed or compressed with deflate or Snappy. A record that doesn't make a
+/// valid transaction is an error naming its position in the file
+pub struct Reader<R> {
+    input: R,
+    schema: Schema,
+    codec: Codec,
+    sync: [u8; SYNC_LEN],
+    currency: CurrencyConfig,
+    /// Block being read and the number of records left in it
+    block: Vec<u8>,
+    pos: usize,
+    left: u64,
+    /// Records read so far
+    read: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut input: R, currency: CurrencyConfig) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid("not an avro container file"));
+        }
+        let mut metadata = HashMap::new();
+        loop {
+            let count = match read_long(&mut input)? {
+                Some(0) => break,
+                Some(count) if count < 0 => {
+                    read_long(&mut input)?;
+                    count.unsigned_abs()
+                }
+                Some(count) => count as u64,
+                None => return Err(invalid("unexpected end of the header")),
+            };
+            for _ in 0..count {
+                let len = read_long(&mut input)?.unwrap_or(-1);
+                let key = String::from_utf8(read_exact(&mut input, len)?)
+                    .map_err(|_| invalid("metadata key is not utf-8"))?;
+                let len = read_long(&mut input)?.unwrap_or(-1);
+                metadata.insert(key, read_exact(&mut input, len)?);
+            }
+        }
+        let mut sync = [0; SYNC_LEN];
+        input.read_exact(&mut sync)?;
+        let schema = metadata
+            .get("avro.schema")
+            .ok_or_else(|| invalid("the header has no schema"))?;
+        let schema = Schema::parse(
+            std::str::from_utf8(schema).map_err(|_| invalid("the schema is not utf-8"))?,
+        )?;
+        let codec = match metadata.get("avro.codec").map(Vec::as_slice) {
+            None | Some(b"null") => Codec::Null,
+            Some(b"deflate") => Codec::Deflate,
+            Some(b"snappy") => Codec::Snappy,
+            Some(codec) => {
+                return Err(invalid(&format!(
+                    "unsupported codec {}",
+                    String::from_utf8_lossy(codec)
+                )))
+            }
+        };
+        Ok(Self {
+            input,
+            schema,
+            codec,
+            sync,
+            currency,
+            block: Vec::new(),
+            pos: 0,
+            left: 0,
+            read: 0,
+            done: false,
+        })
+    }
+
+    /// Reads the next block, `false` at the end of the file
+    fn next_block(&mut self) -> io::Result<bool> {
+        if self.pos != self.block.len() {
+            return Err(invalid("block longer than its records"));
+        }
+        let count = match read_long(&mut self.input)? {
+            Some(count) => u64::try_from(count).map_err(|_| invalid("negative count"))?,
+            None => return Ok(false),
+        };
+        let size = read_long(&mut self.input)?.unwrap_or(-1);
+        let data = read_exact(&mut self.input, size)?;
+        let mut sync = [0; SYNC_LEN];
+        self.input.read_exact(&mut sync)?;
+        if sync != self.sync {
+            return Err(invalid("block not closed by the sync marker"));
+        }
+        self.block = match self.codec {
+            Codec::Null => data,
+            Codec::Deflate => {
+                let mut block = Vec::new();
+                flate2::read::DeflateDecoder::new(data.as_slice()).read_to_end(&mut block)?;
+                block
+            }
+            Codec::Snappy => {
+                let split = data
+                    .len()
+                    .checked_sub(4)
+                    .ok_or_else(|| invalid("snappy block without a checksum"))?;
+                let block = snap::raw::Decoder::new()
+                    .decompress_vec(&data[..split])
+                    .map_err(|e| invalid(&e.to_string()))?;
+                if crc32(&block).to_be_bytes() != data[split..] {
+                    return Err(invalid("checksum mismatch"));
+                }
+                block
+            }
+        };
+        self.pos = 0;
+        self.left = count;
+        Ok(true)
+    }
+
+    fn record(&mut self) -> io::Result<Transaction> {
+        let mut data = Decoder {
+            buf: &self.block[self.pos..],
+        };
+        let transaction = self.schema.transaction(&mut data, self.currency)?;
+        self.pos = self.block.len() - data.buf.len();
+        self.left -= 1;
+        self.read += 1;
+        transaction.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record {}: {:?}", self.read, e),
+            )
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.left == 0 {
+            match self.done {
+                true => return None,
+                false => match self.next_block() {
+                    Ok(true) => {}
+                    Ok(false) => self.done = true,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                },
+            }
+        }
+        let record = self.record();
+        // Nothing past a record that can't be decoded can be trusted
+        if record
+            .as_ref()
+            .is_err_and(|e| e.kind() == io::ErrorKind::InvalidData)
+        {
+            self.left = 0;
+            self.done = true;
+        }
+        Some(record)
+    }
+}
+
+/// Source of the schemas of messages framed for a schema registry, by id
+pub trait SchemaRegistry {
+    /// JSON definition of the schema with id `id`
+    fn schema(&mut self, id: u32) -> io::Result<String>;
+}
+
+/// Schemas known upfront
+impl SchemaRegistry for HashMap<u32, String> {
+    fn schema(&mut self, id: u32) -> io::Result<String> {
+        self.get(&id).cloned().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no schema with id {}", id))
+        })
+    }
+}
+
+/// Schema registry with the REST interface of the Confluent one, reached over plain HTTP
+pub struct HttpRegistry {
+    addr: String,
+}
+
+impl HttpRegistry {
+    /// `addr` is the `host:port` of the registry
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into() }
+    }
+}
+
+impl SchemaRegistry for HttpRegistry {
+    fn schema(&mut self, id: u32) -> io::Result<String> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        let request = format!(
+            "GET /schemas/ids/{} HTTP/1.1\r\nHost: {}\r\nAccept: application/vnd.schemaregistry.v1+json\r\nConnection: close\r\n\r\n",
+            id, self.addr
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head.lines().next().unwrap_or_default();
+        if !status.starts_with("HTTP/1.1 200") {
+            return Err(io::Error::other(format!(
+                "schema registry answered {}: {}",
+                status,
+                body.trim()
+            )));
+        }
+        let chunked = head
+            .to_ascii_lowercase()
+            .contains("transfer-encoding: chunked");
+        let body = if chunked {
+            dechunk(body)?
+        } else {
+            body.to_string()
+        };
+        serde_json::from_str::<Json>(&body)
+            .map_err(|e| invalid(&format!("the schema registry answered with {}", e)))?
+            .get("schema")
+            .and_then(Json::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| invalid("the schema registry answered without a schema"))
+    }
+}
+
+/// Body of a response sent in chunks
+fn dechunk(mut body: &str) -> io::Result<String> {
+    let mut out = String::new();
+    loop {
+        let (size, rest) = body
+            .split_once("\r\n")
+            .ok_or_else(|| invalid("truncated chunked response"))?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = rest
+            .get(..size)
+            .ok_or_else(|| invalid("truncated chunked response"))?;
+        out.push_str(chunk);
+        body = rest[size..].trim_start_matches("\r\n");
+    }
+}
+
+/// Decoder of messages framed for a schema registry, such as the payloads of a Kafka topic: a zero
+/// byte, the id of the schema as a big endian u32, then the record. Schemas are fetched on first
+/// use and kept, a `TransactionSource` adapting a consumer decodes every message with it
+pub struct MessageDecoder<S> {
+    registry: S,
+    schemas: HashMap<u32, Schema>,
+    currency: CurrencyConfig,
+}
+
+impl<S: SchemaRegistry> MessageDecoder<S> {
+    pub fn new(registry: S, currency: CurrencyConfig) -> Self {
+        Self {
+            registry,
+            schemas: HashMap::new(),
+            currency,
+        }
+    }
+
+    pub fn decode(&mut self, message: &[u8]) -> io::Result<Transaction> {
+        let (id, record) = match message {
+            [REGISTRY_MAGIC, a, b, c, d, record @ ..] => {
+                (u32::from_be_bytes([*a, *b, *c, *d]), record)
+            }
+            _ => return Err(invalid("message not framed for a schema registry")),
+        };
+        if !self.schemas.contains_key(&id) {
+            let schema = Schema::parse(&self.registry.schema(id)?)?;
+            self.schemas.insert(id, schema);
+        }
+        let mut data = Decoder { buf: record };
+        let transaction = self.schemas[&id].transaction(&mut data, self.currency)?;
+        if !data.buf.is_empty() {
+            return Err(invalid("message longer than its record"));
+        }
+        Ok(transaction?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+    use std::{net::TcpListener, thread};
+
+    const SCHEMA: &str = r#"{
+        "type": "record", "name": "Transaction", "namespace": "bank",
+        "fields": [
+            {"name": "type", "type": {"type": "enum", "name": "Kind", "symbols": ["deposit", "withdrawal", "dispute"]}},
+            {"name": "client", "type": "int"},
+            {"name": "tx", "type": "long"},
+            {"name": "tags", "type": {"type": "array", "items": "string"}},
+            {"name": "amount", "type": ["null", {"type": "bytes", "logicalType": "decimal", "precision": 18, "scale": 2}]},
+            {"name": "origin", "type": ["null", "bank.Kind"]}
+        ]
+    }"#;
+
+    fn long(out: &mut Vec<u8>, n: i64) {
+        let mut n = ((n << 1) ^ (n >> 63)) as u64;
+        while n >= 0x80 {
+            out.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    fn bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+        long(out, bytes.len() as i64);
+        out.extend_from_slice(bytes);
+    }
+
+    /// A record of `SCHEMA`
+    fn record(kind: i64, client: i64, tx: i64, amount: Option<i16>) -> Vec<u8> {
+        let mut out = Vec::new();
+        long(&mut out, kind);
+        long(&mut out, client);
+        long(&mut out, tx);
+        // Two tags in a block with its size, then the end of the array
+        long(&mut out, -2);
+        long(&mut out, 4);
+        bytes(&mut out, b"a");
+        bytes(&mut out, b"b");
+        long(&mut out, 0);
+        match amount {
+            Some(amount) => {
+                long(&mut out, 1);
+                bytes(&mut out, &amount.to_be_bytes());
+            }
+            None => long(&mut out, 0),
+        }
+        long(&mut out, 0);
+        out
+    }
+
+    fn records() -> Vec<u8> {
+        [
+            record(0, 1, 1, Some(150)),
+            record(1, 1, 2, Some(25)),
+            record(2, 1, 1, None),
+        ]
+        .concat()
+    }
+
+    fn container(codec: &str, block: &[u8]) -> Vec<u8> {
+        let sync = [7; SYNC_LEN];
+        let mut out = MAGIC.to_vec();
+        long(&mut out, 2);
+        bytes(&mut out, b"avro.schema");
+        bytes(&mut out, SCHEMA.as_bytes());
+        bytes(&mut out, b"avro.codec");
+        bytes(&mut out, codec.as_bytes());
+        long(&mut out, 0);
+        out.extend(sync);
+        long(&mut out, 3);
+        bytes(&mut out, block);
+        out.extend(sync);
+        out
+    }
+
+    fn expected() -> Vec<Transaction> {
+        vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(15000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(2500),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+        ]
+    }
+
+    fn read(file: &[u8]) -> io::Result<Vec<Transaction>> {
+        Reader::new(file, CurrencyConfig::default())?.collect()
+    }
+
+    #[test]
+    fn reads_container_files() {
+        assert_eq!(read(&container("null", &records())).unwrap(), expected());
+
+        // A single stored deflate block
+        let data = records();
+        let mut deflate = vec![1];
+        deflate.extend((data.len() as u16).to_le_bytes());
+        deflate.extend((!(data.len() as u16)).to_le_bytes());
+        deflate.extend(&data);
+        assert_eq!(read(&container("deflate", &deflate)).unwrap(), expected());
+
+        // The length as a one byte varint, a single literal and the checksum
+        assert!(data.len() < 0x80);
+        let mut snappy = vec![data.len() as u8, 60 << 2, data.len() as u8 - 1];
+        snappy.extend(&data);
+        snappy.extend(crc32(&data).to_be_bytes());
+        assert_eq!(read(&container("snappy", &snappy)).unwrap(), expected());
+        let last = snappy.len() - 1;
+        snappy[last] ^= 1;
+        assert!(read(&container("snappy", &snappy)).is_err());
+
+        let mut truncated = container("null", &records());
+        truncated.truncate(truncated.len() - 1);
+        assert!(read(&truncated).is_err());
+        assert!(read(&container("zstandard", &records())).is_err());
+    }
+
+    #[test]
+    fn decodes_registry_messages() {
+        let mut registry = HashMap::new();
+        registry.insert(7, SCHEMA.to_string());
+        let mut decoder = MessageDecoder::new(registry, CurrencyConfig::default());
+        let frame =
+            |id: u32, record: Vec<u8>| [vec![0], id.to_be_bytes().to_vec(), record].concat();
+        assert_eq!(
+            decoder
+                .decode(&frame(7, record(0, 1, 1, Some(150))))
+                .unwrap(),
+            expected()[0]
+        );
+        assert_eq!(
+            decoder.decode(&frame(7, record(2, 1, 1, None))).unwrap(),
+            expected()[2]
+        );
+        assert!(decoder.decode(&frame(8, record(2, 1, 1, None))).is_err());
+        assert!(decoder.decode(&record(2, 1, 1, None)).is_err());
+        // A negative amount is refused like in any other format
+        let refused = decoder.decode(&frame(7, record(0, 1, 3, Some(-150))));
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // The registry wraps the schema in a JSON string
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            for line in io::BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            assert!(request.starts_with("GET /schemas/ids/7 HTTP/1.1"));
+            let body = format!(
+                "{{\"schema\":\"{}\"}}",
+                SCHEMA.replace('"', "\\\"").replace('\n', "\\n")
+            );
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        let schema = HttpRegistry::new(addr.to_string()).schema(7).unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            Schema::parse(&schema).unwrap(),
+            Schema::parse(SCHEMA).unwrap()
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashSet},
+    error::Error,
+    fmt,
+};
+
+use crate::{
+    approvals::PendingApprovals,
+    client_info::{ClientInfo, TransactionError},
+    currency::CurrencyCode,
+    events::EngineWarning,
+    general_ledger::GeneralLedger,
+    payment_engine::ClientTable,
+    risk::Activity,
+    transaction::{ClientId, Transaction, TxId},
+};
+
+/// Why a batch was not applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchError {
+    /// Position in the batch of the first record rejected, `None` when the batch as a whole is
+    /// refused, as happens while another batch is open
+    pub index: Option<usize>,
+    pub reason: TransactionError,
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(
+                f,
+                "record {} of the batch was rejected: {:?}",
+                index, self.reason
+            ),
+            None => write!(f, "the batch was refused: {:?}", self.reason),
+        }
+    }
+}
+
+impl Error for BatchError {}
+
+impl From<TransactionError> for BatchError {
+    fn from(reason: TransactionError) -> Self {
+        Self {
+            index: None,
+            reason,
+        }
+    }
+}
+
+/// Everything the records of a batch can change, saved before the batch is applied so a rejected
+/// record takes the whole batch back. The time driven changes, bookings reaching their value date,
+/// scheduled postings and expired approvals, are done before saving and stay when rolling back
+struct Savepoint {
+    clients: BTreeSet<ClientId>,
+    accounts: Vec<(ClientId, ClientInfo)>,
+    foreign: Vec<((ClientId, CurrencyCode), ClientInfo)>,
+    /// Only saved when a chargeback fee could be charged to it or fees collected in it
+    house: Option<ClientInfo>,
+    general_ledger: Option<GeneralLedger>,
+    /// Ids the batch could add to the index
+    new_ids: HashSet<TxId>,
+    pending: PendingApprovals,
+    activity: Vec<(ClientId, Option<Activity>)>,
+    warnings: usize,
+}
+
+impl Savepoint {
+    fn take(table: &ClientTable, records: &[Transaction]) -> Self {
+        // Disputes and the like can be routed to the owner of their transaction
+        let clients: BTreeSet<_> = records
+            .iter()
+            .flat_map(|r| {
+                Some(r.client())
+                    .into_iter()
+                    .chain(table.tx_index.get(r.tx()))
+            })
+            .collect();
+        Self {
+            accounts: clients
+                .iter()
+                .map(|&c| (c, table.clients[c].clone()))
+                .collect(),
+            foreign: table
+                .foreign
+                .iter()
+                .filter(|((c, _), _)| clients.contains(c))
+                .map(|(&key, info)| (key, info.clone()))
+                .collect(),
+            house: (!table.fees().is_empty()
+                || records
+                    .iter()
+                    .any(|r| matches!(r, Transaction::Chargeback { .. })))
+            .then(|| table.house.clone()),
+            general_ledger: table.general_ledger.clone(),
+            new_ids: records
+                .iter()
+                .map(Transaction::tx)
+                .filter(|&tx| !table.tx_index.contains(tx))
+                .collect(),
+            pending: table.pending.clone(),
+            activity: clients
+                .iter()
+                .map(|&c| (c, table.activity.get(&c).cloned()))
+                .collect(),
+            warnings: table.warnings.len(),
+            clients,
+        }
+    }
+
+    fn restore(self, table: &mut ClientTable) {
+        for (client, info) in self.accounts {
+            table.clients[client] = info;
+        }
+        let clients = &self.clients;
+        table.foreign.retain(|(c, _), _| !clients.contains(c));
+        table.foreign.extend(self.foreign);
+        if let Some(house) = self.house {
+            table.house = house;
+        }
+        table.general_ledger = self.general_ledger;
+        for &tx in &self.new_ids {
+            table.tx_index.remove(tx);
+            table.tx_codes.remove(&tx);
+        }
+        let new_ids = &self.new_ids;
+        table
+            .schedule
+            .retain(|Reverse((_, _, tx))| !new_ids.contains(tx));
+        table
+            .clearing
+            .retain(|Reverse((_, _, tx))| !new_ids.contains(tx));
+        table.pending = self.pending;
+        for (client, activity) in self.activity {
+            match activity {
+                Some(activity) => table.activity.insert(client, activity),
+                None => table.activity.remove(&client),
+            };
+        }
+        table.warnings.truncate(self.warnings);
+    }
+}
+
+impl ClientTable {
+    /// Applies `records` as batch `id`: either every record is applied or, when one is rejected,
+    /// none is and the position and error of the rejected one are returned, for settlement files
+    /// that must be applied as a whole. The batch is logged as `begin`, the records and `commit`,
+    /// so a write-ahead log replays it the same way
+    ///
+    /// The records of a batch are applied together once it commits, with the clock advanced past
+    /// all of them. Accruals can't be part of a batch as they concern every account
+    pub fn apply_batch(&mut self, id: TxId, records: &[Transaction]) -> Result<(), BatchError> {
+        self.handle_transaction(Transaction::Begin { client: 0, tx: id })?;
+        for (index, &record) in records.iter().enumerate() {
+            if let Err(reason) = self.handle_transaction(record) {
+                self.handle_transaction(Transaction::Rollback { client: 0, tx: id })?;
+                return Err(BatchError {
+                    index: Some(index),
+                    reason,
+                });
+            }
+        }
+        self.log(&Transaction::Commit { client: 0, tx: id })?;
+        let outcome = self.commit(id);
+        self.stats
+            .count(&Transaction::Commit { client: 0, tx: id }, outcome.is_ok());
+        outcome
+    }
+
+    /// Handles the `begin`, `commit` and `rollback` records and collects the records of the open
+    /// batch, `None` for the records outside of a batch
+    pub(crate) fn handle_batch_record(
+        &mut self,
+        tx: Transaction,
+    ) -> Option<Result<(), TransactionError>> {
+        let outcome = match tx {
+            Transaction::Begin { tx: id, .. } => self.log(&tx).and_then(|()| self.begin(id)),
+            Transaction::Commit { tx: id, .. } => self
+                .log(&tx)
+                .and_then(|()| self.commit(id).map_err(|e| e.reason)),
+            Transaction::Rollback { tx: id, .. } => self
+                .log(&tx)
+                .and_then(|()| self.take_batch(id))
+                .map(|records| {
+                    for record in &records {
+                        self.stats.count(record, false);
+                    }
+                }),
+            _ if self.batch.is_some() => {
+                let collected = self.collect(tx);
+                if collected.is_err() {
+                    self.stats.count(&tx, false);
+                }
+                return Some(collected);
+            }
+            _ => return None,
+        };
+        self.stats.count(&tx, outcome.is_ok());
+        Some(outcome)
+    }
+
+    fn begin(&mut self, id: TxId) -> Result<(), TransactionError> {
+        if self.batch.is_some() {
+            return Err(TransactionError::InvalidBatch);
+        }
+        self.batch = Some((id, Vec::new()));
+        Ok(())
+    }
+
+    fn collect(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        if let Transaction::Accrue { .. } = tx {
+            return Err(TransactionError::InvalidBatch);
+        }
+        self.log(&tx)?;
+        if let Some((_, records)) = &mut self.batch {
+            records.push(tx);
+        }
+        Ok(())
+    }
+
+    /// Closes the open batch if it is batch `id`
+    fn take_batch(&mut self, id: TxId) -> Result<Vec<Transaction>, TransactionError> {
+        match self.batch.take() {
+            Some((open, records)) if open == id => Ok(records),
+            open => {
+                self.batch = open;
+                Err(TransactionError::InvalidBatch)
+            }
+        }
+    }
+
+    fn commit(&mut self, id: TxId) -> Result<(), BatchError> {
+        let records = self.take_batch(id)?;
+        for _ in &records {
+            self.tick();
+        }
+        let savepoint = Savepoint::take(self, &records);
+        let mut seen = Vec::with_capacity(records.len());
+        let mut grown = Vec::with_capacity(records.len());
+        for (index, &record) in records.iter().enumerate() {
+            if self.dedup.check(&record) {
+                self.stats.duplicates += 1;
+                continue;
+            }
+            seen.push(record);
+            if !self.needs_approval(&record) {
+                grown.push(record.client());
+            }
+            if let Err(error) = self.handle_now(record) {
+                savepoint.restore(self);
+                for record in &seen {
+                    self.dedup.forget(record);
+                    self.stats.count(record, false);
+                }
+                self.warnings.push(EngineWarning::BatchRolledBack {
+                    batch: id,
+                    failed: record,
+                    error,
+                });
+                return Err(BatchError {
+                    index: Some(index),
+                    reason: error,
+                });
+            }
+        }
+        for record in &seen {
+            self.stats.count(record, true);
+        }
+        self.grow_history(grown);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::test_fixtures::{deposit, withdraw};
+
+    #[test]
+    fn batches_apply_all_or_nothing() {
+        let mut table = ClientTable::new();
+        table.handle_transaction(deposit(1, 1, 50000)).unwrap();
+        let before = table.to_string();
+
+        // The deposit leg goes through but the withdrawal overdraws, so neither is kept
+        let transfer = [deposit(2, 2, 90000), withdraw(1, 3, 90000)];
+        assert_eq!(
+            table.apply_batch(10, &transfer),
+            Err(BatchError {
+                index: Some(1),
+                reason: TransactionError::Overdraw
+            })
+        );
+        assert_eq!(table.to_string(), before);
+        assert!(!table.tx_index().contains(2));
+        assert!(matches!(
+            table.take_warnings()[..],
+            [EngineWarning::BatchRolledBack { batch: 10, .. }]
+        ));
+
+        let transfer = [withdraw(1, 2, 20000), deposit(2, 3, 20000)];
+        table.apply_batch(11, &transfer).unwrap();
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n1, 3.0000, 0.0000, 3.0000, false\n2, 2.0000, 0.0000, 2.0000, false\n"
+        );
+    }
+
+    #[test]
+    fn batch_records_are_collected_until_commit() {
+        let mut table = ClientTable::new();
+        let records = [
+            Transaction::Begin { client: 0, tx: 7 },
+            deposit(1, 1, 10000),
+            Transaction::Begin { client: 0, tx: 8 },
+            Transaction::Commit { client: 0, tx: 8 },
+            Transaction::Accrue { client: 0, tx: 1 },
+        ];
+        let outcomes: Vec<_> = table
+            .stream(records.iter().copied())
+            .map(|e| e.outcome)
+            .collect();
+        let invalid = Err(TransactionError::InvalidBatch);
+        assert_eq!(outcomes, [Ok(()), Ok(()), invalid, invalid, invalid]);
+        assert!(!table.clients[1].exists());
+        table
+            .handle_transaction(Transaction::Commit { client: 0, tx: 7 })
+            .unwrap();
+        assert_eq!(table.clients[1].available_funds(), Currency::new(10000));
+
+        table
+            .handle_transaction(Transaction::Begin { client: 0, tx: 9 })
+            .unwrap();
+        table.handle_transaction(deposit(1, 2, 10000)).unwrap();
+        table
+            .handle_transaction(Transaction::Rollback { client: 0, tx: 9 })
+            .unwrap();
+        assert!(!table.tx_index().contains(2));
+        assert_eq!(table.stats().processed, 9);
+        assert_eq!(table.stats().rejected, 4);
+    }
+}
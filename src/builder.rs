@@ -0,0 +1,358 @@
+use std::{error::Error, fmt};
+
+#[cfg(feature = "std-io")]
+use crate::archive::Archive;
+use crate::{
+    credit::CreditLines,
+    currency::{Currency, CurrencyCode, CurrencyConfig, MAX_DECIMALS},
+    fees::FeeSchedule,
+    fx::RateTable,
+    hierarchy::Hierarchy,
+    payment_engine::ClientTable,
+    policy::{DedupPolicy, DisputePolicy, OverdrawPolicy, Policy},
+    risk::RiskLimits,
+    schedules::Schedules,
+    storage::ClientStorage,
+};
+
+/// A configuration `ClientTableBuilder::build` refuses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// An amount of the policy is negative, `setting` names which one
+    NegativeAmount { setting: &'static str },
+    /// `DedupPolicy::Window(0)` would remember no record at all
+    EmptyDedupWindow,
+    /// A memory budget of zero bytes would trim the history on every transaction
+    EmptyMemoryBudget,
+    /// Pending approvals would expire before the next record could approve them
+    ImmediateApprovalTimeout,
+    /// Approvals would release pending transactions without any sign-off
+    NoApprovers,
+    /// More decimals than `MAX_DECIMALS`
+    TooManyDecimals,
+    /// An amount of the configuration doesn't parse at its precision, `setting` names which one
+    InvalidAmount { setting: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NegativeAmount { setting } => write!(f, "{} can't be negative", setting),
+            ConfigError::EmptyDedupWindow => write!(f, "the dedup window can't be empty"),
+            ConfigError::EmptyMemoryBudget => write!(f, "the memory budget can't be zero"),
+            ConfigError::ImmediateApprovalTimeout => {
+                write!(f, "the approval timeout can't be zero")
+            }
+            ConfigError::NoApprovers => write!(f, "approvals need at least one approver"),
+            ConfigError::TooManyDecimals => {
+                write!(f, "amounts can't have more than {} decimals", MAX_DECIMALS)
+            }
+            ConfigError::InvalidAmount { setting } => write!(f, "{} isn't a valid amount", setting),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Configuration of a `ClientTable`, checked once by `build`. Everything left out keeps the
+/// default of `ClientTable::new`: dense storage, the default `Policy` and amounts with 4 decimals
+pub struct ClientTableBuilder {
+    /// `None` leaves the storage to `ClientTable::adapt`
+    storage: Option<ClientStorage>,
+    policy: Policy,
+    currency: CurrencyConfig,
+    base_currency: Option<CurrencyCode>,
+    rates: RateTable,
+    schedules: Schedules,
+    hierarchy: Hierarchy,
+    limits: RiskLimits,
+    credit_lines: CreditLines,
+    fees: FeeSchedule,
+    #[cfg(feature = "std-io")]
+    spill: Option<Archive>,
+    extended_report: bool,
+    unlock_reverses: bool,
+    deterministic: bool,
+}
+
+impl Default for ClientTableBuilder {
+    fn default() -> Self {
+        Self {
+            storage: None,
+            policy: Policy::default(),
+            currency: CurrencyConfig::default(),
+            base_currency: None,
+            rates: RateTable::new(),
+            schedules: Schedules::new(),
+            hierarchy: Hierarchy::new(),
+            limits: RiskLimits::new(),
+            credit_lines: CreditLines::new(),
+            fees: FeeSchedule::new(),
+            #[cfg(feature = "std-io")]
+            spill: None,
+            extended_report: false,
+            unlock_reverses: false,
+            deterministic: cfg!(feature = "audit-build"),
+        }
+    }
+}
+
+impl ClientTableBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Where the clients are kept, see `ClientStorage`
+    pub fn storage(mut self, storage: ClientStorage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Replaces the whole policy, the policy setters below change a single field of it
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: DedupPolicy) -> Self {
+        self.policy.dedup = dedup;
+        self
+    }
+
+    /// How far the withdrawals of the clients without an override can draw their accounts down
+    pub fn overdraw(mut self, overdraw: OverdrawPolicy) -> Self {
+        self.policy.overdraw = overdraw;
+        self
+    }
+
+    pub fn disputes(mut self, disputes: DisputePolicy) -> Self {
+        self.policy.disputes = disputes;
+        self
+    }
+
+    /// Precision of the amounts, the transactions have to be parsed with the same one
+    pub fn currency(mut self, currency: CurrencyConfig) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// See `ClientTable::set_base_currency`
+    pub fn base_currency(mut self, code: Option<CurrencyCode>) -> Self {
+        self.base_currency = code;
+        self
+    }
+
+    pub fn rates(mut self, rates: RateTable) -> Self {
+        self.rates = rates;
+        self
+    }
+
+    pub fn schedules(mut self, schedules: Schedules) -> Self {
+        self.schedules = schedules;
+        self
+    }
+
+    pub fn hierarchy(mut self, hierarchy: Hierarchy) -> Self {
+        self.hierarchy = hierarchy;
+        self
+    }
+
+    pub fn limits(mut self, limits: RiskLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn credit_lines(mut self, credit_lines: CreditLines) -> Self {
+        self.credit_lines = credit_lines;
+        self
+    }
+
+    pub fn fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    /// Archive receiving the history spilled under memory pressure
+    #[cfg(feature = "std-io")]
+    pub fn spill_archive(mut self, archive: Archive) -> Self {
+        self.spill = Some(archive);
+        self
+    }
+
+    /// See `ClientTable::set_extended_report`
+    pub fn extended_report(mut self, extended: bool) -> Self {
+        self.extended_report = extended;
+        self
+    }
+
+    /// See `ClientTable::set_unlock_reverses`
+    pub fn unlock_reverses(mut self, reverse: bool) -> Self {
+        self.unlock_reverses = reverse;
+        self
+    }
+
+    /// See `ClientTable::set_deterministic`
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Checks the configuration and creates the table
+    pub fn build(self) -> Result<ClientTable, ConfigError> {
+        self.validate()?;
+        let mut table = match self.storage {
+            Some(storage) => ClientTable::with_storage(storage, self.policy),
+            None => ClientTable::with_policy(self.policy),
+        };
+        table.set_currency_config(self.currency);
+        table.set_base_currency(self.base_currency);
+        table.set_rates(self.rates);
+        table.set_schedules(self.schedules);
+        table.set_hierarchy(self.hierarchy);
+        table.set_limits(self.limits);
+        table.set_credit_lines(self.credit_lines);
+        table.set_fees(self.fees);
+        #[cfg(feature = "std-io")]
+        if let Some(archive) = self.spill {
+            table.set_spill_archive(archive);
+        }
+        table.set_extended_report(self.extended_report);
+        table.set_unlock_reverses(self.unlock_reverses);
+        table.set_deterministic(self.deterministic);
+        Ok(table)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let policy = &self.policy;
+        let amounts = [
+            ("the overdraft limit", Some(policy.overdraw.overdraft())),
+            (
+                "the chargeback fee",
+                policy.chargeback_fee.map(|fee| fee.amount),
+            ),
+            (
+                "the approval threshold",
+                policy.approvals.map(|approvals| approvals.threshold),
+            ),
+        ];
+        for &(setting, amount) in amounts.iter() {
+            if amount.is_some_and(|amount| amount < Currency::ZERO) {
+                return Err(ConfigError::NegativeAmount { setting });
+            }
+        }
+        if policy.dedup == DedupPolicy::Window(0) {
+            return Err(ConfigError::EmptyDedupWindow);
+        }
+        if policy
+            .memory_budget
+            .is_some_and(|budget| budget.limit_bytes == 0)
+        {
+            return Err(ConfigError::EmptyMemoryBudget);
+        }
+        if policy
+            .approvals
+            .is_some_and(|approvals| approvals.timeout == 0)
+        {
+            return Err(ConfigError::ImmediateApprovalTimeout);
+        }
+        if self
+            .policy
+            .approvals
+            .is_some_and(|approvals| approvals.approvers == 0)
+        {
+            return Err(ConfigError::NoApprovers);
+        }
+        Ok(())
+    }
+}
+
+impl ClientTable {
+    /// Starts the configuration of a table, see `ClientTableBuilder`
+    pub fn builder() -> ClientTableBuilder {
+        ClientTableBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        currency::Rounding,
+        policy::{ApprovalPolicy, ChargebackFee, FeePayer},
+        transaction::Transaction,
+    };
+
+    #[test]
+    fn builder_validates_the_configuration() {
+        let currency = CurrencyConfig::new(2, Rounding::default()).unwrap();
+        let mut table = ClientTable::builder()
+            .currency(currency)
+            .overdraw(OverdrawPolicy::Overdraft(Currency::new(500)))
+            .disputes(DisputePolicy::DepositsOnly)
+            .dedup(DedupPolicy::Full)
+            .build()
+            .unwrap();
+        assert_eq!(table.currency_config(), currency);
+        assert_eq!(table.policy().disputes, DisputePolicy::DepositsOnly);
+        table.process(vec![
+            Transaction::Withdraw {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(500),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+        ]);
+        let mut report = Vec::new();
+        table.write_csv(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client, available, held, total, locked\n1, -5.00, 0.00, -5.00, false\n"
+        );
+
+        let invalid = |builder: ClientTableBuilder| builder.build().err();
+        assert_eq!(
+            invalid(ClientTable::builder().overdraw(OverdrawPolicy::Overdraft(Currency::new(-1)))),
+            Some(ConfigError::NegativeAmount {
+                setting: "the overdraft limit"
+            })
+        );
+        assert_eq!(
+            invalid(ClientTable::builder().policy(Policy {
+                chargeback_fee: Some(ChargebackFee {
+                    amount: Currency::new(-1),
+                    payer: FeePayer::House,
+                }),
+                ..Policy::default()
+            })),
+            Some(ConfigError::NegativeAmount {
+                setting: "the chargeback fee"
+            })
+        );
+        assert_eq!(
+            invalid(ClientTable::builder().dedup(DedupPolicy::Window(0))),
+            Some(ConfigError::EmptyDedupWindow)
+        );
+        assert_eq!(
+            invalid(ClientTable::builder().policy(Policy {
+                approvals: Some(ApprovalPolicy {
+                    threshold: Currency::ZERO,
+                    timeout: 0,
+                    approvers: 1,
+                }),
+                ..Policy::default()
+            })),
+            Some(ConfigError::ImmediateApprovalTimeout)
+        );
+        assert_eq!(
+            invalid(ClientTable::builder().policy(Policy {
+                approvals: Some(ApprovalPolicy {
+                    threshold: Currency::ZERO,
+                    timeout: 1,
+                    approvers: 0,
+                }),
+                ..Policy::default()
+            })),
+            Some(ConfigError::NoApprovers)
+        );
+    }
+}
@@ -0,0 +1,830 @@
+//! The command line, defined with clap from the table of flags
+//!
+//! Every subcommand takes the flags too, so they may be given before or after it. The completion
+//! scripts and `--help-json` are generated from the same definition
+use std::io::{self, Write};
+
+use clap::{
+    builder::PossibleValuesParser, parser::ValueSource, Arg, ArgAction, ArgMatches, ValueHint,
+};
+use clap_complete::Generator;
+#[cfg(feature = "serde")]
+use serde_json::json;
+
+use crate::logging;
+
+/// What a flag takes as its value, used to complete it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    /// A switch without a value
+    None,
+    File,
+    Directory,
+    /// One of a fixed set of words
+    Choices(&'static [&'static str]),
+    /// Anything else, such as a number or an address
+    Text,
+}
+
+/// A flag of the command line
+#[derive(Clone, Copy, Debug)]
+pub struct Flag {
+    pub name: &'static str,
+    pub value: Value,
+    /// What the value is, as worded in the usage errors, empty for switches
+    pub placeholder: &'static str,
+    pub help: &'static str,
+}
+
+impl Flag {
+    /// Name of the flag without its dashes, the id of its clap argument
+    pub fn id(&self) -> &'static str {
+        &self.name[2..]
+    }
+
+    /// The flag as a clap argument. Every occurrence of a flag taking a value is kept, `given`
+    /// hands them over in order so the last one wins unless the flag adds up, like a sink
+    pub fn arg(&self) -> Arg {
+        let arg = Arg::new(self.id()).long(self.id()).help(self.help);
+        let arg = match self.value {
+            Value::None => return arg.action(ArgAction::SetTrue),
+            Value::File => arg.value_hint(ValueHint::FilePath),
+            Value::Directory => arg.value_hint(ValueHint::DirPath),
+            Value::Choices(choices) => arg.value_parser(PossibleValuesParser::new(choices)),
+            Value::Text => arg,
+        };
+        arg.action(ArgAction::Append)
+            .value_name(self.placeholder)
+            .allow_hyphen_values(true)
+    }
+}
+
+const fn flag(
+    name: &'static str,
+    value: Value,
+    placeholder: &'static str,
+    help: &'static str,
+) -> Flag {
+    Flag {
+        name,
+        value,
+        placeholder,
+        help,
+    }
+}
+
+const ROUNDING: &[&str] = &["reject", "toward-zero", "half-up", "half-even"];
+
+/// Every flag `main` accepts, keep in sync with its argument parsing
+pub const FLAGS: &[Flag] = &[
+    flag(
+        "--config",
+        Value::File,
+        "a file",
+        "TOML or YAML file of settings, see config::EngineConfig, which the command line overrides",
+    ),
+    flag(
+        "--format",
+        Value::Choices(&["csv", "json", "bin", "parquet", "avro"]),
+        "csv, json, bin, parquet or avro",
+        "Format of the input files, parquet input gets a parquet report",
+    ),
+    flag(
+        "--header",
+        Value::Choices(&["detect", "required", "absent"]),
+        "detect, required or absent",
+        "Whether the csv input starts with a header",
+    ),
+    flag(
+        "--decimals",
+        Value::Text,
+        "a number of decimals",
+        "Precision of the amounts",
+    ),
+    flag(
+        "--rounding",
+        Value::Choices(ROUNDING),
+        "a rounding mode",
+        "Rounding of amounts with more decimals than the precision",
+    ),
+    flag(
+        "--max-amount",
+        Value::Text,
+        "an amount",
+        "Rejects records with a larger amount",
+    ),
+    flag(
+        "--base-currency",
+        Value::Text,
+        "a currency code",
+        "Currency of the regular accounts",
+    ),
+    flag(
+        "--rates",
+        Value::File,
+        "a rates file",
+        "Exchange rates used by conversions",
+    ),
+    flag(
+        "--fx-spread",
+        Value::Text,
+        "a spread in basis points",
+        "Spread taken from every conversion",
+    ),
+    flag(
+        "--fx-rounding",
+        Value::Choices(ROUNDING),
+        "a rounding mode",
+        "Rounding of converted amounts",
+    ),
+    flag(
+        "--schedules",
+        Value::File,
+        "a schedules file",
+        "Recurring interest and fee postings",
+    ),
+    flag(
+        "--accrue-every",
+        Value::Text,
+        "a number of records",
+        "Closes an accrual day every given number of records",
+    ),
+    flag(
+        "--limits",
+        Value::File,
+        "a risk limits file",
+        "Withdrawal and velocity limits of the clients",
+    ),
+    flag(
+        "--credit-lines",
+        Value::File,
+        "a credit lines file",
+        "Credit limits and interest rates of the clients",
+    ),
+    flag(
+        "--fees",
+        Value::File,
+        "a fee schedule",
+        "Fees charged for deposits, withdrawals and chargebacks",
+    ),
+    flag(
+        "--trial-balance",
+        Value::File,
+        "a file",
+        "Keeps a double-entry general ledger and writes its trial balance to the file",
+    ),
+    flag(
+        "--client-master",
+        Value::File,
+        "a client master file",
+        "Parents of the sub-accounts, which inherit their locks and limits",
+    ),
+    flag(
+        "--rollup",
+        Value::None,
+        "",
+        "Reports every account with the balances of its sub-accounts",
+    ),
+    flag(
+        "--unlock-reverses",
+        Value::None,
+        "",
+        "Makes unlocks reverse the chargeback that locked the account",
+    ),
+    flag(
+        "--spec-compat",
+        Value::None,
+        "",
+        "Runs the engine of the original specification on a single csv file, implied by spec-compat builds",
+    ),
+    flag(
+        "--deterministic",
+        Value::None,
+        "",
+        "Makes the outputs depend on the input alone, implied by audit builds",
+    ),
+    flag(
+        "--value-dated",
+        Value::None,
+        "",
+        "Holds bookings until their value date",
+    ),
+    flag(
+        "--clearing",
+        Value::Text,
+        "instant or t+<business days>",
+        "Delay before deposits clear, every batch run closes a business day",
+    ),
+    flag(
+        "--dispute-window",
+        Value::Text,
+        "a number of days",
+        "Rejects disputes made longer after their transaction, per the timestamp column",
+    ),
+    flag(
+        "--overdraw",
+        Value::Text,
+        "strict, exact or a limit",
+        "How far withdrawals can draw the available funds down: exact by default, where the whole balance can be withdrawn, strict to refuse that like the original engine, or a limit allowing an overdraft",
+    ),
+    flag(
+        "--dispute-routing",
+        Value::Choices(&["client", "tx", "tx-warn"]),
+        "client, tx or tx-warn",
+        "How disputes find the account they concern",
+    ),
+    flag(
+        "--dedup",
+        Value::Text,
+        "full or a window size",
+        "Skips records seen before",
+    ),
+    flag(
+        "--tx-index",
+        Value::Choices(&["auto", "hashed", "chunked", "direct"]),
+        "auto, hashed, chunked or direct",
+        "Index of the transaction ids",
+    ),
+    flag(
+        "--storage",
+        Value::Choices(&["auto", "dense", "sparse"]),
+        "auto, dense or sparse",
+        "Storage of the clients, auto picks it from the start of the input",
+    ),
+    flag(
+        "--history-lookup",
+        Value::Choices(&["auto", "scan", "indexed"]),
+        "auto, scan or indexed",
+        "How disputes find their transaction, auto picks it from the start of the input",
+    ),
+    flag(
+        "--independent",
+        Value::None,
+        "",
+        "Processes every input file on its own thread, a batch has to begin and commit in one file",
+    ),
+    flag(
+        "--threads",
+        Value::Text,
+        "a number of threads",
+        "Shards the clients over the given number of threads, feeds with batches are refused",
+    ),
+    flag(
+        "--client-map",
+        Value::File,
+        "a mapping file",
+        "Maps up to 65535 external client ids of any form to internal ones, the report shows the external ids",
+    ),
+    flag(
+        "--memory-budget",
+        Value::Text,
+        "a number of bytes",
+        "Memory the transaction history may use",
+    ),
+    flag(
+        "--spill-to",
+        Value::File,
+        "an archive file",
+        "Archive receiving the history spilled under memory pressure",
+    ),
+    flag(
+        "--store",
+        Value::Directory,
+        "a store directory",
+        "Keeps the clients in a directory across runs, needs the kv-store feature",
+    ),
+    flag(
+        "--restore-from",
+        Value::File,
+        "a snapshot file",
+        "Starts from a snapshot",
+    ),
+    flag(
+        "--snapshot-to",
+        Value::File,
+        "a snapshot file",
+        "Writes a snapshot of the final state",
+    ),
+    flag(
+        "--checkpoint-every",
+        Value::Text,
+        "a number of records",
+        "Also writes the snapshot with the input offset every so many records",
+    ),
+    flag(
+        "--resume-from-offset",
+        Value::Text,
+        "a number of bytes",
+        "Skips the start of the input, to resume from a checkpoint",
+    ),
+    flag(
+        "--verbose",
+        Value::None,
+        "",
+        "Logs the storage picked for the input and the counters of the run",
+    ),
+    flag(
+        "--progress",
+        Value::Text,
+        "a number of seconds",
+        "Logs how far the input was read every so many seconds",
+    ),
+    flag(
+        "--import-from",
+        Value::File,
+        "an export file",
+        "Starts from an interchange export",
+    ),
+    flag(
+        "--export-to",
+        Value::File,
+        "an export file",
+        "Writes an interchange export of the final state",
+    ),
+    flag(
+        "--wal",
+        Value::File,
+        "a log file",
+        "Write-ahead log replayed on start and appended to",
+    ),
+    flag(
+        "--force-compat",
+        Value::None,
+        "",
+        "Reads files written by incompatible versions",
+    ),
+    flag(
+        "--extended-report",
+        Value::None,
+        "",
+        "Adds the legal hold column to the report",
+    ),
+    flag(
+        "--rejects",
+        Value::File,
+        "a file",
+        "Writes the rejected records",
+    ),
+    flag(
+        "--annotations",
+        Value::File,
+        "a file",
+        "Dispositions of known bad records",
+    ),
+    flag(
+        "--attest-key",
+        Value::File,
+        "a key file",
+        "Signs the statements with the key",
+    ),
+    flag(
+        "--period",
+        Value::Text,
+        "a statement period",
+        "Period named in the statements",
+    ),
+    flag(
+        "--normalized-out",
+        Value::File,
+        "a file",
+        "Writes the records in normalized form",
+    ),
+    flag(
+        "--events-per-client",
+        Value::Directory,
+        "a directory",
+        "Writes the events of every client to its own file",
+    ),
+    flag(
+        "--events-coalesced",
+        Value::File,
+        "a file or pipe",
+        "Writes coalesced balance changes",
+    ),
+    flag(
+        "--coalesce-every",
+        Value::Text,
+        "a number of records",
+        "Records per interval of the coalesced events",
+    ),
+    flag(
+        "--events-keyed",
+        Value::File,
+        "a file or pipe",
+        "Writes the events keyed by client",
+    ),
+    flag(
+        "--log-level",
+        Value::Choices(logging::LEVELS),
+        "a log level",
+        "Logs every transaction to stderr, rejections at warn and applied ones at debug, notes of the run are at info",
+    ),
+    flag(
+        "--log-json",
+        Value::None,
+        "",
+        "Writes the transaction log as JSON lines",
+    ),
+    flag(
+        "--sort",
+        Value::Choices(&["client", "total"]),
+        "client or total",
+        "Orders the report by client id or by largest total funds",
+    ),
+    flag(
+        "--locked-only",
+        Value::None,
+        "",
+        "Only reports the locked accounts",
+    ),
+    flag(
+        "--clients",
+        Value::Text,
+        "client ranges",
+        "Only reports the clients in the given ranges, such as 1-100,200",
+    ),
+    flag(
+        "--top",
+        Value::Text,
+        "a number of accounts",
+        "Reports at most the given number of accounts",
+    ),
+    flag(
+        "--outbox",
+        Value::File,
+        "an outbox file",
+        "Publishes the keyed events through a durable outbox, retrying failures",
+    ),
+    flag(
+        "--sink-retries",
+        Value::Text,
+        "a number of retries",
+        "Retries of a failed publication through the outbox, with exponential backoff",
+    ),
+    flag(
+        "--ship-to",
+        Value::Directory,
+        "a directory",
+        "Ships snapshots and log segments to a standby while serving",
+    ),
+    flag(
+        "--admin-token",
+        Value::File,
+        "a token file",
+        "Enables the admin endpoints of the server for requests bearing the token",
+    ),
+    flag(
+        "--ship-every",
+        Value::Text,
+        "a number of milliseconds",
+        "How often the server ships",
+    ),
+    flag(
+        "--standby",
+        Value::Directory,
+        "a directory",
+        "Follows the shipped state until promoted",
+    ),
+    flag(
+        "--arrivals",
+        Value::Text,
+        "an arrival profile",
+        "Arrival profile of replay",
+    ),
+    flag(
+        "--state",
+        Value::File,
+        "a snapshot file",
+        "State apply reads and writes",
+    ),
+    flag("--audit-log", Value::File, "a file", "Audit trail of apply"),
+    flag(
+        "--type",
+        Value::Text,
+        "a record type",
+        "Record type posted by apply",
+    ),
+    flag(
+        "--client",
+        Value::Text,
+        "a client id",
+        "Client of the record posted by apply",
+    ),
+    flag(
+        "--tx",
+        Value::Text,
+        "a transaction id",
+        "Transaction id of the record posted by apply",
+    ),
+    flag(
+        "--currency",
+        Value::Text,
+        "a currency code",
+        "Currency of the record posted by apply",
+    ),
+    flag(
+        "--amount",
+        Value::Text,
+        "an amount",
+        "Amount of the record posted by apply",
+    ),
+    flag(
+        "--value-date",
+        Value::Text,
+        "a value",
+        "Value date of the booking posted by apply",
+    ),
+    flag(
+        "--to",
+        Value::Text,
+        "a value",
+        "Target currency of the conversion posted by apply",
+    ),
+    flag(
+        "--strict",
+        Value::None,
+        "",
+        "Also refuses records with more columns than the schema, the first bad record aborts",
+    ),
+    flag(
+        "--lenient",
+        Value::None,
+        "",
+        "Skips and counts the records that can't be parsed instead of aborting",
+    ),
+    flag(
+        "--history-json",
+        Value::None,
+        "",
+        "Prints the ledger of history as JSON",
+    ),
+    flag(
+        "--tag",
+        Value::Text,
+        "a memo",
+        "Only prints the ledger entries of history with this memo",
+    ),
+    flag(
+        "--by-tag",
+        Value::None,
+        "",
+        "Prints the totals of every memo instead of the ledger of history",
+    ),
+    flag(
+        "--help-json",
+        Value::None,
+        "",
+        "Describes the flags and commands as JSON",
+    ),
+];
+
+/// The command line of the `bank` binary
+pub fn command() -> clap::Command {
+    let files = || {
+        Arg::new("files")
+            .num_args(0..)
+            .value_hint(ValueHint::FilePath)
+            .help("Input files, standard input without any")
+    };
+    let text = |id: &'static str, help: &'static str| Arg::new(id).required(true).help(help);
+    with_flags(clap::Command::new("bank").bin_name("bank"))
+        .about("Processes transactions and reports the balances of the clients")
+        .arg(files())
+        .subcommand(
+            subcommand("query", "Prints the clients matching the expression")
+                .arg(text("expression", "Expression the clients have to match"))
+                .arg(files()),
+        )
+        .subcommand(
+            subcommand(
+                "history",
+                "Prints the ledger of a client instead of the report",
+            )
+            .arg(text("id", "Id of the client").value_name("client"))
+            .arg(files()),
+        )
+        .subcommand(
+            subcommand(
+                "serve",
+                "Exposes the engine over HTTP, a bare port listens on localhost",
+            )
+            .arg(text("address", "Address to listen on")),
+        )
+        .subcommand(
+            subcommand("replay", "Load tests a running server")
+                .arg(text("address", "Address of the server"))
+                .arg(files()),
+        )
+        .subcommand(
+            subcommand("convert", "Encodes csv records in the binary format").arg(
+                Arg::new("files")
+                    .num_args(2..)
+                    .required(true)
+                    .value_hint(ValueHint::FilePath)
+                    .help("Csv files followed by the output"),
+            ),
+        )
+        .subcommand(
+            subcommand("diff", "Prints the balances that differ between two inputs").arg(
+                Arg::new("files")
+                    .num_args(2)
+                    .required(true)
+                    .value_hint(ValueHint::FilePath)
+                    .help("Input files or snapshots"),
+            ),
+        )
+        .subcommand(subcommand(
+            "apply",
+            "Posts a single transaction to a saved state, see --state and --type",
+        ))
+        .subcommand(
+            clap::Command::new("completions")
+                .about("Prints the completion script of a shell")
+                .arg(
+                    Arg::new("shell")
+                        .required(true)
+                        .value_parser(clap::value_parser!(clap_complete::Shell)),
+                ),
+        )
+}
+
+fn subcommand(name: &'static str, about: &'static str) -> clap::Command {
+    with_flags(clap::Command::new(name)).about(about)
+}
+
+fn with_flags(command: clap::Command) -> clap::Command {
+    command
+        .args(FLAGS.iter().map(Flag::arg))
+        .args_override_self(true)
+}
+
+/// The flags of `matches` with their values, in the order they were given. Switches have an
+/// empty value
+pub fn given(matches: &ArgMatches) -> Vec<(&'static str, String)> {
+    let mut given = Vec::new();
+    for f in FLAGS {
+        // `completions` takes no flags
+        let known = matches.try_contains_id(f.id()).is_ok();
+        if !known || matches.value_source(f.id()) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let indices = matches.indices_of(f.id()).into_iter().flatten();
+        match f.value {
+            Value::None => given.extend(indices.map(|i| (i, f.name, String::new()))),
+            _ => {
+                let values = matches.get_many::<String>(f.id()).into_iter().flatten();
+                given.extend(indices.zip(values).map(|(i, v)| (i, f.name, v.clone())))
+            }
+        }
+    }
+    given.sort_by_key(|&(i, ..)| i);
+    given
+        .into_iter()
+        .map(|(_, name, value)| (name, value))
+        .collect()
+}
+
+/// Writes the completion script of `shell` for the `bank` binary
+pub fn write_completions<W: Write>(w: &mut W, shell: clap_complete::Shell) -> io::Result<()> {
+    let mut command = command();
+    command.build();
+    shell.try_generate(&command, w)
+}
+
+/// Writes the flags and commands as a JSON object, for tools inspecting the command line:
+/// `{"commands":[{"name":..,"usage":..,"help":..}],"flags":[{"name":..,"value":..,"placeholder":..,
+/// "choices":[..],"help":..}]}` where `value` is `file`, `directory`, `choice`, `text` or null
+#[cfg(feature = "serde")]
+pub fn write_help_json<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut command = command();
+    command.build();
+    let commands: Vec<_> = command
+        .get_subcommands()
+        .map(|c| {
+            let usage = c.clone().render_usage().to_string();
+            json!({
+                "name": c.get_name(),
+                "usage": usage.trim_start_matches("Usage: "),
+                "help": c.get_about().map(ToString::to_string),
+            })
+        })
+        .collect();
+    let flags: Vec<_> = command
+        .get_arguments()
+        .filter(|a| a.get_long().is_some() && a.get_id() != "help")
+        .map(|a| {
+            let choices: Vec<_> = a
+                .get_possible_values()
+                .iter()
+                .map(|v| v.get_name().to_string())
+                .collect();
+            let value = match a.get_value_hint() {
+                _ if !a.get_action().takes_values() => None,
+                _ if !choices.is_empty() => Some("choice"),
+                ValueHint::FilePath => Some("file"),
+                ValueHint::DirPath => Some("directory"),
+                _ => Some("text"),
+            };
+            let placeholder = a.get_value_names().and_then(|names| names.first());
+            json!({
+                "name": format!("--{}", a.get_id()),
+                "value": value,
+                "placeholder": placeholder.map_or(String::new(), ToString::to_string),
+                "choices": choices,
+                "help": a.get_help().map(ToString::to_string),
+            })
+        })
+        .collect();
+    serde_json::to_writer(&mut *w, &json!({ "commands": commands, "flags": flags }))?;
+    writeln!(w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_are_unique_and_complete_in_every_shell() {
+        for (i, f) in FLAGS.iter().enumerate() {
+            assert!(f.name.starts_with("--"));
+            assert!(FLAGS[..i].iter().all(|g| g.name != f.name), "{}", f.name);
+            assert_eq!(
+                f.value == Value::None,
+                f.placeholder.is_empty(),
+                "{}",
+                f.name
+            );
+        }
+        command().debug_assert();
+        for shell in [
+            clap_complete::Shell::Bash,
+            clap_complete::Shell::Zsh,
+            clap_complete::Shell::Fish,
+        ] {
+            let mut script = Vec::new();
+            write_completions(&mut script, shell).unwrap();
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("tx-index"));
+            assert!(script.contains("half-even"));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn help_json_describes_every_flag() {
+        let mut json = Vec::new();
+        write_help_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["commands"][0]["name"], "query");
+        assert_eq!(
+            json["commands"][0]["usage"],
+            "bank query [OPTIONS] <expression> [files]..."
+        );
+        let flags = json["flags"].as_array().unwrap();
+        assert_eq!(flags.len(), FLAGS.len());
+        let format = flags.iter().find(|f| f["name"] == "--format").unwrap();
+        assert_eq!(format["value"], "choice");
+        assert_eq!(format["placeholder"], "csv, json, bin, parquet or avro");
+        assert_eq!(
+            format["choices"],
+            json!(["csv", "json", "bin", "parquet", "avro"])
+        );
+        let value_dated = flags.iter().find(|f| f["name"] == "--value-dated").unwrap();
+        assert_eq!(value_dated["value"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn flags_are_given_in_order_before_and_after_the_subcommand() {
+        let matches = command()
+            .try_get_matches_from([
+                "bank",
+                "--strict",
+                "--max-amount",
+                "-5",
+                "--lenient",
+                "query",
+                "total > 1",
+                "a.csv",
+                "--max-amount",
+                "7",
+                "b.csv",
+            ])
+            .unwrap();
+        assert_eq!(
+            given(&matches),
+            [
+                ("--strict", String::new()),
+                ("--max-amount", "-5".to_string()),
+                ("--lenient", String::new()),
+            ]
+        );
+        let (name, query) = matches.subcommand().unwrap();
+        assert_eq!(name, "query");
+        assert_eq!(given(query), [("--max-amount", "7".to_string())]);
+        let files: Vec<_> = query.get_many::<String>("files").unwrap().collect();
+        assert_eq!(files, ["a.csv", "b.csv"]);
+        assert!(command()
+            .try_get_matches_from(["bank", "--header", "sometimes"])
+            .is_err());
+    }
+}
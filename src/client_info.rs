@@ -1,76 +1,985 @@
-use std::fmt;
+#[cfg(feature = "std-io")]
+use std::io::{self, Write};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+};
 
-use crate::{currency::Currency, transaction::TxId};
+#[cfg(feature = "std-io")]
+use crate::rejects::quote;
+use crate::{
+    currency::{AggregationOverflow, Currency},
+    policy::{DisputePolicy, HistoryLookup, LockedAccountPolicy, OverdrawPolicy, Policy},
+    transaction::{Memo, Timestamp, TxId},
+};
 
 /// ClientInfo is optimized around the assumption that disputes are a lot rarer than normal transactions
 /// Thus it uses vectors instead of hashmaps to achieve fast insertions for the common transactions
 /// This does means that a dispute takes longer to execute than what might be expected due to having to search the entire vector
 /// Dispute follow up transactions(resolve/chargeback) are reletivley cheap as the amount of dispute to search through should be very short
-/// If disputes becomes an issue one could dynamically "upgrade" from a vector to a hashmap once some threshhold has been reached
+/// If disputes becomes an issue `HistoryLookup::Indexed` adds a hashmap from tx id to position in `transfers`
+/// A client whose history grows past `INDEX_THRESHOLD` transfers gets the hashmap whatever the
+/// policy, so disputes against a long history stay cheap even when the input was sampled as
+/// dispute-free
 #[derive(Default, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClientInfo {
     available_funds: Currency,
     held_funds: Currency,
     locked: bool,
+    account_type: AccountType,
     transfers: Vec<ClientTransaction>,
     disputes: Vec<ClientTransaction>,
+    /// How the disputes that were resolved or charged back ended, a transaction can only be
+    /// disputed once. Leaves memory together with the transfer it belongs to
+    settled: BTreeMap<TxId, DisputeState>,
+    fees: Vec<ClientTransaction>,
+    /// Number of transfers moved out of `transfers` into an archive
+    archived: usize,
+    /// Funds of bookings and uncleared deposits, not part of the available funds yet
+    booked_funds: Currency,
+    /// Bookings and uncleared deposits, together with when they are released
+    bookings: Vec<(Release, ClientTransaction)>,
+    /// Legal hold orders ring-fencing part of the available funds from withdrawals, by order id
+    legal_holds: Vec<(TxId, Currency)>,
+    /// Memos of the transfers that came with one, by tx id
+    memos: Vec<(TxId, Memo)>,
+    /// Built with `HistoryLookup::Indexed` or past `INDEX_THRESHOLD` transfers
+    #[cfg_attr(feature = "serde", serde(skip))]
+    index: Option<HistoryIndex>,
+}
+
+/// Hash index of a client history, kept up to date with it once built
+#[derive(Default, Clone, Debug)]
+struct HistoryIndex {
+    /// Position of every transfer in `transfers` by tx id
+    transfers: HashMap<TxId, usize>,
+    /// Where every transaction that was disputed stands, the others are undisputed
+    disputes: HashMap<TxId, DisputeState>,
 }
 
 impl ClientInfo {
-    pub fn deposit(&mut self, amount: Currency, tx: TxId) {
-        self.available_funds += amount;
-        self.transfers.push(ClientTransaction::new(amount, tx));
+    /// Number of transfers from which the history of a client is indexed in any case
+    pub const INDEX_THRESHOLD: usize = 64;
+
+    /// Account of a client that has not been seen yet, identical to `ClientInfo::default()`
+    pub const EMPTY: ClientInfo = ClientInfo {
+        available_funds: Currency::ZERO,
+        held_funds: Currency::ZERO,
+        locked: false,
+        account_type: AccountType::Standard,
+        transfers: Vec::new(),
+        disputes: Vec::new(),
+        settled: BTreeMap::new(),
+        fees: Vec::new(),
+        archived: 0,
+        booked_funds: Currency::ZERO,
+        bookings: Vec::new(),
+        legal_holds: Vec::new(),
+        memos: Vec::new(),
+        index: None,
+    };
+
+    pub fn deposit(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.deposit_at(amount, tx, None, policy)
+    }
+
+    /// Deposits like `deposit`, recording that the transfer took place at `at`
+    pub fn deposit_at(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.credit(TransferKind::Deposit, amount, tx, at, policy)
+    }
+
+    pub fn withdraw(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.withdraw_at(amount, tx, None, policy)
+    }
+
+    /// Withdraws like `withdraw`, recording that the transfer took place at `at`
+    pub fn withdraw_at(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.debit(TransferKind::Withdrawal, amount, tx, at, policy)
     }
 
-    pub fn withdraw(&mut self, amount: Currency, tx: TxId) -> Result<(), TransactionError> {
-        if self.available_funds <= amount {
+    /// Credits the proceeds of currency conversion `tx`, the same way as a deposit
+    pub fn convert_in(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.credit(TransferKind::Conversion, amount, tx, at, policy)
+    }
+
+    /// Debits the funds exchanged by currency conversion `tx`, the same way as a withdrawal
+    pub fn convert_out(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        self.debit(TransferKind::Conversion, amount, tx, at, policy)
+    }
+
+    fn credit(
+        &mut self,
+        kind: TransferKind,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if self.locked && policy.locked_accounts != LockedAccountPolicy::AcceptDeposits {
+            return Err(TransactionError::AccountLocked);
+        }
+        self.available_funds = add(self.available_funds, amount)?;
+        self.push_transfer(ClientTransaction::new(kind, amount, tx).stamped(at), policy);
+        Ok(())
+    }
+
+    fn debit(
+        &mut self,
+        kind: TransferKind,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if self.locked {
+            return Err(TransactionError::AccountLocked);
+        }
+        let overdraft = policy.overdraw.overdraft();
+        let overdraws = match policy.overdraw {
+            OverdrawPolicy::Strict => self.available_funds <= amount,
+            _ => add(self.available_funds, overdraft)? < amount,
+        };
+        if overdraws {
             return Err(TransactionError::Overdraw);
         }
-        self.available_funds -= amount;
-        self.transfers.push(ClientTransaction::new(-amount, tx));
+        let floor = sub(policy.minimum_balances.floor(self.account_type), overdraft)?;
+        if self.available_funds < add(floor, amount)? {
+            return Err(TransactionError::BelowMinimumBalance);
+        }
+        // Ring-fenced funds can't be drawn from the overdraft either
+        let legal_held = self.legal_held()?;
+        if legal_held > Currency::ZERO && self.available_funds < add(legal_held, amount)? {
+            return Err(TransactionError::LegalHold);
+        }
+        let stored = amount.checked_neg().ok_or(TransactionError::Overflow)?;
+        self.available_funds = sub(self.available_funds, amount)?;
+        self.push_transfer(ClientTransaction::new(kind, stored, tx).stamped(at), policy);
         Ok(())
     }
 
-    pub fn dispute(&mut self, tx: TxId) -> Result<(), TransactionError> {
-        for t in &self.transfers {
-            if t.tx == tx {
-                self.available_funds -= t.amount;
-                self.held_funds += t.amount;
-                self.disputes.push(ClientTransaction::new(t.amount, t.tx));
-                return Ok(());
+    /// Ring-fences `amount` of the available funds from withdrawals until the order is released,
+    /// the whole available balance if no amount is given. An order can exceed the available funds,
+    /// withdrawals then stay blocked until enough funds came in. Other operations are unaffected,
+    /// and orders are still placed on locked accounts
+    pub fn place_legal_hold(
+        &mut self,
+        order: TxId,
+        amount: Option<Currency>,
+    ) -> Result<(), TransactionError> {
+        if self.legal_holds.iter().any(|(id, _)| *id == order) {
+            return Err(TransactionError::DuplicateTxId);
+        }
+        let amount = amount.unwrap_or_else(|| self.available_funds.positive_part());
+        if amount < Currency::ZERO {
+            return Err(TransactionError::InvalidAmount);
+        }
+        // Checked here so the total of the orders always fits
+        add(self.legal_held()?, amount)?;
+        self.legal_holds.push((order, amount));
+        Ok(())
+    }
+
+    pub fn release_legal_hold(&mut self, order: TxId) -> Result<(), TransactionError> {
+        let i = self
+            .legal_holds
+            .iter()
+            .position(|(id, _)| *id == order)
+            .ok_or(TransactionError::InvalidTxId)?;
+        self.legal_holds.remove(i);
+        Ok(())
+    }
+
+    /// Funds ring-fenced by legal hold orders, tracked apart from the funds held by disputes
+    pub fn legal_held(&self) -> Result<Currency, AggregationOverflow> {
+        Currency::sum_of(
+            "legal hold total",
+            self.legal_holds.iter().map(|(_, amount)| *amount),
+        )
+    }
+
+    /// Books a deposit made at `at` whose funds only become available once `release` is due,
+    /// see `release_booking`
+    pub fn book(
+        &mut self,
+        amount: Currency,
+        tx: TxId,
+        at: Option<Timestamp>,
+        release: Release,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if self.locked && policy.locked_accounts != LockedAccountPolicy::AcceptDeposits {
+            return Err(TransactionError::AccountLocked);
+        }
+        self.booked_funds = add(self.booked_funds, amount)?;
+        self.bookings.push((
+            release,
+            ClientTransaction::new(TransferKind::Deposit, amount, tx).stamped(at),
+        ));
+        Ok(())
+    }
+
+    /// Moves a booking that is due to the available funds, from then on it is a
+    /// regular deposit which can be disputed. This happens even if the account got locked since
+    pub fn release_booking(&mut self, tx: TxId, policy: &Policy) -> Result<(), TransactionError> {
+        let i = self
+            .bookings
+            .iter()
+            .position(|(_, t)| t.tx == tx)
+            .ok_or(TransactionError::InvalidTxId)?;
+        let booking = self.bookings[i].1;
+        self.available_funds = add(self.available_funds, booking.amount)?;
+        self.booked_funds = sub(self.booked_funds, booking.amount)?;
+        self.bookings.swap_remove(i);
+        self.push_transfer(booking, policy);
+        Ok(())
+    }
+
+    /// Bookings and uncleared deposits still waiting to be released as `(release, tx)`
+    pub fn bookings(&self) -> impl Iterator<Item = (Release, TxId)> + '_ {
+        self.bookings.iter().map(|(release, t)| (*release, t.tx))
+    }
+
+    /// Ids of the transfers, disputes and bookings of the client still in memory, fees excluded
+    pub(crate) fn tx_ids(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.transfers
+            .iter()
+            .chain(&self.disputes)
+            .chain(self.bookings.iter().map(|(_, t)| t))
+            .map(|t| t.tx)
+            .chain(self.settled.keys().copied())
+    }
+
+    fn push_transfer(&mut self, transfer: ClientTransaction, policy: &Policy) {
+        if policy.history == Some(HistoryLookup::Indexed) && self.index.is_none() {
+            self.index_transfers();
+        }
+        self.append_transfer(transfer);
+    }
+
+    /// Adds `transfer` to the history and the index, which is built once the history reaches
+    /// `INDEX_THRESHOLD` transfers
+    fn append_transfer(&mut self, transfer: ClientTransaction) {
+        if let Some(index) = &mut self.index {
+            index
+                .transfers
+                .entry(transfer.tx)
+                .or_insert(self.transfers.len());
+        }
+        self.transfers.push(transfer);
+        if self.index.is_none() && self.transfers.len() >= Self::INDEX_THRESHOLD {
+            self.index_transfers();
+        }
+    }
+
+    /// Builds the hash index of the transfers and of their disputes, kept up to date from then on
+    pub fn index_transfers(&mut self) {
+        let mut transfers = HashMap::with_capacity(self.transfers.len());
+        for (i, t) in self.transfers.iter().enumerate() {
+            transfers.entry(t.tx).or_insert(i);
+        }
+        let settled = self.settled.iter().map(|(&tx, &state)| (tx, state));
+        let open = self.disputes.iter().map(|d| (d.tx, DisputeState::Disputed));
+        self.index = Some(HistoryIndex {
+            transfers,
+            disputes: settled.chain(open).collect(),
+        });
+    }
+
+    /// Whether the history has its hash index, see `index_transfers`
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
+    pub(crate) fn find_transfer(&self, tx: TxId) -> Option<&ClientTransaction> {
+        match &self.index {
+            Some(index) => index.transfers.get(&tx).map(|&i| &self.transfers[i]),
+            None => self.transfers.iter().find(|t| t.tx == tx),
+        }
+    }
+
+    /// Records in the index that `tx` moved to `state` in its dispute lifecycle
+    fn index_dispute(&mut self, tx: TxId, state: DisputeState) {
+        if let Some(index) = &mut self.index {
+            index.disputes.insert(tx, state);
+        }
+    }
+
+    /// When transfer `tx` took place, `None` if it is not in the history or had no timestamp
+    pub fn transfer_time(&self, tx: TxId) -> Option<Timestamp> {
+        self.find_transfer(tx).and_then(|t| t.at)
+    }
+
+    /// Records that transfer `tx`, just applied, came with `memo`
+    pub(crate) fn tag_transfer(&mut self, tx: TxId, memo: Memo) {
+        self.memos.push((tx, memo));
+    }
+
+    /// Disputing a deposit moves the deposited amount from available to held
+    /// Disputing a withdrawal holds the withdrawn amount on top of the available funds,
+    /// as the client claims money back that already left the account
+    pub fn dispute(&mut self, tx: TxId, policy: &Policy) -> Result<(), TransactionError> {
+        if self.locked {
+            return Err(TransactionError::AccountLocked);
+        }
+        match self.dispute_state(tx) {
+            DisputeState::Undisputed => {}
+            DisputeState::Disputed => return Err(TransactionError::AlreadyDisputed),
+            DisputeState::Resolved | DisputeState::ChargedBack => {
+                return Err(TransactionError::DisputeSettled)
             }
         }
-        Err(TransactionError::InvalidTxId)
+        let t = *self
+            .find_transfer(tx)
+            .ok_or(TransactionError::InvalidTxId)?;
+        let available = match t.kind {
+            TransferKind::Deposit => sub(self.available_funds, t.amount)?,
+            TransferKind::Withdrawal if policy.disputes == DisputePolicy::DepositsOnly => {
+                return Err(TransactionError::NotDisputable)
+            }
+            TransferKind::Withdrawal if self.is_refunded(tx) => {
+                return Err(TransactionError::NotDisputable)
+            }
+            TransferKind::Withdrawal | TransferKind::Fee => self.available_funds,
+            TransferKind::Conversion | TransferKind::Interest | TransferKind::Refund => {
+                return Err(TransactionError::NotDisputable)
+            }
+        };
+        self.held_funds = add(self.held_funds, t.disputed_amount())?;
+        self.available_funds = available;
+        self.disputes.push(t);
+        self.index_dispute(tx, DisputeState::Disputed);
+        Ok(())
     }
 
-    pub fn resolve(&mut self, dispute_tx: TxId) -> Result<(), TransactionError> {
-        for d in &self.disputes {
-            if d.tx == dispute_tx {
-                self.available_funds += d.amount;
-                self.held_funds -= d.amount;
-                return Ok(());
+    pub fn is_disputed(&self, tx: TxId) -> bool {
+        match &self.index {
+            Some(index) => index.disputes.get(&tx) == Some(&DisputeState::Disputed),
+            None => self.disputes.iter().any(|d| d.tx == tx),
+        }
+    }
+
+    /// Where transaction `tx` stands in its dispute lifecycle
+    pub fn dispute_state(&self, tx: TxId) -> DisputeState {
+        if let Some(index) = &self.index {
+            return index
+                .disputes
+                .get(&tx)
+                .copied()
+                .unwrap_or(DisputeState::Undisputed);
+        }
+        if self.is_disputed(tx) {
+            return DisputeState::Disputed;
+        }
+        self.settled
+            .get(&tx)
+            .copied()
+            .unwrap_or(DisputeState::Undisputed)
+    }
+
+    /// Position of the open dispute of `tx`, settling a dispute twice is refused
+    fn open_dispute(&self, tx: TxId) -> Result<usize, TransactionError> {
+        match self.disputes.iter().position(|d| d.tx == tx) {
+            Some(i) => Ok(i),
+            None if self.dispute_state(tx) == DisputeState::Undisputed => {
+                Err(TransactionError::InvalidTxId)
             }
+            None => Err(TransactionError::DisputeSettled),
         }
-        Err(TransactionError::InvalidTxId)
     }
 
+    /// Closes the open dispute at position `i` of `disputes`
+    fn close_dispute(&mut self, i: usize, state: DisputeState) {
+        let d = self.disputes.remove(i);
+        self.settled.insert(d.tx, state);
+        self.index_dispute(d.tx, state);
+    }
+
+    /// Releases the hold, a disputed deposit becomes available again while a disputed
+    /// withdrawal stands and the held amount simply disappears
+    pub fn resolve(&mut self, dispute_tx: TxId) -> Result<(), TransactionError> {
+        let i = self.open_dispute(dispute_tx)?;
+        let d = self.disputes[i];
+        let available = match d.kind {
+            TransferKind::Deposit => add(self.available_funds, d.amount)?,
+            TransferKind::Withdrawal
+            | TransferKind::Fee
+            | TransferKind::Conversion
+            | TransferKind::Interest
+            | TransferKind::Refund => self.available_funds,
+        };
+        self.held_funds = self.release_hold(d)?;
+        self.available_funds = available;
+        self.close_dispute(i, DisputeState::Resolved);
+        Ok(())
+    }
+
+    /// Reverses the disputed transaction, a deposit is taken out of the account while a
+    /// withdrawal is credited back as available funds
     pub fn chargeback(&mut self, dispute_tx: TxId) -> Result<(), TransactionError> {
-        for d in &self.disputes {
-            if d.tx == dispute_tx {
-                self.held_funds -= d.amount;
-                self.locked = true;
-                return Ok(());
+        self.chargeback_with_fee(dispute_tx, Currency::default())
+    }
+
+    /// Chargeback where the client also pays a fee, either both are applied or neither is
+    pub fn chargeback_with_fee(
+        &mut self,
+        dispute_tx: TxId,
+        fee: Currency,
+    ) -> Result<(), TransactionError> {
+        let i = self.open_dispute(dispute_tx)?;
+        let d = self.disputes[i];
+        let mut available = self.available_funds;
+        if d.kind == TransferKind::Withdrawal {
+            available = add(available, d.disputed_amount())?;
+        }
+        available = sub(available, fee)?;
+        let stored_fee = fee.checked_neg().ok_or(TransactionError::Overflow)?;
+        self.held_funds = self.release_hold(d)?;
+        self.available_funds = available;
+        self.locked = true;
+        if fee != Currency::default() {
+            self.fees.push(ClientTransaction::new(
+                TransferKind::Fee,
+                stored_fee,
+                dispute_tx,
+            ));
+        }
+        self.close_dispute(i, DisputeState::ChargedBack);
+        Ok(())
+    }
+
+    /// Held funds left once the hold of dispute `d` is released, refused when they don't cover it
+    fn release_hold(&self, d: ClientTransaction) -> Result<Currency, TransactionError> {
+        if self.held_funds < d.disputed_amount() {
+            return Err(TransactionError::InconsistentHold);
+        }
+        sub(self.held_funds, d.disputed_amount())
+    }
+
+    /// Checks that the held funds are exactly the amounts put on hold by the open disputes
+    pub fn check_hold(&self) -> Result<(), TransactionError> {
+        let disputed = Currency::sum_of(
+            "disputed total",
+            self.disputes.iter().map(|d| d.disputed_amount()),
+        )?;
+        if self.held_funds != disputed {
+            return Err(TransactionError::InconsistentHold);
+        }
+        Ok(())
+    }
+
+    /// Lifts the lock put on the account by the chargeback of `dispute_tx`. With `reverse` the
+    /// chargeback is undone as well: a charged back deposit is credited again, a charged back
+    /// withdrawal is taken out again, and the dispute counts as resolved from then on. A fee charged
+    /// with the chargeback is kept either way
+    pub fn unlock(&mut self, dispute_tx: TxId, reverse: bool) -> Result<(), TransactionError> {
+        if !self.locked {
+            return Err(TransactionError::NotLocked);
+        }
+        if self.settled.get(&dispute_tx) != Some(&DisputeState::ChargedBack) {
+            return Err(TransactionError::InvalidTxId);
+        }
+        if reverse {
+            let t = *self
+                .find_transfer(dispute_tx)
+                .ok_or(TransactionError::InvalidTxId)?;
+            let available = match t.kind {
+                TransferKind::Deposit => add(self.available_funds, t.amount)?,
+                TransferKind::Withdrawal => {
+                    let available = sub(self.available_funds, t.disputed_amount())?;
+                    if available < Currency::ZERO {
+                        return Err(TransactionError::Overdraw);
+                    }
+                    available
+                }
+                // Never disputed, so never charged back
+                TransferKind::Fee
+                | TransferKind::Conversion
+                | TransferKind::Interest
+                | TransferKind::Refund => return Err(TransactionError::NotDisputable),
+            };
+            self.available_funds = available;
+            self.settled.insert(dispute_tx, DisputeState::Resolved);
+            self.index_dispute(dispute_tx, DisputeState::Resolved);
+        }
+        self.locked = false;
+        Ok(())
+    }
+
+    /// Charges a fee related to the transaction `tx`, the fee is kept in its own ledger
+    /// so it never shows up as a disputable transfer
+    pub fn charge_fee(&mut self, amount: Currency, tx: TxId) -> Result<(), TransactionError> {
+        let stored = amount.checked_neg().ok_or(TransactionError::Overflow)?;
+        self.available_funds = sub(self.available_funds, amount)?;
+        self.fees
+            .push(ClientTransaction::new(TransferKind::Fee, stored, tx));
+        Ok(())
+    }
+
+    /// Credits a fee paid by a client, for the house account collecting the fee revenue. The fee
+    /// only adds to the balance, the ledger entry stays with the client that paid it
+    pub fn collect_fee(&mut self, amount: Currency) -> Result<(), TransactionError> {
+        self.available_funds = add(self.available_funds, amount)?;
+        Ok(())
+    }
+
+    /// Credits interest earned on the account, kept in the fee ledger along with the fees as
+    /// neither can be disputed
+    pub fn post_interest(&mut self, amount: Currency, tx: TxId) -> Result<(), TransactionError> {
+        self.available_funds = add(self.available_funds, amount)?;
+        self.fees
+            .push(ClientTransaction::new(TransferKind::Interest, amount, tx));
+        Ok(())
+    }
+
+    /// Credits withdrawal `tx` back in full and returns the amount. The refund goes to the fee
+    /// ledger under the id of the withdrawal, which links the two, and the withdrawal can't be
+    /// disputed from then on. A withdrawal under dispute or charged back is refused as its funds
+    /// are on their way back already
+    pub fn refund(&mut self, tx: TxId, policy: &Policy) -> Result<Currency, TransactionError> {
+        if self.locked && policy.locked_accounts != LockedAccountPolicy::AcceptDeposits {
+            return Err(TransactionError::AccountLocked);
+        }
+        let t = *self
+            .find_transfer(tx)
+            .ok_or(TransactionError::InvalidTxId)?;
+        if t.kind != TransferKind::Withdrawal {
+            return Err(TransactionError::NotRefundable);
+        }
+        if self.is_refunded(tx) {
+            return Err(TransactionError::AlreadyRefunded);
+        }
+        match self.dispute_state(tx) {
+            DisputeState::Undisputed | DisputeState::Resolved => {}
+            DisputeState::Disputed => return Err(TransactionError::AlreadyDisputed),
+            DisputeState::ChargedBack => return Err(TransactionError::DisputeSettled),
+        }
+        let amount = t.disputed_amount();
+        self.available_funds = add(self.available_funds, amount)?;
+        self.fees
+            .push(ClientTransaction::new(TransferKind::Refund, amount, tx));
+        Ok(amount)
+    }
+
+    /// Whether withdrawal `tx` was refunded
+    pub fn is_refunded(&self, tx: TxId) -> bool {
+        self.fees
+            .iter()
+            .any(|f| f.kind == TransferKind::Refund && f.tx == tx)
+    }
+
+    pub fn account_type(&self) -> AccountType {
+        self.account_type
+    }
+
+    /// Number of transfers moved out of the active history into an archive
+    pub fn archived(&self) -> usize {
+        self.archived
+    }
+
+    pub fn set_account_type(&mut self, account_type: AccountType) {
+        self.account_type = account_type;
+    }
+
+    /// Removes all but the newest `keep_last` transfers from the active history and returns them
+    pub fn archive_transfers(&mut self, keep_last: usize) -> Vec<ClientTransaction> {
+        let cut = self.transfers.len().saturating_sub(keep_last);
+        self.archived += cut;
+        let archived = self.transfers.drain(..cut).collect();
+        if cut > 0 {
+            self.transfers.shrink_to_fit();
+            if self.index.is_some() {
+                self.index_transfers();
+            }
+        }
+        archived
+    }
+
+    /// Trims the active history down to the newest `keep_last` transfers and fees, together with
+    /// the memos and settled disputes of the transfers that leave memory. Refunds stay as long as
+    /// their withdrawal does and the chargebacks of a locked account stay so it can be unlocked
+    pub fn trim_history(&mut self, keep_last: usize) -> Trimmed {
+        let mut entries = self.archive_transfers(keep_last);
+        let active: HashSet<TxId> = self.transfers.iter().map(|t| t.tx).collect();
+        let cut = self.fees.len().saturating_sub(keep_last);
+        let mut kept = Vec::with_capacity(self.fees.len() - cut);
+        for (i, fee) in self.fees.drain(..).enumerate() {
+            let refunded = fee.kind == TransferKind::Refund && active.contains(&fee.tx);
+            if i < cut && !refunded {
+                entries.push(fee);
+            } else {
+                kept.push(fee);
+            }
+        }
+        self.fees = kept;
+        let settled = self.take_settled(&active);
+        self.memos.retain(|(tx, _)| active.contains(tx));
+        if self.index.is_some() {
+            self.index_transfers();
+        }
+        Trimmed { entries, settled }
+    }
+
+    /// Removes how the disputes of the transfers that left memory ended and returns it, the
+    /// chargebacks of a locked account stay so it can be unlocked
+    #[cfg(feature = "std-io")]
+    pub(crate) fn take_archived_settled(&mut self) -> Vec<(TxId, DisputeState)> {
+        let active: HashSet<TxId> = self.transfers.iter().map(|t| t.tx).collect();
+        let settled = self.take_settled(&active);
+        if !settled.is_empty() && self.index.is_some() {
+            self.index_transfers();
+        }
+        settled
+    }
+
+    fn take_settled(&mut self, active: &HashSet<TxId>) -> Vec<(TxId, DisputeState)> {
+        let locked = self.locked;
+        let mut settled = Vec::new();
+        self.settled.retain(|&tx, &mut state| {
+            let keep = active.contains(&tx) || locked && state == DisputeState::ChargedBack;
+            if !keep {
+                settled.push((tx, state));
+            }
+            keep
+        });
+        settled
+    }
+
+    /// Removes the transfers that took place before `cutoff` from the active history and returns
+    /// them. Transfers without a timestamp are kept as their age is unknown
+    pub fn archive_transfers_before(&mut self, cutoff: Timestamp) -> Vec<ClientTransaction> {
+        let (archived, kept): (Vec<_>, Vec<_>) = self
+            .transfers
+            .drain(..)
+            .partition(|t| t.at.is_some_and(|at| at < cutoff));
+        self.transfers = kept;
+        self.archived += archived.len();
+        if !archived.is_empty() && self.index.is_some() {
+            self.index_transfers();
+        }
+        archived
+    }
+
+    /// Deposits and withdrawals still in memory, oldest first. Archived transfers are not included
+    pub fn history(&self) -> &[ClientTransaction] {
+        &self.transfers
+    }
+
+    /// Transfers under dispute, in the order the disputes were opened
+    pub fn open_disputes(&self) -> &[ClientTransaction] {
+        &self.disputes
+    }
+
+    /// Fees charged to the client and interest credited to it, oldest first
+    pub fn fees(&self) -> &[ClientTransaction] {
+        &self.fees
+    }
+
+    /// Memos of the transfers that came with one, in the order the transfers were applied
+    pub fn memos(&self) -> &[(TxId, Memo)] {
+        &self.memos
+    }
+
+    /// Number of entries kept in memory for this client
+    pub fn history_len(&self) -> usize {
+        self.transfers.len()
+            + self.disputes.len()
+            + self.settled.len()
+            + self.fees.len()
+            + self.bookings.len()
+            + self.memos.len()
+    }
+
+    /// Brings an archived transfer back into the active history so it can be disputed again, or
+    /// an archived fee or refund back into the fee ledger
+    pub fn recall(&mut self, transfer: ClientTransaction) {
+        let fee = matches!(
+            transfer.kind,
+            TransferKind::Fee | TransferKind::Interest | TransferKind::Refund
+        );
+        if fee && !self.fees.contains(&transfer) {
+            self.fees.push(transfer);
+        } else if !fee && self.find_transfer(transfer.tx).is_none() {
+            self.archived = self.archived.saturating_sub(1);
+            self.append_transfer(transfer);
+        }
+    }
+
+    /// Restores how the dispute of a recalled transfer ended
+    pub fn recall_settled(&mut self, tx: TxId, state: DisputeState) {
+        if self.dispute_state(tx) == DisputeState::Undisputed {
+            self.settled.insert(tx, state);
+            self.index_dispute(tx, state);
+        }
+    }
+
+    /// Adds the balances and history of `other` to this account, used to combine internal accounts
+    /// such as the house account kept separately by several engines
+    pub fn absorb(&mut self, other: ClientInfo) -> Result<(), TransactionError> {
+        let available = add(self.available_funds, other.available_funds)?;
+        let held = add(self.held_funds, other.held_funds)?;
+        let booked = add(self.booked_funds, other.booked_funds)?;
+        self.available_funds = available;
+        self.held_funds = held;
+        self.booked_funds = booked;
+        self.locked |= other.locked;
+        self.bookings.extend(other.bookings);
+        self.legal_holds.extend(other.legal_holds);
+        self.transfers.extend(other.transfers);
+        self.disputes.extend(other.disputes);
+        self.settled.extend(other.settled);
+        self.fees.extend(other.fees);
+        self.archived += other.archived;
+        let indexed = self.index.is_some() || other.index.is_some();
+        if indexed || self.transfers.len() >= Self::INDEX_THRESHOLD {
+            self.index_transfers();
+        }
+        Ok(())
+    }
+
+    /// Writes the whole state as snapshot records of the form `section, owner, fields...`
+    /// An `account` record with the balances is followed by one record per history entry
+    #[cfg(feature = "std-io")]
+    pub(crate) fn write_snapshot<W: Write>(
+        &self,
+        w: &mut W,
+        owner: impl fmt::Display,
+    ) -> io::Result<()> {
+        writeln!(
+            w,
+            "account, {}, {}, {}, {}, {}, {}",
+            owner,
+            self.available_funds,
+            self.held_funds,
+            self.locked,
+            self.account_type.name(),
+            self.archived
+        )?;
+        let history = [
+            ("transfer", &self.transfers),
+            ("dispute", &self.disputes),
+            ("fee", &self.fees),
+        ];
+        for (section, entries) in history.iter() {
+            for t in entries.iter() {
+                write!(
+                    w,
+                    "{}, {}, {}, {}, {}",
+                    section,
+                    owner,
+                    t.tx,
+                    t.kind.name(),
+                    t.amount
+                )?;
+                match t.at {
+                    Some(at) => writeln!(w, ", {}", at)?,
+                    None => writeln!(w)?,
+                }
+            }
+        }
+        for (tx, state) in &self.settled {
+            writeln!(w, "settled, {}, {}, {}", owner, tx, state.name())?;
+        }
+        for (order, amount) in &self.legal_holds {
+            writeln!(w, "legal_hold, {}, {}, {}", owner, order, amount)?;
+        }
+        for (tx, memo) in &self.memos {
+            writeln!(w, "memo, {}, {}, {}", owner, tx, quote(memo.as_str()))?;
+        }
+        for (release, t) in &self.bookings {
+            let (section, due) = match release {
+                Release::ValueDate(value_date) => ("booking", value_date),
+                Release::ClearingDay(day) => ("clearing", day),
+            };
+            write!(
+                w,
+                "{}, {}, {}, {}, {}, {}",
+                section,
+                owner,
+                t.tx,
+                t.kind.name(),
+                t.amount,
+                due
+            )?;
+            match t.at {
+                Some(at) => writeln!(w, ", {}", at)?,
+                None => writeln!(w)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the account still holds nothing worth keeping in a snapshot
+    pub(crate) fn is_pristine(&self) -> bool {
+        !self.exists()
+            && self.history_len() == 0
+            && self.available_funds == Currency::ZERO
+            && self.held_funds == Currency::ZERO
+            && !self.locked
+            && self.account_type == AccountType::Standard
+    }
+
+    /// Restores a record written by `write_snapshot`, `fields` are the ones following the owner
+    /// Returns `None` if the record is malformed
+    #[cfg(feature = "std-io")]
+    pub(crate) fn read_snapshot(&mut self, section: &str, fields: &[&str]) -> Option<()> {
+        match (section, fields) {
+            ("account", [available, held, locked, account_type, archived]) => {
+                self.available_funds = available.parse().ok()?;
+                self.held_funds = held.parse().ok()?;
+                self.locked = locked.parse().ok()?;
+                self.account_type = AccountType::from_name(account_type)?;
+                self.archived = archived.parse().ok()?;
+            }
+            ("settled", [tx, state]) => {
+                let state = DisputeState::from_name(state).filter(|state| {
+                    matches!(state, DisputeState::Resolved | DisputeState::ChargedBack)
+                })?;
+                let tx = tx.parse().ok()?;
+                self.settled.insert(tx, state);
+                self.index_dispute(tx, state);
+            }
+            ("legal_hold", [order, amount]) => {
+                let hold = (order.parse().ok()?, amount.parse().ok()?);
+                self.legal_holds.push(hold);
+            }
+            ("memo", [tx, memo]) => {
+                let memo = (tx.parse().ok()?, memo.parse().ok()?);
+                self.memos.push(memo);
             }
+            (section @ ("booking" | "clearing"), [tx, kind, amount, due, ref at @ ..])
+                if at.len() <= 1 =>
+            {
+                let mut entry = ClientTransaction::new(
+                    TransferKind::from_name(kind)?,
+                    amount.parse().ok()?,
+                    tx.parse().ok()?,
+                );
+                if let [at] = at {
+                    entry.at = Some(at.parse().ok()?);
+                }
+                self.booked_funds = add(self.booked_funds, entry.amount).ok()?;
+                let due = due.parse().ok()?;
+                let release = match section {
+                    "booking" => Release::ValueDate(due),
+                    _ => Release::ClearingDay(due),
+                };
+                self.bookings.push((release, entry));
+            }
+            (section, [tx, kind, amount, ref at @ ..]) if at.len() <= 1 => {
+                let mut entry = ClientTransaction::new(
+                    TransferKind::from_name(kind)?,
+                    amount.parse().ok()?,
+                    tx.parse().ok()?,
+                );
+                if let [at] = at {
+                    entry.at = Some(at.parse().ok()?);
+                }
+                self.restore_entry(section, entry)?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Adds a history entry to the `transfer`, `dispute` or `fee` ledger without touching the
+    /// balances, `None` for any other section
+    #[cfg(any(test, feature = "std-io", feature = "serde"))]
+    pub(crate) fn restore_entry(&mut self, section: &str, entry: ClientTransaction) -> Option<()> {
+        match section {
+            "transfer" => self.append_transfer(entry),
+            "dispute" => {
+                self.disputes.push(entry);
+                self.index_dispute(entry.tx, DisputeState::Disputed);
+            }
+            "fee" => self.fees.push(entry),
+            _ => return None,
         }
-        Err(TransactionError::InvalidTxId)
+        Some(())
+    }
+
+    /// Sets the balances and flags of an account restored from elsewhere, its history is added
+    /// with `restore_entry`
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore_balances(
+        &mut self,
+        available: Currency,
+        held: Currency,
+        locked: bool,
+        account_type: AccountType,
+        archived: usize,
+    ) {
+        self.available_funds = available;
+        self.held_funds = held;
+        self.locked = locked;
+        self.account_type = account_type;
+        self.archived = archived;
     }
 
     pub fn exists(&self) -> bool {
         !self.transfers.is_empty()
+            || !self.bookings.is_empty()
+            || !self.legal_holds.is_empty()
+            || self.archived > 0
     }
 
-    fn total_funds(&self) -> Currency {
-        self.available_funds + self.held_funds
+    pub fn available_funds(&self) -> Currency {
+        self.available_funds
+    }
+
+    pub fn held_funds(&self) -> Currency {
+        self.held_funds
+    }
+
+    /// Funds booked but not available before their value date or clearing day
+    pub fn booked_funds(&self) -> Currency {
+        self.booked_funds
+    }
+
+    /// Available, held and booked funds, which may not fit a `Currency` even when each of them does
+    pub fn total_funds(&self) -> Result<Currency, AggregationOverflow> {
+        Currency::sum_of(
+            "total funds",
+            [self.available_funds, self.held_funds, self.booked_funds],
+        )
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
     }
 }
 
@@ -81,39 +990,286 @@ impl fmt::Display for ClientInfo {
             "{}, {}, {}, {}",
             self.available_funds,
             self.held_funds,
-            self.total_funds(),
+            self.total_funds().map_err(|_| fmt::Error)?,
             self.locked
         )
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TransactionError {
     Overdraw,
     InvalidTxId,
+    AccountLocked,
+    BelowMinimumBalance,
+    DuplicateTxId,
+    NotDisputable,
+    /// A resolve or chargeback references a transaction that was never disputed
+    NotDisputed,
+    /// A dispute references a transaction whose dispute is still open
+    AlreadyDisputed,
+    /// A dispute, resolve or chargeback references a transaction whose dispute was already
+    /// resolved or charged back
+    DisputeSettled,
+    /// The operation would take a balance outside of what `Currency` can represent
+    Overflow,
+    /// The transaction couldn't be written to the write-ahead log and was not applied
+    NotLogged,
+    /// A withdrawal would take the available funds below the amount under legal hold
+    LegalHold,
+    /// The amount of the record is negative
+    InvalidAmount,
+    /// A conversion between currencies without an exchange rate, or involving the base accounts
+    /// while no base currency is set
+    NoRate,
+    /// The transaction breaks one of the risk limits of the client, see `RiskLimits`
+    LimitExceeded,
+    /// An unlock references an account that is not locked
+    NotLocked,
+    /// A batch was opened while another one is open, a commit or rollback doesn't match the open
+    /// batch, or the record can't be part of a batch
+    InvalidBatch,
+    /// A dispute arrives after the dispute window of its transaction closed, see
+    /// `Policy::dispute_window`
+    DisputeExpired,
+    /// A `TransactionInterceptor` refused the transaction
+    Intercepted,
+    /// A refund references a transaction that is not a withdrawal
+    NotRefundable,
+    /// A refund references a withdrawal that was refunded already
+    AlreadyRefunded,
+    /// The held funds don't match the open disputes, a resolve or chargeback would release more
+    /// than is held
+    InconsistentHold,
+    /// An approver signed off a pending transaction they already approved
+    AlreadyApproved,
+}
+
+impl From<AggregationOverflow> for TransactionError {
+    fn from(_: AggregationOverflow) -> Self {
+        TransactionError::Overflow
+    }
+}
+
+fn add(lhs: Currency, rhs: Currency) -> Result<Currency, TransactionError> {
+    lhs.checked_add(rhs).ok_or(TransactionError::Overflow)
+}
+
+fn sub(lhs: Currency, rhs: Currency) -> Result<Currency, TransactionError> {
+    lhs.checked_sub(rhs).ok_or(TransactionError::Overflow)
+}
+
+/// Kind of account a client holds, used to pick the per-type rules of the policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AccountType {
+    #[default]
+    Standard,
+    Savings,
+    Business,
+}
+
+impl AccountType {
+    /// Name used in the files written by the engine
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountType::Standard => "standard",
+            AccountType::Savings => "savings",
+            AccountType::Business => "business",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(AccountType::Standard),
+            "savings" => Some(AccountType::Savings),
+            "business" => Some(AccountType::Business),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum TransferKind {
+    Deposit,
+    Withdrawal,
+    Fee,
+    /// Either leg of a currency conversion, conversions can't be disputed
+    Conversion,
+    /// Interest credited by a schedule, see `Schedules`
+    Interest,
+    /// Withdrawal credited back by a refund, kept in the fee ledger under the id of the withdrawal
+    Refund,
+}
+
+impl TransferKind {
+    /// Name used in the files written by the engine
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferKind::Deposit => "deposit",
+            TransferKind::Withdrawal => "withdrawal",
+            TransferKind::Fee => "fee",
+            TransferKind::Conversion => "conversion",
+            TransferKind::Interest => "interest",
+            TransferKind::Refund => "refund",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deposit" => Some(TransferKind::Deposit),
+            "withdrawal" => Some(TransferKind::Withdrawal),
+            "fee" => Some(TransferKind::Fee),
+            "conversion" => Some(TransferKind::Conversion),
+            "interest" => Some(TransferKind::Interest),
+            "refund" => Some(TransferKind::Refund),
+            _ => None,
+        }
+    }
+}
+
+/// Stage of the dispute lifecycle of a transaction, disputes go from `Undisputed` to `Disputed`
+/// and end as `Resolved` or `ChargedBack`. The only way back is an unlock reversing a chargeback,
+/// which leaves the dispute `Resolved`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DisputeState {
+    Undisputed,
+    Disputed,
+    Resolved,
+    ChargedBack,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl DisputeState {
+    /// Name used in the files written by the engine
+    pub fn name(self) -> &'static str {
+        match self {
+            DisputeState::Undisputed => "undisputed",
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged_back",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "undisputed" => Some(DisputeState::Undisputed),
+            "disputed" => Some(DisputeState::Disputed),
+            "resolved" => Some(DisputeState::Resolved),
+            "charged_back" => Some(DisputeState::ChargedBack),
+            _ => None,
+        }
+    }
+}
+
+/// When booked funds become available
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Release {
+    /// Once the engine clock reaches the value date of a booking
+    ValueDate(u64),
+    /// At the clearing sweep closing the given business day, see `ClearingDelay`
+    ClearingDay(u64),
+}
+
+/// History entries moved out of memory by `ClientInfo::trim_history`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trimmed {
+    /// Transfers, fees, interest and refunds
+    pub entries: Vec<ClientTransaction>,
+    /// How the disputes of the trimmed transfers ended
+    pub settled: Vec<(TxId, DisputeState)>,
+}
+
+impl Trimmed {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.settled.is_empty()
+    }
+}
+
+/// Amounts are stored signed by their effect on the available funds, so withdrawals and fees are negative
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClientTransaction {
     tx: TxId,
+    kind: TransferKind,
     amount: Currency,
+    /// When the transaction took place, if the input gave a timestamp
+    at: Option<Timestamp>,
 }
 
 impl ClientTransaction {
-    fn new(amount: Currency, tx: TxId) -> Self {
-        Self { tx, amount }
+    pub fn new(kind: TransferKind, amount: Currency, tx: TxId) -> Self {
+        Self {
+            tx,
+            kind,
+            amount,
+            at: None,
+        }
+    }
+
+    pub fn at(&self) -> Option<Timestamp> {
+        self.at
+    }
+
+    /// The same transaction taking place at `at`
+    pub fn stamped(self, at: Option<Timestamp>) -> Self {
+        Self { at, ..self }
+    }
+
+    pub fn tx(&self) -> TxId {
+        self.tx
+    }
+
+    pub fn kind(&self) -> TransferKind {
+        self.kind
+    }
+
+    pub fn amount(&self) -> Currency {
+        self.amount
+    }
+
+    /// The positive amount put on hold when this transaction is disputed
+    pub(crate) fn disputed_amount(&self) -> Currency {
+        match self.kind {
+            // Conversions, interest and refunds are never disputed
+            TransferKind::Deposit
+            | TransferKind::Conversion
+            | TransferKind::Interest
+            | TransferKind::Refund => self.amount,
+            TransferKind::Withdrawal | TransferKind::Fee => -self.amount,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::MinimumBalances;
 
     #[test]
     fn handle_deposit() {
         let amount = Currency::new(5000);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
+        clinfo.deposit(amount, 1, &policy).unwrap();
         assert_eq!(clinfo.available_funds, amount);
         assert_eq!(clinfo.transfers[0].amount, amount);
         assert_eq!(clinfo.transfers[0].tx, 1);
@@ -124,9 +1280,10 @@ mod tests {
         let amount = Currency::new(5000);
         let amount2 = Currency::new(1000);
         let amount3 = Currency::new(4000);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
-        clinfo.withdraw(amount2, 2).unwrap();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.withdraw(amount2, 2, &policy).unwrap();
         assert_eq!(clinfo.available_funds, amount3);
         assert_eq!(clinfo.transfers[1].amount, -amount2);
         assert_eq!(clinfo.transfers[1].tx, 2);
@@ -136,9 +1293,10 @@ mod tests {
     fn handle_withdraw_not_enough_money() {
         let amount = Currency::new(5000);
         let amount2 = Currency::new(6000);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
-        assert!(clinfo.withdraw(amount2, 2).is_err());
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        assert!(clinfo.withdraw(amount2, 2, &policy).is_err());
         assert_eq!(clinfo.available_funds, amount);
         assert_eq!(clinfo.transfers.len(), 1);
     }
@@ -147,12 +1305,13 @@ mod tests {
     fn handle_dispute() {
         let amount = Currency::new(5000);
         let amount0 = Currency::new(0);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
-        clinfo.dispute(1).unwrap();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
         assert_eq!(clinfo.available_funds, amount0);
         assert_eq!(clinfo.held_funds, amount);
-        assert_eq!(clinfo.total_funds(), amount);
+        assert_eq!(clinfo.total_funds().unwrap(), amount);
         assert_eq!(clinfo.disputes[0].amount, amount);
         assert_eq!(clinfo.disputes[0].tx, 1);
     }
@@ -161,25 +1320,426 @@ mod tests {
     fn handle_resolve() {
         let amount = Currency::new(5000);
         let amount0 = Currency::new(0);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
-        clinfo.dispute(1).unwrap();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
         clinfo.resolve(1).unwrap();
         assert_eq!(clinfo.available_funds, amount);
         assert_eq!(clinfo.held_funds, amount0);
-        assert_eq!(clinfo.total_funds(), amount);
+        assert_eq!(clinfo.total_funds().unwrap(), amount);
+    }
+
+    #[test]
+    fn long_histories_get_indexed() {
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        for tx in 1..ClientInfo::INDEX_THRESHOLD as TxId {
+            clinfo.deposit(Currency::new(100), tx, &policy).unwrap();
+        }
+        clinfo.dispute(1, &policy).unwrap();
+        clinfo.dispute(2, &policy).unwrap();
+        clinfo.resolve(1).unwrap();
+        assert!(!clinfo.is_indexed());
+        let last = ClientInfo::INDEX_THRESHOLD as TxId;
+        clinfo.deposit(Currency::new(100), last, &policy).unwrap();
+        assert!(clinfo.is_indexed());
+        assert_eq!(clinfo.dispute_state(1), DisputeState::Resolved);
+        assert_eq!(clinfo.dispute_state(2), DisputeState::Disputed);
+        assert_eq!(clinfo.dispute_state(3), DisputeState::Undisputed);
+        clinfo.dispute(last, &policy).unwrap();
+        clinfo.chargeback(2).unwrap();
+        assert!(clinfo.is_disputed(last));
+        assert_eq!(clinfo.dispute_state(2), DisputeState::ChargedBack);
+        assert_eq!(clinfo.find_transfer(last).unwrap().tx, last);
     }
 
     #[test]
     fn handle_chargeback() {
         let amount = Currency::new(5000);
         let amount0 = Currency::new(0);
+        let policy = Policy::default();
         let mut clinfo = ClientInfo::default();
-        clinfo.deposit(amount, 1);
-        clinfo.dispute(1).unwrap();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
         clinfo.chargeback(1).unwrap();
         assert_eq!(clinfo.available_funds, amount0);
         assert_eq!(clinfo.held_funds, amount0);
-        assert_eq!(clinfo.total_funds(), amount0);
+        assert_eq!(clinfo.total_funds().unwrap(), amount0);
+    }
+
+    #[test]
+    fn disputes_follow_their_lifecycle() {
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(Currency::new(5000), 1, &policy).unwrap();
+        clinfo.deposit(Currency::new(3000), 2, &policy).unwrap();
+        assert_eq!(clinfo.dispute_state(1), DisputeState::Undisputed);
+        clinfo.dispute(1, &policy).unwrap();
+        assert_eq!(
+            clinfo.dispute(1, &policy),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        clinfo.resolve(1).unwrap();
+        assert_eq!(clinfo.dispute_state(1), DisputeState::Resolved);
+        assert!(clinfo.open_disputes().is_empty());
+        assert_eq!(clinfo.resolve(1), Err(TransactionError::DisputeSettled));
+        assert_eq!(clinfo.chargeback(1), Err(TransactionError::DisputeSettled));
+        assert_eq!(
+            clinfo.dispute(1, &policy),
+            Err(TransactionError::DisputeSettled)
+        );
+        assert_eq!(clinfo.available_funds, Currency::new(8000));
+
+        clinfo.dispute(2, &policy).unwrap();
+        clinfo.chargeback(2).unwrap();
+        assert_eq!(clinfo.dispute_state(2), DisputeState::ChargedBack);
+        assert_eq!(clinfo.resolve(2), Err(TransactionError::DisputeSettled));
+        assert_eq!(clinfo.to_string(), "0.5000, 0.0000, 0.5000, true");
+    }
+
+    #[test]
+    fn handle_charge_fee() {
+        let amount = Currency::new(5000);
+        let fee = Currency::new(1000);
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.charge_fee(fee, 1).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(4000));
+        assert_eq!(clinfo.transfers.len(), 1);
+        assert_eq!(clinfo.fees[0].amount, -fee);
+        assert_eq!(clinfo.fees[0].tx, 1);
+    }
+
+    #[test]
+    fn locked_account_rejects_operations() {
+        let amount = Currency::new(5000);
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.deposit(amount, 2, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
+        clinfo.chargeback(1).unwrap();
+        assert!(matches!(
+            clinfo.deposit(amount, 3, &policy),
+            Err(TransactionError::AccountLocked)
+        ));
+        assert!(matches!(
+            clinfo.withdraw(amount, 4, &policy),
+            Err(TransactionError::AccountLocked)
+        ));
+        assert!(matches!(
+            clinfo.dispute(2, &policy),
+            Err(TransactionError::AccountLocked)
+        ));
+        assert_eq!(clinfo.available_funds, amount);
+        assert_eq!(clinfo.transfers.len(), 2);
+    }
+
+    #[test]
+    fn locked_account_accepts_deposits_when_allowed() {
+        let amount = Currency::new(5000);
+        let policy = Policy {
+            locked_accounts: LockedAccountPolicy::AcceptDeposits,
+            ..Policy::default()
+        };
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(amount, 1, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
+        clinfo.chargeback(1).unwrap();
+        clinfo.deposit(amount, 2, &policy).unwrap();
+        assert_eq!(clinfo.available_funds, amount);
+        assert!(matches!(
+            clinfo.withdraw(amount, 3, &policy),
+            Err(TransactionError::AccountLocked)
+        ));
+    }
+
+    #[test]
+    fn withdraw_respects_minimum_balance() {
+        let policy = Policy {
+            minimum_balances: MinimumBalances {
+                savings: Currency::new(2000),
+                ..MinimumBalances::default()
+            },
+            ..Policy::default()
+        };
+        let mut clinfo = ClientInfo::default();
+        clinfo.set_account_type(AccountType::Savings);
+        clinfo.deposit(Currency::new(5000), 1, &policy).unwrap();
+        assert!(matches!(
+            clinfo.withdraw(Currency::new(4000), 2, &policy),
+            Err(TransactionError::BelowMinimumBalance)
+        ));
+        clinfo.withdraw(Currency::new(3000), 3, &policy).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(2000));
+
+        let mut standard = ClientInfo::default();
+        standard.deposit(Currency::new(5000), 1, &policy).unwrap();
+        standard.withdraw(Currency::new(4000), 2, &policy).unwrap();
+        assert_eq!(standard.available_funds, Currency::new(1000));
+    }
+
+    #[test]
+    fn withdraw_per_overdraw_policy() {
+        let withdraw = |overdraw, amount| {
+            let policy = Policy {
+                overdraw,
+                ..Policy::default()
+            };
+            let mut clinfo = ClientInfo::default();
+            clinfo.deposit(Currency::new(5000), 1, &policy).unwrap();
+            clinfo
+                .withdraw(Currency::new(amount), 2, &policy)
+                .map(|()| clinfo.available_funds)
+        };
+        assert_eq!(
+            withdraw(OverdrawPolicy::Strict, 5000),
+            Err(TransactionError::Overdraw)
+        );
+        assert_eq!(withdraw(OverdrawPolicy::Strict, 4999), Ok(Currency::new(1)));
+        assert_eq!(
+            withdraw(OverdrawPolicy::AllowExact, 5000),
+            Ok(Currency::ZERO)
+        );
+        assert_eq!(
+            withdraw(OverdrawPolicy::AllowExact, 5001),
+            Err(TransactionError::Overdraw)
+        );
+        let overdraft = OverdrawPolicy::Overdraft(Currency::new(3000));
+        assert_eq!(withdraw(overdraft, 8000), Ok(Currency::new(-3000)));
+        assert_eq!(withdraw(overdraft, 8001), Err(TransactionError::Overdraw));
+    }
+
+    #[test]
+    fn legal_holds_block_overdrafts() {
+        let policy = Policy {
+            overdraw: OverdrawPolicy::Overdraft(Currency::new(3000)),
+            ..Policy::default()
+        };
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(Currency::new(5000), 1, &policy).unwrap();
+        clinfo
+            .place_legal_hold(9, Some(Currency::new(1000)))
+            .unwrap();
+        assert!(matches!(
+            clinfo.withdraw(Currency::new(6000), 2, &policy),
+            Err(TransactionError::LegalHold)
+        ));
+        clinfo.withdraw(Currency::new(4000), 3, &policy).unwrap();
+        clinfo.release_legal_hold(9).unwrap();
+        clinfo.withdraw(Currency::new(3500), 4, &policy).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(-2500));
+    }
+
+    fn withdrawn(policy: &Policy) -> ClientInfo {
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(Currency::new(5000), 1, policy).unwrap();
+        clinfo.withdraw(Currency::new(2000), 2, policy).unwrap();
+        clinfo
+    }
+
+    #[test]
+    fn dispute_deposit_with_either_policy() {
+        for disputes in [
+            DisputePolicy::DepositsOnly,
+            DisputePolicy::DepositsAndWithdrawals,
+        ] {
+            let policy = Policy {
+                disputes,
+                ..Policy::default()
+            };
+            let mut clinfo = withdrawn(&policy);
+            clinfo.dispute(1, &policy).unwrap();
+            assert_eq!(clinfo.available_funds, Currency::new(-2000));
+            assert_eq!(clinfo.held_funds, Currency::new(5000));
+            assert_eq!(clinfo.total_funds().unwrap(), Currency::new(3000));
+        }
+    }
+
+    #[test]
+    fn dispute_withdrawal_rejected_when_deposits_only() {
+        let policy = Policy {
+            disputes: DisputePolicy::DepositsOnly,
+            ..Policy::default()
+        };
+        let mut clinfo = withdrawn(&policy);
+        assert!(matches!(
+            clinfo.dispute(2, &policy),
+            Err(TransactionError::NotDisputable)
+        ));
+        assert_eq!(clinfo.available_funds, Currency::new(3000));
+        assert_eq!(clinfo.held_funds, Currency::new(0));
+        assert!(clinfo.disputes.is_empty());
+    }
+
+    #[test]
+    fn dispute_withdrawal_holds_withdrawn_amount() {
+        let policy = Policy {
+            disputes: DisputePolicy::DepositsAndWithdrawals,
+            ..Policy::default()
+        };
+        let mut clinfo = withdrawn(&policy);
+        clinfo.dispute(2, &policy).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(3000));
+        assert_eq!(clinfo.held_funds, Currency::new(2000));
+        assert_eq!(clinfo.total_funds().unwrap(), Currency::new(5000));
+    }
+
+    #[test]
+    fn resolve_disputed_withdrawal() {
+        let policy = Policy::default();
+        let mut clinfo = withdrawn(&policy);
+        clinfo.dispute(2, &policy).unwrap();
+        clinfo.resolve(2).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(3000));
+        assert_eq!(clinfo.held_funds, Currency::new(0));
+        assert!(!clinfo.locked);
+    }
+
+    #[test]
+    fn chargeback_disputed_withdrawal() {
+        let policy = Policy::default();
+        let mut clinfo = withdrawn(&policy);
+        clinfo.dispute(2, &policy).unwrap();
+        clinfo.chargeback(2).unwrap();
+        assert_eq!(clinfo.available_funds, Currency::new(5000));
+        assert_eq!(clinfo.held_funds, Currency::new(0));
+        assert!(clinfo.locked);
+    }
+
+    #[test]
+    fn unlock_reinstates_and_optionally_reverses() {
+        let policy = Policy::default();
+        let mut clinfo = withdrawn(&policy);
+        assert_eq!(clinfo.unlock(2, false), Err(TransactionError::NotLocked));
+        clinfo.dispute(2, &policy).unwrap();
+        clinfo.chargeback(2).unwrap();
+        let mut reinstated = clinfo.clone();
+        assert_eq!(clinfo.unlock(1, false), Err(TransactionError::InvalidTxId));
+        reinstated.unlock(2, false).unwrap();
+        assert!(!reinstated.locked);
+        assert_eq!(reinstated.available_funds, Currency::new(5000));
+        assert_eq!(reinstated.dispute_state(2), DisputeState::ChargedBack);
+
+        clinfo.unlock(2, true).unwrap();
+        assert!(!clinfo.locked);
+        assert_eq!(clinfo.available_funds, Currency::new(3000));
+        assert_eq!(clinfo.dispute_state(2), DisputeState::Resolved);
+    }
+
+    #[test]
+    fn refund_credits_a_withdrawal_once() {
+        let policy = Policy::default();
+        let mut clinfo = withdrawn(&policy);
+        assert_eq!(
+            clinfo.refund(1, &policy),
+            Err(TransactionError::NotRefundable)
+        );
+        assert_eq!(
+            clinfo.refund(3, &policy),
+            Err(TransactionError::InvalidTxId)
+        );
+        assert_eq!(clinfo.refund(2, &policy), Ok(Currency::new(2000)));
+        assert_eq!(clinfo.available_funds, Currency::new(5000));
+        let refund = clinfo.fees[0];
+        assert_eq!(
+            (refund.kind, refund.amount, refund.tx),
+            (TransferKind::Refund, Currency::new(2000), 2)
+        );
+        assert_eq!(
+            clinfo.refund(2, &policy),
+            Err(TransactionError::AlreadyRefunded)
+        );
+        assert_eq!(
+            clinfo.dispute(2, &policy),
+            Err(TransactionError::NotDisputable)
+        );
+
+        let mut disputed = withdrawn(&policy);
+        disputed.dispute(2, &policy).unwrap();
+        assert_eq!(
+            disputed.refund(2, &policy),
+            Err(TransactionError::AlreadyDisputed)
+        );
+        disputed.resolve(2).unwrap();
+        disputed.refund(2, &policy).unwrap();
+    }
+
+    #[test]
+    fn archive_and_recall_transfers() {
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        for tx in 1..=3 {
+            clinfo.deposit(Currency::new(1000), tx, &policy).unwrap();
+        }
+        let archived = clinfo.archive_transfers(1);
+        assert_eq!(archived.len(), 2);
+        assert_eq!(archived[0].tx, 1);
+        assert_eq!(clinfo.transfers.len(), 1);
+        assert!(matches!(
+            clinfo.dispute(1, &policy),
+            Err(TransactionError::InvalidTxId)
+        ));
+        clinfo.archive_transfers(0);
+        assert!(clinfo.exists());
+        clinfo.recall(archived[0]);
+        clinfo.dispute(1, &policy).unwrap();
+        assert_eq!(clinfo.held_funds, Currency::new(1000));
+    }
+
+    #[test]
+    fn overflow_is_rejected() {
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(Currency::MAX, 1, &policy).unwrap();
+        assert!(matches!(
+            clinfo.deposit(Currency::new(1), 2, &policy),
+            Err(TransactionError::Overflow)
+        ));
+        assert_eq!(clinfo.available_funds, Currency::MAX);
+        assert_eq!(clinfo.transfers.len(), 1);
+        assert!(matches!(
+            clinfo.withdraw(Currency::new(-1), 3, &policy),
+            Err(TransactionError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn chargeback_with_fee_is_atomic() {
+        let policy = Policy::default();
+        let mut clinfo = ClientInfo::default();
+        clinfo.deposit(Currency::new(5000), 1, &policy).unwrap();
+        clinfo.dispute(1, &policy).unwrap();
+        assert!(matches!(
+            clinfo.chargeback_with_fee(1, Currency::MIN),
+            Err(TransactionError::Overflow)
+        ));
+        assert!(!clinfo.locked);
+        assert_eq!(clinfo.held_funds, Currency::new(5000));
+        clinfo.chargeback_with_fee(1, Currency::new(1000)).unwrap();
+        assert!(clinfo.locked);
+        assert_eq!(clinfo.available_funds, Currency::new(-1000));
+        assert_eq!(clinfo.fees[0].amount, Currency::new(-1000));
+    }
+
+    #[test]
+    fn drifted_hold_is_not_released() {
+        let policy = Policy::default();
+        let mut clinfo = withdrawn(&policy);
+        clinfo.dispute(2, &policy).unwrap();
+        assert_eq!(clinfo.check_hold(), Ok(()));
+        clinfo.held_funds = Currency::new(1000);
+        assert_eq!(clinfo.check_hold(), Err(TransactionError::InconsistentHold));
+        assert_eq!(clinfo.resolve(2), Err(TransactionError::InconsistentHold));
+        assert_eq!(
+            clinfo.chargeback(2),
+            Err(TransactionError::InconsistentHold)
+        );
+        assert_eq!(clinfo.available_funds, Currency::new(3000));
+        assert_eq!(clinfo.held_funds, Currency::new(1000));
+        assert!(!clinfo.locked);
+        assert_eq!(clinfo.open_disputes().len(), 1);
     }
 }
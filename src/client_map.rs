@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    csv_parser::{parse_fields, split_fields, with_currency_code, ParseCSVError},
+    currency::{CurrencyCode, CurrencyConfig},
+    payment_engine::ClientTable,
+    transaction::{ClientId, Transaction},
+    version::{CompatCheck, Stamp},
+};
+
+/// Maps external account identifiers (IBANs, UUIDs, ...) to the dense internal `ClientId`s used by
+/// the engine, so feeds keyed by arbitrary strings can be processed without preprocessing.
+/// Ids are handed out in order of first appearance and the table is persisted as a stamped csv file
+/// of `external, client` records so the assignment stays stable across runs
+///
+/// Any string is an identifier, so sub-ledgers whose ids collide or go beyond the range of a
+/// `ClientId` can be merged by prefixing their ids, such as `ledger-a/70000`, and `labels` gives
+/// the report the original identifiers back. Only the external ids are unbounded: the internal
+/// ones are still `u16`s handed out from 0, so a mapping holds at most `ClientId::MAX` distinct
+/// clients and the next one is refused with `ClientIdsExhausted`. `ClientId` itself keeps its
+/// width, it is part of the binary format, the keys of the stores and the FFI
+#[derive(Clone, Debug, Default)]
+pub struct ClientMap {
+    ids: HashMap<String, ClientId>,
+    next: ClientId,
+}
+
+impl ClientMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a previously saved mapping table, a missing file yields an empty mapping
+    pub fn load(path: impl AsRef<Path>, check: CompatCheck) -> io::Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e),
+        };
+        let mut map = Self::new();
+        let mut reader = BufReader::new(file);
+        Stamp::check(&mut reader, check)?;
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            let (external, client) = line
+                .rsplit_once(',')
+                .and_then(|(e, c)| Some((e.trim(), c.trim().parse::<ClientId>().ok()?)))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid client map record: {}", line),
+                    )
+                })?;
+            map.ids.insert(external.to_string(), client);
+            map.next = map.next.max(client.saturating_add(1));
+        }
+        Ok(map)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        Stamp::write(&mut w)?;
+        writeln!(w, "external, client")?;
+        let mut ids: Vec<_> = self.ids.iter().collect();
+        ids.sort_by_key(|(_, &client)| client);
+        for (external, client) in ids {
+            writeln!(w, "{}, {}", external, client)?;
+        }
+        w.flush()
+    }
+
+    /// Returns the internal id of `external`, assigning the next free one on first sight
+    pub fn resolve(&mut self, external: &str) -> Result<ClientId, ParseCSVError> {
+        if let Some(&client) = self.ids.get(external) {
+            return Ok(client);
+        }
+        // `next` can't go past ClientId::MAX, so that id is never handed out
+        if self.next == ClientId::MAX {
+            return Err(ParseCSVError::ClientIdsExhausted);
+        }
+        let client = self.next;
+        self.ids.insert(external.to_string(), client);
+        self.next += 1;
+        Ok(client)
+    }
+
+    pub fn get(&self, external: &str) -> Option<ClientId> {
+        self.ids.get(external).copied()
+    }
+
+    /// External identifier of every internal id handed out, see `ClientTable::set_client_labels`
+    pub fn labels(&self) -> HashMap<ClientId, String> {
+        self.ids
+            .iter()
+            .map(|(external, &client)| (client, external.clone()))
+            .collect()
+    }
+
+    /// Parses a csv line of the extended schema where the client column holds an external identifier
+    pub fn parse_line(&mut self, line: io::Result<String>) -> Result<Transaction, ParseCSVError> {
+        self.parse_line_with(line, CurrencyConfig::default())
+    }
+
+    /// Same as `parse_line` for feeds using a different precision than the default one
+    pub fn parse_line_with(
+        &mut self,
+        line: io::Result<String>,
+        currency: CurrencyConfig,
+    ) -> Result<Transaction, ParseCSVError> {
+        let line = line?;
+        let fields = split_fields(&line)?;
+        let mut fields = fields.iter().map(|f| f.as_ref());
+        let transaction_type = fields.next();
+        let client = match fields.next() {
+            Some(external) if !external.is_empty() => self.resolve(external)?,
+            _ => return Err(ParseCSVError::UnknownRecord),
+        };
+        let tx_id = fields.next();
+        let (code, amount) = match fields.next() {
+            Some(code) if CurrencyCode::is_code(code) => (Some(code), fields.next()),
+            amount => (None, amount),
+        };
+        let value_date = fields.next();
+        let transaction = parse_fields(
+            transaction_type,
+            client,
+            tx_id,
+            amount,
+            value_date,
+            currency,
+        )?;
+        with_currency_code(transaction, code)
+    }
+}
+
+impl ClientTable {
+    /// Shows `labels` instead of the ids in the client column of the report and of the attested
+    /// statement, such as the external identifiers of a `ClientMap`. Clients without a label keep
+    /// their id
+    pub fn set_client_labels(&mut self, labels: HashMap<ClientId, String>) {
+        self.client_labels = labels;
+    }
+
+    /// What the report shows for `client`
+    pub fn client_label(&self, client: ClientId) -> String {
+        match self.client_labels.get(&client) {
+            Some(label) => label.clone(),
+            None => client.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+
+    #[test]
+    fn assigns_dense_ids_in_order() {
+        let mut map = ClientMap::new();
+        assert_eq!(map.resolve("DE89370400440532013000").unwrap(), 0);
+        assert_eq!(map.resolve("GB29NWBK60161331926819").unwrap(), 1);
+        assert_eq!(map.resolve("DE89370400440532013000").unwrap(), 0);
+        assert_eq!(map.get("unknown"), None);
+    }
+
+    #[test]
+    fn parses_extended_schema() {
+        let mut map = ClientMap::new();
+        let tx = map
+            .parse_line(Ok("deposit, 3f2a-uuid, 7, 1.5".to_string()))
+            .unwrap();
+        assert_eq!(
+            tx,
+            Transaction::Deposit {
+                client: 0,
+                tx: 7,
+                amount: Currency::new(15000),
+            }
+        );
+        assert!(map.parse_line(Ok("deposit, , 7, 1.5".to_string())).is_err());
+    }
+
+    #[test]
+    fn report_shows_the_external_ids() {
+        let mut map = ClientMap::new();
+        let mut table = ClientTable::new();
+        for line in [
+            "deposit, ledger-a/70000, 1, 1.5",
+            "deposit, ledger-b/70000, 2, 2.0",
+            "withdrawal, ledger-a/70000, 3, 0.5",
+        ]
+        .iter()
+        {
+            let tx = map.parse_line(Ok(line.to_string())).unwrap();
+            table.handle_transaction(tx).unwrap();
+        }
+        table.set_client_labels(map.labels());
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n\
+             ledger-a/70000, 1.0000, 0.0000, 1.0000, false\n\
+             ledger-b/70000, 2.0000, 0.0000, 2.0000, false\n"
+        );
+    }
+
+    #[test]
+    fn labels_with_separators_are_quoted() {
+        let mut table = ClientTable::new();
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 0,
+                tx: 1,
+                amount: Currency::new(15000),
+            })
+            .unwrap();
+        table.set_client_labels(HashMap::from([(0, "acme, inc".to_string())]));
+        let report = table.to_string();
+        let row = report.lines().nth(1).unwrap();
+        assert_eq!(row, "\"acme, inc\", 1.5000, 0.0000, 1.5000, false");
+        let fields = split_fields(row).unwrap();
+        assert_eq!(fields, ["acme, inc", "1.5000", "0.0000", "1.5000", "false"]);
+    }
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let path = std::env::temp_dir().join(format!("bank_client_map_{}.csv", std::process::id()));
+        let mut map = ClientMap::new();
+        map.resolve("a").unwrap();
+        map.resolve("b").unwrap();
+        map.save(&path).unwrap();
+        let mut loaded = ClientMap::load(&path, CompatCheck::Strict).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("b"), Some(1));
+        assert_eq!(loaded.resolve("c").unwrap(), 2);
+    }
+
+    #[test]
+    fn exhausted_ids_are_reported() {
+        let mut map = ClientMap {
+            ids: HashMap::new(),
+            next: ClientId::MAX,
+        };
+        assert!(matches!(
+            map.resolve("a"),
+            Err(ParseCSVError::ClientIdsExhausted)
+        ));
+    }
+}
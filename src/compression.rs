@@ -0,0 +1,112 @@
+use std::io::{self, BufRead};
+
+/// First bytes of a gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// First bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Whether `head`, the first bytes of an input, starts with the magic of a compressed format
+pub fn is_compressed(head: &[u8]) -> bool {
+    head.starts_with(&GZIP_MAGIC) || head.starts_with(&ZSTD_MAGIC)
+}
+
+/// Wraps `reader` in a decompressor when its first bytes are the magic of a compressed format,
+/// so compressed inputs are read transparently whatever their name. Other inputs are handed back
+/// as they are
+///
+/// Gzip is decoded by flate2 with the `gzip` feature, zstd by the zstd crate with the `zstd`
+/// feature. Concatenated gzip members and zstd frames are read as one input
+pub fn decompress<'a>(mut reader: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+    let head = reader.fill_buf()?;
+    if head.starts_with(&GZIP_MAGIC) {
+        return gzip(reader);
+    }
+    if head.starts_with(&ZSTD_MAGIC) {
+        return zstd(reader);
+    }
+    Ok(reader)
+}
+
+#[cfg(feature = "gzip")]
+fn gzip<'a>(reader: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+    let decoder = flate2::bufread::MultiGzDecoder::new(reader);
+    Ok(Box::new(io::BufReader::new(decoder)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip<'a>(_: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+    Err(unsupported("gzip input needs the gzip feature"))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd<'a>(reader: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+    let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
+    Ok(Box::new(io::BufReader::new(decoder)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd<'a>(_: Box<dyn BufRead + 'a>) -> io::Result<Box<dyn BufRead + 'a>> {
+    Err(unsupported("zstd input needs the zstd feature"))
+}
+
+#[cfg_attr(all(feature = "gzip", feature = "zstd"), allow(dead_code))]
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const RECORDS: &str = "deposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n";
+
+    fn read(input: &[u8]) -> io::Result<String> {
+        let mut out = String::new();
+        decompress(Box::new(input))?.read_to_string(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn plain_input_is_read_as_it_is() {
+        assert_eq!(read(RECORDS.as_bytes()).unwrap(), RECORDS);
+        assert!(!is_compressed(RECORDS.as_bytes()));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_members_are_concatenated_and_checked() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+        let (first, second) = RECORDS.split_at(RECORDS.len() / 2);
+        let mut gzipped = Vec::new();
+        for part in [first, second] {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            gzipped.extend(encoder.finish().unwrap());
+        }
+        assert!(is_compressed(&gzipped));
+        assert_eq!(read(&gzipped).unwrap(), RECORDS);
+        let crc = gzipped.len() - 8;
+        gzipped[crc] ^= 1;
+        assert!(read(&gzipped).is_err());
+        assert!(read(&gzipped[..20]).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_frames_are_decoded() {
+        let mut compressed = zstd::encode_all(RECORDS.as_bytes(), 0).unwrap();
+        assert!(is_compressed(&compressed));
+        assert_eq!(read(&compressed).unwrap(), RECORDS);
+        compressed.truncate(compressed.len() - 1);
+        assert!(read(&compressed).is_err());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn zstd_needs_its_feature() {
+        let error = read(&[0x28, 0xb5, 0x2f, 0xfd, 0]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
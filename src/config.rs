@@ -0,0 +1,395 @@
+#[cfg(feature = "config")]
+use std::{fmt, fs, io, path::Path};
+use std::{ops::RangeInclusive, path::PathBuf};
+
+#[cfg(feature = "config")]
+use serde::{Deserialize, Deserializer};
+
+#[cfg(feature = "config")]
+use crate::serialization::from_name;
+use crate::{
+    builder::{ClientTableBuilder, ConfigError},
+    credit::CreditLines,
+    currency::{Currency, CurrencyCode, CurrencyConfig, Rounding},
+    error::EngineError,
+    fees::FeeSchedule,
+    payment_engine::ClientTable,
+    policy::{
+        ClearingDelay, DedupPolicy, DisputeRouting, OverdrawPolicy, Policy, SettlementPolicy,
+    },
+    report::{ReportOptions, ReportOrder},
+    risk::RiskLimits,
+    transaction::ClientId,
+};
+
+/// Seconds in the days of `PolicySettings::dispute_window`
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Settings of a run, read from a `--config` file whose sections are the fields, and which the
+/// command line overrides
+///
+/// A setting left out keeps the default of `ClientTable::new`. Amounts are kept as written until
+/// the precision of the `currency` section is known
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct EngineConfig {
+    pub currency: CurrencySettings,
+    pub policies: PolicySettings,
+    pub limits: LimitSettings,
+    pub fees: FeeSettings,
+    pub output: OutputSettings,
+}
+
+/// Precision of the amounts, see `CurrencyConfig`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
+pub struct CurrencySettings {
+    pub decimals: u32,
+    pub rounding: Rounding,
+    /// See `ClientTable::set_base_currency`
+    pub base: Option<CurrencyCode>,
+    pub max_amount: Option<Amount>,
+}
+
+impl Default for CurrencySettings {
+    fn default() -> Self {
+        let currency = CurrencyConfig::default();
+        Self {
+            decimals: currency.decimals(),
+            rounding: currency.rounding(),
+            base: None,
+            max_amount: None,
+        }
+    }
+}
+
+/// The parts of the `Policy` a file can set, along with `ClientTable::set_unlock_reverses`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
+pub struct PolicySettings {
+    pub overdraw: Overdraw,
+    pub dedup: DedupPolicy,
+    /// In days
+    pub dispute_window: Option<u64>,
+    pub dispute_routing: DisputeRouting,
+    pub clearing: ClearingDelay,
+    /// `SettlementPolicy::ValueDated` rather than `Immediate`
+    pub value_dated: bool,
+    pub unlock_reverses: bool,
+}
+
+/// Files of per client limits
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
+pub struct LimitSettings {
+    /// See `RiskLimits::load`
+    pub risk: Option<PathBuf>,
+    /// See `CreditLines::load`
+    pub credit_lines: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
+pub struct FeeSettings {
+    /// See `FeeSchedule::load`
+    pub schedule: Option<PathBuf>,
+}
+
+/// What the report lists and how
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
+pub struct OutputSettings {
+    pub sort: ReportOrder,
+    pub locked_only: bool,
+    /// Written as ranges such as `"1-100,200"`, see `ReportOptions::parse_clients`
+    #[cfg_attr(feature = "config", serde(deserialize_with = "client_ranges"))]
+    pub clients: Vec<RangeInclusive<ClientId>>,
+    pub top: Option<usize>,
+    /// Period of attested statements, see `attestation::check_period`
+    pub period: String,
+    pub rollup: bool,
+    /// See `ClientTable::set_extended_report`
+    pub extended_report: bool,
+}
+
+/// An amount as written, parsed once the precision of the run is known
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Amount(pub String);
+
+impl Amount {
+    fn parse(
+        &self,
+        currency: CurrencyConfig,
+        setting: &'static str,
+    ) -> Result<Currency, ConfigError> {
+        currency
+            .parse(&self.0)
+            .map_err(|_| ConfigError::InvalidAmount { setting })
+    }
+}
+
+/// `OverdrawPolicy` with the limit of an overdraft as written
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Overdraw {
+    Strict,
+    #[default]
+    Exact,
+    Limit(Amount),
+}
+
+impl Overdraw {
+    /// `strict`, `exact` or the limit of an overdraft
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "strict" => Some(Overdraw::Strict),
+            "exact" => Some(Overdraw::Exact),
+            "" => None,
+            limit => Some(Overdraw::Limit(Amount(limit.to_string()))),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Precision of the amounts of the run
+    pub fn currency(&self) -> Result<CurrencyConfig, ConfigError> {
+        let settings = &self.currency;
+        let currency = CurrencyConfig::new(settings.decimals, settings.rounding)
+            .ok_or(ConfigError::TooManyDecimals)?
+            .in_currency(settings.base);
+        match &settings.max_amount {
+            Some(max) => Ok(currency.with_max_amount(max.parse(currency, "the maximum amount")?)),
+            None => Ok(currency),
+        }
+    }
+
+    /// The policy of the run, what the file can't set keeps the default
+    pub fn policy(&self, currency: CurrencyConfig) -> Result<Policy, ConfigError> {
+        let policies = &self.policies;
+        Ok(Policy {
+            settlement: match policies.value_dated {
+                true => SettlementPolicy::ValueDated,
+                false => SettlementPolicy::Immediate,
+            },
+            clearing: policies.clearing,
+            dispute_routing: policies.dispute_routing,
+            dedup: policies.dedup,
+            dispute_window: policies
+                .dispute_window
+                .map(|days| days.saturating_mul(SECONDS_PER_DAY)),
+            overdraw: match &policies.overdraw {
+                Overdraw::Strict => OverdrawPolicy::Strict,
+                Overdraw::Exact => OverdrawPolicy::AllowExact,
+                Overdraw::Limit(limit) => {
+                    OverdrawPolicy::Overdraft(limit.parse(currency, "the overdraft limit")?)
+                }
+            },
+            ..Policy::default()
+        })
+    }
+
+    /// Options of the report, a query is only given on the command line
+    pub fn report(&self) -> ReportOptions {
+        let output = &self.output;
+        ReportOptions {
+            order: output.sort,
+            locked_only: output.locked_only,
+            clients: output.clients.clone(),
+            top: output.top,
+            query: None,
+        }
+    }
+
+    /// A builder set up with the precision, policy, limits and fees of the configuration, the
+    /// files of the limits and fees being loaded
+    pub fn builder(&self) -> Result<ClientTableBuilder, EngineError> {
+        let currency = self.currency()?;
+        let mut builder = ClientTable::builder()
+            .currency(currency)
+            .base_currency(self.currency.base)
+            .policy(self.policy(currency)?)
+            .extended_report(self.output.extended_report)
+            .unlock_reverses(self.policies.unlock_reverses);
+        if let Some(path) = &self.limits.risk {
+            builder = builder.limits(RiskLimits::load(path, currency)?);
+        }
+        if let Some(path) = &self.limits.credit_lines {
+            builder = builder.credit_lines(CreditLines::load(path, currency)?);
+        }
+        if let Some(path) = &self.fees.schedule {
+            builder = builder.fees(FeeSchedule::load(path, currency)?);
+        }
+        Ok(builder)
+    }
+}
+
+/// Reads a configuration file, see `EngineConfig`. Files ending in `.yaml` or `.yml` are read as
+/// YAML and anything else as TOML
+#[cfg(feature = "config")]
+pub fn load(path: impl AsRef<Path>) -> io::Result<EngineConfig> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml") | Some("yml") => parse_yaml(&text),
+        _ => parse_toml(&text),
+    }
+}
+
+#[cfg(feature = "config")]
+pub fn parse_toml(text: &str) -> io::Result<EngineConfig> {
+    toml::from_str(text).map_err(|e| invalid_config(&e))
+}
+
+/// An empty document is the default configuration
+#[cfg(feature = "config")]
+pub fn parse_yaml(text: &str) -> io::Result<EngineConfig> {
+    match serde_yaml::from_str(text) {
+        Ok(config) => Ok(config),
+        Err(_) if text.trim().is_empty() => Ok(EngineConfig::default()),
+        Err(e) => Err(invalid_config(&e)),
+    }
+}
+
+#[cfg(feature = "config")]
+fn invalid_config(message: &dyn fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid configuration: {}", message),
+    )
+}
+
+#[cfg(feature = "config")]
+impl<'de> Deserialize<'de> for Overdraw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_name(
+            deserializer,
+            "strict, exact or a limit",
+            Overdraw::from_name,
+        )
+    }
+}
+
+#[cfg(feature = "config")]
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_name(deserializer, "an amount", |amount| {
+            Some(Amount(amount.to_string()))
+        })
+    }
+}
+
+#[cfg(feature = "config")]
+fn client_ranges<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RangeInclusive<ClientId>>, D::Error> {
+    let expecting = "client ranges such as 1-100,200";
+    from_name(deserializer, expecting, ReportOptions::parse_clients)
+}
+
+#[cfg(all(test, feature = "config"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_and_yaml_read_the_same_settings() {
+        let toml = r#"
+# Settings of the nightly run
+[currency]
+decimals = 2
+
+[policies]
+overdraw = "strict"   # no overdrafts
+dedup = 1_000
+unlock-reverses = true
+value-dated = false
+
+[output]
+sort = 'total'
+clients = "1-100,200"
+period = "2024 # Q1"
+"#;
+        let yaml = r#"
+---
+currency:
+  decimals: 2
+policies:
+  overdraw: strict   # no overdrafts
+  dedup: 1000
+  unlock-reverses: true
+  value-dated: false
+output:
+  sort: 'total'
+  clients: 1-100,200
+  period: "2024 # Q1"
+"#;
+        let mut expected = EngineConfig::default();
+        expected.currency.decimals = 2;
+        expected.policies.overdraw = Overdraw::Strict;
+        expected.policies.dedup = DedupPolicy::Window(1000);
+        expected.policies.unlock_reverses = true;
+        expected.output.sort = ReportOrder::Total;
+        expected.output.clients = vec![1..=100, 200..=200];
+        expected.output.period = "2024 # Q1".to_string();
+        assert_eq!(parse_toml(toml).unwrap(), expected);
+        assert_eq!(parse_yaml(yaml).unwrap(), expected);
+        assert_eq!(parse_yaml("").unwrap(), EngineConfig::default());
+
+        let error = |result: io::Result<EngineConfig>| result.unwrap_err().to_string();
+        assert!(error(parse_toml("[output]\ncolour = true\n")).contains("unknown field `colour`"));
+        assert!(error(parse_toml("decimals = 2\n")).contains("unknown field `decimals`"));
+        assert!(error(parse_toml("[output]\nrollup = \"yes\"\n")).contains("invalid type"));
+        assert!(error(parse_toml("[policies]\ndedup = 0\n")).contains("full or a window size"));
+        assert!(error(parse_yaml("output:\n  sort: size\n")).contains("client or total"));
+        assert!(parse_toml("[currency]\ndecimals = 2\ndecimals = 3\n").is_err());
+        assert!(parse_yaml("- sort\n").is_err());
+    }
+
+    #[test]
+    fn amounts_are_read_at_the_configured_precision() {
+        let config = parse_toml(
+            "[currency]\ndecimals = 2\nmax-amount = 1000\n[policies]\noverdraw = \"50.25\"\n",
+        )
+        .unwrap();
+        let currency = config.currency().unwrap();
+        assert_eq!(currency.decimals(), 2);
+        assert_eq!(
+            config.policy(currency).unwrap().overdraw,
+            OverdrawPolicy::Overdraft(currency.parse("50.25").unwrap())
+        );
+
+        let config = parse_toml("[currency]\ndecimals = 1\n[policies]\noverdraw = \"0.25\"\n");
+        let config = config.unwrap();
+        let currency = config.currency().unwrap();
+        assert_eq!(
+            config.policy(currency),
+            Err(ConfigError::InvalidAmount {
+                setting: "the overdraft limit"
+            })
+        );
+        let config = parse_toml("[currency]\ndecimals = 40\n").unwrap();
+        assert_eq!(config.currency(), Err(ConfigError::TooManyDecimals));
+    }
+}
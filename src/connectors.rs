@@ -0,0 +1,386 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str, thread,
+    time::Duration,
+};
+
+use rdkafka::{
+    consumer::{BaseConsumer, CommitMode, Consumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    ClientConfig, Message,
+};
+
+use crate::{
+    cancel::CancellationToken,
+    client_info::TransactionError,
+    csv_parser::{self, Header},
+    currency::CurrencyConfig,
+    payment_engine::{ClientTable, Summary},
+    transaction::Transaction,
+};
+
+/// How long `consume` waits before polling a source again once it has caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Transactions `consume` handles before committing them, it also commits whenever it catches up
+pub const COMMIT_EVERY: usize = 1000;
+
+/// Continuous feed of transactions, such as a message queue consumer
+///
+/// `commit` acknowledges every transaction returned by `poll` so far, the same way committing the
+/// consumed offsets works for Kafka. A source restarted after a failure resumes after the last
+/// committed transaction, see `KafkaSource` for Kafka
+pub trait TransactionSource {
+    /// Returns the next transaction, or `None` if there is none available right now
+    fn poll(&mut self) -> io::Result<Option<Transaction>>;
+
+    fn commit(&mut self) -> io::Result<()>;
+}
+
+impl ClientTable {
+    /// Applies the transactions of `source` until `token` is cancelled, committing them every
+    /// `COMMIT_EVERY` transactions, whenever the source has nothing more right now and before
+    /// returning. Rejected transactions are committed too as replaying them would be rejected again.
+    ///
+    /// Delivery is at-least-once: the engine state and the committed position are not updated
+    /// together, so the transactions handled since the last commit are delivered again after a
+    /// crash. A transaction that can't be written to the write-ahead log is not applied, and
+    /// consumption stops with an error without committing. Repeated deposits and withdrawals are
+    /// rejected by their tx id once the table is recovered from its log or snapshot, a
+    /// `DedupPolicy` also skips the other repeated records
+    pub fn consume(
+        &mut self,
+        source: &mut impl TransactionSource,
+        token: &CancellationToken,
+    ) -> io::Result<Summary> {
+        let mut summary = Summary::default();
+        let mut uncommitted = 0;
+        while !token.is_cancelled() {
+            let tx = match source.poll()? {
+                Some(tx) => tx,
+                None => {
+                    if uncommitted > 0 {
+                        source.commit()?;
+                        uncommitted = 0;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            match self.handle_transaction(tx) {
+                Ok(()) => summary.applied += 1,
+                Err(TransactionError::NotLogged) => {
+                    return Err(io::Error::other(
+                        "transaction could not be logged, it was left uncommitted",
+                    ))
+                }
+                Err(_) => summary.rejected += 1,
+            }
+            uncommitted += 1;
+            if uncommitted >= COMMIT_EVERY {
+                source.commit()?;
+                uncommitted = 0;
+            }
+        }
+        if uncommitted > 0 {
+            source.commit()?;
+        }
+        Ok(summary)
+    }
+}
+
+/// Append only csv file used as a topic, the committed position is kept in a file next to it
+/// named after the topic with an `.offset` extension
+///
+/// Handy to run the engine against a feed written by another process without a broker, and as
+/// the reference implementation of `TransactionSource`
+pub struct FileTopic {
+    reader: BufReader<File>,
+    /// Byte position after the last transaction returned by `poll`
+    position: u64,
+    offset_path: PathBuf,
+    currency: CurrencyConfig,
+}
+
+impl FileTopic {
+    /// Opens the topic at the committed position, or at its start if nothing was committed yet
+    pub fn open(path: impl AsRef<Path>, currency: CurrencyConfig) -> io::Result<Self> {
+        let path = path.as_ref();
+        let offset_path = path.with_extension("offset");
+        let committed = match fs::read_to_string(&offset_path) {
+            Ok(offset) => offset.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid offset in {}", offset_path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        let position = if committed == 0 {
+            csv_parser::skip_header(&mut reader, Header::Detect)?;
+            reader.stream_position()?
+        } else {
+            reader.seek(SeekFrom::Start(committed))?
+        };
+        Ok(Self {
+            reader,
+            position,
+            offset_path,
+            currency,
+        })
+    }
+
+    /// Byte position in the topic the next run resumes from after a commit
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl TransactionSource for FileTopic {
+    fn poll(&mut self) -> io::Result<Option<Transaction>> {
+        loop {
+            let mut line = String::new();
+            let read = self.reader.read_line(&mut line)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            if !line.ends_with('\n') {
+                // The writer is still appending this record, read it again once it is complete
+                self.reader.seek(SeekFrom::Start(self.position))?;
+                return Ok(None);
+            }
+            self.position += read as u64;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let tx = csv_parser::parse_line_with(Ok(line.trim_end().to_string()), self.currency)?;
+            return Ok(Some(tx));
+        }
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        // Written aside and renamed so a crash never leaves a truncated offset behind
+        let tmp = self.offset_path.with_extension("offset.tmp");
+        fs::write(&tmp, self.position.to_string())?;
+        fs::rename(&tmp, &self.offset_path)
+    }
+}
+
+/// Kafka topic read by a consumer group, every message carrying one csv record without header
+///
+/// Auto commit is off: the offset of a message is stored once `poll` returned its transaction and
+/// committed to the group by `commit`, so a restarted consumer resumes after the last committed
+/// message. Messages without a payload are skipped
+pub struct KafkaSource {
+    consumer: BaseConsumer,
+    currency: CurrencyConfig,
+}
+
+impl KafkaSource {
+    /// Joins `group` on `brokers` and subscribes to `topic`, starting from its beginning when the
+    /// group has no committed offset yet
+    pub fn subscribe(
+        brokers: &str,
+        group: &str,
+        topic: &str,
+        currency: CurrencyConfig,
+    ) -> io::Result<Self> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group)
+            .set("auto.offset.reset", "earliest");
+        Self::with_config(config, topic, currency)
+    }
+
+    /// Subscribes to `topic` with further librdkafka settings, such as the security protocol.
+    /// Automatic commits and offset stores are turned off whatever `config` says
+    pub fn with_config(
+        mut config: ClientConfig,
+        topic: &str,
+        currency: CurrencyConfig,
+    ) -> io::Result<Self> {
+        let consumer: BaseConsumer = config
+            .set("enable.auto.commit", "false")
+            .set("enable.auto.offset.store", "false")
+            .create()
+            .map_err(kafka_error)?;
+        consumer.subscribe(&[topic]).map_err(kafka_error)?;
+        Ok(Self { consumer, currency })
+    }
+}
+
+impl TransactionSource for KafkaSource {
+    fn poll(&mut self) -> io::Result<Option<Transaction>> {
+        loop {
+            let message = match self.consumer.poll(Duration::ZERO) {
+                Some(message) => message.map_err(kafka_error)?,
+                None => return Ok(None),
+            };
+            let tx = match message.payload() {
+                Some(payload) => {
+                    let line = str::from_utf8(payload)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    Some(csv_parser::parse_line_with(
+                        Ok(line.trim_end().to_string()),
+                        self.currency,
+                    )?)
+                }
+                None => None,
+            };
+            self.consumer
+                .store_offset_from_message(&message)
+                .map_err(kafka_error)?;
+            if tx.is_some() {
+                return Ok(tx);
+            }
+        }
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+            committed => committed.map_err(kafka_error),
+        }
+    }
+}
+
+fn kafka_error(e: KafkaError) -> io::Error {
+    io::Error::other(format!("kafka: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn resumes_after_the_committed_transaction() {
+        let dir = std::env::temp_dir().join(format!("bank_topic_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("topic.csv");
+        let mut topic = File::create(&path).unwrap();
+        write!(topic, "type, client, tx, amount\ndeposit, 1, 1, 2.0\n").unwrap();
+
+        let mut table = ClientTable::new();
+        let mut source = FileTopic::open(&path, CurrencyConfig::default()).unwrap();
+        let tx = source.poll().unwrap().unwrap();
+        table.handle_transaction(tx).unwrap();
+        source.commit().unwrap();
+        // Not complete yet
+        write!(topic, "withdrawal, 1, 2, 0.5").unwrap();
+        assert!(source.poll().unwrap().is_none());
+        writeln!(topic).unwrap();
+        assert!(source.poll().unwrap().is_some());
+        // Never committed, so it is delivered again
+        drop(source);
+
+        let mut source = FileTopic::open(&path, CurrencyConfig::default()).unwrap();
+        let token = CancellationToken::new();
+        let tx = source.poll().unwrap().unwrap();
+        assert_eq!(tx.tx(), 2);
+        table.handle_transaction(tx).unwrap();
+        source.commit().unwrap();
+        token.cancel();
+        assert_eq!(
+            table.consume(&mut source, &token).unwrap(),
+            Summary::default()
+        );
+        assert_eq!(
+            table.clients[1].to_string(),
+            "1.5000, 0.0000, 1.5000, false"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn consume_applies_and_commits() {
+        let dir = std::env::temp_dir().join(format!("bank_consume_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("topic.csv");
+        fs::write(&path, "deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 5.0\n").unwrap();
+
+        let mut table = ClientTable::new();
+        let mut source = FileTopic::open(&path, CurrencyConfig::default()).unwrap();
+        let token = CancellationToken::new();
+        let stopper = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                token.cancel()
+            })
+        };
+        let summary = table.consume(&mut source, &token).unwrap();
+        stopper.join().unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                applied: 1,
+                rejected: 1
+            }
+        );
+        let committed = fs::read_to_string(dir.join("topic.offset")).unwrap();
+        assert_eq!(committed, source.position().to_string());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Source handing out deposits, counting the commits
+    struct Counted {
+        left: u32,
+        commits: usize,
+    }
+
+    impl TransactionSource for Counted {
+        fn poll(&mut self) -> io::Result<Option<Transaction>> {
+            if self.left == 0 {
+                return Ok(None);
+            }
+            self.left -= 1;
+            Ok(Some(Transaction::Deposit {
+                client: 1,
+                tx: self.left,
+                amount: crate::currency::Currency::new(10000),
+            }))
+        }
+
+        fn commit(&mut self) -> io::Result<()> {
+            self.commits += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commits_are_batched() {
+        let mut source = Counted {
+            left: 2 * COMMIT_EVERY as u32 + 500,
+            commits: 0,
+        };
+        let token = CancellationToken::new();
+        let stopper = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                token.cancel()
+            })
+        };
+        let summary = ClientTable::new().consume(&mut source, &token).unwrap();
+        stopper.join().unwrap();
+        assert_eq!(summary.applied, 2 * COMMIT_EVERY + 500);
+        // Two full batches and the rest once the source was drained
+        assert_eq!(source.commits, 3);
+    }
+
+    #[test]
+    fn kafka_sources_refuse_an_invalid_configuration() {
+        let mut config = ClientConfig::new();
+        config.set("no.such.setting", "1");
+        assert!(
+            KafkaSource::with_config(config, "transactions", CurrencyConfig::default()).is_err()
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    client_info::TransactionError,
+    csv_parser::split_fields,
+    currency::{Currency, CurrencyConfig},
+    events::EngineWarning,
+    fx::Rate,
+    general_ledger::GlAccount,
+    payment_engine::ClientTable,
+    transaction::{ClientId, TxId},
+};
+
+/// Credit granted to a client, withdrawals from the regular account can take the available funds
+/// down to minus the limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CreditLine {
+    pub limit: Currency,
+    /// Interest charged on the negative available funds whenever an accrual day closes, rounded
+    /// toward zero to the minor unit of the amounts. `None` lends for free
+    pub rate: Option<Rate>,
+}
+
+impl CreditLine {
+    /// Part of the limit drawn with `available` funds
+    pub fn used(&self, available: Currency) -> Currency {
+        available.negative_part()
+    }
+}
+
+/// Credit lines of the clients, the clients without one can't go below zero unless the overdraw
+/// policy lets them
+///
+/// Interest on the credit used is charged like a fee, to the fee ledger of the client under the id
+/// of the accrual day, and goes to the fee income of the general ledger
+#[derive(Clone, Debug, Default)]
+pub struct CreditLines {
+    clients: HashMap<ClientId, CreditLine>,
+}
+
+impl CreditLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the credit lines from a csv file of `client, limit, rate` records, see `read_csv`
+    pub fn load(path: impl AsRef<Path>, currency: CurrencyConfig) -> io::Result<Self> {
+        Self::read_csv(BufReader::new(File::open(path)?), currency)
+    }
+
+    /// Reads `client, limit, rate` records, the first line is skipped if it is a header. The limit
+    /// is parsed with `currency` and the rate is the one charged every accrual day, an empty rate
+    /// charges no interest
+    pub fn read_csv<R: BufRead>(reader: R, currency: CurrencyConfig) -> io::Result<Self> {
+        let mut lines = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            match fields[..] {
+                [ref client, ..] if i == 0 && client.eq_ignore_ascii_case("client") => {}
+                [ref empty] if empty.is_empty() => {}
+                [ref client, ref limit, ref rate] => {
+                    let credit = (|| {
+                        let client = client.parse().ok()?;
+                        let limit = currency.parse(limit).ok()?;
+                        let rate = match rate.as_ref() {
+                            "" => None,
+                            rate => Some(rate.parse().ok()?),
+                        };
+                        Some((client, CreditLine { limit, rate }))
+                    })();
+                    match credit {
+                        Some((client, credit)) => lines.set(client, credit),
+                        None => return Err(invalid_credit_line(&line)),
+                    }
+                }
+                _ => return Err(invalid_credit_line(&line)),
+            }
+        }
+        Ok(lines)
+    }
+
+    pub fn set(&mut self, client: ClientId, credit: CreditLine) {
+        self.clients.insert(client, credit);
+    }
+
+    pub fn get(&self, client: ClientId) -> Option<CreditLine> {
+        self.clients.get(&client).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+fn invalid_credit_line(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid credit line record: {}", line),
+    )
+}
+
+impl ClientTable {
+    /// Replaces the credit lines, which take precedence over the overdraw policy of the regular
+    /// accounts. The reports then get `credit_limit` and `credit_used` columns
+    pub fn set_credit_lines(&mut self, credit_lines: CreditLines) {
+        self.credit_lines = credit_lines;
+    }
+
+    pub fn credit_lines(&self) -> &CreditLines {
+        &self.credit_lines
+    }
+
+    /// Charges the interest on the credit used by the clients that are not locked, under
+    /// transaction id `tx`. Charges that would overflow are skipped with a warning
+    pub(crate) fn charge_credit_interest(&mut self, tx: TxId) {
+        let minor_unit = self.currency_config().minor_unit();
+        for (client, info) in self.clients.iter_mut() {
+            let (credit, rate) = match self.credit_lines.get(client) {
+                Some(
+                    credit @ CreditLine {
+                        rate: Some(rate), ..
+                    },
+                ) => (credit, rate),
+                _ => continue,
+            };
+            if !info.exists() || info.is_locked() {
+                continue;
+            }
+            let interest = rate
+                .of(credit.used(info.available_funds()), minor_unit)
+                .ok_or(TransactionError::Overflow);
+            let charged = match interest {
+                Ok(interest) if interest == Currency::ZERO => continue,
+                Ok(interest) => info.charge_fee(interest, tx).map(|()| interest),
+                Err(e) => Err(e),
+            };
+            match (charged, &mut self.general_ledger) {
+                (Ok(interest), Some(ledger)) => {
+                    ledger.post(GlAccount::ClientFunds, GlAccount::FeeIncome, interest)
+                }
+                (Ok(_), None) => {}
+                (Err(_), _) => self
+                    .warnings
+                    .push(EngineWarning::PostingFailed { client, tx }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn credit_lines_allow_negative_balances() {
+        let csv = "client, limit, rate\n1, 100, 0.01\n2, 50,\n";
+        let credit_lines =
+            CreditLines::read_csv(csv.as_bytes(), CurrencyConfig::default()).unwrap();
+        assert_eq!(
+            credit_lines.get(2),
+            Some(CreditLine {
+                limit: Currency::new(500_000),
+                rate: None,
+            })
+        );
+        assert!(
+            CreditLines::read_csv("1, x, 0.01\n".as_bytes(), CurrencyConfig::default()).is_err()
+        );
+
+        let mut table = ClientTable::new();
+        table.set_credit_lines(credit_lines);
+        let withdraw = |client, tx, amount| Transaction::Withdraw {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100_000),
+            },
+            withdraw(1, 2, 1_100_000),
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: Currency::new(100_000),
+            },
+        ]);
+        assert_eq!(
+            table.handle_transaction(withdraw(1, 4, 1)),
+            Err(TransactionError::Overdraw)
+        );
+        assert_eq!(
+            table.handle_transaction(withdraw(3, 5, 100_001)),
+            Err(TransactionError::Overdraw)
+        );
+        // Credit can be used without ever depositing
+        assert!(table.handle_transaction(withdraw(2, 6, 1)).is_ok());
+
+        table.accrue(7);
+        assert_eq!(
+            table.client(1).unwrap().available_funds(),
+            Currency::new(-1_010_000)
+        );
+        let mut report = Vec::new();
+        table.write_csv(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client, available, held, total, locked, credit_limit, credit_used\n\
+             1, -101.0000, 0.0000, -101.0000, false, 100.0000, 101.0000\n\
+             2, -0.0001, 0.0000, -0.0001, false, 50.0000, 0.0001\n\
+             3, 10.0000, 0.0000, 10.0000, false, 0.0000, 0.0000\n"
+        );
+    }
+}
@@ -1,6 +1,13 @@
-use std::{io, num};
+use std::{
+    borrow::Cow,
+    io::{self, BufRead},
+    iter, num,
+};
 
-use crate::{currency::ParseCurrencyError, transaction::Transaction};
+use crate::{
+    currency::{Currency, CurrencyCode, CurrencyConfig, ParseCurrencyError, Rounding},
+    transaction::{ClientId, InvalidMemo, Stamped, Timestamp, Transaction, TxId},
+};
 
 #[derive(Debug)]
 pub enum ParseCSVError {
@@ -8,6 +15,42 @@ pub enum ParseCSVError {
     ParseIntError(num::ParseIntError),
     ParseCurrencyError(ParseCurrencyError),
     UnknownRecord,
+    /// Every internal client id has been handed out to an external identifier, a `ClientMap`
+    /// holds at most `ClientId::MAX` of them
+    ClientIdsExhausted,
+    /// A quoted field is never closed or is followed by something other than a separator
+    InvalidQuoting,
+    /// The amount is zero or negative
+    NonPositiveAmount,
+    /// The amount has more decimals than the precision and the rounding mode rejects them
+    TooManyDecimals,
+    /// The amount is above the maximum, see `CurrencyConfig::with_max_amount`
+    AmountTooLarge,
+    /// The memo is longer than `Memo::MAX_LEN` bytes or has control characters
+    InvalidMemo,
+    /// The record has more columns than the schema, only checked by `check_columns`
+    TooManyColumns,
+}
+
+/// Column names expected in the header, in order
+pub const HEADER: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Header of the normalized feed, the extra `seq` column is ignored when it is read back
+pub const NORMALIZED_HEADER: [&str; 5] = ["type", "client", "tx", "amount", "seq"];
+
+/// Header of feeds carrying a currency code for each deposit and withdrawal
+pub const CURRENCY_HEADER: [&str; 5] = ["type", "client", "tx", "currency", "amount"];
+
+/// How the first line of the input is treated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Header {
+    /// The first line is a header if its first column is named `type`, otherwise it is a record
+    #[default]
+    Detect,
+    /// The first line must be a header
+    Required,
+    /// Every line is a record
+    Absent,
 }
 
 impl From<io::Error> for ParseCSVError {
@@ -28,6 +71,12 @@ impl From<ParseCurrencyError> for ParseCSVError {
     }
 }
 
+impl From<InvalidMemo> for ParseCSVError {
+    fn from(_: InvalidMemo) -> Self {
+        ParseCSVError::InvalidMemo
+    }
+}
+
 impl From<ParseCSVError> for io::Error {
     fn from(error: ParseCSVError) -> Self {
         io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", error))
@@ -35,38 +84,735 @@ impl From<ParseCSVError> for io::Error {
 }
 
 pub fn parse_line(line: io::Result<String>) -> Result<Transaction, ParseCSVError> {
+    parse_line_with(line, CurrencyConfig::default())
+}
+
+/// Same as `parse_line` for feeds using a different precision than the default one
+pub fn parse_line_with(
+    line: io::Result<String>,
+    currency: CurrencyConfig,
+) -> Result<Transaction, ParseCSVError> {
+    parse_line_stamped(line, currency).map(|stamped| stamped.transaction)
+}
+
+/// Same as `parse_line_with` keeping the timestamp of the record, an optional sixth column after
+/// the value date holding seconds since the Unix epoch. Records without one leave it empty or out
+/// The `seq` of the bookings and conversions of a normalized feed lands in that column. The
+/// optional seventh column is the free text memo, quoted if it has a comma
+pub fn parse_line_stamped(
+    line: io::Result<String>,
+    currency: CurrencyConfig,
+) -> Result<Stamped, ParseCSVError> {
     let line = line?;
-    let mut fields = line.split(',').map(|f| f.trim());
+    if line.contains('"') {
+        let fields = split_fields(&line)?;
+        return parse_split(fields.iter().map(|f| f.as_ref()), currency);
+    }
+    parse_split(line.split(',').map(|f| f.trim()), currency)
+}
+
+/// Parses the fields of a record, a currency code may sit between the tx id and the amount
+fn parse_split<'a>(
+    mut fields: impl Iterator<Item = &'a str>,
+    currency: CurrencyConfig,
+) -> Result<Stamped, ParseCSVError> {
     let transaction_type = fields.next();
     let client = fields.next();
     let tx_id = fields.next();
-    let amount = fields.next();
-    use Transaction::*;
-    match (transaction_type, client, tx_id, amount) {
-        (Some("withdrawal"), Some(client), Some(tx_id), Some(amount)) => {
-            Ok(Transaction::Withdraw {
-                client: client.parse()?,
-                tx: tx_id.parse()?,
-                amount: amount.parse()?,
-            })
+    let (code, amount) = match fields.next() {
+        Some(code) if CurrencyCode::is_code(code) => (Some(code), fields.next()),
+        amount => (None, amount),
+    };
+    let value_date = fields.next();
+    let timestamp = match fields.next() {
+        Some(timestamp) if !timestamp.is_empty() => Some(timestamp.parse()?),
+        _ => None,
+    };
+    let memo = match fields.next() {
+        Some(memo) if !memo.is_empty() => Some(memo.parse()?),
+        _ => None,
+    };
+    let transaction = parse_record(
+        transaction_type,
+        client,
+        tx_id,
+        amount,
+        value_date,
+        currency_of(currency, code),
+    )?;
+    Ok(Stamped {
+        transaction: with_currency_code(transaction, code)?,
+        timestamp,
+        memo,
+    })
+}
+
+/// Columns of a csv record, the currency one only when the fourth field is a currency code
+fn columns(fields: &[Cow<'_, str>]) -> &'static [&'static str] {
+    if fields.get(3).is_some_and(|f| CurrencyCode::is_code(f)) {
+        &[
+            "type",
+            "client",
+            "tx",
+            "currency",
+            "amount",
+            "value_date",
+            "timestamp",
+            "memo",
+        ]
+    } else {
+        &[
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "value_date",
+            "timestamp",
+            "memo",
+        ]
+    }
+}
+
+/// Rejects a record with more columns than the schema, which the parsers otherwise ignore
+pub fn check_columns(record: &str) -> Result<(), ParseCSVError> {
+    let fields = split_fields(record)?;
+    if fields.len() > columns(&fields).len() {
+        return Err(ParseCSVError::TooManyColumns);
+    }
+    Ok(())
+}
+
+/// Column of `record` that `error` comes from, numbered from 1 and named as in the header. `None`
+/// when the record as a whole is malformed, such as an unknown type or missing columns
+pub fn error_column(record: &str, error: &ParseCSVError) -> Option<(usize, &'static str)> {
+    let fields = split_fields(record).ok()?;
+    let names = columns(&fields);
+    let suspects: &[&str] = match error {
+        ParseCSVError::ParseIntError(_) => &["client", "tx", "value_date", "timestamp"],
+        ParseCSVError::ParseCurrencyError(_)
+        | ParseCSVError::NonPositiveAmount
+        | ParseCSVError::TooManyDecimals
+        | ParseCSVError::AmountTooLarge => &["amount"],
+        ParseCSVError::InvalidMemo => &["memo"],
+        ParseCSVError::TooManyColumns => return Some((names.len() + 1, "extra")),
+        _ => return None,
+    };
+    names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| suspects.contains(name))
+        .find(|&(i, &name)| {
+            let field = fields.get(i).map_or("", |f| f.as_ref());
+            match name {
+                "client" => field.parse::<ClientId>().is_err(),
+                "tx" => field.parse::<TxId>().is_err(),
+                // The target currency of a conversion sits in the value date column
+                "value_date" => {
+                    !field.is_empty()
+                        && !CurrencyCode::is_code(field)
+                        && field.parse::<u64>().is_err()
+                }
+                "timestamp" => !field.is_empty() && field.parse::<Timestamp>().is_err(),
+                _ => true,
+            }
+        })
+        .map(|(i, &name)| (i + 1, name))
+}
+
+/// Precision of the amount of a record in currency `code`, see `CurrencyConfig::in_currency`.
+/// Records without a code keep `currency`, which already is the one of the base currency
+pub fn currency_of(currency: CurrencyConfig, code: Option<&str>) -> CurrencyConfig {
+    match code.and_then(|code| code.parse().ok()) {
+        Some(code) => currency.in_currency(Some(code)),
+        None => currency,
+    }
+}
+
+/// Moves a parsed deposit or withdrawal to the account in currency `code` if the record had one
+pub fn with_currency_code(
+    transaction: Transaction,
+    code: Option<&str>,
+) -> Result<Transaction, ParseCSVError> {
+    match code {
+        Some(code) => transaction
+            .in_currency(code.parse()?)
+            .ok_or(ParseCSVError::UnknownRecord),
+        None => Ok(transaction),
+    }
+}
+
+/// Names of the fields of a record in the formats that name them, the keys of the JSON input
+pub const FIELD_NAMES: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "value_date",
+    "currency",
+    "to",
+];
+
+/// Same as `parse_record` for the fields named in `FIELD_NAMES`, in that order, as read from the
+/// structured formats
+pub fn parse_named(
+    fields: [Option<&str>; 7],
+    currency: CurrencyConfig,
+) -> Result<Transaction, ParseCSVError> {
+    let [transaction_type, client, tx_id, amount, value_date, code, to] = fields;
+    let transaction = parse_record(
+        transaction_type,
+        client,
+        tx_id,
+        amount,
+        // Target currency of a conversion, which the csv schema puts in the value date column
+        to.or(value_date),
+        currency_of(currency, code),
+    )?;
+    with_currency_code(transaction, code)
+}
+
+/// Text of a decimal stored as an integer number of `10^-scale`, which binary formats use for
+/// amounts
+pub fn decimal_text(units: i128, scale: u32) -> String {
+    if scale == 0 {
+        return units.to_string();
+    }
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", units.unsigned_abs(), width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if units < 0 { "-" } else { "" };
+    format!("{}{}.{}", sign, integer, fraction)
+}
+
+/// Splits a record into its fields following RFC 4180, quoted fields may contain separators and line
+/// breaks and escape quotes by doubling them. Whitespace around fields is dropped, but kept inside
+/// quotes. Records without quotes borrow from the line
+pub fn split_fields(line: &str) -> Result<Vec<Cow<'_, str>>, ParseCSVError> {
+    if !line.contains('"') {
+        return Ok(line.split(',').map(|f| Cow::Borrowed(f.trim())).collect());
+    }
+    let mut fields = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    loop {
+        while let Some((_, c)) = chars.peek() {
+            if *c == ',' || !c.is_whitespace() {
+                break;
+            }
+            chars.next();
         }
-        (Some("deposit"), Some(client), Some(tx_id), Some(amount)) => Ok(Deposit {
-            client: client.parse()?,
+        let field = if let Some((_, '"')) = chars.peek() {
+            chars.next();
+            let mut field = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) if matches!(chars.peek(), Some((_, '"'))) => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some((_, '"')) => break,
+                    Some((_, c)) => field.push(c),
+                    None => return Err(ParseCSVError::InvalidQuoting),
+                }
+            }
+            while let Some((_, c)) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                if !c.is_whitespace() {
+                    return Err(ParseCSVError::InvalidQuoting);
+                }
+                chars.next();
+            }
+            Cow::Owned(field)
+        } else {
+            let start = chars.peek().map_or(line.len(), |(i, _)| *i);
+            let mut end = line.len();
+            while let Some((i, c)) = chars.peek() {
+                if *c == ',' {
+                    end = *i;
+                    break;
+                }
+                if *c == '"' {
+                    return Err(ParseCSVError::InvalidQuoting);
+                }
+                chars.next();
+            }
+            Cow::Borrowed(line[start..end].trim())
+        };
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+/// Consumes the header line if there is one, checking that the columns are the expected ones
+/// Only peeks at the first line when it turns out to be a record. Returns whether a line was consumed
+pub fn skip_header<R: BufRead>(reader: &mut R, header: Header) -> io::Result<bool> {
+    if header == Header::Absent {
+        return Ok(false);
+    }
+    let buf = reader.fill_buf()?;
+    let first = buf.split(|b| *b == b'\n').next().unwrap_or_default();
+    let first = String::from_utf8_lossy(first);
+    let is_header = match split_fields(&first) {
+        Ok(fields) => fields
+            .first()
+            .is_some_and(|f| f.eq_ignore_ascii_case(HEADER[0])),
+        Err(_) => false,
+    };
+    if !is_header && header == Header::Detect {
+        return Ok(false);
+    }
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let matches = |fields: &[Cow<'_, str>], expected: &[&str]| {
+        fields.len() == expected.len()
+            && fields
+                .iter()
+                .zip(expected.iter())
+                .all(|(found, expected)| found.eq_ignore_ascii_case(expected))
+    };
+    let valid = match split_fields(&line) {
+        Ok(fields) => {
+            matches(&fields, &HEADER)
+                || matches(&fields, &NORMALIZED_HEADER)
+                || matches(&fields, &CURRENCY_HEADER)
+        }
+        Err(_) => false,
+    };
+    if valid {
+        return Ok(true);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Invalid csv header `{}`, expected `{}`",
+            line.trim_end(),
+            HEADER.join(", ")
+        ),
+    ))
+}
+
+/// Iterator over the records of a csv input, joining the lines of a record whose quoted fields
+/// contain line breaks
+pub struct Records<R> {
+    lines: io::Lines<R>,
+    /// Number of the next line of the input, starting at 1
+    next_line: usize,
+}
+
+impl<R: BufRead> Records<R> {
+    pub fn new(reader: R) -> Self {
+        Records {
+            lines: reader.lines(),
+            next_line: 1,
+        }
+    }
+
+    /// Numbers the lines from `line` instead of 1, such as 2 once a header was skipped
+    pub fn starting_at(mut self, line: usize) -> Self {
+        self.next_line = line;
+        self
+    }
+
+    /// Pairs every record with the number of the line it starts on
+    pub fn numbered(mut self) -> impl Iterator<Item = (usize, io::Result<String>)> {
+        iter::from_fn(move || {
+            let line = self.next_line;
+            self.next().map(|record| (line, record))
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        self.next_line += 1;
+        // An odd number of quotes means a quoted field is still open, escaped quotes come in pairs
+        while record.matches('"').count() % 2 == 1 {
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    self.next_line += 1;
+                    record.push('\n');
+                    record.push_str(&line);
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                // Left to the parser to report
+                None => break,
+            }
+        }
+        Some(Ok(record))
+    }
+}
+
+/// Builds a transaction from its already split fields, shared by every input format
+/// `value_date` is only read for bookings and holds the target currency of conversions, other
+/// records may use that column for something else
+pub fn parse_record(
+    transaction_type: Option<&str>,
+    client: Option<&str>,
+    tx_id: Option<&str>,
+    amount: Option<&str>,
+    value_date: Option<&str>,
+    currency: CurrencyConfig,
+) -> Result<Transaction, ParseCSVError> {
+    match client {
+        Some(client) => parse_fields(
+            transaction_type,
+            client.parse()?,
+            tx_id,
+            amount,
+            value_date,
+            currency,
+        ),
+        None => Err(ParseCSVError::UnknownRecord),
+    }
+}
+
+/// Same as `parse_record` for inputs where the client id has already been resolved
+pub fn parse_fields(
+    transaction_type: Option<&str>,
+    client: ClientId,
+    tx_id: Option<&str>,
+    amount: Option<&str>,
+    value_date: Option<&str>,
+    currency: CurrencyConfig,
+) -> Result<Transaction, ParseCSVError> {
+    use Transaction::*;
+    match (transaction_type, tx_id, amount) {
+        (Some("booking"), Some(tx_id), Some(amount)) => Ok(Booking {
+            client,
+            tx: tx_id.parse()?,
+            amount: parse_amount(amount, currency)?,
+            value_date: value_date.ok_or(ParseCSVError::UnknownRecord)?.parse()?,
+        }),
+        (Some("withdrawal"), Some(tx_id), Some(amount)) => Ok(Transaction::Withdraw {
+            client,
+            tx: tx_id.parse()?,
+            amount: parse_amount(amount, currency)?,
+        }),
+        (Some("deposit"), Some(tx_id), Some(amount)) => Ok(Deposit {
+            client,
+            tx: tx_id.parse()?,
+            amount: parse_amount(amount, currency)?,
+        }),
+        (Some("dispute"), Some(tx_id), _) => Ok(Dispute {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("resolve"), Some(tx_id), _) => Ok(Resolve {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("chargeback"), Some(tx_id), _) => Ok(Chargeback {
+            client,
             tx: tx_id.parse()?,
-            amount: amount.parse()?,
         }),
-        (Some("dispute"), Some(client), Some(tx_id), _) => Ok(Dispute {
-            client: client.parse()?,
+        (Some("legal_hold"), Some(tx_id), amount) => Ok(LegalHold {
+            client,
             tx: tx_id.parse()?,
+            amount: match amount {
+                Some(amount) if !amount.is_empty() => Some(parse_amount(amount, currency)?),
+                _ => None,
+            },
         }),
-        (Some("resolve"), Some(client), Some(tx_id), _) => Ok(Resolve {
-            client: client.parse()?,
+        (Some("release"), Some(tx_id), _) => Ok(ReleaseHold {
+            client,
             tx: tx_id.parse()?,
         }),
-        (Some("chargeback"), Some(client), Some(tx_id), _) => Ok(Chargeback {
-            client: client.parse()?,
+        (Some("convert"), Some(tx_id), Some(amount)) => Ok(Convert {
+            client,
+            tx: tx_id.parse()?,
+            from: None,
+            to: value_date.ok_or(ParseCSVError::UnknownRecord)?.parse()?,
+            amount: parse_amount(amount, currency)?,
+        }),
+        (Some("approve"), Some(tx_id), approver) => Ok(Approve {
+            client,
+            tx: tx_id.parse()?,
+            approver: match approver {
+                Some(approver) if !approver.is_empty() => approver.parse()?,
+                _ => 0,
+            },
+        }),
+        (Some("accrue"), Some(tx_id), _) => Ok(Accrue {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("unlock"), Some(tx_id), _) => Ok(Unlock {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("refund"), Some(tx_id), _) => Ok(Refund {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("begin"), Some(tx_id), _) => Ok(Begin {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("commit"), Some(tx_id), _) => Ok(Commit {
+            client,
+            tx: tx_id.parse()?,
+        }),
+        (Some("rollback"), Some(tx_id), _) => Ok(Rollback {
+            client,
             tx: tx_id.parse()?,
         }),
         _ => Err(ParseCSVError::UnknownRecord),
     }
 }
+
+/// Parses the amount of a record, which must be strictly positive and within the maximum of
+/// `currency`. Whether it has too many decimals depends on the precision and rounding mode
+fn parse_amount(amount: &str, currency: CurrencyConfig) -> Result<Currency, ParseCSVError> {
+    let amount = currency.parse(amount).map_err(|e| {
+        let decimals = amount
+            .split_once('.')
+            .map(|(_, fraction)| fraction.trim_end_matches('0'))
+            .filter(|fraction| fraction.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(0, str::len);
+        if decimals > currency.precision() as usize && currency.rounding() == Rounding::Reject {
+            ParseCSVError::TooManyDecimals
+        } else {
+            e.into()
+        }
+    })?;
+    if amount <= Currency::ZERO {
+        return Err(ParseCSVError::NonPositiveAmount);
+    }
+    if currency.max_amount().is_some_and(|max| amount > max) {
+        return Err(ParseCSVError::AmountTooLarge);
+    }
+    Ok(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+
+    #[test]
+    fn quoted_fields() {
+        let fields = split_fields(r#"deposit, "1", "a, ""quoted"" value" , 2"#).unwrap();
+        assert_eq!(fields, ["deposit", "1", r#"a, "quoted" value"#, "2"]);
+        assert_eq!(
+            split_fields("deposit, 1, 2,").unwrap(),
+            ["deposit", "1", "2", ""]
+        );
+        assert!(matches!(
+            split_fields(r#"deposit, "1"x, 2"#),
+            Err(ParseCSVError::InvalidQuoting)
+        ));
+        assert!(matches!(
+            split_fields(r#"deposit, "1, 2"#),
+            Err(ParseCSVError::InvalidQuoting)
+        ));
+        assert_eq!(
+            parse_line(Ok(r#""deposit","1","2","1.5""#.to_string())).unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(15000)
+            }
+        );
+    }
+
+    #[test]
+    fn bookings_carry_a_value_date() {
+        assert_eq!(
+            parse_line(Ok("booking, 1, 2, 1.5, 40".to_string())).unwrap(),
+            Transaction::Booking {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(15000),
+                value_date: 40
+            }
+        );
+        assert!(parse_line(Ok("booking, 1, 2, 1.5".to_string())).is_err());
+        // The extra column of other records is ignored, such as the seq of the normalized feed
+        assert!(parse_line(Ok("deposit, 1, 2, 1.5, 7".to_string())).is_ok());
+    }
+
+    #[test]
+    fn records_may_carry_a_timestamp() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 2,
+            amount: Currency::new(15000),
+        };
+        let stamped = |line: &str| parse_line_stamped(Ok(line.to_string()), Default::default());
+        assert_eq!(
+            stamped("deposit, 1, 2, 1.5, , 1700000000").unwrap(),
+            Stamped {
+                transaction: deposit,
+                timestamp: Some(1700000000),
+                memo: None,
+            }
+        );
+        assert_eq!(stamped("deposit, 1, 2, 1.5").unwrap(), deposit.into());
+        assert_eq!(
+            stamped("dispute, 1, 2, , , 1700000000").unwrap().timestamp,
+            Some(1700000000)
+        );
+        assert!(stamped("deposit, 1, 2, 1.5, , yesterday").is_err());
+        let memo = stamped("deposit, 1, 2, 1.5, , , \"rent, march\"")
+            .unwrap()
+            .memo;
+        assert_eq!(memo.unwrap().as_str(), "rent, march");
+        assert!(stamped(&format!("deposit, 1, 2, 1.5, , , {}", "x".repeat(33))).is_err());
+    }
+
+    #[test]
+    fn records_may_carry_a_currency_code() {
+        assert_eq!(
+            parse_line(Ok("deposit, 1, 2, usd, 1.5".to_string())).unwrap(),
+            Transaction::ForeignDeposit {
+                client: 1,
+                tx: 2,
+                code: "USD".parse().unwrap(),
+                amount: Currency::new(15000),
+            }
+        );
+        assert!(parse_line(Ok("dispute, 1, 2, USD".to_string())).is_err());
+        assert!(parse_line(Ok("withdrawal, 1, 2, USD".to_string())).is_err());
+    }
+
+    #[test]
+    fn conversions_name_their_target_currency() {
+        let convert = |from: Option<&str>| Transaction::Convert {
+            client: 1,
+            tx: 2,
+            from: from.map(|c| c.parse().unwrap()),
+            to: "GBP".parse().unwrap(),
+            amount: Currency::new(15000),
+        };
+        assert_eq!(
+            parse_line(Ok("convert, 1, 2, USD, 1.5, GBP".to_string())).unwrap(),
+            convert(Some("USD"))
+        );
+        assert_eq!(
+            parse_line(Ok("convert, 1, 2, 1.5, GBP".to_string())).unwrap(),
+            convert(None)
+        );
+        assert!(parse_line(Ok("convert, 1, 2, USD, 1.5".to_string())).is_err());
+    }
+
+    #[test]
+    fn records_span_quoted_line_breaks() {
+        let input = "deposit, 1, 1, 1.0\n\"dis\npute\", 1, 1,\nresolve, 1, 1,\n";
+        let records: Vec<_> = Records::new(input.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            [
+                "deposit, 1, 1, 1.0",
+                "\"dis\npute\", 1, 1,",
+                "resolve, 1, 1,"
+            ]
+        );
+        let lines: Vec<_> = Records::new(input.as_bytes())
+            .starting_at(2)
+            .numbered()
+            .map(|(line, _)| line)
+            .collect();
+        assert_eq!(lines, [2, 3, 5]);
+    }
+
+    #[test]
+    fn headers() {
+        let mut input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n".as_bytes();
+        skip_header(&mut input, Header::Detect).unwrap();
+        assert_eq!(input, b"deposit, 1, 1, 1.0\n");
+        skip_header(&mut input, Header::Detect).unwrap();
+        assert_eq!(input, b"deposit, 1, 1, 1.0\n");
+        assert!(skip_header(&mut input, Header::Required).is_err());
+
+        let mut quoted = "\"Type\",\"Client\",\"Tx\",\"Amount\"\n".as_bytes();
+        skip_header(&mut quoted, Header::Required).unwrap();
+        assert!(quoted.is_empty());
+
+        let mut normalized = "type, client, tx, amount, seq\n".as_bytes();
+        skip_header(&mut normalized, Header::Required).unwrap();
+
+        let mut reordered = "type, tx, client, amount\n".as_bytes();
+        assert!(skip_header(&mut reordered, Header::Detect).is_err());
+    }
+
+    #[test]
+    fn amounts_are_validated() {
+        let parse = |line: &str, currency| parse_line_with(Ok(line.to_string()), currency);
+        let default = CurrencyConfig::default();
+        for line in [
+            "deposit, 1, 1, -50",
+            "withdrawal, 1, 1, -0.0001",
+            "booking, 1, 1, -1, 3",
+        ] {
+            assert!(matches!(
+                parse(line, default),
+                Err(ParseCSVError::NonPositiveAmount)
+            ));
+        }
+        for line in ["deposit, 1, 1, 0", "legal_hold, 1, 1, 0.0000"] {
+            assert!(matches!(
+                parse(line, default),
+                Err(ParseCSVError::NonPositiveAmount)
+            ));
+        }
+        assert!(matches!(
+            parse("deposit, 1, 1, 1.00001", default),
+            Err(ParseCSVError::TooManyDecimals)
+        ));
+        assert!(matches!(
+            parse("deposit, 1, 1, 1.0x", default),
+            Err(ParseCSVError::ParseCurrencyError(_))
+        ));
+        // Trailing zeros and rounded amounts are within the precision
+        assert!(parse("deposit, 1, 1, 1.500000", default).is_ok());
+        let rounded = CurrencyConfig::new(4, Rounding::HalfUp).unwrap();
+        assert!(parse("deposit, 1, 1, 1.00001", rounded).is_ok());
+        assert!(matches!(
+            parse("deposit, 1, 1, 0.00001", rounded),
+            Err(ParseCSVError::NonPositiveAmount)
+        ));
+
+        let capped = default.with_max_amount(Currency::new(10_000_000));
+        assert!(parse("deposit, 1, 1, 1000", capped).is_ok());
+        assert!(matches!(
+            parse("convert, 1, 1, 1000.0001, USD", capped),
+            Err(ParseCSVError::AmountTooLarge)
+        ));
+        // Records without an amount are not affected
+        assert!(parse("dispute, 1, 1, -5", capped).is_ok());
+    }
+
+    #[test]
+    fn malformed_records_point_at_their_column() {
+        let column = |line: &str| {
+            let error = check_columns(line)
+                .and_then(|()| parse_line_stamped(Ok(line.to_string()), CurrencyConfig::default()))
+                .unwrap_err();
+            error_column(line, &error)
+        };
+        assert_eq!(column("deposit, x, 1, 1"), Some((2, "client")));
+        assert_eq!(column("deposit, 1, 1.5, 1"), Some((3, "tx")));
+        assert_eq!(column("deposit, 1, 1, EUR, 1.2.3"), Some((5, "amount")));
+        assert_eq!(column("booking, 1, 1, 5, soon"), Some((5, "value_date")));
+        assert_eq!(column("deposit, 1, 1, 5, , noon"), Some((6, "timestamp")));
+        assert_eq!(column("deposit, 1, 1, 5, , , , extra"), Some((8, "extra")));
+        assert_eq!(
+            column("deposit, 1, 1, EUR, 5, , , , extra"),
+            Some((9, "extra"))
+        );
+        assert_eq!(column("withdrawal, 1"), None);
+        assert_eq!(column(""), None);
+        // Empty columns within the schema are only a trailing comma
+        assert!(check_columns("deposit, 1, 1, 5, , , memo").is_ok());
+        assert!(check_columns("deposit, 1, 1, 5,").is_ok());
+    }
+}
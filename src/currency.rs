@@ -1,46 +1,402 @@
 use std::{
-    fmt,
-    ops::{Add, AddAssign, Neg, SubAssign},
+    convert::TryFrom,
+    error::Error,
+    fmt, io,
+    iter::Sum,
+    ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign},
     str::FromStr,
 };
 
 #[derive(Debug)]
 pub struct ParseCurrencyError;
+
+/// A total over many amounts doesn't fit a `Currency`, `aggregate` names what was summed up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AggregationOverflow {
+    pub aggregate: &'static str,
+}
+
+impl fmt::Display for AggregationOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} overflows the supported amount range", self.aggregate)
+    }
+}
+
+impl Error for AggregationOverflow {}
+
+impl From<AggregationOverflow> for io::Error {
+    fn from(error: AggregationOverflow) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Integer the amounts are kept in, `i128` with the `wide-currency` feature
+#[cfg(not(feature = "wide-currency"))]
+pub type Units = i64;
+/// Integer the amounts are kept in, `i128` with the `wide-currency` feature
+#[cfg(feature = "wide-currency")]
+pub type Units = i128;
+
 /// Datatype for the currency used in the csv, as we atmost have 4 decimals of precision
 /// then a i64 should be plenty to hold the values.
 /// The current implementation allows amounts of up to 2^63 / 1000 or around 300 trillion with 4 decimal precision
 /// this is more than 30 times the entire worlds wealth
 /// Alternative approach is using either rust_decimal and some BigNumber lib, but that would hurt the performance quite a bit
+///
+/// The value counts units of the smallest representable amount, which is 0.0001 unless a feed is
+/// read with a different `CurrencyConfig`. The arithmetic doesn't depend on the scale, only parsing
+/// and formatting do, so `FromStr` and `Display` use the default 4 decimals
+///
+/// The `wide-currency` feature keeps the units in an `i128` instead, for totals beyond the range
+/// above or many more decimals. The API stays the same, `new` still takes an `i64` and
+/// `from_units` and `to_i64` convert from and to the full range
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Currency(i64);
+pub struct Currency(Units);
+
+/// Largest supported number of decimals, a single unit has to fit in an i64
+pub const MAX_DECIMALS: u32 = 18;
+
+/// What happens to amounts with more decimals than the configured precision
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// The amount is invalid
+    #[default]
+    Reject,
+    /// Drops the extra decimals
+    TowardZero,
+    /// Rounds to the nearest value, halfway values away from zero
+    HalfUp,
+    /// Rounds to the nearest value, halfway values to the even neighbour (banker's rounding)
+    HalfEven,
+}
+
+impl Rounding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(Rounding::Reject),
+            "toward-zero" => Some(Rounding::TowardZero),
+            "half-up" => Some(Rounding::HalfUp),
+            "half-even" => Some(Rounding::HalfEven),
+            _ => None,
+        }
+    }
+}
+
+/// Precision at which amounts are parsed and formatted
+///
+/// Amounts are always stored with `decimals` decimals. A config for a currency with fewer minor
+/// units, see `in_currency`, only accepts amounts with as many decimals as the currency has, and
+/// formats them with that many
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrencyConfig {
+    decimals: u32,
+    rounding: Rounding,
+    /// ISO 4217 exponent of the currency the amounts are in, if known
+    exponent: Option<u32>,
+    /// Largest amount a record may carry, see `with_max_amount`
+    max_amount: Option<Currency>,
+}
+
+impl Default for CurrencyConfig {
+    fn default() -> Self {
+        CurrencyConfig {
+            decimals: 4,
+            rounding: Rounding::default(),
+            exponent: None,
+            max_amount: None,
+        }
+    }
+}
+
+impl CurrencyConfig {
+    /// Returns `None` if `decimals` is above `MAX_DECIMALS`
+    pub fn new(decimals: u32, rounding: Rounding) -> Option<Self> {
+        if decimals > MAX_DECIMALS {
+            return None;
+        }
+        Some(CurrencyConfig {
+            decimals,
+            rounding,
+            exponent: None,
+            max_amount: None,
+        })
+    }
+
+    /// Same precision for amounts in the currency `code`, whose exponent is looked up in the ISO
+    /// 4217 table. `None` and codes missing from the table, such as the ones of precious metals,
+    /// go back to the plain precision of the feed. An exponent above `decimals` can't be stored
+    /// and is capped to it
+    pub fn in_currency(self, code: Option<CurrencyCode>) -> Self {
+        CurrencyConfig {
+            exponent: code.and_then(|code| code.exponent()),
+            ..self
+        }
+    }
+
+    pub fn decimals(&self) -> u32 {
+        self.decimals
+    }
+
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    /// Makes the parsers reject records whose amount is above `max`, amounts are unbounded by
+    /// default
+    pub fn with_max_amount(self, max: Currency) -> Self {
+        CurrencyConfig {
+            max_amount: Some(max),
+            ..self
+        }
+    }
+
+    pub fn max_amount(&self) -> Option<Currency> {
+        self.max_amount
+    }
+
+    /// Number of units in 1
+    fn scale(&self) -> i64 {
+        10i64.pow(self.decimals)
+    }
+
+    /// Number of decimals the amounts of the currency have
+    pub fn precision(&self) -> u32 {
+        self.exponent
+            .map_or(self.decimals, |e| e.min(self.decimals))
+    }
+
+    /// Number of units in the smallest amount of the currency
+    pub fn minor_unit(&self) -> Currency {
+        Currency(Units::pow(10, self.decimals - self.precision()))
+    }
+
+    /// Amounts with more decimals than the currency has are rounded as configured
+    pub fn parse(&self, s: &str) -> Result<Currency, ParseCurrencyError> {
+        let minor = CurrencyConfig {
+            decimals: self.precision(),
+            exponent: None,
+            ..*self
+        };
+        let units = minor.parse_units(s)?;
+        units
+            .checked_mul(self.minor_unit().0)
+            .map(Currency)
+            .ok_or(ParseCurrencyError)
+    }
+
+    fn parse_units(&self, s: &str) -> Result<Units, ParseCurrencyError> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer, fraction),
+            None => (digits, ""),
+        };
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if integer.is_empty() || !is_digits(integer) || !is_digits(fraction) {
+            return Err(ParseCurrencyError);
+        }
+        let integer: i128 = integer.parse().map_err(|_| ParseCurrencyError)?;
+        let decimals = self.decimals as usize;
+        let (kept, extra) = fraction.split_at(fraction.len().min(decimals));
+        let kept = format!("{:0<width$}", kept, width = decimals);
+        let mut units = integer
+            .checked_mul(self.scale() as i128)
+            .and_then(|units| units.checked_add(kept.parse::<i128>().unwrap_or(0)))
+            .ok_or(ParseCurrencyError)?;
+        if extra.bytes().any(|b| b != b'0') {
+            let halfway = extra.as_bytes()[0] == b'5' && extra[1..].bytes().all(|b| b == b'0');
+            let above_half = extra.as_bytes()[0] >= b'5' && !halfway;
+            units += match self.rounding {
+                Rounding::Reject => return Err(ParseCurrencyError),
+                Rounding::TowardZero => 0,
+                Rounding::HalfUp => (halfway || above_half) as i128,
+                Rounding::HalfEven => (above_half || halfway && units % 2 == 1) as i128,
+            };
+        }
+        let units = if negative { -units } else { units };
+        Units::try_from(units).map_err(|_| ParseCurrencyError)
+    }
+
+    /// Formats `amount` with exactly as many decimals as the currency has, or all the configured
+    /// decimals if the amount has a fraction of the smallest amount of the currency
+    pub fn display(&self, amount: Currency) -> impl fmt::Display {
+        Formatted {
+            amount,
+            config: *self,
+        }
+    }
+}
+
+struct Formatted {
+    amount: Currency,
+    config: CurrencyConfig,
+}
+
+impl fmt::Display for Formatted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut units = self.amount.0;
+        let mut decimals = self.config.decimals;
+        let minor = self.config.minor_unit().0;
+        if units % minor == 0 {
+            units /= minor;
+            decimals = self.config.precision();
+        }
+        // The sign is written separately as the integer part alone loses it for values between -1 and 0
+        let sign = if units.is_negative() { "-" } else { "" };
+        let scale = Units::pow(10, decimals);
+        write!(f, "{}{}", sign, (units / scale).unsigned_abs())?;
+        if decimals > 0 {
+            write!(
+                f,
+                ".{:0>width$}",
+                (units % scale).unsigned_abs(),
+                width = decimals as usize
+            )?;
+        }
+        Ok(())
+    }
+}
 
 impl Currency {
+    pub const ZERO: Currency = Currency(0);
+    pub const MAX: Currency = Currency(Units::MAX);
+    pub const MIN: Currency = Currency(Units::MIN);
+
     #[allow(dead_code)]
     pub fn new(x: i64) -> Self {
-        Self(x)
+        Self(Units::from(x))
+    }
+
+    /// Amount of `units` of the smallest representable amount, over the full range of the backend
+    pub fn from_units(units: Units) -> Self {
+        Self(units)
+    }
+
+    /// Number of units of the smallest representable amount, the inverse of `from_units`
+    pub fn units(self) -> Units {
+        self.0
+    }
+
+    /// Number of units as an `i64`, the inverse of `new`. Always `Some` unless the feature
+    /// `wide-currency` is on
+    #[allow(clippy::useless_conversion)]
+    pub fn to_i64(self) -> Option<i64> {
+        i64::try_from(self.0).ok()
+    }
+
+    /// Number of units widened to an `i128`, for intermediate results of the arithmetic
+    #[allow(clippy::useless_conversion)]
+    pub fn to_i128(self) -> i128 {
+        i128::from(self.0)
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Currency)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Currency)
+    }
+
+    pub fn checked_neg(self) -> Option<Self> {
+        self.0.checked_neg().map(Currency)
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Currency(self.0.saturating_add(rhs.0))
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Currency(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Size of the amount, `Currency::MIN` saturates to `Currency::MAX`
+    pub fn abs(self) -> Self {
+        Currency(self.0.saturating_abs())
+    }
+
+    /// The amount if it is positive, zero otherwise
+    pub fn positive_part(self) -> Self {
+        self.max(Currency::ZERO)
+    }
+
+    /// Size of the amount if it is negative, zero otherwise. `Currency::MIN` saturates to
+    /// `Currency::MAX`
+    pub fn negative_part(self) -> Self {
+        self.min(Currency::ZERO).abs()
+    }
+
+    pub fn checked_mul(self, rhs: i64) -> Option<Self> {
+        self.0.checked_mul(Units::from(rhs)).map(Currency)
+    }
+
+    /// Divides by `rhs` with banker's rounding, the quotient is rounded to the nearest unit and
+    /// halfway values to the even one. `None` if `rhs` is zero or the quotient doesn't fit
+    pub fn checked_div(self, rhs: i64) -> Option<Self> {
+        div_half_even(self.to_i128(), i128::from(rhs)).and_then(Self::from_i128)
+    }
+
+    /// Share of the amount given in basis points, 100 being 1%, with banker's rounding like
+    /// `checked_div`. `None` if the share doesn't fit
+    pub fn mul_percent(self, bps: u32) -> Option<Self> {
+        let share = self.to_i128().checked_mul(i128::from(bps))?;
+        div_half_even(share, BASIS_POINTS).and_then(Self::from_i128)
+    }
+
+    /// Amount of `units` narrowed back from an `i128`, `None` if it doesn't fit
+    #[allow(clippy::useless_conversion)]
+    pub fn from_i128(units: i128) -> Option<Self> {
+        Units::try_from(units).ok().map(Currency)
+    }
+
+    /// Sum of `amounts`, `None` if it doesn't fit. The running total is kept in 128 bits, so only
+    /// the final sum has to fit and amounts of either sign can come in any order. With the
+    /// `wide-currency` feature the running total is as wide as the amounts and has to fit too
+    pub fn checked_sum<I: IntoIterator<Item = Currency>>(amounts: I) -> Option<Self> {
+        let total = amounts
+            .into_iter()
+            .try_fold(0i128, |total, amount| total.checked_add(amount.to_i128()))?;
+        Self::from_i128(total)
+    }
+
+    /// `checked_sum` reporting an overflow as the `aggregate` that overflowed
+    pub fn sum_of<I: IntoIterator<Item = Currency>>(
+        aggregate: &'static str,
+        amounts: I,
+    ) -> Result<Self, AggregationOverflow> {
+        Self::checked_sum(amounts).ok_or(AggregationOverflow { aggregate })
     }
 }
 
+/// Basis points in a whole, see `Currency::mul_percent`
+const BASIS_POINTS: i128 = 10_000;
+
+/// Divides `numerator` by `denominator` rounding the quotient to the nearest integer, halfway
+/// values to the even one, `None` if `denominator` is zero
+fn div_half_even(numerator: i128, denominator: i128) -> Option<i128> {
+    let quotient = numerator.checked_div(denominator)?;
+    let remainder = numerator % denominator;
+    let twice = remainder.abs() * 2;
+    let away = twice > denominator.abs() || twice == denominator.abs() && quotient % 2 != 0;
+    if !away {
+        return Some(quotient);
+    }
+    // The quotient is truncated, rounding away from zero moves it in the sign of the exact result
+    let step = if (numerator < 0) == (denominator < 0) {
+        1
+    } else {
+        -1
+    };
+    Some(quotient + step)
+}
+
 impl FromStr for Currency {
     type Err = ParseCurrencyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut splitted = s.split('.');
-        let first = splitted.next().map(i64::from_str);
-        let second = splitted
-            .next()
-            .map(|s| format!("{:0<4}", s))
-            .map(|s| i64::from_str(&s));
-        match (first, second) {
-            (Some(Ok(first)), None) => Ok(Currency(first * 10000)),
-            (Some(Ok(first)), Some(Ok(second))) => {
-                let first = first * 10000;
-                let second = if first.is_negative() { -second } else { second };
-
-                Ok(Currency(first + second))
-            }
-            _ => Err(ParseCurrencyError),
-        }
+        CurrencyConfig::default().parse(s)
     }
 }
 
@@ -64,6 +420,64 @@ impl SubAssign for Currency {
     }
 }
 
+impl Sub for Currency {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Currency(self.0 - rhs.0)
+    }
+}
+
+impl Mul<i64> for Currency {
+    type Output = Self;
+
+    fn mul(self, rhs: i64) -> Self::Output {
+        Currency(self.0 * Units::from(rhs))
+    }
+}
+
+impl MulAssign<i64> for Currency {
+    fn mul_assign(&mut self, rhs: i64) {
+        *self = *self * rhs
+    }
+}
+
+/// Division with banker's rounding, see `checked_div`
+///
+/// # Panics
+/// If `rhs` is zero, or for `Currency::MIN` divided by -1
+impl Div<i64> for Currency {
+    type Output = Self;
+
+    fn div(self, rhs: i64) -> Self::Output {
+        self.checked_div(rhs)
+            .expect("currency division by zero or overflow")
+    }
+}
+
+/// Division with banker's rounding, see `checked_div`
+///
+/// # Panics
+/// If `rhs` is zero, or for `Currency::MIN` divided by -1
+impl DivAssign<i64> for Currency {
+    fn div_assign(&mut self, rhs: i64) {
+        *self = *self / rhs
+    }
+}
+
+/// Sum with the overflow behaviour of `+`, `checked_sum` reports it instead
+impl Sum for Currency {
+    fn sum<I: Iterator<Item = Currency>>(amounts: I) -> Self {
+        amounts.fold(Currency::ZERO, Add::add)
+    }
+}
+
+impl<'a> Sum<&'a Currency> for Currency {
+    fn sum<I: Iterator<Item = &'a Currency>>(amounts: I) -> Self {
+        amounts.copied().sum()
+    }
+}
+
 impl Neg for Currency {
     type Output = Self;
 
@@ -74,7 +488,223 @@ impl Neg for Currency {
 
 impl fmt::Display for Currency {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}.{:0>4}", self.0 / 10000, self.0.abs() % 10000)
+        write!(f, "{}", CurrencyConfig::default().display(*self))
+    }
+}
+
+/// ISO 4217 style three letter code of the currency of an account, such as `EUR`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CurrencyCode([u8; 3]);
+
+impl CurrencyCode {
+    /// Whether `s` looks like a currency code rather than an amount, used to detect the optional
+    /// currency column
+    pub fn is_code(s: &str) -> bool {
+        s.len() == 3 && s.bytes().all(|b| b.is_ascii_alphabetic())
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("codes are ascii")
+    }
+
+    /// Number of decimals of the minor unit of the currency according to ISO 4217, `None` for
+    /// codes not in the table
+    pub fn exponent(&self) -> Option<u32> {
+        ISO_4217
+            .binary_search_by(|(code, _)| code.as_bytes().cmp(&self.0[..]))
+            .ok()
+            .map(|i| ISO_4217[i].1)
+    }
+}
+
+/// Active ISO 4217 codes and their exponents, sorted by code. Funds and precious metals without
+/// a minor unit are left out
+const ISO_4217: &[(&str, u32)] = &[
+    ("AED", 2),
+    ("AFN", 2),
+    ("ALL", 2),
+    ("AMD", 2),
+    ("ANG", 2),
+    ("AOA", 2),
+    ("ARS", 2),
+    ("AUD", 2),
+    ("AWG", 2),
+    ("AZN", 2),
+    ("BAM", 2),
+    ("BBD", 2),
+    ("BDT", 2),
+    ("BGN", 2),
+    ("BHD", 3),
+    ("BIF", 0),
+    ("BMD", 2),
+    ("BND", 2),
+    ("BOB", 2),
+    ("BOV", 2),
+    ("BRL", 2),
+    ("BSD", 2),
+    ("BTN", 2),
+    ("BWP", 2),
+    ("BYN", 2),
+    ("BZD", 2),
+    ("CAD", 2),
+    ("CDF", 2),
+    ("CHE", 2),
+    ("CHF", 2),
+    ("CHW", 2),
+    ("CLF", 4),
+    ("CLP", 0),
+    ("CNY", 2),
+    ("COP", 2),
+    ("COU", 2),
+    ("CRC", 2),
+    ("CUP", 2),
+    ("CVE", 2),
+    ("CZK", 2),
+    ("DJF", 0),
+    ("DKK", 2),
+    ("DOP", 2),
+    ("DZD", 2),
+    ("EGP", 2),
+    ("ERN", 2),
+    ("ETB", 2),
+    ("EUR", 2),
+    ("FJD", 2),
+    ("FKP", 2),
+    ("GBP", 2),
+    ("GEL", 2),
+    ("GHS", 2),
+    ("GIP", 2),
+    ("GMD", 2),
+    ("GNF", 0),
+    ("GTQ", 2),
+    ("GYD", 2),
+    ("HKD", 2),
+    ("HNL", 2),
+    ("HTG", 2),
+    ("HUF", 2),
+    ("IDR", 2),
+    ("ILS", 2),
+    ("INR", 2),
+    ("IQD", 3),
+    ("IRR", 2),
+    ("ISK", 0),
+    ("JMD", 2),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KES", 2),
+    ("KGS", 2),
+    ("KHR", 2),
+    ("KMF", 0),
+    ("KPW", 2),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("KYD", 2),
+    ("KZT", 2),
+    ("LAK", 2),
+    ("LBP", 2),
+    ("LKR", 2),
+    ("LRD", 2),
+    ("LSL", 2),
+    ("LYD", 3),
+    ("MAD", 2),
+    ("MDL", 2),
+    ("MGA", 2),
+    ("MKD", 2),
+    ("MMK", 2),
+    ("MNT", 2),
+    ("MOP", 2),
+    ("MRU", 2),
+    ("MUR", 2),
+    ("MVR", 2),
+    ("MWK", 2),
+    ("MXN", 2),
+    ("MXV", 2),
+    ("MYR", 2),
+    ("MZN", 2),
+    ("NAD", 2),
+    ("NGN", 2),
+    ("NIO", 2),
+    ("NOK", 2),
+    ("NPR", 2),
+    ("NZD", 2),
+    ("OMR", 3),
+    ("PAB", 2),
+    ("PEN", 2),
+    ("PGK", 2),
+    ("PHP", 2),
+    ("PKR", 2),
+    ("PLN", 2),
+    ("PYG", 0),
+    ("QAR", 2),
+    ("RON", 2),
+    ("RSD", 2),
+    ("RUB", 2),
+    ("RWF", 0),
+    ("SAR", 2),
+    ("SBD", 2),
+    ("SCR", 2),
+    ("SDG", 2),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("SHP", 2),
+    ("SLE", 2),
+    ("SOS", 2),
+    ("SRD", 2),
+    ("SSP", 2),
+    ("STN", 2),
+    ("SVC", 2),
+    ("SYP", 2),
+    ("SZL", 2),
+    ("THB", 2),
+    ("TJS", 2),
+    ("TMT", 2),
+    ("TND", 3),
+    ("TOP", 2),
+    ("TRY", 2),
+    ("TTD", 2),
+    ("TWD", 2),
+    ("TZS", 2),
+    ("UAH", 2),
+    ("UGX", 0),
+    ("USD", 2),
+    ("USN", 2),
+    ("UYI", 0),
+    ("UYU", 2),
+    ("UYW", 4),
+    ("UZS", 2),
+    ("VED", 2),
+    ("VES", 2),
+    ("VND", 0),
+    ("VUV", 0),
+    ("WST", 2),
+    ("XAF", 0),
+    ("XCD", 2),
+    ("XCG", 2),
+    ("XOF", 0),
+    ("XPF", 0),
+    ("YER", 2),
+    ("ZAR", 2),
+    ("ZMW", 2),
+    ("ZWG", 2),
+];
+
+/// Codes are case insensitive and stored in upper case
+impl FromStr for CurrencyCode {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !Self::is_code(s) {
+            return Err(ParseCurrencyError);
+        }
+        let mut code = [0; 3];
+        code.copy_from_slice(s.to_ascii_uppercase().as_bytes());
+        Ok(CurrencyCode(code))
+    }
+}
+
+impl fmt::Display for CurrencyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -82,6 +712,15 @@ impl fmt::Display for Currency {
 mod tests {
     use super::*;
 
+    #[test]
+    fn currency_codes() {
+        let code: CurrencyCode = "eur".parse().unwrap();
+        assert_eq!(code.to_string(), "EUR");
+        assert!("EU".parse::<CurrencyCode>().is_err());
+        assert!("1.5".parse::<CurrencyCode>().is_err());
+        assert!(!CurrencyCode::is_code("100"));
+    }
+
     #[test]
     fn can_parse_positive_strings() {
         let num1 = "1.5";
@@ -106,6 +745,14 @@ mod tests {
         assert_eq!(Currency::from_str(num4).unwrap(), Currency(-15000));
     }
 
+    #[test]
+    fn negative_amounts_above_minus_one_keep_their_sign() {
+        // The integer part of "-0.5" is a non negative zero, the sign has to come from the string
+        assert_eq!(Currency::from_str("-0.5").unwrap(), Currency(-5000));
+        assert_eq!(Currency::from_str("-0.0001").unwrap(), Currency(-1));
+        assert_eq!(Currency::from_str("-0").unwrap(), Currency::ZERO);
+    }
+
     #[test]
     fn can_parse_all_decimals() {
         let num1 = "1.0005";
@@ -136,6 +783,121 @@ mod tests {
         assert_eq!(neg_currency3.to_string(), "-1.0050");
         assert_eq!(pos_currency4.to_string(), "1.0005");
         assert_eq!(neg_currency4.to_string(), "-1.0005");
+        assert_eq!(Currency(-2500).to_string(), "-0.2500");
+        assert_eq!(Currency(2500).to_string(), "0.2500");
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-currency"))]
+    fn parse_out_of_range() {
+        assert!(Currency::from_str("922337203685478").is_err());
+        assert!(Currency::from_str("922337203685477.5808").is_err());
+        assert_eq!(
+            Currency::from_str("922337203685477.5807").unwrap(),
+            Currency::MAX
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "wide-currency")]
+    fn parse_out_of_range() {
+        let max = "17014118346046923173168730371588410.5727";
+        assert_eq!(Currency::from_str(max).unwrap(), Currency::MAX);
+        assert!(Currency::from_str("17014118346046923173168730371588410.5728").is_err());
+        assert!(Currency::from_str("1000000000000000000000000000000000000").is_err());
+        assert_eq!(Currency::MAX.to_string(), max);
+        assert_eq!(Currency::MAX.to_i64(), None);
+        // The range of the default backend is a small part of it
+        let total = Currency::from_str("922337203685478").unwrap();
+        assert_eq!(total.units(), 9_223_372_036_854_780_000);
+    }
+
+    #[test]
+    fn conversions() {
+        let one = Currency::new(10000);
+        assert_eq!(one.to_i64(), Some(10000));
+        assert_eq!(one.to_i128(), 10000);
+        assert_eq!(Currency::from_units(one.units()), one);
+        assert_eq!(Currency::from_i128(-10000), Some(-one));
+        assert_eq!(
+            Currency::from_i128(i128::MAX).is_some(),
+            cfg!(feature = "wide-currency")
+        );
+        assert_eq!(Currency::new(i64::MIN).to_i64(), Some(i64::MIN));
+    }
+
+    #[test]
+    fn checked_operations() {
+        let max = Currency::MAX;
+        let min = Currency::MIN;
+        let one = Currency(10000);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(min.checked_sub(one), None);
+        assert_eq!(min.checked_neg(), None);
+        assert_eq!(one.checked_add(one), Some(Currency(20000)));
+        assert_eq!(one.checked_sub(one), Some(Currency(0)));
+        assert_eq!(one.checked_neg(), Some(Currency(-10000)));
+    }
+
+    #[test]
+    fn saturating_operations() {
+        let max = Currency::MAX;
+        let min = Currency::MIN;
+        let one = Currency(10000);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(min.saturating_sub(one), min);
+        assert_eq!(one.saturating_sub(one), Currency(0));
+    }
+
+    #[test]
+    fn amounts_follow_the_exponent_of_their_currency() {
+        let code = |code: &str| code.parse::<CurrencyCode>().unwrap();
+        assert_eq!(code("JPY").exponent(), Some(0));
+        assert_eq!(code("bhd").exponent(), Some(3));
+        assert_eq!(code("EUR").exponent(), Some(2));
+        assert_eq!(code("XAU").exponent(), None);
+        assert!(ISO_4217.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let feed = CurrencyConfig::default();
+        let yen = feed.in_currency(Some(code("JPY")));
+        assert_eq!(yen.parse("1500").ok(), Some(Currency(15_000_000)));
+        assert_eq!(yen.parse("1500.00").ok(), Some(Currency(15_000_000)));
+        assert!(yen.parse("1500.5").is_err());
+        assert_eq!(yen.display(Currency(15_000_000)).to_string(), "1500");
+        // A fraction of a yen, which only conversions could produce, is still shown in full
+        assert_eq!(yen.display(Currency(15_000_001)).to_string(), "1500.0001");
+
+        let dinar = feed.in_currency(Some(code("BHD")));
+        assert_eq!(dinar.parse("-1.234").ok(), Some(Currency(-12_340)));
+        assert!(dinar.parse("1.2345").is_err());
+        assert_eq!(dinar.display(Currency(-12_340)).to_string(), "-1.234");
+        let rounded = CurrencyConfig::new(4, Rounding::HalfEven)
+            .unwrap()
+            .in_currency(Some(code("BHD")));
+        assert_eq!(rounded.parse("1.2345").ok(), Some(Currency(12_340)));
+
+        // Exponents above the feed precision are capped to it
+        let coarse = CurrencyConfig::new(2, Rounding::Reject).unwrap();
+        let unidad = coarse.in_currency(Some(code("CLF")));
+        assert_eq!(unidad.precision(), 2);
+        assert_eq!(unidad.parse("1.25").ok(), Some(Currency(125)));
+        assert_eq!(feed.in_currency(Some(code("XAU"))), feed);
+    }
+
+    #[test]
+    fn sums_only_fail_when_the_total_overflows() {
+        let max = Currency::MAX;
+        let one = Currency(10000);
+        #[cfg(not(feature = "wide-currency"))]
+        assert_eq!(Currency::checked_sum(vec![max, one, -one]), Some(max));
+        assert_eq!(Currency::checked_sum(vec![max, -one, one]), Some(max));
+        assert_eq!(Currency::checked_sum(vec![max; 65536]), None);
+        assert_eq!(Currency::checked_sum(Vec::new()), Some(Currency::ZERO));
+        let overflow = Currency::sum_of("held funds", vec![max, one]).unwrap_err();
+        assert_eq!(
+            overflow.to_string(),
+            "held funds overflows the supported amount range"
+        );
     }
 
     #[test]
@@ -178,4 +940,148 @@ mod tests {
         num3 -= num2;
         assert_eq!(num3, Currency(30000));
     }
+
+    #[test]
+    fn subtraction_and_multiplication() {
+        let one = Currency(10000);
+        assert_eq!(one - Currency(15000), Currency(-5000));
+        assert_eq!(one * 3, Currency(30000));
+        assert_eq!(one * -2, Currency(-20000));
+        assert_eq!(Currency::MAX.checked_mul(2), None);
+        assert_eq!(one.checked_mul(0), Some(Currency::ZERO));
+    }
+
+    #[test]
+    fn compound_assignment_and_sums() {
+        let mut amount = Currency(10000);
+        amount *= 3;
+        assert_eq!(amount, Currency(30000));
+        amount /= 4;
+        assert_eq!(amount, Currency(7500));
+        amount /= 4;
+        assert_eq!(amount, Currency(1875));
+
+        let amounts = [Currency(15000), Currency(-5000), Currency(2500)];
+        assert_eq!(amounts.iter().sum::<Currency>(), Currency(12500));
+        assert_eq!(amounts.iter().copied().sum::<Currency>(), Currency(12500));
+        assert_eq!(
+            Vec::<Currency>::new().into_iter().sum::<Currency>(),
+            Currency::ZERO
+        );
+    }
+
+    #[test]
+    fn parts_and_magnitude() {
+        assert_eq!(Currency(-5).abs(), Currency(5));
+        assert_eq!(Currency::MIN.abs(), Currency::MAX);
+        assert_eq!(Currency(7).positive_part(), Currency(7));
+        assert_eq!(Currency(-7).positive_part(), Currency::ZERO);
+        assert_eq!(Currency(7).negative_part(), Currency::ZERO);
+        assert_eq!(Currency(-7).negative_part(), Currency(7));
+        assert_eq!(Currency::MIN.negative_part(), Currency::MAX);
+        assert_eq!(Currency(3).max(Currency(-4)), Currency(3));
+        assert_eq!(Currency(3).min(Currency(-4)), Currency(-4));
+    }
+
+    #[test]
+    fn division_rounds_half_to_even() {
+        assert_eq!(Currency(5) / 2, Currency(2));
+        assert_eq!(Currency(7) / 2, Currency(4));
+        assert_eq!(Currency(-5) / 2, Currency(-2));
+        assert_eq!(Currency(-7) / 2, Currency(-4));
+        assert_eq!(Currency(7) / -2, Currency(-4));
+        assert_eq!(Currency(10) / 3, Currency(3));
+        assert_eq!(Currency(11) / 3, Currency(4));
+        assert_eq!(Currency(-11) / -3, Currency(4));
+        assert_eq!(Currency(10000) / 3, Currency(3333));
+        assert_eq!(Currency(1).checked_div(0), None);
+        assert_eq!(Currency::MIN.checked_div(-1), None);
+        assert_eq!(Currency::MIN.checked_div(1), Some(Currency::MIN));
+
+        // Every quotient is the nearest one, and the even one when two are as near
+        for numerator in -200..=200i64 {
+            for denominator in (-9..=9i64).filter(|&d| d != 0) {
+                let quotient = (Currency::new(numerator) / denominator).to_i64().unwrap();
+                let error = |q: i64| (numerator - q * denominator).abs() * 2;
+                let den = denominator.abs();
+                assert!(
+                    error(quotient) < den || error(quotient) == den && quotient % 2 == 0,
+                    "{} / {} = {}",
+                    numerator,
+                    denominator,
+                    quotient
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn percentages_in_basis_points() {
+        let amount = Currency(123456);
+        assert_eq!(amount.mul_percent(10000), Some(amount));
+        assert_eq!(amount.mul_percent(0), Some(Currency::ZERO));
+        // 1% of 12.3456 is 0.123456, 0.1235 at 4 decimals
+        assert_eq!(amount.mul_percent(100), Some(Currency(1235)));
+        assert_eq!((-amount).mul_percent(100), Some(Currency(-1235)));
+        // Halves of 0.0001, 0.0003 and 0.0005 go to the even unit
+        assert_eq!(Currency(1).mul_percent(5000), Some(Currency(0)));
+        assert_eq!(Currency(3).mul_percent(5000), Some(Currency(2)));
+        assert_eq!(Currency(-3).mul_percent(5000), Some(Currency(-2)));
+        assert_eq!(Currency(5).mul_percent(5000), Some(Currency(2)));
+        assert_eq!(Currency::MAX.mul_percent(20000), None);
+        assert_eq!(Currency::MAX.mul_percent(u32::MAX), None);
+
+        for units in -100..=100i64 {
+            for bps in [1, 25, 50, 333, 2500, 5000, 7500, 15000].iter() {
+                let share = Currency::new(units)
+                    .mul_percent(*bps)
+                    .unwrap()
+                    .to_i64()
+                    .unwrap();
+                let error = (units * i64::from(*bps) - share * 10000).abs() * 2;
+                assert!(error < 10000 || error == 10000 && share % 2 == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn configured_precision() {
+        let cents = CurrencyConfig::new(2, Rounding::Reject).unwrap();
+        assert_eq!(cents.parse("1.5").unwrap(), Currency(150));
+        assert!(cents.parse("1.505").is_err());
+        assert_eq!(cents.parse("1.500").unwrap(), Currency(150));
+        assert_eq!(cents.display(Currency(-5)).to_string(), "-0.05");
+
+        let crypto = CurrencyConfig::new(8, Rounding::Reject).unwrap();
+        assert_eq!(crypto.parse("0.00000001").unwrap(), Currency(1));
+        assert_eq!(
+            crypto.display(Currency(123456789)).to_string(),
+            "1.23456789"
+        );
+
+        let whole = CurrencyConfig::new(0, Rounding::Reject).unwrap();
+        assert_eq!(whole.display(Currency(-12)).to_string(), "-12");
+        assert!(CurrencyConfig::new(MAX_DECIMALS + 1, Rounding::Reject).is_none());
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let round = |rounding, s| CurrencyConfig::new(2, rounding).unwrap().parse(s).unwrap();
+        assert_eq!(round(Rounding::TowardZero, "1.019"), Currency(101));
+        assert_eq!(round(Rounding::TowardZero, "-1.019"), Currency(-101));
+        assert_eq!(round(Rounding::HalfUp, "1.015"), Currency(102));
+        assert_eq!(round(Rounding::HalfUp, "-1.015"), Currency(-102));
+        assert_eq!(round(Rounding::HalfUp, "1.0149"), Currency(101));
+        assert_eq!(round(Rounding::HalfEven, "1.015"), Currency(102));
+        assert_eq!(round(Rounding::HalfEven, "1.025"), Currency(102));
+        assert_eq!(round(Rounding::HalfEven, "1.0251"), Currency(103));
+        assert_eq!(round(Rounding::HalfEven, "-1.025"), Currency(-102));
+    }
+
+    #[test]
+    fn rejects_malformed_amounts() {
+        for s in ["", "-", ".5", "1.2.3", "1.-2", "--1", "1e5", "1.00005"].iter() {
+            assert!(Currency::from_str(s).is_err(), "{}", s);
+        }
+    }
 }
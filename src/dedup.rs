@@ -0,0 +1,188 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem::{self, Discriminant},
+};
+
+use crate::{
+    policy::DedupPolicy,
+    transaction::{ClientId, Transaction, TxId},
+};
+
+/// Identity of a record: its type, client and transaction id. The type is part of it as a deposit
+/// and the dispute referencing it share the client and the transaction id
+type Key = (Discriminant<Transaction>, ClientId, TxId);
+
+/// Records seen so far, used to skip the duplicates of a replayed log, see `DedupPolicy`
+#[derive(Clone, Debug, Default)]
+pub enum Dedup {
+    #[default]
+    Off,
+    Full(HashSet<Key>),
+    Window {
+        capacity: usize,
+        /// Tick every key was last seen at
+        seen: HashMap<Key, u64>,
+        /// Keys in the order they were seen, a key seen again is queued again and its older
+        /// entries are skipped when they reach the front
+        order: VecDeque<(Key, u64)>,
+        tick: u64,
+    },
+}
+
+impl Dedup {
+    pub fn new(policy: DedupPolicy) -> Self {
+        match policy {
+            DedupPolicy::Off => Dedup::Off,
+            DedupPolicy::Full => Dedup::Full(HashSet::new()),
+            DedupPolicy::Window(capacity) => Dedup::Window {
+                capacity: capacity.max(1),
+                seen: HashMap::new(),
+                order: VecDeque::new(),
+                tick: 0,
+            },
+        }
+    }
+
+    /// Records `transaction` as seen, returns whether it was seen before
+    pub fn check(&mut self, transaction: &Transaction) -> bool {
+        self.insert(key(transaction))
+    }
+
+    /// Forgets that `transaction` was seen, for a record that ended up not being applied
+    pub fn forget(&mut self, transaction: &Transaction) {
+        let key = key(transaction);
+        match self {
+            Dedup::Off => {}
+            Dedup::Full(seen) => {
+                seen.remove(&key);
+            }
+            // The queued entries of the key are skipped once it is no longer seen
+            Dedup::Window { seen, .. } => {
+                seen.remove(&key);
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Key) -> bool {
+        match self {
+            Dedup::Off => false,
+            Dedup::Full(seen) => !seen.insert(key),
+            Dedup::Window {
+                capacity,
+                seen,
+                order,
+                tick,
+            } => {
+                *tick += 1;
+                let duplicate = seen.insert(key, *tick).is_some();
+                order.push_back((key, *tick));
+                // Evicts the least recently seen keys
+                while seen.len() > *capacity {
+                    let (oldest, at) = order.pop_front().expect("every seen key is queued");
+                    if seen.get(&oldest) == Some(&at) {
+                        seen.remove(&oldest);
+                    }
+                }
+                // Drops the stale entries of keys seen again, so the queue stays bounded too
+                if order.len() > 2 * *capacity {
+                    order.retain(|(key, at)| seen.get(key) == Some(at));
+                }
+                duplicate
+            }
+        }
+    }
+
+    /// Keys in the order they were seen, least recent first for a window
+    fn keys(&self) -> Vec<Key> {
+        match self {
+            Dedup::Off => Vec::new(),
+            Dedup::Full(seen) => seen.iter().copied().collect(),
+            Dedup::Window { seen, order, .. } => order
+                .iter()
+                .filter(|(key, at)| seen.get(key) == Some(at))
+                .map(|(key, _)| *key)
+                .collect(),
+        }
+    }
+
+    /// Removes and returns the records of the clients matching `keep`, with the same policy
+    pub fn split_off(&mut self, keep: impl Fn(ClientId) -> bool) -> Self {
+        let (moved, kept): (Vec<_>, Vec<_>) = self.keys().into_iter().partition(|k| keep(k.1));
+        let mut split = self.emptied();
+        *self = self.emptied();
+        for key in kept {
+            self.insert(key);
+        }
+        for key in moved {
+            split.insert(key);
+        }
+        split
+    }
+
+    /// Adds the records seen by `other`, a window keeps its capacity
+    pub fn merge(&mut self, other: Dedup) {
+        for key in other.keys() {
+            self.insert(key);
+        }
+    }
+
+    fn emptied(&self) -> Self {
+        match self {
+            Dedup::Off => Dedup::Off,
+            Dedup::Full(_) => Dedup::new(DedupPolicy::Full),
+            Dedup::Window { capacity, .. } => Dedup::new(DedupPolicy::Window(*capacity)),
+        }
+    }
+}
+
+fn key(transaction: &Transaction) -> Key {
+    (
+        mem::discriminant(transaction),
+        transaction.client(),
+        transaction.tx(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::deposit;
+
+    #[test]
+    fn window_forgets_the_least_recently_seen() {
+        let mut dedup = Dedup::new(DedupPolicy::Window(2));
+        assert!(!dedup.check(&deposit(1, 1, 10000)));
+        assert!(!dedup.check(&deposit(1, 2, 10000)));
+        assert!(dedup.check(&deposit(1, 1, 10000)));
+        assert!(!dedup.check(&Transaction::Dispute { client: 1, tx: 1 }));
+        // 2 was the least recently seen and got evicted, 1 is still in the window
+        assert!(!dedup.check(&deposit(1, 2, 10000)));
+        assert!(!dedup.check(&deposit(1, 3, 10000)));
+        assert!(dedup.check(&deposit(1, 2, 10000)));
+        for tx in 0..100 {
+            dedup.check(&deposit(1, 2, 10000));
+            dedup.check(&deposit(1, tx % 2, 10000));
+        }
+        match &dedup {
+            Dedup::Window { seen, order, .. } => {
+                assert_eq!(seen.len(), 2);
+                assert!(order.len() <= 4);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn full_set_splits_by_client() {
+        let mut dedup = Dedup::new(DedupPolicy::Full);
+        for tx in 1..=3 {
+            dedup.check(&deposit(1, tx, 10000));
+        }
+        dedup.check(&Transaction::Dispute { client: 2, tx: 4 });
+        let mut split = dedup.split_off(|client| client == 2);
+        assert!(split.check(&Transaction::Dispute { client: 2, tx: 4 }));
+        assert!(!split.check(&deposit(1, 1, 10000)));
+        assert!(dedup.check(&deposit(1, 3, 10000)));
+        assert!(!dedup.check(&Transaction::Dispute { client: 2, tx: 4 }));
+    }
+}
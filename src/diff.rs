@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use crate::{
+    client_info::ClientInfo,
+    currency::{Currency, CurrencyCode, CurrencyConfig},
+    payment_engine::ClientTable,
+    transaction::ClientId,
+};
+
+/// Balances of an account compared by `ClientTable::diff`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Balances {
+    pub available: Currency,
+    pub held: Currency,
+    pub locked: bool,
+}
+
+impl From<&ClientInfo> for Balances {
+    fn from(info: &ClientInfo) -> Self {
+        Self {
+            available: info.available_funds(),
+            held: info.held_funds(),
+            locked: info.is_locked(),
+        }
+    }
+}
+
+/// Account whose balances differ between two tables, `None` on the side it doesn't exist
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountDiff {
+    pub client: ClientId,
+    /// Currency of a foreign account, `None` for the regular one
+    pub code: Option<CurrencyCode>,
+    pub left: Option<Balances>,
+    pub right: Option<Balances>,
+}
+
+impl AccountDiff {
+    /// Writes a `client, field, left, right` record for every field that differs, a foreign
+    /// account is written as `client/currency` and a missing one as `-`
+    pub fn write_csv<W: Write>(&self, w: &mut W, currency: CurrencyConfig) -> io::Result<()> {
+        let currency = currency.in_currency(self.code);
+        let field = |balances: Option<&Balances>, i: usize| match balances {
+            Some(b) if i == 0 => currency.display(b.available).to_string(),
+            Some(b) if i == 1 => currency.display(b.held).to_string(),
+            Some(b) => b.locked.to_string(),
+            None => "-".to_string(),
+        };
+        for (i, name) in ["available", "held", "locked"].iter().enumerate() {
+            let (left, right) = (field(self.left.as_ref(), i), field(self.right.as_ref(), i));
+            if left == right {
+                continue;
+            }
+            match self.code {
+                Some(code) => write!(w, "{}/{}", self.client, code)?,
+                None => write!(w, "{}", self.client)?,
+            }
+            writeln!(w, ", {}, {}, {}", name, left, right)?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientTable {
+    /// Accounts whose available or held funds or lock differ in `other`, by client then currency
+    pub fn diff(&self, other: &ClientTable) -> Vec<AccountDiff> {
+        let mut accounts: BTreeMap<_, (Option<Balances>, Option<Balances>)> = BTreeMap::new();
+        for (side, table) in [self, other].iter().enumerate() {
+            for (key, info) in table.accounts() {
+                let entry = accounts.entry(key).or_default();
+                match side {
+                    0 => entry.0 = Some(info.into()),
+                    _ => entry.1 = Some(info.into()),
+                }
+            }
+        }
+        accounts
+            .into_iter()
+            .filter(|(_, (left, right))| left != right)
+            .map(|((client, code), (left, right))| AccountDiff {
+                client,
+                code,
+                left,
+                right,
+            })
+            .collect()
+    }
+
+    /// Every account that exists, the foreign ones with their currency
+    fn accounts(&self) -> impl Iterator<Item = ((ClientId, Option<CurrencyCode>), &ClientInfo)> {
+        let regular = self.clients().map(|(client, info)| ((client, None), info));
+        let foreign = self
+            .foreign
+            .iter()
+            .filter(|(_, info)| info.exists())
+            .map(|(&(client, code), info)| ((client, Some(code)), info));
+        regular.chain(foreign)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn diffs_balances() {
+        let deposit = |client, tx, amount| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        let mut left = ClientTable::new();
+        left.process(vec![deposit(1, 1, 25_000), deposit(2, 2, 10_000)]);
+        let mut right = ClientTable::new();
+        right.process(vec![
+            deposit(1, 1, 25_000),
+            deposit(2, 2, 10_000),
+            Transaction::Dispute { client: 2, tx: 2 },
+            deposit(3, 3, 5_000),
+        ]);
+        assert!(left.diff(&left).is_empty());
+
+        let diffs = left.diff(&right);
+        let mut out = Vec::new();
+        for diff in &diffs {
+            diff.write_csv(&mut out, CurrencyConfig::default()).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2, available, 1.0000, 0.0000\n\
+             2, held, 0.0000, 1.0000\n\
+             3, available, -, 0.5000\n\
+             3, held, -, 0.0000\n\
+             3, locked, -, false\n"
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fmt, io, path::PathBuf};
+
+#[cfg(feature = "serde")]
+use crate::json_parser::ParseJsonError;
+use crate::{
+    builder::ConfigError, client_info::TransactionError, csv_parser::ParseCSVError,
+    currency::AggregationOverflow, payment_engine::MergeError,
+};
+
+/// Any error the engine can run into, grouped by what went wrong so callers can react to the
+/// category rather than to every individual error
+#[derive(Debug)]
+pub enum EngineError {
+    /// Reading the input or writing an output failed
+    Io(io::Error),
+    /// A record of the input couldn't be parsed
+    Parse(ParseCSVError),
+    /// A JSON record couldn't be parsed
+    #[cfg(feature = "serde")]
+    Json(ParseJsonError),
+    /// A transaction that had to be applied was rejected
+    Transaction(TransactionError),
+    /// Two tables couldn't be combined
+    Merge(MergeError),
+    /// A total of a report doesn't fit the amount range, the report was not written
+    Aggregation(AggregationOverflow),
+    /// The engine was configured or invoked incorrectly
+    Usage(String),
+    /// Error while processing one of several input files
+    InFile(PathBuf, Box<EngineError>),
+    /// Error about a record of the input, with its column when it can be told
+    AtLine {
+        line: usize,
+        column: Option<(usize, &'static str)>,
+        record: String,
+        error: Box<EngineError>,
+    },
+}
+
+impl EngineError {
+    /// Process exit code for the category of the error, following the BSD `sysexits.h` values
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            EngineError::Usage(_) => 64,
+            EngineError::Parse(ParseCSVError::IoError(_)) => 74,
+            EngineError::Parse(_) => 65,
+            #[cfg(feature = "serde")]
+            EngineError::Json(ParseJsonError::IoError(_)) => 74,
+            #[cfg(feature = "serde")]
+            EngineError::Json(_) => 65,
+            EngineError::Transaction(_) | EngineError::Merge(_) => 70,
+            EngineError::Aggregation(_) => 65,
+            EngineError::Io(_) => 74,
+            EngineError::InFile(_, e) => e.exit_code(),
+            EngineError::AtLine { error, .. } => error.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "I/O error: {}", e),
+            EngineError::Parse(e) => write!(f, "Invalid record: {:?}", e),
+            #[cfg(feature = "serde")]
+            EngineError::Json(e) => write!(f, "Invalid JSON record: {:?}", e),
+            EngineError::Transaction(e) => write!(f, "Transaction rejected: {:?}", e),
+            EngineError::Merge(e) => write!(f, "Tables can't be merged: {:?}", e),
+            EngineError::Aggregation(e) => write!(f, "Report not written: {}", e),
+            EngineError::Usage(message) => write!(f, "{}", message),
+            EngineError::InFile(path, e) => write!(f, "{}: {}", path.display(), e),
+            EngineError::AtLine {
+                line,
+                column,
+                record,
+                error,
+            } => {
+                write!(f, "line {}", line)?;
+                if let Some((column, name)) = column {
+                    write!(f, ", column {} ({})", column, name)?;
+                }
+                match record.trim() {
+                    "" => write!(f, ": {}, the line is empty", error),
+                    record => write!(f, ": {} in `{}`", error, record),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl EngineError {
+    /// Attributes the error to the input file `path`
+    pub fn in_file(self, path: impl Into<PathBuf>) -> Self {
+        EngineError::InFile(path.into(), Box::new(self))
+    }
+
+    /// Attributes the error to `record` at `line` of the input, and to a column of it if known
+    pub fn at_line(self, line: usize, column: Option<(usize, &'static str)>, record: &str) -> Self {
+        EngineError::AtLine {
+            line,
+            column,
+            record: record.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+/// Report writers return `io::Result`, an aggregation overflow they ran into is unwrapped back
+/// into its own category
+impl From<io::Error> for EngineError {
+    fn from(error: io::Error) -> Self {
+        match error.get_ref().and_then(|e| e.downcast_ref()) {
+            Some(&overflow) => EngineError::Aggregation(overflow),
+            None => EngineError::Io(error),
+        }
+    }
+}
+
+impl From<ParseCSVError> for EngineError {
+    fn from(error: ParseCSVError) -> Self {
+        EngineError::Parse(error)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<ParseJsonError> for EngineError {
+    fn from(error: ParseJsonError) -> Self {
+        EngineError::Json(error)
+    }
+}
+
+impl From<TransactionError> for EngineError {
+    fn from(error: TransactionError) -> Self {
+        EngineError::Transaction(error)
+    }
+}
+
+impl From<MergeError> for EngineError {
+    fn from(error: MergeError) -> Self {
+        EngineError::Merge(error)
+    }
+}
+
+/// A configuration the builder refuses is a usage error
+impl From<ConfigError> for EngineError {
+    fn from(error: ConfigError) -> Self {
+        EngineError::Usage(error.to_string())
+    }
+}
+
+/// A command line clap refuses is a usage error
+#[cfg(feature = "std-io")]
+impl From<clap::Error> for EngineError {
+    fn from(error: clap::Error) -> Self {
+        let message = error.to_string();
+        let message = message.trim_start_matches("error: ").trim_end();
+        EngineError::Usage(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories_have_distinct_exit_codes() {
+        let io = EngineError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        let parse = EngineError::from(ParseCSVError::UnknownRecord);
+        let usage = EngineError::Usage("bad flag".to_string());
+        let merge = EngineError::from(MergeError::ClientConflict(1));
+        let codes = [io, parse, usage, merge].map(|e| e.exit_code());
+        assert_eq!(codes, [74, 65, 64, 70]);
+
+        let located = EngineError::from(ParseCSVError::UnknownRecord).at_line(
+            3,
+            Some((2, "client")),
+            "deposit, x",
+        );
+        assert_eq!(located.exit_code(), 65);
+        assert_eq!(
+            located.to_string(),
+            "line 3, column 2 (client): Invalid record: UnknownRecord in `deposit, x`"
+        );
+
+        let overflow = AggregationOverflow {
+            aggregate: "booked total",
+        };
+        assert!(matches!(
+            EngineError::from(io::Error::from(overflow)),
+            EngineError::Aggregation(e) if e == overflow
+        ));
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    client_info::TransactionError,
+    csv_parser::NORMALIZED_HEADER,
+    currency::{AggregationOverflow, Currency, CurrencyConfig},
+    transaction::{ClientId, Transaction, TxId},
+};
+
+/// Outcome of a single transaction handled by the engine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub transaction: Transaction,
+    pub outcome: Result<(), TransactionError>,
+    /// Precision of the feed the transaction was read from, used to format its amount
+    pub currency: CurrencyConfig,
+}
+
+/// Formatted as a csv record of the form `type, client, tx, amount, outcome`, foreign deposits
+/// and withdrawals carry their currency code before the amount
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, ",
+            self.transaction.kind_name(),
+            self.transaction.client(),
+            self.transaction.tx()
+        )?;
+        if let Some(code) = self.transaction.currency_code() {
+            write!(f, "{}, ", code)?;
+        }
+        if let Some(amount) = self.transaction.record_amount() {
+            write!(f, "{}", self.currency.display(amount))?;
+        }
+        match self.outcome {
+            Ok(()) => write!(f, ", applied"),
+            Err(e) => write!(f, ", rejected {:?}", e),
+        }
+    }
+}
+
+/// Something operators should know about beyond the outcome of the transactions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EngineWarning {
+    /// The history reached the soft memory limit and `trimmed` entries were moved out of memory
+    /// over `passes` trims, repeated trims update the warning until it is taken
+    MemoryPressure {
+        used_bytes: usize,
+        limit_bytes: usize,
+        trimmed: usize,
+        spilled: bool,
+        passes: usize,
+    },
+    /// Spilling to the archive failed, the history was pruned instead
+    SpillFailed(String),
+    /// The counterparty sent a record the dispute protocol doesn't allow
+    ProtocolViolation(Transaction),
+    /// Writing to the write-ahead log failed, the transaction was rejected
+    LogFailed(String),
+    /// The last record of the write-ahead log was torn or corrupt and was cut off on recovery
+    LogTruncated(String),
+    /// A booking reached its value date but crediting it would overflow, it stays booked
+    SettlementFailed { client: ClientId, tx: TxId },
+    /// A scheduled posting to the account of the client would overflow and was skipped, see
+    /// `Schedules`
+    PostingFailed { client: ClientId, tx: TxId },
+    /// The fee of the `FeeSchedule` for transaction `tx` of `client` would overflow and was skipped
+    FeeSkipped { client: ClientId, tx: TxId },
+    /// A dispute, resolve or chargeback named another client than the owner of its transaction
+    /// and was routed to the owner, see `DisputeRouting::TxIdWarn`
+    MisroutedDispute {
+        transaction: Transaction,
+        owner: ClientId,
+    },
+    /// A record of batch `batch` was rejected, every record of the batch was rolled back
+    BatchRolledBack {
+        batch: TxId,
+        failed: Transaction,
+        error: TransactionError,
+    },
+}
+
+impl fmt::Display for EngineWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineWarning::MemoryPressure {
+                used_bytes,
+                limit_bytes,
+                trimmed,
+                spilled,
+                passes,
+            } => write!(
+                f,
+                "history uses {} of {} bytes, {} {} entries in {} trims",
+                used_bytes,
+                limit_bytes,
+                if *spilled { "spilled" } else { "pruned" },
+                trimmed,
+                passes
+            ),
+            EngineWarning::SpillFailed(error) => {
+                write!(f, "spilling history failed, pruned instead: {}", error)
+            }
+            EngineWarning::ProtocolViolation(transaction) => write!(
+                f,
+                "protocol violation: {} of transaction {} of client {} without a dispute",
+                transaction.kind_name(),
+                transaction.tx(),
+                transaction.client()
+            ),
+            EngineWarning::LogFailed(error) => {
+                write!(f, "writing the write-ahead log failed: {}", error)
+            }
+            EngineWarning::LogTruncated(record) => write!(
+                f,
+                "dropped the torn last record of the write-ahead log: {:?}",
+                record
+            ),
+            EngineWarning::SettlementFailed { client, tx } => write!(
+                f,
+                "booking {} of client {} could not be settled at its value date",
+                tx, client
+            ),
+            EngineWarning::PostingFailed { client, tx } => write!(
+                f,
+                "scheduled posting {} to client {} overflows and was skipped",
+                tx, client
+            ),
+            EngineWarning::FeeSkipped { client, tx } => write!(
+                f,
+                "fee of transaction {} of client {} overflows and was skipped",
+                tx, client
+            ),
+            EngineWarning::MisroutedDispute { transaction, owner } => write!(
+                f,
+                "{} of transaction {} names client {} but was routed to its owner {}",
+                transaction.kind_name(),
+                transaction.tx(),
+                transaction.client(),
+                owner
+            ),
+            EngineWarning::BatchRolledBack {
+                batch,
+                failed,
+                error,
+            } => write!(
+                f,
+                "batch {} rolled back as {} {} was rejected: {:?}",
+                batch,
+                failed.kind_name(),
+                failed.tx(),
+                error
+            ),
+        }
+    }
+}
+
+/// Destination for the events emitted while processing transactions
+pub trait EventSink {
+    fn emit(&mut self, event: &Event) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the events of every client to its own `client_<id>.csv` file inside a directory,
+/// so downstream consumers only interested in a few accounts can skip the rest of the firehose
+pub struct PerClientFiles {
+    dir: PathBuf,
+    files: HashMap<ClientId, BufWriter<File>>,
+}
+
+impl PerClientFiles {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+}
+
+impl EventSink for PerClientFiles {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let client = event.transaction.client();
+        let file = match self.files.get_mut(&client) {
+            Some(file) => file,
+            None => {
+                let path = self.dir.join(format!("client_{}.csv", client));
+                let mut file = BufWriter::new(File::create(path)?);
+                writeln!(file, "type, client, tx, amount, outcome")?;
+                self.files.entry(client).or_insert(file)
+            }
+        };
+        writeln!(file, "{}", event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for file in self.files.values_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes every event as a keyed record `client<TAB>event`, keyed by client id so a partitioned
+/// topic keeps the events of a client in order. This is the framing expected by
+/// `kafka-console-producer --property parse.key=true`, so the output can be piped straight into a topic
+pub struct KeyedProducer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> KeyedProducer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> EventSink for KeyedProducer<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        writeln!(self.out, "{}\t{}", event.transaction.client(), event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Coalesces the events of every client into one summary per interval, for consumers that can't
+/// keep up with an event per transaction
+///
+/// Intervals are counted in events like the engine clock, a summary line
+/// `client, interval, applied, rejected, deposited, withdrawn, last_tx` is written for every
+/// client with events in the interval once it closes, by client id. Deposited and withdrawn sum
+/// the amounts of the applied deposits, bookings and withdrawals in the base currency, the
+/// remaining changes only show in the counts, a sum that overflows fails the sink with an
+/// `AggregationOverflow`. The summaries of the interval still open are written on `flush`
+pub struct Coalesced<W: Write> {
+    out: W,
+    every: u64,
+    seen: u64,
+    interval: u64,
+    pending: BTreeMap<ClientId, Summary>,
+}
+
+#[derive(Default)]
+struct Summary {
+    applied: u64,
+    rejected: u64,
+    deposited: Currency,
+    withdrawn: Currency,
+    last_tx: TxId,
+    currency: CurrencyConfig,
+}
+
+impl<W: Write> Coalesced<W> {
+    /// Writes the header right away, `every` is the length of an interval in events
+    pub fn new(mut out: W, every: u64) -> io::Result<Self> {
+        writeln!(
+            out,
+            "client, interval, applied, rejected, deposited, withdrawn, last_tx"
+        )?;
+        Ok(Self {
+            out,
+            every: every.max(1),
+            seen: 0,
+            interval: 0,
+            pending: BTreeMap::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        for (client, s) in std::mem::take(&mut self.pending) {
+            writeln!(
+                self.out,
+                "{}, {}, {}, {}, {}, {}, {}",
+                client,
+                self.interval,
+                s.applied,
+                s.rejected,
+                s.currency.display(s.deposited),
+                s.currency.display(s.withdrawn),
+                s.last_tx
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> EventSink for Coalesced<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let transaction = &event.transaction;
+        let summary = self.pending.entry(transaction.client()).or_default();
+        summary.last_tx = transaction.tx();
+        summary.currency = event.currency;
+        match (event.outcome, transaction) {
+            (Err(_), _) => summary.rejected += 1,
+            (Ok(()), Transaction::Deposit { amount, .. })
+            | (Ok(()), Transaction::Booking { amount, .. }) => {
+                summary.applied += 1;
+                summary.deposited = add(summary.deposited, *amount, "deposited total")?;
+            }
+            (Ok(()), Transaction::Withdraw { amount, .. }) => {
+                summary.applied += 1;
+                summary.withdrawn = add(summary.withdrawn, *amount, "withdrawn total")?;
+            }
+            (Ok(()), _) => summary.applied += 1,
+        }
+        self.seen += 1;
+        if self.seen.is_multiple_of(self.every) {
+            self.write_pending()?;
+            self.interval += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.out.flush()
+    }
+}
+
+fn add(total: Currency, amount: Currency, aggregate: &'static str) -> io::Result<Currency> {
+    Ok(total
+        .checked_add(amount)
+        .ok_or(AggregationOverflow { aggregate })?)
+}
+
+/// Re-emits the accepted transactions as a cleaned csv feed in the normalized schema: lowercase
+/// record types, amounts at the configured precision and a `seq` column numbering the records from 1,
+/// so downstream systems can correlate them without re-implementing the parser's leniency
+pub struct NormalizedFeed<W: Write> {
+    out: W,
+    seq: u64,
+}
+
+impl<W: Write> NormalizedFeed<W> {
+    /// Writes the header right away, so an empty feed is still a valid file
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "{}", NORMALIZED_HEADER.join(", "))?;
+        Ok(Self { out, seq: 0 })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> EventSink for NormalizedFeed<W> {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        if event.outcome.is_err() {
+            return Ok(());
+        }
+        self.seq += 1;
+        let transaction = &event.transaction;
+        write!(
+            self.out,
+            "{}, {}, {}, ",
+            transaction.kind_name(),
+            transaction.client(),
+            transaction.tx()
+        )?;
+        if let Some(code) = transaction.currency_code() {
+            write!(self.out, "{}, ", code)?;
+        }
+        if let Some(amount) = transaction.record_amount() {
+            write!(self.out, "{}", event.currency.display(amount))?;
+        }
+        if let Some(approver) = transaction.approver() {
+            write!(self.out, "{}", approver)?;
+        }
+        // Bookings use the column after the amount for their value date and conversions for their
+        // target currency, the seq comes after it
+        if let Some(value_date) = transaction.value_date() {
+            write!(self.out, ", {}", value_date)?;
+        }
+        if let Some(to) = transaction.target_currency() {
+            write!(self.out, ", {}", to)?;
+        }
+        writeln!(self.out, ", {}", self.seq)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+
+    #[test]
+    fn event_display() {
+        let applied = Event {
+            transaction: Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(15000),
+            },
+            outcome: Ok(()),
+            currency: CurrencyConfig::default(),
+        };
+        let rejected = Event {
+            transaction: Transaction::Dispute { client: 1, tx: 3 },
+            outcome: Err(TransactionError::InvalidTxId),
+            currency: CurrencyConfig::default(),
+        };
+        assert_eq!(applied.to_string(), "deposit, 1, 2, 1.5000, applied");
+        assert_eq!(
+            rejected.to_string(),
+            "dispute, 1, 3, , rejected InvalidTxId"
+        );
+    }
+
+    #[test]
+    fn per_client_files_partition_events() {
+        let dir = std::env::temp_dir().join(format!("bank_events_{}", std::process::id()));
+        let mut sink = PerClientFiles::new(&dir).unwrap();
+        for (client, tx) in [(1, 1), (2, 2), (1, 3)] {
+            sink.emit(&Event {
+                transaction: Transaction::Dispute { client, tx },
+                outcome: Ok(()),
+                currency: CurrencyConfig::default(),
+            })
+            .unwrap();
+        }
+        sink.flush().unwrap();
+        let client1 = fs::read_to_string(dir.join("client_1.csv")).unwrap();
+        let client2 = fs::read_to_string(dir.join("client_2.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(client1.lines().count(), 3);
+        assert_eq!(client2.lines().count(), 2);
+        assert!(client2.ends_with("dispute, 2, 2, , applied\n"));
+    }
+
+    #[test]
+    fn keyed_producer_prefixes_client_key() {
+        let mut producer = KeyedProducer::new(Vec::new());
+        producer
+            .emit(&Event {
+                transaction: Transaction::Withdraw {
+                    client: 7,
+                    tx: 1,
+                    amount: Currency::new(10000),
+                },
+                outcome: Err(TransactionError::Overdraw),
+                currency: CurrencyConfig::default(),
+            })
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(producer.into_inner()).unwrap(),
+            "7\twithdrawal, 7, 1, 1.0000, rejected Overdraw\n"
+        );
+    }
+
+    #[test]
+    fn coalesced_summarizes_every_client_per_interval() {
+        let mut sink = Coalesced::new(Vec::new(), 3).unwrap();
+        let events = [
+            (
+                Transaction::Deposit {
+                    client: 2,
+                    tx: 1,
+                    amount: Currency::new(15000),
+                },
+                Ok(()),
+            ),
+            (
+                Transaction::Deposit {
+                    client: 1,
+                    tx: 2,
+                    amount: Currency::new(10000),
+                },
+                Ok(()),
+            ),
+            (
+                Transaction::Withdraw {
+                    client: 2,
+                    tx: 3,
+                    amount: Currency::new(5000),
+                },
+                Ok(()),
+            ),
+            (
+                Transaction::Withdraw {
+                    client: 2,
+                    tx: 4,
+                    amount: Currency::new(90000),
+                },
+                Err(TransactionError::Overdraw),
+            ),
+            (Transaction::Dispute { client: 2, tx: 1 }, Ok(())),
+        ];
+        for (transaction, outcome) in events.iter() {
+            sink.emit(&Event {
+                transaction: *transaction,
+                outcome: *outcome,
+                currency: CurrencyConfig::default(),
+            })
+            .unwrap();
+        }
+        sink.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "client, interval, applied, rejected, deposited, withdrawn, last_tx
+1, 0, 1, 0, 1.0000, 0.0000, 2
+2, 0, 2, 0, 1.5000, 0.5000, 3
+2, 1, 1, 1, 0.0000, 0.0000, 1
+"
+        );
+    }
+
+    #[test]
+    fn normalized_feed_only_keeps_accepted_records() {
+        let mut feed = NormalizedFeed::new(Vec::new()).unwrap();
+        let events = [
+            (
+                Transaction::Deposit {
+                    client: 2,
+                    tx: 1,
+                    amount: Currency::new(15000),
+                },
+                Ok(()),
+            ),
+            (
+                Transaction::Withdraw {
+                    client: 2,
+                    tx: 2,
+                    amount: Currency::new(90000),
+                },
+                Err(TransactionError::Overdraw),
+            ),
+            (Transaction::Dispute { client: 2, tx: 1 }, Ok(())),
+        ];
+        for (transaction, outcome) in events.iter() {
+            feed.emit(&Event {
+                transaction: *transaction,
+                outcome: *outcome,
+                currency: CurrencyConfig::default(),
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(feed.into_inner()).unwrap(),
+            "type, client, tx, amount, seq\ndeposit, 2, 1, 1.5000, 1\ndispute, 2, 1, , 2\n"
+        );
+    }
+}
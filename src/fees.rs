@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    csv_parser::split_fields,
+    currency::{Currency, CurrencyConfig},
+    fx::Rate,
+};
+
+/// Transactions a fee can be charged for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FeeKind {
+    /// Deposits and bookings, charged when they are applied even if they still have to clear
+    Deposit,
+    Withdrawal,
+    /// Charged on the disputed amount, on top of the `ChargebackFee` of the policy
+    Chargeback,
+}
+
+impl FeeKind {
+    /// Record type the fee is charged for, as in the input
+    pub fn name(self) -> &'static str {
+        match self {
+            FeeKind::Deposit => "deposit",
+            FeeKind::Withdrawal => "withdrawal",
+            FeeKind::Chargeback => "chargeback",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deposit" => Some(FeeKind::Deposit),
+            "withdrawal" => Some(FeeKind::Withdrawal),
+            "chargeback" => Some(FeeKind::Chargeback),
+            _ => None,
+        }
+    }
+}
+
+/// Fee made of a flat amount and a percentage of the amount of the transaction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fee {
+    pub flat: Currency,
+    /// Share of the amount, rounded toward zero to the minor unit of the amounts
+    pub rate: Option<Rate>,
+}
+
+impl Fee {
+    /// Fee on a transaction of `amount`, `None` if it can't be represented
+    pub fn on(&self, amount: Currency, minor_unit: Currency) -> Option<Currency> {
+        let share = match self.rate {
+            Some(rate) => rate.of(amount, minor_unit)?,
+            None => Currency::ZERO,
+        };
+        self.flat.checked_add(share)
+    }
+}
+
+/// Fees charged to the clients for their transactions, in the regular accounts only
+///
+/// A fee is charged in full once its transaction is applied, even if it takes the available funds
+/// below zero, and goes to the fee ledger of the client under the id of the transaction. The fees
+/// collected are credited to the house account, which the report then lists as the fee revenue
+#[derive(Clone, Debug, Default)]
+pub struct FeeSchedule {
+    fees: HashMap<FeeKind, Fee>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the fees from a csv file of `type, flat, rate` records, see `read_csv`
+    pub fn load(path: impl AsRef<Path>, currency: CurrencyConfig) -> io::Result<Self> {
+        Self::read_csv(BufReader::new(File::open(path)?), currency)
+    }
+
+    /// Reads `type, flat, rate` records, the first line is skipped if it is a header. The type is
+    /// `deposit`, `withdrawal` or `chargeback`, the flat amount is parsed with `currency` and the
+    /// rate is a fraction of the amount such as `0.01` for 1%. An empty field leaves that part out
+    pub fn read_csv<R: BufRead>(reader: R, currency: CurrencyConfig) -> io::Result<Self> {
+        let mut schedule = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            match fields[..] {
+                [ref kind, _, _] if i == 0 && kind.eq_ignore_ascii_case("type") => {}
+                [ref empty] if empty.is_empty() => {}
+                [ref kind, ref flat, ref rate] => {
+                    let fee = parse_fee(flat, rate, currency).ok_or_else(|| invalid_fee(&line))?;
+                    let kind = FeeKind::from_name(kind).ok_or_else(|| invalid_fee(&line))?;
+                    schedule.set(kind, fee);
+                }
+                _ => return Err(invalid_fee(&line)),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Sets the fee charged for `kind`, replacing the previous one
+    pub fn set(&mut self, kind: FeeKind, fee: Fee) {
+        self.fees.insert(kind, fee);
+    }
+
+    pub fn fee(&self, kind: FeeKind) -> Option<Fee> {
+        self.fees.get(&kind).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fees.is_empty()
+    }
+}
+
+fn parse_fee(flat: &str, rate: &str, currency: CurrencyConfig) -> Option<Fee> {
+    let flat = match flat {
+        "" => Currency::ZERO,
+        flat => currency.parse(flat).ok().filter(|&f| f >= Currency::ZERO)?,
+    };
+    let rate = match rate {
+        "" => None,
+        rate => Some(rate.parse().ok()?),
+    };
+    Some(Fee { flat, rate })
+}
+
+fn invalid_fee(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid fee record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_fees_from_csv() {
+        let csv = "type, flat, rate\nwithdrawal, 0.5, 0.01\nchargeback, 15,\n";
+        let fees = FeeSchedule::read_csv(csv.as_bytes(), CurrencyConfig::default()).unwrap();
+        let withdrawal = fees.fee(FeeKind::Withdrawal).unwrap();
+        let cent = Currency::new(100);
+        // 0.50 and 1% of 12.3456 rounded down to the cent
+        assert_eq!(
+            withdrawal.on(Currency::new(123456), cent),
+            Some(Currency::new(6200))
+        );
+        assert_eq!(
+            fees.fee(FeeKind::Chargeback),
+            Some(Fee {
+                flat: Currency::new(150000),
+                rate: None
+            })
+        );
+        assert_eq!(fees.fee(FeeKind::Deposit), None);
+
+        for invalid in [
+            "deposit, -1, ",
+            "deposit, 1, -0.1",
+            "transfer, 1, ",
+            "deposit, 1",
+        ]
+        .iter()
+        {
+            assert!(FeeSchedule::read_csv(invalid.as_bytes(), CurrencyConfig::default()).is_err());
+        }
+    }
+}
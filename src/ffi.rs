@@ -0,0 +1,222 @@
+use std::{
+    ffi::{c_char, CStr},
+    io, ptr, slice,
+};
+
+use crate::{
+    csv_parser,
+    currency::{CurrencyConfig, Rounding},
+    payment_engine::ClientTable,
+    transaction::ClientId,
+};
+
+/// The transaction was applied, see `bank_engine_submit`
+pub const BANK_APPLIED: i32 = 0;
+/// The engine rejected the transaction, the accounts are unchanged
+pub const BANK_REJECTED: i32 = 1;
+/// The record couldn't be parsed, or an argument was null
+pub const BANK_INVALID: i32 = -1;
+
+/// Engine handed to C callers, behind the opaque `BankEngine` of `include/bank.h`
+pub struct BankEngine {
+    table: ClientTable,
+}
+
+/// Balances of a client in units of the smallest amount, 10^-decimals
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BankBalance {
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    pub locked: bool,
+}
+
+/// Creates an engine parsing amounts with `decimals` decimals, null if that is above
+/// `MAX_DECIMALS`. The engine is freed with `bank_engine_free`
+#[no_mangle]
+pub extern "C" fn bank_engine_new(decimals: u32) -> *mut BankEngine {
+    let table = CurrencyConfig::new(decimals, Rounding::default())
+        .and_then(|currency| ClientTable::builder().currency(currency).build().ok());
+    match table {
+        Some(table) => Box::into_raw(Box::new(BankEngine { table })),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Frees an engine, null is ignored
+///
+/// # Safety
+///
+/// `engine` comes from `bank_engine_new` and isn't used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn bank_engine_free(engine: *mut BankEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Handles a csv record such as `deposit, 1, 1, 2.5`, returns `BANK_APPLIED`, `BANK_REJECTED` or
+/// `BANK_INVALID`
+///
+/// # Safety
+///
+/// `engine` comes from `bank_engine_new` and `record` is a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn bank_engine_submit(engine: *mut BankEngine, record: *const c_char) -> i32 {
+    let (engine, record) = match (engine.as_mut(), record.is_null()) {
+        (Some(engine), false) => (engine, CStr::from_ptr(record)),
+        _ => return BANK_INVALID,
+    };
+    let currency = engine.table.currency_config();
+    let transaction = match record.to_str() {
+        Ok(record) => csv_parser::parse_line_with(Ok(record.to_string()), currency),
+        Err(_) => return BANK_INVALID,
+    };
+    match transaction.map(|t| engine.table.handle_transaction(t)) {
+        Ok(Ok(())) => BANK_APPLIED,
+        Ok(Err(_)) => BANK_REJECTED,
+        Err(_) => BANK_INVALID,
+    }
+}
+
+/// Writes the balances of `client` to `out`, returns false if the client hasn't been seen or a
+/// balance doesn't fit in 64 bits
+///
+/// # Safety
+///
+/// `engine` comes from `bank_engine_new` and `out` points to a `BankBalance`
+#[no_mangle]
+pub unsafe extern "C" fn bank_engine_balance(
+    engine: *const BankEngine,
+    client: ClientId,
+    out: *mut BankBalance,
+) -> bool {
+    let (engine, out) = match (engine.as_ref(), out.as_mut()) {
+        (Some(engine), Some(out)) => (engine, out),
+        _ => return false,
+    };
+    let info = match engine.table.client(client) {
+        Some(info) => info,
+        None => return false,
+    };
+    let balance = (|| {
+        Some(BankBalance {
+            available: info.available_funds().to_i64()?,
+            held: info.held_funds().to_i64()?,
+            total: info.total_funds().ok()?.to_i64()?,
+            locked: info.is_locked(),
+        })
+    })();
+    match balance {
+        Some(balance) => {
+            *out = balance;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Writes the csv report to `buf` as a NUL terminated string and returns its length without the
+/// terminator. Like `snprintf` a report longer than `len - 1` bytes is truncated, the caller
+/// retries with a buffer of the returned length plus one. `buf` may be null when `len` is 0
+///
+/// # Safety
+///
+/// `engine` comes from `bank_engine_new` and `buf` points to `len` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn bank_engine_report(
+    engine: *const BankEngine,
+    buf: *mut c_char,
+    len: usize,
+) -> usize {
+    let engine = match engine.as_ref() {
+        Some(engine) => engine,
+        None => return 0,
+    };
+    let report = match report(&engine.table) {
+        Ok(report) => report,
+        Err(_) => return 0,
+    };
+    if !buf.is_null() && len > 0 {
+        let buf = slice::from_raw_parts_mut(buf.cast::<u8>(), len);
+        let n = report.len().min(len - 1);
+        buf[..n].copy_from_slice(&report[..n]);
+        buf[n] = 0;
+    }
+    report.len()
+}
+
+fn report(table: &ClientTable) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    table.write_csv(&mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, fs};
+
+    #[test]
+    fn embeds_the_engine() {
+        assert!(bank_engine_new(19).is_null());
+        let engine = bank_engine_new(2);
+        let submit = |record: &str| unsafe {
+            bank_engine_submit(engine, CString::new(record).unwrap().as_ptr())
+        };
+        assert_eq!(submit("deposit, 1, 1, 2.5"), BANK_APPLIED);
+        assert_eq!(submit("withdrawal, 1, 2, 5"), BANK_REJECTED);
+        assert_eq!(submit("deposit, 1, x, 1"), BANK_INVALID);
+        assert_eq!(
+            unsafe { bank_engine_submit(engine, ptr::null()) },
+            BANK_INVALID
+        );
+
+        let mut balance = BankBalance::default();
+        assert!(unsafe { bank_engine_balance(engine, 1, &mut balance) });
+        assert_eq!(
+            balance,
+            BankBalance {
+                available: 250,
+                held: 0,
+                total: 250,
+                locked: false,
+            }
+        );
+        assert!(!unsafe { bank_engine_balance(engine, 2, &mut balance) });
+
+        let len = unsafe { bank_engine_report(engine, ptr::null_mut(), 0) };
+        let mut buf = vec![1 as c_char; len + 1];
+        assert_eq!(
+            unsafe { bank_engine_report(engine, buf.as_mut_ptr(), buf.len()) },
+            len
+        );
+        let report = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap();
+        assert_eq!(
+            report,
+            "client, available, held, total, locked\n1, 2.50, 0.00, 2.50, false\n"
+        );
+        let mut short = vec![1 as c_char; 8];
+        unsafe { bank_engine_report(engine, short.as_mut_ptr(), short.len()) };
+        assert_eq!(
+            unsafe { CStr::from_ptr(short.as_ptr()) }.to_bytes(),
+            b"client,"
+        );
+        unsafe { bank_engine_free(engine) };
+    }
+
+    #[test]
+    fn header_declares_every_export() {
+        let header =
+            fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/include/bank.h")).unwrap();
+        let source = include_str!("ffi.rs");
+        let exports = source
+            .lines()
+            .filter_map(|l| l.split("extern \"C\" fn ").nth(1))
+            .filter_map(|l| l.split('(').next());
+        for export in exports {
+            assert!(header.contains(&format!("{}(", export)), "{}", export);
+        }
+    }
+}
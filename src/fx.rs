@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    str::FromStr,
+};
+
+#[cfg(feature = "serde")]
+use crate::json_parser::object;
+use crate::{
+    csv_parser::split_fields,
+    currency::{Currency, CurrencyCode, CurrencyConfig, ParseCurrencyError, Rounding},
+};
+
+/// Decimals of the exchange rates
+pub const RATE_DECIMALS: u32 = 8;
+
+/// Spreads are given in basis points, hundredths of a percent
+const BASIS_POINTS: i128 = 10_000;
+
+/// Exchange rate with `RATE_DECIMALS` decimals, the amount of the target currency one unit of the
+/// source currency buys
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rate(i64);
+
+impl Rate {
+    pub const ONE: Rate = Rate(10i64.pow(RATE_DECIMALS));
+
+    fn config() -> CurrencyConfig {
+        CurrencyConfig::new(RATE_DECIMALS, Rounding::Reject).expect("within MAX_DECIMALS")
+    }
+
+    /// `amount` times the rate rounded toward zero to a multiple of `minor_unit`, `None` if the
+    /// amount is negative or the result can't be represented
+    pub fn of(self, amount: Currency, minor_unit: Currency) -> Option<Currency> {
+        if amount < Currency::ZERO {
+            return None;
+        }
+        let numerator = amount.to_i128().checked_mul(self.0 as i128)?;
+        let denominator = Rate::ONE.0 as i128 * minor_unit.to_i128();
+        let units = divide(numerator, denominator, Rounding::TowardZero)?;
+        Currency::from_i128(units.checked_mul(minor_unit.to_i128())?)
+    }
+}
+
+/// Rates must be positive and have at most `RATE_DECIMALS` decimals
+impl FromStr for Rate {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = Self::config().parse(s)?;
+        if rate <= Currency::ZERO {
+            return Err(ParseCurrencyError);
+        }
+        rate.to_i64().map(Rate).ok_or(ParseCurrencyError)
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Self::config().display(Currency::new(self.0)))
+    }
+}
+
+/// Exchange rates between pairs of currencies, used by `convert` records
+///
+/// Only the pairs that were set can be converted, the inverse of a rate is not derived as it
+/// would usually differ from the quoted one. The spread is taken from every conversion in favour
+/// of the bank, and the converted amount is rounded to the precision of the amounts with the
+/// configured rounding, `HalfEven` by default
+#[derive(Clone, Debug)]
+pub struct RateTable {
+    rates: HashMap<(CurrencyCode, CurrencyCode), Rate>,
+    spread: u32,
+    rounding: Rounding,
+}
+
+impl Default for RateTable {
+    fn default() -> Self {
+        Self {
+            rates: HashMap::new(),
+            spread: 0,
+            rounding: Rounding::HalfEven,
+        }
+    }
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the rates from a csv file of `from, to, rate` records, or from a file of newline
+    /// delimited JSON objects with the same keys if its extension is `json`, which needs the serde
+    /// feature
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        match path.extension() {
+            #[cfg(feature = "serde")]
+            Some(extension) if extension == "json" => Self::read_json(reader),
+            #[cfg(not(feature = "serde"))]
+            Some(extension) if extension == "json" => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "JSON rate files need a build with the serde feature",
+            )),
+            _ => Self::read_csv(reader),
+        }
+    }
+
+    /// Reads `from, to, rate` records, the first line is skipped if it is a header
+    pub fn read_csv<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut table = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            match fields[..] {
+                [ref from, _, _] if i == 0 && from.eq_ignore_ascii_case("from") => {}
+                [ref from, ref to, ref rate] => table.insert_record(from, to, rate, &line)?,
+                [ref empty] if empty.is_empty() => {}
+                _ => return Err(invalid_rate(&line)),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Reads newline delimited `{"from":"EUR","to":"USD","rate":"1.08"}` objects
+    #[cfg(feature = "serde")]
+    pub fn read_json<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut table = Self::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = object(&line).map_err(io::Error::from)?;
+            let get = |key| fields.get(key).and_then(|v: &Option<String>| v.as_deref());
+            match (get("from"), get("to"), get("rate")) {
+                (Some(from), Some(to), Some(rate)) => table.insert_record(from, to, rate, &line)?,
+                _ => return Err(invalid_rate(&line)),
+            }
+        }
+        Ok(table)
+    }
+
+    fn insert_record(&mut self, from: &str, to: &str, rate: &str, line: &str) -> io::Result<()> {
+        match (from.parse(), to.parse(), rate.parse()) {
+            (Ok(from), Ok(to), Ok(rate)) => {
+                self.set_rate(from, to, rate);
+                Ok(())
+            }
+            _ => Err(invalid_rate(line)),
+        }
+    }
+
+    /// Sets the current rate of the pair, replacing the previous one
+    pub fn set_rate(&mut self, from: CurrencyCode, to: CurrencyCode, rate: Rate) {
+        self.rates.insert((from, to), rate);
+    }
+
+    /// Current rate of the pair, converting a currency to itself is always at par
+    pub fn rate(&self, from: CurrencyCode, to: CurrencyCode) -> Option<Rate> {
+        if from == to {
+            return Some(Rate::ONE);
+        }
+        self.rates.get(&(from, to)).copied()
+    }
+
+    /// Sets the spread in basis points, `None` if it is above 100%
+    pub fn set_spread(&mut self, basis_points: u32) -> Option<()> {
+        if basis_points as i128 > BASIS_POINTS {
+            return None;
+        }
+        self.spread = basis_points;
+        Some(())
+    }
+
+    pub fn spread(&self) -> u32 {
+        self.spread
+    }
+
+    /// Sets how converted amounts with more decimals than the amounts have are rounded, `Reject`
+    /// makes such conversions fail
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+    /// Amount of `to` that `amount` of `from` converts to after the spread, `None` if the pair has
+    /// no rate, the amount is negative or the result can't be represented
+    pub fn convert(
+        &self,
+        amount: Currency,
+        from: CurrencyCode,
+        to: CurrencyCode,
+    ) -> Option<Currency> {
+        self.convert_to(amount, from, to, Currency::new(1))
+    }
+
+    /// Same as `convert` with the result rounded to a multiple of `minor_unit`, the smallest
+    /// amount of `to`, see `CurrencyConfig::minor_unit`
+    pub fn convert_to(
+        &self,
+        amount: Currency,
+        from: CurrencyCode,
+        to: CurrencyCode,
+        minor_unit: Currency,
+    ) -> Option<Currency> {
+        if amount < Currency::ZERO {
+            return None;
+        }
+        let rate = self.rate(from, to)?;
+        let kept = BASIS_POINTS - self.spread as i128;
+        let numerator = amount
+            .to_i128()
+            .checked_mul(rate.0 as i128)?
+            .checked_mul(kept)?;
+        let denominator = Rate::ONE.0 as i128 * BASIS_POINTS * minor_unit.to_i128();
+        let units = divide(numerator, denominator, self.rounding)?;
+        Currency::from_i128(units.checked_mul(minor_unit.to_i128())?)
+    }
+}
+
+/// Divides two non negative numbers, rounding the quotient as `rounding` says
+fn divide(numerator: i128, denominator: i128, rounding: Rounding) -> Option<i128> {
+    let (quotient, remainder) = (numerator / denominator, numerator % denominator);
+    if remainder == 0 {
+        return Some(quotient);
+    }
+    let twice = remainder * 2;
+    let round_up = match rounding {
+        Rounding::Reject => return None,
+        Rounding::TowardZero => false,
+        Rounding::HalfUp => twice >= denominator,
+        Rounding::HalfEven => twice > denominator || twice == denominator && quotient % 2 == 1,
+    };
+    Some(quotient + round_up as i128)
+}
+
+fn invalid_rate(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid exchange rate record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str) -> CurrencyCode {
+        code.parse().unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loads_rates_from_csv_and_json() {
+        let csv = RateTable::read_csv("from, to, rate\nEUR, USD, 1.0845\n".as_bytes()).unwrap();
+        let json = RateTable::read_json(r#"{"from":"EUR","to":"USD","rate":"1.0845"}"#.as_bytes())
+            .unwrap();
+        let expected = "1.0845".parse().ok();
+        assert_eq!(csv.rate(code("EUR"), code("USD")), expected);
+        assert_eq!(json.rate(code("EUR"), code("USD")), expected);
+        assert_eq!(csv.rate(code("USD"), code("EUR")), None);
+        assert!(RateTable::read_csv("EUR, USD, -1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn conversions_apply_the_spread_and_rounding() {
+        let (eur, usd) = (code("EUR"), code("USD"));
+        let mut rates = RateTable::new();
+        rates.set_rate(eur, usd, "1.25".parse().unwrap());
+        // 0.0002 EUR is 0.00025 USD
+        assert_eq!(
+            rates.convert(Currency::new(2), eur, usd),
+            Some(Currency::new(2))
+        );
+        rates.set_rounding(Rounding::HalfUp);
+        assert_eq!(
+            rates.convert(Currency::new(2), eur, usd),
+            Some(Currency::new(3))
+        );
+        rates.set_rounding(Rounding::Reject);
+        assert_eq!(rates.convert(Currency::new(2), eur, usd), None);
+        rates.set_spread(100).unwrap();
+        assert_eq!(
+            rates.convert(Currency::new(1_000_000), eur, usd),
+            Some(Currency::new(1_237_500))
+        );
+        assert_eq!(rates.convert(Currency::new(1), usd, eur), None);
+        assert!(rates.set_spread(10_001).is_none());
+    }
+
+    #[test]
+    fn conversions_round_to_the_minor_unit_of_the_target() {
+        let (eur, jpy) = (code("EUR"), code("JPY"));
+        let mut rates = RateTable::new();
+        rates.set_rate(eur, jpy, "161.37".parse().unwrap());
+        let yen = CurrencyConfig::default().in_currency(Some(jpy));
+        // 1.55 EUR is 250.1235 JPY
+        let converted = rates.convert_to(Currency::new(15500), eur, jpy, yen.minor_unit());
+        assert_eq!(converted, Some(Currency::new(2_500_000)));
+        assert_eq!(yen.display(converted.unwrap()).to_string(), "250");
+    }
+}
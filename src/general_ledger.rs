@@ -0,0 +1,225 @@
+use std::io::{self, Write};
+
+use crate::{
+    currency::{AggregationOverflow, Currency},
+    payment_engine::ClientTable,
+};
+
+/// Header of the trial balance written by `ClientTable::write_trial_balance`
+pub const HEADER: &str = "account, debit, credit";
+
+/// Accounts of the general ledger, the client accounts are rolled up into `ClientFunds` and
+/// `ClientHolds`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GlAccount {
+    /// Funds received from and paid out to the clients
+    Cash,
+    /// What the clients can use, their available and booked funds
+    ClientFunds,
+    /// What the clients have under dispute
+    ClientHolds,
+    /// Disputed withdrawals, which the bank pays back if they are charged back, and the chargeback
+    /// fees the house absorbs
+    ChargebackLosses,
+    /// Fees charged to the clients
+    FeeIncome,
+    /// Interest credited to the clients
+    InterestExpense,
+}
+
+impl GlAccount {
+    pub const ALL: [GlAccount; 6] = [
+        GlAccount::Cash,
+        GlAccount::ClientFunds,
+        GlAccount::ClientHolds,
+        GlAccount::ChargebackLosses,
+        GlAccount::FeeIncome,
+        GlAccount::InterestExpense,
+    ];
+
+    /// Name used in the trial balance
+    pub fn name(self) -> &'static str {
+        match self {
+            GlAccount::Cash => "cash",
+            GlAccount::ClientFunds => "client_funds",
+            GlAccount::ClientHolds => "client_holds",
+            GlAccount::ChargebackLosses => "chargeback_losses",
+            GlAccount::FeeIncome => "fee_income",
+            GlAccount::InterestExpense => "interest_expense",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Double-entry ledger mirroring every movement of the regular client accounts against the
+/// internal accounts, see `ClientTable::enable_general_ledger`
+///
+/// Every posting debits one account and credits another by the same amount, so the debit and
+/// credit balances of the trial balance are always equal. The balances are kept wider than a
+/// `Currency`, unless the `wide-currency` feature is on, and only have to fit one when they are
+/// reported
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeneralLedger {
+    /// Balance of every account in units of the amounts, debit balances are positive
+    balances: [i128; 6],
+}
+
+impl GeneralLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a movement of `amount` from `credit` to `debit`
+    pub fn post(&mut self, debit: GlAccount, credit: GlAccount, amount: Currency) {
+        self.balances[debit.index()] += amount.to_i128();
+        self.balances[credit.index()] -= amount.to_i128();
+    }
+
+    /// Balance of `account`, positive for a debit balance and negative for a credit one
+    pub fn balance(&self, account: GlAccount) -> Result<Currency, AggregationOverflow> {
+        Currency::from_i128(self.balances[account.index()]).ok_or(AggregationOverflow {
+            aggregate: account.name(),
+        })
+    }
+
+    /// Adds the postings of `other` to this ledger
+    pub fn absorb(&mut self, other: &GeneralLedger) {
+        for (balance, other) in self.balances.iter_mut().zip(other.balances.iter()) {
+            *balance += other;
+        }
+    }
+}
+
+impl ClientTable {
+    /// Starts mirroring the movements of the regular accounts in a `GeneralLedger`. The funds the
+    /// clients already have are brought in as cash received, so the client accounts of the ledger
+    /// match the balances from the start. Accounts in foreign currencies are not part of it
+    pub fn enable_general_ledger(&mut self) -> Result<(), AggregationOverflow> {
+        let mut ledger = GeneralLedger::new();
+        for (_, info) in self.clients() {
+            let funds = info
+                .available_funds()
+                .checked_add(info.booked_funds())
+                .ok_or(AggregationOverflow {
+                    aggregate: "client funds",
+                })?;
+            ledger.post(GlAccount::Cash, GlAccount::ClientFunds, funds);
+            ledger.post(GlAccount::Cash, GlAccount::ClientHolds, info.held_funds());
+        }
+        self.general_ledger = Some(ledger);
+        Ok(())
+    }
+
+    pub fn general_ledger(&self) -> Option<&GeneralLedger> {
+        self.general_ledger.as_ref()
+    }
+
+    /// Writes the balance of every account of the general ledger on its debit or credit side,
+    /// followed by a `total` row, nothing if the general ledger is not enabled
+    pub fn write_trial_balance<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let ledger = match &self.general_ledger {
+            Some(ledger) => ledger,
+            None => return Ok(()),
+        };
+        let currency = self.currency_config().in_currency(self.base_currency());
+        let mut rows = Vec::new();
+        for account in GlAccount::ALL.iter() {
+            let balance = ledger.balance(*account)?;
+            let (debit, credit) = if balance < Currency::ZERO {
+                (Currency::ZERO, -balance)
+            } else {
+                (balance, Currency::ZERO)
+            };
+            rows.push((account.name(), debit, credit));
+        }
+        let debits = Currency::sum_of("total debits", rows.iter().map(|r| r.1))?;
+        let credits = Currency::sum_of("total credits", rows.iter().map(|r| r.2))?;
+        writeln!(w, "{}", HEADER)?;
+        for (name, debit, credit) in rows.into_iter().chain(Some(("total", debits, credits))) {
+            writeln!(
+                w,
+                "{}, {}, {}",
+                name,
+                currency.display(debit),
+                currency.display(credit)
+            )?;
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        policy::{ChargebackFee, FeePayer, Policy},
+        transaction::Transaction,
+    };
+
+    #[test]
+    fn trial_balance_matches_the_client_accounts() {
+        let mut table = ClientTable::with_policy(Policy {
+            chargeback_fee: Some(ChargebackFee {
+                amount: Currency::new(5000),
+                payer: FeePayer::Client,
+            }),
+            ..Policy::default()
+        });
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(30000),
+            })
+            .unwrap();
+        table.enable_general_ledger().unwrap();
+        let deposit = |client, tx, amount| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        table.process(vec![
+            deposit(1, 2, 20000),
+            Transaction::Withdraw {
+                client: 1,
+                tx: 3,
+                amount: Currency::new(10000),
+            },
+            deposit(2, 4, 10000),
+            Transaction::Dispute { client: 2, tx: 4 },
+            Transaction::Chargeback { client: 2, tx: 4 },
+            Transaction::Dispute { client: 1, tx: 3 },
+            Transaction::Dispute { client: 1, tx: 2 },
+        ]);
+        let ledger = table.general_ledger().unwrap();
+        let funds = |client| {
+            let info = table.client(client).unwrap();
+            info.available_funds() + info.booked_funds()
+        };
+        assert_eq!(
+            ledger.balance(GlAccount::ClientFunds).unwrap(),
+            -(funds(1) + funds(2))
+        );
+        assert_eq!(
+            ledger.balance(GlAccount::ClientHolds).unwrap(),
+            -table.client(1).unwrap().held_funds()
+        );
+
+        let mut out = Vec::new();
+        table.write_trial_balance(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "account, debit, credit\n\
+             cash, 4.0000, 0.0000\n\
+             client_funds, 0.0000, 1.5000\n\
+             client_holds, 0.0000, 3.0000\n\
+             chargeback_losses, 1.0000, 0.0000\n\
+             fee_income, 0.0000, 0.5000\n\
+             interest_expense, 0.0000, 0.0000\n\
+             total, 5.0000, 5.0000\n"
+        );
+    }
+}
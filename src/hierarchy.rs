@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    iter,
+    path::Path,
+};
+
+use crate::{
+    csv_parser::split_fields,
+    currency::{AggregationOverflow, Currency},
+    payment_engine::ClientTable,
+    policy::LockedAccountPolicy,
+    transaction::{ClientId, Transaction},
+};
+
+/// Parent of every sub-account, as listed in the client master file
+///
+/// Corporate clients keep a sub-ledger per department under a parent account. A lock on a parent
+/// applies to all of its descendants, as do the risk limits of the closest ancestor for the
+/// accounts without limits of their own. `ClientTable::write_rollup` reports every account together
+/// with its descendants
+#[derive(Clone, Debug, Default)]
+pub struct Hierarchy {
+    parents: BTreeMap<ClientId, ClientId>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the hierarchy from a client master file, see `read_csv`
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_csv(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads `client, parent` records, the first line is skipped if it is a header. An empty parent
+    /// makes the client a top level account
+    pub fn read_csv<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut hierarchy = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            match fields[..] {
+                [ref client, ..] if i == 0 && client.eq_ignore_ascii_case("client") => {}
+                [ref empty] if empty.is_empty() => {}
+                [ref client, ref parent] => {
+                    let client = client.parse().map_err(|_| invalid_record(&line))?;
+                    if parent.is_empty() {
+                        hierarchy.parents.remove(&client);
+                        continue;
+                    }
+                    let parent = parent.parse().map_err(|_| invalid_record(&line))?;
+                    if !hierarchy.set_parent(client, parent) {
+                        return Err(invalid_record(&line));
+                    }
+                }
+                _ => return Err(invalid_record(&line)),
+            }
+        }
+        Ok(hierarchy)
+    }
+
+    /// Makes `client` a sub-account of `parent`, returns `false` and leaves the hierarchy unchanged
+    /// if `client` is `parent` or one of its ancestors
+    pub fn set_parent(&mut self, client: ClientId, parent: ClientId) -> bool {
+        if client == parent || self.ancestors(parent).any(|a| a == client) {
+            return false;
+        }
+        self.parents.insert(client, parent);
+        true
+    }
+
+    pub fn parent(&self, client: ClientId) -> Option<ClientId> {
+        self.parents.get(&client).copied()
+    }
+
+    /// Ancestors of `client`, its parent first
+    pub fn ancestors(&self, client: ClientId) -> impl Iterator<Item = ClientId> + '_ {
+        iter::successors(self.parent(client), move |&a| self.parent(a))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    /// Clients with a parent or children
+    fn members(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.parents.iter().flat_map(|(&c, &p)| [c, p])
+    }
+}
+
+/// Balances of an account and its descendants
+#[derive(Clone, Copy, Debug, Default)]
+struct Rollup {
+    available: Currency,
+    held: Currency,
+    total: Currency,
+    /// Accounts summed up, the account itself included if it exists
+    accounts: usize,
+}
+
+impl ClientTable {
+    /// Whether `tx` is refused because an ancestor of its account is locked, which makes the
+    /// account behave as if it were locked itself
+    pub(crate) fn locked_by_parent(&self, tx: &Transaction) -> bool {
+        use Transaction::*;
+        if self.hierarchy().is_empty() {
+            return false;
+        }
+        let credit = match tx {
+            Deposit { .. } | ForeignDeposit { .. } | Booking { .. } => true,
+            Withdraw { .. } | ForeignWithdraw { .. } | Convert { .. } | Dispute { .. } => false,
+            _ => return false,
+        };
+        if credit && self.policy().locked_accounts == LockedAccountPolicy::AcceptDeposits {
+            return false;
+        }
+        self.hierarchy()
+            .ancestors(tx.client())
+            .any(|a| self.clients[a].is_locked())
+    }
+
+    /// Writes every account with the balances of its regular account summed up with the ones of
+    /// all its descendants, along with the number of accounts summed up. `locked` tells whether
+    /// the account or one of its ancestors is locked. Foreign accounts are left out
+    ///
+    /// Fails with an `AggregationOverflow` before writing anything if a sum doesn't fit
+    pub fn write_rollup<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let overflow = AggregationOverflow {
+            aggregate: "A rolled up balance",
+        };
+        let hierarchy = self.hierarchy();
+        let mut rollups: BTreeMap<ClientId, Rollup> = hierarchy
+            .members()
+            .map(|client| (client, Rollup::default()))
+            .collect();
+        for (client, info) in self.clients() {
+            for account in iter::once(client).chain(hierarchy.ancestors(client)) {
+                let rollup = rollups.entry(account).or_default();
+                rollup.available = rollup
+                    .available
+                    .checked_add(info.available_funds())
+                    .ok_or(overflow)?;
+                rollup.held = rollup.held.checked_add(info.held_funds()).ok_or(overflow)?;
+                rollup.total = rollup
+                    .total
+                    .checked_add(info.total_funds()?)
+                    .ok_or(overflow)?;
+                rollup.accounts += 1;
+            }
+        }
+        let currency = self.currency_config();
+        writeln!(w, "client, available, held, total, locked, accounts")?;
+        for (client, rollup) in rollups {
+            let locked = iter::once(client)
+                .chain(hierarchy.ancestors(client))
+                .any(|a| self.clients[a].is_locked());
+            writeln!(
+                w,
+                "{}, {}, {}, {}, {}, {}",
+                client,
+                currency.display(rollup.available),
+                currency.display(rollup.held),
+                currency.display(rollup.total),
+                locked,
+                rollup.accounts
+            )?;
+        }
+        w.flush()
+    }
+}
+
+fn invalid_record(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid client master record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{deposit, withdraw};
+    use crate::{
+        client_info::TransactionError,
+        risk::{Limits, RiskLimits},
+    };
+
+    #[test]
+    fn master_file_rejects_cycles() {
+        let master = "client, parent\n2, 1\n3, 2\n4,\n";
+        let hierarchy = Hierarchy::read_csv(master.as_bytes()).unwrap();
+        assert_eq!(hierarchy.ancestors(3).collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(hierarchy.parent(4), None);
+        assert!(Hierarchy::read_csv("2, 1\n1, 2\n".as_bytes()).is_err());
+        assert!(Hierarchy::read_csv("1, 1\n".as_bytes()).is_err());
+        assert!(Hierarchy::read_csv("1, x\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn locks_and_limits_cascade_and_balances_roll_up() {
+        let mut table = ClientTable::new();
+        table.set_hierarchy(Hierarchy::read_csv("2, 1\n3, 2\n".as_bytes()).unwrap());
+        let mut limits = RiskLimits::new();
+        limits.set(
+            2,
+            Limits {
+                max_withdrawal: Some(Currency::new(10000)),
+                ..Limits::default()
+            },
+        );
+        table.set_limits(limits);
+        for t in [
+            deposit(1, 1, 50000),
+            deposit(2, 2, 30000),
+            deposit(3, 3, 20000),
+        ] {
+            table.handle_transaction(t).unwrap();
+        }
+        // Client 3 has no limits of its own and gets the ones of its parent
+        assert_eq!(
+            table.handle_transaction(withdraw(3, 4, 15000)),
+            Err(TransactionError::LimitExceeded)
+        );
+        table.handle_transaction(withdraw(1, 5, 15000)).unwrap();
+
+        let mut out = Vec::new();
+        table.write_rollup(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client, available, held, total, locked, accounts\n\
+             1, 8.5000, 0.0000, 8.5000, false, 3\n\
+             2, 5.0000, 0.0000, 5.0000, false, 2\n\
+             3, 2.0000, 0.0000, 2.0000, false, 1\n"
+        );
+
+        // Charging back the deposit of client 2 locks it, and client 3 with it
+        for t in [
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+        ] {
+            table.handle_transaction(t).unwrap();
+        }
+        assert_eq!(
+            table.handle_transaction(deposit(3, 6, 100)),
+            Err(TransactionError::AccountLocked)
+        );
+        table.handle_transaction(deposit(1, 7, 100)).unwrap();
+        let mut out = Vec::new();
+        table.write_rollup(&mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("3, 2.0000, 0.0000, 2.0000, true, 1\n"));
+    }
+}
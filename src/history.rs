@@ -0,0 +1,450 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use crate::{
+    client_info::{ClientInfo, ClientTransaction, DisputeState, TransferKind},
+    currency::{AggregationOverflow, Currency},
+    payment_engine::ClientTable,
+    rejects::quote,
+    transaction::{ClientId, Memo, Timestamp, TxId},
+};
+
+/// Header of the csv written by `ClientTable::export_history`
+pub const HEADER: &str = "tx, event, amount, available, held, dispute, time, memo";
+
+/// Header of the csv written by `ClientTable::export_history_with` with `by_tag`
+pub const TAG_HEADER: &str = "memo, entries, available, held";
+
+/// What the history export lists, see `ClientTable::export_history_with`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HistoryOptions {
+    /// Only lists the entries with this memo
+    pub tag: Option<Memo>,
+    /// Lists the `TagTotal` of every memo instead of the entries
+    pub by_tag: bool,
+    /// Writes JSON instead of csv
+    pub json: bool,
+}
+
+/// Entries of a ledger sharing a memo, as computed by `tag_totals`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagTotal {
+    /// `None` for the entries without a memo
+    pub memo: Option<Memo>,
+    pub entries: usize,
+    /// What the entries added to the available funds
+    pub available: Currency,
+    /// What the entries added to the held funds
+    pub held: Currency,
+}
+
+/// A change to the balances of an account, as listed by `ClientTable::ledger`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedgerEntry {
+    pub tx: TxId,
+    /// `deposit`, `withdrawal`, `conversion`, `fee` or `interest` for the transfers, `dispute`,
+    /// `resolve` or `chargeback` for the steps of their disputes
+    pub event: &'static str,
+    /// Amount of the transaction, always positive
+    pub amount: Currency,
+    /// What the entry added to the available funds, negative when it took funds out
+    pub available: Currency,
+    /// What the entry added to the held funds
+    pub held: Currency,
+    /// Where the transaction stands in its dispute lifecycle after the entry, `None` for fees and
+    /// interest which can't be disputed
+    pub dispute: Option<DisputeState>,
+    /// When the transaction took place, if the input gave a timestamp
+    pub at: Option<Timestamp>,
+    /// Memo of the transfer, which its dispute steps and refund share
+    pub memo: Option<Memo>,
+}
+
+impl ClientTable {
+    /// Every change to the balances of `client` still in memory, `None` if the client hasn't been
+    /// seen. Each transfer is followed by the steps of its dispute, the fees and interest come
+    /// last. The order of the disputes relative to the other transfers is not kept, but the entries
+    /// add up to the balances of the account as long as no transfer was archived. Bookings waiting
+    /// for their value date and the accounts in foreign currencies are left out
+    pub fn ledger(&self, client: ClientId) -> Option<Vec<LedgerEntry>> {
+        self.client(client).map(ledger)
+    }
+
+    /// Writes the ledger of `client` as csv, returns `false` without writing anything if the
+    /// client hasn't been seen
+    pub fn export_history<W: Write>(&self, w: W, client: ClientId) -> io::Result<bool> {
+        self.export_history_with(w, client, HistoryOptions::default())
+    }
+
+    /// Same as `export_history` as a JSON array with one object per entry
+    /// Amounts are written as strings, like in `write_json`
+    pub fn export_history_json<W: Write>(&self, w: W, client: ClientId) -> io::Result<bool> {
+        let options = HistoryOptions {
+            json: true,
+            ..HistoryOptions::default()
+        };
+        self.export_history_with(w, client, options)
+    }
+
+    /// Writes the ledger of `client` restricted to a memo, or the totals of every memo, for
+    /// categorized statements. Returns `false` without writing anything if the client hasn't been
+    /// seen
+    pub fn export_history_with<W: Write>(
+        &self,
+        mut w: W,
+        client: ClientId,
+        options: HistoryOptions,
+    ) -> io::Result<bool> {
+        let mut entries = match self.ledger(client) {
+            Some(entries) => entries,
+            None => return Ok(false),
+        };
+        if let Some(tag) = options.tag {
+            entries.retain(|e| e.memo == Some(tag));
+        }
+        let currency = self.currency_config().in_currency(self.base_currency());
+        let memo = |memo: Option<Memo>| memo.map(|m| m.to_string()).unwrap_or_default();
+        if options.by_tag {
+            let totals = tag_totals(&entries)?;
+            if options.json {
+                write!(w, "[")?;
+                for (i, t) in totals.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    writeln!(w, "{}", separator)?;
+                    write!(
+                        w,
+                        "{{\"memo\":{},\"entries\":{},\"available\":\"{}\",\"held\":\"{}\"}}",
+                        json_memo(t.memo),
+                        t.entries,
+                        currency.display(t.available),
+                        currency.display(t.held)
+                    )?;
+                }
+                writeln!(w, "\n]")?;
+            } else {
+                writeln!(w, "{}", TAG_HEADER)?;
+                for t in &totals {
+                    writeln!(
+                        w,
+                        "{}, {}, {}, {}",
+                        quote(&memo(t.memo)),
+                        t.entries,
+                        currency.display(t.available),
+                        currency.display(t.held)
+                    )?;
+                }
+            }
+        } else if options.json {
+            write!(w, "[")?;
+            for (i, e) in entries.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                writeln!(w, "{}", separator)?;
+                write!(
+                    w,
+                    "{{\"tx\":{},\"event\":\"{}\",\"amount\":\"{}\",\"available\":\"{}\",\"held\":\"{}\"",
+                    e.tx,
+                    e.event,
+                    currency.display(e.amount),
+                    currency.display(e.available),
+                    currency.display(e.held)
+                )?;
+                match e.dispute {
+                    Some(state) => write!(w, ",\"dispute\":\"{}\"", state.name())?,
+                    None => write!(w, ",\"dispute\":null")?,
+                }
+                match e.at {
+                    Some(at) => write!(w, ",\"time\":{}", at)?,
+                    None => write!(w, ",\"time\":null")?,
+                }
+                write!(w, ",\"memo\":{}}}", json_memo(e.memo))?;
+            }
+            writeln!(w, "\n]")?;
+        } else {
+            writeln!(w, "{}", HEADER)?;
+            for e in &entries {
+                write!(
+                    w,
+                    "{}, {}, {}, {}, {}, {}, ",
+                    e.tx,
+                    e.event,
+                    currency.display(e.amount),
+                    currency.display(e.available),
+                    currency.display(e.held),
+                    e.dispute.map_or("", DisputeState::name)
+                )?;
+                if let Some(at) = e.at {
+                    write!(w, "{}", at)?;
+                }
+                match e.memo {
+                    Some(m) => writeln!(w, ", {}", quote(m.as_str()))?,
+                    None => writeln!(w, ", ")?,
+                }
+            }
+        }
+        w.flush()?;
+        Ok(true)
+    }
+}
+
+/// Number of entries and what they added to the balances for every memo, the entries without one
+/// first and then by memo
+pub fn tag_totals(entries: &[LedgerEntry]) -> Result<Vec<TagTotal>, AggregationOverflow> {
+    let mut totals: BTreeMap<Option<Memo>, TagTotal> = BTreeMap::new();
+    let overflow = AggregationOverflow {
+        aggregate: "tag total",
+    };
+    for e in entries {
+        let total = totals.entry(e.memo).or_insert(TagTotal {
+            memo: e.memo,
+            entries: 0,
+            available: Currency::ZERO,
+            held: Currency::ZERO,
+        });
+        total.entries += 1;
+        total.available = total.available.checked_add(e.available).ok_or(overflow)?;
+        total.held = total.held.checked_add(e.held).ok_or(overflow)?;
+    }
+    Ok(totals.into_values().collect())
+}
+
+fn json_memo(memo: Option<Memo>) -> String {
+    match memo {
+        Some(memo) => format!(
+            "\"{}\"",
+            memo.as_str().replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => "null".to_string(),
+    }
+}
+
+fn ledger(info: &ClientInfo) -> Vec<LedgerEntry> {
+    let memos: HashMap<_, _> = info.memos().iter().copied().collect();
+    let mut entries = Vec::new();
+    for t in info.history() {
+        let entry = |event, available, held, dispute| LedgerEntry {
+            tx: t.tx(),
+            event,
+            amount: positive(t.amount()),
+            available,
+            held,
+            dispute: Some(dispute),
+            at: t.at(),
+            memo: memos.get(&t.tx()).copied(),
+        };
+        entries.push(entry(
+            t.kind().name(),
+            t.amount(),
+            Currency::ZERO,
+            DisputeState::Undisputed,
+        ));
+        let state = info.dispute_state(t.tx());
+        if state == DisputeState::Undisputed {
+            continue;
+        }
+        let [dispute, resolve, chargeback] = dispute_effects(t);
+        let (available, held) = dispute;
+        entries.push(entry("dispute", available, held, DisputeState::Disputed));
+        let ((available, held), event) = match state {
+            DisputeState::Resolved => (resolve, "resolve"),
+            DisputeState::ChargedBack => (chargeback, "chargeback"),
+            DisputeState::Undisputed | DisputeState::Disputed => continue,
+        };
+        entries.push(entry(event, available, held, state));
+    }
+    for t in info.fees() {
+        entries.push(LedgerEntry {
+            tx: t.tx(),
+            event: t.kind().name(),
+            amount: positive(t.amount()),
+            available: t.amount(),
+            held: Currency::ZERO,
+            dispute: None,
+            at: t.at(),
+            memo: match t.kind() {
+                TransferKind::Refund => memos.get(&t.tx()).copied(),
+                _ => None,
+            },
+        });
+    }
+    entries
+}
+
+/// What opening, resolving and charging back a dispute of `t` add to the available and held funds
+/// A chargeback reversed by an unlock counts as resolved, which has the same effect
+fn dispute_effects(t: &ClientTransaction) -> [(Currency, Currency); 3] {
+    let amount = t.disputed_amount();
+    match t.kind() {
+        TransferKind::Deposit => [
+            (-amount, amount),
+            (amount, -amount),
+            (Currency::ZERO, -amount),
+        ],
+        // A disputed withdrawal is held on top of the available funds
+        TransferKind::Withdrawal
+        | TransferKind::Fee
+        | TransferKind::Conversion
+        | TransferKind::Interest
+        | TransferKind::Refund => [
+            (Currency::ZERO, amount),
+            (Currency::ZERO, -amount),
+            (amount, -amount),
+        ],
+    }
+}
+
+fn positive(amount: Currency) -> Currency {
+    if amount < Currency::ZERO {
+        -amount
+    } else {
+        amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn history_lists_transfers_and_their_disputes() {
+        let mut table = ClientTable::new();
+        let deposit = |tx, amount| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(amount),
+        };
+        table.process(vec![
+            deposit(1, 50000),
+            deposit(2, 20000),
+            Transaction::Withdraw {
+                client: 1,
+                tx: 3,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+            Transaction::Dispute { client: 1, tx: 2 },
+            Transaction::Chargeback { client: 1, tx: 2 },
+        ]);
+        let mut csv = Vec::new();
+        assert!(table.export_history(&mut csv, 1).unwrap());
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "tx, event, amount, available, held, dispute, time, memo\n\
+             1, deposit, 5.0000, 5.0000, 0.0000, undisputed, , \n\
+             1, dispute, 5.0000, -5.0000, 5.0000, disputed, , \n\
+             1, resolve, 5.0000, 5.0000, -5.0000, resolved, , \n\
+             2, deposit, 2.0000, 2.0000, 0.0000, undisputed, , \n\
+             2, dispute, 2.0000, -2.0000, 2.0000, disputed, , \n\
+             2, chargeback, 2.0000, 0.0000, -2.0000, charged_back, , \n\
+             3, withdrawal, 1.0000, -1.0000, 0.0000, undisputed, , \n"
+        );
+        // The entries add up to the balances
+        let entries = table.ledger(1).unwrap();
+        let sum = |f: fn(&LedgerEntry) -> Currency| entries.iter().map(f).sum::<Currency>();
+        let info = table.client(1).unwrap();
+        assert_eq!(sum(|e| e.available), info.available_funds());
+        assert_eq!(sum(|e| e.held), info.held_funds());
+
+        let mut json = Vec::new();
+        assert!(table.export_history_json(&mut json, 1).unwrap());
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with(
+            "[\n{\"tx\":1,\"event\":\"deposit\",\"amount\":\"5.0000\",\"available\":\"5.0000\",\"held\":\"0.0000\",\"dispute\":\"undisputed\",\"time\":null,\"memo\":null},\n"
+        ));
+        assert!(json.ends_with("}\n]\n"));
+        assert!(!table.export_history(Vec::new(), 2).unwrap());
+    }
+
+    #[test]
+    fn disputed_withdrawals_are_held_on_top() {
+        let mut table = ClientTable::new();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(50000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 1, tx: 2 },
+        ]);
+        let entries = table.ledger(1).unwrap();
+        assert_eq!(
+            entries[2],
+            LedgerEntry {
+                tx: 2,
+                event: "dispute",
+                amount: Currency::new(10000),
+                available: Currency::ZERO,
+                held: Currency::new(10000),
+                dispute: Some(DisputeState::Disputed),
+                at: None,
+                memo: None,
+            }
+        );
+    }
+
+    #[test]
+    fn history_filters_and_totals_by_memo() {
+        let mut table = ClientTable::new();
+        let records = [
+            "deposit, 1, 1, 50, , , salary",
+            "withdrawal, 1, 2, 10, , , \"rent, march\"",
+            "withdrawal, 1, 3, 4, , , groceries",
+            "withdrawal, 1, 4, 2, , , groceries",
+            "deposit, 1, 5, 1",
+            "dispute, 1, 3",
+        ];
+        for record in records.iter() {
+            let stamped = crate::csv_parser::parse_line_stamped(
+                Ok(record.to_string()),
+                table.currency_config(),
+            )
+            .unwrap();
+            table.handle_stamped(stamped).unwrap();
+        }
+        let groceries = "groceries".parse().unwrap();
+        assert_eq!(table.client(1).unwrap().memos().len(), 4);
+
+        let export = |options| {
+            let mut out = Vec::new();
+            assert!(table.export_history_with(&mut out, 1, options).unwrap());
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            export(HistoryOptions {
+                tag: Some(groceries),
+                ..HistoryOptions::default()
+            }),
+            "tx, event, amount, available, held, dispute, time, memo\n\
+             3, withdrawal, 4.0000, -4.0000, 0.0000, undisputed, , \"groceries\"\n\
+             3, dispute, 4.0000, 0.0000, 4.0000, disputed, , \"groceries\"\n\
+             4, withdrawal, 2.0000, -2.0000, 0.0000, undisputed, , \"groceries\"\n"
+        );
+        assert_eq!(
+            export(HistoryOptions {
+                by_tag: true,
+                ..HistoryOptions::default()
+            }),
+            "memo, entries, available, held\n\
+             \"\", 1, 1.0000, 0.0000\n\
+             \"groceries\", 3, -6.0000, 4.0000\n\
+             \"rent, march\", 1, -10.0000, 0.0000\n\
+             \"salary\", 1, 50.0000, 0.0000\n"
+        );
+        assert_eq!(
+            export(HistoryOptions {
+                tag: Some(groceries),
+                by_tag: true,
+                json: true,
+            }),
+            "[\n{\"memo\":\"groceries\",\"entries\":3,\"available\":\"-6.0000\",\"held\":\"4.0000\"}\n]\n"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    fmt, fs,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compression::{decompress, is_compressed},
+    csv_parser::{skip_header, Header},
+};
+
+/// Source of input records named on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    /// `-`
+    Stdin,
+    File(PathBuf),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Stdin => write!(f, "-"),
+            Input::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Turns the command line arguments into inputs, `-` reads stdin and arguments with `*` or `?`
+/// in their file name are expanded to the matching files in name order, for shells that don't
+/// expand them. A pattern matching nothing is an error rather than silently reading nothing
+pub fn expand(args: &[String]) -> io::Result<Vec<Input>> {
+    let mut inputs = Vec::with_capacity(args.len());
+    for arg in args {
+        if arg == "-" {
+            inputs.push(Input::Stdin);
+            continue;
+        }
+        let path = Path::new(arg);
+        let pattern = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => name,
+            _ => {
+                inputs.push(Input::File(path.to_path_buf()));
+                continue;
+            }
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut matched = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_match = name.to_str().is_some_and(|name| matches(pattern, name));
+            if is_match && entry.file_type()?.is_file() {
+                matched.push(path.with_file_name(name));
+            }
+        }
+        if matched.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file matches {}", arg),
+            ));
+        }
+        matched.sort();
+        inputs.extend(matched.into_iter().map(Input::File));
+    }
+    Ok(inputs)
+}
+
+/// Combined size of the inputs, `None` if one of them is stdin or compressed as their size as
+/// read isn't known upfront. The headers skipped after the first input are counted too, so the
+/// feed handed out by `Inputs` can be slightly shorter
+pub fn size(inputs: &[Input]) -> Option<u64> {
+    let mut total = 0;
+    for input in inputs {
+        let path = match input {
+            Input::File(path) => path,
+            Input::Stdin => return None,
+        };
+        let mut reader = BufReader::new(File::open(path).ok()?);
+        if is_compressed(reader.fill_buf().ok()?) {
+            return None;
+        }
+        total += reader.get_ref().metadata().ok()?.len();
+    }
+    Some(total)
+}
+
+/// Whether `name` matches the wildcard `pattern`, where `*` stands for any run of characters
+/// and `?` for a single one
+fn matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    // Position after the last `*` seen in the pattern, and where the name was when it was seen
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the star swallow one more character
+                Some((after, at)) => {
+                    star = Some((after, at + 1));
+                    p = after;
+                    n = at + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The inputs read one after the other as a single feed
+///
+/// Every input but the first gets its csv header skipped here, the first one is left to the
+/// caller like a single file would be. A line break is added after an input that doesn't end
+/// with one so its last record isn't glued to the first record of the next input. Compressed
+/// inputs are decompressed on the fly, see `decompress`
+pub struct Inputs {
+    pending: VecDeque<Input>,
+    current: Option<Box<dyn BufRead>>,
+    header: Header,
+    /// Whether any input was opened yet
+    started: bool,
+    /// Whether the last byte handed out was a line break, or nothing was handed out yet
+    at_line_start: bool,
+    /// Whether the line break closing the previous input is due
+    separator: bool,
+    /// Whether inputs are handed out byte for byte, see `raw`
+    raw: bool,
+}
+
+impl Inputs {
+    /// Pass `Header::Absent` for formats without a header
+    pub fn new(inputs: Vec<Input>, header: Header) -> Self {
+        Self {
+            pending: inputs.into(),
+            current: None,
+            header,
+            started: false,
+            at_line_start: true,
+            separator: false,
+            raw: false,
+        }
+    }
+
+    /// Hands the inputs out byte for byte without skipping headers or adding line breaks, for
+    /// binary formats
+    pub fn raw(mut self) -> Self {
+        self.raw = true;
+        self
+    }
+
+    /// Opens the next input, `false` once every input has been read
+    fn advance(&mut self) -> io::Result<bool> {
+        let input = match self.pending.pop_front() {
+            Some(input) => input,
+            None => return Ok(false),
+        };
+        let reader: Box<dyn BufRead> = match &input {
+            Input::Stdin => Box::new(BufReader::new(io::stdin())),
+            Input::File(path) => {
+                Box::new(BufReader::new(File::open(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{}: {}", input, e))
+                })?))
+            }
+        };
+        let mut reader = decompress(reader)?;
+        if self.started && !self.raw {
+            skip_header(&mut reader, self.header)?;
+        }
+        self.started = true;
+        self.current = Some(reader);
+        Ok(true)
+    }
+}
+
+impl Read for Inputs {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Inputs {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            if self.separator {
+                return Ok(b"\n");
+            }
+            let exhausted = match &mut self.current {
+                Some(current) => current.fill_buf()?.is_empty(),
+                None => true,
+            };
+            if !exhausted {
+                break;
+            }
+            if self.current.take().is_some() && !self.at_line_start && !self.raw {
+                self.separator = true;
+                continue;
+            }
+            if !self.advance()? {
+                return Ok(&[]);
+            }
+        }
+        self.current
+            .as_mut()
+            .expect("an input with data is open")
+            .fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.separator {
+            if amt > 0 {
+                self.separator = false;
+                self.at_line_start = true;
+            }
+            return;
+        }
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => return,
+        };
+        if amt > 0 {
+            let buf = current.fill_buf().expect("consuming buffered data");
+            self.at_line_start = buf[amt - 1] == b'\n';
+        }
+        current.consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn wildcards_match_file_names() {
+        assert!(matches("*.csv", "feed.csv"));
+        assert!(matches("feed-??.csv", "feed-01.csv"));
+        assert!(matches("*-*.csv", "a-b-c.csv"));
+        assert!(!matches("*.csv", "feed.json"));
+        assert!(!matches("feed-?.csv", "feed-10.csv"));
+    }
+
+    #[test]
+    fn inputs_are_read_in_order_as_one_feed() {
+        let dir = env::temp_dir().join(format!("bank_inputs_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("b.csv"),
+            "type, client, tx, amount\ndeposit, 2, 2, 1.0",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("a.csv"),
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0\n",
+        )
+        .unwrap();
+        fs::write(dir.join("c.json"), "{}\n").unwrap();
+        let pattern = dir.join("*.csv").to_string_lossy().into_owned();
+        let inputs = expand(&[pattern, "-".to_string()]).unwrap();
+        assert_eq!(
+            inputs,
+            [
+                Input::File(dir.join("a.csv")),
+                Input::File(dir.join("b.csv")),
+                Input::Stdin
+            ]
+        );
+        let missing = expand(&[dir.join("*.txt").to_string_lossy().into_owned()]);
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let mut feed = Inputs::new(inputs[..2].to_vec(), Header::Detect);
+        let mut read = String::new();
+        feed.read_to_string(&mut read).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            read,
+            "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 1.0\n"
+        );
+    }
+}
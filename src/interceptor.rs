@@ -0,0 +1,202 @@
+use crate::{
+    client_info::TransactionError,
+    events::{Event, EventSink},
+    logging::Logger,
+    payment_engine::ClientTable,
+    transaction::Transaction,
+};
+
+/// Hooks run around every transaction handed to `ClientTable::handle_transaction`, for fraud
+/// checks, enrichment or side effects the engine doesn't know about
+///
+/// Interceptors run in the order they were added. The first `before_apply` returning an error
+/// rejects the transaction with it, every interceptor then sees the outcome in `after_apply`
+pub trait TransactionInterceptor: Send {
+    /// Called before `tx` is applied. Changes to `tx` are what gets logged and applied, an error
+    /// rejects it without touching the accounts
+    fn before_apply(&mut self, _tx: &mut Transaction) -> Result<(), TransactionError> {
+        Ok(())
+    }
+
+    /// Called once `event.transaction` was applied or rejected
+    fn after_apply(&mut self, _event: &Event) {}
+}
+
+/// Interceptor logging every transaction before it is applied at the trace level, then its
+/// outcome the same way `Logger` does as an event sink
+#[derive(Debug, Default)]
+pub struct LoggingInterceptor {
+    logger: Logger,
+}
+
+impl LoggingInterceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TransactionInterceptor for LoggingInterceptor {
+    fn before_apply(&mut self, tx: &mut Transaction) -> Result<(), TransactionError> {
+        tracing::trace!(
+            client = tx.client(),
+            tx = tx.tx(),
+            r#type = %tx.kind_name(),
+            "handling"
+        );
+        Ok(())
+    }
+
+    fn after_apply(&mut self, event: &Event) {
+        // A log that can't be written doesn't stop the engine
+        let _ = self.logger.emit(event);
+    }
+}
+
+impl ClientTable {
+    /// Runs `interceptor` around every transaction handled from now on
+    pub fn add_interceptor(&mut self, interceptor: Box<dyn TransactionInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Runs the `before_apply` hooks, stopping at the first one rejecting `tx`
+    pub(crate) fn before_apply(&mut self, tx: &mut Transaction) -> Result<(), TransactionError> {
+        self.interceptors
+            .iter_mut()
+            .try_for_each(|interceptor| interceptor.before_apply(tx))
+    }
+
+    pub(crate) fn after_apply(
+        &mut self,
+        transaction: Transaction,
+        outcome: Result<(), TransactionError>,
+    ) {
+        if self.interceptors.is_empty() {
+            return;
+        }
+        let event = Event {
+            transaction,
+            outcome,
+            currency: self.currency_config(),
+        };
+        for interceptor in &mut self.interceptors {
+            interceptor.after_apply(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        currency::Currency,
+        logging::{self, tests::Captured},
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Refuses withdrawals above a limit and records the outcomes it sees
+    struct Cap {
+        limit: Currency,
+        seen: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl TransactionInterceptor for Cap {
+        fn before_apply(&mut self, tx: &mut Transaction) -> Result<(), TransactionError> {
+            match tx {
+                Transaction::Withdraw { amount, .. } if *amount > self.limit => {
+                    Err(TransactionError::Intercepted)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn after_apply(&mut self, event: &Event) {
+            self.seen.lock().unwrap().push(*event);
+        }
+    }
+
+    /// Tops every deposit up by one unit
+    struct Bonus;
+
+    impl TransactionInterceptor for Bonus {
+        fn before_apply(&mut self, tx: &mut Transaction) -> Result<(), TransactionError> {
+            if let Transaction::Deposit { amount, .. } = tx {
+                *amount += Currency::new(1);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interceptors_veto_and_enrich() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut table = ClientTable::new();
+        table.add_interceptor(Box::new(Bonus));
+        table.add_interceptor(Box::new(Cap {
+            limit: Currency::new(50),
+            seen: Arc::clone(&seen),
+        }));
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(100),
+        };
+        let large = Transaction::Withdraw {
+            client: 1,
+            tx: 2,
+            amount: Currency::new(60),
+        };
+        let small = Transaction::Withdraw {
+            client: 1,
+            tx: 3,
+            amount: Currency::new(40),
+        };
+        assert_eq!(table.handle_transaction(deposit), Ok(()));
+        assert_eq!(
+            table.handle_transaction(large),
+            Err(TransactionError::Intercepted)
+        );
+        assert_eq!(table.handle_transaction(small), Ok(()));
+        assert_eq!(table.clients[1].available_funds(), Currency::new(61));
+
+        let seen = seen.lock().unwrap();
+        let outcomes: Vec<_> = seen.iter().map(|e| (e.transaction, e.outcome)).collect();
+        let enriched = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(101),
+        };
+        assert_eq!(
+            outcomes,
+            [
+                (enriched, Ok(())),
+                (large, Err(TransactionError::Intercepted)),
+                (small, Ok(())),
+            ]
+        );
+    }
+
+    #[test]
+    fn logging_interceptor_logs_outcomes() {
+        let out = Captured::default();
+        let subscriber = logging::subscriber(out.clone(), tracing::Level::TRACE, false, false);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut logging = LoggingInterceptor::new();
+            let mut tx = Transaction::Withdraw {
+                client: 2,
+                tx: 9,
+                amount: Currency::new(10_000),
+            };
+            logging.before_apply(&mut tx).unwrap();
+            logging.after_apply(&Event {
+                transaction: tx,
+                outcome: Err(TransactionError::Overdraw),
+                currency: Default::default(),
+            });
+        });
+        assert_eq!(
+            out.text(),
+            "TRACE handling client=2 tx=9 type=withdrawal\n \
+             WARN transaction{client=2 tx=9 type=withdrawal amount=1.0000}: rejected reason=Overdraw\n"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    approvals::PendingApprovals,
+    client_info::{AccountType, ClientInfo, ClientTransaction, TransferKind},
+    currency::{AggregationOverflow, Currency, CurrencyCode, CurrencyConfig},
+    json_parser::object,
+    payment_engine::ClientTable,
+    transaction::{ClientId, TxId},
+    tx_index::TxIndex,
+    version::ENGINE_VERSION,
+};
+
+/// Name of the interchange format, written in the header object
+pub const INTERCHANGE_FORMAT: &str = "bank-interchange";
+
+/// Version of the interchange format, bumped whenever a change would make older importers
+/// misread an export. Adding keys is not such a change, importers ignore the keys they don't know
+pub const INTERCHANGE_VERSION: u32 = 1;
+
+impl ClientTable {
+    /// Exports the state of every account in a neutral format, meant to migrate the ledger between
+    /// this engine and other systems. Unlike `snapshot` the format is documented and stable
+    ///
+    /// The export is newline delimited JSON, amounts are strings with the precision given in the
+    /// header and history amounts are signed by their effect on the available funds. The first
+    /// object is the header:
+    ///
+    /// `{"format":"bank-interchange","version":1,"engine":"0.1.0","decimals":4,"base_currency":"EUR"}`
+    ///
+    /// It is followed by an `account` object for every account with a summary of its history, the
+    /// currency is `null` for the regular accounts:
+    ///
+    /// `{"record":"account","client":1,"currency":null,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false,"account_type":"standard","transfers":2,"deposited":"2.0000","withdrawn":"0.5000","archived":0}`
+    ///
+    /// and by one object per entry of its active history, the `transfer`s, open `dispute`s and `fee`s:
+    ///
+    /// `{"record":"transfer","client":1,"currency":null,"tx":1,"kind":"deposit","amount":"2.0000"}`
+    ///
+    /// The house account, pending approvals, bookings, legal holds, settled disputes and archived
+    /// transfers are specific to this engine and are not exported
+    pub fn export_interchange<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let currency = self.currency_config();
+        write!(
+            w,
+            "{{\"format\":\"{}\",\"version\":{},\"engine\":\"{}\",\"decimals\":{},\"base_currency\":",
+            INTERCHANGE_FORMAT,
+            INTERCHANGE_VERSION,
+            ENGINE_VERSION,
+            currency.decimals()
+        )?;
+        write_code(&mut w, self.base_currency())?;
+        writeln!(w, "}}")?;
+        let base = self.clients().map(|(client, info)| (client, None, info));
+        let foreign = self
+            .foreign
+            .iter()
+            .filter(|(_, info)| info.exists())
+            .map(|(&(client, code), info)| (client, Some(code), info));
+        for (client, code, info) in base.chain(foreign) {
+            let mut keys = format!(",\"client\":{},\"currency\":", client);
+            match code {
+                Some(code) => keys.push_str(&format!("\"{}\"", code)),
+                None => keys.push_str("null"),
+            }
+            write_account(&mut w, &keys, info, currency)?;
+        }
+        w.flush()
+    }
+
+    /// Replaces the accounts of this table with the ones of an export written by
+    /// `export_interchange` or by another system following its format. The transaction index is
+    /// rebuilt from the imported history and pending approvals are dropped. The export must use
+    /// the precision of this table, and the table is left untouched if it can't be imported
+    pub fn import_interchange<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        let currency = self.currency_config();
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or_else(|| invalid("Missing header"))??;
+        let header = fields(&header)?;
+        let get = |key| header.get(key).and_then(|v: &Option<String>| v.as_deref());
+        if get("format") != Some(INTERCHANGE_FORMAT) {
+            return Err(invalid("Not an interchange export"));
+        }
+        match get("version").map(str::parse::<u32>) {
+            Some(Ok(version)) if version <= INTERCHANGE_VERSION => {}
+            _ => return Err(invalid("Unsupported interchange version")),
+        }
+        if get("decimals") != Some(&currency.decimals().to_string()) {
+            return Err(invalid("The export uses a different precision"));
+        }
+        let mut clients = self.clients.empty_like();
+        let mut foreign: BTreeMap<_, ClientInfo> = BTreeMap::new();
+        let mut tx_index = TxIndex::new(self.tx_index.strategy());
+        let mut tx_codes = HashMap::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = fields(&line)?;
+            let get = |key| record.get(key).and_then(|v| v.as_deref());
+            let corrupt = || invalid(&format!("Invalid interchange record: {}", line));
+            let client: ClientId = get("client")
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(corrupt)?;
+            let code = match get("currency") {
+                Some(code) => Some(code.parse::<CurrencyCode>().map_err(|_| corrupt())?),
+                None => None,
+            };
+            let info = match code.filter(|&code| Some(code) != self.base_currency()) {
+                Some(code) => foreign.entry((client, code)).or_default(),
+                None => &mut clients[client],
+            };
+            if let Some(tx) = restore_record(info, &record, currency, &line)? {
+                tx_index.insert(tx, client);
+                if let Some(code) = code.filter(|&code| Some(code) != self.base_currency()) {
+                    tx_codes.insert(tx, code);
+                }
+            }
+        }
+        self.clients = clients;
+        self.foreign = foreign;
+        self.tx_index = tx_index;
+        self.tx_codes = tx_codes;
+        self.pending = PendingApprovals::default();
+        self.recount_history();
+        self.reschedule_bookings();
+        Ok(())
+    }
+}
+
+/// Writes the `account` object of `info` followed by an object per entry of its active history,
+/// `keys` is inserted after the `record` key of every object
+pub(crate) fn write_account<W: Write>(
+    w: &mut W,
+    keys: &str,
+    info: &ClientInfo,
+    currency: CurrencyConfig,
+) -> io::Result<()> {
+    let total_of = |aggregate, kind| {
+        Currency::sum_of(
+            aggregate,
+            info.history()
+                .iter()
+                .filter(|t| t.kind() == kind)
+                .map(|t| t.amount()),
+        )
+    };
+    let deposited = total_of("deposited total", TransferKind::Deposit)?;
+    let withdrawn = total_of("withdrawn total", TransferKind::Withdrawal)?
+        .checked_neg()
+        .ok_or(AggregationOverflow {
+            aggregate: "withdrawn total",
+        })?;
+    writeln!(
+        w,
+        "{{\"record\":\"account\"{},\"available\":\"{}\",\"held\":\"{}\",\"total\":\"{}\",\"locked\":{},\"account_type\":\"{}\",\"transfers\":{},\"deposited\":\"{}\",\"withdrawn\":\"{}\",\"archived\":{}}}",
+        keys,
+        currency.display(info.available_funds()),
+        currency.display(info.held_funds()),
+        currency.display(info.total_funds()?),
+        info.is_locked(),
+        info.account_type().name(),
+        info.history().len(),
+        currency.display(deposited),
+        currency.display(withdrawn),
+        info.archived()
+    )?;
+    let ledgers = [
+        ("transfer", info.history()),
+        ("dispute", info.open_disputes()),
+        ("fee", info.fees()),
+    ];
+    for (record, entries) in ledgers.iter() {
+        for t in entries.iter() {
+            writeln!(
+                w,
+                "{{\"record\":\"{}\"{},\"tx\":{},\"kind\":\"{}\",\"amount\":\"{}\"}}",
+                record,
+                keys,
+                t.tx(),
+                t.kind().name(),
+                currency.display(t.amount())
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies an object written by `write_account` to `info`, returns the id of a restored transfer
+pub(crate) fn restore_record(
+    info: &mut ClientInfo,
+    record: &HashMap<String, Option<String>>,
+    currency: CurrencyConfig,
+    line: &str,
+) -> io::Result<Option<TxId>> {
+    let get = |key| record.get(key).and_then(|v| v.as_deref());
+    let corrupt = || invalid(&format!("Invalid interchange record: {}", line));
+    let amount = |key| get(key).and_then(|a| currency.parse(a).ok());
+    match get("record") {
+        Some("account") => {
+            let (available, held) = match (amount("available"), amount("held")) {
+                (Some(available), Some(held)) => (available, held),
+                _ => return Err(corrupt()),
+            };
+            if amount("total").is_some_and(|total| available.checked_add(held) != Some(total)) {
+                return Err(invalid(&format!("Unbalanced account: {}", line)));
+            }
+            let account_type = get("account_type")
+                .map_or(Some(AccountType::Standard), AccountType::from_name)
+                .ok_or_else(corrupt)?;
+            let archived = get("archived")
+                .map_or(Ok(0), str::parse)
+                .map_err(|_| corrupt())?;
+            info.restore_balances(
+                available,
+                held,
+                get("locked") == Some("true"),
+                account_type,
+                archived,
+            );
+            Ok(None)
+        }
+        Some(section) => {
+            let tx = get("tx")
+                .and_then(|tx| tx.parse().ok())
+                .ok_or_else(corrupt)?;
+            let kind = get("kind")
+                .and_then(TransferKind::from_name)
+                .ok_or_else(corrupt)?;
+            let amount = amount("amount").ok_or_else(corrupt)?;
+            info.restore_entry(section, ClientTransaction::new(kind, amount, tx))
+                .ok_or_else(corrupt)?;
+            Ok(Some(tx).filter(|_| section == "transfer"))
+        }
+        None => Err(corrupt()),
+    }
+}
+
+fn write_code<W: Write>(w: &mut W, code: Option<CurrencyCode>) -> io::Result<()> {
+    match code {
+        Some(code) => write!(w, "\"{}\"", code),
+        None => write!(w, "null"),
+    }
+}
+
+pub(crate) fn fields(line: &str) -> io::Result<HashMap<String, Option<String>>> {
+    object(line).map_err(io::Error::from)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn export_can_be_imported() {
+        let usd = "USD".parse().unwrap();
+        let mut original = ClientTable::new();
+        original.set_account_type(2, AccountType::Savings);
+        original.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(5000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::ForeignDeposit {
+                client: 1,
+                tx: 4,
+                code: usd,
+                amount: Currency::new(7000),
+            },
+        ]);
+        let mut export = Vec::new();
+        original.export_interchange(&mut export).unwrap();
+        let export = String::from_utf8(export).unwrap();
+        assert!(export.contains(
+            r#"{"record":"account","client":1,"currency":null,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false,"account_type":"standard","transfers":2,"deposited":"2.0000","withdrawn":"0.5000","archived":0}"#
+        ));
+
+        let mut imported = ClientTable::new();
+        imported.import_interchange(export.as_bytes()).unwrap();
+        assert_eq!(imported.to_string(), original.to_string());
+        assert_eq!(imported.clients[2].account_type(), AccountType::Savings);
+        let after = vec![
+            Transaction::Resolve { client: 2, tx: 3 },
+            Transaction::Dispute { client: 1, tx: 4 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 1,
+                amount: Currency::new(1),
+            },
+        ];
+        assert_eq!(original.process(after.clone()), imported.process(after));
+        assert_eq!(imported.to_string(), original.to_string());
+    }
+
+    #[test]
+    fn mismatching_exports_are_refused() {
+        let mut table = ClientTable::new();
+        let header = r#"{"format":"bank-interchange","version":1,"decimals":2}"#;
+        assert!(table.import_interchange(header.as_bytes()).is_err());
+        let unbalanced = r#"{"format":"bank-interchange","version":1,"decimals":4}
+{"record":"account","client":1,"available":"1.0","held":"0","total":"2.0"}"#;
+        assert!(table.import_interchange(unbalanced.as_bytes()).is_err());
+        let newer = r#"{"format":"bank-interchange","version":2,"decimals":4}"#;
+        assert!(table.import_interchange(newer.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, io};
+
+use serde_json::{value::RawValue, Value};
+
+use crate::{
+    csv_parser::{currency_of, parse_record, with_currency_code, ParseCSVError},
+    currency::CurrencyConfig,
+    transaction::Transaction,
+};
+
+/// Parser for newline delimited JSON records such as
+/// `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`
+/// Records are flat objects, only strings, numbers, booleans and null values are supported.
+/// Amounts may be given either as strings or as numbers, strings are preferred as they never go
+/// through a float representation upstream
+#[derive(Debug)]
+pub enum ParseJsonError {
+    IoError(io::Error),
+    /// The line is not a flat JSON object
+    Malformed,
+    /// The object is valid JSON but not a valid transaction
+    InvalidRecord(ParseCSVError),
+}
+
+impl From<io::Error> for ParseJsonError {
+    fn from(error: io::Error) -> Self {
+        ParseJsonError::IoError(error)
+    }
+}
+
+impl From<ParseCSVError> for ParseJsonError {
+    fn from(error: ParseCSVError) -> Self {
+        ParseJsonError::InvalidRecord(error)
+    }
+}
+
+impl From<ParseJsonError> for io::Error {
+    fn from(error: ParseJsonError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", error))
+    }
+}
+
+pub fn parse_line(line: io::Result<String>) -> Result<Transaction, ParseJsonError> {
+    parse_line_with(line, CurrencyConfig::default())
+}
+
+/// Same as `parse_line` for feeds using a different precision than the default one
+pub fn parse_line_with(
+    line: io::Result<String>,
+    currency: CurrencyConfig,
+) -> Result<Transaction, ParseJsonError> {
+    let line = line?;
+    let fields = object(&line)?;
+    let get = |key| fields.get(key).and_then(|v: &Option<String>| v.as_deref());
+    let code = get("currency");
+    // Approvals carry their approver in the amount column of the csv schema and conversions
+    // their target currency in the value date column
+    let transaction = parse_record(
+        get("type"),
+        get("client"),
+        get("tx"),
+        get("amount").or_else(|| get("approver")),
+        get("value_date").or_else(|| get("to")),
+        currency_of(currency, code),
+    )?;
+    Ok(with_currency_code(transaction, code)?)
+}
+
+/// The key/value pairs of a flat JSON object, `null` values are returned as `None` and numbers
+/// and booleans as written
+pub(crate) fn object(line: &str) -> Result<HashMap<String, Option<String>>, ParseJsonError> {
+    let raw: HashMap<String, &RawValue> =
+        serde_json::from_str(line).map_err(|_| ParseJsonError::Malformed)?;
+    raw.into_iter()
+        .map(|(key, value)| {
+            let value = match serde_json::from_str(value.get()) {
+                Ok(Value::Null) => None,
+                Ok(Value::String(text)) => Some(text),
+                Ok(Value::Number(_)) | Ok(Value::Bool(_)) => Some(value.get().to_string()),
+                _ => return Err(ParseJsonError::Malformed),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::Currency;
+
+    fn parse(line: &str) -> Result<Transaction, ParseJsonError> {
+        parse_line(Ok(line.to_string()))
+    }
+
+    #[test]
+    fn parses_deposit_with_string_amount() {
+        let tx = parse(r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}"#).unwrap();
+        assert_eq!(
+            tx,
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(15000),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_fields_in_any_order_with_whitespace() {
+        let tx =
+            parse(r#" { "amount": 2.25, "tx": 7, "type": "withdrawal", "client": 3 } "#).unwrap();
+        assert_eq!(
+            tx,
+            Transaction::Withdraw {
+                client: 3,
+                tx: 7,
+                amount: Currency::new(22500),
+            }
+        );
+    }
+
+    #[test]
+    fn numbers_are_kept_as_written() {
+        let tx = parse(r#"{"type":"deposit","client":1,"tx":1,"amount":0.30000000000000004}"#);
+        assert!(matches!(
+            tx,
+            Err(ParseJsonError::InvalidRecord(
+                ParseCSVError::TooManyDecimals
+            ))
+        ));
+        let tx = parse(r#"{"type":"deposit","client":1,"tx":1,"amount":"1\u002e5"}"#).unwrap();
+        assert_eq!(tx.amount(), Some(Currency::new(15000)));
+    }
+
+    #[test]
+    fn parses_dispute_with_null_amount() {
+        let tx = parse(r#"{"type":"dispute","client":1,"tx":1,"amount":null}"#).unwrap();
+        assert_eq!(tx, Transaction::Dispute { client: 1, tx: 1 });
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(matches!(
+            parse(r#"{"type":"deposit","client":1"#),
+            Err(ParseJsonError::Malformed)
+        ));
+        assert!(matches!(
+            parse(r#"{"type" "deposit"}"#),
+            Err(ParseJsonError::Malformed)
+        ));
+        assert!(matches!(
+            parse(r#"{"type":"deposit","client":[1]}"#),
+            Err(ParseJsonError::Malformed)
+        ));
+        assert!(matches!(
+            parse(r#"{"type":"deposit","client":1,"tx":1}"#),
+            Err(ParseJsonError::InvalidRecord(ParseCSVError::UnknownRecord))
+        ));
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use sled::{transaction::TransactionError, Batch, Transactional, Tree};
+
+use crate::{
+    client_info::ClientInfo,
+    storage::ClientStore,
+    transaction::{ClientId, TxId},
+    version::{CompatCheck, Stamp},
+};
+
+/// Updated clients written to the database at once
+pub const BATCH_CLIENTS: usize = 1024;
+
+/// Key of the engine stamp in the default tree
+const STAMP: &[u8] = b"stamp";
+
+/// Client store persisted in a sled database, so the balances and histories of the clients
+/// outlive the process and a restart picks up where the last run stopped
+///
+/// The `accounts` tree maps a client to its balances, the `history` tree maps a client, a tx id
+/// and the position of the entry in the history of the client to an entry. Keys are big endian,
+/// so the entries of a client and of one of its transactions are contiguous and scanned by prefix.
+/// Values are the snapshot records of the clients, `section, client, fields...`. sled compacts
+/// its log by itself
+///
+/// Updated clients are written in batches of `BATCH_CLIENTS`, and by `flush`, each batch in one
+/// transaction over both trees. Every account stays in memory, there are at most 65536 of them,
+/// while the size of their history is bounded with a memory budget, see `Policy::memory_budget`
+#[derive(Debug)]
+pub struct KvStore {
+    dir: PathBuf,
+    db: sled::Db,
+    accounts: Tree,
+    history: Tree,
+    clients: BTreeMap<ClientId, ClientInfo>,
+    /// Clients handed out for an update since the last batch
+    dirty: BTreeSet<ClientId>,
+    /// First error of a batch written by `update`, which can't report it, returned by `flush`
+    error: Option<io::Error>,
+}
+
+impl KvStore {
+    /// Opens the store in `dir`, creating it if needed, and loads the clients it holds
+    pub fn open(dir: impl Into<PathBuf>, check: CompatCheck) -> io::Result<Self> {
+        let dir = dir.into();
+        let db = sled::open(&dir)?;
+        if let Some(stamp) = db.get(STAMP)? {
+            Stamp::check(&mut &stamp[..], check)?;
+        }
+        let mut stamp = Vec::new();
+        Stamp::write(&mut stamp)?;
+        db.insert(STAMP, stamp)?;
+        let accounts = db.open_tree("accounts")?;
+        let history = db.open_tree("history")?;
+        let mut clients = BTreeMap::new();
+        for entry in accounts.iter() {
+            let (key, account) = entry?;
+            let client = ClientId::from_be_bytes([key[0], key[1]]);
+            let mut info = ClientInfo::default();
+            restore(&mut info, &account)?;
+            // Ordered by their position in the history rather than by tx id
+            let mut entries = history
+                .scan_prefix(key)
+                .map(|entry| entry.map(|(key, record)| (key[key.len() - 4..].to_vec(), record)))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            for (_, record) in entries {
+                restore(&mut info, &record)?;
+            }
+            clients.insert(client, info);
+        }
+        Ok(Self {
+            dir,
+            db,
+            accounts,
+            history,
+            clients,
+            dirty: BTreeSet::new(),
+            error: None,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the clients updated since the last batch
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let mut accounts = Batch::default();
+        let mut history = Batch::default();
+        for client in std::mem::take(&mut self.dirty) {
+            let key = client.to_be_bytes();
+            // Entries trimmed from the history since the last write go away
+            for entry in self.history.scan_prefix(key) {
+                history.remove(entry?.0);
+            }
+            let info = &self.clients[&client];
+            if info.is_pristine() {
+                accounts.remove(&key);
+                continue;
+            }
+            let mut records = Vec::new();
+            info.write_snapshot(&mut records, client)?;
+            let mut records = records.split(|&b| b == b'\n').filter(|r| !r.is_empty());
+            if let Some(account) = records.next() {
+                accounts.insert(&key, account);
+            }
+            for (position, record) in records.enumerate() {
+                history.insert(history_key(client, record, position)?, record);
+            }
+        }
+        (&self.accounts, &self.history)
+            .transaction(|(a, h)| {
+                a.apply_batch(&accounts)?;
+                h.apply_batch(&history)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => io::Error::from(e),
+                TransactionError::Abort(()) => unreachable!("batches are never aborted"),
+            })
+    }
+}
+
+/// `client`, the tx id of `record` and the position of `record` in the history of the client
+fn history_key(client: ClientId, record: &[u8], position: usize) -> io::Result<Vec<u8>> {
+    let tx: Option<TxId> = std::str::from_utf8(record)
+        .ok()
+        .and_then(|r| r.split(',').nth(2))
+        .and_then(|tx| tx.trim().parse().ok());
+    let tx = tx.ok_or_else(|| corrupt(record))?;
+    let mut key = client.to_be_bytes().to_vec();
+    key.extend(tx.to_be_bytes());
+    key.extend((position as u32).to_be_bytes());
+    Ok(key)
+}
+
+/// Restores one snapshot record of a client into `info`
+fn restore(info: &mut ClientInfo, record: &[u8]) -> io::Result<()> {
+    let restored = std::str::from_utf8(record).ok().and_then(|record| {
+        let fields: Vec<_> = record.split(',').map(|f| f.trim()).collect();
+        match fields[..] {
+            [section, _client, ref rest @ ..] => info.read_snapshot(section, rest),
+            _ => None,
+        }
+    });
+    restored.ok_or_else(|| corrupt(record))
+}
+
+fn corrupt(record: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupt store record: {}", String::from_utf8_lossy(record)),
+    )
+}
+
+impl ClientStore for KvStore {
+    fn get(&self, client: ClientId) -> &ClientInfo {
+        ClientStore::get(&self.clients, client)
+    }
+
+    fn update(&mut self, client: ClientId) -> &mut ClientInfo {
+        if self.dirty.len() >= BATCH_CLIENTS && !self.dirty.contains(&client) {
+            if let Err(e) = self.write_batch() {
+                self.error.get_or_insert(e);
+            }
+        }
+        self.dirty.insert(client);
+        self.clients.entry(client).or_default()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+        ClientStore::iter(&self.clients)
+    }
+
+    /// Any of the clients may be changed, so they are all written with the next batch
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+        self.dirty.extend(self.clients.keys());
+        ClientStore::iter_mut(&mut self.clients)
+    }
+
+    /// Tables rebuilt from a snapshot or an export keep the rebuilt clients in memory, the store
+    /// is bound to its directory
+    fn empty_like(&self) -> Box<dyn ClientStore> {
+        Box::new(BTreeMap::<ClientId, ClientInfo>::new())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.write_batch()?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        currency::Currency, payment_engine::ClientTable, policy::Policy, storage::ClientStorage,
+        transaction::Transaction,
+    };
+
+    #[test]
+    fn clients_and_their_history_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("bank_kv_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = || {
+            let store = KvStore::open(&dir, CompatCheck::Strict).unwrap();
+            ClientTable::with_storage(ClientStorage::custom(store), Policy::default())
+        };
+        let deposits = |range: std::ops::Range<u32>| {
+            range
+                .map(|tx| Transaction::Deposit {
+                    client: (tx % 3000) as ClientId,
+                    tx,
+                    amount: Currency::new(10000),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut table = open();
+        // More clients than a batch, so part of them is written before the flush
+        table.process(deposits(0..3000));
+        table.flush_clients().unwrap();
+        drop(table);
+        let mut reopened = open();
+        reopened.process(deposits(3000..3500));
+        reopened
+            .handle_transaction(Transaction::Dispute { client: 7, tx: 7 })
+            .unwrap();
+        reopened.flush_clients().unwrap();
+        let report = reopened.to_string();
+        drop(reopened);
+
+        {
+            let store = KvStore::open(&dir, CompatCheck::Strict).unwrap();
+            // The two deposits of client 7 and the dispute of the first one
+            let mut prefix = 7u16.to_be_bytes().to_vec();
+            assert_eq!(store.history.scan_prefix(&prefix).count(), 3);
+            prefix.extend(7u32.to_be_bytes());
+            assert_eq!(store.history.scan_prefix(&prefix).count(), 2);
+        }
+        let mut restarted = open();
+        assert_eq!(restarted.to_string(), report);
+        // The dispute came back along with the transfers
+        restarted
+            .handle_transaction(Transaction::Resolve { client: 7, tx: 7 })
+            .unwrap();
+        drop(restarted);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
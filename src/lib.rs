@@ -0,0 +1,89 @@
+pub mod annotations;
+pub mod approvals;
+#[cfg(feature = "std-io")]
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_ingest;
+pub mod attestation;
+#[cfg(feature = "std-io")]
+pub mod audit;
+#[cfg(feature = "avro")]
+pub mod avro;
+pub mod batch;
+pub mod binary;
+pub mod builder;
+pub mod cancel;
+#[cfg(feature = "std-io")]
+pub mod cli;
+pub mod client_info;
+pub mod client_map;
+#[cfg(feature = "std-io")]
+pub mod compression;
+pub mod config;
+#[cfg(feature = "connectors")]
+pub mod connectors;
+pub mod credit;
+pub mod csv_parser;
+pub mod currency;
+pub mod dedup;
+pub mod diff;
+pub mod error;
+pub mod events;
+pub mod fees;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fx;
+pub mod general_ledger;
+pub mod hierarchy;
+pub mod history;
+#[cfg(feature = "std-io")]
+pub mod inputs;
+pub mod interceptor;
+#[cfg(feature = "serde")]
+pub mod interchange;
+#[cfg(feature = "serde")]
+pub mod json_parser;
+#[cfg(feature = "kv-store")]
+pub mod kv_store;
+pub mod logging;
+#[cfg(feature = "std-io")]
+pub mod outbox;
+#[cfg(feature = "std-io")]
+pub mod parallel;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod payment_engine;
+pub mod policy;
+#[cfg(feature = "std-io")]
+pub mod progress;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod query;
+pub mod rejects;
+#[cfg(feature = "std-io")]
+pub mod replay;
+pub mod report;
+pub mod risk;
+pub mod rng;
+pub mod schedules;
+#[cfg(feature = "serde")]
+pub mod serialization;
+#[cfg(feature = "std-io")]
+pub mod server;
+#[cfg(feature = "std-io")]
+pub mod snapshot;
+pub mod spec;
+#[cfg(feature = "std-io")]
+pub mod standby;
+pub mod storage;
+#[cfg(test)]
+mod test_fixtures;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod transaction;
+pub mod tx_index;
+pub mod version;
+#[cfg(feature = "std-io")]
+pub mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
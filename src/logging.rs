@@ -0,0 +1,242 @@
+use std::io;
+
+use tracing::{debug, info, info_span, warn, Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::events::{Event, EventSink};
+
+/// Names `--log-level` accepts, from the most to the least severe
+pub const LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Event sink turning the transactions handled by the engine into `tracing` spans and events
+///
+/// Every transaction gets a `transaction` span carrying its client, tx, type and amount. Within
+/// it, applied transactions are logged at the debug level and rejected ones at the warn level with
+/// the reason, so the records explaining a balance can be found by client. `flush` logs the number
+/// of transactions seen at the info level. Where the records go is up to the subscriber, see
+/// `subscriber`
+#[derive(Debug, Default)]
+pub struct Logger {
+    applied: u64,
+    rejected: u64,
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventSink for Logger {
+    fn emit(&mut self, event: &Event) -> io::Result<()> {
+        let t = &event.transaction;
+        let span = info_span!(
+            "transaction",
+            client = t.client(),
+            tx = t.tx(),
+            r#type = %t.kind_name(),
+            currency = tracing::field::Empty,
+            amount = tracing::field::Empty,
+            approver = tracing::field::Empty,
+        );
+        if !span.is_disabled() {
+            if let Some(code) = t.currency_code() {
+                span.record("currency", tracing::field::display(code));
+            }
+            if let Some(amount) = t.record_amount() {
+                span.record(
+                    "amount",
+                    tracing::field::display(event.currency.display(amount)),
+                );
+            }
+            if let Some(approver) = t.approver() {
+                span.record("approver", approver);
+            }
+        }
+        let _entered = span.enter();
+        match event.outcome {
+            Ok(()) => {
+                self.applied += 1;
+                debug!("applied")
+            }
+            Err(e) => {
+                self.rejected += 1;
+                warn!(reason = ?e, "rejected")
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        info!(
+            applied = self.applied,
+            rejected = self.rejected,
+            "transactions handled"
+        );
+        Ok(())
+    }
+}
+
+/// Subscriber writing the records up to `max_level` to `out`, one per line
+///
+/// Records are written as `LEVEL span{field=value ...}: message field=value`, or as JSON objects
+/// with the fields of the event and of its span when `json` is set. `timestamps` prefixes them
+/// with the time of the wall clock
+pub fn subscriber<W>(
+    out: W,
+    max_level: Level,
+    json: bool,
+    timestamps: bool,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_writer(out)
+        .with_max_level(max_level)
+        .with_target(false)
+        .with_ansi(false);
+    match (json, timestamps) {
+        (false, true) => Box::new(builder.finish()),
+        (false, false) => Box::new(builder.without_time().finish()),
+        (true, timestamps) => {
+            let builder = builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false);
+            match timestamps {
+                true => Box::new(builder.finish()),
+                false => Box::new(builder.without_time().finish()),
+            }
+        }
+    }
+}
+
+/// Logs to stderr from now on, see `subscriber`
+pub fn install(max_level: Level, json: bool, timestamps: bool) -> io::Result<()> {
+    tracing::subscriber::set_global_default(subscriber(io::stderr, max_level, json, timestamps))
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        client_info::TransactionError,
+        currency::{Currency, CurrencyConfig},
+        transaction::Transaction,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Records written to memory, to be read back by the tests
+    #[derive(Clone, Default)]
+    pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Captured {
+        pub(crate) fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&'w self) -> Self {
+            self.clone()
+        }
+    }
+
+    fn events() -> [Event; 2] {
+        [
+            Event {
+                transaction: Transaction::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: Currency::new(15000),
+                },
+                outcome: Ok(()),
+                currency: CurrencyConfig::default(),
+            },
+            Event {
+                transaction: Transaction::Withdraw {
+                    client: 1,
+                    tx: 2,
+                    amount: Currency::new(90000),
+                },
+                outcome: Err(TransactionError::Overdraw),
+                currency: CurrencyConfig::default(),
+            },
+        ]
+    }
+
+    #[test]
+    fn rejections_are_logged_within_their_transaction_span() {
+        let out = Captured::default();
+        let text = subscriber(out.clone(), Level::INFO, false, false);
+        tracing::subscriber::with_default(text, || {
+            let mut logger = Logger::new();
+            for event in events().iter() {
+                logger.emit(event).unwrap();
+            }
+            logger.flush().unwrap();
+        });
+        assert_eq!(
+            out.text(),
+            " WARN transaction{client=1 tx=2 type=withdrawal amount=9.0000}: rejected reason=Overdraw\n \
+             INFO transactions handled applied=1 rejected=1\n"
+        );
+
+        let out = Captured::default();
+        let json = subscriber(out.clone(), Level::DEBUG, true, false);
+        tracing::subscriber::with_default(json, || {
+            let mut logger = Logger::new();
+            for event in events().iter() {
+                logger.emit(event).unwrap();
+            }
+        });
+        let records: Vec<serde_json::Value> = out
+            .text()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            [
+                serde_json::json!({
+                    "level": "DEBUG",
+                    "message": "applied",
+                    "span": {
+                        "name": "transaction",
+                        "client": 1,
+                        "tx": 1,
+                        "type": "deposit",
+                        "amount": "1.5000",
+                    },
+                }),
+                serde_json::json!({
+                    "level": "WARN",
+                    "message": "rejected",
+                    "reason": "Overdraw",
+                    "span": {
+                        "name": "transaction",
+                        "client": 1,
+                        "tx": 2,
+                        "type": "withdrawal",
+                        "amount": "9.0000",
+                    },
+                }),
+            ]
+        );
+    }
+}
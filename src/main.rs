@@ -1,36 +1,1670 @@
-use csv_parser::parse_line;
-use payment_engine::ClientTable;
+#[cfg(feature = "serde")]
+use bank::json_parser;
+use bank::{
+    annotations::{Annotations, Disposition},
+    archive::Archive,
+    attestation,
+    audit::AuditLog,
+    binary,
+    cancel::CancellationToken,
+    cli,
+    client_map::ClientMap,
+    config::{Amount, EngineConfig, Overdraw},
+    csv_parser::{self, Header, Records},
+    currency::{Currency, CurrencyConfig, Rounding},
+    error::EngineError,
+    events::{Coalesced, EventSink, KeyedProducer, NormalizedFeed, PerClientFiles},
+    fx::RateTable,
+    hierarchy::Hierarchy,
+    history::HistoryOptions,
+    inputs::{self, Input, Inputs},
+    logging::{self, Logger},
+    outbox::{Outbox, RetryPolicy},
+    payment_engine::ClientTable,
+    policy::{
+        ClearingDelay, DedupPolicy, DisputeRouting, HistoryLookup, MemoryBudget, Policy,
+        PressureAction, SettlementPolicy,
+    },
+    progress::{self, Counted, InputOffset, Progress},
+    query::Query,
+    rejects::{RejectReason, Rejects, PARSE_ERROR},
+    replay::{self, ArrivalProfile},
+    report::{ReportOptions, ReportOrder},
+    schedules::Schedules,
+    server::{self, Server},
+    spec::SpecTable,
+    standby::{Shipper, Standby},
+    storage::{ClientStorage, Layout},
+    transaction::{ClientId, Memo, Stamped, Transaction},
+    tx_index::IndexStrategy,
+    version::{CompatCheck, Stamp},
+    wal::Wal,
+};
 use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
     env,
-    fs::File,
-    io::{self, BufRead, BufReader},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
 };
-mod client_info;
-mod csv_parser;
-mod currency;
-mod payment_engine;
-mod transaction;
-
-fn main() -> Result<(), io::Error> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Please supply an csv file");
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Missing csv file",
+use tracing::{info, warn};
+
+/// Transfers per client kept in memory when the memory budget is nearly used up
+const KEEP_UNDER_PRESSURE: usize = 16;
+
+/// Records per interval of `--events-coalesced` unless `--coalesce-every` says otherwise
+const COALESCE_EVERY: u64 = 10_000;
+
+/// How often a server started with `--ship-to` ships what it handled, unless `--ship-every` says otherwise
+const SHIP_EVERY: Duration = Duration::from_secs(1);
+
+/// How often a standby looks for newly shipped files
+const STANDBY_POLL: Duration = Duration::from_millis(200);
+
+/// Flags a run of the original specification accepts, they have no effect on it
+const SPEC_FLAGS: &[&str] = &["--spec-compat", "--deterministic"];
+
+/// Supported input formats, selected with `--format`
+enum Format {
+    Csv,
+    Json,
+    /// Fixed width records, see `binary::encode`
+    Bin,
+    /// Parquet files, the report is written as one too, see `parquet::Decoder`
+    Parquet,
+    /// Avro object container files, see `avro::Reader`
+    Avro,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), EngineError> {
+    let mut format = Format::Csv;
+    let mut independent = false;
+    let mut client_map = None;
+    let mut threads = 1;
+    let mut compat = CompatCheck::Strict;
+    let mut header = Header::default();
+    let mut memory_budget = None;
+    let mut spill_to = None;
+    let mut store = None;
+    let mut restore_from = None;
+    let mut snapshot_to = None;
+    let mut checkpoint_every = None;
+    let mut resume_from = None;
+    let mut progress_every = None;
+    let mut import_from = None;
+    let mut export_to = None;
+    let mut wal = None;
+    let mut attest_key = None;
+    let mut query = None;
+    let mut history = None;
+    let mut history_json = false;
+    let mut history_tag: Option<Memo> = None;
+    let mut by_tag = false;
+    let mut malformed = Malformed::default();
+    let mut serve = None;
+    let mut ship_to = None;
+    let mut ship_every = SHIP_EVERY;
+    let mut admin_token = None;
+    let mut verbose = false;
+    let mut standby = None;
+    let mut replay = None;
+    let mut arrivals = None;
+    let mut tx_index = None;
+    let mut storage = None;
+    let mut history_lookup = None;
+    let mut rejects = None;
+    let mut annotations = None;
+    let mut deterministic = cfg!(feature = "audit-build");
+    let mut rates = None;
+    let mut fx_spread = 0;
+    let mut schedules = None;
+    let mut trial_balance = None;
+    let mut client_master = None;
+    let mut accrue_every = None;
+    let mut fx_rounding = None;
+    let mut apply = false;
+    let mut convert = false;
+    let mut diff = false;
+    let mut state = None;
+    let mut audit_log = None;
+    // Fields of the record posted with `bank apply`, in the order of the csv columns
+    let mut record: [Option<String>; 6] = Default::default();
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    let mut coalesced = None;
+    let mut coalesce_every = COALESCE_EVERY;
+    let mut keyed = None;
+    let mut log_level = None;
+    let mut log_json = false;
+    let mut outbox = None;
+    let mut retry = RetryPolicy::default();
+    let (config_file, args) = without_config(env::args().skip(1))?;
+    // The settings of the file, which the flags of the command line override
+    let mut config = match config_file {
+        Some(path) => read_config(&path)?,
+        None => EngineConfig::default(),
+    };
+    let matches = match cli::command().try_get_matches_from(env::args().take(1).chain(args)) {
+        Ok(matches) => matches,
+        // `--help` is printed rather than refused
+        Err(e) if !e.use_stderr() => {
+            e.print()?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let subcommand = matches.subcommand();
+    // The flags given ahead of the subcommand, then the ones following it
+    let flags: Vec<_> = cli::given(&matches)
+        .into_iter()
+        .chain(subcommand.into_iter().flat_map(|(_, sub)| cli::given(sub)))
+        .collect();
+    let given = |flag: &str| flags.iter().any(|(arg, _)| *arg == flag);
+    if (cfg!(feature = "spec-compat") || given("--spec-compat")) && !given("--help-json") {
+        return run_spec(&matches, &flags);
+    }
+    for (arg, value) in flags {
+        match arg {
+            "--format" => {
+                format = match value.as_str() {
+                    "csv" => Format::Csv,
+                    "json" if cfg!(feature = "serde") => Format::Json,
+                    "json" => {
+                        return Err(invalid_input(
+                            "--format json needs a build with the serde feature",
+                        ))
+                    }
+                    "bin" => Format::Bin,
+                    "parquet" if cfg!(feature = "parquet") => Format::Parquet,
+                    "parquet" => {
+                        return Err(invalid_input(
+                            "--format parquet needs a build with the parquet feature",
+                        ))
+                    }
+                    "avro" if cfg!(feature = "avro") => Format::Avro,
+                    "avro" => {
+                        return Err(invalid_input(
+                            "--format avro needs a build with the avro feature",
+                        ))
+                    }
+                    _ => {
+                        return Err(invalid_input(
+                            "--format expects csv, json, bin, parquet or avro",
+                        ))
+                    }
+                }
+            }
+            "--events-per-client" => {
+                let dir = value;
+                sinks.push(Box::new(PerClientFiles::new(dir)?))
+            }
+            "--events-coalesced" => coalesced = Some(value),
+            "--coalesce-every" => {
+                coalesce_every = value
+                    .parse()
+                    .map_err(|_| invalid_input("--coalesce-every expects a number of records"))?
+            }
+            "--events-keyed" => keyed = Some(value),
+            "--log-level" => {
+                log_level = Some(value.parse::<tracing::Level>().map_err(|_| {
+                    invalid_input("--log-level expects error, warn, info, debug or trace")
+                })?)
+            }
+            "--log-json" => log_json = true,
+            "--sort" => {
+                config.output.sort = ReportOrder::from_name(&value)
+                    .ok_or_else(|| invalid_input("--sort expects client or total"))?
+            }
+            "--locked-only" => config.output.locked_only = true,
+            "--clients" => {
+                config.output.clients = ReportOptions::parse_clients(&value).ok_or_else(|| {
+                    invalid_input("--clients expects client ranges such as 1-100,200")
+                })?
+            }
+            "--top" => {
+                config.output.top = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid_input("--top expects a number of accounts"))?,
+                )
+            }
+            "--outbox" => outbox = Some(value),
+            "--sink-retries" => {
+                retry.retries = value
+                    .parse()
+                    .map_err(|_| invalid_input("--sink-retries expects a number of retries"))?
+            }
+            "--header" => {
+                header = match value.as_str() {
+                    "detect" => Header::Detect,
+                    "required" => Header::Required,
+                    "absent" => Header::Absent,
+                    _ => return Err(invalid_input("--header expects detect, required or absent")),
+                }
+            }
+            "--decimals" => {
+                config.currency.decimals = value
+                    .parse()
+                    .map_err(|_| invalid_input("--decimals expects a number of decimals"))?
+            }
+            "--rounding" => config.currency.rounding = rounding_mode(&value, arg)?,
+            "--max-amount" => config.currency.max_amount = Some(Amount(value)),
+            "--fx-rounding" => fx_rounding = Some(rounding_mode(&value, arg)?),
+            "--fx-spread" => {
+                fx_spread = value
+                    .parse()
+                    .ok()
+                    .filter(|&spread| spread <= 10_000)
+                    .ok_or_else(|| invalid_input("--fx-spread expects 0 to 10000 basis points"))?
+            }
+            "--rates" => rates = Some(value),
+            "--schedules" => schedules = Some(value),
+            "--limits" => config.limits.risk = Some(value.into()),
+            "--credit-lines" => config.limits.credit_lines = Some(value.into()),
+            "--fees" => config.fees.schedule = Some(value.into()),
+            "--trial-balance" => trial_balance = Some(value),
+            "--client-master" => client_master = Some(value),
+            "--rollup" => config.output.rollup = true,
+            "--accrue-every" => {
+                accrue_every = value
+                    .parse()
+                    .ok()
+                    .filter(|&records: &u64| records > 0)
+                    .map(Some)
+                    .ok_or_else(|| invalid_input("--accrue-every expects a number of records"))?
+            }
+            "--normalized-out" => {
+                let path = value;
+                let out = BufWriter::new(File::create(path)?);
+                sinks.push(Box::new(NormalizedFeed::new(out)?))
+            }
+            "--independent" => independent = true,
+            "--threads" => {
+                threads = value
+                    .parse()
+                    .map_err(|_| invalid_input("--threads expects a number of threads"))?
+            }
+            "--memory-budget" => {
+                memory_budget = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid_input("--memory-budget expects a number of bytes"))?,
+                )
+            }
+            "--spill-to" => spill_to = Some(value),
+            "--store" => store = Some(value),
+            "--restore-from" => restore_from = Some(value),
+            "--snapshot-to" => snapshot_to = Some(value),
+            "--checkpoint-every" => {
+                checkpoint_every = Some(
+                    value
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&records| records > 0)
+                        .ok_or_else(|| {
+                            invalid_input("--checkpoint-every expects a number of records")
+                        })?,
+                )
+            }
+            "--resume-from-offset" => {
+                resume_from =
+                    Some(value.parse::<u64>().map_err(|_| {
+                        invalid_input("--resume-from-offset expects a number of bytes")
+                    })?)
+            }
+            "--progress" => {
+                progress_every = Some(
+                    value
+                        .parse()
+                        .map(Duration::from_secs)
+                        .map_err(|_| invalid_input("--progress expects a number of seconds"))?,
+                )
+            }
+            "--import-from" | "--export-to" if cfg!(not(feature = "serde")) => {
+                return Err(invalid_input(&format!(
+                    "{} needs a build with the serde feature",
+                    arg
+                )))
+            }
+            "--import-from" => import_from = Some(value),
+            "--export-to" => export_to = Some(value),
+            "--attest-key" => attest_key = Some(fs::read(value)?),
+            "--period" => config.output.period = value,
+            "--history-json" => history_json = true,
+            "--tag" => {
+                let tag = value;
+                history_tag = Some(
+                    tag.parse()
+                        .map_err(|_| invalid_input("--tag expects a memo of up to 32 bytes"))?,
+                );
+            }
+            "--by-tag" => by_tag = true,
+            "--verbose" => verbose = true,
+            "--ship-to" => ship_to = Some(value),
+            "--admin-token" => {
+                let token = fs::read_to_string(value)?;
+                if token.trim().is_empty() {
+                    return Err(invalid_input(
+                        "--admin-token expects a file holding a token",
+                    ));
+                }
+                admin_token = Some(token.trim().to_string());
+            }
+            "--ship-every" => {
+                ship_every = value
+                    .parse()
+                    .map(Duration::from_millis)
+                    .map_err(|_| invalid_input("--ship-every expects a number of milliseconds"))?
+            }
+            // `bank serve <address> --standby <dir>` follows the state shipped to the directory
+            // and only starts serving once promoted
+            "--standby" => standby = Some(value),
+            "--arrivals" => arrivals = Some(value),
+            "--state" => state = Some(value),
+            "--audit-log" => audit_log = Some(value),
+            "--type" => record[0] = Some(value),
+            "--client" => record[1] = Some(value),
+            "--tx" => record[2] = Some(value),
+            "--currency" => record[3] = Some(value),
+            "--amount" => record[4] = Some(value),
+            "--value-date" | "--to" => record[5] = Some(value),
+            "--value-dated" => config.policies.value_dated = true,
+            "--clearing" => {
+                config.policies.clearing = ClearingDelay::from_name(&value).ok_or_else(|| {
+                    invalid_input("--clearing expects instant or t+<business days>")
+                })?
+            }
+            "--dispute-window" => {
+                let days: u64 = value
+                    .parse()
+                    .map_err(|_| invalid_input("--dispute-window expects a number of days"))?;
+                config.policies.dispute_window = Some(days);
+            }
+            "--overdraw" => {
+                config.policies.overdraw = Overdraw::from_name(&value)
+                    .ok_or_else(|| invalid_input("--overdraw expects strict, exact or a limit"))?
+            }
+            "--dispute-routing" => {
+                config.policies.dispute_routing =
+                    DisputeRouting::from_name(&value).ok_or_else(|| {
+                        invalid_input("--dispute-routing expects client, tx or tx-warn")
+                    })?
+            }
+            "--dedup" => {
+                config.policies.dedup = DedupPolicy::from_name(&value)
+                    .ok_or_else(|| invalid_input("--dedup expects full or a window size"))?
+            }
+            "--tx-index" => {
+                let strategy = value;
+                tx_index = match IndexStrategy::from_name(&strategy) {
+                    _ if strategy == "auto" => None,
+                    Some(strategy) => Some(strategy),
+                    None => {
+                        return Err(invalid_input(
+                            "--tx-index expects auto, hashed, chunked or direct",
+                        ))
+                    }
+                }
+            }
+            "--storage" => {
+                storage = match value.as_str() {
+                    "auto" => None,
+                    "dense" => Some(ClientStorage::dense()),
+                    "sparse" => Some(ClientStorage::sparse()),
+                    _ => return Err(invalid_input("--storage expects auto, dense or sparse")),
+                }
+            }
+            "--history-lookup" => {
+                history_lookup = match value.as_str() {
+                    "auto" => None,
+                    "scan" => Some(HistoryLookup::Scan),
+                    "indexed" => Some(HistoryLookup::Indexed),
+                    _ => {
+                        return Err(invalid_input(
+                            "--history-lookup expects auto, scan or indexed",
+                        ))
+                    }
+                }
+            }
+            "--extended-report" => config.output.extended_report = true,
+            "--unlock-reverses" => config.policies.unlock_reverses = true,
+            "--deterministic" => deterministic = true,
+            "--strict" => malformed = Malformed::Strict,
+            "--lenient" => malformed = Malformed::Lenient,
+            "--base-currency" => {
+                let code = value;
+                config.currency.base = Some(code.parse().map_err(|_| {
+                    invalid_input("--base-currency expects a three letter currency code")
+                })?);
+            }
+            "--annotations" => annotations = Some(value),
+            "--rejects" => rejects = Some(value),
+            "--wal" => wal = Some(value),
+            "--force-compat" => compat = CompatCheck::Force,
+            "--client-map" => client_map = Some(value),
+            #[cfg(feature = "serde")]
+            "--help-json" => {
+                cli::write_help_json(&mut io::stdout().lock())?;
+                return Ok(());
+            }
+            #[cfg(not(feature = "serde"))]
+            "--help-json" => {
+                return Err(invalid_input(
+                    "--help-json needs a build with the serde feature",
+                ))
+            }
+            // `--config` was read by `without_config`
+            _ => {}
+        }
+    }
+    let files = subcommand.map_or(&matches, |(_, sub)| sub);
+    let paths: Vec<String> = match files.try_get_many::<String>("files") {
+        Ok(Some(files)) => files.cloned().collect(),
+        _ => Vec::new(),
+    };
+    match subcommand {
+        // `bank query <expression> <file>` only prints the clients matching the expression
+        Some(("query", sub)) => query = sub.get_one::<String>("expression").cloned(),
+        // `bank history <client> <file>` prints the ledger of the client instead of the report
+        Some(("history", sub)) => {
+            let client = sub.get_one::<String>("id").map_or("", String::as_str);
+            history = Some(
+                client
+                    .parse::<ClientId>()
+                    .map_err(|_| invalid_input("history expects a client id"))?,
+            );
+        }
+        // `bank serve <address>` exposes the engine over HTTP instead of processing a file
+        Some(("serve", sub)) => serve = sub.get_one::<String>("address").cloned(),
+        // `bank replay <address> <file> [--arrivals <profile>]` load tests a running server
+        Some(("replay", sub)) => replay = sub.get_one::<String>("address").cloned(),
+        // `bank convert <csv file>... <output>` encodes csv records in the binary format
+        Some(("convert", _)) => convert = true,
+        // `bank diff <left> <right>` prints the balances differing between two inputs or snapshots
+        Some(("diff", _)) => diff = true,
+        // `bank apply --state <snapshot> --type <type> --client <id> --tx <id> [--amount <amount>]`
+        // posts a single transaction to the saved state and records it in an audit trail
+        Some(("apply", _)) => apply = true,
+        // `bank completions <shell>` prints the completion script of the shell, see `cli`
+        Some(("completions", sub)) => {
+            if let Some(&shell) = sub.get_one::<clap_complete::Shell>("shell") {
+                cli::write_completions(&mut io::stdout().lock(), shell)?;
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    match (keyed, outbox) {
+        (Some(path), None) => {
+            let out = BufWriter::new(File::create(path)?);
+            sinks.push(Box::new(KeyedProducer::new(out)))
+        }
+        // Appended to, the outbox first publishes what a previous run failed to
+        (Some(path), Some(outbox)) => {
+            let out = OpenOptions::new().create(true).append(true).open(path)?;
+            sinks.push(Box::new(KeyedProducer::new(Outbox::open(
+                outbox, out, retry,
+            )?)))
+        }
+        (None, Some(_)) => return Err(invalid_input("--outbox expects --events-keyed")),
+        (None, None) => {}
+    }
+    // The notes and warnings of the run always go through the subscriber, the transactions and
+    // timestamps only once logging is asked for
+    let logs = log_level.is_some() || log_json;
+    let level = log_level.unwrap_or(tracing::Level::INFO);
+    logging::install(level, log_json, logs && !deterministic)?;
+    if logs {
+        sinks.push(Box::new(Logger::new()))
+    }
+    if let Some(path) = coalesced {
+        let out = BufWriter::new(File::create(path)?);
+        sinks.push(Box::new(Coalesced::new(out, coalesce_every)?))
+    }
+    attestation::check_period(&config.output.period)?;
+    let currency = config.currency()?;
+    let mut report = config.report();
+    let (rollup, period) = (config.output.rollup, config.output.period.clone());
+    let keep_last = KEEP_UNDER_PRESSURE;
+    let policy = Policy {
+        memory_budget: memory_budget.map(|limit_bytes| MemoryBudget {
+            limit_bytes,
+            action: match spill_to {
+                Some(_) => PressureAction::Spill { keep_last },
+                None => PressureAction::Prune { keep_last },
+            },
+        }),
+        tx_index,
+        history: history_lookup,
+        ..config.policy(currency)?
+    };
+    if diff {
+        let (left, right) = match &paths[..] {
+            [left, right] => (left, right),
+            _ => return Err(invalid_input("diff expects two input files or snapshots")),
+        };
+        let side = |path: &String| {
+            diff_side(path, policy, currency, header, compat)
+                .map_err(|e| e.in_file(Path::new(path)))
+        };
+        let diffs = side(left)?.diff(&side(right)?);
+        let mut out = io::stdout().lock();
+        writeln!(out, "client, field, left, right")?;
+        for account in &diffs {
+            account.write_csv(&mut out, currency)?;
+        }
+        out.flush()?;
+        if diffs.is_empty() {
+            info!("the balances are identical");
+            return Ok(());
+        }
+        info!(accounts = diffs.len(), "balances differ");
+        // Like diff(1) the exit status tells whether there are differences
+        std::process::exit(1);
+    }
+    if let Some(query) = query {
+        report.query =
+            Some(Query::compile(&query, currency).map_err(|e| invalid_input(&e.to_string()))?);
+    }
+    if attest_key.is_some() && report != ReportOptions::default() {
+        return Err(invalid_input(
+            "attested statements cover every account and can't be filtered by a query or the report options",
+        ));
+    }
+    if let Some(addr) = replay {
+        let path = match &paths[..] {
+            [path] => path,
+            _ => return Err(invalid_input("replay expects a single input file")),
+        };
+        let profile = match arrivals {
+            Some(profile) => ArrivalProfile::load(&profile)
+                .map_err(|e| EngineError::from(e).in_file(Path::new(&profile)))?,
+            None => ArrivalProfile::default(),
+        };
+        let reader = BufReader::new(File::open(path)?);
+        let stats = replay::replay(addr.as_str(), reader, header, &profile)?;
+        info!(%stats, "replayed");
+        return Ok(());
+    }
+    if convert {
+        let (output, inputs) = match paths.split_last() {
+            Some((output, inputs)) if !inputs.is_empty() => (output, inputs),
+            _ => {
+                return Err(invalid_input(
+                    "convert expects input files and an output file",
+                ))
+            }
+        };
+        let mut reader = Inputs::new(inputs::expand(inputs)?, header);
+        csv_parser::skip_header(&mut reader, header)?;
+        let mut encoder = binary::Encoder::new(BufWriter::new(File::create(output)?), currency)?;
+        let mut converted = 0;
+        for record in Records::new(reader) {
+            encoder.write(&csv_parser::parse_line_with(record, currency)?)?;
+            converted += 1;
+        }
+        encoder.into_inner().flush()?;
+        info!(records = converted, %output, "converted");
+        return Ok(());
+    }
+    if apply {
+        if restore_from.is_some() || snapshot_to.is_some() || !paths.is_empty() {
+            return Err(invalid_input(
+                "apply reads and writes the state given with --state and takes no input file",
+            ));
+        }
+        restore_from = state.clone();
+        snapshot_to = state.clone();
+    }
+    // Threads and the network make the outcome depend on timing
+    if deterministic && (serve.is_some() || replay.is_some() || threads > 1 || independent) {
+        return Err(invalid_input(
+            "serve, replay, --threads and --independent are not deterministic and can't be combined with --deterministic or an audit build",
+        ));
+    }
+    if malformed != Malformed::Abort
+        && (threads > 1
+            || independent
+            || serve.is_some()
+            || apply
+            || !matches!(format, Format::Csv | Format::Json))
+    {
+        return Err(invalid_input(
+            "--strict and --lenient only support a sequential run over csv or json input",
+        ));
+    }
+    // Offsets only make sense for a single pass over the records, in the order they are read
+    if (progress_every.is_some() || checkpoint_every.is_some() || resume_from.is_some())
+        && (threads > 1
+            || independent
+            || serve.is_some()
+            || apply
+            || !matches!(format, Format::Csv | Format::Json))
+    {
+        return Err(invalid_input(
+            "--progress, --checkpoint-every and --resume-from-offset only support a sequential run over csv or json input",
+        ));
+    }
+    if progress_every.is_some() && deterministic {
+        return Err(invalid_input(
+            "--progress reads the wall clock and can't be combined with --deterministic or an audit build",
+        ));
+    }
+    if checkpoint_every.is_some() && snapshot_to.is_none() {
+        return Err(invalid_input("--checkpoint-every expects --snapshot-to"));
+    }
+    // A lock cascades to sub-accounts that may be handled by another thread
+    if client_master.is_some() && (threads > 1 || independent) {
+        return Err(invalid_input(
+            "--client-master can't be combined with --threads or --independent",
+        ));
+    }
+    if history.is_some() && (rollup || attest_key.is_some() || report != ReportOptions::default()) {
+        return Err(invalid_input(
+            "history can't be combined with --rollup, --attest-key, query or the report options",
+        ));
+    }
+    if (history_json || history_tag.is_some() || by_tag) && history.is_none() {
+        return Err(invalid_input(
+            "--history-json, --tag and --by-tag expect history",
+        ));
+    }
+    let history = history.map(|client| {
+        let options = HistoryOptions {
+            tag: history_tag,
+            by_tag,
+            json: history_json,
+        };
+        (client, options)
+    });
+    let parquet = matches!(format, Format::Parquet);
+    if parquet && (history.is_some() || rollup || attest_key.is_some()) {
+        return Err(invalid_input(
+            "--format parquet can't be combined with history, --rollup or --attest-key",
+        ));
+    }
+    if rollup && (attest_key.is_some() || report != ReportOptions::default()) {
+        return Err(invalid_input(
+            "--rollup can't be combined with --attest-key, query or the report options",
+        ));
+    }
+    // The store holds the clients of this table, which a restore or import would replace
+    if store.is_some()
+        && (restore_from.is_some()
+            || import_from.is_some()
+            || standby.is_some()
+            || threads > 1
+            || independent)
+    {
+        return Err(invalid_input(
+            "--store can't be combined with --restore-from, --import-from, --standby, --threads or --independent",
+        ));
+    }
+    if (ship_to.is_some() || standby.is_some() || admin_token.is_some()) && serve.is_none() {
+        return Err(invalid_input(
+            "--ship-to, --standby and --admin-token expect serve",
         ));
     }
-    let mut client_table = ClientTable::new();
+    if standby.is_some() && (restore_from.is_some() || import_from.is_some() || wal.is_some()) {
+        return Err(invalid_input(
+            "--standby takes its state from the shipped files and can't be combined with --restore-from, --import-from or --wal",
+        ));
+    }
+    let mut builder = config
+        .builder()?
+        .policy(policy)
+        .deterministic(deterministic);
+    if let Some(dir) = store {
+        if storage.is_some() {
+            return Err(invalid_input("--storage can't be combined with --store"));
+        }
+        builder = builder.storage(open_store(&dir, compat)?);
+    }
+    if let Some(storage) = storage {
+        builder = builder.storage(storage);
+    }
+    let mut rates = match rates {
+        Some(path) => RateTable::load(path)?,
+        None => RateTable::new(),
+    };
+    rates.set_spread(fx_spread).expect("checked while parsing");
+    if let Some(rounding) = fx_rounding {
+        rates.set_rounding(rounding);
+    }
+    builder = builder.rates(rates);
+    let mut schedules = match schedules {
+        Some(path) => Schedules::load(path, currency)?,
+        None => Schedules::new(),
+    };
+    if let Some(records) = accrue_every {
+        schedules
+            .set_day_length(records)
+            .expect("checked while parsing");
+    }
+    builder = builder.schedules(schedules);
+    if let Some(path) = client_master {
+        builder = builder.hierarchy(Hierarchy::load(path)?);
+    }
+    if let Some(spill_to) = spill_to {
+        builder = builder.spill_archive(Archive::new(spill_to, compat));
+    }
+    let mut client_table = builder.build()?;
+    let audit_log = state
+        .as_ref()
+        .map(|state| audit_log.unwrap_or_else(|| format!("{}.audit", state)));
+    if let Some(snapshot) = &restore_from {
+        match (client_table.restore(snapshot, compat), &audit_log) {
+            // The state of apply can be rebuilt from its audit trail when the snapshot is lost
+            (Err(e), Some(trail)) if apply && Path::new(trail).exists() => {
+                warn!(%snapshot, error = %e, audit_log = %trail, "cannot restore the snapshot, rebuilding it from the audit log");
+                let summary = client_table
+                    .recover_from_audit(trail)
+                    .map_err(|e| e.in_file(Path::new(trail)))?;
+                info!(
+                    transactions = summary.applied + summary.rejected,
+                    "replayed the audit log"
+                );
+            }
+            (result, _) => result?,
+        }
+    }
+    let resume_from = resume_at(&mut client_table, restore_from.as_deref(), resume_from)?;
+    if let Some(export) = import_from {
+        import_interchange(&mut client_table, &export)?;
+    }
+    // The balances restored or imported so far are the opening balances of the ledger
+    if trial_balance.is_some() {
+        client_table
+            .enable_general_ledger()
+            .map_err(io::Error::from)?;
+    }
+    let started = Instant::now();
+    if let Some(wal) = &wal {
+        client_table.recover_from_wal(wal, compat)?;
+        client_table.set_wal(Wal::open(wal, compat)?);
+    }
+    if apply {
+        let audit_log = audit_log.ok_or_else(|| invalid_input("apply expects --state"))?;
+        let [kind, client, tx, code, amount, value_date] = &record;
+        let transaction = csv_parser::parse_record(
+            kind.as_deref(),
+            client.as_deref(),
+            tx.as_deref(),
+            amount.as_deref(),
+            value_date.as_deref(),
+            currency,
+        )
+        .and_then(|t| csv_parser::with_currency_code(t, code.as_deref()))?;
+        let operator = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        let mut log = AuditLog::open(audit_log)?;
+        let outcome = client_table.apply_audited(transaction, &operator, &mut log)?;
+        report_warnings(&mut client_table);
+        outcome?;
+        persist(
+            &mut client_table,
+            snapshot_to,
+            export_to,
+            trial_balance.as_deref(),
+        )?;
+        info!(r#type = %transaction.kind_name(), tx = transaction.tx(), "applied");
+        return Ok(());
+    }
+    if let Some(dir) = standby {
+        info!(%dir, "standing by");
+        let mut standby = Standby::new(dir, compat);
+        standby.follow(&mut client_table, &CancellationToken::new(), STANDBY_POLL)?;
+        info!(shipment = standby.applied(), "promoted");
+    }
+    if let Some(addr) = serve {
+        let shipper = match ship_to {
+            Some(_) if wal.is_some() => {
+                return Err(invalid_input(
+                    "--ship-to logs to the shipped segments and can't be combined with --wal",
+                ))
+            }
+            Some(dir) => Some(Shipper::start(dir, &mut client_table)?),
+            None => None,
+        };
+        let mut server = Server::bind(server::listen_address(&addr), client_table)?;
+        if let Some(shipper) = shipper {
+            server.ship_with(shipper, ship_every);
+        }
+        if let Some(token) = admin_token {
+            server.set_admin_token(token);
+        }
+        info!(addr = %server.local_addr()?, "serving");
+        let mut client_table = server.run(&CancellationToken::new())?;
+        report_stats(&client_table, started, verbose);
+        report_warnings(&mut client_table);
+        persist(
+            &mut client_table,
+            snapshot_to,
+            export_to,
+            trial_balance.as_deref(),
+        )?;
+        return Ok(());
+    }
+    let inputs = inputs::expand(&paths)?;
+    if inputs.is_empty() {
+        println!("Please supply an csv file");
+        return Err(invalid_input("Missing csv file"));
+    }
+    if independent {
+        // Independent files, each processed on its own thread and merged afterwards
+        if !matches!(format, Format::Csv)
+            || client_map.is_some()
+            || !sinks.is_empty()
+            || wal.is_some()
+            || policy.settlement == SettlementPolicy::ValueDated
+            || rejects.is_some()
+            || annotations.is_some()
+        {
+            return Err(invalid_input(
+                "--independent only supports plain csv input without event outputs, --wal, --value-dated, --rejects or --annotations",
+            ));
+        }
+        let files = inputs
+            .iter()
+            .map(|input| match input {
+                Input::File(path) => Ok(path),
+                Input::Stdin => Err(invalid_input("--independent can't read stdin")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        client_table.process_files(&files, header, &|file, summary| {
+            info!(
+                file = %files[file].display(),
+                records = summary.applied + summary.rejected,
+                "input read"
+            )
+        })?;
+        close_business_day(&mut client_table);
+        report_stats(&client_table, started, verbose);
+        report_warnings(&mut client_table);
+        persist(
+            &mut client_table,
+            snapshot_to,
+            export_to,
+            trial_balance.as_deref(),
+        )?;
+        return write_report(
+            &client_table,
+            attest_key.as_deref(),
+            &period,
+            &report,
+            history,
+            parquet,
+        );
+    }
+    if inputs.len() > 1 && annotations.is_some() {
+        // Line numbers only identify a record within a single input
+        return Err(invalid_input("--annotations supports a single input"));
+    }
+    if let Format::Bin | Format::Parquet | Format::Avro = format {
+        if inputs.len() > 1 || rejects.is_some() || annotations.is_some() {
+            return Err(invalid_input(
+                "--format bin, parquet and avro read a single input and don't support --rejects or --annotations",
+            ));
+        }
+    }
 
-    let f = File::open(&args[1]).unwrap();
-    let reader = BufReader::new(f);
-    for tx in reader.lines().skip(1).map(parse_line) {
-        if let Err(_e) = client_table.handle_transaction(tx?) {
-            // From the task, we don't handle any of these errors
-            // But in an actual setup we would probably log them or something
+    let input_header = match format {
+        Format::Csv => header,
+        Format::Json | Format::Bin | Format::Parquet | Format::Avro => Header::Absent,
+    };
+    let size = progress_every.and_then(|_| inputs::size(&inputs));
+    let mut reader = Inputs::new(inputs, input_header);
+    if let Format::Bin | Format::Parquet | Format::Avro = format {
+        reader = reader.raw();
+    }
+    let start = resume_from.unwrap_or_default();
+    progress::skip_bytes(&mut reader, start.bytes)?;
+    let mut reader = Counted::new(reader, start.bytes);
+    let mut first_line = start.line;
+    // The header of a resumed feed was read before the offset
+    if let (Format::Csv, None) = (&format, resume_from) {
+        if csv_parser::skip_header(&mut reader, header)? {
+            first_line += 1;
+        }
+    }
+    let mut options = ProcessOptions {
+        malformed,
+        position: reader.counter(),
+        progress: progress_every.map(|every| Progress::new(every, size, start)),
+        checkpoint: checkpoint_every
+            .map(|every| (snapshot_to.clone().expect("checked with the flags"), every)),
+    };
+    let mut rejects = match rejects {
+        Some(path) => Some(Rejects::new(BufWriter::new(File::create(path)?))?),
+        None => None,
+    };
+    let mut annotations = match annotations {
+        Some(path) => Some(Annotations::new(BufWriter::new(File::create(path)?))?),
+        None => None,
+    };
+    if threads > 1 {
+        if !matches!(format, Format::Csv)
+            || client_map.is_some()
+            || !sinks.is_empty()
+            || wal.is_some()
+            || policy.settlement == SettlementPolicy::ValueDated
+            || rejects.is_some()
+            || annotations.is_some()
+        {
+            // Every shard runs its own clock, which would release bookings late
+            return Err(invalid_input(
+                "--threads only supports plain csv input without event outputs, --wal, --value-dated, --rejects or --annotations",
+            ));
         }
+        client_table.process_parallel(reader, threads)?;
+        close_business_day(&mut client_table);
+        report_stats(&client_table, started, verbose);
+        report_warnings(&mut client_table);
+        persist(
+            &mut client_table,
+            snapshot_to,
+            export_to,
+            trial_balance.as_deref(),
+        )?;
+        return write_report(
+            &client_table,
+            attest_key.as_deref(),
+            &period,
+            &report,
+            history,
+            parquet,
+        );
     }
+    match (format, &client_map) {
+        (Format::Csv, Some(map_path)) => {
+            let mut map = ClientMap::load(map_path, compat)?;
+            process(
+                &mut client_table,
+                Records::new(reader).starting_at(first_line).numbered(),
+                |l| map.parse_line_with(l, currency).map(Stamped::from),
+                &mut sinks,
+                rejects.as_mut(),
+                annotations.as_mut(),
+                &mut options,
+            )?;
+            map.save(map_path)?;
+            client_table.set_client_labels(map.labels());
+        }
+        (Format::Json, Some(_))
+        | (Format::Bin, Some(_))
+        | (Format::Parquet, Some(_))
+        | (Format::Avro, Some(_)) => {
+            return Err(invalid_input(
+                "--client-map is only supported for csv input",
+            ))
+        }
+        (Format::Csv, None) => process(
+            &mut client_table,
+            Records::new(reader).starting_at(first_line).numbered(),
+            |l| {
+                let l = l?;
+                if malformed != Malformed::Abort {
+                    csv_parser::check_columns(&l)?;
+                }
+                csv_parser::parse_line_stamped(Ok(l), currency)
+            },
+            &mut sinks,
+            rejects.as_mut(),
+            annotations.as_mut(),
+            &mut options,
+        )?,
+        #[cfg(feature = "serde")]
+        (Format::Json, None) => process(
+            &mut client_table,
+            reader
+                .lines()
+                .enumerate()
+                .map(|(i, l)| (i + first_line, l))
+                .filter(|(_, l)| !matches!(l, Ok(l) if l.trim().is_empty())),
+            |l| json_parser::parse_line_with(l, currency).map(Stamped::from),
+            &mut sinks,
+            rejects.as_mut(),
+            annotations.as_mut(),
+            &mut options,
+        )?,
+        #[cfg(not(feature = "serde"))]
+        (Format::Json, None) => {
+            return Err(invalid_input(
+                "--format json needs a build with the serde feature",
+            ))
+        }
+        (Format::Bin, None) => {
+            let decoder = binary::Decoder::new(reader)?;
+            if decoder.decimals() != currency.decimals() {
+                return Err(invalid_input(&format!(
+                    "the binary feed has {} decimals, run with --decimals {}",
+                    decoder.decimals(),
+                    decoder.decimals()
+                )));
+            }
+            let decoded = process_decoded(&mut client_table, decoder, &mut sinks)?;
+            client_table.count_bytes((binary::HEADER_LEN + decoded * binary::RECORD_LEN) as u64);
+        }
+        (Format::Parquet, None) => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            process_parquet(&mut client_table, data, &mut sinks)?
+        }
+        (Format::Avro, None) => process_avro(&mut client_table, reader, &mut sinks)?,
+    }
+    close_business_day(&mut client_table);
+    report_stats(&client_table, started, verbose);
+    report_warnings(&mut client_table);
+    persist(
+        &mut client_table,
+        snapshot_to,
+        export_to,
+        trial_balance.as_deref(),
+    )?;
+
+    if rollup {
+        client_table.write_rollup(BufWriter::new(io::stdout().lock()))?;
+        return Ok(());
+    }
+    write_report(
+        &client_table,
+        attest_key.as_deref(),
+        &period,
+        &report,
+        history,
+        parquet,
+    )
+}
 
-    println!("{}", client_table);
+/// Writes the balances to stdout, signing every row when an attestation key is configured
+/// or filtered and sorted according to the report options
+/// With `history` only the ledger of the client is written, filtered or totaled by memo and as
+/// JSON if asked to
+/// With `parquet` the report is written as a Parquet file
+fn write_report(
+    client_table: &ClientTable,
+    attest_key: Option<&[u8]>,
+    period: &str,
+    report: &ReportOptions,
+    history: Option<(ClientId, HistoryOptions)>,
+    parquet: bool,
+) -> Result<(), EngineError> {
+    let out = BufWriter::new(io::stdout().lock());
+    if parquet {
+        return write_parquet(client_table, out, report);
+    }
+    if let Some((client, options)) = history {
+        if !client_table.export_history_with(out, client, options)? {
+            return Err(invalid_input(&format!("client {} has no history", client)));
+        }
+        return Ok(());
+    }
+    match attest_key {
+        Some(key) => client_table.write_attested_csv(out, key, period)?,
+        None => client_table.write_csv_with(out, report)?,
+    }
     Ok(())
 }
+
+/// What `process` does with the records that can't be parsed, see `--strict` and `--lenient`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Malformed {
+    /// The first one aborts the run, columns past the schema are ignored
+    #[default]
+    Abort,
+    /// The first one aborts the run, columns past the schema make a record malformed
+    Strict,
+    /// They are skipped and counted in the stats, columns past the schema make a record malformed
+    Lenient,
+}
+
+/// How `process` follows its way through the input
+struct ProcessOptions {
+    malformed: Malformed,
+    /// Bytes of the feed consumed so far, the offset each record ends at
+    position: Rc<Cell<u64>>,
+    progress: Option<Progress>,
+    /// Snapshot written with the offset of the input every so many records
+    checkpoint: Option<(String, usize)>,
+}
+
+/// Offset of the input to resume from, checked against the one the restored snapshot was
+/// checkpointed at. The offset of the snapshot is cleared, the next snapshot only records one if
+/// this run checkpoints too
+fn resume_at(
+    client_table: &mut ClientTable,
+    snapshot: Option<&str>,
+    bytes: Option<u64>,
+) -> Result<Option<InputOffset>, EngineError> {
+    let saved = client_table.input_offset();
+    client_table.set_input_offset(None);
+    match (bytes, saved) {
+        (Some(bytes), Some(saved)) if bytes == saved.bytes => Ok(Some(saved)),
+        (Some(bytes), Some(saved)) => Err(invalid_input(&format!(
+            "the snapshot was checkpointed at byte {} of its input, not {}",
+            saved.bytes, bytes
+        ))),
+        // Without a checkpoint the lines are numbered from the offset
+        (Some(bytes), None) => Ok(Some(InputOffset { bytes, line: 1 })),
+        (None, Some(saved)) => {
+            info!(
+                snapshot = snapshot.unwrap_or("the snapshot"),
+                offset = saved.bytes,
+                "checkpointed within its input, pass the offset to --resume-from-offset to resume it"
+            );
+            Ok(None)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Applies the records to the table, the first record that can't be parsed aborts the run with its
+/// line unless a rejects file is given, which then collects every record that fails to parse or
+/// is refused, or unless `Malformed::Lenient` skips them
+/// Parse errors are collected as the records are read, which is ahead of the engine while the
+/// storage sample is taken, so the rejects are not necessarily in line order. Annotations are
+/// written in line order
+/// A checkpoint records the end of the last record handled, it is put off while a batch is open
+/// as the snapshot doesn't include the records collected for it
+fn process<E: Into<io::Error> + Into<EngineError>>(
+    client_table: &mut ClientTable,
+    records: impl Iterator<Item = (usize, io::Result<String>)>,
+    mut parse: impl FnMut(io::Result<String>) -> Result<Stamped, E>,
+    sinks: &mut [Box<dyn EventSink>],
+    rejects: Option<&mut Rejects<BufWriter<File>>>,
+    mut annotations: Option<&mut Annotations<BufWriter<File>>>,
+    options: &mut ProcessOptions,
+) -> Result<(), EngineError> {
+    let lenient = options.malformed == Malformed::Lenient;
+    let tracked = rejects.is_some() || annotations.is_some();
+    let rejects = RefCell::new(rejects);
+    let bytes_read = Cell::new(0);
+    let malformed = Cell::new(0);
+    // Line and text of the records read but not handled yet, only kept when collecting rejects or
+    // annotations. Records that failed to parse stay queued with the reason until annotated
+    let parsed = RefCell::new(VecDeque::new());
+    // Where the records handed to the engine end, and where the last record read ends
+    let ends = RefCell::new(VecDeque::new());
+    let position = Rc::clone(&options.position);
+    let end = Cell::new(InputOffset {
+        bytes: position.get(),
+        line: 1,
+    });
+    let mut fatal: Option<EngineError> = None;
+    let mut transactions = records
+        .map_while(|(line, record)| {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    fatal = Some(e.into());
+                    return None;
+                }
+            };
+            bytes_read.set(bytes_read.get() + record.len() as u64 + 1);
+            end.set(InputOffset {
+                bytes: position.get(),
+                line: line + record.matches('\n').count() + 1,
+            });
+            let mut rejects = rejects.borrow_mut();
+            let kept = record.clone();
+            match (parse(Ok(record)), rejects.as_mut()) {
+                (Ok(tx), _) => {
+                    if tracked {
+                        parsed.borrow_mut().push_back((line, kept, None));
+                    }
+                    ends.borrow_mut().push_back(end.get());
+                    Some(Some(tx))
+                }
+                (Err(e), Some(rejects)) => {
+                    let e: io::Error = e.into();
+                    let reason = RejectReason::Parse(&e);
+                    parsed
+                        .borrow_mut()
+                        .push_back((line, String::new(), Some(reason.kind())));
+                    malformed.set(malformed.get() + 1);
+                    match rejects.reject(line, &kept, reason) {
+                        Ok(()) => Some(None),
+                        Err(e) => {
+                            fatal = Some(e.into());
+                            None
+                        }
+                    }
+                }
+                (Err(_), None) if lenient => {
+                    if tracked {
+                        let failed = Some(PARSE_ERROR.to_string());
+                        parsed.borrow_mut().push_back((line, String::new(), failed));
+                    }
+                    malformed.set(malformed.get() + 1);
+                    Some(None)
+                }
+                (Err(e), None) => {
+                    if tracked {
+                        let failed = Some(PARSE_ERROR.to_string());
+                        parsed.borrow_mut().push_back((line, String::new(), failed));
+                    }
+                    let e: EngineError = e.into();
+                    let column = match &e {
+                        EngineError::Parse(e) => csv_parser::error_column(&kept, e),
+                        _ => None,
+                    };
+                    fatal = Some(e.at_line(line, column, &kept));
+                    None
+                }
+            }
+        })
+        .flatten();
+    // Pick what wasn't chosen of the storage from the start of the feed
+    let mut sample = Vec::new();
+    if client_table.adapts() {
+        sample.extend(transactions.by_ref().take(Layout::SAMPLE_SIZE));
+        let records: Vec<_> = sample.iter().map(|s| s.transaction).collect();
+        client_table.adapt(&records);
+    }
+    let transactions = sample.into_iter().chain(transactions);
+    let mut stream = client_table.stream(transactions);
+    let mut since_checkpoint = 0;
+    while let Some(event) = stream.next() {
+        let at = ends.borrow_mut().pop_front().expect("one per transaction");
+        if let Some(progress) = options.progress.as_mut() {
+            progress.tick(io::stderr().lock(), at)?;
+        }
+        if let Some((path, every)) = &options.checkpoint {
+            since_checkpoint += 1;
+            if since_checkpoint >= *every && !stream.table().in_batch() {
+                stream.table().checkpoint(path, at)?;
+                since_checkpoint = 0;
+            }
+        }
+        if tracked {
+            let (line, record) =
+                annotate_failed(&parsed, annotations.as_deref_mut())?.unwrap_or_default();
+            if let (Some(rejects), Err(e)) = (rejects.borrow_mut().as_mut(), event.outcome) {
+                rejects.reject(line, &record, RejectReason::Engine(e))?;
+            }
+            if let Some(annotations) = annotations.as_mut() {
+                let queued = stream.table().pending().contains(event.transaction.tx());
+                annotations.annotate(line, &Disposition::of(event.outcome, queued))?;
+            }
+        }
+        for sink in sinks.iter_mut() {
+            sink.emit(&event)?;
+        }
+    }
+    annotate_failed(&parsed, annotations.as_deref_mut())?;
+    client_table.count_bytes(bytes_read.get());
+    client_table.count_malformed(malformed.get());
+    if options.checkpoint.is_some() && fatal.is_none() {
+        client_table.set_input_offset(Some(end.get()));
+    }
+    for sink in sinks.iter_mut() {
+        sink.flush()?;
+    }
+    if let Some(rejects) = rejects.into_inner() {
+        rejects.flush()?;
+        info!(%rejects, "records rejected");
+    }
+    if let Some(annotations) = annotations {
+        annotations.flush()?;
+    }
+    match fatal {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Applies the records of a binary feed, which has no text to put in rejects or annotations
+fn process_decoded<E: Into<EngineError>>(
+    client_table: &mut ClientTable,
+    decoder: impl Iterator<Item = Result<Transaction, E>>,
+    sinks: &mut [Box<dyn EventSink>],
+) -> Result<usize, EngineError> {
+    let decoded = Cell::new(0);
+    let mut fatal: Option<EngineError> = None;
+    let mut transactions = decoder.map_while(|record| match record {
+        Ok(tx) => {
+            decoded.set(decoded.get() + 1);
+            Some(tx)
+        }
+        Err(e) => {
+            fatal = Some(e.into());
+            None
+        }
+    });
+    let mut sample = Vec::new();
+    if client_table.adapts() {
+        sample.extend(transactions.by_ref().take(Layout::SAMPLE_SIZE));
+        client_table.adapt(&sample);
+    }
+    for event in client_table.stream(sample.into_iter().chain(transactions)) {
+        for sink in sinks.iter_mut() {
+            sink.emit(&event)?;
+        }
+    }
+    for sink in sinks.iter_mut() {
+        sink.flush()?;
+    }
+    match fatal {
+        Some(e) => Err(e),
+        None => Ok(decoded.get()),
+    }
+}
+
+/// Applies the transactions of a Parquet file, held in memory as a whole
+#[cfg(feature = "parquet")]
+fn process_parquet(
+    client_table: &mut ClientTable,
+    data: Vec<u8>,
+    sinks: &mut [Box<dyn EventSink>],
+) -> Result<(), EngineError> {
+    let bytes = data.len() as u64;
+    let decoder = bank::parquet::Decoder::new(data, client_table.currency_config())?;
+    process_decoded(client_table, decoder, sinks)?;
+    client_table.count_bytes(bytes);
+    Ok(())
+}
+
+#[cfg(not(feature = "parquet"))]
+fn process_parquet(
+    _client_table: &mut ClientTable,
+    _data: Vec<u8>,
+    _sinks: &mut [Box<dyn EventSink>],
+) -> Result<(), EngineError> {
+    Err(invalid_input(
+        "--format parquet needs a build with the parquet feature",
+    ))
+}
+
+/// Applies the transactions of an Avro container file, read a block at a time
+#[cfg(feature = "avro")]
+fn process_avro(
+    client_table: &mut ClientTable,
+    reader: Counted<Inputs>,
+    sinks: &mut [Box<dyn EventSink>],
+) -> Result<(), EngineError> {
+    let counter = reader.counter();
+    let start = counter.get();
+    let decoder = bank::avro::Reader::new(reader, client_table.currency_config())?;
+    process_decoded(client_table, decoder, sinks)?;
+    client_table.count_bytes(counter.get() - start);
+    Ok(())
+}
+
+#[cfg(not(feature = "avro"))]
+fn process_avro(
+    _client_table: &mut ClientTable,
+    _reader: Counted<Inputs>,
+    _sinks: &mut [Box<dyn EventSink>],
+) -> Result<(), EngineError> {
+    Err(invalid_input(
+        "--format avro needs a build with the avro feature",
+    ))
+}
+
+/// Writes the report as a Parquet file
+#[cfg(feature = "parquet")]
+fn write_parquet(
+    client_table: &ClientTable,
+    out: impl Write,
+    report: &ReportOptions,
+) -> Result<(), EngineError> {
+    Ok(client_table.write_parquet_with(out, report)?)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(
+    _client_table: &ClientTable,
+    _out: impl Write,
+    _report: &ReportOptions,
+) -> Result<(), EngineError> {
+    Err(invalid_input(
+        "--format parquet needs a build with the parquet feature",
+    ))
+}
+
+/// Annotates the queued records that failed to parse up to the next parsed one, which is returned
+fn annotate_failed(
+    parsed: &RefCell<VecDeque<(usize, String, Option<String>)>>,
+    mut annotations: Option<&mut Annotations<BufWriter<File>>>,
+) -> io::Result<Option<(usize, String)>> {
+    while let Some((line, record, failed)) = parsed.borrow_mut().pop_front() {
+        match failed {
+            Some(reason) => {
+                if let Some(annotations) = annotations.as_mut() {
+                    annotations.annotate(line, &Disposition::Rejected(reason))?;
+                }
+            }
+            None => return Ok(Some((line, record))),
+        }
+    }
+    Ok(None)
+}
+
+/// A batch run is a business day, with a clearing delay its sweep runs once the input is consumed
+fn close_business_day(client_table: &mut ClientTable) {
+    if client_table.policy().clearing != ClearingDelay::Instant {
+        client_table.clear();
+    }
+}
+
+/// Logs the layout and counters of the run together with its throughput with `--verbose`, the
+/// throughput is left out of deterministic runs as it varies from one run to the next
+fn report_stats(client_table: &ClientTable, started: Instant, verbose: bool) {
+    if !verbose {
+        return;
+    }
+    if let Some(layout) = client_table.layout() {
+        info!(%layout, "storage layout");
+    }
+    let stats = client_table.stats();
+    if client_table.is_deterministic() {
+        info!(%stats, "run finished");
+    } else {
+        let elapsed = started.elapsed();
+        info!(
+            %stats,
+            elapsed = ?elapsed,
+            per_second = (stats.processed as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64,
+            "run finished"
+        );
+    }
+    info!(tx_index = %client_table.tx_index(), "transaction ids held");
+}
+
+fn report_warnings(client_table: &mut ClientTable) {
+    for warning in client_table.take_warnings() {
+        warn!(%warning, "engine warning");
+    }
+    match client_table.booked_total() {
+        Ok(booked) if booked != Currency::ZERO => info!(
+            booked = %client_table.currency_config().display(booked),
+            "booked but not available yet"
+        ),
+        Ok(_) => {}
+        Err(overflow) => warn!(error = %overflow, "booked funds not reported"),
+    }
+}
+
+/// Runs the engine of the original specification on the one csv file given, see `bank::spec`.
+/// Only the flags of `SPEC_FLAGS` are accepted along
+fn run_spec(matches: &clap::ArgMatches, flags: &[(&str, String)]) -> Result<(), EngineError> {
+    let mut extensions: Vec<_> = flags
+        .iter()
+        .map(|(arg, _)| *arg)
+        .filter(|arg| !SPEC_FLAGS.contains(arg))
+        .collect();
+    extensions.extend(matches.subcommand_name());
+    extensions.dedup();
+    if !extensions.is_empty() {
+        return Err(invalid_input(&format!(
+            "{} can't be combined with --spec-compat or a spec-compat build",
+            extensions.join(", ")
+        )));
+    }
+    let paths: Vec<&String> = matches
+        .get_many("files")
+        .map_or_else(Vec::new, |files| files.collect());
+    let path = match paths[..] {
+        [path] => path,
+        [] => {
+            println!("Please supply an csv file");
+            return Err(invalid_input("Missing csv file"));
+        }
+        _ => return Err(invalid_input("--spec-compat takes a single csv file")),
+    };
+    let mut table = SpecTable::new();
+    table.process(BufReader::new(File::open(path)?))?;
+    print!("{}", table);
+    Ok(())
+}
+
+/// Takes the `--config <file>` out of the command line, the flags left override its settings
+fn without_config(
+    mut args: impl Iterator<Item = String>,
+) -> Result<(Option<String>, Vec<String>), EngineError> {
+    let mut config = None;
+    let mut command_line = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if config.is_some() {
+                return Err(invalid_input("--config can only be given once"));
+            }
+            config = Some(value(&mut args, &arg, "a file")?);
+        } else {
+            command_line.push(arg);
+        }
+    }
+    Ok((config, command_line))
+}
+
+#[cfg(feature = "config")]
+fn read_config(path: &str) -> Result<EngineConfig, EngineError> {
+    bank::config::load(path).map_err(|e| EngineError::from(e).in_file(path))
+}
+
+#[cfg(not(feature = "config"))]
+fn read_config(_: &str) -> Result<EngineConfig, EngineError> {
+    Err(invalid_input(
+        "--config needs a build with the config feature",
+    ))
+}
+
+/// Takes the value following a flag
+fn value(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+    expected: &str,
+) -> Result<String, EngineError> {
+    args.next()
+        .ok_or_else(|| invalid_input(&format!("{} expects {}", flag, expected)))
+}
+
+fn invalid_input(message: &str) -> EngineError {
+    EngineError::Usage(message.to_string())
+}
+
+/// Writes the snapshot and the interchange export of the final state, if they were asked for
+/// Opens the persistent client store given with `--store`
+/// Table of one side of `bank diff`, a snapshot is restored and any other file is processed as
+/// csv records
+fn diff_side(
+    path: &str,
+    policy: Policy,
+    currency: CurrencyConfig,
+    header: Header,
+    compat: CompatCheck,
+) -> Result<ClientTable, EngineError> {
+    let mut table = ClientTable::builder()
+        .policy(policy)
+        .currency(currency)
+        .build()?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let first = reader.fill_buf()?.split(|b| *b == b'\n').next();
+    if Stamp::parse(&String::from_utf8_lossy(first.unwrap_or_default())).is_some() {
+        table.restore(path, compat)?;
+        return Ok(table);
+    }
+    csv_parser::skip_header(&mut reader, header)?;
+    let transactions = Records::new(reader)
+        .map(|record| csv_parser::parse_line_with(record, currency))
+        .collect::<Result<Vec<_>, _>>()?;
+    table.process(transactions);
+    Ok(table)
+}
+
+#[cfg(feature = "kv-store")]
+fn open_store(dir: &str, compat: CompatCheck) -> Result<ClientStorage, EngineError> {
+    Ok(ClientStorage::custom(bank::kv_store::KvStore::open(
+        dir, compat,
+    )?))
+}
+
+#[cfg(not(feature = "kv-store"))]
+fn open_store(_dir: &str, _compat: CompatCheck) -> Result<ClientStorage, EngineError> {
+    Err(invalid_input(
+        "--store needs a build with the kv-store feature",
+    ))
+}
+
+#[cfg(feature = "serde")]
+fn import_interchange(client_table: &mut ClientTable, path: &str) -> io::Result<()> {
+    client_table.import_interchange(BufReader::new(File::open(path)?))
+}
+
+#[cfg(feature = "serde")]
+fn export_interchange(client_table: &ClientTable, path: &str) -> io::Result<()> {
+    client_table.export_interchange(BufWriter::new(File::create(path)?))
+}
+
+#[cfg(not(feature = "serde"))]
+fn import_interchange(_client_table: &mut ClientTable, _path: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "--import-from needs a build with the serde feature",
+    ))
+}
+
+#[cfg(not(feature = "serde"))]
+fn export_interchange(_client_table: &ClientTable, _path: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "--export-to needs a build with the serde feature",
+    ))
+}
+
+fn persist(
+    client_table: &mut ClientTable,
+    snapshot_to: Option<String>,
+    export_to: Option<String>,
+    trial_balance: Option<&str>,
+) -> io::Result<()> {
+    client_table.flush_clients()?;
+    if let Some(snapshot) = snapshot_to {
+        client_table.snapshot(snapshot)?;
+    }
+    if let Some(export) = export_to {
+        export_interchange(client_table, &export)?;
+    }
+    if let Some(path) = trial_balance {
+        client_table.write_trial_balance(BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
+
+/// Parses the value of a rounding mode flag
+fn rounding_mode(value: &str, flag: &str) -> Result<Rounding, EngineError> {
+    Rounding::from_name(value).ok_or_else(|| {
+        invalid_input(&format!(
+            "{} expects reject, toward-zero, half-up or half-even",
+            flag
+        ))
+    })
+}
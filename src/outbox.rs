@@ -0,0 +1,264 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+/// Bytes collected before the outbox publishes them, at the end of a record
+pub const OUTBOX_BATCH: usize = 64 << 10;
+
+/// How often a failed publication is attempted again, waiting twice as long after every attempt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, 0 gives up on the first failure
+    pub retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Runs `attempt` until it succeeds, fails with an error that is not transient or runs out of
+    /// retries, sleeping between attempts
+    pub fn run<T>(&self, mut attempt: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = self.retries;
+        loop {
+            match attempt() {
+                Err(e) if retries > 0 && is_transient(&e) => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries -= 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Errors a broker, endpoint or pipe may recover from
+pub fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | BrokenPipe
+    )
+}
+
+/// Durable outbox in front of the output of an event sink, for sinks publishing to a network
+///
+/// Everything written is appended to the outbox file first, then published to the output by
+/// batches of `OUTBOX_BATCH` bytes ending on a record and on `flush`, retrying transient failures
+/// according to a `RetryPolicy`. The outbox file is emptied once its content is published. If the
+/// output still fails, the error is returned and the unpublished bytes stay in the outbox file,
+/// they are published before anything else the next time the outbox is opened. Events are thus
+/// delivered at least once: a crash after publishing but before emptying the outbox publishes the
+/// last batch again
+///
+/// A failed flush stops the CLI before it saves the engine state, so a snapshot never gets ahead
+/// of the events published
+pub struct Outbox<W: Write> {
+    out: W,
+    file: File,
+    /// Content of the outbox file, not published yet
+    pending: Vec<u8>,
+    retry: RetryPolicy,
+}
+
+impl<W: Write> Outbox<W> {
+    /// Opens the outbox file at `path`, creating it if needed, and publishes what a previous run
+    /// left in it
+    pub fn open(path: impl AsRef<Path>, out: W, retry: RetryPolicy) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut pending = Vec::new();
+        file.read_to_end(&mut pending)?;
+        let mut outbox = Self {
+            out,
+            file,
+            pending,
+            retry,
+        };
+        outbox.publish()?;
+        Ok(outbox)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Bytes waiting in the outbox
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Publishes the pending bytes and empties the outbox, keeping what could not be published
+    fn publish(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+        let mut published = 0;
+        let (out, pending, retry) = (&mut self.out, &self.pending, self.retry);
+        let result = retry
+            .run(|| {
+                // Resumes after a partial write so no byte is published twice
+                while published < pending.len() {
+                    match out.write(&pending[published..])? {
+                        0 => return Err(io::ErrorKind::WriteZero.into()),
+                        n => published += n,
+                    }
+                }
+                Ok(())
+            })
+            .and_then(|()| retry.run(|| out.flush()));
+        self.pending.drain(..published);
+        self.file.set_len(0)?;
+        self.file.write_all(&self.pending)?;
+        result
+    }
+}
+
+impl<W: Write> Write for Outbox<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= OUTBOX_BATCH && buf.ends_with(b"\n") {
+            self.publish()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.publish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Output failing a number of times, each time after taking part of the bytes
+    struct Flaky {
+        published: Vec<u8>,
+        failures: u32,
+        took_part: bool,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.took_part = !self.took_part;
+                if !self.took_part {
+                    self.failures -= 1;
+                    return Err(io::ErrorKind::ConnectionReset.into());
+                }
+                let n = buf.len().min(3);
+                self.published.extend_from_slice(&buf[..n]);
+                return Ok(n);
+            }
+            self.published.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            match self.failures {
+                0 => Ok(()),
+                _ => Err(io::ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    fn flaky(failures: u32) -> Flaky {
+        Flaky {
+            published: Vec::new(),
+            failures,
+            took_part: false,
+        }
+    }
+
+    #[test]
+    fn retry_policy_backs_off_on_transient_errors_only() {
+        let policy = RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            Err::<(), _>(io::Error::from(io::ErrorKind::TimedOut))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+        attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn unpublished_events_are_published_on_the_next_run() {
+        let path = std::env::temp_dir().join(format!("bank_outbox_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let retry = RetryPolicy {
+            retries: 0,
+            ..RetryPolicy::default()
+        };
+        // Takes 3 bytes, then fails
+        let mut outbox = Outbox::open(&path, flaky(1), retry).unwrap();
+        writeln!(outbox, "1\tdeposit, 1, 1, 1.0000, applied").unwrap();
+        assert!(outbox.flush().is_err());
+        assert_eq!(outbox.into_inner().published, b"1\td");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "eposit, 1, 1, 1.0000, applied\n"
+        );
+
+        let outbox = Outbox::open(&path, flaky(0), retry).unwrap();
+        assert_eq!(outbox.pending(), 0);
+        assert_eq!(
+            outbox.into_inner().published,
+            b"eposit, 1, 1, 1.0000, applied\n"
+        );
+
+        let retry = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let mut outbox = Outbox::open(&path, flaky(3), retry).unwrap();
+        writeln!(outbox, "2\twithdrawal, 2, 2, 1.0000, applied").unwrap();
+        outbox.flush().unwrap();
+        assert_eq!(
+            outbox.into_inner().published,
+            b"2\twithdrawal, 2, 2, 1.0000, applied\n"
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    mem,
+    path::Path,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+};
+
+use crate::{
+    cancel::CancellationToken,
+    compression::decompress,
+    csv_parser::{parse_line_with, skip_header, split_fields, Header, ParseCSVError, Records},
+    error::EngineError,
+    payment_engine::{ClientTable, Summary},
+    policy::DisputeRouting,
+    transaction::{ClientId, TxId},
+};
+
+/// Number of lines sent to a shard at once, sending every line on its own spends more time in the
+/// channel than in the engine
+const BATCH_SIZE: usize = 1024;
+
+/// Number of records between two progress reports of `process_files`
+pub const PROGRESS_INTERVAL: usize = 100_000;
+
+impl ClientTable {
+    /// Processes csv records (without the header line, see `skip_header`) on `num_threads` worker threads
+    ///
+    /// Records are routed by client id so each worker owns a disjoint set of clients and applies
+    /// their transactions in input order, parsing happens on the workers as it dominates the runtime.
+    /// Transaction ids are global, so the dispatcher rejects deposits and withdrawals reusing an id
+    /// already claimed by another client. Unlike the sequential engine it also does so when the
+    /// earlier record was itself rejected. Pending approvals expire based on the records seen by
+    /// their own worker. When disputes are routed by transaction id, the dispatcher sends them to
+    /// the worker owning the client that claimed the id. `accrue` records go to every worker and
+    /// are counted once by each of them. A batch spanning several workers couldn't be applied all
+    /// or nothing, so batch records are refused with an `InvalidInput` error. On an error the other
+    /// workers keep going, so the table is only meaningful when `Ok` is returned
+    pub fn process_parallel<R: BufRead>(
+        &mut self,
+        reader: R,
+        num_threads: usize,
+    ) -> Result<Summary, ParseCSVError> {
+        self.process_parallel_until_cancelled(reader, num_threads, &CancellationToken::new())
+    }
+
+    /// Same as `process_parallel`, but stops reading once `token` is cancelled. Records already
+    /// handed to a worker are still applied, so the table ends at a record boundary
+    pub fn process_parallel_until_cancelled<R: BufRead>(
+        &mut self,
+        reader: R,
+        num_threads: usize,
+        token: &CancellationToken,
+    ) -> Result<Summary, ParseCSVError> {
+        let num_threads = num_threads.max(1);
+        let by_tx = self.policy().dispute_routing != DisputeRouting::Client;
+        let mut shards = self.shard(num_threads);
+        let mut claimed: HashMap<TxId, ClientId> =
+            shards.iter().flat_map(|s| s.indexed_txs()).collect();
+
+        let (dispatched, worker_results) = thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(num_threads);
+            let mut workers = Vec::with_capacity(num_threads);
+            for shard in shards.iter_mut() {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<String>>(4);
+                senders.push(sender);
+                workers.push(scope.spawn(move || run_shard(shard, receiver)));
+            }
+
+            let dispatched = dispatch(reader, &senders, &mut claimed, by_tx, token);
+            drop(senders);
+            let results: Vec<_> = workers
+                .into_iter()
+                .map(|w| w.join().expect("shard worker panicked"))
+                .collect();
+            (dispatched, results)
+        });
+
+        for shard in shards {
+            // Shards hold disjoint clients and transaction ids by construction
+            self.merge(shard)
+                .expect("shards of a single table never conflict");
+        }
+        let mut summary = Summary {
+            applied: 0,
+            rejected: dispatched?,
+        };
+        for result in worker_results {
+            summary += result?;
+        }
+        Ok(summary)
+    }
+}
+
+impl ClientTable {
+    /// Processes independent csv files concurrently, one thread and one table configured like this
+    /// one per file, then merges the results into this table
+    ///
+    /// The files must touch disjoint sets of clients and transaction ids, neither of which may
+    /// already be in this table, otherwise merging fails with an error naming the offending file.
+    /// `progress` is called from the worker threads with the index of the file and its summary so
+    /// far, every `PROGRESS_INTERVAL` records and once the file is done. Each file runs its own
+    /// clock. On error this table is left untouched
+    pub fn process_files<P: AsRef<Path> + Sync>(
+        &mut self,
+        paths: &[P],
+        header: Header,
+        progress: &(dyn Fn(usize, Summary) + Sync),
+    ) -> Result<Summary, EngineError> {
+        let mut tables: Vec<_> = paths.iter().map(|_| self.empty_like()).collect();
+        let results: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = tables
+                .iter_mut()
+                .zip(paths)
+                .enumerate()
+                .map(|(i, (table, path))| {
+                    scope.spawn(move || run_file(table, path.as_ref(), header, &|s| progress(i, s)))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|w| w.join().expect("file worker panicked"))
+                .collect()
+        });
+        let mut combined = self.empty_like();
+        let mut summary = Summary::default();
+        for ((table, result), path) in tables.into_iter().zip(results).zip(paths) {
+            let path = path.as_ref();
+            summary += result.map_err(|e| e.in_file(path))?;
+            combined
+                .merge(table)
+                .map_err(|e| EngineError::from(e).in_file(path))?;
+        }
+        self.merge(combined)?;
+        Ok(summary)
+    }
+}
+
+fn run_file(
+    table: &mut ClientTable,
+    path: &Path,
+    header: Header,
+    progress: &dyn Fn(Summary),
+) -> Result<Summary, EngineError> {
+    let mut reader = decompress(Box::new(BufReader::new(File::open(path)?)))?;
+    skip_header(&mut reader, header)?;
+    let mut summary = Summary::default();
+    for (i, line) in Records::new(reader).enumerate() {
+        if let Ok(line) = &line {
+            table.count_bytes(line.len() as u64 + 1);
+        }
+        let tx = parse_line_with(line, table.currency_config())?;
+        match table.handle_transaction(tx) {
+            Ok(()) => summary.applied += 1,
+            Err(_) => summary.rejected += 1,
+        }
+        if (i + 1) % PROGRESS_INTERVAL == 0 {
+            progress(summary);
+        }
+    }
+    progress(summary);
+    Ok(summary)
+}
+
+/// Reads the records and sends them in batches to the shard owning their client, or to every
+/// shard for records concerning all clients
+/// Returns the number of records rejected by the dispatcher itself
+fn dispatch<R: BufRead>(
+    reader: R,
+    senders: &[SyncSender<Vec<String>>],
+    claimed: &mut HashMap<TxId, ClientId>,
+    by_tx: bool,
+    token: &CancellationToken,
+) -> io::Result<usize> {
+    let mut rejected = 0;
+    let mut batches = vec![Vec::with_capacity(BATCH_SIZE); senders.len()];
+    for line in Records::new(reader) {
+        if token.is_cancelled() {
+            break;
+        }
+        let line = line?;
+        let shard = match route(&line, claimed, by_tx) {
+            Route::Client(client) => client as usize % senders.len(),
+            Route::All => {
+                for shard in 0..senders.len() {
+                    push(&mut batches, senders, shard, line.clone());
+                }
+                continue;
+            }
+            // Let the first worker parse the line and report the error
+            Route::Unparsable => 0,
+            Route::DuplicateTxId => {
+                rejected += 1;
+                continue;
+            }
+            Route::Batch => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "batches can't be split over several threads, process the feed on one",
+                ))
+            }
+        };
+        push(&mut batches, senders, shard, line);
+    }
+    for (sender, batch) in senders.iter().zip(batches) {
+        let _ = sender.send(batch);
+    }
+    Ok(rejected)
+}
+
+/// Adds `line` to the batch of `shard`, sending the batch once it is full
+fn push(
+    batches: &mut [Vec<String>],
+    senders: &[SyncSender<Vec<String>>],
+    shard: usize,
+    line: String,
+) {
+    batches[shard].push(line);
+    if batches[shard].len() == BATCH_SIZE {
+        let batch = mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+        // A worker only hangs up after a parse error, which it reports when joined
+        let _ = senders[shard].send(batch);
+    }
+}
+
+enum Route {
+    Client(ClientId),
+    /// Records concerning every client
+    All,
+    /// `begin`, `commit` and `rollback`
+    Batch,
+    DuplicateTxId,
+    Unparsable,
+}
+
+/// Peeks at the type, client and tx columns without parsing the whole record, `by_tx` routes the
+/// dispute family to the client that claimed the transaction id
+fn route(line: &str, claimed: &mut HashMap<TxId, ClientId>, by_tx: bool) -> Route {
+    let fields = match split_fields(line) {
+        Ok(fields) => fields,
+        Err(_) => return Route::Unparsable,
+    };
+    let mut fields = fields.iter().map(|f| f.as_ref());
+    let transaction_type = fields.next();
+    let client = match fields.next().map(str::parse::<ClientId>) {
+        Some(Ok(client)) => client,
+        _ => return Route::Unparsable,
+    };
+    let tx = fields.next().map(str::parse::<TxId>);
+    match (transaction_type, tx) {
+        (Some("deposit") | Some("withdrawal"), Some(Ok(tx)))
+            if *claimed.entry(tx).or_insert(client) != client =>
+        {
+            Route::DuplicateTxId
+        }
+        (Some("dispute") | Some("resolve") | Some("chargeback"), Some(Ok(tx))) if by_tx => {
+            Route::Client(claimed.get(&tx).copied().unwrap_or(client))
+        }
+        (Some("accrue"), _) => Route::All,
+        (Some("begin") | Some("commit") | Some("rollback"), _) => Route::Batch,
+        _ => Route::Client(client),
+    }
+}
+
+fn run_shard(
+    shard: &mut ClientTable,
+    batches: Receiver<Vec<String>>,
+) -> Result<Summary, ParseCSVError> {
+    let mut summary = Summary::default();
+    for batch in batches {
+        for line in batch {
+            shard.count_bytes(line.len() as u64 + 1);
+            // Returning drops the receiver, which tells the dispatcher to stop sending to this shard
+            let tx = parse_line_with(Ok(line), shard.currency_config())?;
+            match shard.handle_transaction(tx) {
+                Ok(()) => summary.applied += 1,
+                Err(_) => summary.rejected += 1,
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        csv_parser::parse_line,
+        currency::Currency,
+        policy::{OverdrawPolicy, Policy},
+        schedules::{Posting, Schedules},
+    };
+
+    const INPUT: &str = "deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.5
+deposit, 3, 4, 1.0
+dispute, 2, 2,
+deposit, 4, 1, 3.0
+withdrawal, 3, 5, 4.0
+chargeback, 2, 2,
+deposit, 5, 6, 1.5
+";
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let mut sequential = ClientTable::new();
+        let expected = sequential.process(
+            INPUT
+                .lines()
+                .map(|l| parse_line(Ok(l.to_string())).unwrap()),
+        );
+        for threads in 1..=4 {
+            let mut parallel = ClientTable::new();
+            let summary = parallel
+                .process_parallel(INPUT.as_bytes(), threads)
+                .unwrap();
+            assert_eq!(summary, expected);
+            assert_eq!(parallel.to_string(), sequential.to_string());
+        }
+    }
+
+    #[test]
+    fn accrue_records_reach_every_worker() {
+        let mut schedules = Schedules::new();
+        schedules.push(Posting::Interest("0.5".parse().unwrap()), 1);
+        let input = "deposit, 1, 1, 2.0\ndeposit, 2, 2, 4.0\naccrue, 0, 3,\n";
+        let mut sequential = ClientTable::new();
+        sequential.set_schedules(schedules.clone());
+        sequential.process(
+            input
+                .lines()
+                .map(|l| parse_line(Ok(l.to_string())).unwrap()),
+        );
+        let mut parallel = ClientTable::new();
+        parallel.set_schedules(schedules);
+        parallel.process_parallel(input.as_bytes(), 2).unwrap();
+        assert_eq!(parallel.to_string(), sequential.to_string());
+        assert_eq!(parallel.accrual_day(), 1);
+        assert_eq!(
+            parallel.client(2).unwrap().available_funds(),
+            Currency::new(60000)
+        );
+    }
+
+    #[test]
+    fn batches_are_refused() {
+        // The overdraw of client 2 rolls back the withdrawal of client 1 when run on one thread
+        let input = "deposit, 1, 1, 5.0
+begin, 0, 10,
+withdrawal, 1, 2, 1.0
+withdrawal, 2, 3, 1.0
+commit, 0, 10,
+";
+        let mut sequential = ClientTable::new();
+        sequential.process(
+            input
+                .lines()
+                .map(|l| parse_line(Ok(l.to_string())).unwrap()),
+        );
+        assert_eq!(
+            sequential.client(1).unwrap().available_funds(),
+            Currency::new(50000)
+        );
+        let mut table = ClientTable::new();
+        match table.process_parallel(input.as_bytes(), 2) {
+            Err(ParseCSVError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            result => panic!("batch applied on several threads: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_errors_are_reported() {
+        let mut table = ClientTable::new();
+        let result =
+            table.process_parallel("deposit, 1, 1, 1.0\ndeposit, x, 2, 1.0\n".as_bytes(), 2);
+        assert!(matches!(result, Err(ParseCSVError::ParseIntError(_))));
+    }
+
+    #[test]
+    fn files_are_processed_apart_and_merged() {
+        let dir = std::env::temp_dir();
+        let paths: Vec<_> = (0..2)
+            .map(|i| dir.join(format!("bank_files_{}_{}.csv", std::process::id(), i)))
+            .collect();
+        std::fs::write(&paths[0], "type, client, tx, amount\ndeposit, 1, 1, 2.0\n").unwrap();
+        std::fs::write(&paths[1], "deposit, 2, 2, 3.0\nwithdrawal, 2, 3, 5.0\n").unwrap();
+        let reports = std::sync::Mutex::new(Vec::new());
+        let mut table = ClientTable::new();
+        let summary = table
+            .process_files(&paths, Header::Detect, &|file, summary| {
+                reports.lock().unwrap().push((file, summary))
+            })
+            .unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                applied: 2,
+                rejected: 1
+            }
+        );
+        assert_eq!(reports.into_inner().unwrap().len(), 2);
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n1, 2.0000, 0.0000, 2.0000, false\n2, 3.0000, 0.0000, 3.0000, false\n"
+        );
+
+        // Both files touching client 1 can't be merged
+        std::fs::write(&paths[1], "deposit, 1, 2, 3.0\n").unwrap();
+        let mut table = ClientTable::new();
+        let result = table.process_files(&paths, Header::Detect, &|_, _| {});
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert!(matches!(
+            result,
+            Err(EngineError::InFile(_, e)) if matches!(*e, EngineError::Merge(_))
+        ));
+        assert_eq!(table.to_string(), ClientTable::new().to_string());
+    }
+
+    #[test]
+    fn overdraw_policies_reach_the_shards_and_file_tables() {
+        let table = || {
+            let mut table = ClientTable::with_policy(Policy {
+                overdraw: OverdrawPolicy::Strict,
+                ..Default::default()
+            });
+            table.set_overdraw_policy(2, OverdrawPolicy::Overdraft(Currency::new(20000)));
+            table
+        };
+        let input = "deposit, 1, 1, 1.0\ndeposit, 2, 2, 1.0\nwithdrawal, 1, 3, 1.0\nwithdrawal, 2, 4, 3.0\n";
+        let expected = "client, available, held, total, locked\n1, 1.0000, 0.0000, 1.0000, false\n2, -2.0000, 0.0000, -2.0000, false\n";
+        for threads in 1..=4 {
+            let mut parallel = table();
+            let summary = parallel
+                .process_parallel(input.as_bytes(), threads)
+                .unwrap();
+            assert_eq!(summary.rejected, 1);
+            assert_eq!(parallel.to_string(), expected);
+        }
+
+        let dir = std::env::temp_dir();
+        let paths: Vec<_> = (1..=2)
+            .map(|i| dir.join(format!("bank_overdraw_{}_{}.csv", std::process::id(), i)))
+            .collect();
+        std::fs::write(&paths[0], "deposit, 1, 1, 1.0\nwithdrawal, 1, 3, 1.0\n").unwrap();
+        std::fs::write(&paths[1], "deposit, 2, 2, 1.0\nwithdrawal, 2, 4, 3.0\n").unwrap();
+        let mut files = table();
+        let result = files.process_files(&paths, Header::Detect, &|_, _| {});
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(result.unwrap().rejected, 1);
+        assert_eq!(files.to_string(), expected);
+    }
+
+    #[test]
+    fn disputes_routed_by_tx_reach_the_owning_shard() {
+        let policy = Policy {
+            dispute_routing: DisputeRouting::TxId,
+            ..Default::default()
+        };
+        let input =
+            "deposit, 1, 1, 2.0\ndeposit, 2, 2, 3.0\ndispute, 900, 2,\nchargeback, 901, 2,\n";
+        for threads in 1..=4 {
+            let mut table = ClientTable::with_policy(policy);
+            let summary = table.process_parallel(input.as_bytes(), threads).unwrap();
+            assert_eq!(summary.rejected, 0);
+            assert!(table.client(2).unwrap().is_locked());
+        }
+    }
+
+    #[test]
+    fn cancelled_before_start_applies_nothing() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut table = ClientTable::new();
+        let summary = table
+            .process_parallel_until_cancelled(INPUT.as_bytes(), 2, &token)
+            .unwrap();
+        assert_eq!(summary, Summary::default());
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use parquet::{
+    basic::{Compression, LogicalType, Repetition, Type as PhysicalType},
+    data_type::{BoolType, ByteArray, ByteArrayType, Int32Type, Int64Type},
+    errors::ParquetError,
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::{SerializedColumnWriter, SerializedFileWriter},
+    },
+    record::{reader::RowIter, Field},
+    schema::types::Type,
+};
+
+use crate::{
+    csv_parser::{self, FIELD_NAMES},
+    currency::{Currency, CurrencyConfig},
+    payment_engine::ClientTable,
+    report::ReportOptions,
+    transaction::Transaction,
+    version::ENGINE_VERSION,
+};
+
+/// Digits of the largest amount a 64 bit decimal column holds
+const DECIMAL_PRECISION: i32 = 18;
+
+fn corrupt(e: ParquetError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Corrupt parquet input: {}", e),
+    )
+}
+
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unsupported parquet input: {}", message),
+    )
+}
+
+/// The value of a field as the text parsers expect it, decimals are written out with their point
+fn text(name: &str, field: Field) -> io::Result<Option<String>> {
+    let text = match field {
+        Field::Null => return Ok(None),
+        Field::Bool(b) => b.to_string(),
+        Field::Byte(n) => n.to_string(),
+        Field::Short(n) => n.to_string(),
+        Field::Int(n) | Field::Date(n) => n.to_string(),
+        Field::Long(n) | Field::TimestampMillis(n) | Field::TimestampMicros(n) => n.to_string(),
+        Field::UByte(n) => n.to_string(),
+        Field::UShort(n) => n.to_string(),
+        Field::UInt(n) => n.to_string(),
+        Field::ULong(n) => n.to_string(),
+        Field::Float16(x) => x.to_string(),
+        Field::Float(x) => x.to_string(),
+        Field::Double(x) => x.to_string(),
+        Field::Decimal(decimal) => {
+            // Big endian two's complement, sign extended from the first byte
+            let bytes = decimal.data();
+            if bytes.len() > 16 {
+                return Err(unsupported(&format!("decimals of column {}", name)));
+            }
+            let fill = if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+                -1
+            } else {
+                0
+            };
+            let n = bytes
+                .iter()
+                .fold(fill, |n: i128, &b| n << 8 | i128::from(b));
+            csv_parser::decimal_text(n, decimal.scale().max(0) as u32)
+        }
+        Field::Str(s) => s,
+        Field::Bytes(bytes) => String::from_utf8(bytes.data().to_vec()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Corrupt parquet input: column {} is not utf-8", name),
+            )
+        })?,
+        Field::Group(_) | Field::ListInternal(_) | Field::MapInternal(_) => {
+            return Err(unsupported(&format!("{} is a nested column", name)))
+        }
+    };
+    Ok(Some(text))
+}
+
+/// Transactions of a Parquet file, one per row, read with the `parquet` crate
+///
+/// Columns are looked up by name, the same names as the keys of the JSON input: `type`, `client`
+/// and `tx` are required, `amount`, `currency`, `value_date` and `to` are optional. Any other
+/// column is ignored. Amounts may be strings, integers, floating point numbers or decimals,
+/// they are parsed with the configured precision like the amounts of the csv input
+///
+/// Only flat schemas are supported. Pages may be uncompressed or compressed with Snappy or gzip,
+/// and their values plain or dictionary encoded, which covers what the usual writers produce by
+/// default
+pub struct Decoder {
+    /// Rows left, `None` once a row failed to be read
+    rows: Option<RowIter<'static>>,
+    len: usize,
+    currency: CurrencyConfig,
+    /// Column read into each of `FIELD_NAMES`
+    fields: Vec<Option<usize>>,
+    /// Rows read so far
+    read: usize,
+}
+
+impl Decoder {
+    /// The whole file has to be in memory as the metadata is at its end
+    pub fn new(data: Vec<u8>, currency: CurrencyConfig) -> io::Result<Self> {
+        let reader = SerializedFileReader::new(Bytes::from(data)).map_err(corrupt)?;
+        let metadata = reader.metadata().file_metadata();
+        let columns = metadata.schema().get_fields();
+        for column in columns {
+            if column.is_group() {
+                return Err(unsupported(&format!(
+                    "{} is a nested column",
+                    column.name()
+                )));
+            }
+            if column.get_basic_info().repetition() == Repetition::REPEATED {
+                return Err(unsupported(&format!(
+                    "{} is a repeated column",
+                    column.name()
+                )));
+            }
+        }
+        let fields: Vec<_> = FIELD_NAMES
+            .iter()
+            .map(|name| columns.iter().position(|c| c.name() == *name))
+            .collect();
+        for (name, column) in FIELD_NAMES.iter().zip(&fields).take(3) {
+            if column.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the parquet input has no {} column", name),
+                ));
+            }
+        }
+        let len = usize::try_from(metadata.num_rows()).unwrap_or(0);
+        Ok(Self {
+            rows: Some(RowIter::from_file_into(Box::new(reader))),
+            len,
+            currency,
+            fields,
+            read: 0,
+        })
+    }
+
+    /// Number of rows in the file
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn record(&mut self, mut row: Vec<(String, Field)>) -> io::Result<Transaction> {
+        self.read += 1;
+        let mut text_fields = Vec::with_capacity(FIELD_NAMES.len());
+        for column in &self.fields {
+            text_fields.push(match column {
+                Some(column) => {
+                    let (name, field) = &mut row[*column];
+                    text(name, std::mem::replace(field, Field::Null))?
+                }
+                None => None,
+            });
+        }
+        let fields = [0, 1, 2, 3, 4, 5, 6].map(|i| text_fields[i].as_deref());
+        csv_parser::parse_named(fields, self.currency).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("row {}: {:?}", self.read, e),
+            )
+        })
+    }
+}
+
+impl Iterator for Decoder {
+    type Item = io::Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.rows.as_mut()?.next()? {
+            Ok(row) => Some(self.record(row.into_columns())),
+            Err(e) => {
+                // Nothing past a broken page can be trusted
+                self.rows = None;
+                Some(Err(corrupt(e)))
+            }
+        }
+    }
+}
+
+/// Annotation of the physical type of a written column
+#[derive(Clone, Copy, Debug)]
+enum Annotation {
+    None,
+    Utf8,
+    Uint16,
+    Decimal(u32),
+}
+
+/// Value of a written column in a row
+#[derive(Clone, Debug, PartialEq)]
+enum Cell {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+/// Column of a file being written, with its values
+struct Written {
+    name: &'static str,
+    physical: PhysicalType,
+    annotation: Annotation,
+    optional: bool,
+    cells: Vec<Cell>,
+}
+
+impl Written {
+    fn new(name: &'static str, physical: PhysicalType, annotation: Annotation) -> Self {
+        Self {
+            name,
+            physical,
+            annotation,
+            optional: false,
+            cells: Vec::new(),
+        }
+    }
+
+    fn optional(self) -> Self {
+        Self {
+            optional: true,
+            ..self
+        }
+    }
+
+    fn schema(&self) -> Result<Type, ParquetError> {
+        let repetition = match self.optional {
+            true => Repetition::OPTIONAL,
+            false => Repetition::REQUIRED,
+        };
+        let builder =
+            Type::primitive_type_builder(self.name, self.physical).with_repetition(repetition);
+        let builder = match self.annotation {
+            Annotation::None => builder,
+            Annotation::Utf8 => builder.with_logical_type(Some(LogicalType::String)),
+            Annotation::Uint16 => builder.with_logical_type(Some(LogicalType::Integer {
+                bit_width: 16,
+                is_signed: false,
+            })),
+            Annotation::Decimal(scale) => builder
+                .with_logical_type(Some(LogicalType::Decimal {
+                    scale: scale as i32,
+                    precision: DECIMAL_PRECISION,
+                }))
+                .with_precision(DECIMAL_PRECISION)
+                .with_scale(scale as i32),
+        };
+        builder.build()
+    }
+
+    /// Writes the values of the column to the writer of its chunk
+    fn write(&self, writer: &mut SerializedColumnWriter<'_>) -> Result<(), ParquetError> {
+        let levels: Option<Vec<i16>> = self.optional.then(|| {
+            self.cells
+                .iter()
+                .map(|c| (*c != Cell::Null).into())
+                .collect()
+        });
+        let levels = levels.as_deref();
+        let present = self.cells.iter().filter(|c| **c != Cell::Null);
+        let int = |cell: &Cell| match cell {
+            Cell::Int(n) => *n,
+            _ => unreachable!("integer columns only hold integers"),
+        };
+        match self.physical {
+            PhysicalType::BOOLEAN => {
+                let values: Vec<_> = present.map(|c| *c == Cell::Bool(true)).collect();
+                writer
+                    .typed::<BoolType>()
+                    .write_batch(&values, levels, None)?;
+            }
+            PhysicalType::INT32 => {
+                let values: Vec<_> = present.map(|c| int(c) as i32).collect();
+                writer
+                    .typed::<Int32Type>()
+                    .write_batch(&values, levels, None)?;
+            }
+            PhysicalType::INT64 => {
+                let values: Vec<_> = present.map(int).collect();
+                writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, levels, None)?;
+            }
+            _ => {
+                let values: Vec<_> = present
+                    .map(|c| match c {
+                        Cell::Bytes(bytes) => ByteArray::from(bytes.clone()),
+                        _ => unreachable!("string columns only hold strings"),
+                    })
+                    .collect();
+                writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, levels, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `columns` as a Parquet file of a single row group, or of none without rows
+fn write_file<W: Write>(
+    mut w: W,
+    columns: &[Written],
+    rows: usize,
+    compression: Compression,
+) -> io::Result<()> {
+    let fields = columns
+        .iter()
+        .map(|column| column.schema().map(Arc::new))
+        .collect::<Result<_, _>>()?;
+    let schema = Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()?;
+    let properties = WriterProperties::builder()
+        .set_created_by(format!("bank version {}", ENGINE_VERSION))
+        .set_compression(compression)
+        .build();
+    // The writer wants to own a `Send` output, which a locked stdout is not
+    let mut out = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut out, Arc::new(schema), Arc::new(properties))?;
+    if rows > 0 {
+        let mut group = writer.next_row_group()?;
+        for column in columns {
+            let mut chunk = group
+                .next_column()?
+                .expect("a column writer per field of the schema");
+            column.write(&mut chunk)?;
+            chunk.close()?;
+        }
+        group.close()?;
+    }
+    writer.close()?;
+    w.write_all(&out)?;
+    w.flush()
+}
+
+impl ClientTable {
+    /// Same as `write_csv_with` as a Parquet file with the same columns. Amounts are decimals with
+    /// as many digits after the point as the configured precision, and the client ids unsigned 16
+    /// bit integers. The names given by a client map go in an `external_id` column after them
+    pub fn write_parquet_with<W: Write>(&self, w: W, options: &ReportOptions) -> io::Result<()> {
+        let rows = self.report_rows_with(options)?;
+        let amount = |name| {
+            Written::new(
+                name,
+                PhysicalType::INT64,
+                Annotation::Decimal(self.currency_config().decimals()),
+            )
+        };
+        let mut client = Written::new("client", PhysicalType::INT32, Annotation::Uint16);
+        let mut external_id =
+            Written::new("external_id", PhysicalType::BYTE_ARRAY, Annotation::Utf8).optional();
+        let mut currency =
+            Written::new("currency", PhysicalType::BYTE_ARRAY, Annotation::Utf8).optional();
+        let mut available = amount("available");
+        let mut held = amount("held");
+        let mut total = amount("total");
+        let mut locked = Written::new("locked", PhysicalType::BOOLEAN, Annotation::None);
+        let mut pending = amount("pending");
+        let mut booked = amount("booked");
+        let mut legal_hold = amount("legal_hold");
+
+        let units = |amount: Currency| {
+            amount.to_i64().map(Cell::Int).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} is too large for a parquet decimal", amount),
+                )
+            })
+        };
+        let text = |s: Option<String>| s.map_or(Cell::Null, |s| Cell::Bytes(s.into_bytes()));
+        for row in &rows {
+            let info = row.info;
+            client.cells.push(Cell::Int(row.client.into()));
+            external_id
+                .cells
+                .push(text(self.client_labels.get(&row.client).cloned()));
+            currency.cells.push(text(row.code.map(|c| c.to_string())));
+            available.cells.push(units(info.available_funds())?);
+            held.cells.push(units(info.held_funds())?);
+            total.cells.push(units(row.total)?);
+            locked.cells.push(Cell::Bool(info.is_locked()));
+            pending.cells.push(units(row.pending.unwrap_or_default())?);
+            booked.cells.push(units(info.booked_funds())?);
+            legal_hold.cells.push(units(row.legal_hold)?);
+        }
+
+        let mut columns = vec![client];
+        if !self.client_labels.is_empty() {
+            columns.push(external_id);
+        }
+        if self.multi_currency() {
+            columns.push(currency);
+        }
+        columns.extend([available, held, total, locked]);
+        if self.policy().approvals.is_some() {
+            columns.push(pending);
+        }
+        if self.policy().defers_funds() {
+            columns.push(booked);
+        }
+        if self.extended_report {
+            columns.push(legal_hold);
+        }
+        write_file(w, &columns, rows.len(), Compression::UNCOMPRESSED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::currency::CurrencyCode;
+    use parquet::basic::GzipLevel;
+
+    fn feed(compression: Compression) -> Vec<u8> {
+        let mut kind = Written::new("type", PhysicalType::BYTE_ARRAY, Annotation::Utf8);
+        let mut client = Written::new("client", PhysicalType::INT32, Annotation::Uint16);
+        let mut tx = Written::new("tx", PhysicalType::INT64, Annotation::None);
+        let mut amount =
+            Written::new("amount", PhysicalType::INT64, Annotation::Decimal(2)).optional();
+        let mut memo = Written::new("memo", PhysicalType::BYTE_ARRAY, Annotation::Utf8).optional();
+        let rows = [
+            ("deposit", 1, 1, Some(150)),
+            ("deposit", 2, 2, Some(1000)),
+            ("withdrawal", 1, 3, Some(25)),
+            ("dispute", 2, 2, None),
+        ];
+        for (t, c, id, a) in rows {
+            kind.cells.push(Cell::Bytes(t.as_bytes().to_vec()));
+            client.cells.push(Cell::Int(c));
+            tx.cells.push(Cell::Int(id));
+            amount.cells.push(a.map_or(Cell::Null, Cell::Int));
+            memo.cells.push(Cell::Null);
+        }
+        let mut out = Vec::new();
+        write_file(
+            &mut out,
+            &[kind, client, tx, amount, memo],
+            rows.len(),
+            compression,
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn reads_transactions() {
+        let expected = vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(15000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(100000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 3,
+                amount: Currency::new(2500),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+        ];
+        // The types are dictionary encoded, as the writer does by default
+        for compression in [
+            Compression::UNCOMPRESSED,
+            Compression::SNAPPY,
+            Compression::GZIP(GzipLevel::default()),
+        ] {
+            let decoder = Decoder::new(feed(compression), CurrencyConfig::default()).unwrap();
+            assert_eq!(decoder.len(), 4);
+            let transactions: Vec<_> = decoder.map(Result::unwrap).collect();
+            assert_eq!(transactions, expected, "{:?}", compression);
+        }
+
+        let mut truncated = feed(Compression::UNCOMPRESSED);
+        truncated.truncate(truncated.len() - 1);
+        assert!(Decoder::new(truncated, CurrencyConfig::default()).is_err());
+        // Two decimals don't fit a precision of one
+        let currency = CurrencyConfig::new(1, Default::default()).unwrap();
+        let mut decoder = Decoder::new(feed(Compression::UNCOMPRESSED), currency).unwrap();
+        let error = decoder.nth(2).unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "row 3: TooManyDecimals");
+    }
+
+    /// Every row of a file as its column names and the text of their values
+    fn rows(data: Vec<u8>) -> Vec<Vec<(String, Option<String>)>> {
+        let reader = SerializedFileReader::new(Bytes::from(data)).unwrap();
+        RowIter::from_file_into(Box::new(reader))
+            .map(|row| {
+                row.unwrap()
+                    .into_columns()
+                    .into_iter()
+                    .map(|(name, field)| {
+                        let text = text(&name, field).unwrap();
+                        (name, text)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_the_report() {
+        let mut table = ClientTable::new();
+        table.set_base_currency(Some("EUR".parse::<CurrencyCode>().unwrap()));
+        for t in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(15000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(100000),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+        ] {
+            table.handle_transaction(t).unwrap();
+        }
+        let mut out = Vec::new();
+        table
+            .write_parquet_with(&mut out, &ReportOptions::default())
+            .unwrap();
+
+        let row = |values: &[(&str, &str)]| {
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), Some(value.to_string())))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rows(out),
+            [
+                row(&[
+                    ("client", "1"),
+                    ("currency", "EUR"),
+                    ("available", "1.5000"),
+                    ("held", "0.0000"),
+                    ("total", "1.5000"),
+                    ("locked", "false"),
+                ]),
+                row(&[
+                    ("client", "2"),
+                    ("currency", "EUR"),
+                    ("available", "0.0000"),
+                    ("held", "0.0000"),
+                    ("total", "0.0000"),
+                    ("locked", "true"),
+                ]),
+            ]
+        );
+
+        let mut empty = Vec::new();
+        ClientTable::new()
+            .write_parquet_with(&mut empty, &ReportOptions::default())
+            .unwrap();
+        let reader = SerializedFileReader::new(Bytes::from(empty)).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 0);
+    }
+}
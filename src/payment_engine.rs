@@ -1,52 +1,3636 @@
-use std::fmt;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    mem,
+    ops::AddAssign,
+};
 
 use crate::{
-    client_info::{ClientInfo, TransactionError},
-    transaction::{ClientId, Transaction},
+    approvals::PendingApprovals,
+    cancel::CancellationToken,
+    client_info::{
+        AccountType, ClientInfo, ClientTransaction, DisputeState, Release, TransactionError,
+        TransferKind, Trimmed,
+    },
+    credit::{CreditLine, CreditLines},
+    currency::{AggregationOverflow, Currency, CurrencyCode, CurrencyConfig},
+    dedup::Dedup,
+    events::{EngineWarning, Event},
+    fees::{FeeKind, FeeSchedule},
+    fx::RateTable,
+    general_ledger::{GeneralLedger, GlAccount},
+    hierarchy::Hierarchy,
+    interceptor::TransactionInterceptor,
+    policy::{
+        ClearingDelay, DisputeRouting, FeePayer, HistoryLookup, MemoryBudget, OverdrawPolicy,
+        Policy, PressureAction, SettlementPolicy, UndisputedPolicy,
+    },
+    query::Query,
+    rejects::quote_if_needed,
+    risk::{Activity, RiskLimits},
+    schedules::{Posting, Schedules},
+    storage::{ClientStorage, Layout},
+    transaction::{ClientId, Stamped, Timestamp, Transaction, TxId},
+    tx_index::TxIndex,
+};
+#[cfg(feature = "std-io")]
+use crate::{
+    archive::{Archive, RetentionPeriod},
+    progress::InputOffset,
+    wal::Wal,
 };
 
 /// Since there are so few possible client ids due to the assumption that clients are valid u16's
 /// It makes much more sense to simply use a vector instead of using a HashMap for performance
+/// Small inputs touching only a few clients can use the lazily populated `sparse` storage instead
 pub struct ClientTable {
-    clients: Vec<ClientInfo>,
+    pub(crate) clients: ClientStorage,
+    /// Internal account absorbing the fees the clients are not charged for
+    pub(crate) house: ClientInfo,
+    policy: Policy,
+    /// Owner of every applied deposit/withdrawal, used to reject duplicate ids and
+    /// to make sure disputes only ever touch the client the transaction belongs to
+    pub(crate) tx_index: TxIndex,
+    pub(crate) pending: PendingApprovals,
+    /// Logical clock counting handled records, used to expire pending approvals
+    pub(crate) clock: u64,
+    /// Approximate number of history entries held by the clients, only tracked with a memory budget
+    history_len: usize,
+    /// Clients whose history grew since it was last trimmed, the only ones that can need it
+    grown: HashSet<ClientId>,
+    /// History size the next trim waits for while the history stays above the soft limit
+    pressure_mark: usize,
+    #[cfg(feature = "std-io")]
+    spill: Option<Archive>,
+    pub(crate) warnings: Vec<EngineWarning>,
+    /// Precision the amounts were parsed at, used when writing them out
+    currency: CurrencyConfig,
+    /// Log every transaction is written to before it is handled
+    #[cfg(feature = "std-io")]
+    pub(crate) wal: Option<Wal>,
+    /// Bookings waiting for their value date, soonest first
+    pub(crate) schedule: BinaryHeap<Reverse<(u64, ClientId, TxId)>>,
+    /// Deposits waiting to clear, by the business day they clear at
+    pub(crate) clearing: BinaryHeap<Reverse<(u64, ClientId, TxId)>>,
+    /// Current business day, advanced by every clearing sweep
+    pub(crate) day: u64,
+    /// Whether reports include the extended columns, see `set_extended_report`
+    pub(crate) extended_report: bool,
+    /// Accounts of the clients in currencies other than the base one
+    pub(crate) foreign: BTreeMap<(ClientId, CurrencyCode), ClientInfo>,
+    /// Currency of the indexed transactions that went to a foreign account
+    pub(crate) tx_codes: HashMap<TxId, CurrencyCode>,
+    /// Currency of the regular accounts, see `set_base_currency`
+    base_currency: Option<CurrencyCode>,
+    /// Exchange rates used by conversions
+    rates: RateTable,
+    /// Throughput counters, see `stats`
+    pub(crate) stats: Stats,
+    /// Records seen so far, see `DedupPolicy`
+    pub(crate) dedup: Dedup,
+    /// Recurring postings made at the end of every accrual day
+    schedules: Schedules,
+    /// Accrual days closed so far, see `accrue`
+    pub(crate) accrual_day: u64,
+    /// Withdrawal and velocity limits of the clients
+    limits: RiskLimits,
+    /// Clients overdrawing differently from the policy, see `set_overdraw_policy`
+    overdraw: HashMap<ClientId, OverdrawPolicy>,
+    /// Credit granted to the clients, see `set_credit_lines`
+    pub(crate) credit_lines: CreditLines,
+    /// Fees charged for the transactions, collected in the house account
+    fees: FeeSchedule,
+    /// Double-entry mirror of the regular accounts, see `enable_general_ledger`
+    pub(crate) general_ledger: Option<GeneralLedger>,
+    /// Parents of the sub-accounts, see `Hierarchy`
+    hierarchy: Hierarchy,
+    /// Recent activity of the clients with limits, not part of snapshots
+    pub(crate) activity: HashMap<ClientId, Activity>,
+    /// Whether unlocks reverse the chargeback that locked the account, see `set_unlock_reverses`
+    unlock_reverses: bool,
+    /// Whether the outputs only depend on the input, see `set_deterministic`
+    deterministic: bool,
+    /// Id and records of the open batch, see `apply_batch`
+    pub(crate) batch: Option<(TxId, Vec<Transaction>)>,
+    /// Timestamp of the transaction being handled, see `handle_stamped`
+    time: Option<Timestamp>,
+    /// Offset of the input recorded in snapshots, see `checkpoint`
+    #[cfg(feature = "std-io")]
+    pub(crate) input_offset: Option<InputOffset>,
+    /// Records of the write-ahead log already applied to this state, see `recover_from_wal`
+    #[cfg(feature = "std-io")]
+    pub(crate) wal_position: u64,
+    /// Whether `adapt` may switch the storage, only when the caller didn't pick it
+    adaptive_storage: bool,
+    /// Layout in use after the last `adapt`
+    layout: Option<Layout>,
+    /// Names the report shows instead of the ids, see `set_client_labels`
+    pub(crate) client_labels: HashMap<ClientId, String>,
+    /// Hooks around every handled transaction, see `add_interceptor`
+    pub(crate) interceptors: Vec<Box<dyn TransactionInterceptor>>,
 }
 
 impl ClientTable {
     pub fn new() -> Self {
-        Self {
-            clients: vec![Default::default(); ClientId::MAX.into()],
+        Self::with_policy(Policy::default())
+    }
+
+    /// Table allocating clients lazily, see `ClientStorage`
+    pub fn sparse() -> Self {
+        Self::with_storage(ClientStorage::sparse(), Policy::default())
+    }
+
+    pub fn with_policy(policy: Policy) -> Self {
+        let mut table = Self::with_storage(ClientStorage::dense(), policy);
+        table.adaptive_storage = true;
+        table
+    }
+
+    /// Table on `clients`, which a custom store may have filled already, see `ClientStorage::custom`
+    /// `adapt` keeps the storage
+    pub fn with_storage(clients: ClientStorage, policy: Policy) -> Self {
+        let stored = matches!(clients, ClientStorage::Custom(_));
+        let mut table = Self {
+            clients,
+            house: Default::default(),
+            policy,
+            tx_index: TxIndex::new(policy.tx_index.unwrap_or_default()),
+            pending: PendingApprovals::default(),
+            clock: 0,
+            history_len: 0,
+            grown: HashSet::new(),
+            pressure_mark: 0,
+            #[cfg(feature = "std-io")]
+            spill: None,
+            warnings: Vec::new(),
+            currency: CurrencyConfig::default(),
+            #[cfg(feature = "std-io")]
+            wal: None,
+            schedule: BinaryHeap::new(),
+            clearing: BinaryHeap::new(),
+            day: 0,
+            extended_report: false,
+            foreign: BTreeMap::new(),
+            tx_codes: HashMap::new(),
+            base_currency: None,
+            rates: RateTable::new(),
+            stats: Stats::default(),
+            dedup: Dedup::new(policy.dedup),
+            schedules: Schedules::new(),
+            accrual_day: 0,
+            limits: RiskLimits::new(),
+            overdraw: HashMap::new(),
+            credit_lines: CreditLines::new(),
+            fees: FeeSchedule::new(),
+            general_ledger: None,
+            hierarchy: Hierarchy::new(),
+            activity: HashMap::new(),
+            unlock_reverses: false,
+            deterministic: cfg!(feature = "audit-build"),
+            batch: None,
+            time: None,
+            #[cfg(feature = "std-io")]
+            input_offset: None,
+            #[cfg(feature = "std-io")]
+            wal_position: 0,
+            adaptive_storage: false,
+            layout: None,
+            client_labels: HashMap::new(),
+            interceptors: Vec::new(),
+        };
+        if stored {
+            table.index_clients();
+        }
+        table
+    }
+
+    /// Switches what the caller left open of the storage, history lookup and transaction index to
+    /// what suits `sample`, the first transactions of the input, and returns the layout in use so
+    /// it can be logged. The storage is open unless it was given to `with_storage` or `sparse`, the
+    /// history lookup and index unless the policy names them. Once adapted nothing is left open,
+    /// see `adapts`. Existing clients and transaction ids are kept
+    pub fn adapt(&mut self, sample: &[Transaction]) -> Layout {
+        let mut layout = Layout::choose(sample);
+        if self.adaptive_storage {
+            self.clients.convert(layout.dense);
+            self.adaptive_storage = false;
+        }
+        layout.dense = self.clients.is_dense();
+        layout.tx_index = *self.policy.tx_index.get_or_insert(layout.tx_index);
+        self.tx_index.convert(layout.tx_index);
+        layout.history = *self.policy.history.get_or_insert(layout.history);
+        if layout.history == HistoryLookup::Indexed {
+            for (_, client) in self.clients.iter_mut() {
+                client.index_transfers();
+            }
+            self.house.index_transfers();
+        }
+        self.layout = Some(layout);
+        layout
+    }
+
+    /// Layout picked by the last `adapt`, `None` if the table was never adapted
+    pub fn layout(&self) -> Option<Layout> {
+        self.layout
+    }
+
+    /// Whether `adapt` would change anything, so sampling the input is worth it
+    pub fn adapts(&self) -> bool {
+        self.adaptive_storage || self.policy.history.is_none() || self.policy.tx_index.is_none()
+    }
+
+    pub fn set_account_type(&mut self, client: ClientId, account_type: AccountType) {
+        self.clients[client].set_account_type(account_type);
+    }
+
+    /// Overrides how far the withdrawals of `client` can draw their accounts down, in every
+    /// currency. Like the risk limits this is configuration and not part of snapshots
+    pub fn set_overdraw_policy(&mut self, client: ClientId, overdraw: OverdrawPolicy) {
+        self.overdraw.insert(client, overdraw);
+    }
+
+    /// Policy the withdrawals of `client` are checked against, `code` being the currency of a
+    /// foreign account which the credit lines don't cover
+    fn withdrawal_policy(&self, client: ClientId, code: Option<CurrencyCode>) -> Policy {
+        let credit = match code {
+            None => self.credit_lines.get(client),
+            Some(_) => None,
+        };
+        let overdraw = credit
+            .map(|credit| OverdrawPolicy::Overdraft(credit.limit))
+            .or_else(|| self.overdraw.get(&client).copied());
+        match overdraw {
+            Some(overdraw) => Policy {
+                overdraw,
+                ..self.policy
+            },
+            None => self.policy,
+        }
+    }
+
+    /// Sets the precision of the amounts, the transactions have to be parsed with the same one
+    pub fn set_currency_config(&mut self, currency: CurrencyConfig) {
+        self.currency = currency;
+    }
+
+    pub fn currency_config(&self) -> CurrencyConfig {
+        self.currency
+    }
+
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Adds a `legal_hold` column with the funds ring-fenced by legal hold orders to the reports
+    pub fn set_extended_report(&mut self, extended: bool) {
+        self.extended_report = extended;
+    }
+
+    /// Names the currency of the regular accounts, deposits and withdrawals in it go to them rather
+    /// than to a foreign account, and the reports gain a currency column
+    pub fn set_base_currency(&mut self, code: Option<CurrencyCode>) {
+        self.base_currency = code;
+    }
+
+    pub fn base_currency(&self) -> Option<CurrencyCode> {
+        self.base_currency
+    }
+
+    /// Replaces the exchange rates, conversions from then on use the new ones
+    pub fn set_rates(&mut self, rates: RateTable) {
+        self.rates = rates;
+    }
+
+    pub fn rates(&self) -> &RateTable {
+        &self.rates
+    }
+
+    /// Replaces the recurring postings, the accrual days closed so far keep counting
+    pub fn set_schedules(&mut self, schedules: Schedules) {
+        self.schedules = schedules;
+    }
+
+    pub fn schedules(&self) -> &Schedules {
+        &self.schedules
+    }
+
+    /// Replaces the risk limits, the activity seen so far still counts against the new ones
+    pub fn set_limits(&mut self, limits: RiskLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &RiskLimits {
+        &self.limits
+    }
+
+    /// Replaces the fees charged for the transactions, the reports then list the house account
+    /// with the fee revenue
+    pub fn set_fees(&mut self, fees: FeeSchedule) {
+        self.fees = fees;
+    }
+
+    pub fn fees(&self) -> &FeeSchedule {
+        &self.fees
+    }
+
+    /// Makes the parents of `hierarchy` cascade their locks and risk limits to their sub-accounts,
+    /// see `write_rollup` for the rolled up balances
+    pub fn set_hierarchy(&mut self, hierarchy: Hierarchy) {
+        self.hierarchy = hierarchy;
+    }
+
+    pub fn hierarchy(&self) -> &Hierarchy {
+        &self.hierarchy
+    }
+
+    /// Writes what a persistent client store holds back, see `ClientStore::flush`
+    pub fn flush_clients(&mut self) -> io::Result<()> {
+        self.clients.flush()
+    }
+
+    /// Makes `unlock` records reverse the fund movement of the chargeback they reference on top
+    /// of lifting the lock, by default they only lift it
+    pub fn set_unlock_reverses(&mut self, reverse: bool) {
+        self.unlock_reverses = reverse;
+    }
+
+    /// Makes the outputs of the table depend on its input alone, so replaying the same records
+    /// gives byte identical files: the audit trail is timestamped with the number of records
+    /// handled rather than the wall clock. On by default in builds with the `audit-build` feature
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Accrual days closed so far
+    pub fn accrual_day(&self) -> u64 {
+        self.accrual_day
+    }
+
+    pub fn house(&self) -> &ClientInfo {
+        &self.house
+    }
+
+    /// Operations still waiting for an approval
+    pub fn pending(&self) -> &PendingApprovals {
+        &self.pending
+    }
+
+    /// Archive receiving the history spilled under memory pressure
+    #[cfg(feature = "std-io")]
+    pub fn set_spill_archive(&mut self, archive: Archive) {
+        self.spill = Some(archive);
+    }
+
+    /// Logs every transaction to `wal` before handling it, see `recover_from_wal`
+    #[cfg(feature = "std-io")]
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    /// Detaches the write-ahead log, dropping it flushes the last records
+    #[cfg(feature = "std-io")]
+    pub fn take_wal(&mut self) -> Option<Wal> {
+        self.wal.take()
+    }
+
+    /// Counters of the transactions handled by this table and the input they were read from
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Adds `bytes` to the input read, the table only sees parsed transactions so readers report
+    /// the size of the records themselves
+    pub fn count_bytes(&mut self, bytes: u64) {
+        self.stats.bytes_read += bytes;
+    }
+
+    /// Adds `records` to the records skipped by the reader because they couldn't be parsed
+    pub fn count_malformed(&mut self, records: u64) {
+        self.stats.malformed += records;
+    }
+
+    /// Returns the warnings emitted since the last call
+    pub fn take_warnings(&mut self) -> Vec<EngineWarning> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// Same as `handle_transaction` for a transaction that may carry a timestamp and a memo, the
+    /// transfers it applies are stamped and tagged with them and a dispute is checked against the
+    /// dispute window
+    pub fn handle_stamped(&mut self, stamped: Stamped) -> Result<(), TransactionError> {
+        use Transaction::*;
+        let Stamped {
+            transaction,
+            timestamp,
+            memo,
+        } = stamped;
+        self.time = timestamp;
+        let applied = self.apply_intercepted(transaction);
+        self.time = None;
+        // A duplicate dropped by dedup keeps the memo of the record that was applied
+        if let (Ok(true), Some(memo)) = (applied, memo) {
+            if let Deposit { client, tx, .. }
+            | Withdraw { client, tx, .. }
+            | ForeignDeposit { client, tx, .. }
+            | ForeignWithdraw { client, tx, .. }
+            | Booking { client, tx, .. } = transaction
+            {
+                self.account_of(client, tx).tag_transfer(tx, memo);
+            }
         }
+        applied.map(|_| ())
     }
 
+    /// Runs the interceptors around the transaction, see `add_interceptor`
     pub fn handle_transaction(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        self.apply_intercepted(tx).map(|_| ())
+    }
+
+    /// Same as `handle_transaction`, returning whether the transaction was handled rather than
+    /// dropped as a duplicate
+    fn apply_intercepted(&mut self, mut tx: Transaction) -> Result<bool, TransactionError> {
+        let outcome = match self.before_apply(&mut tx) {
+            Ok(()) => self.handle_intercepted(tx),
+            Err(e) => {
+                self.stats.count(&tx, false);
+                Err(e)
+            }
+        };
+        self.after_apply(tx, outcome.map(|_| ()));
+        debug_assert_eq!(
+            self.check_hold(tx.client()),
+            Ok(()),
+            "held funds drifted from the open disputes after {:?}",
+            tx
+        );
+        outcome
+    }
+
+    fn handle_intercepted(&mut self, tx: Transaction) -> Result<bool, TransactionError> {
+        if let Some(outcome) = self.handle_batch_record(tx) {
+            return outcome.map(|()| true);
+        }
+        if self.dedup.check(&tx) {
+            self.stats.duplicates += 1;
+            return Ok(false);
+        }
+        let outcome = self.handle(tx);
+        self.stats.count(&tx, outcome.is_ok());
+        outcome.map(|()| true)
+    }
+
+    fn handle(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        self.log(&tx)?;
+        self.tick();
+        let queued = self.needs_approval(&tx);
+        self.handle_now(tx)?;
+        if !queued {
+            self.grow_history([tx.client()]);
+        }
+        Ok(())
+    }
+
+    /// Writes `tx` to the write-ahead log if there is one
+    #[cfg(feature = "std-io")]
+    pub(crate) fn log(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        if let Some(wal) = &mut self.wal {
+            // A transaction that can't be logged is not applied, it would be lost on recovery
+            if let Err(e) = wal.append(tx, self.currency) {
+                self.warnings.push(EngineWarning::LogFailed(e.to_string()));
+                return Err(TransactionError::NotLogged);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "std-io"))]
+    pub(crate) fn log(&mut self, _: &Transaction) -> Result<(), TransactionError> {
+        Ok(())
+    }
+
+    /// Advances the clock by a record, releasing what became due
+    pub(crate) fn tick(&mut self) {
+        self.clock += 1;
+        self.release_due_bookings();
+        if let Some(length) = self.schedules.day_length() {
+            if self.clock.is_multiple_of(length) {
+                self.accrue(self.accrual_day as TxId + 1);
+            }
+        }
+        if let Some(approvals) = self.policy.approvals {
+            self.pending.expire(self.clock, approvals.timeout);
+        }
+    }
+
+    pub(crate) fn needs_approval(&self, tx: &Transaction) -> bool {
+        self.policy
+            .approvals
+            .is_some_and(|approvals| approvals.requires_approval(tx))
+    }
+
+    /// Applies `tx` at the current clock, or queues it if it needs an approval
+    pub(crate) fn handle_now(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        if self.needs_approval(&tx) {
+            self.check_unused(tx.tx())?;
+            self.pending.queue(tx, self.clock);
+            return Ok(());
+        }
+        self.apply_within_limits(tx)
+    }
+
+    /// Counts a new history entry for each of `clients` against the memory budget. The history
+    /// is trimmed when it reaches the soft limit, and while it stays above it the next trim waits
+    /// until it grew by another `MemoryBudget::headroom`
+    pub(crate) fn grow_history(&mut self, clients: impl IntoIterator<Item = ClientId>) {
+        if let Some(budget) = self.policy.memory_budget {
+            // Every applied transaction is counted as a new entry, which slightly overestimates
+            // as resolves and chargebacks don't grow the history
+            for client in clients {
+                self.history_len += 1;
+                self.grown.insert(client);
+            }
+            if self.history_bytes() >= self.pressure_mark.max(budget.soft_limit()) {
+                self.relieve_memory_pressure(budget);
+            }
+        }
+    }
+
+    /// Credits the bookings whose value date the clock reached
+    fn release_due_bookings(&mut self) {
+        let due = release_due(&mut self.schedule, self.clock);
+        self.release(due);
+    }
+
+    /// Clearing sweep closing the current business day, the deposits due by the end of it become
+    /// available and a new business day starts. Returns how many deposits cleared
+    pub fn clear(&mut self) -> usize {
+        let due = release_due(&mut self.clearing, self.day);
+        self.day += 1;
+        let cleared = due.len();
+        self.release(due);
+        cleared
+    }
+
+    /// Business day deposits arriving now are counted from
+    pub fn business_day(&self) -> u64 {
+        self.day
+    }
+
+    /// Closes an accrual day, making the postings due at its end and charging the interest on the
+    /// credit used under transaction id `tx`. Postings that would overflow are skipped with a
+    /// warning
+    pub fn accrue(&mut self, tx: TxId) {
+        self.accrual_day += 1;
+        self.charge_credit_interest(tx);
+        let due: Vec<_> = self.schedules.due(self.accrual_day).collect();
+        if due.is_empty() {
+            return;
+        }
+        let minor_unit = self.currency.minor_unit();
+        for (client, info) in self.clients.iter_mut() {
+            if !info.exists() || info.is_locked() {
+                continue;
+            }
+            for posting in due.iter() {
+                let posted = match *posting {
+                    Posting::Interest(_) if info.available_funds() <= Currency::ZERO => Ok(None),
+                    Posting::Interest(rate) => match rate.of(info.available_funds(), minor_unit) {
+                        Some(interest) if interest == Currency::ZERO => Ok(None),
+                        Some(interest) => info.post_interest(interest, tx).map(|()| {
+                            Some((GlAccount::InterestExpense, GlAccount::ClientFunds, interest))
+                        }),
+                        None => Err(TransactionError::Overflow),
+                    },
+                    Posting::Fee(fee) => info
+                        .charge_fee(fee, tx)
+                        .map(|()| Some((GlAccount::ClientFunds, GlAccount::FeeIncome, fee))),
+                };
+                match (posted, &mut self.general_ledger) {
+                    (Ok(Some((debit, credit, amount))), Some(ledger)) => {
+                        ledger.post(debit, credit, amount)
+                    }
+                    (Ok(_), _) => {}
+                    (Err(_), _) => self
+                        .warnings
+                        .push(EngineWarning::PostingFailed { client, tx }),
+                }
+            }
+        }
+        if self.policy.memory_budget.is_some() {
+            self.recount_history();
+        }
+    }
+
+    /// Total booked over every client, the funds waiting for a value date or to clear
+    pub fn booked_total(&self) -> Result<Currency, AggregationOverflow> {
+        Currency::sum_of(
+            "booked total",
+            self.clients.iter().map(|(_, info)| info.booked_funds()),
+        )
+    }
+
+    fn release(&mut self, due: Vec<(ClientId, TxId)>) {
+        for (client, tx) in due {
+            if self.clients[client]
+                .release_booking(tx, &self.policy)
+                .is_err()
+            {
+                self.warnings
+                    .push(EngineWarning::SettlementFailed { client, tx });
+            }
+        }
+    }
+
+    /// Rebuilds the schedules of the bookings and uncleared deposits from the clients
+    pub(crate) fn reschedule_bookings(&mut self) {
+        self.schedule.clear();
+        self.clearing.clear();
+        for (client, info) in self.clients.iter() {
+            for (release, tx) in info.bookings() {
+                match release {
+                    Release::ValueDate(value_date) => {
+                        self.schedule.push(Reverse((value_date, client, tx)))
+                    }
+                    Release::ClearingDay(day) => self.clearing.push(Reverse((day, client, tx))),
+                }
+            }
+        }
+    }
+
+    /// Approximate memory used by the client histories
+    pub fn history_bytes(&self) -> usize {
+        self.history_len * mem::size_of::<ClientTransaction>()
+    }
+
+    fn relieve_memory_pressure(&mut self, budget: MemoryBudget) {
+        let used_bytes = self.history_bytes();
+        let keep_last = match budget.action {
+            PressureAction::Spill { keep_last } | PressureAction::Prune { keep_last } => keep_last,
+        };
+        let mut spilled = false;
+        let mut trimmed = 0;
+        let mut grown: Vec<_> = self.grown.drain().collect();
+        // Archived in client order like the other archival paths
+        grown.sort_unstable();
+        for client in grown {
+            let info = &mut self.clients[client];
+            let before = info.history_len();
+            let removed = info.trim_history(keep_last);
+            self.history_len = self.history_len.saturating_sub(before - info.history_len());
+            if removed.is_empty() {
+                continue;
+            }
+            trimmed += removed.entries.len() + removed.settled.len();
+            if let PressureAction::Spill { .. } = budget.action {
+                spilled |= self.spill_trimmed(client, &removed);
+            }
+        }
+        self.pressure_mark = self.history_bytes() + budget.headroom();
+        // A single warning covers every trim until the warnings are taken
+        let pending = self
+            .warnings
+            .iter_mut()
+            .find(|w| matches!(w, EngineWarning::MemoryPressure { .. }));
+        match pending {
+            Some(EngineWarning::MemoryPressure {
+                used_bytes: used,
+                trimmed: total,
+                spilled: any_spilled,
+                passes,
+                ..
+            }) => {
+                *used = used_bytes;
+                *total += trimmed;
+                *any_spilled |= spilled;
+                *passes += 1;
+            }
+            _ => self.warnings.push(EngineWarning::MemoryPressure {
+                used_bytes,
+                limit_bytes: budget.limit_bytes,
+                trimmed,
+                spilled,
+                passes: 1,
+            }),
+        }
+    }
+
+    /// Writes what was trimmed from the history of `client` to the spill archive, false without one
+    #[cfg(feature = "std-io")]
+    fn spill_trimmed(&mut self, client: ClientId, removed: &Trimmed) -> bool {
+        let archive = match &self.spill {
+            Some(archive) => archive,
+            None => return false,
+        };
+        let settled = removed.settled.iter().map(|&(tx, s)| (client, tx, s));
+        let written = archive
+            .append(removed.entries.iter().map(|t| (client, t)))
+            .and_then(|_| archive.append_settled(settled));
+        match written {
+            Ok(_) => true,
+            Err(e) => {
+                self.warnings
+                    .push(EngineWarning::SpillFailed(e.to_string()));
+                self.spill = None;
+                false
+            }
+        }
+    }
+
+    #[cfg(not(feature = "std-io"))]
+    fn spill_trimmed(&mut self, _: ClientId, _: &Trimmed) -> bool {
+        false
+    }
+
+    /// Rebuilds the transaction index, the history count and the schedules from the clients
+    fn index_clients(&mut self) {
+        for (client, info) in self.clients.iter() {
+            for tx in info.tx_ids() {
+                self.tx_index.insert(tx, client);
+            }
+        }
+        self.recount_history();
+        self.reschedule_bookings();
+    }
+
+    pub(crate) fn recount_history(&mut self) {
+        self.history_len = 0;
+        self.grown.clear();
+        self.pressure_mark = 0;
+        for (client, info) in self.clients.iter() {
+            let len = info.history_len();
+            self.history_len += len;
+            if len > 0 && self.policy.memory_budget.is_some() {
+                self.grown.insert(client);
+            }
+        }
+    }
+
+    /// Applies `tx` if it keeps the client within its risk limits, see `RiskLimits`
+    fn apply_within_limits(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        if self.locked_by_parent(&tx) {
+            return Err(TransactionError::AccountLocked);
+        }
+        if self.limits.is_empty() || tx.amount().is_none() {
+            return self.apply_with_fee(tx);
+        }
+        let client = tx.client();
+        let limits = self.limits.inherited(client, &self.hierarchy);
+        let withdrawal = match tx {
+            Transaction::Withdraw { amount, .. } => Some(amount),
+            Transaction::ForeignWithdraw { code, amount, .. }
+                if Some(code) == self.base_currency =>
+            {
+                Some(amount)
+            }
+            _ => None,
+        };
+        let (now, day) = (self.clock, self.day);
+        let activity = self.activity.entry(client).or_default();
+        activity.check(&limits, withdrawal, now, day)?;
+        self.apply_with_fee(tx)?;
+        self.activity
+            .entry(client)
+            .or_default()
+            .record(&limits, withdrawal, now, day);
+        Ok(())
+    }
+
+    /// Applies `tx` and charges the fee the `FeeSchedule` has for it
+    fn apply_with_fee(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        self.apply(tx)?;
+        if !self.fees.is_empty() {
+            self.charge_scheduled_fee(tx);
+        }
+        Ok(())
+    }
+
+    /// Charges the fee for `tx`, just applied, to its client and credits it to the house. Fees are
+    /// only charged in the regular accounts, a fee that would overflow is skipped with a warning
+    fn charge_scheduled_fee(&mut self, tx: Transaction) {
         use Transaction::*;
-        #[allow(clippy::unit_arg)]
-        match tx {
-            Withdraw { client, tx, amount } => self.clients[client as usize].withdraw(amount, tx),
-            Deposit { client, tx, amount } => Ok(self.clients[client as usize].deposit(amount, tx)),
-            Dispute { client, tx } => self.clients[client as usize].dispute(tx),
-            Resolve { client, tx } => self.clients[client as usize].resolve(tx),
-            Chargeback { client, tx } => self.clients[client as usize].chargeback(tx),
+        let base = |code| Some(code) == self.base_currency;
+        let (kind, client, amount) = match tx {
+            Deposit { client, amount, .. } | Booking { client, amount, .. } => {
+                (FeeKind::Deposit, client, amount)
+            }
+            ForeignDeposit {
+                client,
+                code,
+                amount,
+                ..
+            } if base(code) => (FeeKind::Deposit, client, amount),
+            Withdraw { client, amount, .. } => (FeeKind::Withdrawal, client, amount),
+            ForeignWithdraw {
+                client,
+                code,
+                amount,
+                ..
+            } if base(code) => (FeeKind::Withdrawal, client, amount),
+            Chargeback { tx, .. } if !self.tx_codes.contains_key(&tx) => {
+                // Routed to the owner of the transaction, see `route_dispute`
+                let owner = match self.tx_index.get(tx) {
+                    Some(owner) => owner,
+                    None => return,
+                };
+                match self.clients[owner].find_transfer(tx) {
+                    Some(t) => (FeeKind::Chargeback, owner, t.disputed_amount()),
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        let fee = match self.fees.fee(kind) {
+            Some(fee) => fee.on(amount, self.currency.minor_unit()),
+            None => return,
+        };
+        let tx = tx.tx();
+        let fits = |fee: Currency| {
+            self.clients[client]
+                .available_funds()
+                .checked_sub(fee)
+                .is_some()
+                && self.house.available_funds().checked_add(fee).is_some()
+        };
+        match fee {
+            Some(fee) if fee == Currency::ZERO => {}
+            Some(fee) if fits(fee) => {
+                self.clients[client]
+                    .charge_fee(fee, tx)
+                    .expect("checked to fit");
+                self.house.collect_fee(fee).expect("checked to fit");
+                self.post(GlAccount::ClientFunds, GlAccount::FeeIncome, fee);
+            }
+            _ => self.warnings.push(EngineWarning::FeeSkipped { client, tx }),
         }
     }
-}
 
-impl fmt::Debug for ClientTable {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_list()
-            .entries(self.clients.iter().filter(|c| c.exists()))
-            .finish()
+    fn apply(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        use Transaction::*;
+        let tx = self.route_dispute(tx);
+        match tx {
+            Withdraw { client, tx, amount } => {
+                self.check_unused(tx)?;
+                let policy = self.withdrawal_policy(client, None);
+                self.clients[client].withdraw_at(amount, tx, self.time, &policy)?;
+                self.tx_index.insert(tx, client);
+                self.post(GlAccount::ClientFunds, GlAccount::Cash, amount);
+                Ok(())
+            }
+            Deposit { client, tx, amount } => {
+                self.check_unused(tx)?;
+                match self.policy.clearing {
+                    ClearingDelay::Instant | ClearingDelay::BusinessDays(0) => {
+                        self.clients[client].deposit_at(amount, tx, self.time, &self.policy)?
+                    }
+                    ClearingDelay::BusinessDays(days) => {
+                        // Cleared by the sweep closing the `days`th business day from now
+                        let day = self.day.saturating_add(days - 1);
+                        let release = Release::ClearingDay(day);
+                        self.clients[client].book(amount, tx, self.time, release, &self.policy)?;
+                        self.clearing.push(Reverse((day, client, tx)));
+                    }
+                }
+                self.tx_index.insert(tx, client);
+                self.post(GlAccount::Cash, GlAccount::ClientFunds, amount);
+                Ok(())
+            }
+            ForeignDeposit {
+                client,
+                tx,
+                code,
+                amount,
+            } if Some(code) == self.base_currency => self.apply(Deposit { client, tx, amount }),
+            ForeignWithdraw {
+                client,
+                tx,
+                code,
+                amount,
+            } if Some(code) == self.base_currency => self.apply(Withdraw { client, tx, amount }),
+            ForeignDeposit {
+                client,
+                tx,
+                code,
+                amount,
+            } => {
+                self.check_unused(tx)?;
+                let info = self.foreign.entry((client, code)).or_default();
+                info.deposit_at(amount, tx, self.time, &self.policy)?;
+                self.tx_index.insert(tx, client);
+                self.tx_codes.insert(tx, code);
+                Ok(())
+            }
+            ForeignWithdraw {
+                client,
+                tx,
+                code,
+                amount,
+            } => {
+                self.check_unused(tx)?;
+                let policy = self.withdrawal_policy(client, Some(code));
+                let info = self.foreign.entry((client, code)).or_default();
+                info.withdraw_at(amount, tx, self.time, &policy)?;
+                self.tx_index.insert(tx, client);
+                self.tx_codes.insert(tx, code);
+                Ok(())
+            }
+            Convert {
+                client,
+                tx,
+                from,
+                to,
+                amount,
+            } => {
+                self.check_unused(tx)?;
+                if amount < Currency::ZERO {
+                    return Err(TransactionError::InvalidAmount);
+                }
+                let from = from
+                    .or(self.base_currency)
+                    .ok_or(TransactionError::NoRate)?;
+                let minor_unit = self.currency.in_currency(Some(to)).minor_unit();
+                let converted = self
+                    .rates
+                    .convert_to(amount, from, to, minor_unit)
+                    .ok_or(TransactionError::NoRate)?;
+                let (policy, at) = (self.policy, self.time);
+                let before = self.currency_account(client, from).clone();
+                self.currency_account(client, from)
+                    .convert_out(amount, tx, at, &policy)?;
+                // Both legs or neither, put the source account back if the target refuses the funds
+                if let Err(e) = self
+                    .currency_account(client, to)
+                    .convert_in(converted, tx, at, &policy)
+                {
+                    *self.currency_account(client, from) = before;
+                    return Err(e);
+                }
+                self.tx_index.insert(tx, client);
+                if Some(from) == self.base_currency {
+                    self.post(GlAccount::ClientFunds, GlAccount::Cash, amount);
+                }
+                if Some(to) == self.base_currency {
+                    self.post(GlAccount::Cash, GlAccount::ClientFunds, converted);
+                }
+                Ok(())
+            }
+            Booking {
+                client,
+                tx,
+                amount,
+                value_date,
+            } if self.policy.settlement == SettlementPolicy::ValueDated => {
+                self.check_unused(tx)?;
+                let info = &mut self.clients[client];
+                if value_date > self.clock {
+                    info.book(
+                        amount,
+                        tx,
+                        self.time,
+                        Release::ValueDate(value_date),
+                        &self.policy,
+                    )?;
+                    self.schedule.push(Reverse((value_date, client, tx)));
+                } else {
+                    // Already due, the value date takes the place of the clearing delay
+                    info.deposit_at(amount, tx, self.time, &self.policy)?;
+                }
+                self.tx_index.insert(tx, client);
+                self.post(GlAccount::Cash, GlAccount::ClientFunds, amount);
+                Ok(())
+            }
+            Booking {
+                client, tx, amount, ..
+            } => self.apply(Deposit { client, tx, amount }),
+            Dispute { client, tx } => {
+                self.check_owner(client, tx)?;
+                self.check_dispute_window(client, tx)?;
+                let policy = self.policy;
+                self.account_of(client, tx).dispute(tx, &policy)?;
+                self.post_dispute(tx, DisputeStep::Open);
+                Ok(())
+            }
+            Resolve { client, tx: id } => {
+                self.check_owner(client, id)?;
+                self.settle(tx, |table| table.account_of(client, id).resolve(id))?;
+                self.post_dispute(id, DisputeStep::Resolve);
+                Ok(())
+            }
+            Chargeback { client, tx: id } => {
+                self.check_owner(client, id)?;
+                self.settle(tx, |table| table.chargeback(client, id))?;
+                self.post_dispute(id, DisputeStep::Chargeback);
+                Ok(())
+            }
+            Unlock { client, tx } => {
+                self.check_owner(client, tx)?;
+                let reverse = self.unlock_reverses;
+                self.account_of(client, tx).unlock(tx, reverse)?;
+                if reverse {
+                    self.post_dispute(tx, DisputeStep::Reinstate);
+                }
+                Ok(())
+            }
+            Refund { client, tx } => {
+                self.check_owner(client, tx)?;
+                let policy = self.policy;
+                let amount = self.account_of(client, tx).refund(tx, &policy)?;
+                if !self.tx_codes.contains_key(&tx) {
+                    self.post(GlAccount::Cash, GlAccount::ClientFunds, amount);
+                }
+                Ok(())
+            }
+            LegalHold { client, tx, amount } => self.clients[client].place_legal_hold(tx, amount),
+            ReleaseHold { client, tx } => self.clients[client].release_legal_hold(tx),
+            Accrue { tx, .. } => {
+                self.accrue(tx);
+                Ok(())
+            }
+            // Handled before the records reach the engine, see `handle_batch_record`
+            Begin { .. } | Commit { .. } | Rollback { .. } => Err(TransactionError::InvalidBatch),
+            Approve {
+                client,
+                tx,
+                approver,
+            } => {
+                let required = self.policy.approvals.map_or(1, |a| a.approvers);
+                match self.pending.approve(client, tx, approver, required)? {
+                    Some(approved) => self.apply_within_limits(approved),
+                    None => Ok(()),
+                }
+            }
+        }
     }
-}
 
-impl fmt::Display for ClientTable {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "client, available, held, total, locked")?;
-        for c in 0..self.clients.len() {
-            if self.clients[c].exists() {
-                writeln!(f, "{}, {}", c, self.clients[c])?;
+    /// Runs `settle` against the dispute referenced by `transaction`, transactions that were never
+    /// disputed are handled according to the `UndisputedPolicy`
+    fn settle(
+        &mut self,
+        transaction: Transaction,
+        settle: impl FnOnce(&mut Self) -> Result<(), TransactionError>,
+    ) -> Result<(), TransactionError> {
+        let (client, tx) = (transaction.client(), transaction.tx());
+        match self.account_of(client, tx).dispute_state(tx) {
+            DisputeState::Disputed => return settle(self),
+            DisputeState::Resolved | DisputeState::ChargedBack => {
+                return Err(TransactionError::DisputeSettled)
+            }
+            DisputeState::Undisputed => {}
+        }
+        match self.policy.undisputed {
+            UndisputedPolicy::Reject => Err(TransactionError::InvalidTxId),
+            UndisputedPolicy::RecordViolation => {
+                self.warnings
+                    .push(EngineWarning::ProtocolViolation(transaction));
+                Err(TransactionError::NotDisputed)
             }
+            UndisputedPolicy::AutoOpen => {
+                // Opening the dispute and settling it is a single operation, undo the dispute if
+                // the settlement fails
+                let policy = self.policy;
+                let before = self.account_of(client, tx).clone();
+                self.account_of(client, tx).dispute(tx, &policy)?;
+                let settled = settle(self);
+                match settled {
+                    Ok(()) => self.post_dispute(tx, DisputeStep::Open),
+                    Err(_) => *self.account_of(client, tx) = before,
+                }
+                settled
+            }
+        }
+    }
+
+    /// Records a movement of the regular accounts in the general ledger, if there is one
+    fn post(&mut self, debit: GlAccount, credit: GlAccount, amount: Currency) {
+        if let Some(ledger) = &mut self.general_ledger {
+            ledger.post(debit, credit, amount);
+        }
+    }
+
+    /// Records a step of the dispute of transfer `tx`, just applied, in the general ledger. A
+    /// disputed withdrawal is provisioned as a chargeback loss until it is resolved
+    fn post_dispute(&mut self, tx: TxId, step: DisputeStep) {
+        use GlAccount::*;
+        if self.general_ledger.is_none() || self.tx_codes.contains_key(&tx) {
+            return;
+        }
+        let transfer = self
+            .tx_index
+            .get(tx)
+            .and_then(|owner| self.clients[owner].find_transfer(tx).copied());
+        let (kind, amount) = match transfer {
+            Some(t) => (t.kind(), t.disputed_amount()),
+            None => return,
+        };
+        let (debit, credit) = match (kind, step) {
+            (TransferKind::Deposit, DisputeStep::Open) => (ClientFunds, ClientHolds),
+            (TransferKind::Deposit, DisputeStep::Resolve) => (ClientHolds, ClientFunds),
+            (TransferKind::Deposit, DisputeStep::Chargeback) => (ClientHolds, Cash),
+            (TransferKind::Deposit, DisputeStep::Reinstate) => (Cash, ClientFunds),
+            (TransferKind::Withdrawal, DisputeStep::Open) => (ChargebackLosses, ClientHolds),
+            (TransferKind::Withdrawal, DisputeStep::Resolve) => (ClientHolds, ChargebackLosses),
+            (TransferKind::Withdrawal, DisputeStep::Chargeback) => (ClientHolds, ClientFunds),
+            (TransferKind::Withdrawal, DisputeStep::Reinstate) => (ClientFunds, ChargebackLosses),
+            // Never disputed
+            (
+                TransferKind::Fee
+                | TransferKind::Conversion
+                | TransferKind::Interest
+                | TransferKind::Refund,
+                _,
+            ) => return,
+        };
+        self.post(debit, credit, amount);
+    }
+
+    /// Points disputes, resolves, chargebacks, unlocks and refunds at the owner of their transaction
+    /// when the `DisputeRouting` ignores the client column. Unknown transactions are left alone and
+    /// rejected by the ownership check
+    fn route_dispute(&mut self, transaction: Transaction) -> Transaction {
+        use Transaction::*;
+        if self.policy.dispute_routing == DisputeRouting::Client {
+            return transaction;
+        }
+        let owner = match self.tx_index.get(transaction.tx()) {
+            Some(owner) if owner != transaction.client() => owner,
+            _ => return transaction,
+        };
+        let routed = match transaction {
+            Dispute { tx, .. } => Dispute { client: owner, tx },
+            Resolve { tx, .. } => Resolve { client: owner, tx },
+            Chargeback { tx, .. } => Chargeback { client: owner, tx },
+            Unlock { tx, .. } => Unlock { client: owner, tx },
+            Refund { tx, .. } => Refund { client: owner, tx },
+            _ => return transaction,
+        };
+        if self.policy.dispute_routing == DisputeRouting::TxIdWarn {
+            self.warnings
+                .push(EngineWarning::MisroutedDispute { transaction, owner });
+        }
+        routed
+    }
+
+    fn check_unused(&self, tx: TxId) -> Result<(), TransactionError> {
+        if self.tx_index.contains(tx) || self.pending.contains(tx) {
+            return Err(TransactionError::DuplicateTxId);
         }
         Ok(())
     }
+
+    /// Rejects a dispute of `tx` made longer than `Policy::dispute_window` after the transaction
+    fn check_dispute_window(&mut self, client: ClientId, tx: TxId) -> Result<(), TransactionError> {
+        let (window, now) = match (self.policy.dispute_window, self.time) {
+            (Some(window), Some(now)) => (window, now),
+            _ => return Ok(()),
+        };
+        match self.account_of(client, tx).transfer_time(tx) {
+            Some(at) if now.saturating_sub(at) > window => Err(TransactionError::DisputeExpired),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_owner(&self, client: ClientId, tx: TxId) -> Result<(), TransactionError> {
+        match self.tx_index.get(tx) {
+            Some(owner) if owner == client => Ok(()),
+            _ => Err(TransactionError::InvalidTxId),
+        }
+    }
+
+    /// The chargeback fee is assessed together with the chargeback itself, either both or neither are applied
+    fn chargeback(&mut self, client: ClientId, tx: TxId) -> Result<(), TransactionError> {
+        match self.policy.chargeback_fee {
+            None => self.account_of(client, tx).chargeback(tx),
+            Some(fee) if fee.payer == FeePayer::Client => {
+                self.account_of(client, tx)
+                    .chargeback_with_fee(tx, fee.amount)?;
+                if !self.tx_codes.contains_key(&tx) {
+                    self.post(GlAccount::ClientFunds, GlAccount::FeeIncome, fee.amount);
+                }
+                Ok(())
+            }
+            Some(fee) => {
+                // Make sure the house can absorb the fee before touching the client
+                if self
+                    .house
+                    .available_funds()
+                    .checked_sub(fee.amount)
+                    .is_none()
+                {
+                    return Err(TransactionError::Overflow);
+                }
+                self.account_of(client, tx).chargeback(tx)?;
+                self.house.charge_fee(fee.amount, tx)?;
+                self.post(GlAccount::ChargebackLosses, GlAccount::Cash, fee.amount);
+                Ok(())
+            }
+        }
+    }
+
+    /// Account of `client` in currency `code`, the regular one for the base currency
+    fn currency_account(&mut self, client: ClientId, code: CurrencyCode) -> &mut ClientInfo {
+        if Some(code) == self.base_currency {
+            return &mut self.clients[client];
+        }
+        self.foreign.entry((client, code)).or_default()
+    }
+
+    /// Account transaction `tx` of `client` was applied to, a foreign account for transactions in a
+    /// foreign currency. The client's fees and locks only ever concern that account
+    fn account_of(&mut self, client: ClientId, tx: TxId) -> &mut ClientInfo {
+        match self.tx_codes.get(&tx) {
+            Some(&code) => self.foreign.entry((client, code)).or_default(),
+            None => &mut self.clients[client],
+        }
+    }
+
+    /// Moves all but the newest `keep_last` transfers of every client into `archive`
+    /// Archived transfers can no longer be disputed until they are brought back with `recall_archived`
+    #[cfg(feature = "std-io")]
+    pub fn archive_history(&mut self, keep_last: usize, archive: &Archive) -> io::Result<usize> {
+        self.archive_with(archive, |info| info.archive_transfers(keep_last))
+    }
+
+    /// Moves the transfers that took place more than `retention` before `now` into `archive`,
+    /// transfers without a timestamp stay in the active history
+    #[cfg(feature = "std-io")]
+    pub fn archive_expired(
+        &mut self,
+        retention: RetentionPeriod,
+        now: Timestamp,
+        archive: &Archive,
+    ) -> io::Result<usize> {
+        let cutoff = retention.cutoff(now);
+        self.archive_with(archive, |info| info.archive_transfers_before(cutoff))
+    }
+
+    #[cfg(feature = "std-io")]
+    fn archive_with(
+        &mut self,
+        archive: &Archive,
+        mut take: impl FnMut(&mut ClientInfo) -> Vec<ClientTransaction>,
+    ) -> io::Result<usize> {
+        let mut archived = 0;
+        let mut result = Ok(());
+        for (client, info) in self.clients.iter_mut() {
+            let transfers = take(info);
+            if !transfers.is_empty() {
+                let settled = info.take_archived_settled();
+                let written = archive
+                    .append(transfers.iter().map(|t| (client, t)))
+                    .and_then(|written| {
+                        if !settled.is_empty() {
+                            let settled = settled.into_iter().map(|(tx, s)| (client, tx, s));
+                            archive.append_settled(settled)?;
+                        }
+                        Ok(written)
+                    });
+                match written {
+                    Ok(written) => archived += written,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+        }
+        self.recount_history();
+        result.map(|()| archived)
+    }
+
+    /// Restores an archived transfer into the active history, returns false if the archive doesn't have it
+    #[cfg(feature = "std-io")]
+    pub fn recall_archived(
+        &mut self,
+        archive: &Archive,
+        client: ClientId,
+        tx: TxId,
+    ) -> io::Result<bool> {
+        match archive.find(client, tx)? {
+            Some(archived) => {
+                let info = &mut self.clients[client];
+                for entry in archived.entries {
+                    info.recall(entry);
+                }
+                if let Some(state) = archived.settled {
+                    info.recall_settled(tx, state);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Index of the applied transaction ids, see `TxIndex`
+    pub fn tx_index(&self) -> &TxIndex {
+        &self.tx_index
+    }
+
+    /// Transaction ids applied so far together with the client owning them
+    pub fn indexed_txs(&self) -> impl Iterator<Item = (TxId, ClientId)> + '_ {
+        self.tx_index.iter()
+    }
+
+    /// Empty table with the same policy, storage and currency configuration as this one
+    pub fn empty_like(&self) -> ClientTable {
+        let mut table = Self::with_storage(self.clients.empty_like(), self.policy);
+        table.adaptive_storage = self.adaptive_storage;
+        table.tx_index = TxIndex::new(self.tx_index.strategy());
+        table.currency = self.currency;
+        table.base_currency = self.base_currency;
+        table.rates = self.rates.clone();
+        table.schedules = self.schedules.clone();
+        table.limits = self.limits.clone();
+        table.overdraw = self.overdraw.clone();
+        table.credit_lines = self.credit_lines.clone();
+        table.fees = self.fees.clone();
+        table.general_ledger = self.general_ledger.as_ref().map(|_| GeneralLedger::new());
+        table.hierarchy = self.hierarchy.clone();
+        table.extended_report = self.extended_report;
+        table.unlock_reverses = self.unlock_reverses;
+        table.deterministic = self.deterministic;
+        table
+    }
+
+    /// Takes over the state of `other`, a table made with `empty_like` and rebuilt from elsewhere,
+    /// while keeping the log, the spill archive and the warnings of this one
+    #[cfg(feature = "std-io")]
+    pub(crate) fn replace_state(&mut self, other: ClientTable) {
+        let wal = self.wal.take();
+        let spill = self.spill.take();
+        let warnings = mem::take(&mut self.warnings);
+        *self = other;
+        self.wal = wal;
+        self.spill = spill;
+        self.warnings.splice(0..0, warnings);
+    }
+
+    /// Splits the table into `n` independent tables, client `c` ends up in table `c % n`
+    /// together with its indexed transactions and pending approvals. The house account and the spill
+    /// archive stay behind, so shards under memory pressure prune instead of spilling
+    pub fn shard(&mut self, n: usize) -> Vec<ClientTable> {
+        let mut shards: Vec<_> = (0..n).map(|_| self.empty_like()).collect();
+        for (client, info) in self.clients.iter_mut() {
+            if info.exists() {
+                shards[client as usize % n].clients[client] = mem::take(info);
+            }
+        }
+        for ((client, code), info) in mem::take(&mut self.foreign) {
+            shards[client as usize % n]
+                .foreign
+                .insert((client, code), info);
+        }
+        let empty = TxIndex::new(self.tx_index.strategy());
+        let tx_index = mem::replace(&mut self.tx_index, empty);
+        for (tx, client) in tx_index.iter() {
+            let shard = &mut shards[client as usize % n];
+            shard.tx_index.insert(tx, client);
+            if let Some(code) = self.tx_codes.remove(&tx) {
+                shard.tx_codes.insert(tx, code);
+            }
+        }
+        for (client, activity) in self.activity.drain() {
+            shards[client as usize % n]
+                .activity
+                .insert(client, activity);
+        }
+        for (i, shard) in shards.iter_mut().enumerate() {
+            shard.pending = self.pending.split_off(|t| t.client() as usize % n == i);
+            shard.dedup = self.dedup.split_off(|client| client as usize % n == i);
+            shard.clock = self.clock;
+            shard.day = self.day;
+            shard.accrual_day = self.accrual_day;
+            shard.recount_history();
+            shard.reschedule_bookings();
+        }
+        self.history_len = 0;
+        self.schedule.clear();
+        self.clearing.clear();
+        shards
+    }
+
+    /// Moves every client of `other` into this table, the two tables must not share any clients or
+    /// transaction ids. On error this table is left untouched
+    pub fn merge(&mut self, mut other: ClientTable) -> Result<(), MergeError> {
+        for (client, info) in other.clients.iter() {
+            if info.exists() && self.clients[client].exists() {
+                return Err(MergeError::ClientConflict(client));
+            }
+        }
+        if let Some(&(client, _)) = other
+            .foreign
+            .keys()
+            .find(|account| self.foreign.contains_key(account))
+        {
+            return Err(MergeError::ClientConflict(client));
+        }
+        if let Some((tx, _)) = other
+            .tx_index
+            .iter()
+            .find(|&(tx, _)| self.tx_index.contains(tx))
+        {
+            return Err(MergeError::DuplicateTxId(tx));
+        }
+        self.house
+            .absorb(mem::take(&mut other.house))
+            .map_err(|_| MergeError::Overflow)?;
+        if let (Some(ledger), Some(other)) = (&mut self.general_ledger, &other.general_ledger) {
+            ledger.absorb(other);
+        }
+        for (client, info) in other.clients.iter_mut() {
+            if info.exists() {
+                self.clients[client] = mem::take(info);
+            }
+        }
+        self.foreign.append(&mut other.foreign);
+        self.tx_index.extend(other.tx_index.iter());
+        self.tx_codes.extend(other.tx_codes);
+        self.pending.merge(other.pending);
+        self.dedup.merge(other.dedup);
+        self.activity.extend(other.activity);
+        self.schedule.extend(other.schedule);
+        self.clearing.extend(other.clearing);
+        self.day = self.day.max(other.day);
+        self.clock = self.clock.max(other.clock);
+        self.accrual_day = self.accrual_day.max(other.accrual_day);
+        self.history_len += other.history_len;
+        self.grown.extend(other.grown);
+        self.warnings.extend(other.warnings);
+        self.stats += other.stats;
+        Ok(())
+    }
+
+    /// Writes the final account state as csv, the same format as the `Display` implementation
+    /// Fails with an `AggregationOverflow` before writing anything if a total of the report, the
+    /// pending amount or the legal holds of a client, doesn't fit
+    pub fn write_csv<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_csv_filtered(w, &|_, _| true)
+    }
+
+    /// Same as `write_csv` restricted to the clients matching `query`
+    pub fn write_csv_matching<W: Write>(&self, w: W, query: &Query) -> io::Result<()> {
+        self.write_csv_filtered(w, &|client, info| query.matches(client, info))
+    }
+
+    fn write_csv_filtered<W: Write>(
+        &self,
+        mut w: W,
+        keep: &dyn Fn(ClientId, &ClientInfo) -> bool,
+    ) -> io::Result<()> {
+        let rows = self.report_rows()?;
+        let mut report = String::new();
+        self.fmt_report(&mut report, &rows, keep)
+            .expect("formatting into a String can't fail");
+        w.write_all(report.as_bytes())?;
+        w.flush()
+    }
+
+    /// State of a single client, `None` if the client hasn't been seen
+    pub fn client(&self, client: ClientId) -> Option<&ClientInfo> {
+        Some(&self.clients[client]).filter(|info| info.exists())
+    }
+
+    /// Account of `client` in the foreign currency `code`, `None` if it hasn't been used
+    pub fn foreign_account(&self, client: ClientId, code: CurrencyCode) -> Option<&ClientInfo> {
+        self.foreign
+            .get(&(client, code))
+            .filter(|info| info.exists())
+    }
+
+    /// Checks that the held funds of every account of `client` match its open disputes, see
+    /// `ClientInfo::check_hold`. Debug builds run it after every transaction
+    pub fn check_hold(&self, client: ClientId) -> Result<(), TransactionError> {
+        self.client(client).map_or(Ok(()), ClientInfo::check_hold)?;
+        self.foreign
+            .iter()
+            .filter(|((owner, _), _)| *owner == client)
+            .try_for_each(|(_, info)| info.check_hold())
+    }
+
+    /// Clients that have been seen so far, in client id order
+    pub fn clients(&self) -> impl Iterator<Item = (ClientId, &ClientInfo)> + '_ {
+        self.clients.iter().filter(|(_, info)| info.exists())
+    }
+
+    /// Writes the final account state as a JSON array with one object per client, or per client and
+    /// currency once there are foreign accounts
+    /// Amounts are written as strings so consumers don't lose precision by parsing them as floats
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let rows = self.report_rows()?;
+        write!(w, "[")?;
+        for (i, row) in rows.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            writeln!(w, "{}", separator)?;
+            self.write_json_object(&mut w, row)?;
+        }
+        writeln!(w, "\n]")?;
+        w.flush()
+    }
+
+    /// Writes the JSON object `write_json` uses for a single client, returns `false` without
+    /// writing anything if the client is not part of the report. Clients with accounts in several
+    /// currencies are represented by their first row
+    pub fn write_client_json<W: Write>(&self, mut w: W, client: ClientId) -> io::Result<bool> {
+        match self.report_rows()?.iter().find(|row| row.client == client) {
+            Some(row) => {
+                self.write_json_object(&mut w, row)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn write_json_object<W: Write>(&self, w: &mut W, row: &ReportRow<'_>) -> io::Result<()> {
+        let currency = self.currency.in_currency(row.code);
+        let info = row.info;
+        write!(w, "{{\"client\":{}", row.client)?;
+        if self.multi_currency() {
+            match row.code {
+                Some(code) => write!(w, ",\"currency\":\"{}\"", code)?,
+                None => write!(w, ",\"currency\":null")?,
+            }
+        }
+        write!(
+            w,
+            ",\"available\":\"{}\",\"held\":\"{}\",\"total\":\"{}\",\"locked\":{}",
+            currency.display(info.available_funds()),
+            currency.display(info.held_funds()),
+            currency.display(row.total),
+            info.is_locked()
+        )?;
+        if let Some(pending) = row.pending {
+            write!(w, ",\"pending\":\"{}\"", currency.display(pending))?;
+        }
+        if self.policy.defers_funds() {
+            write!(
+                w,
+                ",\"booked\":\"{}\"",
+                currency.display(info.booked_funds())
+            )?;
+        }
+        if self.extended_report {
+            write!(
+                w,
+                ",\"legal_hold\":\"{}\"",
+                currency.display(row.legal_hold)
+            )?;
+        }
+        if !self.credit_lines.is_empty() {
+            let (limit, used) = row.credit_usage();
+            write!(
+                w,
+                ",\"credit_limit\":\"{}\",\"credit_used\":\"{}\"",
+                currency.display(limit),
+                currency.display(used)
+            )?;
+        }
+        write!(w, "}}")
+    }
+
+    /// Whether the reports have a currency column, which is the case once a base currency is set or
+    /// a foreign account has been opened
+    pub(crate) fn multi_currency(&self) -> bool {
+        self.base_currency.is_some() || !self.foreign.is_empty()
+    }
+
+    /// Accounts to include in the report in client order, the regular account of a client comes
+    /// before its foreign ones. The pending amount is only present when approvals are enabled, and
+    /// is always zero for foreign accounts as only regular transactions wait for approval
+    ///
+    /// The totals of the rows are computed here with checked sums, so an overflow is reported
+    /// before any part of the report is written
+    pub(crate) fn report_rows(&self) -> Result<Vec<ReportRow<'_>>, AggregationOverflow> {
+        let approvals = self.policy.approvals.is_some();
+        let mut rows = Vec::new();
+        for (client, info) in self.clients.iter() {
+            let pending = if approvals {
+                Some(self.pending.amount_for(client)?)
+            } else {
+                None
+            };
+            if info.exists() || pending.is_some_and(|p| p != Currency::default()) {
+                rows.push(ReportRow {
+                    client,
+                    code: self.base_currency,
+                    info,
+                    pending,
+                    legal_hold: info.legal_held()?,
+                    total: info.total_funds()?,
+                    credit: self.credit_lines.get(client),
+                });
+            }
+        }
+        if !self.foreign.is_empty() {
+            for (&(client, code), info) in self.foreign.iter().filter(|(_, info)| info.exists()) {
+                rows.push(ReportRow {
+                    client,
+                    code: Some(code),
+                    info,
+                    pending: if approvals {
+                        Some(Currency::ZERO)
+                    } else {
+                        None
+                    },
+                    legal_hold: info.legal_held()?,
+                    total: info.total_funds()?,
+                    credit: None,
+                });
+            }
+            // Stable, so the regular accounts stay ahead of the foreign ones which are in code order
+            rows.sort_by_key(|row| row.client);
+        }
+        if !self.fees.is_empty() {
+            self.house_row()?;
+        }
+        Ok(rows)
+    }
+
+    /// Row of the house account listing the fees collected from the clients, see `FeeSchedule`
+    fn house_row(&self) -> Result<ReportRow<'_>, AggregationOverflow> {
+        Ok(ReportRow {
+            client: 0,
+            code: self.base_currency,
+            info: &self.house,
+            pending: self.policy.approvals.map(|_| Currency::ZERO),
+            legal_hold: Currency::ZERO,
+            total: self.house.total_funds()?,
+            credit: None,
+        })
+    }
+
+    /// Applies every transaction from `txs` in order and returns how many were applied and rejected
+    pub fn process<I: IntoIterator>(&mut self, txs: I) -> Summary
+    where
+        I::Item: Into<Stamped>,
+    {
+        self.stream(txs).summary()
+    }
+
+    /// Lazily applies the transactions from `txs`, yielding an event with the outcome of each one as it is handled
+    /// The transactions may be `Stamped` with the time they took place, see `handle_stamped`
+    pub fn stream<I: IntoIterator>(&mut self, txs: I) -> TransactionStream<'_, I::IntoIter>
+    where
+        I::Item: Into<Stamped>,
+    {
+        TransactionStream {
+            table: self,
+            txs: txs.into_iter(),
+            summary: Summary::default(),
+            cancel: None,
+        }
+    }
+}
+
+/// Account listed in the reports
+pub(crate) struct ReportRow<'a> {
+    pub(crate) client: ClientId,
+    pub(crate) code: Option<CurrencyCode>,
+    pub(crate) info: &'a ClientInfo,
+    pub(crate) pending: Option<Currency>,
+    pub(crate) legal_hold: Currency,
+    /// Available, held and booked funds of the account
+    pub(crate) total: Currency,
+    /// Credit line of a regular account
+    pub(crate) credit: Option<CreditLine>,
+}
+
+impl ReportRow<'_> {
+    /// Credit limit of the account and how much of it is used, zero without a credit line
+    fn credit_usage(&self) -> (Currency, Currency) {
+        match self.credit {
+            Some(credit) => (credit.limit, credit.used(self.info.available_funds())),
+            None => (Currency::ZERO, Currency::ZERO),
+        }
+    }
+}
+
+/// Step of a dispute recorded in the general ledger, see `ClientTable::post_dispute`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DisputeStep {
+    Open,
+    Resolve,
+    Chargeback,
+    /// An unlock reversing the chargeback
+    Reinstate,
+}
+
+/// Pops the entries of `schedule` due at `now`
+fn release_due(
+    schedule: &mut BinaryHeap<Reverse<(u64, ClientId, TxId)>>,
+    now: u64,
+) -> Vec<(ClientId, TxId)> {
+    let mut due = Vec::new();
+    while let Some(&Reverse((at, client, tx))) = schedule.peek() {
+        if at > now {
+            break;
+        }
+        schedule.pop();
+        due.push((client, tx));
+    }
+    due
+}
+
+impl Default for ClientTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts of how many transactions were applied and rejected by the engine
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub applied: usize,
+    pub rejected: usize,
+}
+
+impl AddAssign for Summary {
+    fn add_assign(&mut self, other: Self) {
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+    }
+}
+
+/// Throughput counters kept by a table over its whole life, unlike `Summary` which covers a
+/// single batch. Counters are not part of snapshots
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Transactions handled, applied or not
+    pub processed: u64,
+    pub rejected: u64,
+    /// Transactions handled per record type, named as in the input files
+    pub per_type: BTreeMap<&'static str, u64>,
+    /// Bytes of input the transactions were parsed from, see `ClientTable::count_bytes`
+    pub bytes_read: u64,
+    /// Records skipped as duplicates, see `DedupPolicy`, not counted as processed
+    pub duplicates: u64,
+    /// Records skipped because they couldn't be parsed, see `ClientTable::count_malformed`
+    pub malformed: u64,
+}
+
+impl Stats {
+    pub(crate) fn count(&mut self, tx: &Transaction, applied: bool) {
+        self.processed += 1;
+        if !applied {
+            self.rejected += 1;
+        }
+        *self.per_type.entry(tx.kind_name()).or_default() += 1;
+    }
+}
+
+impl AddAssign for Stats {
+    fn add_assign(&mut self, other: Self) {
+        self.processed += other.processed;
+        self.rejected += other.rejected;
+        for (kind, count) in other.per_type {
+            *self.per_type.entry(kind).or_default() += count;
+        }
+        self.bytes_read += other.bytes_read;
+        self.duplicates += other.duplicates;
+        self.malformed += other.malformed;
+    }
+}
+
+/// One line summary such as `5 transactions, 1 rejected, 120 bytes read (3 deposit, 2 withdrawal)`
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} transactions, {} rejected, {} bytes read",
+            self.processed, self.rejected, self.bytes_read
+        )?;
+        for (i, (kind, count)) in self.per_type.iter().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{}{} {}", separator, count, kind)?;
+        }
+        if !self.per_type.is_empty() {
+            write!(f, ")")?;
+        }
+        if self.duplicates > 0 {
+            write!(f, ", {} duplicates skipped", self.duplicates)?;
+        }
+        if self.malformed > 0 {
+            write!(f, ", {} malformed records skipped", self.malformed)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// Both tables hold state for the client
+    ClientConflict(ClientId),
+    /// Both tables applied a transaction with the same id
+    DuplicateTxId(TxId),
+    /// Combining the house accounts overflowed
+    Overflow,
+}
+
+/// Adapter feeding an iterator of transactions into a `ClientTable`
+/// Each call to `next` applies a single transaction, so callers can inspect the individual events
+/// or simply drain it with `summary`
+pub struct TransactionStream<'a, I> {
+    table: &'a mut ClientTable,
+    txs: I,
+    summary: Summary,
+    cancel: Option<CancellationToken>,
+}
+
+impl<'a, I: Iterator> TransactionStream<'a, I>
+where
+    I::Item: Into<Stamped>,
+{
+    /// Ends the stream before the next transaction once `token` is cancelled, the transaction being
+    /// applied when the stop is requested is always completed
+    pub fn until_cancelled(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Table the transactions are applied to, as left by the last transaction handled
+    pub fn table(&self) -> &ClientTable {
+        self.table
+    }
+
+    /// Applies the remaining transactions and returns the counts for the whole stream
+    pub fn summary(mut self) -> Summary {
+        while self.next().is_some() {}
+        self.summary
+    }
+}
+
+impl<'a, I: Iterator> Iterator for TransactionStream<'a, I>
+where
+    I::Item: Into<Stamped>,
+{
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+            return None;
+        }
+        let stamped = self.txs.next()?.into();
+        let transaction = stamped.transaction;
+        let outcome = self.table.handle_stamped(stamped);
+        match outcome {
+            Ok(()) => self.summary.applied += 1,
+            Err(_) => self.summary.rejected += 1,
+        }
+        Some(Event {
+            transaction,
+            outcome,
+            currency: self.table.currency,
+        })
+    }
+}
+
+impl fmt::Debug for ClientTable {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_list()
+            .entries(self.clients.iter().map(|(_, c)| c).filter(|c| c.exists()))
+            .finish()
+    }
+}
+
+/// When approvals are enabled the report gains a pending column with the amount awaiting approval,
+/// and with foreign accounts a currency column after the client one
+impl fmt::Display for ClientTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.report_rows().map_err(|_| fmt::Error)?;
+        self.fmt_report(f, &rows, &|_, _| true)
+    }
+}
+
+impl ClientTable {
+    pub(crate) fn fmt_report(
+        &self,
+        f: &mut dyn fmt::Write,
+        rows: &[ReportRow<'_>],
+        keep: &dyn Fn(ClientId, &ClientInfo) -> bool,
+    ) -> fmt::Result {
+        let multi_currency = self.multi_currency();
+        write!(f, "client")?;
+        if multi_currency {
+            write!(f, ", currency")?;
+        }
+        write!(f, ", available, held, total, locked")?;
+        if self.policy.approvals.is_some() {
+            write!(f, ", pending")?;
+        }
+        let deferred = self.policy.defers_funds();
+        if deferred {
+            write!(f, ", booked")?;
+        }
+        if self.extended_report {
+            write!(f, ", legal_hold")?;
+        }
+        if !self.credit_lines.is_empty() {
+            write!(f, ", credit_limit, credit_used")?;
+        }
+        writeln!(f)?;
+        for row in rows {
+            if keep(row.client, row.info) {
+                match self.client_labels.get(&row.client) {
+                    Some(label) => self.fmt_report_row(f, &quote_if_needed(label), row)?,
+                    None => self.fmt_report_row(f, &row.client, row)?,
+                }
+            }
+        }
+        // `report_rows` checked the total of the house account already
+        if !self.fees.is_empty() {
+            let house = self.house_row().map_err(|_| fmt::Error)?;
+            self.fmt_report_row(f, &"house", &house)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `row` of the report, with `label` in the client column
+    fn fmt_report_row(
+        &self,
+        f: &mut dyn fmt::Write,
+        label: &dyn fmt::Display,
+        row: &ReportRow<'_>,
+    ) -> fmt::Result {
+        let info = row.info;
+        let currency = self.currency.in_currency(row.code);
+        write!(f, "{}", label)?;
+        if self.multi_currency() {
+            match row.code {
+                Some(code) => write!(f, ", {}", code)?,
+                None => write!(f, ", ")?,
+            }
+        }
+        write!(
+            f,
+            ", {}, {}, {}, {}",
+            currency.display(info.available_funds()),
+            currency.display(info.held_funds()),
+            currency.display(row.total),
+            info.is_locked()
+        )?;
+        if let Some(pending) = row.pending {
+            write!(f, ", {}", currency.display(pending))?;
+        }
+        if self.policy.defers_funds() {
+            write!(f, ", {}", currency.display(info.booked_funds()))?;
+        }
+        if self.extended_report {
+            write!(f, ", {}", currency.display(row.legal_hold))?;
+        }
+        if !self.credit_lines.is_empty() {
+            let (limit, used) = row.credit_usage();
+            write!(
+                f,
+                ", {}, {}",
+                currency.display(limit),
+                currency.display(used)
+            )?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std-io")]
+    use crate::version::CompatCheck;
+    use crate::{
+        client_info::TransferKind,
+        fees::Fee,
+        policy::{ApprovalPolicy, ChargebackFee, DedupPolicy, DisputePolicy, LockedAccountPolicy},
+        risk::{Limits, Velocity},
+        testkit,
+        tx_index::IndexStrategy,
+    };
+    use proptest::prelude::*;
+
+    #[test]
+    fn stats_count_every_handled_transaction() {
+        let mut table = ClientTable::new();
+        table.process(deposits(3));
+        table.process(vec![
+            Transaction::Withdraw {
+                client: 1,
+                tx: 10,
+                amount: Currency::new(1),
+            },
+            Transaction::Dispute { client: 9, tx: 1 },
+        ]);
+        table.count_bytes(60);
+        let mut other = ClientTable::new();
+        other.process(vec![Transaction::Deposit {
+            client: 7,
+            tx: 20,
+            amount: Currency::new(10000),
+        }]);
+        table.merge(other).unwrap();
+        let stats = table.stats();
+        assert_eq!((stats.processed, stats.rejected), (6, 1));
+        assert_eq!(stats.per_type["deposit"], 4);
+        assert_eq!(
+            stats.to_string(),
+            "6 transactions, 1 rejected, 60 bytes read (4 deposit, 1 dispute, 1 withdrawal)"
+        );
+    }
+
+    #[test]
+    fn replayed_duplicates_are_skipped() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(10000),
+        };
+        let dispute = Transaction::Dispute { client: 1, tx: 1 };
+        let resolve = Transaction::Resolve { client: 1, tx: 1 };
+        let log = vec![deposit, dispute, resolve, deposit, dispute, resolve];
+        let mut table = ClientTable::new();
+        let summary = table.process(log.clone());
+        // Without deduplication the replayed records end up among the rejected ones
+        assert_eq!((summary.applied, summary.rejected), (3, 3));
+
+        for dedup in [DedupPolicy::Full, DedupPolicy::Window(3)] {
+            let mut table = ClientTable::with_policy(Policy {
+                dedup,
+                ..Policy::default()
+            });
+            let summary = table.process(log.clone());
+            assert_eq!((summary.applied, summary.rejected), (6, 0));
+            assert_eq!(table.clients[1].held_funds(), Currency::ZERO);
+            assert_eq!(table.clients[1].available_funds(), Currency::new(10000));
+            let stats = table.stats();
+            assert_eq!((stats.processed, stats.duplicates), (3, 3));
+            assert!(stats.to_string().ends_with(", 3 duplicates skipped"));
+        }
+    }
+
+    proptest! {
+        // Every case builds a full table for each policy
+        #![proptest_config(ProptestConfig::with_cases(40))]
+        #[test]
+        fn generated_feeds_keep_the_invariants(feed in testkit::feed(5, 1_000_000, 0..300)) {
+            let fee = ChargebackFee {
+                amount: Currency::new(500),
+                payer: FeePayer::Client,
+            };
+            let policies = [
+                Policy::default(),
+                Policy {
+                    disputes: DisputePolicy::DepositsAndWithdrawals,
+                    undisputed: UndisputedPolicy::AutoOpen,
+                    ..Policy::default()
+                },
+                Policy {
+                    chargeback_fee: Some(fee),
+                    locked_accounts: LockedAccountPolicy::AcceptDeposits,
+                    undisputed: UndisputedPolicy::RecordViolation,
+                    history: Some(HistoryLookup::Indexed),
+                    ..Policy::default()
+                },
+            ];
+            for policy in policies.iter() {
+                let mut table = ClientTable::with_policy(*policy);
+                if let Err(violation) = testkit::run_checked(&mut table, feed.iter().copied()) {
+                    prop_assert!(false, "with {:?}: {}", policy, violation);
+                }
+            }
+        }
+    }
+
+    fn charged_back(policy: Policy) -> ClientTable {
+        let mut table = ClientTable::with_policy(policy);
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+        ]);
+        table
+    }
+
+    #[test]
+    fn process_counts_applied_and_rejected() {
+        let mut table = ClientTable::new();
+        let txs = vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(50000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 3 },
+        ];
+        let summary = table.process(txs);
+        assert_eq!(
+            summary,
+            Summary {
+                applied: 2,
+                rejected: 2
+            }
+        );
+    }
+
+    #[test]
+    fn stream_yields_each_outcome() {
+        let mut table = ClientTable::new();
+        let txs = vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Chargeback { client: 1, tx: 1 },
+        ];
+        let mut stream = table.stream(txs);
+        assert!(stream.next().unwrap().outcome.is_ok());
+        assert_eq!(
+            stream.next().unwrap(),
+            Event {
+                transaction: Transaction::Chargeback { client: 1, tx: 1 },
+                outcome: Err(TransactionError::InvalidTxId),
+                currency: CurrencyConfig::default(),
+            }
+        );
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn chargeback_without_fee() {
+        let table = charged_back(Policy::default());
+        assert_eq!(table.clients[1].to_string(), "0.0000, 0.0000, 0.0000, true");
+        assert_eq!(table.house.to_string(), "0.0000, 0.0000, 0.0000, false");
+    }
+
+    #[test]
+    fn chargeback_fee_charged_to_client() {
+        let table = charged_back(Policy {
+            chargeback_fee: Some(ChargebackFee {
+                amount: Currency::new(2500),
+                payer: FeePayer::Client,
+            }),
+            ..Policy::default()
+        });
+        assert_eq!(
+            table.clients[1].to_string(),
+            "-0.2500, 0.0000, -0.2500, true"
+        );
+        assert_eq!(table.house.to_string(), "0.0000, 0.0000, 0.0000, false");
+    }
+
+    #[test]
+    fn chargeback_fee_charged_to_house() {
+        let table = charged_back(Policy {
+            chargeback_fee: Some(ChargebackFee {
+                amount: Currency::new(2500),
+                payer: FeePayer::House,
+            }),
+            ..Policy::default()
+        });
+        assert_eq!(table.clients[1].to_string(), "0.0000, 0.0000, 0.0000, true");
+        assert_eq!(table.house.to_string(), "-0.2500, 0.0000, -0.2500, false");
+    }
+
+    #[test]
+    fn locked_account_deposit_follows_policy() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 2,
+            amount: Currency::new(10000),
+        };
+        let mut table = charged_back(Policy::default());
+        assert!(matches!(
+            table.handle_transaction(deposit),
+            Err(TransactionError::AccountLocked)
+        ));
+        let mut table = charged_back(Policy {
+            locked_accounts: LockedAccountPolicy::AcceptDeposits,
+            ..Policy::default()
+        });
+        assert!(table.handle_transaction(deposit).is_ok());
+        assert_eq!(table.clients[1].to_string(), "1.0000, 0.0000, 1.0000, true");
+    }
+
+    #[test]
+    fn unlock_follows_the_table_flag() {
+        let unlock = Transaction::Unlock { client: 1, tx: 1 };
+        let mut table = charged_back(Policy::default());
+        assert_eq!(
+            table.handle_transaction(Transaction::Unlock { client: 2, tx: 1 }),
+            Err(TransactionError::InvalidTxId)
+        );
+        table.handle_transaction(unlock).unwrap();
+        assert_eq!(
+            table.clients[1].to_string(),
+            "0.0000, 0.0000, 0.0000, false"
+        );
+        assert_eq!(
+            table.handle_transaction(unlock),
+            Err(TransactionError::NotLocked)
+        );
+
+        let mut table = charged_back(Policy::default());
+        table.set_unlock_reverses(true);
+        table.handle_transaction(unlock).unwrap();
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+    }
+
+    #[test]
+    fn refunds_credit_the_owner_of_the_withdrawal() {
+        let mut table = ClientTable::new();
+        table.enable_general_ledger().unwrap();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(5000),
+            },
+        ]);
+        let refund = crate::csv_parser::parse_line(Ok("refund, 1, 2".to_string())).unwrap();
+        assert_eq!(refund, Transaction::Refund { client: 1, tx: 2 });
+        assert_eq!(
+            table.handle_transaction(Transaction::Refund { client: 2, tx: 2 }),
+            Err(TransactionError::InvalidTxId)
+        );
+        table.handle_transaction(refund).unwrap();
+        assert_eq!(
+            table.handle_transaction(refund),
+            Err(TransactionError::AlreadyRefunded)
+        );
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+        let ledger = table.general_ledger().unwrap();
+        assert_eq!(
+            ledger.balance(GlAccount::ClientFunds),
+            Ok(Currency::new(-20000))
+        );
+    }
+
+    #[test]
+    fn duplicate_tx_ids_are_rejected() {
+        let mut table = ClientTable::new();
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(10000),
+        };
+        table.handle_transaction(deposit).unwrap();
+        assert!(matches!(
+            table.handle_transaction(deposit),
+            Err(TransactionError::DuplicateTxId)
+        ));
+        assert!(matches!(
+            table.handle_transaction(Transaction::Withdraw {
+                client: 2,
+                tx: 1,
+                amount: Currency::new(10000),
+            }),
+            Err(TransactionError::DuplicateTxId)
+        ));
+        assert_eq!(
+            table.clients[1].to_string(),
+            "1.0000, 0.0000, 1.0000, false"
+        );
+    }
+
+    #[test]
+    fn dispute_for_other_clients_tx_is_rejected() {
+        let mut table = ClientTable::new();
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(10000),
+            })
+            .unwrap();
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(10000),
+            })
+            .unwrap();
+        assert!(matches!(
+            table.handle_transaction(Transaction::Dispute { client: 2, tx: 1 }),
+            Err(TransactionError::InvalidTxId)
+        ));
+        assert!(table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .is_ok());
+    }
+
+    fn approval_table() -> ClientTable {
+        ClientTable::with_policy(Policy {
+            approvals: Some(ApprovalPolicy {
+                threshold: Currency::new(50000),
+                timeout: 2,
+                approvers: 2,
+            }),
+            ..Policy::default()
+        })
+    }
+
+    #[test]
+    fn large_deposit_waits_for_approval() {
+        let mut table = approval_table();
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100000),
+            })
+            .unwrap();
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked, pending\n1, 0.0000, 0.0000, 0.0000, false, 10.0000\n"
+        );
+        let approve = |approver| Transaction::Approve {
+            client: 1,
+            tx: 1,
+            approver,
+        };
+        // One approver short of the policy
+        table.handle_transaction(approve(7)).unwrap();
+        assert_eq!(table.clients[1].available_funds(), Currency::ZERO);
+        table.handle_transaction(approve(8)).unwrap();
+        assert!(table.pending().is_empty());
+        assert_eq!(
+            table.clients[1].to_string(),
+            "10.0000, 0.0000, 10.0000, false"
+        );
+    }
+
+    #[test]
+    fn report_fails_when_a_total_overflows() {
+        let mut table = approval_table();
+        for tx in 1..=2 {
+            table
+                .handle_transaction(Transaction::Deposit {
+                    client: 1,
+                    tx,
+                    amount: Currency::MAX,
+                })
+                .unwrap();
+        }
+        let mut out = Vec::new();
+        let error = table.write_csv(&mut out).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "pending amount overflows the supported amount range"
+        );
+        assert!(out.is_empty());
+        assert!(table.write_json(&mut out).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-currency"))]
+    fn report_fails_when_the_total_funds_overflow() {
+        // The disputed withdrawal is held while the available funds stay, so the total is beyond
+        // the range of 64 bit amounts although each balance fits
+        let input = "deposit, 1, 1, 600000000000000
+withdrawal, 1, 2, 500000000000000
+deposit, 1, 3, 600000000000000
+dispute, 1, 2,
+";
+        let mut table = ClientTable::new();
+        let summary = table.process(
+            input
+                .lines()
+                .map(|l| crate::csv_parser::parse_line(Ok(l.to_string())).unwrap()),
+        );
+        assert_eq!(summary.rejected, 0);
+        assert!(table.clients[1].total_funds().is_err());
+        let mut out = Vec::new();
+        let error = table.write_csv(&mut out).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "total funds overflows the supported amount range"
+        );
+        assert!(table.write_json(&mut out).is_err());
+        assert!(table
+            .write_attested_csv(&mut out, b"key", "2024-01")
+            .is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn pending_approval_expires() {
+        let mut table = approval_table();
+        let small = |tx| Transaction::Deposit {
+            client: 2,
+            tx,
+            amount: Currency::new(10000),
+        };
+        table
+            .handle_transaction(Transaction::Withdraw {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100000),
+            })
+            .unwrap();
+        table.handle_transaction(small(2)).unwrap();
+        table.handle_transaction(small(3)).unwrap();
+        table.handle_transaction(small(4)).unwrap();
+        assert!(matches!(
+            table.handle_transaction(Transaction::Approve {
+                client: 1,
+                tx: 1,
+                approver: 7,
+            }),
+            Err(TransactionError::InvalidTxId)
+        ));
+        assert!(!table.clients[1].exists());
+    }
+
+    #[test]
+    fn pending_tx_id_cannot_be_reused() {
+        let mut table = approval_table();
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100000),
+            })
+            .unwrap();
+        assert!(matches!(
+            table.handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100),
+            }),
+            Err(TransactionError::DuplicateTxId)
+        ));
+    }
+
+    #[test]
+    fn write_csv_and_json() {
+        let mut table = ClientTable::new();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(15000),
+            },
+            Transaction::Deposit {
+                client: 3,
+                tx: 2,
+                amount: Currency::new(20000),
+            },
+            Transaction::Dispute { client: 3, tx: 2 },
+        ]);
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client, available, held, total, locked\n\
+             1, 1.5000, 0.0000, 1.5000, false\n\
+             3, 0.0000, 2.0000, 2.0000, false\n"
+        );
+        let mut json = Vec::new();
+        table.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[\n\
+             {\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false},\n\
+             {\"client\":3,\"available\":\"0.0000\",\"held\":\"2.0000\",\"total\":\"2.0000\",\"locked\":false}\n\
+             ]\n"
+        );
+    }
+
+    #[test]
+    fn write_json_empty_table() {
+        let mut json = Vec::new();
+        ClientTable::new().write_json(&mut json).unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(), "[\n]\n");
+    }
+
+    #[test]
+    #[cfg(feature = "std-io")]
+    fn archived_history_can_be_recalled_for_disputes() {
+        let path =
+            std::env::temp_dir().join(format!("bank_table_archive_{}.csv", std::process::id()));
+        let archive = Archive::new(&path, CompatCheck::Strict);
+        let mut table = ClientTable::new();
+        table.process((1..=3).map(|tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(10000),
+        }));
+        assert_eq!(table.archive_history(1, &archive).unwrap(), 2);
+        let dispute = Transaction::Dispute { client: 1, tx: 1 };
+        assert!(table.handle_transaction(dispute).is_err());
+        assert!(table.recall_archived(&archive, 1, 1).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        table.handle_transaction(dispute).unwrap();
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 1.0000, 3.0000, false"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std-io")]
+    fn transfers_older_than_the_retention_period_are_archived() {
+        let path =
+            std::env::temp_dir().join(format!("bank_expired_archive_{}.csv", std::process::id()));
+        let archive = Archive::new(&path, CompatCheck::Strict);
+        let mut table = ClientTable::new();
+        let day = RetentionPeriod::DAY;
+        for (tx, timestamp) in [(1, Some(day)), (2, Some(5 * day)), (3, None)] {
+            table
+                .handle_stamped(Stamped {
+                    transaction: Transaction::Deposit {
+                        client: 1,
+                        tx,
+                        amount: Currency::new(10000),
+                    },
+                    timestamp,
+                    memo: None,
+                })
+                .unwrap();
+        }
+        let archived = table
+            .archive_expired(RetentionPeriod::days(3), 6 * day, &archive)
+            .unwrap();
+        let recalled = table.recall_archived(&archive, 1, 1).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(archived, 1);
+        assert!(recalled);
+        let history: Vec<_> = table.clients[1].history().iter().map(|t| t.tx()).collect();
+        assert_eq!(history, [2, 3, 1]);
+        assert_eq!(table.clients[1].history()[2].at(), Some(day));
+    }
+
+    #[test]
+    #[cfg(feature = "std-io")]
+    fn settled_disputes_expire_with_their_transfer() {
+        let path =
+            std::env::temp_dir().join(format!("bank_expired_settled_{}.csv", std::process::id()));
+        let archive = Archive::new(&path, CompatCheck::Strict);
+        let mut table = ClientTable::new();
+        let day = RetentionPeriod::DAY;
+        for (transaction, timestamp) in [
+            (
+                Transaction::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: Currency::new(10000),
+                },
+                Some(day),
+            ),
+            (Transaction::Dispute { client: 1, tx: 1 }, None),
+            (Transaction::Resolve { client: 1, tx: 1 }, None),
+        ] {
+            table
+                .handle_stamped(Stamped {
+                    transaction,
+                    timestamp,
+                    memo: None,
+                })
+                .unwrap();
+        }
+        assert_eq!(table.clients[1].history_len(), 2);
+        table
+            .archive_expired(RetentionPeriod::days(3), 6 * day, &archive)
+            .unwrap();
+        assert_eq!(table.clients[1].history_len(), 0);
+        assert_eq!(table.clients[1].dispute_state(1), DisputeState::Undisputed);
+        let recalled = table.recall_archived(&archive, 1, 1).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(recalled);
+        assert_eq!(table.clients[1].dispute_state(1), DisputeState::Resolved);
+        let dispute = Transaction::Dispute { client: 1, tx: 1 };
+        assert_eq!(
+            table.handle_transaction(dispute),
+            Err(TransactionError::DisputeSettled)
+        );
+    }
+
+    #[test]
+    fn merge_rejects_overlapping_tables() {
+        let deposit = |client, tx| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(10000),
+        };
+        let mut table = ClientTable::new();
+        table.process(vec![deposit(1, 1)]);
+        let mut same_client = ClientTable::new();
+        same_client.process(vec![deposit(1, 2)]);
+        let mut same_tx = ClientTable::new();
+        same_tx.process(vec![deposit(2, 1)]);
+        let mut disjoint = ClientTable::new();
+        disjoint.process(vec![deposit(2, 2)]);
+        assert_eq!(table.merge(same_client), Err(MergeError::ClientConflict(1)));
+        assert_eq!(table.merge(same_tx), Err(MergeError::DuplicateTxId(1)));
+        table.merge(disjoint).unwrap();
+        assert!(table.clients[2].exists());
+        assert!(matches!(
+            table.handle_transaction(deposit(3, 2)),
+            Err(TransactionError::DuplicateTxId)
+        ));
+    }
+
+    #[test]
+    fn merge_overflow_leaves_the_table_untouched() {
+        let policy = Policy::default();
+        let mut table = ClientTable::new();
+        let mut other = ClientTable::new();
+        for (i, table) in [&mut table, &mut other].iter_mut().enumerate() {
+            let tx = i as TxId * 2;
+            table
+                .house
+                .deposit(Currency::new(10000), tx, &policy)
+                .unwrap();
+            table
+                .house
+                .book(Currency::MAX, tx + 1, None, Release::ValueDate(1), &policy)
+                .unwrap();
+        }
+        assert_eq!(table.merge(other), Err(MergeError::Overflow));
+        assert_eq!(table.house.available_funds(), Currency::new(10000));
+        assert_eq!(table.house.booked_funds(), Currency::MAX);
+        assert_eq!(table.house.history_len(), 2);
+    }
+
+    #[test]
+    fn sparse_table_matches_dense_report() {
+        let txs = vec![
+            Transaction::Deposit {
+                client: 9,
+                tx: 1,
+                amount: Currency::new(10000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 5, tx: 1 },
+        ];
+        let mut dense = ClientTable::new();
+        let mut sparse = ClientTable::sparse();
+        assert_eq!(dense.process(txs.clone()), sparse.process(txs));
+        assert_eq!(dense.to_string(), sparse.to_string());
+    }
+
+    fn budgeted(action: PressureAction) -> ClientTable {
+        ClientTable::with_policy(Policy {
+            memory_budget: Some(MemoryBudget {
+                limit_bytes: 10 * mem::size_of::<ClientTransaction>(),
+                action,
+            }),
+            ..Policy::default()
+        })
+    }
+
+    fn deposits(n: u32) -> impl Iterator<Item = Transaction> {
+        (1..=n).map(|tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(10000),
+        })
+    }
+
+    #[test]
+    fn memory_pressure_prunes_history() {
+        let mut table = budgeted(PressureAction::Prune { keep_last: 2 });
+        table.process(deposits(9));
+        let warnings = table.take_warnings();
+        assert_eq!(
+            warnings,
+            vec![EngineWarning::MemoryPressure {
+                used_bytes: 9 * mem::size_of::<ClientTransaction>(),
+                limit_bytes: 10 * mem::size_of::<ClientTransaction>(),
+                trimmed: 7,
+                spilled: false,
+                passes: 1,
+            }]
+        );
+        assert_eq!(table.clients[1].history_len(), 2);
+        assert_eq!(
+            table.clients[1].to_string(),
+            "9.0000, 0.0000, 9.0000, false"
+        );
+        assert!(table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .is_err());
+        assert!(table.take_warnings().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "std-io")]
+    fn memory_pressure_spills_to_archive() {
+        let path = std::env::temp_dir().join(format!("bank_spill_{}.csv", std::process::id()));
+        let mut table = budgeted(PressureAction::Spill { keep_last: 2 });
+        table.set_spill_archive(Archive::new(&path, CompatCheck::Strict));
+        table.process(deposits(9));
+        let recalled = table.recall_archived(&Archive::new(&path, CompatCheck::Strict), 1, 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            table.take_warnings()[..],
+            [EngineWarning::MemoryPressure { spilled: true, .. }]
+        ));
+        assert!(recalled.unwrap());
+        table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .unwrap();
+    }
+
+    #[test]
+    fn history_above_the_soft_limit_is_not_trimmed_on_every_transaction() {
+        let mut table = ClientTable::with_policy(Policy {
+            memory_budget: Some(MemoryBudget {
+                limit_bytes: 100 * mem::size_of::<ClientTransaction>(),
+                action: PressureAction::Prune { keep_last: 1000 },
+            }),
+            ..Policy::default()
+        });
+        table.process(deposits(1000));
+        // Trimmed when reaching 90 entries, then once every 10 more
+        assert!(matches!(
+            table.take_warnings()[..],
+            [EngineWarning::MemoryPressure {
+                trimmed: 0,
+                passes: 92,
+                ..
+            }]
+        ));
+        assert_eq!(table.clients[1].history_len(), 1000);
+    }
+
+    #[test]
+    #[cfg(feature = "std-io")]
+    fn memory_pressure_trims_settled_disputes() {
+        let path =
+            std::env::temp_dir().join(format!("bank_spill_settled_{}.csv", std::process::id()));
+        let mut table = budgeted(PressureAction::Spill { keep_last: 1 });
+        table.set_spill_archive(Archive::new(&path, CompatCheck::Strict));
+        table.process(deposits(4));
+        for tx in 1..=3 {
+            table.process(vec![
+                Transaction::Dispute { client: 1, tx },
+                Transaction::Resolve { client: 1, tx },
+            ]);
+        }
+        let history_len = table.clients[1].history_len();
+        let recalled = table.recall_archived(&Archive::new(&path, CompatCheck::Strict), 1, 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(history_len <= 3);
+        assert!(recalled.unwrap());
+        assert_eq!(
+            table.handle_transaction(Transaction::Dispute { client: 1, tx: 1 }),
+            Err(TransactionError::DisputeSettled)
+        );
+    }
+
+    #[test]
+    fn reports_at_configured_precision() {
+        let mut table = ClientTable::new();
+        table.set_currency_config(CurrencyConfig::new(8, Default::default()).unwrap());
+        let events: Vec<_> = table
+            .stream(vec![Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(123456789),
+            }])
+            .collect();
+        assert_eq!(events[0].to_string(), "deposit, 1, 1, 1.23456789, applied");
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n1, 1.23456789, 0.00000000, 1.23456789, false\n"
+        );
+    }
+
+    #[test]
+    fn stream_stops_at_record_boundary_once_cancelled() {
+        let token = CancellationToken::new();
+        let mut table = ClientTable::new();
+        let txs = (1..=3).map(|tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(10000),
+        });
+        let mut stream = table.stream(txs).until_cancelled(token.clone());
+        assert!(stream.next().is_some());
+        token.cancel();
+        assert!(stream.next().is_none());
+        assert_eq!(
+            stream.summary(),
+            Summary {
+                applied: 1,
+                rejected: 0
+            }
+        );
+    }
+
+    #[test]
+    fn disputes_can_be_routed_by_tx_id() {
+        // The acquirer puts its merchant id 900 in the client column of disputes
+        let feed = vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Dispute { client: 900, tx: 1 },
+            Transaction::Chargeback { client: 900, tx: 1 },
+        ];
+        let mut strict = ClientTable::new();
+        strict.process(feed.clone());
+        assert_eq!(
+            strict.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+
+        for routing in [DisputeRouting::TxId, DisputeRouting::TxIdWarn] {
+            let mut table = ClientTable::with_policy(Policy {
+                dispute_routing: routing,
+                ..Policy::default()
+            });
+            let summary = table.process(feed.clone());
+            assert_eq!(summary.rejected, 0);
+            assert_eq!(table.clients[1].to_string(), "0.0000, 0.0000, 0.0000, true");
+            assert!(table.client(900).is_none());
+            let warned = table.take_warnings().len();
+            assert_eq!(
+                warned,
+                if routing == DisputeRouting::TxId {
+                    0
+                } else {
+                    2
+                }
+            );
+        }
+    }
+
+    fn undisputed(undisputed: UndisputedPolicy, settlement: Transaction) -> ClientTable {
+        let mut table = ClientTable::with_policy(Policy {
+            undisputed,
+            ..Policy::default()
+        });
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            settlement,
+        ]);
+        table
+    }
+
+    #[test]
+    fn undisputed_settlements_are_rejected_by_default() {
+        let mut table = undisputed(
+            UndisputedPolicy::Reject,
+            Transaction::Chargeback { client: 1, tx: 1 },
+        );
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+        assert!(table.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn undisputed_settlements_can_open_the_dispute() {
+        let table = undisputed(
+            UndisputedPolicy::AutoOpen,
+            Transaction::Chargeback { client: 1, tx: 1 },
+        );
+        assert_eq!(table.clients[1].to_string(), "0.0000, 0.0000, 0.0000, true");
+        let table = undisputed(
+            UndisputedPolicy::AutoOpen,
+            Transaction::Resolve { client: 1, tx: 1 },
+        );
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+        assert_eq!(table.clients[1].dispute_state(1), DisputeState::Resolved);
+    }
+
+    #[test]
+    fn undisputed_settlements_can_be_recorded_as_violations() {
+        let chargeback = Transaction::Chargeback { client: 1, tx: 1 };
+        let mut table = undisputed(UndisputedPolicy::RecordViolation, chargeback);
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+        assert_eq!(
+            table.take_warnings(),
+            vec![EngineWarning::ProtocolViolation(chargeback)]
+        );
+        assert_eq!(
+            table.handle_transaction(Transaction::Resolve { client: 1, tx: 1 }),
+            Err(TransactionError::NotDisputed)
+        );
+    }
+
+    #[test]
+    fn failed_auto_opened_settlement_leaves_no_dispute() {
+        let mut table = ClientTable::with_policy(Policy {
+            undisputed: UndisputedPolicy::AutoOpen,
+            chargeback_fee: Some(ChargebackFee {
+                amount: Currency::MIN,
+                payer: FeePayer::Client,
+            }),
+            ..Policy::default()
+        });
+        table.process(vec![Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(20000),
+        }]);
+        assert_eq!(
+            table.handle_transaction(Transaction::Chargeback { client: 1, tx: 1 }),
+            Err(TransactionError::Overflow)
+        );
+        assert!(!table.clients[1].is_disputed(1));
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+    }
+
+    #[test]
+    fn adapting_keeps_clients_and_indexes_disputes() {
+        let mut table = ClientTable::sparse();
+        table.process(deposits(3));
+        let sample = vec![Transaction::Dispute { client: 1, tx: 2 }];
+        let layout = table.adapt(&sample);
+        assert_eq!(
+            (layout.dense, layout.history),
+            (false, HistoryLookup::Indexed)
+        );
+        table.process(sample);
+        table.process(deposits(5).skip(3));
+        table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 5 })
+            .unwrap();
+        assert_eq!(
+            table.clients[1].to_string(),
+            "3.0000, 2.0000, 5.0000, false"
+        );
+    }
+
+    #[test]
+    fn adapting_keeps_what_the_caller_chose() {
+        let disputes: Vec<_> = (1..2000)
+            .map(|client| Transaction::Dispute { client, tx: 1 })
+            .collect();
+        let mut table = ClientTable::with_storage(
+            ClientStorage::sparse(),
+            Policy {
+                history: Some(HistoryLookup::Scan),
+                tx_index: Some(IndexStrategy::Hashed),
+                ..Policy::default()
+            },
+        );
+        assert!(!table.adapts());
+        let layout = table.adapt(&disputes);
+        assert_eq!(
+            (layout.dense, layout.history, layout.tx_index),
+            (false, HistoryLookup::Scan, IndexStrategy::Hashed)
+        );
+        assert!(!table.clients.is_dense());
+
+        let mut table = ClientTable::new();
+        assert!(table.adapts());
+        assert!(table.adapt(&disputes).dense);
+        assert_eq!(table.policy.history, Some(HistoryLookup::Indexed));
+        assert!(!table.adapts());
+    }
+
+    #[test]
+    fn bookings_become_available_at_their_value_date() {
+        let mut table = ClientTable::with_policy(Policy {
+            settlement: SettlementPolicy::ValueDated,
+            ..Policy::default()
+        });
+        let booking = Transaction::Booking {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(20000),
+            value_date: 4,
+        };
+        let withdrawal = Transaction::Withdraw {
+            client: 1,
+            tx: 2,
+            amount: Currency::new(10000),
+        };
+        assert_eq!(
+            table.process(vec![booking, withdrawal]),
+            Summary {
+                applied: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked, booked\n1, 0.0000, 0.0000, 2.0000, false, 2.0000\n"
+        );
+        // Not a transfer yet, so there is nothing to dispute
+        assert!(table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .is_err());
+        // The fourth record is handled once the clock reached the value date
+        let withdrawal = Transaction::Withdraw {
+            client: 1,
+            tx: 3,
+            amount: Currency::new(10000),
+        };
+        table.handle_transaction(withdrawal).unwrap();
+        assert_eq!(
+            table.clients[1].to_string(),
+            "1.0000, 0.0000, 1.0000, false"
+        );
+        assert_eq!(table.clients[1].booked_funds(), Currency::ZERO);
+        table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .unwrap();
+    }
+
+    #[test]
+    fn bookings_are_deposits_by_default() {
+        let mut table = ClientTable::new();
+        table
+            .handle_transaction(Transaction::Booking {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+                value_date: 100,
+            })
+            .unwrap();
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n1, 2.0000, 0.0000, 2.0000, false\n"
+        );
+    }
+
+    #[test]
+    fn client_history_is_exposed() {
+        let mut table = ClientTable::sparse();
+        table.process(deposits(3));
+        table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 2 })
+            .unwrap();
+        assert!(table.client(2).is_none());
+        let client = table.client(1).unwrap();
+        let txs: Vec<_> = client.history().iter().map(|t| t.tx()).collect();
+        assert_eq!(txs, vec![1, 2, 3]);
+        assert_eq!(client.open_disputes()[0].tx(), 2);
+        assert_eq!(client.open_disputes()[0].amount(), Currency::new(10000));
+        assert!(client.fees().is_empty());
+        assert_eq!(
+            (
+                client.available_funds(),
+                client.held_funds(),
+                client.total_funds().unwrap()
+            ),
+            (
+                Currency::new(20000),
+                Currency::new(10000),
+                Currency::new(30000)
+            )
+        );
+        assert!(!client.is_locked());
+    }
+
+    #[test]
+    fn deposits_wait_for_their_clearing_day() {
+        let mut table = ClientTable::with_policy(Policy {
+            clearing: ClearingDelay::BusinessDays(2),
+            ..Policy::default()
+        });
+        table.process(deposits(1));
+        table.clear();
+        table.process(deposits(2).skip(1));
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked, booked\n1, 0.0000, 0.0000, 2.0000, false, 2.0000\n"
+        );
+        assert_eq!(table.clear(), 1);
+        assert_eq!(table.clients[1].available_funds(), Currency::new(10000));
+        assert_eq!(table.booked_total(), Ok(Currency::new(10000)));
+        assert_eq!(table.clear(), 1);
+        assert_eq!(table.booked_total(), Ok(Currency::ZERO));
+        assert_eq!(table.business_day(), 3);
+        assert_eq!(
+            table.clients[1].to_string(),
+            "2.0000, 0.0000, 2.0000, false"
+        );
+    }
+
+    #[test]
+    fn clearing_days_saturate() {
+        let mut table = ClientTable::with_policy(Policy {
+            clearing: ClearingDelay::BusinessDays(u64::MAX),
+            ..Policy::default()
+        });
+        table.clear();
+        table.process(deposits(1));
+        assert_eq!(table.clear(), 0);
+        assert_eq!(table.booked_total(), Ok(Currency::new(10000)));
+    }
+
+    #[test]
+    fn legal_holds_ring_fence_funds_from_withdrawals() {
+        let mut table = ClientTable::sparse();
+        table.set_extended_report(true);
+        table.process(deposits(5));
+        let withdraw = |tx, amount| Transaction::Withdraw {
+            client: 1,
+            tx,
+            amount: Currency::new(amount),
+        };
+        let hold = |tx, amount| Transaction::LegalHold {
+            client: 1,
+            tx,
+            amount,
+        };
+        table
+            .handle_transaction(hold(100, Some(Currency::new(30000))))
+            .unwrap();
+        assert_eq!(
+            table.handle_transaction(withdraw(6, 30000)),
+            Err(TransactionError::LegalHold)
+        );
+        table.handle_transaction(withdraw(7, 20000)).unwrap();
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked, legal_hold\n1, 3.0000, 0.0000, 3.0000, false, 3.0000\n"
+        );
+        // Disputes are unaffected and tracked apart
+        table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 1 })
+            .unwrap();
+        assert_eq!(table.clients[1].held_funds(), Currency::new(10000));
+        assert_eq!(
+            table.handle_transaction(hold(100, None)),
+            Err(TransactionError::DuplicateTxId)
+        );
+        table
+            .handle_transaction(Transaction::ReleaseHold { client: 1, tx: 100 })
+            .unwrap();
+        // Without an amount the whole available balance is ring-fenced
+        table.handle_transaction(hold(101, None)).unwrap();
+        assert_eq!(table.clients[1].legal_held(), Ok(Currency::new(20000)));
+        assert_eq!(
+            table.handle_transaction(withdraw(8, 1)),
+            Err(TransactionError::LegalHold)
+        );
+        assert_eq!(
+            table.handle_transaction(Transaction::ReleaseHold { client: 1, tx: 100 }),
+            Err(TransactionError::InvalidTxId)
+        );
+    }
+
+    #[test]
+    fn foreign_currencies_have_their_own_accounts() {
+        let (eur, usd) = ("EUR".parse().unwrap(), "USD".parse().unwrap());
+        let foreign = |tx, code, amount| Transaction::ForeignDeposit {
+            client: 1,
+            tx,
+            code,
+            amount: Currency::new(amount),
+        };
+        let mut table = ClientTable::new();
+        table.set_base_currency(Some(eur));
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(10000),
+            },
+            foreign(2, eur, 5000),
+            foreign(3, usd, 20000),
+            foreign(4, usd, 5000),
+            Transaction::Dispute { client: 1, tx: 3 },
+            Transaction::Chargeback { client: 1, tx: 3 },
+        ]);
+        assert_eq!(
+            table.handle_transaction(Transaction::ForeignWithdraw {
+                client: 1,
+                tx: 5,
+                code: usd,
+                amount: Currency::new(1000),
+            }),
+            Err(TransactionError::AccountLocked)
+        );
+        assert_eq!(
+            table.to_string(),
+            "client, currency, available, held, total, locked\n\
+             1, EUR, 1.50, 0.00, 1.50, false\n\
+             1, USD, 0.50, 0.00, 0.50, true\n"
+        );
+        assert_eq!(
+            table
+                .foreign_account(1, usd)
+                .unwrap()
+                .total_funds()
+                .unwrap(),
+            Currency::new(5000)
+        );
+        assert!(table.foreign_account(1, eur).is_none());
+
+        let mut merged = ClientTable::new();
+        merged.set_base_currency(Some(eur));
+        for shard in table.shard(3) {
+            merged.merge(shard).unwrap();
+        }
+        assert_eq!(
+            merged.to_string(),
+            "client, currency, available, held, total, locked\n\
+             1, EUR, 1.50, 0.00, 1.50, false\n\
+             1, USD, 0.50, 0.00, 0.50, true\n"
+        );
+    }
+
+    #[test]
+    fn conversions_move_funds_between_currencies() {
+        let (eur, usd) = ("EUR".parse().unwrap(), "USD".parse().unwrap());
+        let mut rates = RateTable::new();
+        rates.set_rate(eur, usd, "1.1".parse().unwrap());
+        rates.set_spread(100).unwrap();
+        let mut table = ClientTable::new();
+        table.set_rates(rates);
+        let convert = |tx, from, to, amount| Transaction::Convert {
+            client: 1,
+            tx,
+            from,
+            to,
+            amount: Currency::new(amount),
+        };
+        table
+            .handle_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(50000),
+            })
+            .unwrap();
+        // The base accounts need a base currency to be converted
+        assert_eq!(
+            table.handle_transaction(convert(2, None, usd, 10000)),
+            Err(TransactionError::NoRate)
+        );
+        table.set_base_currency(Some(eur));
+        table
+            .handle_transaction(convert(2, None, usd, 10000))
+            .unwrap();
+        // 0.99 EUR is 1.089 USD after the spread, rounded to the cent
+        assert_eq!(
+            table.handle_transaction(convert(3, Some(usd), eur, 100)),
+            Err(TransactionError::NoRate)
+        );
+        assert_eq!(
+            table.handle_transaction(convert(2, None, usd, 100)),
+            Err(TransactionError::DuplicateTxId)
+        );
+        assert_eq!(
+            table.handle_transaction(Transaction::Dispute { client: 1, tx: 2 }),
+            Err(TransactionError::NotDisputable)
+        );
+        assert_eq!(
+            table.to_string(),
+            "client, currency, available, held, total, locked\n\
+             1, EUR, 4.00, 0.00, 4.00, false\n\
+             1, USD, 1.09, 0.00, 1.09, false\n"
+        );
+    }
+
+    #[test]
+    fn schedules_post_at_the_end_of_accrual_days() {
+        let mut schedules = Schedules::new();
+        schedules.push(Posting::Interest("0.01".parse().unwrap()), 1);
+        schedules.push(Posting::Fee(Currency::new(10000)), 2);
+        let mut table = ClientTable::new();
+        table.set_schedules(schedules);
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(1_000_000),
+            },
+            Transaction::Accrue { client: 0, tx: 100 },
+            Transaction::Accrue { client: 0, tx: 101 },
+        ]);
+        assert_eq!(table.accrual_day(), 2);
+        // 1% of 100 then of 101, and the fee due every second day
+        assert_eq!(table.clients[1].available_funds(), Currency::new(1_010_100));
+        let postings: Vec<_> = table.clients[1]
+            .fees()
+            .iter()
+            .map(|t| (t.tx(), t.kind(), t.amount()))
+            .collect();
+        assert_eq!(
+            postings,
+            vec![
+                (100, TransferKind::Interest, Currency::new(10000)),
+                (101, TransferKind::Interest, Currency::new(10100)),
+                (101, TransferKind::Fee, Currency::new(-10000)),
+            ]
+        );
+        assert!(table
+            .handle_transaction(Transaction::Dispute { client: 1, tx: 100 })
+            .is_err());
+
+        // The clock closes a day every second record, before handling it
+        let mut schedules = Schedules::new();
+        schedules.push(Posting::Fee(Currency::new(5000)), 1);
+        schedules.set_day_length(2).unwrap();
+        let mut table = ClientTable::new();
+        table.set_schedules(schedules);
+        table.process(deposits(3));
+        assert_eq!(table.accrual_day(), 1);
+        assert_eq!(table.clients[1].available_funds(), Currency::new(25000));
+        assert_eq!(table.clients[1].fees()[0].tx(), 1);
+    }
+
+    #[test]
+    fn risk_limits_reject_withdrawals_and_bursts() {
+        let mut limits = RiskLimits::new();
+        limits.set_default(Limits {
+            max_withdrawal: Some(Currency::new(30000)),
+            daily_withdrawals: Some(Currency::new(50000)),
+            velocity: None,
+        });
+        limits.set(
+            2,
+            Limits {
+                velocity: Some(Velocity {
+                    count: 2,
+                    window: 100,
+                }),
+                ..Limits::default()
+            },
+        );
+        let mut table = ClientTable::new();
+        table.set_limits(limits);
+        let withdraw = |client, tx, amount| Transaction::Withdraw {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100_000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(100_000),
+            },
+        ]);
+        let outcomes: Vec<_> = vec![
+            withdraw(1, 3, 40000),
+            withdraw(1, 4, 30000),
+            withdraw(1, 5, 30000),
+            withdraw(2, 6, 40000),
+            withdraw(2, 7, 40000),
+        ]
+        .into_iter()
+        .map(|t| table.handle_transaction(t))
+        .collect();
+        let limit = Err(TransactionError::LimitExceeded);
+        // Above the single withdrawal limit, within the daily one, over it, then client 2 has a
+        // deposit and a withdrawal within the window already
+        assert_eq!(outcomes, vec![limit, Ok(()), limit, Ok(()), limit]);
+        // A new business day starts a new daily total
+        table.clear();
+        assert!(table.handle_transaction(withdraw(1, 8, 30000)).is_ok());
+        assert_eq!(table.clients[1].available_funds(), Currency::new(40000));
+    }
+
+    #[test]
+    fn overdraw_policy_per_client() {
+        let mut table = ClientTable::with_policy(Policy {
+            overdraw: OverdrawPolicy::Strict,
+            ..Policy::default()
+        });
+        table.set_overdraw_policy(2, OverdrawPolicy::Overdraft(Currency::new(5000)));
+        let withdraw = |client, tx, amount| Transaction::Withdraw {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(10000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(10000),
+            },
+        ]);
+        assert_eq!(
+            table.handle_transaction(withdraw(1, 3, 10000)),
+            Err(TransactionError::Overdraw)
+        );
+        assert!(table.handle_transaction(withdraw(2, 4, 15000)).is_ok());
+        assert_eq!(table.clients[2].available_funds(), Currency::new(-5000));
+        assert_eq!(
+            table.clients[2].total_funds().unwrap(),
+            Currency::new(-5000)
+        );
+
+        // Tables made like this one keep the overrides
+        let mut like = table.empty_like();
+        assert!(like.handle_transaction(withdraw(2, 5, 5000)).is_ok());
+        assert_eq!(like.clients[2].available_funds(), Currency::new(-5000));
+        assert_eq!(
+            like.handle_transaction(withdraw(1, 6, 0)),
+            Err(TransactionError::Overdraw)
+        );
+    }
+
+    #[test]
+    fn disputes_are_rejected_after_the_dispute_window() {
+        let mut table = ClientTable::with_policy(Policy {
+            dispute_window: Some(100),
+            ..Policy::default()
+        });
+        let at = |transaction, timestamp| Stamped {
+            transaction,
+            timestamp: Some(timestamp),
+            memo: None,
+        };
+        let deposit = |tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(10000),
+        };
+        let dispute = |tx| Transaction::Dispute { client: 1, tx };
+        table.process(vec![
+            at(deposit(1), 1000),
+            at(deposit(2), 1000),
+            deposit(3).into(),
+        ]);
+        assert_eq!(table.clients[1].transfer_time(1), Some(1000));
+        assert_eq!(table.clients[1].transfer_time(3), None);
+        assert_eq!(
+            table.handle_stamped(at(dispute(1), 1101)),
+            Err(TransactionError::DisputeExpired)
+        );
+        assert!(table.handle_stamped(at(dispute(2), 1100)).is_ok());
+        // Without a timestamp on either side there is nothing to check
+        assert!(table.handle_stamped(at(dispute(3), 5000)).is_ok());
+        assert!(table.handle_transaction(dispute(1)).is_ok());
+    }
+
+    #[test]
+    fn replayed_records_keep_the_timestamp_and_memo_of_the_first() {
+        let mut table = ClientTable::with_policy(Policy {
+            dedup: DedupPolicy::Full,
+            dispute_window: Some(90 * 86400),
+            ..Policy::default()
+        });
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(10000),
+        };
+        let at = |transaction, timestamp, memo: &str| Stamped {
+            transaction,
+            timestamp: Some(timestamp),
+            memo: memo.parse().ok(),
+        };
+        table.handle_stamped(at(deposit, 0, "payroll")).unwrap();
+        table
+            .handle_stamped(at(deposit, 8640000, "replay"))
+            .unwrap();
+        assert_eq!(table.stats().duplicates, 1);
+        assert_eq!(table.clients[1].transfer_time(1), Some(0));
+        let memos: Vec<_> = table.clients[1]
+            .memos()
+            .iter()
+            .map(|(tx, memo)| (*tx, memo.as_str()))
+            .collect();
+        assert_eq!(memos, [(1, "payroll")]);
+        let dispute = Transaction::Dispute { client: 1, tx: 1 };
+        assert_eq!(
+            table.handle_stamped(at(dispute, 8650000, "")),
+            Err(TransactionError::DisputeExpired)
+        );
+    }
+
+    #[test]
+    fn bookings_keep_their_timestamp_once_released() {
+        let mut table = ClientTable::with_policy(Policy {
+            settlement: SettlementPolicy::ValueDated,
+            dispute_window: Some(100),
+            ..Policy::default()
+        });
+        let booking = Transaction::Booking {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(20000),
+            value_date: 2,
+        };
+        table
+            .handle_stamped(Stamped {
+                transaction: booking,
+                timestamp: Some(1000),
+                memo: None,
+            })
+            .unwrap();
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 2,
+            amount: Currency::new(10000),
+        };
+        table.handle_transaction(deposit).unwrap();
+        assert_eq!(table.clients[1].booked_funds(), Currency::ZERO);
+        assert_eq!(table.clients[1].transfer_time(1), Some(1000));
+        let dispute = Stamped {
+            transaction: Transaction::Dispute { client: 1, tx: 1 },
+            timestamp: Some(1101),
+            memo: None,
+        };
+        assert_eq!(
+            table.handle_stamped(dispute),
+            Err(TransactionError::DisputeExpired)
+        );
+    }
+
+    #[test]
+    fn scheduled_fees_are_collected_by_the_house() {
+        let mut fees = FeeSchedule::new();
+        fees.set(
+            FeeKind::Withdrawal,
+            Fee {
+                flat: Currency::new(5000),
+                rate: Some("0.01".parse().unwrap()),
+            },
+        );
+        fees.set(
+            FeeKind::Chargeback,
+            Fee {
+                flat: Currency::new(20000),
+                rate: None,
+            },
+        );
+        let mut table = ClientTable::new();
+        table.set_fees(fees);
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(100_000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(50000),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 3 },
+        ]);
+        // 0.50 and 1% of 5, then a chargeback fee taking client 2 below zero
+        let charged: Vec<_> = table.clients[1]
+            .fees()
+            .iter()
+            .map(|f| (f.tx(), f.amount()))
+            .collect();
+        assert_eq!(charged, vec![(2, Currency::new(-5500))]);
+        assert_eq!(
+            table.to_string(),
+            "client, available, held, total, locked\n\
+             1, 4.4500, 0.0000, 4.4500, false\n\
+             2, -2.0000, 0.0000, -2.0000, true\n\
+             house, 2.5500, 0.0000, 2.5500, false\n"
+        );
+    }
 }
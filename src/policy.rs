@@ -0,0 +1,288 @@
+use crate::{
+    client_info::AccountType, currency::Currency, transaction::Transaction, tx_index::IndexStrategy,
+};
+
+/// Engine wide knobs changing how the `ClientTable` treats transactions
+/// The defaults don't match the original engine everywhere: disputing a withdrawal holds the
+/// withdrawn amount without crediting the available funds, the whole available balance can be
+/// withdrawn and reused transaction ids are rejected. `--spec-compat` runs the original engine, see
+/// `spec`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Fee assessed whenever a chargeback is processed, `None` disables it
+    pub chargeback_fee: Option<ChargebackFee>,
+    /// What a locked account still accepts
+    pub locked_accounts: LockedAccountPolicy,
+    /// Floors withdrawals are not allowed to take the available funds below
+    pub minimum_balances: MinimumBalances,
+    /// How far withdrawals can draw the available funds down, overridden per client with
+    /// `ClientTable::set_overdraw_policy`
+    pub overdraw: OverdrawPolicy,
+    /// Which kinds of transactions can be disputed
+    pub disputes: DisputePolicy,
+    /// How resolves and chargebacks of transactions that were never disputed are handled
+    pub undisputed: UndisputedPolicy,
+    /// Large operations requiring an `approve` record before they are applied, `None` disables it
+    pub approvals: Option<ApprovalPolicy>,
+    /// Soft limit on the memory used by the client histories, `None` lets them grow unbounded
+    pub memory_budget: Option<MemoryBudget>,
+    /// How disputes find the transfer they reference in the client history, `None` lets
+    /// `ClientTable::adapt` pick from the share of disputes, scanned until then
+    pub history: Option<HistoryLookup>,
+    /// When the funds of a booking become available
+    pub settlement: SettlementPolicy,
+    /// How long deposits wait before their funds become available
+    pub clearing: ClearingDelay,
+    /// Which client disputes, resolves and chargebacks are applied to
+    pub dispute_routing: DisputeRouting,
+    /// Whether records seen before are skipped, for replaying logs that may contain duplicates
+    pub dedup: DedupPolicy,
+    /// How the transaction ids are indexed, `None` lets `ClientTable::adapt` pick from the
+    /// density of the ids, hashed until then
+    pub tx_index: Option<IndexStrategy>,
+    /// Seconds after a transaction during which it can be disputed, `None` keeps it disputable
+    /// forever. Only enforced for transactions and disputes with a timestamp
+    pub dispute_window: Option<u64>,
+}
+
+impl Policy {
+    /// Whether some funds can be booked but not available yet, reports then get a `booked` column
+    pub fn defers_funds(&self) -> bool {
+        self.settlement == SettlementPolicy::ValueDated || self.clearing != ClearingDelay::Instant
+    }
+}
+
+/// Flat fee the acquirer charges for every chargeback
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChargebackFee {
+    pub amount: Currency,
+    pub payer: FeePayer,
+}
+
+/// Who ends up paying a fee
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeePayer {
+    /// The disputed client pays, which may drive their available funds negative
+    Client,
+    /// The house absorbs the fee, the client balances are left untouched
+    House,
+}
+
+/// Which operations a locked(charged back) account still accepts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    /// Deposits, withdrawals and new disputes are all refused
+    #[default]
+    RejectAll,
+    /// Deposits are still credited, so incoming funds are not bounced, everything else is refused
+    AcceptDeposits,
+}
+
+/// Minimum available balance required for each account type, all floors default to zero
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MinimumBalances {
+    pub standard: Currency,
+    pub savings: Currency,
+    pub business: Currency,
+}
+
+impl MinimumBalances {
+    pub fn floor(&self, account_type: AccountType) -> Currency {
+        match account_type {
+            AccountType::Standard => self.standard,
+            AccountType::Savings => self.savings,
+            AccountType::Business => self.business,
+        }
+    }
+}
+
+/// How far a withdrawal can draw the available funds down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverdrawPolicy {
+    /// Some funds have to remain, withdrawing the whole available balance is rejected
+    Strict,
+    /// The whole available balance can be withdrawn, the default. The original engine refused it,
+    /// `Strict` restores that
+    #[default]
+    AllowExact,
+    /// The available funds can go negative down to minus the limit, below the minimum balance of
+    /// the account type. Legal holds still block any withdrawal they don't leave funds for
+    Overdraft(Currency),
+}
+
+impl OverdrawPolicy {
+    /// How far the available funds can go below zero
+    pub fn overdraft(&self) -> Currency {
+        match self {
+            OverdrawPolicy::Strict | OverdrawPolicy::AllowExact => Currency::ZERO,
+            OverdrawPolicy::Overdraft(limit) => *limit,
+        }
+    }
+}
+
+/// Which transactions a client is allowed to dispute
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// Only deposits can be disputed, disputes of withdrawals are rejected
+    DepositsOnly,
+    /// Both deposits and withdrawals can be disputed
+    #[default]
+    DepositsAndWithdrawals,
+}
+
+/// What happens to a resolve or chargeback referencing a transaction without a dispute
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UndisputedPolicy {
+    /// The record is rejected as if the transaction didn't exist
+    #[default]
+    Reject,
+    /// A dispute is opened implicitly and then settled by the record
+    AutoOpen,
+    /// The record is rejected and reported as a protocol violation
+    RecordViolation,
+}
+
+/// How disputes, resolves and chargebacks find the client of the transaction they reference
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputeRouting {
+    /// The client column must name the owner of the transaction, otherwise the record is rejected
+    #[default]
+    Client,
+    /// The owner is looked up by transaction id and the client column is ignored, for feeds
+    /// putting something else there such as a merchant id
+    TxId,
+    /// Like `TxId`, but a client column not naming the owner is reported as a `MisroutedDispute`
+    /// warning
+    TxIdWarn,
+}
+
+impl DisputeRouting {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "client" => Some(DisputeRouting::Client),
+            "tx" => Some(DisputeRouting::TxId),
+            "tx-warn" => Some(DisputeRouting::TxIdWarn),
+            _ => None,
+        }
+    }
+}
+
+/// Which records the engine remembers to skip their duplicates, a record being identified by its
+/// type, client and transaction id. A skipped record is neither applied nor rejected and is
+/// counted in the `duplicates` of the stats. Records are remembered whatever their outcome, and
+/// are not part of snapshots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupPolicy {
+    /// Every record is handled, duplicate deposits and withdrawals are still rejected by id
+    #[default]
+    Off,
+    /// Every record ever seen is remembered, memory grows with the input
+    Full,
+    /// Only the given number of most recently seen records are remembered, enough for logs whose
+    /// duplicates are close to each other such as redeliveries after a reconnect
+    Window(usize),
+}
+
+impl DedupPolicy {
+    /// `full` or the size of a window
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.parse() {
+            _ if name == "full" => Some(DedupPolicy::Full),
+            Ok(records) if records > 0 => Some(DedupPolicy::Window(records)),
+            _ => None,
+        }
+    }
+}
+
+/// How bookings, deposits carrying a value date, are credited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SettlementPolicy {
+    /// The value date is ignored and a booking is credited right away like a deposit
+    #[default]
+    Immediate,
+    /// A booking sits in the booked funds of the client until the engine clock reaches its value
+    /// date, and only then becomes available. Reports get a `booked` column
+    ValueDated,
+}
+
+/// Clearing delay of deposits, counted in business days closed by `ClientTable::clear`, which the
+/// CLI runs once the input of a batch run is consumed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClearingDelay {
+    /// Deposits are available right away
+    #[default]
+    Instant,
+    /// Deposits sit in the booked funds until the sweep closing the `n`th business day after
+    /// the one they arrived on, `BusinessDays(1)` being T+1. Bookings keep their value date
+    BusinessDays(u64),
+}
+
+impl ClearingDelay {
+    /// `instant` or `t+<business days>`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.strip_prefix("t+").map(str::parse) {
+            _ if name == "instant" => Some(ClearingDelay::Instant),
+            Some(Ok(days)) => Some(ClearingDelay::BusinessDays(days)),
+            _ => None,
+        }
+    }
+}
+
+/// How a client history is searched by transaction id
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistoryLookup {
+    /// The history is scanned, cheapest when disputes are rare
+    #[default]
+    Scan,
+    /// Each client keeps a hash index of its transfers, worth it when disputes are frequent
+    Indexed,
+}
+
+/// Deposits and withdrawals above `threshold` wait for `approve` records from `approvers`
+/// distinct operators. Pending operations expire once `timeout` further records have been
+/// processed without all the approvals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApprovalPolicy {
+    pub threshold: Currency,
+    pub timeout: u64,
+    pub approvers: usize,
+}
+
+impl ApprovalPolicy {
+    pub fn requires_approval(&self, tx: &Transaction) -> bool {
+        tx.amount().is_some_and(|amount| amount > self.threshold)
+    }
+}
+
+/// Approximate budget for the in memory transaction history
+/// Once the history reaches 90% of `limit_bytes` the engine applies `action` and emits a warning.
+/// While the trimmed history stays above that, the next trim waits for another `headroom` of growth
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    pub limit_bytes: usize,
+    pub action: PressureAction,
+}
+
+impl MemoryBudget {
+    pub fn soft_limit(&self) -> usize {
+        self.limit_bytes / 10 * 9
+    }
+
+    /// Growth the history has to see before it is trimmed again when a trim couldn't take it
+    /// back below the soft limit
+    pub fn headroom(&self) -> usize {
+        (self.limit_bytes / 10).max(1)
+    }
+}
+
+/// How history is trimmed when the memory budget is nearly used up
+/// Both keep the newest `keep_last` transfers and fees of every client in memory, along with the
+/// settled disputes and memos of the transfers kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PressureAction {
+    /// Older transfers are moved to the spill archive attached to the table, where they can still
+    /// be recalled for late disputes. Without an archive, or if writing it fails, this prunes instead
+    Spill { keep_last: usize },
+    /// Older transfers are dropped and can no longer be disputed
+    Prune { keep_last: usize },
+}
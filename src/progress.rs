@@ -0,0 +1,239 @@
+use std::{
+    cell::Cell,
+    fmt,
+    io::{self, BufRead, Read, Write},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::payment_engine::ClientTable;
+
+/// Where a run stands in its input, saved with the checkpoints so processing can resume from there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputOffset {
+    /// Bytes of the feed consumed, the inputs counted one after the other once decompressed
+    pub bytes: u64,
+    /// Number of the next line
+    pub line: usize,
+}
+
+impl Default for InputOffset {
+    fn default() -> Self {
+        Self { bytes: 0, line: 1 }
+    }
+}
+
+/// Reader counting the bytes consumed from it, which the records read through it end at
+pub struct Counted<R> {
+    inner: R,
+    consumed: Rc<Cell<u64>>,
+}
+
+impl<R: BufRead> Counted<R> {
+    /// Counts from `start`, the bytes already consumed before `inner` was handed over
+    pub fn new(inner: R, start: u64) -> Self {
+        Self {
+            inner,
+            consumed: Rc::new(Cell::new(start)),
+        }
+    }
+
+    /// Handle on the count, which keeps following the reader once it is moved into a parser
+    pub fn counter(&self) -> Rc<Cell<u64>> {
+        Rc::clone(&self.consumed)
+    }
+}
+
+impl<R: BufRead> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.set(self.consumed.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counted<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed.set(self.consumed.get() + amt as u64);
+        self.inner.consume(amt)
+    }
+}
+
+/// Discards the first `bytes` of `reader`, to resume a feed at the offset of a checkpoint. The
+/// input is read rather than seeked so compressed and concatenated inputs resume the same way
+pub fn skip_bytes<R: BufRead>(reader: &mut R, bytes: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(bytes), &mut io::sink())?;
+    if skipped < bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "the input ends at byte {}, before offset {}",
+                skipped, bytes
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Periodic report of how far a run got through its input
+pub struct Progress {
+    every: Duration,
+    /// Size of the feed, if it is known upfront
+    total: Option<u64>,
+    /// Offset the run started at, the rates only count what this run read
+    from: InputOffset,
+    started: Instant,
+    last: Instant,
+}
+
+impl Progress {
+    pub fn new(every: Duration, total: Option<u64>, from: InputOffset) -> Self {
+        let now = Instant::now();
+        Self {
+            every,
+            total,
+            from,
+            started: now,
+            last: now,
+        }
+    }
+
+    /// Writes a progress line to `w` if `every` has passed since the previous one
+    pub fn tick<W: Write>(&mut self, mut w: W, at: InputOffset) -> io::Result<()> {
+        let now = Instant::now();
+        if now.duration_since(self.last) < self.every {
+            return Ok(());
+        }
+        self.last = now;
+        writeln!(w, "{}", self.line(at, now.duration_since(self.started)))
+    }
+
+    /// The line reporting `at`, `elapsed` after the start
+    fn line(&self, at: InputOffset, elapsed: Duration) -> ProgressLine {
+        ProgressLine {
+            at,
+            lines_per_sec: at.line.saturating_sub(self.from.line) as f64 / seconds(elapsed),
+            total: self.total,
+            bytes_per_sec: at.bytes.saturating_sub(self.from.bytes) as f64 / seconds(elapsed),
+        }
+    }
+}
+
+fn seconds(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+struct ProgressLine {
+    at: InputOffset,
+    lines_per_sec: f64,
+    total: Option<u64>,
+    bytes_per_sec: f64,
+}
+
+/// `progress: line 120000, byte 4800000 of 12000000 (40.0%), 25000 lines/s, eta 9s`, the size and
+/// the estimate are left out when the size of the feed is not known
+impl fmt::Display for ProgressLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "progress: line {}, byte {}", self.at.line, self.at.bytes)?;
+        let total = self.total.filter(|&total| total > 0);
+        if let Some(total) = total {
+            let done = self.at.bytes.min(total) as f64;
+            write!(f, " of {} ({:.1}%)", total, done * 100.0 / total as f64)?;
+        }
+        write!(f, ", {:.0} lines/s", self.lines_per_sec)?;
+        match total {
+            Some(total) if self.bytes_per_sec > 0.0 => {
+                let left = total.saturating_sub(self.at.bytes) as f64;
+                write!(f, ", eta {:.0}s", left / self.bytes_per_sec)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ClientTable {
+    /// Where the input was when the snapshot this table was restored from was taken, see
+    /// `checkpoint`
+    pub fn input_offset(&self) -> Option<InputOffset> {
+        self.input_offset
+    }
+
+    /// Sets the input offset the next snapshot records
+    pub fn set_input_offset(&mut self, at: Option<InputOffset>) {
+        self.input_offset = at;
+    }
+
+    /// Whether a batch is being collected, its records are read but not applied until it commits
+    pub fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::CompatCheck;
+
+    #[test]
+    fn counts_the_bytes_of_the_records_read() {
+        let feed = "type, client, tx, amount\r\ndeposit, 1, 1, 1.0\r\ndeposit, 1, 2, 2.0\n";
+        let mut reader = Counted::new(feed.as_bytes(), 0);
+        let counter = reader.counter();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(counter.get(), 26);
+        let mut lines = reader.lines();
+        lines.next().unwrap().unwrap();
+        assert_eq!(counter.get(), 46);
+
+        let mut resumed = feed.as_bytes();
+        skip_bytes(&mut resumed, 46).unwrap();
+        assert_eq!(resumed, b"deposit, 1, 2, 2.0\n");
+        assert!(skip_bytes(&mut feed.as_bytes(), 100).is_err());
+    }
+
+    #[test]
+    fn checkpoints_record_the_input_offset() {
+        let path = std::env::temp_dir().join(format!("bank_checkpoint_{}.csv", std::process::id()));
+        let at = InputOffset {
+            bytes: 4000,
+            line: 41,
+        };
+        let table = ClientTable::new();
+        table.checkpoint(&path, at).unwrap();
+        let mut restored = ClientTable::new();
+        restored.restore(&path, CompatCheck::Strict).unwrap();
+        assert_eq!(restored.input_offset(), Some(at));
+        // A plain snapshot of a table that doesn't track its input has no offset
+        table.snapshot(&path).unwrap();
+        restored.restore(&path, CompatCheck::Strict).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.input_offset(), None);
+    }
+
+    #[test]
+    fn progress_lines() {
+        let from = InputOffset {
+            bytes: 1000,
+            line: 11,
+        };
+        let at = InputOffset {
+            bytes: 4000,
+            line: 41,
+        };
+        let progress = Progress::new(Duration::from_secs(1), Some(10000), from);
+        assert_eq!(
+            progress.line(at, Duration::from_secs(2)).to_string(),
+            "progress: line 41, byte 4000 of 10000 (40.0%), 15 lines/s, eta 4s"
+        );
+        let progress = Progress::new(Duration::from_secs(1), None, from);
+        assert_eq!(
+            progress.line(at, Duration::from_secs(2)).to_string(),
+            "progress: line 41, byte 4000, 15 lines/s"
+        );
+    }
+}
@@ -0,0 +1,342 @@
+// The code the pyo3 macros generate converts the error of every method, even a `PyErr`
+#![allow(clippy::useless_conversion)]
+
+use std::{fs::File, io::BufReader};
+
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyDict, PyList},
+};
+
+use crate::{
+    client_info::ClientInfo,
+    csv_parser::{self, Header, Records},
+    currency::{Currency, CurrencyConfig, Rounding},
+    payment_engine::ClientTable,
+    transaction::{ClientId, Transaction, TxId},
+};
+
+fn currency_config(decimals: u32) -> PyResult<CurrencyConfig> {
+    CurrencyConfig::new(decimals, Rounding::default())
+        .ok_or_else(|| PyValueError::new_err(format!("too many decimals: {}", decimals)))
+}
+
+fn value_error(e: impl std::fmt::Debug) -> PyErr {
+    PyValueError::new_err(format!("{:?}", e))
+}
+
+/// Amounts as `decimal.Decimal`, which keeps them exact in pandas columns
+fn decimal<'py>(py: Python<'py>, text: String) -> PyResult<Bound<'py, PyAny>> {
+    py.import_bound("decimal")?
+        .getattr("Decimal")?
+        .call1((text,))
+}
+
+/// Sets the balances of `info` in `dict`
+fn set_balances(
+    dict: &Bound<'_, PyDict>,
+    info: &ClientInfo,
+    currency: CurrencyConfig,
+) -> PyResult<()> {
+    let py = dict.py();
+    let amount = |amount| decimal(py, currency.display(amount).to_string());
+    dict.set_item("available", amount(info.available_funds())?)?;
+    dict.set_item("held", amount(info.held_funds())?)?;
+    dict.set_item("total", amount(info.total_funds().map_err(value_error)?)?)?;
+    dict.set_item("locked", info.is_locked())
+}
+
+/// Amount with a fixed number of decimals, `Currency("2.5", decimals=4)`
+#[pyclass(name = "Currency", module = "bank", frozen, eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PyCurrency {
+    amount: Currency,
+    currency: CurrencyConfig,
+}
+
+#[pymethods]
+impl PyCurrency {
+    #[new]
+    #[pyo3(signature = (amount, decimals = 4))]
+    fn new(amount: &str, decimals: u32) -> PyResult<Self> {
+        let currency = currency_config(decimals)?;
+        Ok(Self {
+            amount: currency.parse(amount).map_err(value_error)?,
+            currency,
+        })
+    }
+
+    /// Number of units of 10^-decimals
+    #[getter]
+    fn units(&self) -> i128 {
+        self.amount.to_i128()
+    }
+
+    #[getter]
+    fn decimals(&self) -> u32 {
+        self.currency.decimals()
+    }
+
+    fn as_decimal<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        decimal(py, self.__str__())
+    }
+
+    fn __str__(&self) -> String {
+        self.currency.display(self.amount).to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Currency('{}', decimals={})",
+            self.__str__(),
+            self.decimals()
+        )
+    }
+}
+
+/// Transaction record, `Transaction("deposit", 1, 1, "2.5")`
+#[pyclass(name = "Transaction", module = "bank", frozen, eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PyTransaction {
+    transaction: Transaction,
+    currency: CurrencyConfig,
+}
+
+#[pymethods]
+impl PyTransaction {
+    #[new]
+    #[pyo3(signature = (kind, client, tx, amount = None, decimals = 4))]
+    fn new(
+        kind: &str,
+        client: ClientId,
+        tx: TxId,
+        amount: Option<&str>,
+        decimals: u32,
+    ) -> PyResult<Self> {
+        let currency = currency_config(decimals)?;
+        let transaction = csv_parser::parse_record(
+            Some(kind),
+            Some(&client.to_string()),
+            Some(&tx.to_string()),
+            amount,
+            None,
+            currency,
+        )
+        .map_err(value_error)?;
+        Ok(Self {
+            transaction,
+            currency,
+        })
+    }
+
+    /// Parses a csv record such as `deposit, 1, 1, 2.5`
+    #[staticmethod]
+    #[pyo3(signature = (record, decimals = 4))]
+    fn from_csv(record: &str, decimals: u32) -> PyResult<Self> {
+        let currency = currency_config(decimals)?;
+        let transaction =
+            csv_parser::parse_line_with(Ok(record.to_string()), currency).map_err(value_error)?;
+        Ok(Self {
+            transaction,
+            currency,
+        })
+    }
+
+    #[getter]
+    fn kind(&self) -> &'static str {
+        self.transaction.kind_name()
+    }
+
+    #[getter]
+    fn client(&self) -> ClientId {
+        self.transaction.client()
+    }
+
+    #[getter]
+    fn tx(&self) -> TxId {
+        self.transaction.tx()
+    }
+
+    #[getter]
+    fn amount(&self) -> Option<PyCurrency> {
+        self.transaction.record_amount().map(|amount| PyCurrency {
+            amount,
+            currency: self.currency,
+        })
+    }
+
+    fn __repr__(&self) -> String {
+        let amount = self.amount().map(|a| format!(", '{}'", a.__str__()));
+        format!(
+            "Transaction('{}', {}, {}{})",
+            self.kind(),
+            self.client(),
+            self.tx(),
+            amount.unwrap_or_default()
+        )
+    }
+}
+
+/// The engine with the production rules, fed one transaction or a whole csv file at a time
+#[pyclass(name = "PaymentEngine", module = "bank")]
+pub struct PyPaymentEngine {
+    table: ClientTable,
+}
+
+#[pymethods]
+impl PyPaymentEngine {
+    #[new]
+    #[pyo3(signature = (decimals = 4))]
+    fn new(decimals: u32) -> PyResult<Self> {
+        let table = ClientTable::builder()
+            .currency(currency_config(decimals)?)
+            .build()
+            .map_err(value_error)?;
+        Ok(Self { table })
+    }
+
+    /// Applies `transaction`, returns whether it was applied or rejected
+    fn submit(&mut self, transaction: &PyTransaction) -> PyResult<bool> {
+        if transaction.currency.decimals() != self.table.currency_config().decimals() {
+            return Err(PyValueError::new_err(
+                "the transaction has a different number of decimals than the engine",
+            ));
+        }
+        Ok(self
+            .table
+            .handle_transaction(transaction.transaction)
+            .is_ok())
+    }
+
+    /// Applies the records of the csv file at `path`, the header is optional. Returns the number of
+    /// applied and rejected transactions, a record that can't be parsed raises before any is applied
+    fn process_csv(&mut self, path: &str) -> PyResult<(usize, usize)> {
+        let currency = self.table.currency_config();
+        let mut reader = BufReader::new(File::open(path)?);
+        csv_parser::skip_header(&mut reader, Header::Detect)?;
+        let transactions = Records::new(reader)
+            .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+            .map(|l| csv_parser::parse_line_with(l, currency).map_err(value_error))
+            .collect::<PyResult<Vec<_>>>()?;
+        let summary = self.table.process(transactions);
+        Ok((summary.applied, summary.rejected))
+    }
+
+    /// Balances of `client` as a dict, `None` if the client hasn't been seen
+    fn balance<'py>(
+        &self,
+        py: Python<'py>,
+        client: ClientId,
+    ) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let currency = self.table.currency_config();
+        self.table
+            .client(client)
+            .map(|info| {
+                let balance = PyDict::new_bound(py);
+                set_balances(&balance, info, currency)?;
+                Ok(balance)
+            })
+            .transpose()
+    }
+
+    /// The report as the command line tool writes it
+    fn report_csv(&self) -> PyResult<String> {
+        let mut out = Vec::new();
+        self.table.write_csv(&mut out)?;
+        Ok(String::from_utf8(out).expect("the report is utf-8"))
+    }
+
+    /// The report as a list of dicts, one per row, which `pandas.DataFrame` takes as is
+    fn report_records<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let records = PyList::empty_bound(py);
+        for row in self.table.report_rows().map_err(value_error)? {
+            let currency = self.table.currency_config().in_currency(row.code);
+            let record = PyDict::new_bound(py);
+            record.set_item("client", row.client)?;
+            if self.table.multi_currency() {
+                record.set_item("currency", row.code.map(|code| code.to_string()))?;
+            }
+            set_balances(&record, row.info, currency)?;
+            records.append(record)?;
+        }
+        Ok(records)
+    }
+
+    /// The report as a `pandas.DataFrame` indexed by client, pandas has to be installed
+    fn to_pandas<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let frame = py
+            .import_bound("pandas")?
+            .getattr("DataFrame")?
+            .call1((self.report_records(py)?,))?;
+        frame.call_method1("set_index", ("client",))
+    }
+}
+
+/// Engine after the records of the csv file at `path`
+#[pyfunction]
+#[pyo3(signature = (path, decimals = 4))]
+fn process_csv(path: &str, decimals: u32) -> PyResult<PyPaymentEngine> {
+    let mut engine = PyPaymentEngine::new(decimals)?;
+    engine.process_csv(path)?;
+    Ok(engine)
+}
+
+/// Python module `bank`, the shared library built with the `python` feature renamed to `bank.so`
+#[pymodule]
+fn bank(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyCurrency>()?;
+    m.add_class::<PyTransaction>()?;
+    m.add_class::<PyPaymentEngine>()?;
+    m.add_function(wrap_pyfunction!(process_csv, m)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn python_module() {
+        pyo3::prepare_freethreaded_python();
+        let path = env::temp_dir().join(format!("bank_python_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 9\n",
+        )
+        .unwrap();
+        Python::with_gil(|py| {
+            let module = PyModule::new_bound(py, "bank").unwrap();
+            bank(&module).unwrap();
+            let globals = PyDict::new_bound(py);
+            globals.set_item("bank", module).unwrap();
+            globals.set_item("path", path.to_str().unwrap()).unwrap();
+            py.run_bound(
+                r#"
+from decimal import Decimal
+engine = bank.process_csv(path)
+assert engine.report_records() == [
+    {"client": 1, "available": Decimal("2.5"), "held": Decimal("0"), "total": Decimal("2.5"), "locked": False}
+]
+assert engine.submit(bank.Transaction("deposit", 2, 3, "1.25"))
+assert not engine.submit(bank.Transaction.from_csv("withdrawal, 2, 4, 5"))
+assert engine.balance(2)["available"] == Decimal("1.25")
+assert engine.balance(3) is None
+assert engine.report_csv().splitlines()[2] == "2, 1.2500, 0.0000, 1.2500, false"
+amount = bank.Transaction("deposit", 2, 3, "1.25").amount
+assert (amount.units, str(amount)) == (12500, "1.2500")
+assert bank.Currency("1.5", decimals=2) == bank.Currency("1.50", decimals=2)
+try:
+    bank.Transaction("deposit", 2, 3, "-1")
+    raise AssertionError("negative amount accepted")
+except ValueError:
+    pass
+"#,
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    client_info::ClientInfo,
+    currency::{Currency, CurrencyConfig},
+    transaction::ClientId,
+};
+
+/// Filter over the client accounts, compiled from expressions such as
+/// `held > 100 && locked == false` or `!(client == 3 || total <= 0)`
+///
+/// Comparisons take one of the report columns `client`, `available`, `held`, `total` or `locked`
+/// on the left and a literal on the right, and combine with `&&`, `||`, `!` and parentheses.
+/// `&&` binds tighter than `||`. Amounts are read at the precision given to `compile`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Compare(Field, Op, Value),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Client(ClientId),
+    Amount(Currency),
+    Bool(bool),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueryError(String);
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid query: {}", self.0)
+    }
+}
+
+impl Query {
+    pub fn compile(expression: &str, currency: CurrencyConfig) -> Result<Self, QueryError> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            currency,
+        };
+        let query = parser.or()?;
+        match parser.tokens.first() {
+            None => Ok(query),
+            Some(token) => Err(QueryError(format!("unexpected `{}`", token))),
+        }
+    }
+
+    pub fn matches(&self, client: ClientId, info: &ClientInfo) -> bool {
+        match self {
+            Query::Compare(field, op, value) => {
+                let ordering = match (field, value) {
+                    (Field::Client, Value::Client(v)) => client.cmp(v),
+                    (Field::Available, Value::Amount(v)) => info.available_funds().cmp(v),
+                    (Field::Held, Value::Amount(v)) => info.held_funds().cmp(v),
+                    // A total that doesn't fit fails the report anyway
+                    (Field::Total, Value::Amount(v)) => match info.total_funds() {
+                        Ok(total) => total.cmp(v),
+                        Err(_) => return false,
+                    },
+                    (Field::Locked, Value::Bool(v)) => info.is_locked().cmp(v),
+                    // Mismatched types are rejected by `compile`
+                    _ => return false,
+                };
+                match op {
+                    Op::Eq => ordering.is_eq(),
+                    Op::Ne => ordering.is_ne(),
+                    Op::Lt => ordering.is_lt(),
+                    Op::Le => ordering.is_le(),
+                    Op::Gt => ordering.is_gt(),
+                    Op::Ge => ordering.is_ge(),
+                }
+            }
+            Query::Not(query) => !query.matches(client, info),
+            Query::And(lhs, rhs) => lhs.matches(client, info) && rhs.matches(client, info),
+            Query::Or(lhs, rhs) => lhs.matches(client, info) || rhs.matches(client, info),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<&str>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let len = if let Some(op) = ["&&", "||", "==", "!=", "<=", ">="]
+            .iter()
+            .find(|op| rest.starts_with(*op))
+        {
+            op.len()
+        } else if rest.starts_with(|c| "()<>!".contains(c)) {
+            1
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                let c = rest.chars().next().expect("rest is not empty");
+                return Err(QueryError(format!("unexpected `{}`", c)));
+            }
+            len
+        };
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser consuming the tokens from the front
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    currency: CurrencyConfig,
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Result<&'a str, QueryError> {
+        let (first, rest) = self
+            .tokens
+            .split_first()
+            .ok_or_else(|| QueryError("unexpected end of expression".to_string()))?;
+        self.tokens = rest;
+        Ok(first)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.tokens.first() == Some(&token) {
+            self.tokens = &self.tokens[1..];
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.and()?;
+        while self.eat("||") {
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.unary()?;
+        while self.eat("&&") {
+            query = Query::And(Box::new(query), Box::new(self.unary()?));
+        }
+        Ok(query)
+    }
+
+    fn unary(&mut self) -> Result<Query, QueryError> {
+        if self.eat("!") {
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let query = self.or()?;
+            if !self.eat(")") {
+                return Err(QueryError("missing `)`".to_string()));
+            }
+            return Ok(query);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Query, QueryError> {
+        let field = match self.next()? {
+            "client" => Field::Client,
+            "available" => Field::Available,
+            "held" => Field::Held,
+            "total" => Field::Total,
+            "locked" => Field::Locked,
+            other => return Err(QueryError(format!("unknown column `{}`", other))),
+        };
+        let op = match self.next()? {
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            other => {
+                return Err(QueryError(format!(
+                    "expected a comparison, found `{}`",
+                    other
+                )))
+            }
+        };
+        let literal = self.next()?;
+        let invalid = || QueryError(format!("invalid value `{}`", literal));
+        let value = match field {
+            Field::Client => Value::Client(ClientId::from_str(literal).map_err(|_| invalid())?),
+            Field::Available | Field::Held | Field::Total => {
+                Value::Amount(self.currency.parse(literal).map_err(|_| invalid())?)
+            }
+            Field::Locked => Value::Bool(literal.parse().map_err(|_| invalid())?),
+        };
+        Ok(Query::Compare(field, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{payment_engine::ClientTable, transaction::Transaction};
+
+    fn query(expression: &str) -> Result<Query, QueryError> {
+        Query::compile(expression, CurrencyConfig::default())
+    }
+
+    #[test]
+    fn precedence_and_grouping() {
+        let compare = |field, value| Box::new(Query::Compare(field, Op::Eq, value));
+        let a = compare(Field::Client, Value::Client(1));
+        let b = compare(Field::Locked, Value::Bool(true));
+        let c = compare(Field::Held, Value::Amount(Currency::new(0)));
+        assert_eq!(
+            query("client == 1 || locked == true && held == 0").unwrap(),
+            Query::Or(a.clone(), Box::new(Query::And(b.clone(), c.clone())))
+        );
+        assert_eq!(
+            query("!(client==1||locked==true)&&held==0").unwrap(),
+            Query::And(Box::new(Query::Not(Box::new(Query::Or(a, b)))), c)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "",
+            "held >",
+            "held > 1 &&",
+            "balance > 1",
+            "held = 1",
+            "locked == 1",
+            "client == -1",
+            "(held > 1",
+            "held > 1)",
+            "held > 1 $",
+        ]
+        .iter()
+        {
+            assert!(query(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn non_ascii_characters_are_a_parse_error() {
+        assert_eq!(
+            query("held > €"),
+            Err(QueryError("unexpected `€`".to_string()))
+        );
+    }
+
+    #[test]
+    fn filters_clients() {
+        let mut table = ClientTable::new();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(2_000_000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: Currency::new(10000),
+            },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: Currency::new(5_000_000),
+            },
+            Transaction::Dispute { client: 3, tx: 3 },
+            Transaction::Chargeback { client: 3, tx: 3 },
+        ]);
+        let matching = |expression| {
+            let query = query(expression).unwrap();
+            table
+                .clients()
+                .filter(|(client, info)| query.matches(*client, info))
+                .map(|(client, _)| client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching("held > 100 && locked == false"), [1]);
+        assert_eq!(matching("locked == true || available >= 1"), [2, 3]);
+        assert_eq!(matching("!(client != 2)"), [2]);
+        assert_eq!(matching("total < 0.5"), [3]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+};
+
+use crate::client_info::TransactionError;
+
+/// Reason kind of the records that couldn't be parsed
+pub const PARSE_ERROR: &str = "parse error";
+
+/// Why a record ended up in the rejects file
+#[derive(Debug)]
+pub enum RejectReason<'a> {
+    /// The record couldn't be parsed into a transaction
+    Parse(&'a io::Error),
+    /// The engine refused the transaction
+    Engine(TransactionError),
+}
+
+impl RejectReason<'_> {
+    /// Short name of the reason, used to group the rejects in the summary
+    pub fn kind(&self) -> String {
+        match self {
+            RejectReason::Parse(_) => PARSE_ERROR.to_string(),
+            RejectReason::Engine(e) => format!("{:?}", e),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Parse(e) => write!(f, "parse error: {}", e),
+            RejectReason::Engine(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+/// Csv output collecting every rejected record with the line it starts on and the reason it was
+/// rejected, the record itself is written back as it was read
+pub struct Rejects<W: Write> {
+    out: W,
+    /// Number of rejects per `RejectReason::kind`
+    counts: BTreeMap<String, usize>,
+}
+
+impl<W: Write> Rejects<W> {
+    /// Writes the header right away, so a run without rejects still leaves a valid file
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "line, reason, record")?;
+        Ok(Self {
+            out,
+            counts: BTreeMap::new(),
+        })
+    }
+
+    pub fn reject(
+        &mut self,
+        line: usize,
+        record: &str,
+        reason: RejectReason<'_>,
+    ) -> io::Result<()> {
+        *self.counts.entry(reason.kind()).or_default() += 1;
+        writeln!(
+            self.out,
+            "{}, {}, {}",
+            line,
+            quote(&reason.to_string()),
+            quote(record)
+        )
+    }
+
+    /// Total number of rejected records
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// Number of rejected records per reason
+    pub fn counts(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.counts
+            .iter()
+            .map(|(kind, &count)| (kind.as_str(), count))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// One line summary of the rejects, such as `3 rejected: 1 Overdraw, 2 parse error`
+impl<W: Write> fmt::Display for Rejects<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rejected", self.total())?;
+        for (i, (kind, count)) in self.counts().enumerate() {
+            let separator = if i == 0 { ":" } else { "," };
+            write!(f, "{} {} {}", separator, count, kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a field following RFC 4180 so it can be read back by `split_fields`
+pub(crate) fn quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// Quotes `field` like `quote` only if it has a comma, a quote or a line break
+pub(crate) fn quote_if_needed(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(quote(field))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_parser::split_fields;
+
+    #[test]
+    fn records_are_written_back_with_their_reason() {
+        let mut rejects = Rejects::new(Vec::new()).unwrap();
+        let parse_error = io::Error::new(io::ErrorKind::InvalidInput, "UnknownRecord");
+        rejects
+            .reject(2, "bogus, 1", RejectReason::Parse(&parse_error))
+            .unwrap();
+        rejects
+            .reject(
+                5,
+                "withdrawal, 1, 4, 9.0",
+                RejectReason::Engine(TransactionError::Overdraw),
+            )
+            .unwrap();
+        assert_eq!(rejects.to_string(), "2 rejected: 1 Overdraw, 1 parse error");
+        let out = String::from_utf8(rejects.into_inner()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "line, reason, record");
+        assert_eq!(
+            split_fields(lines[1]).unwrap(),
+            ["2", "parse error: UnknownRecord", "bogus, 1"]
+        );
+        assert_eq!(
+            split_fields(lines[2]).unwrap(),
+            ["5", "Overdraw", "withdrawal, 1, 4, 9.0"]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    csv_parser::{skip_header, Header, Records},
+    rng::Rng,
+};
+
+/// How the records of a replay arrive at the server
+///
+/// The default posts batches back to back, the idealized firehose. Real feeds arrive with
+/// network jitter and in bursts, which is what the other knobs are for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArrivalProfile {
+    /// Records posted per request
+    pub batch: usize,
+    /// Mean time between the starts of two requests
+    pub interval: Duration,
+    /// Largest deviation from `interval`, drawn uniformly on either side of it
+    pub jitter: Duration,
+    /// After `burst_every` paced requests the next `burst_len` ones go out without any pause,
+    /// 0 disables bursts
+    pub burst_every: u32,
+    pub burst_len: u32,
+    /// Seed of the jitter, so a run can be repeated
+    pub seed: u64,
+}
+
+impl Default for ArrivalProfile {
+    fn default() -> Self {
+        Self {
+            batch: 100,
+            interval: Duration::ZERO,
+            jitter: Duration::ZERO,
+            burst_every: 0,
+            burst_len: 0,
+            seed: 0,
+        }
+    }
+}
+
+impl ArrivalProfile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads `key = value` lines, `#` starts a comment. Keys left out keep their default, the
+    /// durations take a `us`, `ms` or `s` suffix:
+    ///
+    /// ```text
+    /// batch = 50
+    /// interval = 20ms
+    /// jitter = 15ms
+    /// # 10 requests at once after every 200 paced ones
+    /// burst_every = 200
+    /// burst_len = 10
+    /// seed = 7
+    /// ```
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut profile = Self::default();
+        for line in reader.lines() {
+            let line = line?;
+            let setting = line.split('#').next().unwrap_or_default().trim();
+            if setting.is_empty() {
+                continue;
+            }
+            let (key, value) = match setting.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(invalid_setting(&line)),
+            };
+            let set = match key {
+                "batch" => value
+                    .parse()
+                    .ok()
+                    .filter(|&batch| batch > 0)
+                    .map(|batch| profile.batch = batch),
+                "interval" => parse_duration(value).map(|d| profile.interval = d),
+                "jitter" => parse_duration(value).map(|d| profile.jitter = d),
+                "burst_every" => value.parse().ok().map(|n| profile.burst_every = n),
+                "burst_len" => value.parse().ok().map(|n| profile.burst_len = n),
+                "seed" => value.parse().ok().map(|seed| profile.seed = seed),
+                _ => None,
+            };
+            set.ok_or_else(|| invalid_setting(&line))?;
+        }
+        Ok(profile)
+    }
+
+    /// Endless sequence of the pauses before each request, the first one goes out right away
+    pub fn delays(&self) -> Delays {
+        Delays {
+            profile: *self,
+            rng: Rng::new(self.seed),
+            sent: 0,
+        }
+    }
+}
+
+/// Pauses between requests drawn from an `ArrivalProfile`
+#[derive(Clone, Debug)]
+pub struct Delays {
+    profile: ArrivalProfile,
+    rng: Rng,
+    sent: u64,
+}
+
+impl Iterator for Delays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let p = &self.profile;
+        let n = self.sent;
+        self.sent += 1;
+        let cycle = u64::from(p.burst_every) + u64::from(p.burst_len);
+        if n == 0 || (p.burst_every > 0 && n % cycle >= u64::from(p.burst_every)) {
+            return Some(Duration::ZERO);
+        }
+        let jitter = p.jitter.as_nanos() as i128;
+        let offset = self.rng.below(2 * jitter as u64 + 1) as i128 - jitter;
+        let nanos = (p.interval.as_nanos() as i128 + offset).max(0);
+        Some(Duration::from_nanos(nanos as u64))
+    }
+}
+
+/// Outcome of a replay
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub requests: usize,
+    pub records: usize,
+    /// Records the engine refused
+    pub rejected: usize,
+    pub elapsed: Duration,
+    /// Longest time a request took to be answered
+    pub slowest: Duration,
+    /// Time spent waiting for answers, over all requests
+    pub waited: Duration,
+}
+
+impl fmt::Display for ReplayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mean = self.waited / (self.requests.max(1) as u32);
+        write!(
+            f,
+            "{} records in {} requests, {} rejected, in {:.2?} ({:.0} records/s), requests took {:.2?} on average and {:.2?} at worst",
+            self.records,
+            self.requests,
+            self.rejected,
+            self.elapsed,
+            self.records as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+            mean,
+            self.slowest
+        )
+    }
+}
+
+/// Posts the csv records read from `reader` to the `POST /transactions` endpoint of a `Server`
+/// at `addr`, paced by `profile`
+///
+/// Requests are scheduled from the start of the replay rather than from the previous answer, so a
+/// slow server doesn't slow the arrivals down; a request that is due while the previous one is
+/// still being answered goes out right after it. Stops at the first request the server doesn't
+/// answer with `200 OK`
+pub fn replay<A: ToSocketAddrs, R: BufRead>(
+    addr: A,
+    mut reader: R,
+    header: Header,
+    profile: &ArrivalProfile,
+) -> io::Result<ReplayStats> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to replay to"))?;
+    skip_header(&mut reader, header)?;
+    let mut records = Records::new(reader).peekable();
+    let mut stats = ReplayStats::default();
+    let started = Instant::now();
+    let mut due = started;
+    for delay in profile.delays() {
+        if records.peek().is_none() {
+            break;
+        }
+        let mut body = String::new();
+        for line in records.by_ref().take(profile.batch) {
+            body.push_str(&line?);
+            body.push('\n');
+            stats.records += 1;
+        }
+        due += delay;
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        let sent = Instant::now();
+        let answer = post_transactions(addr, &body)?;
+        let took = sent.elapsed();
+        stats.requests += 1;
+        stats.waited += took;
+        stats.slowest = stats.slowest.max(took);
+        stats.rejected += answer.lines().filter(|l| l.contains(", rejected ")).count();
+    }
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Returns the body of the answer
+fn post_transactions(addr: std::net::SocketAddr, body: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "POST /transactions HTTP/1.1\r\nContent-Type: text/csv\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(io::Error::other(format!(
+            "server answered {}: {}",
+            status,
+            body.trim()
+        )));
+    }
+    Ok(body.to_string())
+}
+
+/// `us`, `ms` or `s` suffixed number
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: u64 = value[..split].parse().ok()?;
+    match value[split..].trim() {
+        "us" => Some(Duration::from_micros(amount)),
+        "ms" => Some(Duration::from_millis(amount)),
+        "s" => Some(Duration::from_secs(amount)),
+        _ => None,
+    }
+}
+
+fn invalid_setting(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid arrival setting: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cancel::CancellationToken, payment_engine::ClientTable, server::Server};
+
+    #[test]
+    fn delays_follow_the_profile() {
+        let profile = ArrivalProfile::read(
+            "interval = 10ms\njitter = 4ms # around the interval\nburst_every = 3\nburst_len = 2\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let delays: Vec<_> = profile.delays().take(11).collect();
+        assert_eq!(delays[0], Duration::ZERO);
+        for (i, delay) in delays.iter().enumerate().skip(1) {
+            if i % 5 >= 3 {
+                assert_eq!(*delay, Duration::ZERO, "request {} is part of a burst", i);
+            } else {
+                assert!((6..=14).contains(&delay.as_millis()), "{:?}", delay);
+            }
+        }
+        assert_eq!(delays, profile.delays().take(11).collect::<Vec<_>>());
+        assert!(ArrivalProfile::read("jitter = 5 minutes".as_bytes()).is_err());
+        assert!(ArrivalProfile::read("batch = 0".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn records_are_replayed_to_the_server() {
+        let server = Server::bind("127.0.0.1:0", ClientTable::new()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let token = CancellationToken::new();
+        let running = {
+            let token = token.clone();
+            thread::spawn(move || server.run(&token))
+        };
+        let profile = ArrivalProfile {
+            batch: 2,
+            interval: Duration::from_millis(1),
+            jitter: Duration::from_millis(1),
+            ..ArrivalProfile::default()
+        };
+        let feed = "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0\nwithdrawal, 1, 3, 5.0\n";
+        let stats = replay(addr, feed.as_bytes(), Header::Detect, &profile).unwrap();
+        token.cancel();
+        let table = running.join().unwrap().unwrap();
+        assert_eq!((stats.requests, stats.records, stats.rejected), (2, 3, 1));
+        assert_eq!(table.clients().count(), 2);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    cmp::Reverse,
+    io::{self, Write},
+    ops::RangeInclusive,
+};
+
+use crate::{
+    client_info::ClientInfo,
+    payment_engine::{ClientTable, ReportRow},
+    query::Query,
+    transaction::ClientId,
+};
+
+/// Order of the accounts in a report
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportOrder {
+    /// By client id, the order of the regular report
+    #[default]
+    Client,
+    /// Largest total funds first, ties by client id. The totals of accounts in different
+    /// currencies are compared as they are
+    Total,
+}
+
+impl ReportOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "client" => Some(ReportOrder::Client),
+            "total" => Some(ReportOrder::Total),
+            _ => None,
+        }
+    }
+}
+
+/// Which accounts a report lists and in which order, see `ClientTable::write_csv_with`
+///
+/// The filters combine, an account is listed if it passes all of them. `top` is applied last, to
+/// the filtered and sorted accounts
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReportOptions {
+    pub order: ReportOrder,
+    /// Only lists the locked accounts
+    pub locked_only: bool,
+    /// Only lists the clients in one of these ranges, every client if empty
+    pub clients: Vec<RangeInclusive<ClientId>>,
+    /// Only lists the accounts matching the query
+    pub query: Option<Query>,
+    /// Lists at most this many accounts
+    pub top: Option<usize>,
+}
+
+impl ReportOptions {
+    /// Reads client ranges such as `1-100,200,300-400`, `None` if one is invalid or empty
+    pub fn parse_clients(ranges: &str) -> Option<Vec<RangeInclusive<ClientId>>> {
+        ranges
+            .split(',')
+            .map(|range| {
+                let (first, last) = range.split_once('-').unwrap_or((range, range));
+                let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+                Some(first..=last).filter(|r| !r.is_empty())
+            })
+            .collect()
+    }
+
+    /// Whether the report lists the account of `client`, before `top` is applied
+    pub fn keeps(&self, client: ClientId, info: &ClientInfo) -> bool {
+        (!self.locked_only || info.is_locked())
+            && (self.clients.is_empty() || self.clients.iter().any(|r| r.contains(&client)))
+            && self.query.as_ref().is_none_or(|q| q.matches(client, info))
+    }
+}
+
+impl ClientTable {
+    /// Same as `write_csv` with the accounts filtered and ordered according to `options`
+    pub fn write_csv_with<W: Write>(&self, mut w: W, options: &ReportOptions) -> io::Result<()> {
+        let rows = self.report_rows_with(options)?;
+        let mut report = String::new();
+        self.fmt_report(&mut report, &rows, &|_, _| true)
+            .expect("formatting into a String can't fail");
+        w.write_all(report.as_bytes())?;
+        w.flush()
+    }
+
+    /// Rows of the report the options ask for, in their order
+    pub(crate) fn report_rows_with(
+        &self,
+        options: &ReportOptions,
+    ) -> io::Result<Vec<ReportRow<'_>>> {
+        let mut rows = self.report_rows()?;
+        rows.retain(|row| options.keeps(row.client, row.info));
+        if options.order == ReportOrder::Total {
+            // Stable, so equal totals stay in client order
+            rows.sort_by_key(|row| Reverse(row.total));
+        }
+        if let Some(top) = options.top {
+            rows.truncate(top);
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{currency::Currency, transaction::Transaction};
+
+    #[test]
+    fn reports_are_filtered_sorted_and_cut() {
+        let mut table = ClientTable::new();
+        for (client, amount) in [(1, 10000), (2, 30000), (3, 20000), (4, 30000), (300, 50000)] {
+            table
+                .handle_transaction(Transaction::Deposit {
+                    client,
+                    tx: client.into(),
+                    amount: Currency::new(amount),
+                })
+                .unwrap();
+        }
+        for t in [
+            Transaction::Dispute { client: 3, tx: 3 },
+            Transaction::Chargeback { client: 3, tx: 3 },
+        ] {
+            table.handle_transaction(t).unwrap();
+        }
+        let report = |options: &ReportOptions| {
+            let mut out = Vec::new();
+            table.write_csv_with(&mut out, options).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let options = ReportOptions {
+            order: ReportOrder::Total,
+            clients: ReportOptions::parse_clients("1-4").unwrap(),
+            top: Some(3),
+            ..ReportOptions::default()
+        };
+        assert_eq!(
+            report(&options),
+            "client, available, held, total, locked\n\
+             2, 3.0000, 0.0000, 3.0000, false\n\
+             4, 3.0000, 0.0000, 3.0000, false\n\
+             1, 1.0000, 0.0000, 1.0000, false\n"
+        );
+        let options = ReportOptions {
+            locked_only: true,
+            ..ReportOptions::default()
+        };
+        assert_eq!(
+            report(&options),
+            "client, available, held, total, locked\n3, 0.0000, 0.0000, 0.0000, true\n"
+        );
+        assert_eq!(report(&ReportOptions::default()), table.to_string());
+
+        assert_eq!(
+            ReportOptions::parse_clients("1-100, 200"),
+            Some(vec![1..=100, 200..=200])
+        );
+        assert_eq!(ReportOptions::parse_clients("5-1"), None);
+        assert_eq!(ReportOptions::parse_clients("x"), None);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    iter,
+    path::Path,
+};
+
+use crate::{
+    client_info::TransactionError,
+    csv_parser::split_fields,
+    currency::{Currency, CurrencyConfig},
+    hierarchy::Hierarchy,
+    transaction::ClientId,
+};
+
+/// At most `count` transactions moving funds within `window` records of the engine clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Velocity {
+    pub count: usize,
+    pub window: u64,
+}
+
+/// Risk limits of a client, `None` disables a limit. Withdrawal limits only cover the regular
+/// accounts, velocity counts the transactions of every currency
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest single withdrawal
+    pub max_withdrawal: Option<Currency>,
+    /// Largest total withdrawn within a business day, see `ClientTable::clear`
+    pub daily_withdrawals: Option<Currency>,
+    pub velocity: Option<Velocity>,
+}
+
+/// Limits of every client, the clients without limits of their own get the default ones
+#[derive(Clone, Debug, Default)]
+pub struct RiskLimits {
+    default: Limits,
+    clients: HashMap<ClientId, Limits>,
+}
+
+impl RiskLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the limits from a csv file, see `read_csv`
+    pub fn load(path: impl AsRef<Path>, currency: CurrencyConfig) -> io::Result<Self> {
+        Self::read_csv(BufReader::new(File::open(path)?), currency)
+    }
+
+    /// Reads `client, max_withdrawal, daily_withdrawals, max_transactions, window` records, the
+    /// first line is skipped if it is a header. An empty field disables the limit and a `*` client
+    /// sets the default limits. Amounts are parsed with `currency`
+    pub fn read_csv<R: BufRead>(reader: R, currency: CurrencyConfig) -> io::Result<Self> {
+        let mut limits = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            match fields[..] {
+                [ref client, ..] if i == 0 && client.eq_ignore_ascii_case("client") => {}
+                [ref empty] if empty.is_empty() => {}
+                [ref client, ref max, ref daily, ref count, ref window] => {
+                    let parsed = parse_limits(max, daily, count, window, currency)
+                        .ok_or_else(|| invalid_limits(&line))?;
+                    match client.as_ref() {
+                        "*" => limits.set_default(parsed),
+                        client => {
+                            let client = client.parse().map_err(|_| invalid_limits(&line))?;
+                            limits.set(client, parsed);
+                        }
+                    }
+                }
+                _ => return Err(invalid_limits(&line)),
+            }
+        }
+        Ok(limits)
+    }
+
+    pub fn set_default(&mut self, limits: Limits) {
+        self.default = limits;
+    }
+
+    /// Sets the limits of `client`, replacing the default ones
+    pub fn set(&mut self, client: ClientId, limits: Limits) {
+        self.clients.insert(client, limits);
+    }
+
+    pub fn limits(&self, client: ClientId) -> Limits {
+        self.clients.get(&client).copied().unwrap_or(self.default)
+    }
+
+    /// Limits of `client` when it is part of `hierarchy`, a sub-account without limits of its own
+    /// gets the ones of its closest ancestor having some. Each account is still checked against
+    /// its own activity
+    pub fn inherited(&self, client: ClientId, hierarchy: &Hierarchy) -> Limits {
+        iter::once(client)
+            .chain(hierarchy.ancestors(client))
+            .find_map(|c| self.clients.get(&c).copied())
+            .unwrap_or(self.default)
+    }
+
+    /// Whether no limits were set at all
+    pub fn is_empty(&self) -> bool {
+        self.default == Limits::default() && self.clients.is_empty()
+    }
+}
+
+fn parse_limits(
+    max: &str,
+    daily: &str,
+    count: &str,
+    window: &str,
+    currency: CurrencyConfig,
+) -> Option<Limits> {
+    let amount = |field: &str| match field {
+        "" => Some(None),
+        field => currency
+            .parse(field)
+            .ok()
+            .filter(|&a| a >= Currency::ZERO)
+            .map(Some),
+    };
+    let velocity = match (count, window) {
+        ("", "") => None,
+        (count, window) => Some(Velocity {
+            count: count.parse().ok()?,
+            window: window.parse().ok().filter(|&window| window > 0)?,
+        }),
+    };
+    Some(Limits {
+        max_withdrawal: amount(max)?,
+        daily_withdrawals: amount(daily)?,
+        velocity,
+    })
+}
+
+/// Recent activity of a client, what its limits are checked against
+#[derive(Clone, Debug, Default)]
+pub struct Activity {
+    /// Business day `withdrawn` adds up
+    day: u64,
+    withdrawn: Currency,
+    /// Engine clock of the recent transactions moving funds, oldest first
+    recent: VecDeque<u64>,
+}
+
+impl Activity {
+    /// Checks a transaction handled at clock `now` on business day `day`, `withdrawal` is the
+    /// amount it takes from the regular account if it is a withdrawal
+    pub fn check(
+        &self,
+        limits: &Limits,
+        withdrawal: Option<Currency>,
+        now: u64,
+        day: u64,
+    ) -> Result<(), TransactionError> {
+        if let Some(velocity) = limits.velocity {
+            let within = self
+                .recent
+                .iter()
+                .filter(|&&at| now - at < velocity.window)
+                .count();
+            if within >= velocity.count {
+                return Err(TransactionError::LimitExceeded);
+            }
+        }
+        let amount = match withdrawal {
+            Some(amount) => amount,
+            None => return Ok(()),
+        };
+        if limits.max_withdrawal.is_some_and(|max| amount > max) {
+            return Err(TransactionError::LimitExceeded);
+        }
+        if let Some(daily) = limits.daily_withdrawals {
+            let today = if self.day == day {
+                self.withdrawn
+            } else {
+                Currency::ZERO
+            };
+            if today.checked_add(amount).is_none_or(|total| total > daily) {
+                return Err(TransactionError::LimitExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a transaction that passed `check` and was applied
+    pub fn record(&mut self, limits: &Limits, withdrawal: Option<Currency>, now: u64, day: u64) {
+        if let Some(velocity) = limits.velocity {
+            while self
+                .recent
+                .front()
+                .is_some_and(|&at| now - at >= velocity.window)
+            {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(now);
+        }
+        if let Some(amount) = withdrawal {
+            if self.day != day {
+                self.day = day;
+                self.withdrawn = Currency::ZERO;
+            }
+            // `check` made sure the total fits
+            self.withdrawn = self.withdrawn.checked_add(amount).unwrap_or(self.withdrawn);
+        }
+    }
+}
+
+fn invalid_limits(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid risk limits record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_default_and_client_limits() {
+        let csv = "client, max_withdrawal, daily_withdrawals, max_transactions, window
+*, 100, 500, ,
+7, , 50, 3, 10
+";
+        let limits = RiskLimits::read_csv(csv.as_bytes(), CurrencyConfig::default()).unwrap();
+        assert_eq!(
+            limits.limits(1),
+            Limits {
+                max_withdrawal: Some(Currency::new(1_000_000)),
+                daily_withdrawals: Some(Currency::new(5_000_000)),
+                velocity: None,
+            }
+        );
+        assert_eq!(
+            limits.limits(7).velocity,
+            Some(Velocity {
+                count: 3,
+                window: 10
+            })
+        );
+        assert_eq!(limits.limits(7).max_withdrawal, None);
+        for invalid in ["1, -5, , ,", "1, , , 3,", "x, 1, , ,", "1, 1"].iter() {
+            assert!(RiskLimits::read_csv(invalid.as_bytes(), CurrencyConfig::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn velocity_counts_within_the_window() {
+        let limits = Limits {
+            velocity: Some(Velocity {
+                count: 2,
+                window: 5,
+            }),
+            ..Limits::default()
+        };
+        let mut activity = Activity::default();
+        for now in [1, 2] {
+            activity.check(&limits, None, now, 0).unwrap();
+            activity.record(&limits, None, now, 0);
+        }
+        assert_eq!(
+            activity.check(&limits, None, 5, 0),
+            Err(TransactionError::LimitExceeded)
+        );
+        // The transaction at clock 1 left the window
+        assert!(activity.check(&limits, None, 6, 0).is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{
+    csv_parser::split_fields,
+    currency::{Currency, CurrencyConfig},
+    fx::Rate,
+};
+
+/// What a schedule posts to every account when it is due
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Posting {
+    /// Interest at the rate on the positive available funds, rounded toward zero to the minor
+    /// unit of the amounts
+    Interest(Rate),
+    /// Fee charged in full, even if it takes the available funds below zero
+    Fee(Currency),
+}
+
+/// Posting made every `every` accrual days
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    pub posting: Posting,
+    pub every: u64,
+}
+
+impl Schedule {
+    /// Whether the schedule posts at the end of accrual day `day`, the first day is 1
+    pub fn is_due(&self, day: u64) -> bool {
+        day.is_multiple_of(self.every)
+    }
+}
+
+/// Recurring postings such as daily interest or monthly fees, made to the regular accounts of the
+/// clients that are not locked whenever an accrual day closes
+///
+/// Days are closed by `accrue` records, or by the engine clock when a day length is set so that
+/// every `day_length` handled records make a day. The postings go to the fee ledger of the
+/// accounts under the id of the `accrue` record, or the number of the day when the clock closed
+/// it, so they show up in the history without ever being disputable
+#[derive(Clone, Debug, Default)]
+pub struct Schedules {
+    schedules: Vec<Schedule>,
+    day_length: Option<u64>,
+}
+
+impl Schedules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the schedules from a csv file of `kind, amount, every` records, see `read_csv`
+    pub fn load(path: impl AsRef<Path>, currency: CurrencyConfig) -> io::Result<Self> {
+        Self::read_csv(BufReader::new(File::open(path)?), currency)
+    }
+
+    /// Reads `interest, <rate>, <every>` and `fee, <amount>, <every>` records, the first line is
+    /// skipped if it is a header. The rate is the one applied at every posting, not a yearly one,
+    /// and fee amounts are parsed with `currency`
+    pub fn read_csv<R: BufRead>(reader: R, currency: CurrencyConfig) -> io::Result<Self> {
+        let mut schedules = Self::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            let posting = match fields[..] {
+                [ref kind, _, _] if i == 0 && kind.eq_ignore_ascii_case("kind") => continue,
+                [ref empty] if empty.is_empty() => continue,
+                [ref kind, ref amount, _] if kind == "interest" => {
+                    amount.parse().ok().map(Posting::Interest)
+                }
+                [ref kind, ref amount, _] if kind == "fee" => currency
+                    .parse(amount)
+                    .ok()
+                    .filter(|&fee| fee > Currency::ZERO)
+                    .map(Posting::Fee),
+                _ => None,
+            };
+            let every = fields.get(2).and_then(|every| every.parse().ok());
+            match (posting, every) {
+                (Some(posting), Some(every)) if schedules.push(posting, every).is_some() => {}
+                _ => return Err(invalid_schedule(&line)),
+            }
+        }
+        Ok(schedules)
+    }
+
+    /// Adds a posting made every `every` accrual days, `None` if `every` is 0
+    pub fn push(&mut self, posting: Posting, every: u64) -> Option<()> {
+        if every == 0 {
+            return None;
+        }
+        self.schedules.push(Schedule { posting, every });
+        Some(())
+    }
+
+    /// Lets the engine clock close an accrual day every `records` handled records, `None` if
+    /// `records` is 0
+    pub fn set_day_length(&mut self, records: u64) -> Option<()> {
+        if records == 0 {
+            return None;
+        }
+        self.day_length = Some(records);
+        Some(())
+    }
+
+    pub fn day_length(&self) -> Option<u64> {
+        self.day_length
+    }
+
+    /// Postings due at the end of accrual day `day`, in the order the schedules were added
+    pub fn due(&self, day: u64) -> impl Iterator<Item = Posting> + '_ {
+        self.schedules
+            .iter()
+            .filter(move |s| s.is_due(day))
+            .map(|s| s.posting)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+}
+
+fn invalid_schedule(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid schedule record: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_schedules_from_csv() {
+        let csv = "kind, amount, every\ninterest, 0.001, 1\nfee, 2.5, 30\n";
+        let schedules = Schedules::read_csv(csv.as_bytes(), CurrencyConfig::default()).unwrap();
+        let interest = Posting::Interest("0.001".parse().unwrap());
+        let fee = Posting::Fee(Currency::new(25000));
+        assert_eq!(schedules.due(1).collect::<Vec<_>>(), vec![interest]);
+        assert_eq!(schedules.due(60).collect::<Vec<_>>(), vec![interest, fee]);
+
+        for invalid in ["fee, -1, 30", "interest, 0.001, 0", "tax, 1, 1", "fee, 1"].iter() {
+            assert!(Schedules::read_csv(invalid.as_bytes(), CurrencyConfig::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn interest_rounds_toward_zero_to_the_minor_unit() {
+        let rate: Rate = "0.001".parse().unwrap();
+        let cent = Currency::new(100);
+        // 0.1% of 12.3456 is 0.0123456
+        assert_eq!(
+            rate.of(Currency::new(123456), cent),
+            Some(Currency::new(100))
+        );
+        assert_eq!(
+            rate.of(Currency::new(123456), Currency::new(1)),
+            Some(Currency::new(123))
+        );
+        assert_eq!(rate.of(Currency::new(-10000), cent), None);
+    }
+}
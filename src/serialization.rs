@@ -0,0 +1,351 @@
+//! `Serialize` and `Deserialize` for the core types, behind the `serde` feature
+//!
+//! Most types derive them where they are defined. The ones here are written as strings, amounts
+//! as decimals so no precision is lost to floats, and the table goes through `TableState`
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    approvals::PendingApprovals,
+    client_info::ClientInfo,
+    currency::{Currency, CurrencyCode, CurrencyConfig, Rounding},
+    payment_engine::ClientTable,
+    policy::{ClearingDelay, DedupPolicy, DisputeRouting},
+    report::ReportOrder,
+    transaction::{ClientId, Memo, TxId},
+};
+
+/// Reads a value from its string, such as the one written by its `Display` or its name
+struct ParseVisitor<T>(&'static str, fn(&str) -> Option<T>);
+
+impl<'de, T> Visitor<'de> for ParseVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<T, E> {
+        (self.1)(s).ok_or_else(|| E::invalid_value(de::Unexpected::Str(s), &self))
+    }
+
+    /// Whole amounts may be written as numbers, fractions have to be strings
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<T, E> {
+        self.visit_str(&n.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<T, E> {
+        self.visit_str(&n.to_string())
+    }
+}
+
+fn from_str<'de, D: Deserializer<'de>, T: FromStr>(
+    deserializer: D,
+    expecting: &'static str,
+) -> Result<T, D::Error> {
+    from_name(deserializer, expecting, |s| s.parse().ok())
+}
+
+/// Reads a value with `parse`, typically the `from_name` of a setting
+pub(crate) fn from_name<'de, D: Deserializer<'de>, T>(
+    deserializer: D,
+    expecting: &'static str,
+    parse: fn(&str) -> Option<T>,
+) -> Result<T, D::Error> {
+    deserializer.deserialize_any(ParseVisitor(expecting, parse))
+}
+
+/// A decimal string at the default precision of 4 decimals, such as `"1.5000"`
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer, "a decimal amount")
+    }
+}
+
+/// `"EUR"`
+impl Serialize for CurrencyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for CurrencyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer, "a three letter currency code")
+    }
+}
+
+impl Serialize for Memo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Memo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer, "a memo of at most 32 bytes")
+    }
+}
+
+// Settings of a configuration file, see `config::EngineConfig`, are read from their names
+
+impl<'de> Deserialize<'de> for Rounding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expecting = "reject, toward-zero, half-up or half-even";
+        from_name(deserializer, expecting, Rounding::from_name)
+    }
+}
+
+impl<'de> Deserialize<'de> for DedupPolicy {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_name(
+            deserializer,
+            "full or a window size",
+            DedupPolicy::from_name,
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for DisputeRouting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_name(
+            deserializer,
+            "client, tx or tx-warn",
+            DisputeRouting::from_name,
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for ClearingDelay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expecting = "instant or t+<business days>";
+        from_name(deserializer, expecting, ClearingDelay::from_name)
+    }
+}
+
+impl<'de> Deserialize<'de> for ReportOrder {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_name(deserializer, "client or total", ReportOrder::from_name)
+    }
+}
+
+/// What a table is serialized as, the same state a snapshot keeps
+///
+/// The configuration of the table, its policy, storage, limits and so on, is not part of it. A
+/// deserialized table has the default configuration apart from the precision and base currency
+#[derive(Serialize, Deserialize)]
+struct TableState<'a> {
+    decimals: u32,
+    base_currency: Option<CurrencyCode>,
+    clock: u64,
+    day: u64,
+    accrual_day: u64,
+    accounts: Vec<Account<'a>>,
+    house: Cow<'a, ClientInfo>,
+    /// Every applied deposit and withdrawal, archived ones included
+    index: Vec<Indexed>,
+    pending: Cow<'a, PendingApprovals>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account<'a> {
+    client: ClientId,
+    /// Currency of a foreign account, `None` for the regular one
+    currency: Option<CurrencyCode>,
+    account: Cow<'a, ClientInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Indexed {
+    tx: TxId,
+    client: ClientId,
+    currency: Option<CurrencyCode>,
+}
+
+impl Serialize for ClientTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let base = self.clients().map(|(client, info)| (client, None, info));
+        let foreign = self
+            .foreign
+            .iter()
+            .map(|(&(client, code), info)| (client, Some(code), info));
+        let accounts = base
+            .chain(foreign)
+            .map(|(client, currency, info)| Account {
+                client,
+                currency,
+                account: Cow::Borrowed(info),
+            })
+            .collect();
+        // Sorted so the same state always serializes the same
+        let mut index: Vec<_> = self.indexed_txs().collect();
+        index.sort_unstable();
+        let index = index
+            .into_iter()
+            .map(|(tx, client)| Indexed {
+                tx,
+                client,
+                currency: self.tx_codes.get(&tx).copied(),
+            })
+            .collect();
+        TableState {
+            decimals: self.currency_config().decimals(),
+            base_currency: self.base_currency(),
+            clock: self.clock,
+            day: self.day,
+            accrual_day: self.accrual_day,
+            accounts,
+            house: Cow::Borrowed(&self.house),
+            index,
+            pending: Cow::Borrowed(&self.pending),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = TableState::deserialize(deserializer)?;
+        let currency = CurrencyConfig::new(state.decimals, Rounding::default())
+            .ok_or_else(|| de::Error::custom("too many decimals"))?;
+        let mut table = ClientTable::new();
+        table.set_currency_config(currency);
+        table.set_base_currency(state.base_currency);
+        for account in state.accounts {
+            let info = account.account.into_owned();
+            match account.currency {
+                Some(code) => {
+                    table.foreign.insert((account.client, code), info);
+                }
+                None => table.clients[account.client] = info,
+            }
+        }
+        for indexed in state.index {
+            table.tx_index.insert(indexed.tx, indexed.client);
+            if let Some(code) = indexed.currency {
+                table.tx_codes.insert(indexed.tx, code);
+            }
+        }
+        table.house = state.house.into_owned();
+        table.pending = state.pending.into_owned();
+        table.clock = state.clock;
+        table.day = state.day;
+        table.accrual_day = state.accrual_day;
+        table.recount_history();
+        table.reschedule_bookings();
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn transactions_are_tagged_by_their_record_type() {
+        let usd = "USD".parse().unwrap();
+        let amount = Currency::new(15000);
+        let transactions = [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount,
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::ForeignWithdraw {
+                client: 2,
+                tx: 2,
+                code: usd,
+                amount,
+            },
+            Transaction::Convert {
+                client: 2,
+                tx: 3,
+                from: Some(usd),
+                to: "EUR".parse().unwrap(),
+                amount,
+            },
+            Transaction::LegalHold {
+                client: 3,
+                tx: 5,
+                amount: None,
+            },
+        ];
+        for t in transactions.iter() {
+            let json = serde_json::to_string(t).unwrap();
+            assert_eq!(serde_json::from_str::<Transaction>(&json).unwrap(), *t);
+        }
+        assert_eq!(
+            serde_json::to_string(&transactions[2]).unwrap(),
+            r#"{"type":"foreign_withdrawal","client":2,"tx":2,"code":"USD","amount":"1.5000"}"#
+        );
+        let withdrawal = r#"{"type":"withdrawal","client":1,"tx":4,"amount":2}"#;
+        assert_eq!(
+            serde_json::from_str::<Transaction>(withdrawal).unwrap(),
+            Transaction::Withdraw {
+                client: 1,
+                tx: 4,
+                amount: Currency::new(20000),
+            }
+        );
+        assert!(serde_json::from_str::<Currency>(r#""1.5.0""#).is_err());
+        // Floats would lose precision
+        assert!(serde_json::from_str::<Currency>("1.5").is_err());
+    }
+
+    #[test]
+    fn tables_round_trip() {
+        let mut table = ClientTable::new();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(20000),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(5000),
+            },
+            Transaction::Dispute { client: 1, tx: 2 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Resolve { client: 2, tx: 3 },
+        ]);
+        let json = serde_json::to_string(&table).unwrap();
+        let mut restored: ClientTable = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.to_string(), table.to_string());
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+
+        let info = restored.client(1).unwrap();
+        assert_eq!(info.open_disputes().len(), 1);
+        let account: ClientInfo =
+            serde_json::from_str(&serde_json::to_string(info).unwrap()).unwrap();
+        assert_eq!(account.to_string(), info.to_string());
+
+        // The index and the settled disputes came along
+        let again = Transaction::Deposit {
+            client: 2,
+            tx: 1,
+            amount: Currency::new(10000),
+        };
+        assert!(restored.handle_transaction(again).is_err());
+        let dispute = Transaction::Dispute { client: 2, tx: 3 };
+        assert!(restored.handle_transaction(dispute).is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{self, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "serde")]
+use crate::json_parser;
+use crate::{
+    cancel::CancellationToken,
+    csv_parser::{self, Header, Records},
+    currency::CurrencyConfig,
+    payment_engine::ClientTable,
+    standby::Shipper,
+    transaction::{ClientId, Transaction},
+};
+use tracing::warn;
+
+/// How often the accept loop checks for a stop request while no connection comes in
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Requests taking longer than this to arrive are dropped, so a stalled client can't hold a worker forever
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest accepted request body
+const MAX_BODY: usize = 16 << 20;
+/// Longest accepted request line or header line, in bytes
+const MAX_LINE: usize = 8 << 10;
+/// Most headers a request may carry
+const MAX_HEADERS: usize = 64;
+/// Connections handled at the same time unless `set_workers` says otherwise
+pub const WORKERS: usize = 8;
+/// Accepted connections waiting for a worker, the ones beyond are answered with a 503 right away
+const BACKLOG: usize = 64;
+
+/// Minimal HTTP/1.1 front end exposing a `ClientTable` as a balance service
+///
+/// - `POST /transactions` applies the csv records of the body, or JSON lines when the content type
+///   is `application/json`, and answers with one event per record. A body that fails to parse is
+///   rejected as a whole
+/// - `GET /clients/{id}` returns the JSON object of a single client
+/// - `GET /report` returns the csv report, `GET /report?format=json` the JSON one
+/// - `POST /admin/clear` runs the clearing sweep closing the business day, see `ClientTable::clear`
+/// - `POST /admin/stop` stops the server once the requests in flight are answered
+///
+/// The admin endpoints need an `Authorization: Bearer <token>` header matching the token given to
+/// `set_admin_token`, and are disabled without one. Connections are handed to a fixed pool of
+/// workers, each serving a single request per connection, and request lines and headers are
+/// bounded. The table sits behind a mutex, applying a batch of records holds the lock for the
+/// whole batch so batches are never interleaved
+///
+/// With `ship_with` the state is shipped to a standby while serving, see `Shipper`
+pub struct Server {
+    listener: TcpListener,
+    table: ClientTable,
+    shipping: Option<(Shipper, Duration)>,
+    admin_token: Option<String>,
+    workers: usize,
+}
+
+/// State shared by the workers
+struct Shared {
+    table: Mutex<ClientTable>,
+    /// Locked before the table whenever both are needed
+    shipper: Option<Mutex<Shipper>>,
+    /// Precision of the table, read once so request bodies are parsed without taking the lock
+    currency: CurrencyConfig,
+    admin_token: Option<String>,
+}
+
+/// Address to listen on, a bare port such as `8080` or `:8080` only listens on localhost
+pub fn listen_address(addr: &str) -> String {
+    match addr.strip_prefix(':').unwrap_or(addr).parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => addr.to_string(),
+    }
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, table: ClientTable) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            table,
+            shipping: None,
+            admin_token: None,
+            workers: WORKERS,
+        })
+    }
+
+    /// Ships the transactions handled every `every`, and a snapshot after every clearing sweep
+    /// and when the server stops. The shipper must have been started on the served table
+    pub fn ship_with(&mut self, shipper: Shipper, every: Duration) {
+        self.shipping = Some((shipper, every));
+    }
+
+    /// Enables the admin endpoints for requests carrying `token`
+    pub fn set_admin_token(&mut self, token: impl Into<String>) {
+        self.admin_token = Some(token.into());
+    }
+
+    /// Number of connections handled at the same time, at least one
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until `token` is cancelled, either by the caller or by `POST /admin/stop`,
+    /// and hands back the table once every connection has been answered
+    pub fn run(self, token: &CancellationToken) -> io::Result<ClientTable> {
+        let (ship_every, shipper) = match self.shipping {
+            Some((shipper, every)) => (every, Some(Mutex::new(shipper))),
+            None => (Duration::MAX, None),
+        };
+        let shared = Arc::new(Shared {
+            currency: self.table.currency_config(),
+            table: Mutex::new(self.table),
+            shipper,
+            admin_token: self.admin_token,
+        });
+        let (queue, connections) = mpsc::sync_channel::<TcpStream>(BACKLOG);
+        let connections = Arc::new(Mutex::new(connections));
+        let workers: Vec<JoinHandle<()>> = (0..self.workers)
+            .map(|_| {
+                let connections = Arc::clone(&connections);
+                let shared = Arc::clone(&shared);
+                let token = token.clone();
+                // Ends once the queue is closed and drained
+                thread::spawn(move || {
+                    while let Ok(stream) = lock(&connections).recv() {
+                        if let Err(e) = handle_connection(stream, &shared, &token) {
+                            warn!(error = %e, "connection failed");
+                        }
+                    }
+                })
+            })
+            .collect();
+        let mut shipped = Instant::now();
+        while !token.is_cancelled() {
+            if let Some(shipper) = &shared.shipper {
+                if shipped.elapsed() >= ship_every {
+                    let mut shipper = lock(shipper);
+                    if let Err(e) = shipper.ship(&mut lock(&shared.table)) {
+                        warn!(dir = %shipper.dir().display(), error = %e, "shipping failed");
+                    }
+                    shipped = Instant::now();
+                }
+            }
+            match self.listener.accept() {
+                Ok((stream, _)) => match queue.try_send(stream) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(mut stream)) => {
+                        let busy = Response::text("503 Service Unavailable", "server busy");
+                        let _ = stream
+                            .set_nonblocking(false)
+                            .and_then(|()| respond(&mut stream, &busy));
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        unreachable!("the workers run until the queue is closed")
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(e),
+            }
+        }
+        drop(queue);
+        for worker in workers {
+            // A panicking worker poisons the mutex, which is reported below
+            let _ = worker.join();
+        }
+        let shared = Arc::try_unwrap(shared)
+            .ok()
+            .expect("every worker has been joined");
+        let mut table = shared
+            .table
+            .into_inner()
+            .map_err(|_| io::Error::other("a request handler panicked while holding the table"))?;
+        if let Some(shipper) = shared.shipper {
+            shipper
+                .into_inner()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .finish(&mut table)?;
+        }
+        Ok(table)
+    }
+}
+
+/// Locks `mutex` even if a handler panicked while holding it
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Request {
+    method: String,
+    target: String,
+    json: bool,
+    /// Token of an `Authorization: Bearer` header
+    bearer: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.into().into_bytes(),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    shared: &Shared,
+    token: &CancellationToken,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader)? {
+        Ok(request) => route(request, shared, token),
+        Err(response) => response,
+    };
+    let mut stream = stream;
+    respond(&mut stream, &response)
+}
+
+fn respond(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+/// Reads a line of at most `MAX_LINE` bytes into `line`, false when it is longer
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    let read = reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+    Ok(read <= MAX_LINE)
+}
+
+/// Reads the request line, the headers and the body, malformed requests are answered right away
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Result<Request, Response>> {
+    let bad_request = |message: &str| Ok(Err(Response::text("400 Bad Request", message)));
+    let too_large = || {
+        Ok(Err(Response::text(
+            "431 Request Header Fields Too Large",
+            "headers too large",
+        )))
+    };
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        return Ok(Err(Response::text(
+            "414 URI Too Long",
+            "request line too long",
+        )));
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target.to_string())
+        }
+        _ => return bad_request("malformed request line"),
+    };
+    let mut length = 0;
+    let mut json = false;
+    let mut bearer = None;
+    let mut headers = 0;
+    loop {
+        if !read_line(reader, &mut line)? {
+            return too_large();
+        }
+        if line.is_empty() {
+            return bad_request("truncated headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return too_large();
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return bad_request("malformed header"),
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            length = match value.parse() {
+                Ok(length) => length,
+                Err(_) => return bad_request("invalid content length"),
+            };
+        } else if name.eq_ignore_ascii_case("content-type") {
+            json = value.starts_with("application/json");
+        } else if name.eq_ignore_ascii_case("authorization") {
+            bearer = value.strip_prefix("Bearer ").map(|t| t.trim().to_string());
+        }
+    }
+    if length > MAX_BODY {
+        return Ok(Err(Response::text(
+            "413 Payload Too Large",
+            "body too large",
+        )));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request {
+        method,
+        target,
+        json,
+        bearer,
+        body,
+    }))
+}
+
+/// Compares the tokens in constant time, so the response time doesn't tell how much matched
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn route(request: Request, shared: &Shared, token: &CancellationToken) -> Response {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+    if path.starts_with("/admin/") {
+        match (&shared.admin_token, &request.bearer) {
+            (None, _) => return Response::text("403 Forbidden", "admin endpoints are disabled"),
+            (Some(expected), Some(given)) if same_token(given, expected) => {}
+            _ => return Response::text("401 Unauthorized", "missing or wrong admin token"),
+        }
+    }
+    let currency = shared.currency;
+    let lock = || lock(&shared.table);
+    match (request.method.as_str(), path) {
+        ("POST", "/transactions") => {
+            let transactions = match parse_body(&request, currency) {
+                Ok(transactions) => transactions,
+                Err(e) => return Response::text("400 Bad Request", e.to_string()),
+            };
+            let mut table = lock();
+            let mut body = String::new();
+            for event in table.stream(transactions) {
+                body.push_str(&format!("{}\n", event));
+            }
+            for warning in table.take_warnings() {
+                warn!(%warning, "engine warning");
+            }
+            Response {
+                status: "200 OK",
+                content_type: "text/csv",
+                body: body.into_bytes(),
+            }
+        }
+        ("GET", "/report") => {
+            let mut body = Vec::new();
+            let (content_type, written) = if query.split('&').any(|p| p == "format=json") {
+                ("application/json", lock().write_json(&mut body))
+            } else {
+                ("text/csv", lock().write_csv(&mut body))
+            };
+            match written {
+                Ok(()) => Response {
+                    status: "200 OK",
+                    content_type,
+                    body,
+                },
+                Err(e) => Response::text("500 Internal Server Error", e.to_string()),
+            }
+        }
+        ("GET", path) if path.starts_with("/clients/") => {
+            let client: ClientId = match path["/clients/".len()..].parse() {
+                Ok(client) => client,
+                Err(_) => return Response::text("400 Bad Request", "invalid client id"),
+            };
+            let mut body = Vec::new();
+            match lock().write_client_json(&mut body, client) {
+                Ok(true) => Response {
+                    status: "200 OK",
+                    content_type: "application/json",
+                    body,
+                },
+                Ok(false) => Response::text("404 Not Found", "unknown client"),
+                Err(e) => Response::text("500 Internal Server Error", e.to_string()),
+            }
+        }
+        ("POST", "/admin/stop") => {
+            token.cancel();
+            Response::text("202 Accepted", "stopping")
+        }
+        ("POST", "/admin/clear") => {
+            let shipper = shared.shipper.as_ref().map(self::lock);
+            let mut table = lock();
+            let cleared = table.clear();
+            // The sweep is not a transaction, only a snapshot carries it to the standby
+            if let Some(mut shipper) = shipper {
+                if let Err(e) = shipper.checkpoint(&mut table) {
+                    warn!(dir = %shipper.dir().display(), error = %e, "shipping failed");
+                }
+            }
+            for warning in table.take_warnings() {
+                warn!(%warning, "engine warning");
+            }
+            Response::text(
+                "200 OK",
+                format!("cleared {}, business day {}", cleared, table.business_day()),
+            )
+        }
+        (_, "/transactions") | (_, "/report") | (_, "/admin/stop") | (_, "/admin/clear") => {
+            Response::text("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Response::text("404 Not Found", "not found"),
+    }
+}
+
+fn parse_body(request: &Request, currency: CurrencyConfig) -> io::Result<Vec<Transaction>> {
+    let mut body = request.body.as_slice();
+    #[cfg(feature = "serde")]
+    if request.json {
+        return body
+            .lines()
+            .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+            .map(|l| json_parser::parse_line_with(l, currency).map_err(io::Error::from))
+            .collect();
+    }
+    #[cfg(not(feature = "serde"))]
+    if request.json {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "JSON bodies need a build with the serde feature",
+        ));
+    }
+    csv_parser::skip_header(&mut body, Header::Detect)?;
+    Records::new(body)
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+        .map(|l| csv_parser::parse_line_with(l, currency).map_err(io::Error::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn post(addr: SocketAddr, path: &str, content_type: &str, body: &str) -> String {
+        request(
+            addr,
+            &format!(
+                "POST {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                path,
+                content_type,
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", path))
+    }
+
+    fn admin(addr: SocketAddr, path: &str, token: &str) -> String {
+        request(
+            addr,
+            &format!(
+                "POST {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\n\r\n",
+                path, token
+            ),
+        )
+    }
+
+    #[test]
+    fn serves_transactions_and_balances() {
+        let mut server = Server::bind("127.0.0.1:0", ClientTable::new()).unwrap();
+        server.set_admin_token("secret");
+        let addr = server.local_addr().unwrap();
+        let token = CancellationToken::new();
+        let running = {
+            let token = token.clone();
+            thread::spawn(move || server.run(&token))
+        };
+
+        let applied = post(
+            addr,
+            "/transactions",
+            "text/csv",
+            "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 9.0\n",
+        );
+        assert!(applied.starts_with("HTTP/1.1 200 OK"));
+        assert!(applied.ends_with(
+            "deposit, 1, 1, 2.5000, applied\nwithdrawal, 1, 2, 9.0000, rejected Overdraw\n"
+        ));
+        // JSON bodies need the serde feature, without it the record is posted as csv
+        let deposit = if cfg!(feature = "serde") {
+            post(
+                addr,
+                "/transactions",
+                "application/json",
+                "{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":\"1\"}\n",
+            )
+        } else {
+            post(addr, "/transactions", "text/csv", "deposit, 2, 3, 1\n")
+        };
+        assert!(deposit.ends_with("deposit, 2, 3, 1.0000, applied\n"));
+        let invalid = post(addr, "/transactions", "text/csv", "deposit, 1, 4, x\n");
+        assert!(invalid.starts_with("HTTP/1.1 400"));
+
+        let client = get(addr, "/clients/1");
+        assert!(client.ends_with(
+            "{\"client\":1,\"available\":\"2.5000\",\"held\":\"0.0000\",\"total\":\"2.5000\",\"locked\":false}"
+        ));
+        assert!(get(addr, "/clients/7").starts_with("HTTP/1.1 404"));
+        assert!(get(addr, "/clients/x").starts_with("HTTP/1.1 400"));
+        assert!(get(addr, "/report").ends_with(
+            "client, available, held, total, locked\n1, 2.5000, 0.0000, 2.5000, false\n2, 1.0000, 0.0000, 1.0000, false\n"
+        ));
+        assert!(get(addr, "/report?format=json").contains("application/json"));
+        assert!(get(addr, "/transactions").starts_with("HTTP/1.1 405"));
+
+        assert!(post(addr, "/admin/stop", "text/plain", "").starts_with("HTTP/1.1 401"));
+        assert!(admin(addr, "/admin/stop", "wrong").starts_with("HTTP/1.1 401"));
+        assert!(admin(addr, "/admin/stop", "secret").starts_with("HTTP/1.1 202"));
+        let table = running.join().unwrap().unwrap();
+        assert!(token.is_cancelled());
+        assert_eq!(table.clients().count(), 2);
+    }
+
+    #[test]
+    fn admin_endpoints_are_disabled_without_a_token() {
+        let mut server = Server::bind("127.0.0.1:0", ClientTable::new()).unwrap();
+        server.set_workers(1);
+        let addr = server.local_addr().unwrap();
+        let token = CancellationToken::new();
+        let running = {
+            let token = token.clone();
+            thread::spawn(move || server.run(&token))
+        };
+        assert!(admin(addr, "/admin/stop", "").starts_with("HTTP/1.1 403"));
+        assert!(admin(addr, "/admin/clear", "anything").starts_with("HTTP/1.1 403"));
+        assert!(!token.is_cancelled());
+        token.cancel();
+        running.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn oversized_request_heads_are_refused() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(MAX_LINE));
+        let read = |head: &str| match read_request(&mut head.as_bytes()).unwrap() {
+            Ok(_) => "ok",
+            Err(response) => response.status,
+        };
+        assert_eq!(read(&long_line), "414 URI Too Long");
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(MAX_LINE));
+        assert_eq!(read(&long_header), "431 Request Header Fields Too Large");
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(read(&many_headers), "431 Request Header Fields Too Large");
+        let enough_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X: 1\r\n".repeat(MAX_HEADERS));
+        assert_eq!(read(&enough_headers), "ok");
+    }
+
+    #[test]
+    fn bare_ports_listen_on_localhost() {
+        assert_eq!(listen_address("8080"), "127.0.0.1:8080");
+        assert_eq!(listen_address(":8080"), "127.0.0.1:8080");
+        assert_eq!(listen_address("0.0.0.0:8080"), "0.0.0.0:8080");
+    }
+}
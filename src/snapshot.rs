@@ -0,0 +1,370 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    approvals::PendingApprovals,
+    client_info::ClientInfo,
+    csv_parser::{parse_record, split_fields, with_currency_code, ParseCSVError},
+    currency::{CurrencyCode, CurrencyConfig},
+    payment_engine::ClientTable,
+    progress::InputOffset,
+    tx_index::TxIndex,
+    version::{CompatCheck, Stamp},
+    wal::Wal,
+};
+
+/// Owner column of the records belonging to the house account
+const HOUSE: &str = "house";
+
+impl ClientTable {
+    /// Writes the complete engine state to `path` so processing can resume from it after a crash
+    ///
+    /// The file is a compatibility stamp followed by csv records: the logical clock, business day
+    /// and accrual day, the input offset if any, how many records of the write-ahead log it
+    /// contains, the balances and history of every client, of their foreign accounts and of
+    /// the house account, the transaction index and the pending approvals. It is written to a
+    /// temporary file first and renamed over `path`, so a crash while checkpointing leaves the
+    /// previous snapshot intact. The policy, the currency precision and base currency, the
+    /// schedules and the spill archive are configuration and are not included, nor are the records
+    /// of an open batch as nothing of it is applied before it commits
+    pub fn snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write_snapshot(path.as_ref(), self.input_offset)
+    }
+
+    /// Snapshot taken while reading a feed, recording that the input was consumed up to `at`
+    /// Resuming the feed from there after restoring it, see `--resume-from-offset`, applies every
+    /// record exactly once. Not to be taken while a batch is open, its records before `at` would
+    /// be lost
+    pub fn checkpoint(&self, path: impl AsRef<Path>, at: InputOffset) -> io::Result<()> {
+        self.write_snapshot(path.as_ref(), Some(at))
+    }
+
+    fn write_snapshot(&self, path: &Path, offset: Option<InputOffset>) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        Stamp::write(&mut w)?;
+        writeln!(w, "clock, {}", self.clock)?;
+        writeln!(w, "day, {}", self.day)?;
+        writeln!(w, "accrual_day, {}", self.accrual_day)?;
+        if let Some(at) = offset {
+            writeln!(w, "offset, {}, {}", at.bytes, at.line)?;
+        }
+        let wal_position = self.wal.as_ref().map_or(self.wal_position, Wal::records);
+        if wal_position > 0 {
+            writeln!(w, "wal, {}", wal_position)?;
+        }
+        for (client, info) in self.clients.iter() {
+            if !info.is_pristine() {
+                info.write_snapshot(&mut w, client)?;
+            }
+        }
+        for ((client, code), info) in &self.foreign {
+            info.write_snapshot(&mut w, format_args!("{}/{}", client, code))?;
+        }
+        self.house.write_snapshot(&mut w, HOUSE)?;
+        // Sorted so the same state always produces the same file
+        let mut index: Vec<_> = self.indexed_txs().collect();
+        index.sort_unstable();
+        for (tx, client) in index {
+            write!(w, "index, {}, {}", tx, client)?;
+            if let Some(code) = self.tx_codes.get(&tx) {
+                write!(w, ", {}", code)?;
+            }
+            writeln!(w)?;
+        }
+        for (t, queued_at) in self.pending.entries() {
+            write!(
+                w,
+                "pending, {}, {}, {}, {}, ",
+                queued_at,
+                t.kind_name(),
+                t.client(),
+                t.tx()
+            )?;
+            if let Some(code) = t.currency_code() {
+                write!(w, "{}, ", code)?;
+            }
+            if let Some(amount) = t.record_amount() {
+                write!(w, "{}", amount)?;
+            }
+            if let Some(value_date) = t.value_date() {
+                write!(w, ", {}", value_date)?;
+            }
+            if let Some(to) = t.target_currency() {
+                write!(w, ", {}", to)?;
+            }
+            writeln!(w)?;
+        }
+        for (client, tx, approver) in self.pending.approvals() {
+            writeln!(w, "approved, {}, {}, {}", client, tx, approver)?;
+        }
+        w.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Replaces the state of this table with the one saved by `snapshot`
+    /// The table keeps its own configuration, and is left untouched if the snapshot can't be read
+    pub fn restore(&mut self, path: impl AsRef<Path>, check: CompatCheck) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Stamp::check(&mut reader, check)?;
+        let mut clients = self.clients.empty_like();
+        let mut house = ClientInfo::default();
+        let mut foreign: BTreeMap<_, ClientInfo> = BTreeMap::new();
+        let mut tx_index = TxIndex::new(self.tx_index.strategy());
+        let mut tx_codes = HashMap::new();
+        let mut pending = PendingApprovals::default();
+        let mut clock = 0;
+        let mut day = 0;
+        let mut accrual_day = 0;
+        let mut input_offset = None;
+        let mut wal_position = 0;
+        for line in reader.lines() {
+            let line = line?;
+            // Memos are quoted
+            let fields = split_fields(&line).map_err(io::Error::from)?;
+            let fields: Vec<_> = fields.iter().map(|f| f.as_ref()).collect();
+            let restored = match fields[..] {
+                ["clock", now] => now.parse().ok().map(|now| clock = now),
+                ["day", today] => today.parse().ok().map(|today| day = today),
+                ["accrual_day", closed] => closed.parse().ok().map(|closed| accrual_day = closed),
+                ["offset", bytes, line] => match (bytes.parse(), line.parse()) {
+                    (Ok(bytes), Ok(line)) => {
+                        input_offset = Some(InputOffset { bytes, line });
+                        Some(())
+                    }
+                    _ => None,
+                },
+                ["wal", records] => records.parse().ok().map(|records| wal_position = records),
+                ["index", tx, client] => match (tx.parse(), client.parse()) {
+                    (Ok(tx), Ok(client)) => {
+                        tx_index.insert(tx, client);
+                        Some(())
+                    }
+                    _ => None,
+                },
+                ["index", tx, client, code] => match (tx.parse(), client.parse(), code.parse()) {
+                    (Ok(tx), Ok(client), Ok(code)) => {
+                        tx_index.insert(tx, client);
+                        tx_codes.insert(tx, code);
+                        Some(())
+                    }
+                    _ => None,
+                },
+                ["pending", queued_at, kind, client, tx, ref rest @ ..] => {
+                    let (code, rest) = match rest {
+                        [code, rest @ ..] if CurrencyCode::is_code(code) => (Some(*code), rest),
+                        _ => (None, rest),
+                    };
+                    let record = match rest {
+                        [amount, ref value_date @ ..] if value_date.len() <= 1 => parse_record(
+                            Some(kind),
+                            Some(client),
+                            Some(tx),
+                            Some(*amount).filter(|a| !a.is_empty()),
+                            value_date.first().copied(),
+                            CurrencyConfig::default(),
+                        )
+                        .and_then(|t| with_currency_code(t, code)),
+                        _ => Err(ParseCSVError::UnknownRecord),
+                    };
+                    match (queued_at.parse(), record) {
+                        (Ok(queued_at), Ok(t)) => {
+                            pending.queue(t, queued_at);
+                            Some(())
+                        }
+                        _ => None,
+                    }
+                }
+                // Written after every pending transaction
+                ["approved", client, tx, approver] => {
+                    match (client.parse(), tx.parse(), approver.parse()) {
+                        (Ok(client), Ok(tx), Ok(approver)) => {
+                            pending.approve(client, tx, approver, usize::MAX).ok()
+                        }
+                        _ => None,
+                    }
+                    .map(|_| ())
+                }
+                [section, HOUSE, ref rest @ ..] => house.read_snapshot(section, rest),
+                [section, account, ref rest @ ..] if account.contains('/') => {
+                    let (client, code) = account.split_once('/').expect("checked by the guard");
+                    match (client.parse(), code.parse()) {
+                        (Ok(client), Ok(code)) => foreign
+                            .entry((client, code))
+                            .or_default()
+                            .read_snapshot(section, rest),
+                        _ => None,
+                    }
+                }
+                [section, client, ref rest @ ..] => match client.parse() {
+                    Ok(client) => clients[client].read_snapshot(section, rest),
+                    Err(_) => None,
+                },
+                _ => None,
+            };
+            if restored.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Corrupt snapshot record: {}", line),
+                ));
+            }
+        }
+        self.clients = clients;
+        self.house = house;
+        self.foreign = foreign;
+        self.tx_index = tx_index;
+        self.tx_codes = tx_codes;
+        self.pending = pending;
+        self.clock = clock;
+        self.day = day;
+        self.accrual_day = accrual_day;
+        self.input_offset = input_offset;
+        self.wal_position = wal_position;
+        self.recount_history();
+        self.reschedule_bookings();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client_info::AccountType,
+        currency::Currency,
+        policy::{ApprovalPolicy, ChargebackFee, FeePayer, Policy, SettlementPolicy},
+        transaction::Transaction,
+        version::COMPAT_LEVEL,
+    };
+
+    #[test]
+    fn restored_table_resumes_where_the_snapshot_was_taken() {
+        let path = std::env::temp_dir().join(format!("bank_snapshot_{}.csv", std::process::id()));
+        let policy = Policy {
+            chargeback_fee: Some(ChargebackFee {
+                amount: Currency::new(1000),
+                payer: FeePayer::House,
+            }),
+            approvals: Some(ApprovalPolicy {
+                threshold: Currency::new(1_000_000),
+                timeout: 100,
+                approvers: 2,
+            }),
+            settlement: SettlementPolicy::ValueDated,
+            ..Policy::default()
+        };
+        let deposit = |client, tx, amount| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(amount),
+        };
+        let before = vec![
+            deposit(1, 1, 50000),
+            deposit(1, 2, 20000),
+            Transaction::Dispute { client: 1, tx: 2 },
+            deposit(2, 3, 10000),
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 3 },
+            deposit(3, 4, 5_000_000),
+            Transaction::Approve {
+                client: 3,
+                tx: 4,
+                approver: 1,
+            },
+            Transaction::LegalHold {
+                client: 1,
+                tx: 50,
+                amount: Some(Currency::new(10000)),
+            },
+            Transaction::ForeignDeposit {
+                client: 1,
+                tx: 6,
+                code: "USD".parse().unwrap(),
+                amount: Currency::new(40000),
+            },
+            Transaction::Dispute { client: 1, tx: 6 },
+            // Released while processing `after`
+            Transaction::Booking {
+                client: 6,
+                tx: 5,
+                amount: Currency::new(30000),
+                value_date: 13,
+            },
+        ];
+        let after = vec![
+            Transaction::Resolve { client: 1, tx: 2 },
+            Transaction::Resolve { client: 1, tx: 6 },
+            Transaction::Approve {
+                client: 3,
+                tx: 4,
+                approver: 2,
+            },
+            deposit(4, 1, 10000),
+        ];
+
+        let mut original = ClientTable::with_policy(policy);
+        original.set_account_type(5, AccountType::Savings);
+        original.process(before);
+        original.snapshot(&path).unwrap();
+        let mut restored = ClientTable::with_policy(policy);
+        restored.restore(&path, CompatCheck::Strict).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.to_string(), original.to_string());
+        assert_eq!(restored.house().to_string(), original.house().to_string());
+        assert_eq!(restored.clients[6].booked_funds(), Currency::new(30000));
+        assert_eq!(restored.clients[1].legal_held(), Ok(Currency::new(10000)));
+        assert_eq!(original.process(after.clone()), restored.process(after));
+        assert_eq!(restored.clients[6].booked_funds(), Currency::ZERO);
+        assert_eq!(restored.to_string(), original.to_string());
+    }
+
+    #[test]
+    fn snapshots_of_an_older_level_are_refused() {
+        let path =
+            std::env::temp_dir().join(format!("bank_old_snapshot_{}.csv", std::process::id()));
+        let mut table = ClientTable::new();
+        table.process(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(10000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+        ]);
+        table.snapshot(&path).unwrap();
+        let snapshot = fs::read_to_string(&path).unwrap();
+        let (stamp, records) = snapshot.split_once('\n').unwrap();
+        let old = Stamp {
+            compat: COMPAT_LEVEL - 1,
+            ..Stamp::parse(stamp).unwrap()
+        };
+        fs::write(&path, format!("{}\n{}", old, records)).unwrap();
+        let mut restored = ClientTable::new();
+        let strict = restored.restore(&path, CompatCheck::Strict);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(strict.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(restored.to_string(), ClientTable::new().to_string());
+    }
+
+    #[test]
+    fn corrupt_snapshot_leaves_table_untouched() {
+        let path =
+            std::env::temp_dir().join(format!("bank_bad_snapshot_{}.csv", std::process::id()));
+        let mut w = Vec::new();
+        Stamp::write(&mut w).unwrap();
+        w.extend_from_slice(b"account, 1, 1.0000, 0.0000, false, standard, 0\nbogus\n");
+        fs::write(&path, w).unwrap();
+        let mut table = ClientTable::new();
+        let result = table.restore(&path, CompatCheck::Strict);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(table.to_string(), ClientTable::new().to_string());
+    }
+}
@@ -0,0 +1,272 @@
+//! The engine of the original specification, which `--spec-compat` runs instead of `ClientTable`
+//!
+//! It keeps the rules of the first version of the engine, quirks included, so its reports match
+//! the ones of that version byte for byte:
+//! - the first line is skipped whatever it holds, and the first record that can't be parsed aborts
+//!   the run
+//! - negative and zero amounts are accepted. The decimals are padded to 4 digits and read as a
+//!   number, so `1.00005` is 5 ten-thousandths, and the sign of a zero integer part is lost, so
+//!   `-0.5` is `0.5`
+//! - transaction ids are not checked for duplicates, a dispute applies to the first transfer of the
+//!   client with the id
+//! - a withdrawal has to leave funds available, withdrawing all of them is refused
+//! - a dispute moves the signed amount of the transfer from the available to the held funds, so
+//!   disputing a withdrawal credits the available funds. Resolves and chargebacks don't close the
+//!   dispute, and locked accounts go on accepting every transaction
+use std::{collections::BTreeMap, fmt, io::BufRead};
+
+use crate::{
+    client_info::TransactionError,
+    csv_parser::ParseCSVError,
+    currency::ParseCurrencyError,
+    error::EngineError,
+    transaction::{ClientId, TxId},
+};
+
+/// Ten-thousandths of the currency, without any bound checks
+type Amount = i64;
+
+/// Record of the original specification
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Record {
+    Deposit(ClientId, TxId, Amount),
+    Withdrawal(ClientId, TxId, Amount),
+    Dispute(ClientId, TxId),
+    Resolve(ClientId, TxId),
+    Chargeback(ClientId, TxId),
+}
+
+/// Reads a record as `type, client, tx, amount`, columns past the amount are ignored as is the
+/// amount of a dispute, resolve or chargeback
+fn parse_record(line: &str) -> Result<Record, ParseCSVError> {
+    let mut fields = line.split(',').map(|f| f.trim());
+    let record = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some("deposit"), Some(client), Some(tx), Some(amount)) => {
+            Record::Deposit(client.parse()?, tx.parse()?, parse_amount(amount)?)
+        }
+        (Some("withdrawal"), Some(client), Some(tx), Some(amount)) => {
+            Record::Withdrawal(client.parse()?, tx.parse()?, parse_amount(amount)?)
+        }
+        (Some("dispute"), Some(client), Some(tx), _) => {
+            Record::Dispute(client.parse()?, tx.parse()?)
+        }
+        (Some("resolve"), Some(client), Some(tx), _) => {
+            Record::Resolve(client.parse()?, tx.parse()?)
+        }
+        (Some("chargeback"), Some(client), Some(tx), _) => {
+            Record::Chargeback(client.parse()?, tx.parse()?)
+        }
+        _ => return Err(ParseCSVError::UnknownRecord),
+    };
+    Ok(record)
+}
+
+/// Reads an amount the way the original did, see the module documentation
+fn parse_amount(s: &str) -> Result<Amount, ParseCurrencyError> {
+    let mut parts = s.split('.');
+    let units = parts.next().map(str::parse::<i64>);
+    let decimals = parts.next().map(|d| format!("{:0<4}", d).parse::<i64>());
+    match (units, decimals) {
+        (Some(Ok(units)), None) => Ok(units.wrapping_mul(10000)),
+        (Some(Ok(units)), Some(Ok(decimals))) => {
+            let units = units.wrapping_mul(10000);
+            let decimals = if units < 0 {
+                decimals.wrapping_neg()
+            } else {
+                decimals
+            };
+            Ok(units.wrapping_add(decimals))
+        }
+        _ => Err(ParseCurrencyError),
+    }
+}
+
+/// Writes an amount with 4 decimals and the sign of its integer part
+struct Display(Amount);
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:0>4}",
+            self.0 / 10000,
+            self.0.wrapping_abs() % 10000
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Account {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    /// Deposits, and withdrawals as negative amounts, in the order they were applied
+    transfers: Vec<(TxId, Amount)>,
+    /// Every dispute applied, settled or not
+    disputes: Vec<(TxId, Amount)>,
+}
+
+impl Account {
+    fn dispute(&mut self, tx: TxId) -> Result<(), TransactionError> {
+        let &(tx, amount) = self
+            .transfers
+            .iter()
+            .find(|t| t.0 == tx)
+            .ok_or(TransactionError::InvalidTxId)?;
+        self.available = self.available.wrapping_sub(amount);
+        self.held = self.held.wrapping_add(amount);
+        self.disputes.push((tx, amount));
+        Ok(())
+    }
+
+    fn disputed(&self, tx: TxId) -> Result<Amount, TransactionError> {
+        self.disputes
+            .iter()
+            .find(|d| d.0 == tx)
+            .map(|d| d.1)
+            .ok_or(TransactionError::InvalidTxId)
+    }
+}
+
+/// Balances of the clients under the rules of the original specification
+///
+/// Only clients with a deposit or a withdrawal are reported, in the order of their ids
+#[derive(Clone, Debug, Default)]
+pub struct SpecTable {
+    clients: BTreeMap<ClientId, Account>,
+}
+
+impl SpecTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the records of `input` after skipping its first line. Refused records are ignored,
+    /// the first one that can't be parsed aborts with its line
+    pub fn process(&mut self, input: impl BufRead) -> Result<(), EngineError> {
+        for (index, line) in input.lines().enumerate().skip(1) {
+            let line = line?;
+            let record = parse_record(&line)
+                .map_err(|e| EngineError::from(e).at_line(index + 1, None, &line))?;
+            let _ = self.apply(record);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) -> Result<(), TransactionError> {
+        match record {
+            Record::Deposit(client, tx, amount) => {
+                let account = self.clients.entry(client).or_default();
+                account.available = account.available.wrapping_add(amount);
+                account.transfers.push((tx, amount));
+            }
+            Record::Withdrawal(client, tx, amount) => {
+                let account = self.clients.entry(client).or_default();
+                if account.available <= amount {
+                    return Err(TransactionError::Overdraw);
+                }
+                account.available = account.available.wrapping_sub(amount);
+                account.transfers.push((tx, amount.wrapping_neg()));
+            }
+            Record::Dispute(client, tx) => self.account(client)?.dispute(tx)?,
+            Record::Resolve(client, tx) => {
+                let account = self.account(client)?;
+                let amount = account.disputed(tx)?;
+                account.available = account.available.wrapping_add(amount);
+                account.held = account.held.wrapping_sub(amount);
+            }
+            Record::Chargeback(client, tx) => {
+                let account = self.account(client)?;
+                let amount = account.disputed(tx)?;
+                account.held = account.held.wrapping_sub(amount);
+                account.locked = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn account(&mut self, client: ClientId) -> Result<&mut Account, TransactionError> {
+        self.clients
+            .get_mut(&client)
+            .ok_or(TransactionError::InvalidTxId)
+    }
+}
+
+/// The report of the original, followed by an empty line as it printed it with `println!`
+impl fmt::Display for SpecTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "client, available, held, total, locked")?;
+        for (client, account) in &self.clients {
+            if account.transfers.is_empty() {
+                continue;
+            }
+            writeln!(
+                f,
+                "{}, {}, {}, {}, {}",
+                client,
+                Display(account.available),
+                Display(account.held),
+                Display(account.available.wrapping_add(account.held)),
+                account.locked
+            )?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(input: &str) -> String {
+        let mut table = SpecTable::new();
+        table.process(input.as_bytes()).unwrap();
+        table.to_string()
+    }
+
+    #[test]
+    fn amounts_keep_their_original_quirks() {
+        assert_eq!(parse_amount("1.5").ok(), Some(15000));
+        assert_eq!(parse_amount("-1.5").ok(), Some(-15000));
+        assert_eq!(parse_amount("-0.5").ok(), Some(5000));
+        assert_eq!(parse_amount("1.00005").ok(), Some(10005));
+        assert_eq!(parse_amount("1.12345").ok(), Some(22345));
+        assert_eq!(parse_amount("2.").ok(), Some(20000));
+        assert_eq!(parse_amount("1.5.9").ok(), Some(15000));
+        assert!(parse_amount(".5").is_err());
+        assert_eq!(Display(-15000).to_string(), "-1.5000");
+        assert_eq!(Display(-5000).to_string(), "0.5000");
+    }
+
+    #[test]
+    fn records_are_applied_under_the_original_rules() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 2.0
+withdrawal, 1, 2, 2.0
+withdrawal, 1, 3, 0.5
+dispute, 1, 3,
+deposit, 1, 1, 1.0
+dispute, 1, 1
+chargeback, 1, 1,
+deposit, 1, 4, -1.0
+withdrawal, 2, 5, 1.0
+dispute, 3, 1,
+";
+        // The whole balance can't be withdrawn, the disputed withdrawal is credited back to the
+        // available funds, the duplicate deposit is applied and only the first transfer of tx 1 is
+        // disputed, the locked account still takes a deposit and the client without transfers is
+        // left out. The held funds of -0.5 lose their sign
+        assert_eq!(
+            report(input),
+            "client, available, held, total, locked\n1, 0.0000, 0.5000, 0.5000, true\n\n"
+        );
+    }
+
+    #[test]
+    fn the_first_malformed_record_aborts() {
+        let mut table = SpecTable::new();
+        let input = "garbage\ndeposit, 1, 1, 1.0\ntransfer, 1, 2, 1.0\ndeposit, 1, 3, 1.0\n";
+        let e = table.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(e, EngineError::AtLine { line: 3, .. }), "{:?}", e);
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    cancel::CancellationToken,
+    error::EngineError,
+    payment_engine::ClientTable,
+    version::{CompatCheck, Stamp},
+    wal::Wal,
+};
+
+/// Segments shipped between two full snapshots
+pub const SNAPSHOT_EVERY: u64 = 16;
+/// File whose presence in the shipping directory promotes a standby, see `Standby::follow`
+pub const PROMOTE: &str = "promote";
+/// Segment the primary is currently logging to, renamed once shipped so readers never see it
+/// half written
+const OPEN_SEGMENT: &str = ".segment.open";
+
+/// Kind of the files in a shipping directory, every file carries the sequence number it was
+/// shipped under
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Shipped {
+    /// Complete state after every earlier sequence number
+    Snapshot,
+    /// Transactions logged since the previous sequence number, in the write-ahead log format
+    Segment,
+}
+
+impl Shipped {
+    fn prefix(self) -> &'static str {
+        match self {
+            Shipped::Snapshot => "snapshot-",
+            Shipped::Segment => "segment-",
+        }
+    }
+
+    fn path(self, dir: &Path, seq: u64) -> PathBuf {
+        dir.join(format!("{}{:020}.csv", self.prefix(), seq))
+    }
+
+    fn parse(name: &str) -> Option<(Self, u64)> {
+        [Shipped::Snapshot, Shipped::Segment]
+            .iter()
+            .find_map(|&kind| {
+                let seq = name.strip_prefix(kind.prefix())?.strip_suffix(".csv")?;
+                seq.parse().ok().map(|seq| (kind, seq))
+            })
+    }
+}
+
+/// Shipped files in `dir` as `(kind, sequence number)` pairs, unsorted
+fn list(dir: &Path) -> io::Result<Vec<(Shipped, u64)>> {
+    let mut shipped = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Some(file) = entry?.file_name().to_str().and_then(Shipped::parse) {
+            shipped.push(file);
+        }
+    }
+    Ok(shipped)
+}
+
+/// Ships the state of a primary to a directory a `Standby` follows, so a failover starts from
+/// the state the primary had at its last shipment rather than from an empty table
+///
+/// Every transaction the table handles is logged to an open segment, taking the write-ahead log
+/// slot of the table, see `ClientTable::set_wal`. `ship` closes the segment under the next
+/// sequence number and writes a full snapshot every `SNAPSHOT_EVERY` segments, after which the
+/// files older than the snapshot are deleted. Only a local or mounted directory is supported,
+/// pushing to an object store is left to a sync job watching it
+#[derive(Debug)]
+pub struct Shipper {
+    dir: PathBuf,
+    /// Sequence number of the last shipped file
+    seq: u64,
+    /// Segments shipped since the last snapshot
+    segments: u64,
+    /// Length of an open segment holding no record yet
+    empty_len: u64,
+}
+
+impl Shipper {
+    /// Starts shipping the state of `table` to `dir`, creating it if needed. A snapshot of the
+    /// current state is shipped right away, numbered after the files already in the directory so
+    /// a restarted primary supersedes what it shipped before
+    pub fn start(dir: impl Into<PathBuf>, table: &mut ClientTable) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let seq = list(&dir)?
+            .into_iter()
+            .map(|(_, seq)| seq)
+            .max()
+            .unwrap_or(0);
+        let mut stamp = Vec::new();
+        Stamp::write(&mut stamp)?;
+        let mut shipper = Self {
+            dir,
+            seq,
+            segments: 0,
+            empty_len: stamp.len() as u64,
+        };
+        // Records logged by a primary that died before shipping them are not known to be in
+        // the state it restarted from
+        match fs::remove_file(shipper.dir.join(OPEN_SEGMENT)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        shipper.checkpoint(table)?;
+        Ok(shipper)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Ships the transactions handled since the last shipment, if any, along with a snapshot
+    /// when one is due
+    pub fn ship(&mut self, table: &mut ClientTable) -> io::Result<()> {
+        self.close_segment(table)?;
+        if self.segments >= SNAPSHOT_EVERY {
+            self.ship_snapshot(table)?;
+        }
+        self.open_segment(table)
+    }
+
+    /// Ships the pending transactions and a snapshot of the current state, used after changes
+    /// that are not transactions and so are missing from the segments, such as a clearing sweep
+    pub fn checkpoint(&mut self, table: &mut ClientTable) -> io::Result<()> {
+        self.close_segment(table)?;
+        self.ship_snapshot(table)?;
+        self.open_segment(table)
+    }
+
+    /// Ships the final state and detaches the open segment from `table`
+    pub fn finish(mut self, table: &mut ClientTable) -> io::Result<()> {
+        self.close_segment(table)?;
+        self.ship_snapshot(table)
+    }
+
+    fn open_segment(&mut self, table: &mut ClientTable) -> io::Result<()> {
+        table.set_wal(Wal::open(self.dir.join(OPEN_SEGMENT), CompatCheck::Strict)?);
+        Ok(())
+    }
+
+    /// Ships the open segment if it holds records, an empty one is dropped
+    fn close_segment(&mut self, table: &mut ClientTable) -> io::Result<()> {
+        let open = self.dir.join(OPEN_SEGMENT);
+        if table.take_wal().is_none() {
+            return Ok(());
+        }
+        if fs::metadata(&open)?.len() <= self.empty_len {
+            return fs::remove_file(open);
+        }
+        self.seq += 1;
+        self.segments += 1;
+        fs::rename(open, Shipped::Segment.path(&self.dir, self.seq))
+    }
+
+    fn ship_snapshot(&mut self, table: &ClientTable) -> io::Result<()> {
+        self.seq += 1;
+        self.segments = 0;
+        table.snapshot(Shipped::Snapshot.path(&self.dir, self.seq))?;
+        // A standby behind the snapshot restores it rather than replaying what came before
+        for (kind, seq) in list(&self.dir)? {
+            if seq < self.seq {
+                match fs::remove_file(kind.path(&self.dir, seq)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a table up to date with the files a `Shipper` writes to a directory
+///
+/// Each poll restores the newest snapshot if it is ahead of what was applied, then replays the
+/// segments that follow in sequence order. The table must be configured like the primary, its
+/// policy, currency precision and schedules are not shipped
+#[derive(Debug)]
+pub struct Standby {
+    dir: PathBuf,
+    check: CompatCheck,
+    /// Sequence number of the last file applied, 0 before the first one
+    applied: u64,
+}
+
+impl Standby {
+    pub fn new(dir: impl Into<PathBuf>, check: CompatCheck) -> Self {
+        Self {
+            dir: dir.into(),
+            check,
+            applied: 0,
+        }
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Applies every file shipped since the last poll and returns how many, a missing directory
+    /// counts as nothing shipped yet
+    pub fn poll(&mut self, table: &mut ClientTable) -> Result<usize, EngineError> {
+        let shipped = match list(&self.dir) {
+            Ok(shipped) => shipped,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut applied = 0;
+        let latest = shipped
+            .iter()
+            .filter(|&&(kind, _)| kind == Shipped::Snapshot)
+            .map(|&(_, seq)| seq)
+            .max();
+        if let Some(seq) = latest.filter(|&seq| seq > self.applied) {
+            match table.restore(Shipped::Snapshot.path(&self.dir, seq), self.check) {
+                Ok(()) => {
+                    self.applied = seq;
+                    applied += 1;
+                }
+                // Superseded by a newer snapshot since the listing, picked up on the next poll
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(applied),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut segments: Vec<_> = shipped
+            .into_iter()
+            .filter(|&(kind, seq)| kind == Shipped::Segment && seq > self.applied)
+            .map(|(_, seq)| seq)
+            .collect();
+        segments.sort_unstable();
+        for seq in segments {
+            // A gap is a segment still being renamed into place
+            if seq != self.applied + 1 {
+                break;
+            }
+            // Every segment is a log of its own, started from the state the previous one ended in
+            table.wal_position = 0;
+            table.recover_from_wal(Shipped::Segment.path(&self.dir, seq), self.check)?;
+            self.applied = seq;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Polls every `interval` until `token` is cancelled or a `PROMOTE` file appears in the
+    /// directory, which is consumed so the next standby following it waits for its own promotion.
+    /// The files shipped before the promotion are applied before returning
+    pub fn follow(
+        &mut self,
+        table: &mut ClientTable,
+        token: &CancellationToken,
+        interval: Duration,
+    ) -> Result<(), EngineError> {
+        let promote = self.dir.join(PROMOTE);
+        loop {
+            let promoted = promote.exists();
+            self.poll(table)?;
+            if promoted {
+                fs::remove_file(&promote)?;
+                return Ok(());
+            }
+            if token.is_cancelled() {
+                return Ok(());
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::deposit;
+
+    #[test]
+    fn standby_follows_shipped_segments_and_snapshots() {
+        let dir = std::env::temp_dir().join(format!("bank_ship_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut primary = ClientTable::new();
+        primary.process(vec![deposit(1, 1, 10000)]);
+        let mut shipper = Shipper::start(&dir, &mut primary).unwrap();
+        let mut replica = ClientTable::new();
+        let mut standby = Standby::new(&dir, CompatCheck::Strict);
+        assert_eq!(standby.poll(&mut replica).unwrap(), 1);
+        assert_eq!(replica.to_string(), primary.to_string());
+
+        primary.process(vec![deposit(1, 2, 10000)]);
+        shipper.ship(&mut primary).unwrap();
+        // Nothing was handled since, so nothing is shipped
+        shipper.ship(&mut primary).unwrap();
+        primary.process(vec![deposit(1, 3, 10000)]);
+        assert_eq!(standby.poll(&mut replica).unwrap(), 1);
+        assert_ne!(replica.to_string(), primary.to_string());
+        shipper.ship(&mut primary).unwrap();
+        assert_eq!(standby.poll(&mut replica).unwrap(), 1);
+        assert_eq!(replica.to_string(), primary.to_string());
+
+        // A clearing sweep is not a transaction and needs a snapshot
+        primary.clear();
+        primary.process(vec![deposit(1, 4, 10000)]);
+        shipper.checkpoint(&mut primary).unwrap();
+        primary.process(vec![deposit(1, 5, 10000)]);
+        shipper.finish(&mut primary).unwrap();
+        assert!(primary.take_wal().is_none());
+        // A late standby starts from the last snapshot, the older files are gone
+        let mut late = ClientTable::new();
+        assert_eq!(
+            Standby::new(&dir, CompatCheck::Strict)
+                .poll(&mut late)
+                .unwrap(),
+            1
+        );
+        fs::write(dir.join(PROMOTE), "").unwrap();
+        standby
+            .follow(&mut replica, &CancellationToken::new(), Duration::ZERO)
+            .unwrap();
+        assert!(!dir.join(PROMOTE).exists());
+        assert_eq!(list(&dir).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replica.to_string(), primary.to_string());
+        assert_eq!(late.to_string(), primary.to_string());
+        assert_eq!(replica.business_day(), 1);
+    }
+}
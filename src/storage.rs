@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, io, mem,
+    ops::{Index, IndexMut},
+};
+
+use crate::{
+    client_info::ClientInfo,
+    policy::HistoryLookup,
+    transaction::{ClientId, Transaction},
+    tx_index::IndexStrategy,
+};
+
+static EMPTY: ClientInfo = ClientInfo::EMPTY;
+
+/// Backend holding the per client state of a `ClientTable`, plugged in with `ClientStorage::custom`
+///
+/// The engine reads a client through `get` and changes it through `update`, both hand out the
+/// state itself rather than a copy. A store backed by disk or an embedded database such as sled
+/// keeps the clients it hands out in a cache, loading them on first access and writing the
+/// updated ones back as it sees fit. Clients are iterated in client id order, like the reports
+pub trait ClientStore: fmt::Debug + Send {
+    /// State of `client`, clients never touched read as an empty account
+    fn get(&self, client: ClientId) -> &ClientInfo;
+
+    /// State of `client` for an update, allocating it if needed
+    fn update(&mut self, client: ClientId) -> &mut ClientInfo;
+
+    /// Every allocated client in client id order, this includes clients which were only touched
+    /// by rejected transactions
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_>;
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_>;
+
+    /// Empty store of the same kind, filled by restores and imports before replacing this one
+    fn empty_like(&self) -> Box<dyn ClientStore>;
+
+    /// Writes the updates a persistent store still holds back
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Dense storage, one slot per possible client id
+impl ClientStore for Vec<ClientInfo> {
+    fn get(&self, client: ClientId) -> &ClientInfo {
+        &self[client as usize]
+    }
+
+    fn update(&mut self, client: ClientId) -> &mut ClientInfo {
+        &mut self[client as usize]
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+        Box::new(
+            self[..]
+                .iter()
+                .enumerate()
+                .map(|(client, info)| (client as ClientId, info)),
+        )
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+        Box::new(
+            self[..]
+                .iter_mut()
+                .enumerate()
+                .map(|(client, info)| (client as ClientId, info)),
+        )
+    }
+
+    fn empty_like(&self) -> Box<dyn ClientStore> {
+        Box::new(vec![ClientInfo::default(); self.len()])
+    }
+}
+
+/// Sparse storage, only the clients touched are allocated
+impl ClientStore for BTreeMap<ClientId, ClientInfo> {
+    fn get(&self, client: ClientId) -> &ClientInfo {
+        BTreeMap::get(self, &client).unwrap_or(&EMPTY)
+    }
+
+    fn update(&mut self, client: ClientId) -> &mut ClientInfo {
+        self.entry(client).or_default()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|(&client, info)| (client, info)))
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+        Box::new(BTreeMap::iter_mut(self).map(|(&client, info)| (client, info)))
+    }
+
+    fn empty_like(&self) -> Box<dyn ClientStore> {
+        Box::new(BTreeMap::new())
+    }
+}
+
+/// Storage of the per client state of a `ClientTable`
+///
+/// `Dense` allocates every possible client up front, giving the fastest lookups when an input
+/// touches a lot of clients. `Sparse` only allocates the clients actually touched, which is a lot
+/// cheaper to create and to iterate for inputs with a handful of clients. A `BTreeMap` is used
+/// rather than a `HashMap` so both variants iterate in client id order. `Custom` holds any other
+/// `ClientStore`, for datasets that don't fit in memory
+#[derive(Debug)]
+pub enum ClientStorage {
+    Dense(Vec<ClientInfo>),
+    Sparse(BTreeMap<ClientId, ClientInfo>),
+    Custom(Box<dyn ClientStore>),
+}
+
+impl ClientStorage {
+    pub fn dense() -> Self {
+        // One slot per possible id, `ClientId::MAX` included
+        ClientStorage::Dense(vec![Default::default(); usize::from(ClientId::MAX) + 1])
+    }
+
+    pub fn sparse() -> Self {
+        ClientStorage::Sparse(BTreeMap::new())
+    }
+
+    pub fn custom(store: impl ClientStore + 'static) -> Self {
+        ClientStorage::Custom(Box::new(store))
+    }
+
+    pub fn is_dense(&self) -> bool {
+        matches!(self, ClientStorage::Dense(_))
+    }
+
+    fn store(&self) -> &dyn ClientStore {
+        match self {
+            ClientStorage::Dense(clients) => clients,
+            ClientStorage::Sparse(clients) => clients,
+            ClientStorage::Custom(store) => store.as_ref(),
+        }
+    }
+
+    fn store_mut(&mut self) -> &mut dyn ClientStore {
+        match self {
+            ClientStorage::Dense(clients) => clients,
+            ClientStorage::Sparse(clients) => clients,
+            ClientStorage::Custom(store) => store.as_mut(),
+        }
+    }
+
+    /// Moves the clients over to the other kind of storage if needed, a custom store is kept as
+    /// it was picked on purpose
+    pub fn convert(&mut self, dense: bool) {
+        if self.is_dense() == dense || matches!(self, ClientStorage::Custom(_)) {
+            return;
+        }
+        let empty = if dense { Self::dense() } else { Self::sparse() };
+        match mem::replace(self, empty) {
+            ClientStorage::Dense(clients) => {
+                for (client, info) in clients.into_iter().enumerate() {
+                    if !info.is_pristine() {
+                        self[client as ClientId] = info;
+                    }
+                }
+            }
+            ClientStorage::Sparse(clients) => {
+                for (client, info) in clients {
+                    self[client] = info;
+                }
+            }
+            ClientStorage::Custom(_) => unreachable!("custom stores are never converted"),
+        }
+    }
+
+    /// Empty storage of the same kind
+    pub fn empty_like(&self) -> Self {
+        match self {
+            ClientStorage::Dense(_) => Self::dense(),
+            ClientStorage::Sparse(_) => Self::sparse(),
+            ClientStorage::Custom(store) => ClientStorage::Custom(store.empty_like()),
+        }
+    }
+
+    /// Iterates over every allocated client in client id order, this includes clients which were
+    /// only touched by rejected transactions so callers usually filter on `ClientInfo::exists`
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+        self.store().iter()
+    }
+
+    pub fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+        self.store_mut().iter_mut()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.store_mut().flush()
+    }
+}
+
+/// Storage, history lookup and transaction index picked from a sample of the input by
+/// `Layout::choose`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub dense: bool,
+    pub history: HistoryLookup,
+    pub tx_index: IndexStrategy,
+    /// Transactions in the sample
+    pub sampled: usize,
+    /// Distinct clients in the sample
+    pub clients: usize,
+    /// Disputes in the sample
+    pub disputes: usize,
+}
+
+impl Layout {
+    /// Number of leading transactions worth sampling
+    pub const SAMPLE_SIZE: usize = 4096;
+    /// Distinct clients from which allocating every client up front pays off
+    pub const DENSE_CLIENTS: usize = 1024;
+    /// Share of disputes, in percent, from which indexing the histories pays off
+    pub const INDEXED_DISPUTE_PERCENT: usize = 1;
+
+    /// Dense storage when the sample already spreads over many clients, sparse otherwise
+    /// Indexed histories when disputes are frequent, scanned ones otherwise
+    /// The transaction index suited to the density of the ids the sample claims
+    pub fn choose(sample: &[Transaction]) -> Self {
+        let clients = sample
+            .iter()
+            .map(Transaction::client)
+            .collect::<HashSet<_>>()
+            .len();
+        let disputes = sample
+            .iter()
+            .filter(|t| matches!(t, Transaction::Dispute { .. }))
+            .count();
+        let history =
+            if disputes > 0 && disputes * 100 >= sample.len() * Self::INDEXED_DISPUTE_PERCENT {
+                HistoryLookup::Indexed
+            } else {
+                HistoryLookup::Scan
+            };
+        // Only the records moving funds claim their id
+        let tx_index = IndexStrategy::choose(
+            sample
+                .iter()
+                .filter(|t| t.amount().is_some())
+                .map(Transaction::tx),
+        );
+        Layout {
+            dense: clients >= Self::DENSE_CLIENTS,
+            history,
+            tx_index,
+            sampled: sample.len(),
+            clients,
+            disputes,
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} storage with {} histories and a {} transaction index ({} clients and {} disputes in the first {} transactions)",
+            if self.dense { "dense" } else { "sparse" },
+            match self.history {
+                HistoryLookup::Scan => "scanned",
+                HistoryLookup::Indexed => "indexed",
+            },
+            self.tx_index.name(),
+            self.clients,
+            self.disputes,
+            self.sampled
+        )
+    }
+}
+
+/// Clients never touched read as an empty account
+impl Index<ClientId> for ClientStorage {
+    type Output = ClientInfo;
+
+    fn index(&self, client: ClientId) -> &ClientInfo {
+        match self {
+            ClientStorage::Dense(clients) => &clients[client as usize],
+            ClientStorage::Sparse(clients) => clients.get(&client).unwrap_or(&EMPTY),
+            ClientStorage::Custom(store) => store.get(client),
+        }
+    }
+}
+
+/// Mutable access allocates the client in sparse storage
+impl IndexMut<ClientId> for ClientStorage {
+    fn index_mut(&mut self, client: ClientId) -> &mut ClientInfo {
+        match self {
+            ClientStorage::Dense(clients) => &mut clients[client as usize],
+            ClientStorage::Sparse(clients) => clients.entry(client).or_default(),
+            ClientStorage::Custom(store) => store.update(client),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{currency::Currency, payment_engine::ClientTable, policy::Policy};
+
+    #[test]
+    fn sparse_only_allocates_touched_clients() {
+        let mut storage = ClientStorage::sparse();
+        assert!(!storage[7].exists());
+        storage[7]
+            .deposit(Currency::new(10000), 1, &Policy::default())
+            .unwrap();
+        storage[3]
+            .deposit(Currency::new(10000), 2, &Policy::default())
+            .unwrap();
+        let clients: Vec<_> = storage.iter().map(|(client, _)| client).collect();
+        assert_eq!(clients, vec![3, 7]);
+        assert!(storage[7].exists());
+    }
+
+    #[test]
+    fn layout_follows_the_sample() {
+        let deposit = |client, tx| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(10000),
+        };
+        let few: Vec<_> = (0..100).map(|tx| deposit(tx as ClientId % 3, tx)).collect();
+        let layout = Layout::choose(&few);
+        assert!(!layout.dense);
+        assert_eq!(layout.history, HistoryLookup::Scan);
+        assert_eq!(layout.tx_index, IndexStrategy::Direct);
+
+        let mut many: Vec<_> = (0..2000).map(|tx| deposit(tx as ClientId, tx)).collect();
+        many.extend((0..50).map(|tx| Transaction::Dispute {
+            client: tx as ClientId,
+            tx,
+        }));
+        let layout = Layout::choose(&many);
+        assert!(layout.dense);
+        assert_eq!(layout.history, HistoryLookup::Indexed);
+        assert_eq!((layout.clients, layout.disputes), (2000, 50));
+    }
+
+    #[test]
+    fn dense_storage_holds_the_highest_client_id() {
+        let mut storage = ClientStorage::dense();
+        storage[ClientId::MAX]
+            .deposit(Currency::new(10000), 1, &Policy::default())
+            .unwrap();
+        let clients: Vec<_> = storage.iter().map(|(client, _)| client).collect();
+        assert!(clients.contains(&ClientId::MAX));
+    }
+
+    #[test]
+    fn convert_keeps_the_clients() {
+        let mut storage = ClientStorage::sparse();
+        storage[9]
+            .deposit(Currency::new(10000), 1, &Policy::default())
+            .unwrap();
+        storage.convert(true);
+        assert!(storage.is_dense());
+        assert_eq!(storage[9].available_funds(), Currency::new(10000));
+        storage.convert(false);
+        let clients: Vec<_> = storage.iter().map(|(client, _)| client).collect();
+        assert_eq!(clients, vec![9]);
+    }
+
+    #[test]
+    fn dense_and_sparse_iterate_in_the_same_order() {
+        let mut dense = ClientStorage::dense();
+        let mut sparse = ClientStorage::sparse();
+        for (tx, &client) in [500, 2, 40].iter().enumerate() {
+            for storage in [&mut dense, &mut sparse] {
+                storage[client]
+                    .deposit(Currency::new(10000), tx as u32, &Policy::default())
+                    .unwrap();
+            }
+        }
+        let existing = |s: &ClientStorage| -> Vec<ClientId> {
+            s.iter()
+                .filter(|(_, info)| info.exists())
+                .map(|(client, _)| client)
+                .collect()
+        };
+        assert_eq!(existing(&dense), vec![2, 40, 500]);
+        assert_eq!(existing(&sparse), existing(&dense));
+    }
+
+    /// Store keeping the clients in a map of its own, like a disk backed store caching them would
+    #[derive(Debug, Default)]
+    struct Cached {
+        clients: BTreeMap<ClientId, ClientInfo>,
+    }
+
+    impl ClientStore for Cached {
+        fn get(&self, client: ClientId) -> &ClientInfo {
+            self.clients.get(&client).unwrap_or(&EMPTY)
+        }
+
+        fn update(&mut self, client: ClientId) -> &mut ClientInfo {
+            self.clients.entry(client).or_default()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+            ClientStore::iter(&self.clients)
+        }
+
+        fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+            ClientStore::iter_mut(&mut self.clients)
+        }
+
+        fn empty_like(&self) -> Box<dyn ClientStore> {
+            Box::new(Cached::default())
+        }
+    }
+
+    #[test]
+    fn tables_run_on_a_custom_store() {
+        let deposit = |client, tx| Transaction::Deposit {
+            client,
+            tx,
+            amount: Currency::new(10000),
+        };
+        let mut table =
+            ClientTable::with_storage(ClientStorage::custom(Cached::default()), Policy::default());
+        let many: Vec<_> = (0..2000).map(|tx| deposit(tx as ClientId, tx)).collect();
+        // The sample asks for dense storage, the custom store is kept anyway
+        assert!(!table.adapt(&many).dense);
+        table.process(many);
+        assert!(matches!(table.clients, ClientStorage::Custom(_)));
+        assert_eq!(table.clients[1999].available_funds(), Currency::new(10000));
+        assert_eq!(table.clients.iter().count(), 2000);
+    }
+}
@@ -0,0 +1,24 @@
+//! Records shared by the unit tests
+
+use crate::{
+    currency::Currency,
+    transaction::{ClientId, Transaction, TxId},
+};
+
+/// Deposit of `amount` in the smallest currency unit
+pub(crate) fn deposit(client: ClientId, tx: TxId, amount: i64) -> Transaction {
+    Transaction::Deposit {
+        client,
+        tx,
+        amount: Currency::new(amount),
+    }
+}
+
+/// Withdrawal of `amount` in the smallest currency unit
+pub(crate) fn withdraw(client: ClientId, tx: TxId, amount: i64) -> Transaction {
+    Transaction::Withdraw {
+        client,
+        tx,
+        amount: Currency::new(amount),
+    }
+}
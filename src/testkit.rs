@@ -0,0 +1,247 @@
+use std::fmt;
+
+use proptest::{collection::SizeRange, prelude::*, sample::Index};
+
+use crate::{
+    client_info::ClientInfo,
+    currency::Currency,
+    payment_engine::ClientTable,
+    transaction::{ClientId, Transaction, TxId},
+};
+
+/// Strategy for feeds of arbitrary transactions over clients `1..=clients`, moving at most
+/// `max_amount` in the smallest currency unit at once
+///
+/// Deposits and withdrawals get fresh transaction ids, disputes, resolves and chargebacks mostly
+/// reference earlier transactions of the right client so they reach the dispute handling, with
+/// now and then a wrong client or an unknown id. Feeds shrink towards fewer and smaller
+/// transactions
+pub fn feed(
+    clients: ClientId,
+    max_amount: i64,
+    len: impl Into<SizeRange>,
+) -> impl Strategy<Value = Vec<Transaction>> {
+    let record = (
+        0..100u8,
+        1..=clients.max(1),
+        1..=max_amount.max(1),
+        any::<Index>(),
+        0..10u8,
+        0..100 as TxId,
+    );
+    proptest::collection::vec(record, len).prop_map(|records| {
+        let mut next_tx: TxId = 1;
+        let mut issued: Vec<(ClientId, TxId)> = Vec::new();
+        let mut feed = Vec::with_capacity(records.len());
+        for (roll, client, amount, pick, miss, unknown) in records {
+            if roll < 65 {
+                let (tx, amount) = (next_tx, Currency::new(amount));
+                next_tx += 1;
+                issued.push((client, tx));
+                feed.push(if roll < 40 {
+                    Transaction::Deposit { client, tx, amount }
+                } else {
+                    Transaction::Withdraw { client, tx, amount }
+                });
+                continue;
+            }
+            // An unknown id one time in ten, an earlier transaction of another client one time in ten
+            let (client, tx) = match issued.get(pick.index(issued.len().max(1))) {
+                Some(&(_, tx)) if miss == 1 => (client, tx),
+                Some(&reference) if miss != 0 => reference,
+                _ => (client, next_tx + unknown),
+            };
+            feed.push(match roll {
+                65..=79 => Transaction::Dispute { client, tx },
+                80..=91 => Transaction::Resolve { client, tx },
+                _ => Transaction::Chargeback { client, tx },
+            });
+        }
+        feed
+    })
+}
+
+/// Strategy for a single arbitrary transaction, see `feed`
+pub fn transaction(clients: ClientId, max_amount: i64) -> impl Strategy<Value = Transaction> {
+    feed(clients, max_amount, 1).prop_map(|feed| feed[0])
+}
+
+/// Broken invariant found by the checkers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Transaction after which the invariant broke, `None` when checking a table on its own
+    pub transaction: Option<Transaction>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.transaction {
+            Some(t) => write!(f, "after {:?}: {}", t, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn violation(message: String) -> Violation {
+    Violation {
+        transaction: None,
+        message,
+    }
+}
+
+/// The held funds of every account are exactly the amounts put on hold by its open disputes
+pub fn check_held_matches_disputes(table: &ClientTable) -> Result<(), Violation> {
+    accounts(table).try_for_each(|(owner, info)| held_matches_disputes(&owner, info))
+}
+
+/// Only a chargeback can take the total funds of an account below zero, and it locks the account
+pub fn check_no_unexplained_negative(table: &ClientTable) -> Result<(), Violation> {
+    accounts(table).try_for_each(|(owner, info)| no_unexplained_negative(&owner, info))
+}
+
+/// Every invariant that holds for a table at rest
+pub fn check_invariants(table: &ClientTable) -> Result<(), Violation> {
+    check_held_matches_disputes(table)?;
+    check_no_unexplained_negative(table)
+}
+
+/// Applies the transactions one at a time, checking after each of them that the accounts of its
+/// client keep the invariants and that a rejected transaction left them untouched. The whole
+/// table is checked once at the end. Stops at the first violation
+pub fn run_checked(
+    table: &mut ClientTable,
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> Result<(), Violation> {
+    for t in transactions {
+        let client = t.client();
+        let before: Vec<_> = client_accounts(table, client)
+            .map(|(_, info)| info.to_string())
+            .collect();
+        let outcome = table.handle_transaction(t);
+        let changed = || {
+            client_accounts(table, client)
+                .map(|(_, info)| info.to_string())
+                .ne(before.iter().cloned())
+        };
+        let checked = match outcome {
+            Err(e) if changed() => Err(violation(format!(
+                "rejected with {:?} but the balances changed",
+                e
+            ))),
+            _ => client_accounts(table, client).try_for_each(|(owner, info)| {
+                held_matches_disputes(&owner, info)?;
+                no_unexplained_negative(&owner, info)
+            }),
+        };
+        checked.map_err(|v| Violation {
+            transaction: Some(t),
+            ..v
+        })?;
+    }
+    check_invariants(table)
+}
+
+fn held_matches_disputes(owner: &str, info: &ClientInfo) -> Result<(), Violation> {
+    let disputed = Currency::sum_of(
+        "disputed total",
+        info.open_disputes().iter().map(|d| d.disputed_amount()),
+    )
+    .map_err(|e| violation(format!("{} {}", owner, e)))?;
+    if info.held_funds() != disputed {
+        return Err(violation(format!(
+            "{} holds {} while its open disputes hold {}",
+            owner,
+            info.held_funds(),
+            disputed
+        )));
+    }
+    Ok(())
+}
+
+fn no_unexplained_negative(owner: &str, info: &ClientInfo) -> Result<(), Violation> {
+    let total = info
+        .total_funds()
+        .map_err(|e| violation(format!("{}: {}", owner, e)))?;
+    if total < Currency::ZERO && !info.is_locked() {
+        return Err(violation(format!(
+            "{} has negative total funds {} without a chargeback",
+            owner, total
+        )));
+    }
+    Ok(())
+}
+
+/// Every client account including the foreign ones, with a name for the messages. The house
+/// account is left out, it pays fees on behalf of clients and has no disputes
+fn accounts(table: &ClientTable) -> impl Iterator<Item = (String, &ClientInfo)> {
+    let clients = table
+        .clients
+        .iter()
+        .map(|(client, info)| (format!("client {}", client), info));
+    clients.chain(foreign_accounts(table, None))
+}
+
+/// Accounts of a single client
+fn client_accounts(
+    table: &ClientTable,
+    client: ClientId,
+) -> impl Iterator<Item = (String, &ClientInfo)> {
+    let base = (format!("client {}", client), &table.clients[client]);
+    std::iter::once(base).chain(foreign_accounts(table, Some(client)))
+}
+
+/// Foreign accounts of every client, or only of `client`
+fn foreign_accounts(
+    table: &ClientTable,
+    client: Option<ClientId>,
+) -> impl Iterator<Item = (String, &ClientInfo)> {
+    table
+        .foreign
+        .iter()
+        .filter(move |((owner, _), _)| client.is_none_or(|client| client == *owner))
+        .map(|((client, code), info)| (format!("client {} in {}", client, code), info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_info::{ClientTransaction, TransferKind};
+
+    proptest! {
+        #[test]
+        fn feeds_reference_earlier_transactions(feed in feed(3, 100, 0..50)) {
+            let mut issued = Vec::new();
+            for t in &feed {
+                prop_assert!((1..=3).contains(&t.client()));
+                match *t {
+                    Transaction::Deposit { tx, amount, .. }
+                    | Transaction::Withdraw { tx, amount, .. } => {
+                        prop_assert_eq!(tx as usize, issued.len() + 1);
+                        prop_assert!(amount > Currency::ZERO && amount <= Currency::new(100));
+                        issued.push(tx);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checkers_report_broken_invariants() {
+        let mut table = ClientTable::new();
+        table.clients[1]
+            .restore_entry(
+                "dispute",
+                ClientTransaction::new(TransferKind::Deposit, Currency::new(10000), 1),
+            )
+            .unwrap();
+        let violation = check_invariants(&table).unwrap_err();
+        assert_eq!(
+            violation.message,
+            "client 1 holds 0.0000 while its open disputes hold 1.0000"
+        );
+    }
+}
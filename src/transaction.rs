@@ -1,9 +1,24 @@
-use crate::currency::Currency;
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use crate::currency::{Currency, CurrencyCode};
 
 pub type ClientId = u16;
 pub type TxId = u32;
+/// Operator signing off an `approve` record
+pub type ApproverId = u32;
+/// Seconds since the Unix epoch, as given by the optional timestamp column of the input
+pub type Timestamp = u64;
 
+/// With the `serde` feature a transaction is an object tagged by its `type`, the name of its
+/// record type such as `withdrawal` or `legal_hold`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
 pub enum Transaction {
+    #[cfg_attr(feature = "serde", serde(rename = "withdrawal"))]
     Withdraw {
         client: ClientId,
         tx: TxId,
@@ -26,4 +41,336 @@ pub enum Transaction {
         client: ClientId,
         tx: TxId,
     },
+    /// Deposit into the client's account in a currency other than the base one
+    ForeignDeposit {
+        client: ClientId,
+        tx: TxId,
+        code: CurrencyCode,
+        amount: Currency,
+    },
+    /// Withdrawal from the client's account in a currency other than the base one
+    #[cfg_attr(feature = "serde", serde(rename = "foreign_withdrawal"))]
+    ForeignWithdraw {
+        client: ClientId,
+        tx: TxId,
+        code: CurrencyCode,
+        amount: Currency,
+    },
+    /// Exchanges `amount` of the client's funds in currency `from`, the base currency if `None`,
+    /// for funds in currency `to` at the current rate, see `RateTable`
+    Convert {
+        client: ClientId,
+        tx: TxId,
+        from: Option<CurrencyCode>,
+        to: CurrencyCode,
+        amount: Currency,
+    },
+    /// Deposit booked now whose funds only become available once the engine clock reaches
+    /// `value_date`, see `SettlementPolicy`
+    Booking {
+        client: ClientId,
+        tx: TxId,
+        amount: Currency,
+        value_date: u64,
+    },
+    /// Court order ring-fencing `amount` of the available funds from withdrawals, or the whole
+    /// available balance when no amount is given. `tx` identifies the order
+    LegalHold {
+        client: ClientId,
+        tx: TxId,
+        amount: Option<Currency>,
+    },
+    /// Lifts the legal hold order `tx`
+    #[cfg_attr(feature = "serde", serde(rename = "release"))]
+    ReleaseHold {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Operator sign-off on a deposit or withdrawal held for approval, it is released once
+    /// `ApprovalPolicy::approvers` distinct operators signed off. The approver is read from the
+    /// amount column
+    Approve {
+        client: ClientId,
+        tx: TxId,
+        approver: ApproverId,
+    },
+    /// Closes an accrual day for every account, posting the schedules due that day under `tx`,
+    /// see `Schedules`. The client column is not used
+    Accrue {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Reinstates an account locked by the chargeback of `tx`, also reversing the funds the
+    /// chargeback moved when the table is set to, see `ClientTable::set_unlock_reverses`
+    Unlock {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Merchant refund crediting withdrawal `tx` back in full. Unlike a dispute it is final, the
+    /// withdrawal can be refunded once and can't be disputed afterwards
+    Refund {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Opens batch `tx`, the records up to the matching `Commit` apply together or not at all,
+    /// see `ClientTable::apply_batch`. The client column is not used
+    Begin {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Applies the records of batch `tx`, rolling all of them back if one is rejected
+    Commit {
+        client: ClientId,
+        tx: TxId,
+    },
+    /// Discards the records of batch `tx` without applying them
+    Rollback {
+        client: ClientId,
+        tx: TxId,
+    },
+}
+
+impl Transaction {
+    pub fn client(&self) -> ClientId {
+        use Transaction::*;
+        match *self {
+            Withdraw { client, .. }
+            | Deposit { client, .. }
+            | Dispute { client, .. }
+            | Resolve { client, .. }
+            | Chargeback { client, .. }
+            | ForeignDeposit { client, .. }
+            | ForeignWithdraw { client, .. }
+            | Convert { client, .. }
+            | Booking { client, .. }
+            | LegalHold { client, .. }
+            | ReleaseHold { client, .. }
+            | Approve { client, .. }
+            | Accrue { client, .. }
+            | Unlock { client, .. }
+            | Refund { client, .. }
+            | Begin { client, .. }
+            | Commit { client, .. }
+            | Rollback { client, .. } => client,
+        }
+    }
+
+    pub fn tx(&self) -> TxId {
+        use Transaction::*;
+        match *self {
+            Withdraw { tx, .. }
+            | Deposit { tx, .. }
+            | Dispute { tx, .. }
+            | Resolve { tx, .. }
+            | Chargeback { tx, .. }
+            | ForeignDeposit { tx, .. }
+            | ForeignWithdraw { tx, .. }
+            | Convert { tx, .. }
+            | Booking { tx, .. }
+            | LegalHold { tx, .. }
+            | ReleaseHold { tx, .. }
+            | Approve { tx, .. }
+            | Accrue { tx, .. }
+            | Unlock { tx, .. }
+            | Refund { tx, .. }
+            | Begin { tx, .. }
+            | Commit { tx, .. }
+            | Rollback { tx, .. } => tx,
+        }
+    }
+
+    /// Name of the record type as used in the input files
+    pub fn kind_name(&self) -> &'static str {
+        use Transaction::*;
+        match self {
+            Withdraw { .. } => "withdrawal",
+            Deposit { .. } => "deposit",
+            Dispute { .. } => "dispute",
+            Resolve { .. } => "resolve",
+            Chargeback { .. } => "chargeback",
+            ForeignDeposit { .. } => "deposit",
+            ForeignWithdraw { .. } => "withdrawal",
+            Convert { .. } => "convert",
+            Booking { .. } => "booking",
+            LegalHold { .. } => "legal_hold",
+            ReleaseHold { .. } => "release",
+            Approve { .. } => "approve",
+            Accrue { .. } => "accrue",
+            Unlock { .. } => "unlock",
+            Refund { .. } => "refund",
+            Begin { .. } => "begin",
+            Commit { .. } => "commit",
+            Rollback { .. } => "rollback",
+        }
+    }
+
+    /// Amount moved by a deposit, booking, withdrawal or conversion, the other records only reference an earlier transaction
+    pub fn amount(&self) -> Option<Currency> {
+        match *self {
+            Transaction::Withdraw { amount, .. }
+            | Transaction::Deposit { amount, .. }
+            | Transaction::ForeignDeposit { amount, .. }
+            | Transaction::ForeignWithdraw { amount, .. }
+            | Transaction::Convert { amount, .. }
+            | Transaction::Booking { amount, .. } => Some(amount),
+            _ => None,
+        }
+    }
+
+    /// Amount column of the record, the moved amount or the amount put under legal hold
+    pub fn record_amount(&self) -> Option<Currency> {
+        match *self {
+            Transaction::LegalHold { amount, .. } => amount,
+            _ => self.amount(),
+        }
+    }
+
+    /// Operator signing off an approval, written in the amount column
+    pub fn approver(&self) -> Option<ApproverId> {
+        match *self {
+            Transaction::Approve { approver, .. } => Some(approver),
+            _ => None,
+        }
+    }
+
+    /// Currency column of the record, only deposits, withdrawals and conversions in a foreign
+    /// currency have one
+    pub fn currency_code(&self) -> Option<CurrencyCode> {
+        match *self {
+            Transaction::ForeignDeposit { code, .. }
+            | Transaction::ForeignWithdraw { code, .. } => Some(code),
+            Transaction::Convert { from, .. } => from,
+            _ => None,
+        }
+    }
+
+    /// Currency a conversion goes to, written in the column after the amount
+    pub fn target_currency(&self) -> Option<CurrencyCode> {
+        match *self {
+            Transaction::Convert { to, .. } => Some(to),
+            _ => None,
+        }
+    }
+
+    /// Moves a deposit, withdrawal or conversion to the account in currency `code`, other records
+    /// reference an earlier transaction and don't take a currency
+    pub fn in_currency(self, code: CurrencyCode) -> Option<Self> {
+        match self {
+            Transaction::Deposit { client, tx, amount } => Some(Transaction::ForeignDeposit {
+                client,
+                tx,
+                code,
+                amount,
+            }),
+            Transaction::Withdraw { client, tx, amount } => Some(Transaction::ForeignWithdraw {
+                client,
+                tx,
+                code,
+                amount,
+            }),
+            Transaction::Convert {
+                client,
+                tx,
+                to,
+                amount,
+                ..
+            } => Some(Transaction::Convert {
+                client,
+                tx,
+                from: Some(code),
+                to,
+                amount,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Engine clock at which the funds of a booking become available
+    pub fn value_date(&self) -> Option<u64> {
+        match *self {
+            Transaction::Booking { value_date, .. } => Some(value_date),
+            _ => None,
+        }
+    }
+}
+
+/// Transaction together with the time it took place, for inputs with a timestamp column, and
+/// the memo of the memo column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stamped {
+    pub transaction: Transaction,
+    pub timestamp: Option<Timestamp>,
+    /// Kept in the history of deposits and withdrawals, ignored for the other records
+    pub memo: Option<Memo>,
+}
+
+impl From<Transaction> for Stamped {
+    fn from(transaction: Transaction) -> Self {
+        Self {
+            transaction,
+            timestamp: None,
+            memo: None,
+        }
+    }
+}
+
+/// Free text memo or tag of a transfer, such as `groceries`. Stored inline so that transactions
+/// stay `Copy`, which is why it is limited to `Memo::MAX_LEN` bytes
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Memo {
+    len: u8,
+    bytes: [u8; Memo::MAX_LEN],
+}
+
+impl Memo {
+    pub const MAX_LEN: usize = 32;
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).expect("memos are utf-8")
+    }
+}
+
+/// A memo that is empty, longer than `Memo::MAX_LEN` bytes or has control characters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidMemo;
+
+impl FromStr for Memo {
+    type Err = InvalidMemo;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > Memo::MAX_LEN || s.chars().any(char::is_control) {
+            return Err(InvalidMemo);
+        }
+        let mut bytes = [0; Memo::MAX_LEN];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Memo {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl fmt::Display for Memo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Memo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Memo").field(&self.as_str()).finish()
+    }
+}
+
+impl PartialOrd for Memo {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Memos sort by their text
+impl Ord for Memo {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
 }
@@ -0,0 +1,411 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, mem,
+};
+
+use crate::transaction::{ClientId, TxId};
+
+/// Transaction ids per chunk of the `Chunked` index, the low 16 bits of an id
+const CHUNK_LEN: usize = 1 << 16;
+
+/// Entries from which a chunk takes less memory as slots than as a sorted list
+const DENSE_CHUNK: usize =
+    (CHUNK_LEN * mem::size_of::<ClientId>() + CHUNK_LEN / 8) / mem::size_of::<(u16, ClientId)>();
+
+/// How the owners of the transaction ids are indexed, see `TxIndex`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexStrategy {
+    /// A hash map, for ids scattered over the whole range
+    #[default]
+    Hashed,
+    /// Chunks of 2^16 ids holding either a sorted list or a slot per id, like a roaring bitmap,
+    /// for ids clustered in a few ranges
+    Chunked,
+    /// A slot per id up to the highest one, for ids mostly handed out in sequence
+    Direct,
+}
+
+impl IndexStrategy {
+    /// Share of the ids up to the highest one, in percent, from which `Direct` is picked
+    pub const DIRECT_PERCENT: usize = 50;
+    /// Average ids per chunk of 2^16 from which `Chunked` is picked
+    pub const CHUNKED_IDS: usize = 64;
+
+    /// Strategy suited to the density of `ids`, a sample of the ids the index will hold
+    pub fn choose(ids: impl IntoIterator<Item = TxId>) -> Self {
+        let ids: HashSet<TxId> = ids.into_iter().collect();
+        let highest = match ids.iter().max() {
+            Some(&highest) => highest as usize,
+            None => return IndexStrategy::Hashed,
+        };
+        let chunks = ids.iter().map(|tx| tx >> 16).collect::<HashSet<_>>().len();
+        if ids.len() * 100 >= (highest + 1) * Self::DIRECT_PERCENT {
+            IndexStrategy::Direct
+        } else if ids.len() >= chunks * Self::CHUNKED_IDS {
+            IndexStrategy::Chunked
+        } else {
+            IndexStrategy::Hashed
+        }
+    }
+
+    /// Name used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            IndexStrategy::Hashed => "hashed",
+            IndexStrategy::Chunked => "chunked",
+            IndexStrategy::Direct => "direct",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hashed" => Some(IndexStrategy::Hashed),
+            "chunked" => Some(IndexStrategy::Chunked),
+            "direct" => Some(IndexStrategy::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// Owner of every applied transaction id, see `ClientTable`
+///
+/// The strategy can be switched at any time with `convert`. A `Direct` index turns itself into a
+/// `Chunked` one when an id far beyond the others would leave most of its slots empty, so a stray
+/// id can't make it allocate gigabytes
+#[derive(Clone, Debug)]
+pub enum TxIndex {
+    Hashed(HashMap<TxId, ClientId>),
+    Chunked(BTreeMap<u16, Chunk>),
+    Direct(Slots),
+}
+
+impl TxIndex {
+    pub fn new(strategy: IndexStrategy) -> Self {
+        match strategy {
+            IndexStrategy::Hashed => TxIndex::Hashed(HashMap::new()),
+            IndexStrategy::Chunked => TxIndex::Chunked(BTreeMap::new()),
+            IndexStrategy::Direct => TxIndex::Direct(Slots::default()),
+        }
+    }
+
+    pub fn strategy(&self) -> IndexStrategy {
+        match self {
+            TxIndex::Hashed(_) => IndexStrategy::Hashed,
+            TxIndex::Chunked(_) => IndexStrategy::Chunked,
+            TxIndex::Direct(_) => IndexStrategy::Direct,
+        }
+    }
+
+    /// Moves the ids over to the index of `strategy` if needed
+    pub fn convert(&mut self, strategy: IndexStrategy) {
+        if self.strategy() == strategy {
+            return;
+        }
+        let old = mem::replace(self, TxIndex::new(strategy));
+        self.extend(old.iter());
+    }
+
+    /// Sets the owner of `tx`, returns the previous one
+    pub fn insert(&mut self, tx: TxId, client: ClientId) -> Option<ClientId> {
+        match self {
+            TxIndex::Hashed(map) => map.insert(tx, client),
+            TxIndex::Chunked(chunks) => chunks
+                .entry((tx >> 16) as u16)
+                .or_default()
+                .insert(tx as u16, client),
+            // Fewer than one slot in 8 would be used
+            TxIndex::Direct(slots)
+                if tx as usize >= slots.owners.len() && (slots.len + 1) * 8 <= tx as usize =>
+            {
+                self.convert(IndexStrategy::Chunked);
+                self.insert(tx, client)
+            }
+            TxIndex::Direct(slots) => slots.insert(tx as usize, client),
+        }
+    }
+
+    /// Forgets `tx`, returns its owner
+    pub fn remove(&mut self, tx: TxId) -> Option<ClientId> {
+        match self {
+            TxIndex::Hashed(map) => map.remove(&tx),
+            TxIndex::Chunked(chunks) => {
+                let high = (tx >> 16) as u16;
+                let chunk = chunks.get_mut(&high)?;
+                let owner = chunk.remove(tx as u16);
+                if chunk.len() == 0 {
+                    chunks.remove(&high);
+                }
+                owner
+            }
+            TxIndex::Direct(slots) => slots.remove(tx as usize),
+        }
+    }
+
+    pub fn get(&self, tx: TxId) -> Option<ClientId> {
+        match self {
+            TxIndex::Hashed(map) => map.get(&tx).copied(),
+            TxIndex::Chunked(chunks) => chunks.get(&((tx >> 16) as u16))?.get(tx as u16),
+            TxIndex::Direct(slots) => slots.get(tx as usize),
+        }
+    }
+
+    pub fn contains(&self, tx: TxId) -> bool {
+        self.get(tx).is_some()
+    }
+
+    /// Number of ids indexed
+    pub fn len(&self) -> usize {
+        match self {
+            TxIndex::Hashed(map) => map.len(),
+            TxIndex::Chunked(chunks) => chunks.values().map(Chunk::len).sum(),
+            TxIndex::Direct(slots) => slots.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Ids with their owner, in id order except for a `Hashed` index
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (TxId, ClientId)> + '_> {
+        match self {
+            TxIndex::Hashed(map) => Box::new(map.iter().map(|(&tx, &client)| (tx, client))),
+            TxIndex::Chunked(chunks) => Box::new(chunks.iter().flat_map(|(&high, chunk)| {
+                chunk
+                    .iter()
+                    .map(move |(low, client)| ((high as TxId) << 16 | low as TxId, client))
+            })),
+            TxIndex::Direct(slots) => {
+                Box::new(slots.iter().map(|(tx, client)| (tx as TxId, client)))
+            }
+        }
+    }
+
+    /// Approximate memory held by the index
+    pub fn bytes(&self) -> usize {
+        match self {
+            // Hash maps keep a control byte per bucket
+            TxIndex::Hashed(map) => map.capacity() * (mem::size_of::<(TxId, ClientId)>() + 1),
+            TxIndex::Chunked(chunks) => chunks
+                .values()
+                .map(|chunk| mem::size_of::<(u16, Chunk)>() + chunk.bytes())
+                .sum(),
+            TxIndex::Direct(slots) => slots.bytes(),
+        }
+    }
+}
+
+impl Default for TxIndex {
+    fn default() -> Self {
+        Self::new(IndexStrategy::default())
+    }
+}
+
+impl Extend<(TxId, ClientId)> for TxIndex {
+    fn extend<I: IntoIterator<Item = (TxId, ClientId)>>(&mut self, ids: I) {
+        for (tx, client) in ids {
+            self.insert(tx, client);
+        }
+    }
+}
+
+/// `hashed index of 120 ids using 1.5 KiB`
+impl fmt::Display for TxIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} index of {} ids using {:.1} KiB",
+            self.strategy().name(),
+            self.len(),
+            self.bytes() as f64 / 1024.0
+        )
+    }
+}
+
+/// Owners of the ids of a chunk sharing their high 16 bits
+#[derive(Clone, Debug)]
+pub enum Chunk {
+    /// Sorted by the low 16 bits of the id
+    Sparse(Vec<(u16, ClientId)>),
+    Dense(Slots),
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::Sparse(Vec::new())
+    }
+}
+
+impl Chunk {
+    fn insert(&mut self, low: u16, client: ClientId) -> Option<ClientId> {
+        match self {
+            Chunk::Sparse(entries) => match entries.binary_search_by_key(&low, |&(low, _)| low) {
+                Ok(i) => Some(mem::replace(&mut entries[i].1, client)),
+                Err(_) if entries.len() + 1 >= DENSE_CHUNK => {
+                    let mut slots = Slots::default();
+                    for &(low, client) in entries.iter() {
+                        slots.insert(low as usize, client);
+                    }
+                    *self = Chunk::Dense(slots);
+                    self.insert(low, client)
+                }
+                Err(i) => {
+                    entries.insert(i, (low, client));
+                    None
+                }
+            },
+            Chunk::Dense(slots) => slots.insert(low as usize, client),
+        }
+    }
+
+    /// A dense chunk stays dense, ids are rarely forgotten
+    fn remove(&mut self, low: u16) -> Option<ClientId> {
+        match self {
+            Chunk::Sparse(entries) => {
+                let i = entries.binary_search_by_key(&low, |&(low, _)| low).ok()?;
+                Some(entries.remove(i).1)
+            }
+            Chunk::Dense(slots) => slots.remove(low as usize),
+        }
+    }
+
+    fn get(&self, low: u16) -> Option<ClientId> {
+        match self {
+            Chunk::Sparse(entries) => entries
+                .binary_search_by_key(&low, |&(low, _)| low)
+                .ok()
+                .map(|i| entries[i].1),
+            Chunk::Dense(slots) => slots.get(low as usize),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Chunk::Sparse(entries) => entries.len(),
+            Chunk::Dense(slots) => slots.len,
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (u16, ClientId)> + '_> {
+        match self {
+            Chunk::Sparse(entries) => Box::new(entries.iter().copied()),
+            Chunk::Dense(slots) => Box::new(slots.iter().map(|(low, client)| (low as u16, client))),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            Chunk::Sparse(entries) => entries.capacity() * mem::size_of::<(u16, ClientId)>(),
+            Chunk::Dense(slots) => slots.bytes(),
+        }
+    }
+}
+
+/// An owner slot per id with a bitset of the used slots, grown up to the highest id inserted
+#[derive(Clone, Debug, Default)]
+pub struct Slots {
+    owners: Vec<ClientId>,
+    used: Vec<u64>,
+    len: usize,
+}
+
+impl Slots {
+    fn insert(&mut self, slot: usize, client: ClientId) -> Option<ClientId> {
+        if slot >= self.owners.len() {
+            self.owners.resize(slot + 1, 0);
+            self.used.resize(slot / 64 + 1, 0);
+        }
+        let previous = self.get(slot);
+        self.owners[slot] = client;
+        self.used[slot / 64] |= 1 << (slot % 64);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    fn remove(&mut self, slot: usize) -> Option<ClientId> {
+        let owner = self.get(slot)?;
+        self.used[slot / 64] &= !(1 << (slot % 64));
+        self.len -= 1;
+        Some(owner)
+    }
+
+    fn get(&self, slot: usize) -> Option<ClientId> {
+        let owner = *self.owners.get(slot)?;
+        Some(owner).filter(|_| self.used[slot / 64] & 1 << (slot % 64) != 0)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (usize, ClientId)> + '_ {
+        (0..self.owners.len()).filter_map(move |slot| self.get(slot).map(|client| (slot, client)))
+    }
+
+    fn bytes(&self) -> usize {
+        self.owners.capacity() * mem::size_of::<ClientId>() + self.used.capacity() * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strategies_hold_the_same_ids() {
+        let ids = [7, 3, 70_000, 65_535, 1 << 20, u32::MAX];
+        for strategy in [
+            IndexStrategy::Hashed,
+            IndexStrategy::Chunked,
+            IndexStrategy::Direct,
+        ] {
+            let mut index = TxIndex::new(strategy);
+            for (client, &tx) in ids.iter().enumerate() {
+                assert_eq!(index.insert(tx, client as ClientId), None);
+            }
+            assert_eq!(index.insert(3, 9), Some(1));
+            assert_eq!(index.len(), ids.len());
+            assert_eq!(index.get(3), Some(9));
+            assert_eq!(index.get(u32::MAX), Some(5));
+            assert!(!index.contains(4));
+            assert_eq!(index.remove(70_000), Some(2));
+            assert_eq!(index.remove(70_000), None);
+            assert_eq!(index.len(), ids.len() - 1);
+            index.insert(70_000, 2);
+            let mut held: Vec<_> = index.iter().collect();
+            held.sort_unstable();
+            assert_eq!(held[..2], [(3, 9), (7, 0)]);
+            let mut fresh = TxIndex::new(strategy);
+            fresh.insert(1, 1);
+            assert!(!fresh.contains(2));
+            // The stray ids made the direct index switch to chunks rather than allocate 8 GiB
+            assert!(index.bytes() < 1 << 20);
+        }
+    }
+
+    #[test]
+    fn chunks_switch_to_slots_when_full_enough() {
+        let mut index = TxIndex::new(IndexStrategy::Chunked);
+        index.extend((0..DENSE_CHUNK as TxId).map(|tx| (tx, 1)));
+        match &index {
+            TxIndex::Chunked(chunks) => assert!(matches!(chunks[&0], Chunk::Dense(_))),
+            _ => unreachable!(),
+        }
+        assert_eq!(index.len(), DENSE_CHUNK);
+        assert_eq!(index.iter().next(), Some((0, 1)));
+        index.convert(IndexStrategy::Hashed);
+        assert_eq!(index.get(DENSE_CHUNK as TxId - 1), Some(1));
+    }
+
+    #[test]
+    fn strategy_follows_the_density() {
+        assert_eq!(IndexStrategy::choose(1..=1000), IndexStrategy::Direct);
+        assert_eq!(
+            IndexStrategy::choose((1..=1000).map(|tx| (1 << 20) + tx * 10)),
+            IndexStrategy::Chunked
+        );
+        assert_eq!(
+            IndexStrategy::choose((1..=1000).map(|tx: TxId| tx.wrapping_mul(2_654_435_761))),
+            IndexStrategy::Hashed
+        );
+        assert_eq!(IndexStrategy::choose(None), IndexStrategy::Hashed);
+    }
+}
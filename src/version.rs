@@ -0,0 +1,121 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+};
+
+/// Version of the crate that wrote an artifact
+pub const ENGINE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Behaviour compatibility level, bumped whenever a change makes the engine interpret persisted
+/// state differently (for example a change to the dispute semantics). Artifacts written under a
+/// different level are refused unless loading is explicitly forced. Older levels can't be
+/// migrated as they lack state the current engine relies on:
+///
+/// - 1: the original format
+/// - 2: snapshots keep bookings waiting for their value date, settled disputes and approval
+///   sign-offs, and a resolved dispute can't be opened again
+/// - 3: archives keep the timestamps of the transfers, their fees and refunds and how their
+///   disputes ended
+/// - 4: snapshots record how many records of the write-ahead log they already contain
+/// - 5: settled disputes are kept by tx id and archived together with their transfer
+pub const COMPAT_LEVEL: u32 = 5;
+
+const PREFIX: &str = "# bank ";
+
+/// Whether artifacts with a missing or different compatibility level may be loaded anyway
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompatCheck {
+    #[default]
+    Strict,
+    Force,
+}
+
+/// Header line written at the start of every persisted artifact, `# bank <version> compat <level>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp {
+    pub version: String,
+    pub compat: u32,
+}
+
+impl Stamp {
+    pub fn current() -> Self {
+        Self {
+            version: ENGINE_VERSION.to_string(),
+            compat: COMPAT_LEVEL,
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.trim().strip_prefix(PREFIX)?.split(' ');
+        let version = words.next()?.to_string();
+        if words.next()? != "compat" {
+            return None;
+        }
+        let compat = words.next()?.parse().ok()?;
+        Some(Self { version, compat })
+    }
+
+    pub fn write<W: Write>(w: &mut W) -> io::Result<()> {
+        writeln!(w, "{}", Self::current())
+    }
+
+    /// Reads the stamp line of an artifact and verifies that it can be loaded by this engine
+    /// Unstamped artifacts are left untouched, so a forced load still sees their first line
+    pub fn check<R: BufRead>(r: &mut R, check: CompatCheck) -> io::Result<()> {
+        let mut line = String::new();
+        if r.fill_buf()?.starts_with(PREFIX.as_bytes()) {
+            r.read_line(&mut line)?;
+        }
+        let stamp = Self::parse(&line);
+        let compatible = matches!(&stamp, Some(s) if s.compat == COMPAT_LEVEL);
+        if compatible || check == CompatCheck::Force {
+            return Ok(());
+        }
+        let found = match stamp {
+            Some(stamp) => stamp.to_string(),
+            None => "no compatibility stamp".to_string(),
+        };
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Artifact written with {} can't be loaded by {}, force the load to ignore this",
+                found,
+                Self::current()
+            ),
+        ))
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{} compat {}", PREFIX, self.version, self.compat)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut out = Vec::new();
+        Stamp::write(&mut out).unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert_eq!(Stamp::parse(&line), Some(Stamp::current()));
+        assert!(Stamp::check(&mut line.as_bytes(), CompatCheck::Strict).is_ok());
+    }
+
+    #[test]
+    fn incompatible_artifacts_are_refused_unless_forced() {
+        let old = format!("# bank 0.0.1 compat {}\n", COMPAT_LEVEL + 1);
+        let unstamped = "type, client, tx, amount\n";
+        for artifact in [old.as_str(), unstamped] {
+            let err = Stamp::check(&mut artifact.as_bytes(), CompatCheck::Strict).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(Stamp::check(&mut artifact.as_bytes(), CompatCheck::Force).is_ok());
+        }
+        let mut unstamped = unstamped.as_bytes();
+        Stamp::check(&mut unstamped, CompatCheck::Force).unwrap();
+        assert_eq!(unstamped, b"type, client, tx, amount\n");
+    }
+}
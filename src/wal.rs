@@ -0,0 +1,355 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    csv_parser::parse_line_with,
+    currency::CurrencyConfig,
+    error::EngineError,
+    events::EngineWarning,
+    payment_engine::{ClientTable, Summary},
+    transaction::Transaction,
+    version::{CompatCheck, Stamp},
+};
+
+/// Append only write-ahead log of the transactions handed to a `ClientTable`
+/// Records are written in the input csv format after a compatibility stamp, so a log can also be
+/// fed back to the CLI as a regular input. Every record is written to the OS in one piece before
+/// the engine applies it, syncing to disk on every record is left to `set_sync` as it costs a lot
+/// of throughput. Once an append fails the log may end in a partial record, so it refuses every
+/// further append until it is recovered and opened again
+pub struct Wal {
+    out: File,
+    sync: bool,
+    /// Records in the log, the position snapshots record, see `ClientTable::snapshot`
+    records: u64,
+    /// Set by a failed append
+    poisoned: bool,
+}
+
+impl Wal {
+    /// Opens the log for appending, creating and stamping it if needed. A log ending in a partial
+    /// record is refused, `ClientTable::recover_from_wal` cuts it off
+    pub fn open(path: impl AsRef<Path>, check: CompatCheck) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut out = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut records = 0;
+        if out.metadata()?.len() == 0 {
+            Stamp::write(&mut out)?;
+        } else {
+            let data = fs::read(path)?;
+            let mut rest = &data[..];
+            Stamp::check(&mut rest, check)?;
+            if !rest.is_empty() && !rest.ends_with(b"\n") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the write-ahead log ends in a partial record, recover from it first",
+                ));
+            }
+            records = rest.iter().filter(|&&b| b == b'\n').count() as u64;
+        }
+        Ok(Self {
+            out,
+            sync: false,
+            records,
+            poisoned: false,
+        })
+    }
+
+    /// Also syncs every record to disk, so the log survives a power loss rather than only a crash
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    /// Number of records in the log
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn append(&mut self, tx: &Transaction, currency: CurrencyConfig) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other(
+                "an earlier append failed, recover from the log and open it again",
+            ));
+        }
+        let mut line = Vec::new();
+        write!(line, "{}, {}, {}, ", tx.kind_name(), tx.client(), tx.tx())?;
+        if let Some(code) = tx.currency_code() {
+            write!(line, "{}, ", code)?;
+        }
+        if let Some(amount) = tx.record_amount() {
+            write!(line, "{}", currency.display(amount))?;
+        }
+        if let Some(approver) = tx.approver() {
+            write!(line, "{}", approver)?;
+        }
+        if let Some(value_date) = tx.value_date() {
+            write!(line, ", {}", value_date)?;
+        }
+        if let Some(to) = tx.target_currency() {
+            write!(line, ", {}", to)?;
+        }
+        writeln!(line)?;
+        let written = self.out.write_all(&line).and_then(|()| match self.sync {
+            true => self.out.sync_data(),
+            false => Ok(()),
+        });
+        match written {
+            Ok(()) => self.records += 1,
+            Err(_) => self.poisoned = true,
+        }
+        written
+    }
+}
+
+impl ClientTable {
+    /// Replays the transactions logged in `path` and returns their outcome, a missing log is
+    /// treated as empty. The table should start from the state the log was started from, usually
+    /// an empty table, or from a snapshot taken while the log was attached: the records the
+    /// snapshot already contains are skipped. The replayed records are not logged again, attach
+    /// the log with `set_wal` afterwards to keep appending to it.
+    /// A last record that is cut short or can't be parsed is what a crash during an append leaves
+    /// behind, it is cut off the log with a `LogTruncated` warning. Nothing is replayed when any
+    /// other record can't be parsed
+    pub fn recover_from_wal(
+        &mut self,
+        path: impl AsRef<Path>,
+        check: CompatCheck,
+    ) -> Result<Summary, EngineError> {
+        let path = path.as_ref();
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Summary::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut rest = &data[..];
+        Stamp::check(&mut rest, check)?;
+        let mut good_len = data.len() - rest.len();
+        let mut records = Vec::new();
+        let mut torn = None;
+        let mut lines = rest.split_inclusive(|&b| b == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            let last = lines.peek().is_none();
+            let parsed = match std::str::from_utf8(line) {
+                Ok(text) if text.ends_with('\n') => {
+                    parse_line_with(Ok(text.trim_end().to_string()), self.currency_config())
+                        .map_err(EngineError::from)
+                }
+                _ => Err(EngineError::from(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "partial write-ahead log record",
+                ))),
+            };
+            match parsed {
+                Ok(tx) => records.push(tx),
+                Err(_) if last => torn = Some(String::from_utf8_lossy(line).into_owned()),
+                // Nothing is applied when a record in the middle of the log is corrupt
+                Err(e) => return Err(e),
+            }
+            if torn.is_none() {
+                good_len += line.len();
+            }
+        }
+        let logged = records.len() as u64;
+        if logged < self.wal_position {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the snapshot contains {} records of the write-ahead log but it only has {}",
+                    self.wal_position, logged
+                ),
+            )
+            .into());
+        }
+        if let Some(record) = torn {
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(good_len as u64)?;
+            self.warnings.push(EngineWarning::LogTruncated(record));
+        }
+        let wal = self.take_wal();
+        let mut summary = Summary::default();
+        for tx in records.into_iter().skip(self.wal_position as usize) {
+            summary += self.process(Some(tx));
+        }
+        self.wal_position = logged;
+        if let Some(wal) = wal {
+            self.set_wal(wal);
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client_info::TransactionError, currency::Currency};
+
+    #[test]
+    fn recovery_replays_logged_transactions() {
+        let path = std::env::temp_dir().join(format!("bank_wal_{}.csv", std::process::id()));
+        let txs = vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Currency::new(25000),
+            },
+            Transaction::Withdraw {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(90000),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+        ];
+        let mut original = ClientTable::new();
+        original.set_wal(Wal::open(&path, CompatCheck::Strict).unwrap());
+        let expected = original.process(txs);
+        drop(original.take_wal());
+
+        let mut recovered = ClientTable::new();
+        let summary = recovered
+            .recover_from_wal(&path, CompatCheck::Strict)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(summary, expected);
+        assert_eq!(recovered.to_string(), original.to_string());
+        assert!(recovered.take_wal().is_none());
+    }
+
+    #[test]
+    fn transactions_are_not_applied_when_logging_fails() {
+        let path = std::env::temp_dir().join(format!("bank_wal_ro_{}.csv", std::process::id()));
+        let wal = Wal::open(&path, CompatCheck::Strict).unwrap();
+        // Reopen the log read only so every write fails
+        let mut wal = Wal {
+            out: File::open(&path).unwrap(),
+            ..wal
+        };
+        wal.set_sync(true);
+        let mut table = ClientTable::new();
+        table.set_wal(wal);
+        let result = table.handle_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(10000),
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Err(TransactionError::NotLogged));
+        assert_eq!(table.take_warnings().len(), 1);
+        assert_eq!(table.to_string(), ClientTable::new().to_string());
+    }
+
+    #[test]
+    fn a_failed_append_poisons_the_log() {
+        let path = std::env::temp_dir().join(format!("bank_wal_poison_{}.csv", std::process::id()));
+        let wal = Wal::open(&path, CompatCheck::Strict).unwrap();
+        let mut wal = Wal {
+            out: File::open(&path).unwrap(),
+            ..wal
+        };
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(10000),
+        };
+        assert!(wal.append(&deposit, CurrencyConfig::default()).is_err());
+        // Even once the file is writable again
+        wal.out = OpenOptions::new().append(true).open(&path).unwrap();
+        assert!(wal.append(&deposit, CurrencyConfig::default()).is_err());
+        drop(wal);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+    }
+
+    #[test]
+    fn a_torn_last_record_is_cut_off() {
+        let path = std::env::temp_dir().join(format!("bank_wal_torn_{}.csv", std::process::id()));
+        let mut original = ClientTable::new();
+        original.set_wal(Wal::open(&path, CompatCheck::Strict).unwrap());
+        original.process(vec![Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Currency::new(25000),
+        }]);
+        drop(original.take_wal());
+        // A crash in the middle of the next append
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"deposit, 1, 2, 1.0").unwrap();
+        drop(log);
+
+        let mut recovered = ClientTable::new();
+        recovered
+            .recover_from_wal(&path, CompatCheck::Strict)
+            .unwrap();
+        assert_eq!(
+            recovered.take_warnings(),
+            [EngineWarning::LogTruncated(
+                "deposit, 1, 2, 1.0".to_string()
+            )]
+        );
+        assert_eq!(recovered.to_string(), original.to_string());
+        // The log takes appends again where the last complete record ends
+        let mut wal = Wal::open(&path, CompatCheck::Strict).unwrap();
+        assert_eq!(wal.records(), 1);
+        wal.append(
+            &Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: Currency::new(10000),
+            },
+            CurrencyConfig::default(),
+        )
+        .unwrap();
+        drop(wal);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.ends_with("deposit, 1, 1, 2.5000\ndeposit, 1, 2, 1.0000\n"));
+    }
+
+    #[test]
+    fn a_corrupt_record_before_the_last_is_an_error() {
+        let path = std::env::temp_dir().join(format!("bank_wal_bad_{}.csv", std::process::id()));
+        drop(Wal::open(&path, CompatCheck::Strict).unwrap());
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"deposit, x, 1, 1.0\ndeposit, 1, 2, 1.0\n")
+            .unwrap();
+        drop(log);
+        let mut table = ClientTable::new();
+        let result = table.recover_from_wal(&path, CompatCheck::Strict);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(table.to_string(), ClientTable::new().to_string());
+    }
+
+    #[test]
+    fn records_in_the_restored_snapshot_are_not_replayed() {
+        let dir = std::env::temp_dir();
+        let wal_path = dir.join(format!("bank_wal_restore_{}.csv", std::process::id()));
+        let snapshot = dir.join(format!("bank_wal_snapshot_{}.csv", std::process::id()));
+        let deposit = |tx, amount| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Currency::new(amount),
+        };
+        let mut original = ClientTable::new();
+        original.set_wal(Wal::open(&wal_path, CompatCheck::Strict).unwrap());
+        original.process(vec![deposit(1, 10000), deposit(2, 20000)]);
+        original.snapshot(&snapshot).unwrap();
+        original.process(vec![deposit(3, 40000)]);
+        drop(original.take_wal());
+
+        let mut recovered = ClientTable::new();
+        recovered.restore(&snapshot, CompatCheck::Strict).unwrap();
+        let summary = recovered
+            .recover_from_wal(&wal_path, CompatCheck::Strict)
+            .unwrap();
+        std::fs::remove_file(&wal_path).unwrap();
+        std::fs::remove_file(&snapshot).unwrap();
+        assert_eq!(summary.applied, 1);
+        assert_eq!(recovered.to_string(), original.to_string());
+    }
+}
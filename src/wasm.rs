@@ -0,0 +1,97 @@
+use std::io;
+
+use js_sys::JSON;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    csv_parser::{self, Header, Records},
+    json_parser,
+    payment_engine::ClientTable,
+    transaction::Transaction,
+};
+
+/// Report of the csv records of `input` as written by `ClientTable::write_json`, the header is
+/// optional. Nothing on this path touches the file system, the network, threads or the clock,
+/// which a wasm build lacks
+pub fn process_csv(input: &str) -> io::Result<String> {
+    let mut body = input.as_bytes();
+    csv_parser::skip_header(&mut body, Header::Detect)?;
+    let transactions = Records::new(body)
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+        .map(|l| csv_parser::parse_line(l).map_err(io::Error::from));
+    report(transactions)
+}
+
+/// Same as `process_csv` for newline delimited JSON objects such as
+/// `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`
+pub fn process_json(input: &str) -> io::Result<String> {
+    let transactions = input
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| json_parser::parse_line(Ok(l.to_string())).map_err(io::Error::from));
+    report(transactions)
+}
+
+/// Rejected transactions are left out of the balances like in the command line tool, a record
+/// that can't be parsed fails the whole input
+fn report(transactions: impl Iterator<Item = io::Result<Transaction>>) -> io::Result<String> {
+    let transactions = transactions.collect::<io::Result<Vec<_>>>()?;
+    let mut table = ClientTable::new();
+    table.process(transactions);
+    let mut out = Vec::new();
+    table.write_json(&mut out)?;
+    Ok(String::from_utf8(out).expect("the report is utf-8"))
+}
+
+/// Balances after the csv records of `input`, as an array of `{client, available, held, total,
+/// locked}` objects with the amounts as strings. This and `processTransactions` are the exports of
+/// a wasm build, which leaves out the default features such as `std-io`. The module and its
+/// JavaScript bindings are generated with
+///
+/// ```text
+/// cargo build --lib --release --target wasm32-unknown-unknown --no-default-features --features wasm
+/// wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/bank.wasm
+/// ```
+///
+/// An input that can't be parsed throws with the error of the engine
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv_js(input: &str) -> Result<JsValue, JsValue> {
+    parse(process_csv(input))
+}
+
+/// Same as `processCsv` for an array of transaction objects such as
+/// `{type: "deposit", client: 1, tx: 1, amount: "1.5"}`
+#[wasm_bindgen(js_name = processTransactions)]
+pub fn process_transactions_js(transactions: Vec<JsValue>) -> Result<JsValue, JsValue> {
+    let lines = transactions
+        .iter()
+        .map(|t| JSON::stringify(t).map(String::from))
+        .collect::<Result<Vec<_>, _>>()?;
+    parse(process_json(&lines.join("\n")))
+}
+
+fn parse(report: io::Result<String>) -> Result<JsValue, JsValue> {
+    let report = report.map_err(|e| JsError::new(&e.to_string()))?;
+    JSON::parse(&report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_csv_and_json_input() {
+        let csv = "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 5\n";
+        let report = process_csv(csv).unwrap();
+        assert_eq!(
+            report,
+            process_json(
+                "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.5\"}\n\
+                 {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"5\"}"
+            )
+            .unwrap()
+        );
+        assert!(report.contains("\"available\":\"2.5000\""));
+        assert!(process_csv("deposit, 1, x, 1\n").is_err());
+    }
+}
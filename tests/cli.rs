@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Writes `input` to a file of its own and runs the CLI on it
+fn run(name: &str, args: &[&str], input: &str) -> Output {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("bank_cli_{}_{}.csv", name, std::process::id()));
+    fs::write(&path, input).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bank"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+/// Runs the CLI on `input` and returns stdout and stderr, the run has to succeed
+fn bank(name: &str, args: &[&str], input: &str) -> (String, String) {
+    let output = run(name, args, input);
+    assert!(output.status.success(), "{:?}", output);
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+// A spec-compat build refuses the extensions
+#[cfg(not(feature = "spec-compat"))]
+#[test]
+fn a_batch_run_closes_a_business_day() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5.0\nwithdrawal, 1, 2, 1.0\n";
+    // The withdrawal comes before the deposit cleared
+    let (report, _) = bank("clearing_t1", &["--clearing", "t+1"], input);
+    assert_eq!(
+        report,
+        "client, available, held, total, locked, booked\n1, 5.0000, 0.0000, 5.0000, false, 0.0000\n"
+    );
+    let (report, log) = bank("clearing_t2", &["--clearing", "t+2"], input);
+    assert_eq!(
+        report,
+        "client, available, held, total, locked, booked\n1, 0.0000, 0.0000, 5.0000, false, 5.0000\n"
+    );
+    assert_eq!(log, " INFO booked but not available yet booked=5.0000\n");
+}
+
+#[cfg(not(feature = "spec-compat"))]
+#[test]
+fn batches_stay_atomic_or_are_refused() {
+    // The overdraw of client 2 rolls the withdrawal of client 1 back
+    let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+begin, 0, 10,
+withdrawal, 1, 2, 1.0
+withdrawal, 2, 3, 1.0
+commit, 0, 10,
+";
+    let (report, _) = bank("batch_sequential", &[], input);
+    assert!(
+        report.contains("1, 5.0000, 0.0000, 5.0000, false"),
+        "{}",
+        report
+    );
+    let (report, _) = bank("batch_independent", &["--independent"], input);
+    assert!(
+        report.contains("1, 5.0000, 0.0000, 5.0000, false"),
+        "{}",
+        report
+    );
+    let threads = run("batch_threads", &["--threads", "2"], input);
+    assert!(!threads.status.success());
+    assert!(threads.stdout.is_empty());
+}
+
+#[cfg(all(feature = "config", not(feature = "spec-compat")))]
+#[test]
+fn the_command_line_overrides_the_config_file() {
+    let config = std::env::temp_dir().join(format!("bank_cli_config_{}.toml", std::process::id()));
+    fs::write(&config, "[output]\nsort = \"total\"\n").unwrap();
+    let config = config.to_str().unwrap();
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n";
+    let (report, _) = bank("config_sorted", &["--config", config], input);
+    assert!(report.starts_with("client, available, held, total, locked\n2,"));
+    let (report, _) = bank(
+        "config_overridden",
+        &["--config", config, "--sort", "client"],
+        input,
+    );
+    assert!(report.starts_with("client, available, held, total, locked\n1,"));
+    fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn spec_compat_reports_what_the_original_engine_did() {
+    // Reports of the first version of the engine, sample1 and sample2 reuse transaction ids
+    let expected = [
+        (
+            "sample.csv",
+            "1, 1.5000, 0.0000, 1.5000, false\n2, 2.0000, 0.0000, 2.0000, false\n",
+        ),
+        (
+            "sample1.csv",
+            "1, 9031.5000, 0.0000, 9031.5000, false\n2, 3.0000, 0.0000, 3.0000, false\n",
+        ),
+        (
+            "sample2.csv",
+            "1, 12900.0000, 0.0000, 12900.0000, false\n2, 1.0000, 0.0000, 1.0000, false\n",
+        ),
+    ];
+    for (sample, balances) in expected.iter() {
+        let input =
+            fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(sample)).unwrap();
+        let (report, log) = bank(sample, &["--spec-compat"], &input);
+        assert_eq!(
+            report,
+            format!("client, available, held, total, locked\n{}\n", balances),
+            "{}",
+            sample
+        );
+        assert_eq!(log, "", "{}", sample);
+    }
+    let refused = run(
+        "spec_compat_threads",
+        &["--spec-compat", "--threads", "2"],
+        "",
+    );
+    assert!(!refused.status.success());
+}